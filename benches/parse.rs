@@ -0,0 +1,78 @@
+/*-
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * Copyright (c) 2024 Rink Springer <rink@rink.nu>
+ * For conditions of distribution and use, see LICENSE file
+ */
+//! Compares the time and memory used by GraDecoder::new() and GraDecoder::new_borrowed() on a
+//! large, uncompressed GRA file. Run with `cargo bench --bench parse`.
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+use qfg5reenigne::qfg5resource::qfg5gra::GraDecoder;
+
+/// Counts the bytes allocated
+struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+const NUM_COLLECTIONS: u32 = 16;
+const NUM_SPRITES: u32 = 8;
+const SIZE: u32 = 256;
+const ITERATIONS: u32 = 20;
+
+/// Colour mode 0, so the pixels are stored as-is
+fn large_gra() -> Vec<u8> {
+    let mut data = Vec::new();
+    data.extend(0u32.to_le_bytes());
+    data.extend(NUM_COLLECTIONS.to_le_bytes());
+    data.extend([ 0u8; 512 ]);
+    let header_size = 28 + 4 * NUM_SPRITES;
+    let collection_size = header_size + NUM_SPRITES * SIZE * SIZE;
+    let first_collection = data.len() as u32 + 4 * NUM_COLLECTIONS;
+    for n in 0..NUM_COLLECTIONS {
+        data.extend((first_collection + n * collection_size).to_le_bytes());
+    }
+    for n in 0..NUM_COLLECTIONS {
+        for value in [ 0, 0, SIZE, SIZE, NUM_SPRITES, 100, 0 ] {
+            data.extend(value.to_le_bytes());
+        }
+        for s in 0..NUM_SPRITES {
+            data.extend((header_size + s * SIZE * SIZE).to_le_bytes());
+        }
+        data.extend((0..NUM_SPRITES * SIZE * SIZE).map(|p| (p + n) as u8));
+    }
+    data
+}
+
+fn measure<'a, T>(name: &str, data: &'a [u8], decode: impl Fn(&'a [u8]) -> T) {
+    let allocated = ALLOCATED.load(Ordering::Relaxed);
+    let started = Instant::now();
+    for _ in 0..ITERATIONS {
+        std::hint::black_box(decode(std::hint::black_box(data)));
+    }
+    let elapsed = started.elapsed() / ITERATIONS;
+    let bytes = (ALLOCATED.load(Ordering::Relaxed) - allocated) / ITERATIONS as usize;
+    println!("{:>12}: {:>10.3?} per parse, {:>9} bytes allocated", name, elapsed, bytes);
+}
+
+fn main() {
+    let data = large_gra();
+    println!("GRA of {} bytes, {} bytes of pixels", data.len(), NUM_COLLECTIONS * NUM_SPRITES * SIZE * SIZE);
+    measure("new", &data, |data| GraDecoder::new(data).unwrap());
+    measure("new_borrowed", &data, |data| GraDecoder::new_borrowed(data).unwrap());
+}
@@ -0,0 +1,72 @@
+/*-
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * Copyright (c) 2024 Rink Springer <rink@rink.nu>
+ * For conditions of distribution and use, see LICENSE file
+ */
+//! Compares extracting every item of a large archive through a file, with read_item() and
+//! read_item_to(), against borrowing the items from a memory map with item_bytes(). Run with
+//! `cargo bench --bench spk --features mmap`.
+use std::io::Write;
+use std::time::Instant;
+use qfg5reenigne::qfg5resource::qfg5spk::SpkArchive;
+
+const NUM_ITEMS: usize = 2000;
+/// Sizes of the items go up to this, like the mix of small resources and large movies and speech
+const MAX_ITEM_SIZE: usize = 256 * 1024;
+const ITERATIONS: u32 = 5;
+
+fn large_spk() -> Vec<u8> {
+    let items: Vec<_> = (0..NUM_ITEMS).map(|n| {
+        let size = (n * 7919) % MAX_ITEM_SIZE;
+        (format!("DIR{}/{}.DAT", n % 10, n), vec![ n as u8; size ])
+    }).collect();
+    let items: Vec<_> = items.iter().map(|(name, data)| (name.as_str(), &data[..])).collect();
+    let mut data = Vec::new();
+    SpkArchive::create(&mut data, &items).unwrap();
+    data
+}
+
+/// Adds up what is written, so that every byte is looked at, as writing it out would
+struct Checksum(u64);
+
+impl Write for Checksum {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0 = buf.iter().fold(self.0, |sum, &b| sum.wrapping_add(b as u64));
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn measure(name: &str, archive: &SpkArchive, extract: impl Fn(&SpkArchive, &mut dyn Write)) {
+    let started = Instant::now();
+    for _ in 0..ITERATIONS {
+        let mut checksum = Checksum(0);
+        extract(archive, &mut checksum);
+        std::hint::black_box(checksum.0);
+    }
+    println!("{:>14}: {:>10.3?} per extraction", name, started.elapsed() / ITERATIONS);
+}
+
+fn main() {
+    let data = large_spk();
+    let path = std::env::temp_dir().join(format!("qfg5-bench-{}.spk", std::process::id()));
+    std::fs::write(&path, &data).unwrap();
+    println!("SPK of {} items, {} bytes", NUM_ITEMS, data.len());
+
+    let file = SpkArchive::new(std::fs::File::open(&path).unwrap()).unwrap();
+    measure("read_item", &file, |archive, w| for item in archive.get_items() {
+        w.write_all(&archive.read_item(item).unwrap()).unwrap();
+    });
+    measure("read_item_to", &file, |archive, w| for item in archive.get_items() {
+        archive.read_item_to(item, w).unwrap();
+    });
+    let mapped = SpkArchive::open_mmap(&path).unwrap();
+    measure("item_bytes", &mapped, |archive, w| for item in archive.get_items() {
+        w.write_all(archive.item_bytes(item).unwrap()).unwrap();
+    });
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,13 @@
+/*-
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * Copyright (c) 2024 Rink Springer <rink@rink.nu>
+ * For conditions of distribution and use, see LICENSE file
+ */
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use qfg5reenigne::qfg5resource::qfg5anm;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = qfg5anm::AnmDecoder::new(data);
+});
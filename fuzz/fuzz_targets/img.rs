@@ -0,0 +1,16 @@
+/*-
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * Copyright (c) 2024 Rink Springer <rink@rink.nu>
+ * For conditions of distribution and use, see LICENSE file
+ */
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use qfg5reenigne::qfg5resource::{qfg5img, qfg5zzz};
+
+fuzz_target!(|data: &[u8]| {
+    // ZZZ files have no header; their size comes from the IMG
+    if let Ok(img) = qfg5img::ImageDecoder::new(data) {
+        let _ = qfg5zzz::ZzzDecoder::new(data, &img);
+    }
+});
@@ -0,0 +1,17 @@
+/*-
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * Copyright (c) 2024 Rink Springer <rink@rink.nu>
+ * For conditions of distribution and use, see LICENSE file
+ */
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use qfg5reenigne::qfg5resource::qfg5spk;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(archive) = qfg5spk::SpkArchive::from_bytes(data.to_vec()) {
+        for item in archive.get_items() {
+            let _ = archive.read_item(item);
+        }
+    }
+});
@@ -0,0 +1,92 @@
+# SPDX-License-Identifier: GPL-3.0-or-later
+#
+# Copyright (c) 2024 Rink Springer <rink@rink.nu>
+# For conditions of distribution and use, see LICENSE file
+#
+# Tests of the Python bindings against the fixtures in tests/fixtures; run with
+#   maturin develop && pytest python/tests
+from pathlib import Path
+
+import pytest
+
+import qfg5reenigne
+
+FIXTURES = Path(__file__).resolve().parents[2] / "tests" / "fixtures"
+
+
+def fixture(kind):
+    return (FIXTURES / f"fixture.{kind}").read_bytes()
+
+
+def test_qgm():
+    qgm = qfg5reenigne.QgmDecoder(fixture("qgm"))
+    assert qgm.file_id == 415
+    assert qgm.messages == [{
+        "id": [1, 2, 3, 4], "speaker_id": 5, "msg_id": 7, "label": "LABEL0000001",
+        "dialog_options": ["OPTION000001"], "text": "Hello",
+    }]
+
+
+def test_model():
+    mdl = qfg5reenigne.Qfg5Model(fixture("mdl"))
+    assert mdl.name == "fixture"
+    assert mdl.palette.shape == (256, 3)
+    assert mdl.palette.tolist()[1] == [255, 0, 0]
+    assert len(mdl.palette_bytes) == 1019
+    assert mdl.warnings == []
+    [quad] = mdl.submeshes
+    assert quad["name"] == "quad"
+    assert quad["vertices"].shape == (4, 3)
+    assert quad["vertices"].format == "f"
+    assert quad["vertices"].tolist()[2] == [1.0, 1.0, 0.0]
+    assert quad["texcoords"].shape == (4, 2)
+    assert quad["faces"].tolist() == [[0, 1, 2], [0, 2, 3]]
+    assert quad["face_texcoords"].tolist() == [[0, 1, 2], [0, 2, 3]]
+    assert quad["face_subbitmaps"].tolist() == [[0], [0]]
+    assert quad["normals"].tolist() == [[0.0, 0.0, 1.0]] * 2
+    assert [l[0] for l in quad["lighting"].tolist()] == [0.0, 1.0, 2.0, 3.0]
+    [subbitmap] = mdl.subbitmaps
+    assert (subbitmap["width"], subbitmap["height"]) == (2, 2)
+    assert subbitmap["pixels"].tolist() == [[1, 0], [0, 1]]
+
+
+def test_gra():
+    gra = qfg5reenigne.GraDecoder(fixture("gra"))
+    assert gra.palette.shape == (256, 3)
+    [sc] = gra.sprite_collections
+    assert (sc["x"], sc["y"], sc["width"], sc["height"], sc["frame_delay"]) == (10, 20, 2, 2, 100)
+    assert [frame.tolist() for frame in sc["frames"]] == [[[0, 1], [1, 0]], [[1, 1], [1, 1]]]
+    rgba = gra.rgba(0, 0)
+    assert rgba.shape == (2, 2, 4)
+    assert rgba.tolist()[0][0][3] == 0
+    assert rgba.tolist()[0][1] == list(gra.palette.tolist()[1]) + [255]
+    with pytest.raises(IndexError):
+        gra.rgba(0, 2)
+
+
+def test_spk():
+    spk = qfg5reenigne.SpkArchive(fixture("spk"))
+    assert [item["filename"] for item in spk.items()] == ["MDL/1.MDL", "ANM/1.ANM"]
+    assert spk.read("mdl/1.mdl") == fixture("mdl")
+    with pytest.raises(KeyError):
+        spk.read("QGM/1.QGM")
+
+
+def test_decode_error():
+    with pytest.raises(qfg5reenigne.DecodeError) as e:
+        qfg5reenigne.QgmDecoder(fixture("mdl"))
+    assert e.value.kind == "bad_magic"
+    assert e.value.offset == 0
+    with pytest.raises(ValueError) as e:
+        qfg5reenigne.Qfg5Model(fixture("mdl")[:-1])
+    assert e.value.kind == "truncated"
+
+
+def test_numpy():
+    np = pytest.importorskip("numpy")
+    mdl = qfg5reenigne.Qfg5Model(fixture("mdl"))
+    vertices = np.asarray(mdl.submeshes[0]["vertices"])
+    assert vertices.dtype == np.float32
+    assert vertices.shape == (4, 3)
+    gra = qfg5reenigne.GraDecoder(fixture("gra"))
+    assert np.asarray(gra.rgba(0, 1))[..., 3].min() == 255
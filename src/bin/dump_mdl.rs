@@ -4,23 +4,121 @@
  * Copyright (c) 2024 Rink Springer <rink@rink.nu>
  * For conditions of distribution and use, see LICENSE file
  */
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use std::env;
+use std::path::{Path, PathBuf};
+use qfg5reenigne::commands::{self, ImageFormat};
 use qfg5reenigne::qfg5resource::qfg5mdl;
 
-fn main() -> Result<()> {
-    let args: Vec<_> = env::args().into_iter().collect();
-    if args.len() != 2 {
-        println!("usage: {} file.mdl", args[0]);
-        return Ok(())
+enum Action<'a> {
+    Dump{ verbose: bool, lighting: bool, json: bool, full: bool },
+    Stats,
+    ExportObj{ prefix: &'a str, submesh: Option<&'a str> },
+    ExportTextures{ out_dir: &'a str, format: ImageFormat, with_alpha: Option<u8> },
+    ExportUvmap{ out_dir: &'a str },
+    Diff{ other: &'a str, tolerance: f32 },
+}
+
+/// The options of the dump: `[--verbose] [--lighting] [--format text|json] [--full]`
+fn dump_options(options: &[String]) -> Option<Action<'static>> {
+    let (mut verbose, mut lighting, mut json, mut full) = (false, false, false, false);
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match option.as_str() {
+            "--verbose" => verbose = true,
+            "--lighting" => lighting = true,
+            "--format" => json = match options.next()?.as_str() {
+                "text" => false,
+                #[cfg(feature = "serde")]
+                "json" => true,
+                _ => return None,
+            },
+            "--full" => full = true,
+            _ => return None,
+        }
     }
+    Some(Action::Dump{ verbose, lighting, json, full })
+}
+
+/// The options of export-textures: `[--bmp] [--with-alpha <index>]`
+fn texture_options(options: &[String]) -> Option<(ImageFormat, Option<u8>)> {
+    let (mut format, mut with_alpha) = (ImageFormat::Png, None);
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match option.as_str() {
+            "--bmp" => format = ImageFormat::Bmp,
+            "--with-alpha" => with_alpha = Some(options.next()?.parse().ok()?),
+            _ => return None,
+        }
+    }
+    Some((format, with_alpha))
+}
+
+// Superseded by `qfg5 mdl <file> dump`, `export-obj` and `export-textures`
+fn main() -> Result<()> {
+    let args: Vec<_> = env::args().collect();
+    let (path, action) = match &args[1..] {
+        [ options @ .., path ] if dump_options(options).is_some() => (path, dump_options(options).unwrap()),
+        [ path, action ] if action == "stats" => (path, Action::Stats),
+        [ path, action, prefix ] if action == "export-obj" => (path, Action::ExportObj{ prefix, submesh: None }),
+        [ path, action, prefix, option, submesh ] if action == "export-obj" && option == "--submesh" => (path, Action::ExportObj{ prefix, submesh: Some(submesh) }),
+        [ path, action, other ] if action == "diff" => (path, Action::Diff{ other, tolerance: 0.0 }),
+        [ path, action, other, option, tolerance ] if action == "diff" && option == "--tolerance" && tolerance.parse::<f32>().is_ok() =>
+            (path, Action::Diff{ other, tolerance: tolerance.parse().unwrap() }),
+        [ path, action, out_dir ] if action == "export-uvmap" => (path, Action::ExportUvmap{ out_dir }),
+        [ path, action, out_dir, options @ .. ] if action == "export-textures" && texture_options(options).is_some() => {
+            let (format, with_alpha) = texture_options(options).unwrap();
+            (path, Action::ExportTextures{ out_dir, format, with_alpha })
+        },
+        _ => {
+            println!("usage: {} [--verbose] [--lighting] [--format text|json] [--full] file.mdl", args[0]);
+            println!("       {} file.mdl stats", args[0]);
+            println!("       {} file.mdl export-obj out-prefix [--submesh name-or-index]", args[0]);
+            println!("       {} file.mdl export-textures out-dir [--bmp] [--with-alpha index]", args[0]);
+            println!("       {} file.mdl export-uvmap out-dir", args[0]);
+            println!("       {} file.mdl diff other.mdl [--tolerance distance]", args[0]);
+            return Ok(())
+        }
+    };
 
-    let mdl_data = std::fs::read(&args[1])?;
-    let mdl = qfg5mdl::Qfg5Model::new(&mdl_data)?;
-    println!("model '{}', {} submeshes", mdl.name, mdl.submeshes.len());
-    for sm in &mdl.submeshes {
-        println!("  submesh '{}', {} vertices, {} texcoords, {} faces, {} lighting faces",
-            sm.name, sm.vertices.len(), sm.texcoords.len(), sm.faces.len(), sm.lighting_vertices.len());
+    let data = std::fs::read(path)?;
+    let mdl = qfg5mdl::Qfg5Model::new(&data)?;
+    let written = match action {
+        #[cfg(feature = "serde")]
+        Action::Dump{ json: true, full, .. } => return commands::write_json(&commands::mdl::json_summary(&mdl, full), &mut std::io::stdout()),
+        Action::Dump{ verbose, lighting, json, full } => {
+            if json || full {
+                return Err(anyhow!("--full only goes with --format json"));
+            }
+            commands::mdl::dump(&mdl, verbose, &mut std::io::stdout())?;
+            if lighting {
+                commands::mdl::dump_lighting(&mdl, &mut std::io::stdout())?;
+            }
+            return Ok(());
+        },
+        Action::Diff{ other, tolerance } => {
+            let other = qfg5mdl::Qfg5Model::new(&std::fs::read(other)?)?;
+            let diff = commands::mdl::diff(&mdl, &other, tolerance);
+            diff.write(&mut std::io::stdout())?;
+            if !diff.is_same() {
+                return Err(anyhow!("the models differ"));
+            }
+            return Ok(());
+        },
+        Action::Stats => return commands::mdl::dump_stats(&mdl, &mut std::io::stdout()),
+        Action::ExportObj{ prefix, submesh } => {
+            // All submeshes side by side, as `qfg5 mdl <file> export-obj` does without --anm
+            let pose = match submesh {
+                Some(selector) => commands::mdl::ExportPose::Submesh(commands::mdl::find_submesh(&mdl, selector)?),
+                None => commands::mdl::ExportPose::Exploded,
+            };
+            commands::mdl::export_obj(&mdl, pose, &PathBuf::from(format!("{}.obj", prefix)))?
+        },
+        Action::ExportTextures{ out_dir, format, with_alpha } => commands::mdl::export_textures(&mdl, Path::new(out_dir), format, with_alpha)?,
+        Action::ExportUvmap{ out_dir } => commands::mdl::export_uvmaps(&mdl, Path::new(out_dir))?,
+    };
+    for path in written {
+        println!("wrote {}", path.display());
     }
     Ok(())
 }
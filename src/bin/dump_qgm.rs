@@ -4,52 +4,147 @@
  * Copyright (c) 2024 Rink Springer <rink@rink.nu>
  * For conditions of distribution and use, see LICENSE file
  */
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use std::path::PathBuf;
 use clap::{Parser, Subcommand};
-use qfg5reenigne::qfg5resource::qfg5qgm;
+use qfg5reenigne::commands;
+use qfg5reenigne::qfg5resource::{qfg5qgm, ResourceKind};
 
 #[derive(Subcommand)]
 enum CliCommands {
     /// Lists all resources
-    List,
+    List {
+        /// Also show the header fields whose meaning is not known yet
+        #[arg(long)]
+        verbose: bool,
+        /// CSV file with a line `id,name` per speaker, to show their names
+        #[arg(long)]
+        speakers: Option<PathBuf>,
+        /// Only list the messages of this speaker, by name or id
+        #[arg(long)]
+        speaker: Option<String>,
+        /// Show the name of the speech resource of every message; with a directory of extracted
+        /// resources or an SPK archive, also whether it exists there, and from an archive its duration
+        #[arg(long, value_name = "DIR_OR_SPK", num_args = 0..=1)]
+        with_audio: Option<Option<PathBuf>>,
+    },
+    /// Writes the messages as CSV with a header row, or to stdout if it is -
+    ExportCsv {
+        /// Output CSV file
+        out_csv: PathBuf,
+    },
+    /// Writes the conversations as a Graphviz DOT graph
+    Graph {
+        /// Output DOT file
+        #[arg(long)]
+        out: PathBuf,
+        /// Further QGM files whose messages are included, so that references between files are followed
+        other_qgm: Vec<PathBuf>,
+    },
+    /// Writes the messages to a PO file for translating them, or to stdout if it is -
+    ExportPo {
+        /// Output PO file
+        out_po: PathBuf,
+    },
+    /// Writes a QGM file with the translations of a PO file; untranslated messages keep their text
+    ImportPo {
+        /// PO file, as written by export-po
+        po: PathBuf,
+        /// Output QGM file
+        out_qgm: PathBuf,
+    },
+    /// Compares the messages with those of another QGM file by label, such as of another release
+    Diff {
+        /// The QGM file to compare with
+        other_qgm: PathBuf,
+        /// Also list the messages that are the same
+        #[arg(long)]
+        verbose: bool,
+    },
+    /// Writes a QGM file with the text of one message replaced
+    Patch {
+        /// Label of the message, such as 0BJ0102.034
+        label: String,
+        /// New text of the message
+        text: String,
+        /// Output QGM file
+        #[arg(long)]
+        out: PathBuf,
+    },
+    /// Reports the dialog options and message labels that refer to a label none of the files has;
+    /// fails if there are any. The input and the others may be directories of QGM files
+    Validate {
+        /// Further QGM files or directories whose labels can be referred to
+        other_qgm: Vec<PathBuf>,
+    },
 }
 
-/// Extracts Quest for Glory 5 messages from *.QGM
+/// Extracts Quest for Glory 5 messages from *.QGM; superseded by `qfg5 qgm <file> list`
 #[derive(Parser)]
 struct Cli {
     /// Input QGM file
     in_qgm: PathBuf,
+    /// Use the messages before the point where the file ends early, instead of failing
+    #[arg(long)]
+    lenient: bool,
     #[command(subcommand)]
     command: Option<CliCommands>
 }
 
-fn list(qgm: &qfg5qgm::QgmDecoder) -> Result<()> {
-    println!("qgm file id: {}", qgm.file_id);
-    for m in &qgm.messages {
-        let message_id = qfg5qgm::QgmLabel::encode(&qgm, &m);
-        println!("{} message {}: '{}'", message_id, m.msg_id, m.text);
-        if let Some(ml) = &m.message_label {
-            println!("  message label: {}", ml);
-        }
-        for dlo in &m.dialog_options {
-            println!("  dialog option: {}", dlo);
-        }
-    }
-    Ok(())
-}
-
 fn main() -> Result<()> {
     env_logger::init();
     let args = Cli::parse();
-    let data = std::fs::read(args.in_qgm)?;
-    let qgm = qfg5qgm::QgmDecoder::new(&data)?;
+    if let Some(CliCommands::Validate { other_qgm }) = &args.command {
+        let paths: Vec<_> = std::iter::once(&args.in_qgm).chain(other_qgm).cloned().collect();
+        let dangling = commands::qgm::validate(&paths, &mut std::io::stdout())?;
+        if dangling > 0 {
+            return Err(anyhow!("{} dangling references", dangling));
+        }
+        return Ok(());
+    }
+    let data = std::fs::read(&args.in_qgm)?;
+    let qgm = if args.lenient { qfg5qgm::QgmDecoder::new_lenient(&data)? } else { qfg5qgm::QgmDecoder::new(&data)? };
+    if let Some(at) = qgm.truncated_at {
+        eprintln!("warning: data ends within the message at offset {:#x}, decoded {} messages", at, qgm.messages.len());
+    }
 
     match &args.command {
-        Some(CliCommands::List) => {
-            list(&qgm)?;
+        Some(CliCommands::List { verbose, speakers, speaker, with_audio }) => {
+            let speakers = speakers.as_deref().map(commands::qgm::read_speakers).transpose()?;
+            let speaker = speaker.as_deref().map(|speaker| commands::qgm::resolve_speaker(speakers.as_ref(), speaker)).transpose()?;
+            let audio = with_audio.as_ref().and_then(|path| path.as_deref()).map(commands::qgm::read_audio).transpose()?;
+            let options = commands::qgm::ListOptions{ unknown: *verbose, speakers: speakers.as_ref(), speaker,
+                audio: with_audio.is_some(), audio_names: audio.as_ref().map(|(names, _)| names),
+                audio_durations: audio.as_ref().map(|(_, durations)| durations) };
+            commands::qgm::list(&qgm, &options, &mut std::io::stdout())?;
+        }
+        Some(CliCommands::ExportCsv { out_csv }) => {
+            commands::qgm::export_csv(&qgm, &mut commands::binary_output(out_csv)?)?;
+        }
+        Some(CliCommands::Graph { out, other_qgm }) => {
+            let mut qgms = vec![ qgm ];
+            for path in other_qgm {
+                qgms.push(commands::decode_input(path, ResourceKind::Qgm, qfg5qgm::QgmDecoder::new)?);
+            }
+            let qgms: Vec<_> = qgms.iter().collect();
+            commands::qgm::graph(&qgms, &mut commands::binary_output(out)?)?;
+        }
+        Some(CliCommands::ExportPo { out_po }) => {
+            commands::qgm::export_po(&qgm, &mut commands::binary_output(out_po)?)?;
+        }
+        Some(CliCommands::ImportPo { po, out_qgm }) => {
+            for warning in commands::qgm::import_po(&data, po, out_qgm)? {
+                eprintln!("warning: {}", warning);
+            }
+        }
+        Some(CliCommands::Diff { other_qgm, verbose }) => {
+            let other = commands::decode_input(other_qgm, ResourceKind::Qgm, qfg5qgm::QgmDecoder::new)?;
+            commands::qgm::diff(&qgm, &other, *verbose).write(&mut std::io::stdout())?;
+        }
+        Some(CliCommands::Patch { label, text, out }) => {
+            commands::qgm::patch(&data, label, text, out)?;
         }
-        None => { },
+        Some(CliCommands::Validate { .. }) | None => { },
     }
     Ok(())
 }
@@ -6,16 +6,18 @@
  */
 use anyhow::Result;
 use std::env;
+use qfg5reenigne::commands;
 use qfg5reenigne::qfg5resource::qfg5rgd;
 
+// Superseded by `qfg5 rgd <file> dump`
 fn main() -> Result<()> {
-    let args: Vec<_> = env::args().into_iter().collect();
+    let args: Vec<_> = env::args().collect();
     if args.len() != 2 {
         println!("usage: {} file.rgd", args[0]);
         return Ok(())
     }
 
-    let rgd_data = std::fs::read(&args[1])?;
-    let _rgd = qfg5rgd::RgdDecoder::new(&rgd_data)?;
-    Ok(())
+    let data = std::fs::read(&args[1])?;
+    let rgd = qfg5rgd::RgdDecoder::new(&data)?;
+    commands::rgd::dump(&rgd, &mut std::io::stdout())
 }
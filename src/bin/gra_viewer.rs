@@ -0,0 +1,407 @@
+/*-
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * Copyright (c) 2024 Rink Springer <rink@rink.nu>
+ * For conditions of distribution and use, see LICENSE file
+ */
+use qfg5reenigne::{
+    threed::{quad, texture},
+    qfg5resource::{qfg5gra, qfg5nod},
+};
+
+use anyhow::Result;
+use clap::Parser;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use winit::{
+    event::*,
+    event_loop::EventLoop,
+    keyboard::{KeyCode, PhysicalKey},
+    window::{Window, WindowBuilder},
+};
+use glyphon::{
+    Attrs, Buffer, Cache, Color, Family, FontSystem, Metrics, Resolution, Shaping, SwashCache,
+    TextArea, TextAtlas, TextBounds, TextRenderer, Viewport,
+};
+
+const MAX_ZOOM: u32 = 16;
+
+/// Displays the sprites of a Quest for Glory 5 *.GRA file
+#[derive(Parser)]
+struct Cli {
+    /// Input GRA file
+    in_gra: PathBuf,
+    /// Room palette (*.NOD) to use instead of the palette of the GRA file
+    #[arg(long)]
+    nod: Option<PathBuf>,
+}
+
+struct State<'a> {
+    surface: wgpu::Surface<'a>,
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    config: wgpu::SurfaceConfiguration,
+    size: winit::dpi::PhysicalSize<u32>,
+    quad: quad::QuadRenderer,
+    gra: qfg5gra::GraDecoder<'static>,
+    palette: [ qfg5nod::PaletteEntry; 256 ],
+    collection: usize,
+    frame: usize,
+    zoom: u32,
+    playing: bool,
+    frame_shown_at: Instant,
+    sprite_bind_group: Option<wgpu::BindGroup>,
+    // Font
+    font_system: FontSystem,
+    swash_cache: SwashCache,
+    viewport: glyphon::Viewport,
+    atlas: glyphon::TextAtlas,
+    text_renderer: glyphon::TextRenderer,
+    text_buffer: glyphon::Buffer,
+    // The window must be declared after the surface so
+    // it gets dropped after it as the surface contains
+    // unsafe references to the window's resources.
+    window: &'a Window,
+}
+
+impl<'a> State<'a> {
+    async fn new(window: &'a Window, gra: qfg5gra::GraDecoder<'static>, palette: [ qfg5nod::PaletteEntry; 256 ]) -> State<'a> {
+        let size = window.inner_size();
+
+        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+            backends: wgpu::Backends::PRIMARY,
+            ..Default::default()
+        });
+        let surface = instance.create_surface(window).unwrap();
+        let adapter = instance.request_adapter(
+            &wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::default(),
+                compatible_surface: Some(&surface),
+                force_fallback_adapter: false,
+            },
+        ).await.unwrap();
+        let (device, queue) = adapter.request_device(
+            &wgpu::DeviceDescriptor {
+                required_features: wgpu::Features::empty(),
+                required_limits: wgpu::Limits::default(),
+                label: None,
+                memory_hints: Default::default(),
+            },
+            None, // Trace path
+        ).await.unwrap();
+
+        let surface_caps = surface.get_capabilities(&adapter);
+        let surface_format = surface_caps.formats.iter()
+            .find(|f| f.is_srgb())
+            .copied()
+            .unwrap_or(surface_caps.formats[0]);
+        let config = wgpu::SurfaceConfiguration {
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+            format: surface_format,
+            width: size.width,
+            height: size.height,
+            present_mode: surface_caps.present_modes[0],
+            alpha_mode: surface_caps.alpha_modes[0],
+            view_formats: vec![],
+            desired_maximum_frame_latency: 2,
+        };
+
+        let mut quad = quad::QuadRenderer::new(&device, config.format);
+        quad.set_checkerboard(&queue, true);
+
+        let mut font_system = FontSystem::new();
+        let swash_cache = SwashCache::new();
+        let cache = Cache::new(&device);
+        let viewport = Viewport::new(&device, &cache);
+        let mut atlas = TextAtlas::new(&device, &queue, &cache, config.format);
+        let text_renderer =
+            TextRenderer::new(&mut atlas, &device, wgpu::MultisampleState::default(), None);
+        let mut text_buffer = Buffer::new(&mut font_system, Metrics::new(20.0, 26.0));
+        text_buffer.set_size(&mut font_system, Some(size.width as f32), Some(size.height as f32));
+
+        let mut result = Self {
+            window,
+            surface,
+            device,
+            queue,
+            config,
+            size,
+            quad,
+            gra,
+            palette,
+            collection: 0,
+            frame: 0,
+            zoom: 2,
+            playing: false,
+            frame_shown_at: Instant::now(),
+            sprite_bind_group: None,
+            font_system,
+            swash_cache,
+            viewport,
+            atlas,
+            text_renderer,
+            text_buffer,
+        };
+        result.update_sprite();
+        result
+    }
+
+    pub fn window(&self) -> &Window {
+        self.window
+    }
+
+    pub fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
+        if new_size.width > 0 && new_size.height > 0 {
+            self.size = new_size;
+            self.config.width = new_size.width;
+            self.config.height = new_size.height;
+            self.surface.configure(&self.device, &self.config);
+            self.text_buffer.set_size(&mut self.font_system, Some(new_size.width as f32), Some(new_size.height as f32));
+            self.update_rect();
+        }
+    }
+
+    fn num_frames(&self) -> usize {
+        self.gra.sprite_collections.get(self.collection).map_or(0, |c| c.sprites.len())
+    }
+
+    fn process_input(&mut self, event: &WindowEvent) -> bool {
+        let WindowEvent::KeyboardInput {
+            event: KeyEvent { state: ElementState::Pressed, physical_key: PhysicalKey::Code(keycode), .. },
+            ..
+        } = event else {
+            return false;
+        };
+        let num_collections = self.gra.sprite_collections.len();
+        match keycode {
+            KeyCode::ArrowRight | KeyCode::ArrowLeft => {
+                let num_frames = self.num_frames();
+                if num_frames > 0 {
+                    self.frame = if *keycode == KeyCode::ArrowRight {
+                        (self.frame + 1) % num_frames
+                    } else {
+                        (self.frame + num_frames - 1) % num_frames
+                    };
+                }
+                self.playing = false;
+            },
+            KeyCode::ArrowDown | KeyCode::ArrowUp => {
+                if num_collections > 0 {
+                    self.collection = if *keycode == KeyCode::ArrowDown {
+                        (self.collection + 1) % num_collections
+                    } else {
+                        (self.collection + num_collections - 1) % num_collections
+                    };
+                }
+                self.frame = 0;
+            },
+            KeyCode::Equal | KeyCode::NumpadAdd => {
+                self.zoom = (self.zoom + 1).min(MAX_ZOOM);
+            },
+            KeyCode::Minus | KeyCode::NumpadSubtract => {
+                self.zoom = (self.zoom - 1).max(1);
+            },
+            KeyCode::Space => {
+                self.playing = !self.playing;
+            },
+            _ => return false,
+        }
+        self.update_sprite();
+        true
+    }
+
+    fn input(&mut self, event: &WindowEvent) -> bool {
+        self.process_input(event)
+    }
+
+    fn update(&mut self) {
+        if !self.playing { return; }
+        let Some(collection) = self.gra.sprite_collections.get(self.collection) else { return; };
+        // frame_delay is assumed to be in milliseconds
+        let delay = Duration::from_millis(collection.frame_delay.max(1) as u64);
+        if self.frame_shown_at.elapsed() >= delay && !collection.sprites.is_empty() {
+            self.frame = (self.frame + 1) % collection.sprites.len();
+            self.update_sprite();
+        }
+    }
+
+    /// Uploads the current frame and refreshes the overlay
+    fn update_sprite(&mut self) {
+        self.frame_shown_at = Instant::now();
+        self.sprite_bind_group = None;
+        let text = match self.gra.sprite_collections.get(self.collection) {
+            Some(collection) => {
+                if let Some(sprite) = collection.sprites.get(self.frame) {
+                    // Palette index 0 is transparent
+                    let label = format!("sprite {}/{}", self.collection, self.frame);
+                    let texture = texture::Texture::from_indexed(&self.device, &self.queue, &sprite.pixels, &self.palette,
+                        collection.width, collection.height, Some(0), &label).unwrap();
+                    self.sprite_bind_group = Some(self.quad.create_bind_group(&self.device, &texture.view));
+                }
+                format!("collection {}/{}: {}x{} at ({}, {}), frame delay {}\nframe {}/{}, zoom {}x{}",
+                    self.collection, self.gra.sprite_collections.len(),
+                    collection.width, collection.height,
+                    collection.x_position, collection.y_position,
+                    collection.frame_delay,
+                    self.frame, collection.sprites.len(),
+                    self.zoom,
+                    if self.playing { ", playing" } else { "" })
+            },
+            None => "no sprite collections".to_string(),
+        };
+        self.text_buffer.set_text(&mut self.font_system, &text, Attrs::new().family(Family::SansSerif), Shaping::Advanced);
+        self.update_rect();
+    }
+
+    /// Centers the current sprite in the window at the current zoom level
+    fn update_rect(&mut self) {
+        let Some(collection) = self.gra.sprite_collections.get(self.collection) else { return; };
+        let width = (collection.width * self.zoom) as f32;
+        let height = (collection.height * self.zoom) as f32;
+        // Keep the sprite on whole pixels so that zoomed pixels stay square
+        let x = ((self.config.width as f32 - width) / 2.0).floor();
+        let y = ((self.config.height as f32 - height) / 2.0).floor();
+        self.quad.set_rect(&self.queue, (self.config.width, self.config.height), x, y, width, height);
+    }
+
+    fn render(&mut self) -> Result<(), wgpu::SurfaceError> {
+        self.viewport.update(
+            &self.queue,
+            Resolution {
+                width: self.config.width,
+                height: self.config.height,
+            },
+        );
+        self.text_renderer
+            .prepare(
+                &self.device,
+                &self.queue,
+                &mut self.font_system,
+                &mut self.atlas,
+                &self.viewport,
+                [TextArea {
+                    buffer: &self.text_buffer,
+                    left: 10.0,
+                    top: 10.0,
+                    scale: 1.0,
+                    bounds: TextBounds {
+                        left: 0,
+                        top: 0,
+                        right: self.config.width as i32,
+                        bottom: self.config.height as i32,
+                    },
+                    default_color: Color::rgb(255, 255, 255),
+                    custom_glyphs: &[],
+                }],
+                &mut self.swash_cache,
+            )
+            .unwrap();
+
+        let output = self.surface.get_current_texture()?;
+        let view = output.texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Render Encoder"),
+        });
+
+        {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Render Pass"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: &view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color {
+                            r: 0.1,
+                            g: 0.2,
+                            b: 0.3,
+                            a: 1.0,
+                        }),
+                        store: wgpu::StoreOp::Store,
+                    },
+                })],
+                depth_stencil_attachment: None,
+                occlusion_query_set: None,
+                timestamp_writes: None,
+            });
+
+            if let Some(bind_group) = &self.sprite_bind_group {
+                self.quad.draw(&mut render_pass, bind_group);
+            }
+            self.text_renderer.render(&self.atlas, &self.viewport, &mut render_pass).unwrap();
+        }
+
+        self.queue.submit(std::iter::once(encoder.finish()));
+        output.present();
+        self.atlas.trim();
+        Ok(())
+    }
+}
+
+async fn run(gra: qfg5gra::GraDecoder<'static>, palette: [ qfg5nod::PaletteEntry; 256 ]) -> Result<()> {
+    env_logger::init();
+    let event_loop = EventLoop::new()?;
+    let window = WindowBuilder::new().with_title("GRA viewer").build(&event_loop)?;
+
+    let mut state = State::new(&window, gra, palette).await;
+
+    event_loop.run(move |event, control_flow| {
+        match event {
+            Event::WindowEvent {
+                ref event,
+                window_id,
+            } if window_id == state.window().id() && !state.input(event) => {
+                match event {
+                    WindowEvent::CloseRequested
+                    | WindowEvent::KeyboardInput {
+                        event:
+                            KeyEvent {
+                                state: ElementState::Pressed,
+                                physical_key: PhysicalKey::Code(KeyCode::Escape),
+                                ..
+                            },
+                        ..
+                    } => control_flow.exit(),
+                    WindowEvent::Resized(physical_size) => {
+                        state.resize(*physical_size);
+                    },
+                    WindowEvent::RedrawRequested => {
+                        state.window().request_redraw();
+
+                        state.update();
+                        match state.render() {
+                            Ok(_) => {}
+                            Err(
+                                wgpu::SurfaceError::Lost | wgpu::SurfaceError::Outdated,
+                            ) => state.resize(state.size),
+                            Err(wgpu::SurfaceError::OutOfMemory) => {
+                                log::error!("OutOfMemory");
+                                control_flow.exit();
+                            }
+                            Err(wgpu::SurfaceError::Timeout) => {
+                                log::warn!("Surface timeout")
+                            }
+                        }
+                    },
+                    _ => {}
+                }
+            },
+            _ => {}
+        }
+    })?;
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let args = Cli::parse();
+    let gra_data = std::fs::read(&args.in_gra)?;
+    let gra = qfg5gra::GraDecoder::new(&gra_data)?;
+    let palette = match &args.nod {
+        Some(nod) => {
+            let nod_data = std::fs::read(nod)?;
+            *qfg5nod::NodDecoder::new(&nod_data)?.get_palette()
+        },
+        None => gra.palette,
+    };
+    pollster::block_on(run(gra, palette))
+}
@@ -0,0 +1,34 @@
+/*-
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * Copyright (c) 2024 Rink Springer <rink@rink.nu>
+ * For conditions of distribution and use, see LICENSE file
+ */
+use anyhow::Result;
+use std::env;
+use std::path::Path;
+use qfg5reenigne::export::gltf;
+use qfg5reenigne::qfg5resource::{qfg5anm, qfg5mdl};
+
+fn main() -> Result<()> {
+    let args: Vec<_> = env::args().collect();
+    let (mdl_path, anm_path, out) = match &args[1..] {
+        [ mdl, out ] => (mdl, None, out),
+        [ mdl, anm, out ] => (mdl, Some(anm), out),
+        _ => {
+            println!("usage: {} file.mdl [file.anm] out.gltf", args[0]);
+            return Ok(())
+        }
+    };
+
+    let data = std::fs::read(mdl_path)?;
+    let mdl = qfg5mdl::Qfg5Model::new(&data)?;
+    let anm = match anm_path {
+        Some(path) => Some(qfg5anm::AnmDecoder::new(&std::fs::read(path)?)?),
+        None => None,
+    };
+    for path in gltf::export(&mdl, anm.as_ref(), Path::new(out))? {
+        println!("wrote {}", path.display());
+    }
+    Ok(())
+}
@@ -7,32 +7,42 @@
 use qfg5reenigne::{
     threed::{
         model::{
+            ColorVertex,
             Vertex,
             ModelVertex,
         },
         model,
         camera,
+        light,
+        lines,
+        pose::Pose,
+        profiling,
         resources,
+        screenshot,
         texture,
     },
     qfg5resource::{
-        qfg5anm, qfg5mdl,
-    }
+        names, qfg5anm, qfg5mdl,
+        manager::ResourceManager,
+    },
+    export::obj,
 };
 
 use anyhow::Result;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use web_time::Instant;
 
 use winit::{
     event::*,
     event_loop::EventLoop,
-    keyboard::{KeyCode, PhysicalKey},
+    keyboard::{KeyCode, ModifiersState, PhysicalKey},
     window::{Window, WindowBuilder},
 };
 use glyphon::{
     Attrs, Buffer, Cache, Color, Family, FontSystem, Metrics, Resolution, Shaping, SwashCache,
     TextArea, TextAtlas, TextBounds, TextRenderer, Viewport,
 };
-use cgmath::prelude::*;
 use wgpu::util::DeviceExt;
 
 
@@ -40,19 +50,22 @@ struct Instance {
     // position: cgmath::Vector3<f32>,
     transform: cgmath::Matrix4<f32>,
     // rotation: cgmath::Quaternion<f32>,
+    tint: [f32; 4],
 }
 
 #[repr(C)]
 #[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 struct InstanceRaw {
     model: [[f32; 4]; 4],
+    tint: [f32; 4],
 }
 
 impl Instance {
     fn to_raw(&self) -> InstanceRaw {
         InstanceRaw {
             //model: (cgmath::Matrix4::from_translation(self.position) * cgmath::Matrix4::from(self.rotation)).into(),
-            model: self.transform.into()
+            model: self.transform.into(),
+            tint: self.tint,
         }
     }
 }
@@ -91,6 +104,11 @@ impl InstanceRaw {
                     shader_location: 8,
                     format: wgpu::VertexFormat::Float32x4,
                 },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 16]>() as wgpu::BufferAddress,
+                    shader_location: 9,
+                    format: wgpu::VertexFormat::Float32x4,
+                },
             ],
         }
     }
@@ -99,6 +117,279 @@ impl InstanceRaw {
 enum RenderWhat {
     MeshIndex(usize),
     AnmIndex(usize),
+    /// All submeshes laid out in a grid, with the given one highlighted
+    Exploded(usize),
+}
+
+/// Colors to tell submeshes apart in the exploded view, both in the overlay and the model
+const HIGHLIGHT_COLORS: [ [u8; 3]; 8 ] = [
+    [ 255, 80, 80 ], [ 80, 255, 80 ], [ 80, 160, 255 ], [ 255, 255, 80 ],
+    [ 255, 80, 255 ], [ 80, 255, 255 ], [ 255, 160, 40 ], [ 180, 120, 255 ],
+];
+const HIGHLIGHT_TINT: f32 = 0.6;
+const KEY_TINT: f32 = 0.2;
+
+fn highlight_color(submesh: usize) -> [u8; 3] {
+    HIGHLIGHT_COLORS[submesh % HIGHLIGHT_COLORS.len()]
+}
+
+/// What the surface of the model shows; the index selects the pipelines in State::render_pipelines
+#[derive(Clone, Copy, PartialEq)]
+enum Shading {
+    Texture,
+    /// The lighting data as vertex colors
+    Lighting,
+    /// The texture multiplied by the lighting colors
+    Combined,
+}
+
+impl Shading {
+    const ALL: [Shading; 3] = [ Shading::Texture, Shading::Lighting, Shading::Combined ];
+
+    fn next(self) -> Self {
+        match self {
+            Shading::Texture => Shading::Lighting,
+            Shading::Lighting => Shading::Combined,
+            Shading::Combined => Shading::Texture,
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Shading::Texture => "texture",
+            Shading::Lighting => "lighting",
+            Shading::Combined => "texture and lighting",
+        }
+    }
+
+    fn entry_points(self) -> (&'static str, &'static str) {
+        match self {
+            Shading::Texture => ("vs_main", "fs_main"),
+            Shading::Lighting => ("vs_color", "fs_color"),
+            Shading::Combined => ("vs_color", "fs_combined"),
+        }
+    }
+}
+
+/// Which faces are culled; the index selects the pipeline in State::render_pipelines
+#[derive(Clone, Copy, PartialEq)]
+enum CullMode {
+    Back,
+    Front,
+    None,
+}
+
+impl CullMode {
+    const ALL: [CullMode; 3] = [ CullMode::Back, CullMode::Front, CullMode::None ];
+
+    fn next(self) -> Self {
+        match self {
+            CullMode::Back => CullMode::Front,
+            CullMode::Front => CullMode::None,
+            CullMode::None => CullMode::Back,
+        }
+    }
+
+    fn face(self) -> Option<wgpu::Face> {
+        match self {
+            CullMode::Back => Some(wgpu::Face::Back),
+            CullMode::Front => Some(wgpu::Face::Front),
+            CullMode::None => None,
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            CullMode::Back => "back",
+            CullMode::Front => "front",
+            CullMode::None => "none",
+        }
+    }
+}
+
+/// Debug lines drawn at the transforms of the animation tracks
+#[derive(Clone, Copy, PartialEq)]
+enum Gizmos {
+    Off,
+    Axes,
+    /// Axes, plus lines connecting the origins of consecutive tracks
+    AxesAndLinks,
+}
+
+impl Gizmos {
+    fn next(self) -> Self {
+        match self {
+            Gizmos::Off => Gizmos::Axes,
+            Gizmos::Axes => Gizmos::AxesAndLinks,
+            Gizmos::AxesAndLinks => Gizmos::Off,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Gizmos::Off => "off",
+            Gizmos::Axes => "axes",
+            Gizmos::AxesAndLinks => "axes and links",
+        }
+    }
+}
+
+/// Projection to start the viewer with
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum ProjectionKind {
+    Perspective,
+    /// Keeps parallel lines parallel, which makes judging proportions easier
+    Orthographic,
+}
+
+/// What to record in the --record mode
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum RecordMode {
+    /// Rotate the camera around the model once
+    Turntable,
+    /// Play the animation once
+    Animation,
+}
+
+struct RecordOptions {
+    mode: RecordMode,
+    frames: usize,
+    out: PathBuf,
+}
+
+/// Renders a fixed number of frames to numbered PNG files, after which the viewer exits. Every
+/// frame is derived from its number rather than the clock, so that recordings are reproducible.
+struct Recording {
+    mode: RecordMode,
+    frames: usize,
+    out: PathBuf,
+    frame: usize,
+    /// Where the camera was when recording started
+    start_eye: cgmath::Point3<f32>,
+}
+
+/// The lighting data is always uploaded, so that the shading can be switched at any time
+const MODEL_OPTIONS: resources::Qfg5ModelOptions = resources::Qfg5ModelOptions{ lighting_colors: true, mipmaps: true, smooth_normals: false, unshared_vertices: false };
+
+/// Memory for the files read by the viewer; enough for the model and animation several times
+#[cfg(not(target_arch = "wasm32"))]
+const CACHE_BYTES: usize = 64 << 20;
+
+/// How long errors stay in the overlay
+const FLASH_DURATION: Duration = Duration::from_secs(2);
+/// How often the frame timing in the overlay changes, so that it can be read
+const TIMING_REFRESH: Duration = Duration::from_millis(250);
+
+/// Polls files for changes by their modification time and size. A change is only reported once
+/// the files have been left alone for a while, so that files still being written are not picked up.
+#[cfg(not(target_arch = "wasm32"))]
+struct FileWatcher {
+    paths: Vec<PathBuf>,
+    stamps: Vec<Option<(std::time::SystemTime, u64)>>,
+    changed_at: Option<Instant>,
+    polled_at: Instant,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl FileWatcher {
+    const POLL_INTERVAL: Duration = Duration::from_millis(250);
+    const DEBOUNCE: Duration = Duration::from_millis(500);
+
+    fn new(paths: Vec<PathBuf>) -> Self {
+        let stamps = paths.iter().map(|path| Self::stamp(path)).collect();
+        FileWatcher{ paths, stamps, changed_at: None, polled_at: Instant::now() }
+    }
+
+    fn stamp(path: &Path) -> Option<(std::time::SystemTime, u64)> {
+        let metadata = std::fs::metadata(path).ok()?;
+        Some((metadata.modified().ok()?, metadata.len()))
+    }
+
+    /// Returns true once after the files have changed and then stayed the same for DEBOUNCE
+    fn poll(&mut self) -> bool {
+        if self.polled_at.elapsed() < Self::POLL_INTERVAL { return false; }
+        self.polled_at = Instant::now();
+
+        let stamps: Vec<_> = self.paths.iter().map(|path| Self::stamp(path)).collect();
+        if stamps != self.stamps {
+            self.stamps = stamps;
+            self.changed_at = Some(Instant::now());
+            return false;
+        }
+        match self.changed_at {
+            Some(changed_at) if changed_at.elapsed() >= Self::DEBOUNCE => {
+                self.changed_at = None;
+                true
+            },
+            _ => false,
+        }
+    }
+}
+
+/// Size of the gizmo axes relative to the largest dimension of the model
+const GIZMO_SCALE: f32 = 0.1;
+const GIZMO_LINK_COLOR: [f32; 3] = [ 1.0, 1.0, 1.0 ];
+
+fn create_render_pipeline(device: &wgpu::Device, layout: &wgpu::PipelineLayout, shader: &wgpu::ShaderModule, format: wgpu::TextureFormat, shading: Shading, cull_mode: CullMode) -> wgpu::RenderPipeline {
+    let (vs_entry_point, fs_entry_point) = shading.entry_points();
+    let buffers = match shading {
+        Shading::Texture => vec![ Vertex::desc(), InstanceRaw::desc() ],
+        Shading::Lighting | Shading::Combined => vec![ Vertex::desc(), InstanceRaw::desc(), ColorVertex::desc() ],
+    };
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some(&format!("Render Pipeline ({}, cull {})", shading.name(), cull_mode.name())),
+        layout: Some(layout),
+        vertex: wgpu::VertexState {
+            module: shader,
+            entry_point: Some(vs_entry_point),
+            buffers: &buffers,
+            compilation_options: wgpu::PipelineCompilationOptions::default(),
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: shader,
+            entry_point: Some(fs_entry_point),
+            targets: &[Some(wgpu::ColorTargetState {
+                format,
+                blend: Some(wgpu::BlendState::REPLACE),
+                write_mask: wgpu::ColorWrites::ALL,
+            })],
+            compilation_options: wgpu::PipelineCompilationOptions::default(),
+        }),
+        primitive: wgpu::PrimitiveState {
+            topology: wgpu::PrimitiveTopology::TriangleList,
+            strip_index_format: None,
+            front_face: wgpu::FrontFace::Ccw,
+            cull_mode: cull_mode.face(),
+            // Setting this to anything other than Fill requires Features::NON_FILL_POLYGON_MODE
+            polygon_mode: wgpu::PolygonMode::Fill,
+            // Requires Features::DEPTH_CLIP_CONTROL
+            unclipped_depth: false,
+            // Requires Features::CONSERVATIVE_RASTERIZATION
+            conservative: false,
+        },
+        depth_stencil: Some(wgpu::DepthStencilState {
+            format: texture::Texture::DEPTH_FORMAT,
+            depth_write_enabled: true,
+            depth_compare: wgpu::CompareFunction::Less,
+            stencil: wgpu::StencilState::default(),
+            bias: wgpu::DepthBiasState::default(),
+        }),
+        multisample: wgpu::MultisampleState {
+            count: 1,
+            mask: !0,
+            alpha_to_coverage_enabled: false,
+        },
+        multiview: None,
+        cache: None,
+    })
 }
 
 struct State<'a> {
@@ -107,19 +398,58 @@ struct State<'a> {
     queue: wgpu::Queue,
     config: wgpu::SurfaceConfiguration,
     size: winit::dpi::PhysicalSize<u32>,
-    render_pipeline: wgpu::RenderPipeline,
+    render_pipelines: [ [ wgpu::RenderPipeline; 3 ]; 3 ],
+    shading: Shading,
+    /// Whether the textures have mipmaps; M rebuilds them with or without
+    mipmaps: bool,
+    /// Whether the normals are averaged per vertex; N rebuilds the model with or without
+    smooth_normals: bool,
+    texture_bind_group_layout: wgpu::BindGroupLayout,
+    cull_mode: CullMode,
+    line_renderer: lines::LineRenderer,
+    gizmos: Gizmos,
     depth_texture: texture::Texture,
     camera: camera::Camera,
     camera_controller: camera::CameraController,
     camera_uniform: camera::CameraUniform,
     camera_buffer: wgpu::Buffer,
     camera_bind_group: wgpu::BindGroup,
+    light: light::Light,
+    light_binding: light::LightBinding,
     instances: Vec<Instance>,
     instance_buffer: wgpu::Buffer,
-    mdl: qfg5mdl::Qfg5Model,
+    mdl_path: PathBuf,
+    anm_path: PathBuf,
+    mdl: qfg5mdl::Qfg5Model<'static>,
     anm: qfg5anm::AnmDecoder,
     obj_model: model::Model,
     render_what: RenderWhat,
+    pose: Pose,
+    modifiers: ModifiersState,
+    status: String,
+    playing: bool,
+    frame_shown_at: Instant,
+    /// Sample the animation in between frames rather than showing exact frames
+    interpolated: bool,
+    /// Position in the animation in seconds, used when interpolating
+    anm_time: f32,
+    last_update: Instant,
+    /// Digits typed so far of a frame number to jump to
+    pending_frame: String,
+    flash: Option<(String, Instant)>,
+    #[cfg(not(target_arch = "wasm32"))]
+    watcher: FileWatcher,
+    /// Reads the model and animation, see read_file()
+    #[cfg(not(target_arch = "wasm32"))]
+    resource_manager: ResourceManager,
+    recording: Option<Recording>,
+    frame_times: profiling::FrameTimes,
+    /// None if the adapter has no timestamp queries
+    gpu_timer: Option<profiling::GpuTimer>,
+    gpu_time: Option<Duration>,
+    /// Whether the overlay shows the frame timing, and when it last changed; toggled with F1
+    show_timing: bool,
+    timing_shown_at: Instant,
     // Font
     font_system: FontSystem,
     swash_cache: SwashCache,
@@ -160,7 +490,7 @@ impl<'a> State<'a> {
 
         let (device, queue) = adapter.request_device(
             &wgpu::DeviceDescriptor {
-                required_features: wgpu::Features::empty(),
+                required_features: adapter.features() & profiling::GpuTimer::FEATURES,
                 // WebGL doesn't support all of wgpu's features, so if
                 // we're building for the web, we'll have to disable some.
                 required_limits: if cfg!(target_arch = "wasm32") {
@@ -198,8 +528,8 @@ impl<'a> State<'a> {
             desired_maximum_frame_latency: 2,
         };
 
-        let camera = camera::Camera {
-            // position the camera 1 unit up and 2 units back
+        let mut camera = camera::Camera {
+            // The direction to look from; frame() moves it to fit the model
             // +z is out of the screen
             eye: (4.0, 12.0, 42.0).into(),
             // have it look at the origin
@@ -207,7 +537,7 @@ impl<'a> State<'a> {
             // which way is "up"
             up: cgmath::Vector3::unit_y(),
             aspect: config.width as f32 / config.height as f32,
-            fovy: 45.0,
+            projection: camera::Projection::Perspective{ fovy: 45.0 },
             znear: 0.1,
             zfar: 100.0,
         };
@@ -279,62 +609,23 @@ impl<'a> State<'a> {
     
         let depth_texture = texture::Texture::create_depth_texture(&device, &config, "depth_texture");
             
+        let light = light::Light::default();
+        let light_bind_group_layout = light::create_bind_group_layout(&device);
+        let light_binding = light::LightBinding::new(&device, &light_bind_group_layout, &light);
+
         let render_pipeline_layout =
             device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
                 label: Some("Render Pipeline Layout"),
-                bind_group_layouts: &[&texture_bind_group_layout, &camera_bind_group_layout],
+                bind_group_layouts: &[&texture_bind_group_layout, &camera_bind_group_layout, &light_bind_group_layout],
                 push_constant_ranges: &[],
             });
 
-        let render_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-            label: Some("Render Pipeline"),
-            layout: Some(&render_pipeline_layout),
-            vertex: wgpu::VertexState {
-                module: &shader,
-                entry_point: Some("vs_main"),
-                buffers: &[
-                    Vertex::desc(),
-                    InstanceRaw::desc(),
-                ],
-                compilation_options: wgpu::PipelineCompilationOptions::default(),
-            },
-            fragment: Some(wgpu::FragmentState {
-                module: &shader,
-                entry_point: Some("fs_main"),
-                targets: &[Some(wgpu::ColorTargetState {
-                    format: config.format,
-                    blend: Some(wgpu::BlendState::REPLACE),
-                    write_mask: wgpu::ColorWrites::ALL,
-                })],
-                compilation_options: wgpu::PipelineCompilationOptions::default(),
-            }),
-            primitive: wgpu::PrimitiveState {
-                topology: wgpu::PrimitiveTopology::TriangleList,
-                strip_index_format: None,
-                front_face: wgpu::FrontFace::Ccw,
-                cull_mode: Some(wgpu::Face::Back),
-                // Setting this to anything other than Fill requires Features::NON_FILL_POLYGON_MODE
-                polygon_mode: wgpu::PolygonMode::Fill,
-                // Requires Features::DEPTH_CLIP_CONTROL
-                unclipped_depth: false,
-                // Requires Features::CONSERVATIVE_RASTERIZATION
-                conservative: false,
-            },
-            depth_stencil: Some(wgpu::DepthStencilState {
-                format: texture::Texture::DEPTH_FORMAT,
-                depth_write_enabled: true,
-                depth_compare: wgpu::CompareFunction::Less,
-                stencil: wgpu::StencilState::default(),
-                bias: wgpu::DepthBiasState::default(),
-            }),
-            multisample: wgpu::MultisampleState {
-                count: 1,
-                mask: !0,
-                alpha_to_coverage_enabled: false,
-            },
-            multiview: None,
-            cache: None,
-        });
+        // Switching the shading or cull mode needs a different pipeline, so keep one for every combination
+        let render_pipelines = Shading::ALL.map(|shading| CullMode::ALL.map(|cull_mode| {
+            create_render_pipeline(&device, &render_pipeline_layout, &shader, config.format, shading, cull_mode)
+        }));
+
+        let line_renderer = lines::LineRenderer::new(&device, config.format, &camera_bind_group_layout);
 
         // Start with empty instances - will be updated by update_render()
         let instances = Vec::new();
@@ -346,18 +637,33 @@ impl<'a> State<'a> {
                 usage: wgpu::BufferUsages::VERTEX,
             }
         );
+        #[cfg(not(target_arch = "wasm32"))]
+        let resource_manager = file_manager(Path::new(mdl_fname), Path::new(anm_fname));
+        #[cfg(not(target_arch = "wasm32"))]
+        let mdl = read_file(&resource_manager, MDL_SOURCE, Path::new(mdl_fname)).unwrap();
+        #[cfg(target_arch = "wasm32")]
         let mdl = resources::load_binary(mdl_fname).await.unwrap();
         let mdl = qfg5mdl::Qfg5Model::new(&mdl).unwrap();
 
         let obj_model =
-            resources::load_qfg5model(&mdl, &device, &queue, &texture_bind_group_layout)
+            resources::load_qfg5model_with_options(&mdl, &device, &queue, &texture_bind_group_layout, &MODEL_OPTIONS)
             .await;
         if let Err(ref e) = obj_model {
             println!("ARGH {:?}", e);
         }
         let obj_model = obj_model.unwrap();
         let render_what = RenderWhat::MeshIndex(0);
+        // Fit what is shown first in the view, however large or small the model is
+        if let Some(bounds) = Pose::submesh(0).bounds(&mdl) {
+            let center = bounds.center();
+            camera.frame((center.x, center.y, center.z).into(), bounds.radius());
+            camera_uniform.update_view_proj(&camera);
+            queue.write_buffer(&camera_buffer, 0, bytemuck::cast_slice(&[camera_uniform]));
+        }
 
+        #[cfg(not(target_arch = "wasm32"))]
+        let anm = read_file(&resource_manager, ANM_SOURCE, Path::new(anm_fname)).unwrap();
+        #[cfg(target_arch = "wasm32")]
         let anm = resources::load_binary(anm_fname).await.unwrap();
         let anm = qfg5anm::AnmDecoder::new(&anm).unwrap();
 
@@ -386,6 +692,8 @@ impl<'a> State<'a> {
         text_buffer.set_text(&mut font_system, "Hello world! 👋\nThis is rendered with 🦅 glyphon 🦁\nThe text below should be partially clipped.\na b c d e f g h i j k l m n o p q r s t u v w x y z", Attrs::new().family(Family::SansSerif), Shaping::Advanced);
         text_buffer.shape_until_scroll(&mut font_system, false);
 
+        let gpu_timer = profiling::GpuTimer::new(&device, &queue);
+
         let mut result = Self {
             window,
             surface,
@@ -393,19 +701,50 @@ impl<'a> State<'a> {
             queue,
             config,
             size,
-            render_pipeline,
+            render_pipelines,
+            shading: Shading::Texture,
+            mipmaps: MODEL_OPTIONS.mipmaps,
+            smooth_normals: MODEL_OPTIONS.smooth_normals,
+            texture_bind_group_layout,
+            cull_mode: CullMode::Back,
+            line_renderer,
+            gizmos: Gizmos::Off,
             depth_texture,
             camera,
             camera_controller,
             camera_uniform,
             camera_buffer,
             camera_bind_group,
+            light,
+            light_binding,
             instances,
             instance_buffer,
+            mdl_path: PathBuf::from(mdl_fname),
+            anm_path: PathBuf::from(anm_fname),
             mdl,
             anm,
             obj_model,
             render_what,
+            pose: Pose{ parts: Vec::new() },
+            modifiers: ModifiersState::empty(),
+            status: String::new(),
+            playing: false,
+            frame_shown_at: Instant::now(),
+            interpolated: false,
+            anm_time: 0.0,
+            last_update: Instant::now(),
+            pending_frame: String::new(),
+            flash: None,
+            #[cfg(not(target_arch = "wasm32"))]
+            watcher: FileWatcher::new(vec![ PathBuf::from(mdl_fname), PathBuf::from(anm_fname) ]),
+            #[cfg(not(target_arch = "wasm32"))]
+            resource_manager,
+            recording: None,
+            frame_times: profiling::FrameTimes::new(profiling::WINDOW_FRAMES),
+            gpu_timer,
+            gpu_time: None,
+            show_timing: false,
+            timing_shown_at: Instant::now(),
             font_system,
             swash_cache,
             viewport,
@@ -428,11 +767,18 @@ impl<'a> State<'a> {
             self.config.height = new_size.height;
             self.depth_texture = texture::Texture::create_depth_texture(&self.device, &self.config, "depth_texture");
             self.surface.configure(&self.device, &self.config);
+            self.camera.resize(new_size.width, new_size.height);
+            self.camera_uniform.update_view_proj(&self.camera);
+            self.queue.write_buffer(&self.camera_buffer, 0, bytemuck::cast_slice(&[self.camera_uniform]));
         }
     }
 
     fn process_input(&mut self, event: &WindowEvent) -> bool {
         match event {
+            WindowEvent::ModifiersChanged(modifiers) => {
+                self.modifiers = modifiers.state();
+                false
+            },
             WindowEvent::KeyboardInput {
                 event:
                     KeyEvent {
@@ -443,6 +789,15 @@ impl<'a> State<'a> {
                 ..
             } => {
                 let is_pressed = *state == ElementState::Pressed;
+                // Alt selects the light, as the arrows and digits have other uses as well
+                if self.modifiers.alt_key() && self.process_light_key(*keycode, is_pressed) {
+                    return true;
+                }
+                if let RenderWhat::AnmIndex(frame) = self.render_what {
+                    if self.process_anm_key(*keycode, is_pressed, frame) {
+                        return true;
+                    }
+                }
                 match keycode {
                     KeyCode::Equal => {
                         if is_pressed {
@@ -454,13 +809,12 @@ impl<'a> State<'a> {
                                     }
                                     self.render_what = RenderWhat::MeshIndex(index);
                                 },
-                                RenderWhat::AnmIndex(mut index) => {
-                                    index += 1;
-                                    if index == self.anm.anims.len() {
-                                        index = 0;
-                                    }
-                                    self.render_what = RenderWhat::AnmIndex(index);
-                                }
+                                RenderWhat::AnmIndex(index) => {
+                                    self.set_frame((index + 1) % self.anm.num_frames().max(1));
+                                },
+                                RenderWhat::Exploded(index) => {
+                                    self.render_what = RenderWhat::Exploded((index + 1) % self.obj_model.meshes.len());
+                                },
                             }
                             self.update_render();
                             true
@@ -479,14 +833,14 @@ impl<'a> State<'a> {
                                     }
                                     self.render_what = RenderWhat::MeshIndex(index);
                                 },
-                                RenderWhat::AnmIndex(mut index) => {
-                                    if index > 0 {
-                                        index -= 1;
-                                    } else {
-                                        index = self.anm.anims.len() - 1;
-                                    }
-                                    self.render_what = RenderWhat::AnmIndex(index);
-                                }
+                                RenderWhat::AnmIndex(index) => {
+                                    let num_frames = self.anm.num_frames().max(1);
+                                    self.set_frame((index + num_frames - 1) % num_frames);
+                                },
+                                RenderWhat::Exploded(index) => {
+                                    let num_meshes = self.obj_model.meshes.len();
+                                    self.render_what = RenderWhat::Exploded((index + num_meshes - 1) % num_meshes);
+                                },
                             }
                             self.update_render();
                             true
@@ -496,9 +850,12 @@ impl<'a> State<'a> {
                     },
                     KeyCode::Space => {
                         if is_pressed {
+                            self.playing = false;
+                            self.pending_frame.clear();
                             self.render_what = match self.render_what {
                                 RenderWhat::MeshIndex(_) => RenderWhat::AnmIndex(0),
-                                RenderWhat::AnmIndex(_) => RenderWhat::MeshIndex(0),
+                                RenderWhat::AnmIndex(_) => RenderWhat::Exploded(0),
+                                RenderWhat::Exploded(_) => RenderWhat::MeshIndex(0),
                             };
                             self.update_render();
                             true
@@ -506,6 +863,74 @@ impl<'a> State<'a> {
                             false
                         }
                     },
+                    KeyCode::KeyG => {
+                        if is_pressed {
+                            self.gizmos = self.gizmos.next();
+                            self.update_render();
+                        }
+                        true
+                    },
+                    KeyCode::KeyL => {
+                        if is_pressed {
+                            self.shading = self.shading.next();
+                            self.update_text();
+                        }
+                        true
+                    },
+                    KeyCode::KeyC => {
+                        if is_pressed {
+                            self.cull_mode = self.cull_mode.next();
+                            self.update_text();
+                        }
+                        true
+                    },
+                    KeyCode::KeyM => {
+                        if is_pressed {
+                            self.mipmaps = !self.mipmaps;
+                            match resources::create_qfg5materials(&self.mdl, &self.device, &self.queue, &self.texture_bind_group_layout, self.mipmaps) {
+                                Ok(materials) => self.obj_model.materials = materials,
+                                Err(e) => self.flash = Some((format!("cannot create textures: {}", e), Instant::now())),
+                            }
+                            self.update_text();
+                        }
+                        true
+                    },
+                    KeyCode::KeyN => {
+                        if is_pressed {
+                            self.smooth_normals = !self.smooth_normals;
+                            let options = resources::Qfg5ModelOptions{ mipmaps: self.mipmaps, smooth_normals: self.smooth_normals, ..MODEL_OPTIONS };
+                            // Loading does not wait for anything, so this does not block
+                            match pollster::block_on(resources::load_qfg5model_with_options(&self.mdl, &self.device, &self.queue, &self.texture_bind_group_layout, &options)) {
+                                Ok(obj_model) => self.obj_model = obj_model,
+                                Err(e) => self.flash = Some((format!("cannot rebuild the model: {}", e), Instant::now())),
+                            }
+                            self.update_text();
+                        }
+                        true
+                    },
+                    // In animation mode, digits select a frame and need Alt to change the ambient light
+                    KeyCode::Digit9 | KeyCode::Digit0 | KeyCode::KeyU => self.process_light_key(*keycode, is_pressed),
+                    KeyCode::KeyO => {
+                        if is_pressed {
+                            self.camera.toggle_projection();
+                            self.update_text();
+                        }
+                        true
+                    },
+                    KeyCode::F1 => {
+                        if is_pressed {
+                            self.show_timing = !self.show_timing;
+                            self.update_text();
+                        }
+                        true
+                    },
+                    // Plain E moves the camera, so exporting needs Ctrl
+                    KeyCode::KeyE if self.modifiers.control_key() => {
+                        if is_pressed {
+                            self.export_pose();
+                        }
+                        true
+                    },
                     _ => false,
                 }
             }
@@ -513,49 +938,322 @@ impl<'a> State<'a> {
         }
     }
 
+    fn process_light_key(&mut self, keycode: KeyCode, is_pressed: bool) -> bool {
+        const ROTATE_STEP: f32 = 15.0;
+        const AMBIENT_STEP: f32 = 0.05;
+        let handled = matches!(keycode, KeyCode::ArrowLeft | KeyCode::ArrowRight | KeyCode::ArrowUp | KeyCode::ArrowDown |
+            KeyCode::Digit9 | KeyCode::Digit0 | KeyCode::KeyU);
+        if !handled || !is_pressed {
+            return handled;
+        }
+        match keycode {
+            KeyCode::ArrowLeft => self.light.rotate(-ROTATE_STEP, 0.0),
+            KeyCode::ArrowRight => self.light.rotate(ROTATE_STEP, 0.0),
+            KeyCode::ArrowUp => self.light.rotate(0.0, ROTATE_STEP),
+            KeyCode::ArrowDown => self.light.rotate(0.0, -ROTATE_STEP),
+            KeyCode::Digit9 => self.light.adjust_ambient(-AMBIENT_STEP),
+            KeyCode::Digit0 => self.light.adjust_ambient(AMBIENT_STEP),
+            KeyCode::KeyU => self.light.enabled = !self.light.enabled,
+            _ => unreachable!(),
+        }
+        self.update_text();
+        true
+    }
+
+    /// Playback and frame selection keys, which only apply to animations
+    fn process_anm_key(&mut self, keycode: KeyCode, is_pressed: bool, frame: usize) -> bool {
+        let digit = match keycode {
+            KeyCode::Digit0 | KeyCode::Numpad0 => Some('0'),
+            KeyCode::Digit1 | KeyCode::Numpad1 => Some('1'),
+            KeyCode::Digit2 | KeyCode::Numpad2 => Some('2'),
+            KeyCode::Digit3 | KeyCode::Numpad3 => Some('3'),
+            KeyCode::Digit4 | KeyCode::Numpad4 => Some('4'),
+            KeyCode::Digit5 | KeyCode::Numpad5 => Some('5'),
+            KeyCode::Digit6 | KeyCode::Numpad6 => Some('6'),
+            KeyCode::Digit7 | KeyCode::Numpad7 => Some('7'),
+            KeyCode::Digit8 | KeyCode::Numpad8 => Some('8'),
+            KeyCode::Digit9 | KeyCode::Numpad9 => Some('9'),
+            _ => None,
+        };
+        let num_frames = self.anm.num_frames();
+        match keycode {
+            _ if digit.is_some() => {
+                if is_pressed {
+                    self.pending_frame.extend(digit);
+                    self.update_text();
+                }
+            },
+            KeyCode::Backspace => {
+                if is_pressed && self.pending_frame.pop().is_some() {
+                    self.update_text();
+                }
+            },
+            KeyCode::Enter | KeyCode::NumpadEnter => {
+                if is_pressed && !self.pending_frame.is_empty() {
+                    let pending = std::mem::take(&mut self.pending_frame);
+                    match pending.parse::<usize>() {
+                        Ok(n) if n < num_frames => {
+                            self.playing = false;
+                            self.set_frame(n);
+                        },
+                        _ => {
+                            self.flash = Some((format!("no frame {} (there are {})", pending, num_frames), Instant::now()));
+                            self.update_text();
+                        },
+                    }
+                }
+            },
+            // The arrows move the camera in the other modes
+            KeyCode::ArrowLeft | KeyCode::ArrowRight => {
+                if is_pressed && num_frames > 0 {
+                    self.playing = false;
+                    self.set_frame(if keycode == KeyCode::ArrowRight {
+                        (frame + 1) % num_frames
+                    } else {
+                        (frame + num_frames - 1) % num_frames
+                    });
+                }
+            },
+            KeyCode::Home | KeyCode::End => {
+                if is_pressed && num_frames > 0 {
+                    self.playing = false;
+                    self.set_frame(if keycode == KeyCode::Home { 0 } else { num_frames - 1 });
+                }
+            },
+            KeyCode::KeyP => {
+                if is_pressed {
+                    self.playing = !self.playing;
+                    self.frame_shown_at = Instant::now();
+                    self.update_text();
+                }
+            },
+            KeyCode::KeyI => {
+                if is_pressed {
+                    self.interpolated = !self.interpolated;
+                    // Continue from the frame currently shown
+                    self.set_frame(frame);
+                }
+            },
+            _ => return false,
+        }
+        true
+    }
+
+    fn set_frame(&mut self, frame: usize) {
+        self.render_what = RenderWhat::AnmIndex(frame);
+        self.frame_shown_at = Instant::now();
+        self.anm_time = self.anm.frame_duration().as_secs_f32() * frame as f32;
+        self.update_render();
+    }
+
     fn input(&mut self, event: &WindowEvent) -> bool {
-        self.process_input(event) || self.camera_controller.process_events(event)
+        if self.process_input(event) {
+            return true;
+        }
+        let speed = self.camera_controller.effective_speed();
+        let consumed = self.camera_controller.process_events(event);
+        if self.camera_controller.effective_speed() != speed {
+            self.update_text();
+        }
+        consumed
     }
 
     fn update(&mut self) {
         self.camera_controller.update_camera(&mut self.camera);
         self.camera_uniform.update_view_proj(&self.camera);
         self.queue.write_buffer(&self.camera_buffer, 0, bytemuck::cast_slice(&[self.camera_uniform])); 
+        self.light_binding.update(&self.queue, &self.light);
+
+        if self.flash.as_ref().is_some_and(|(_, shown_at)| shown_at.elapsed() >= FLASH_DURATION) {
+            self.flash = None;
+            self.update_text();
+        }
+        #[cfg(not(target_arch = "wasm32"))]
+        if self.watcher.poll() {
+            self.reload();
+        }
+        if let Some(timer) = &mut self.gpu_timer {
+            self.gpu_time = timer.poll(&self.device);
+        }
+        if self.show_timing && self.timing_shown_at.elapsed() >= TIMING_REFRESH {
+            self.update_text();
+        }
+
+        let elapsed = self.last_update.elapsed().as_secs_f32();
+        self.last_update = Instant::now();
+        if let RenderWhat::AnmIndex(frame) = self.render_what {
+            let num_frames = self.anm.num_frames();
+            let frame_time = self.anm.frame_duration().as_secs_f32();
+            if self.playing && num_frames > 0 {
+                if self.interpolated {
+                    // Sample at the wall-clock time, keeping track of the frame for the overlay
+                    self.anm_time = (self.anm_time + elapsed).rem_euclid(frame_time * num_frames as f32);
+                    let current = if frame_time > 0.0 { ((self.anm_time / frame_time) as usize).min(num_frames - 1) } else { 0 };
+                    self.render_what = RenderWhat::AnmIndex(current);
+                    self.update_render();
+                } else if self.frame_shown_at.elapsed() >= self.anm.frame_duration() {
+                    self.set_frame((frame + 1) % num_frames);
+                }
+            }
+        }
     }
 
-    fn update_render(&mut self) {
-        let mut instances = Vec::new();
-        let s = match self.render_what {
-            RenderWhat::MeshIndex(index) => {
-                let position = cgmath::Vector3{ x: 0.0, y: 0.0, z: 0.0 };
-                let rotation = cgmath::Quaternion::from_axis_angle(cgmath::Vector3::unit_x(), cgmath::Deg(-90.0));
-                let transform = cgmath::Matrix4::from_translation(position) * cgmath::Matrix4::from(rotation);
-                instances.push(Instance{ transform });
+    #[cfg(not(target_arch = "wasm32"))]
+    fn load_files(&self) -> Result<(qfg5mdl::Qfg5Model<'static>, qfg5anm::AnmDecoder, model::Model)> {
+        let mdl = qfg5mdl::Qfg5Model::new(&read_file(&self.resource_manager, MDL_SOURCE, &self.mdl_path)?)?;
+        let anm = qfg5anm::AnmDecoder::new(&read_file(&self.resource_manager, ANM_SOURCE, &self.anm_path)?)?;
+        if anm.anims.len() != mdl.submeshes.len() {
+            return Err(anyhow::anyhow!("animation has {} anims, but the model has {} submeshes", anm.anims.len(), mdl.submeshes.len()));
+        }
+        let obj_model = pollster::block_on(resources::load_qfg5model_with_options(&mdl, &self.device, &self.queue, &self.texture_bind_group_layout,
+            &resources::Qfg5ModelOptions{ mipmaps: self.mipmaps, smooth_normals: self.smooth_normals, ..MODEL_OPTIONS }))?;
+        Ok((mdl, anm, obj_model))
+    }
+
+    /// Reloads the model and animation after they changed on disk, keeping the camera and
+    /// the current selection where possible. On failure, the previous model stays loaded.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn reload(&mut self) {
+        for path in [ &self.mdl_path, &self.anm_path ] {
+            if let Some(name) = path.file_name() {
+                self.resource_manager.invalidate(&name.to_string_lossy());
+            }
+        }
+        let message = match self.load_files() {
+            Ok((mdl, anm, obj_model)) => {
+                self.mdl = mdl;
+                self.anm = anm;
+                self.obj_model = obj_model;
+                let last_mesh = self.obj_model.meshes.len().saturating_sub(1);
+                match self.render_what {
+                    RenderWhat::MeshIndex(index) => self.render_what = RenderWhat::MeshIndex(index.min(last_mesh)),
+                    RenderWhat::Exploded(index) => self.render_what = RenderWhat::Exploded(index.min(last_mesh)),
+                    RenderWhat::AnmIndex(frame) => {
+                        let frame = frame.min(self.anm.num_frames().saturating_sub(1));
+                        self.anm_time = self.anm_time.min(self.anm.frame_duration().as_secs_f32() * self.anm.num_frames() as f32);
+                        self.render_what = RenderWhat::AnmIndex(frame);
+                    },
+                }
+                self.update_render();
+                "reloaded".to_string()
+            },
+            Err(e) => format!("reload failed: {}", e),
+        };
+        self.flash = Some((message, Instant::now()));
+        self.update_text();
+    }
+
+    fn current_pose(&self) -> Pose {
+        match self.render_what {
+            RenderWhat::MeshIndex(index) => Pose::submesh(index),
+            RenderWhat::AnmIndex(_) if self.interpolated => Pose::anm_time(&self.anm, self.anm_time),
+            RenderWhat::AnmIndex(index) => Pose::anm_frame(&self.anm, index),
+            RenderWhat::Exploded(_) => Pose::exploded(&self.mdl),
+        }
+    }
 
+    /// Tint of each pose part; in the exploded view every submesh shows its key color
+    fn part_tint(&self, submesh: usize) -> [f32; 4] {
+        let RenderWhat::Exploded(selected) = self.render_what else { return [ 0.0; 4 ]; };
+        let [ r, g, b ] = highlight_color(submesh);
+        let alpha = if submesh == selected { HIGHLIGHT_TINT } else { KEY_TINT };
+        [ r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0, alpha ]
+    }
+
+    fn update_text(&mut self) {
+        let mut s = match self.render_what {
+            RenderWhat::MeshIndex(index) => {
                 let submesh = &self.mdl.submeshes[index];
-                &format!("mesh: {} - {} - {}/{}", self.mdl.name, submesh.name, index, self.mdl.submeshes.len())
+                format!("mesh: {} - {} - {}/{}", self.mdl.name, submesh.name, index, self.mdl.submeshes.len())
             },
             RenderWhat::AnmIndex(index) => {
-                for (mesh_index, _) in self.mdl.submeshes.iter().enumerate() {
-                    let block = &self.anm.anims[mesh_index].blocks[index];
-                    /*
-                    let x = cgmath::Vector4{ x: block.rotation[0], y: block.rotation[1], z: block.rotation[2], w: 0.0 };
-                    let y = cgmath::Vector4{ x: block.rotation[3], y: block.rotation[4], z: block.rotation[5], w: 0.0 };
-                    let z = cgmath::Vector4{ x: block.rotation[6], y: block.rotation[7], z: block.rotation[8], w: 0.0 };
-                    */
-                    let x = cgmath::Vector4{ x: block.rotation[0], y: block.rotation[3], z: block.rotation[6], w: 0.0 };
-                    let y = cgmath::Vector4{ x: block.rotation[1], y: block.rotation[4], z: block.rotation[7], w: 0.0 };
-                    let z = cgmath::Vector4{ x: block.rotation[2], y: block.rotation[5], z: block.rotation[8], w: 0.0 };
-                    let w = cgmath::Vector4{ x: block.translation[0], y: block.translation[1], z: block.translation[2], w: 1.0 };
-                    let transform = cgmath::Matrix4{ x, y, z, w };
-                    instances.push(Instance{ transform });
+                let time = if self.interpolated { self.anm_time } else { self.anm.frame_duration().as_secs_f32() * index as f32 };
+                let mut s = format!("animation: {} - {}\nframe {} / {}, {:.2}s{}, {}", self.mdl.name, self.anm.name,
+                    index, self.anm.num_frames(), time, if self.playing { ", playing" } else { "" },
+                    if self.interpolated { "interpolated" } else { "stepped" });
+                if !self.pending_frame.is_empty() {
+                    s = format!("{}\ngo to frame: {}_", s, self.pending_frame);
                 }
-                &format!("animation: {} - {} - {}/{}", self.mdl.name, self.anm.name, index, self.anm.anims.len())
+                s
+            },
+            RenderWhat::Exploded(index) => {
+                format!("exploded: {} - {} - {}/{}", self.mdl.name, self.mdl.submeshes[index].name, index, self.mdl.submeshes.len())
+            },
+        };
+        let projection = match self.camera.projection {
+            camera::Projection::Perspective{ fovy } => format!("perspective, {:.1} degrees", fovy),
+            camera::Projection::Orthographic{ height } => format!("orthographic, {:.1} units high", height),
+        };
+        s = format!("{}\ncamera speed: {:.2}, {}", s, self.camera_controller.effective_speed(), projection);
+
+        // Helps deciding whether the indices of a model need to be flipped
+        let winding = match self.render_what {
+            RenderWhat::MeshIndex(index) => self.mdl.submeshes[index].winding_stats(),
+            _ => self.mdl.winding_stats(),
+        };
+        s = format!("{}\nshading: {}, mipmaps {}, {} normals", s, self.shading.name(), if self.mipmaps { "on" } else { "off" },
+            if self.smooth_normals { "smooth" } else { "flat" });
+        s = match self.light.enabled {
+            true => format!("{}\nlight: azimuth {:.0}, elevation {:.0}, ambient {:.2}", s, self.light.azimuth, self.light.elevation, self.light.ambient),
+            false => format!("{}\nlight: off (unlit)", s),
+        };
+        s = format!("{}\ncull: {}, winding disagrees with normal: {:.1}% ({}/{} faces, {} degenerate)", s,
+            self.cull_mode.name(), winding.mismatch_fraction() * 100.0,
+            winding.mismatched, winding.judged(), winding.degenerate);
+        if let RenderWhat::AnmIndex(_) = self.render_what {
+            s = format!("{}\ngizmos: {}", s, self.gizmos.name());
+        }
+        if !self.status.is_empty() {
+            s = format!("{}\n{}", s, self.status);
+        }
+        if let Some((flash, _)) = &self.flash {
+            s = format!("{}\n{}", s, flash);
+        }
+        if self.show_timing {
+            s = format!("{}\n{}", s, self.timing_text());
+            #[cfg(not(target_arch = "wasm32"))]
+            {
+                let stats = self.resource_manager.stats();
+                s = format!("{}\ncache: {} hits, {} misses, {} files, {} KiB", s, stats.hits, stats.misses, stats.entries, stats.bytes / 1024);
             }
+            self.timing_shown_at = Instant::now();
+        }
+        s.push('\n');
+
+        // In the exploded view, list the submesh names in their key colors
+        let names: Vec<(String, usize)> = match self.render_what {
+            RenderWhat::Exploded(selected) => self.mdl.submeshes.iter().enumerate().map(|(n, submesh)| {
+                let name = &submesh.name;
+                let name = if n == selected { format!("[{}]", name) } else { name.to_string() };
+                (format!("{}  ", name), n)
+            }).collect(),
+            _ => Vec::new(),
         };
-        self.text_buffer.set_text(&mut self.font_system, s, Attrs::new().family(Family::SansSerif), Shaping::Advanced);
+        let attrs = Attrs::new().family(Family::SansSerif);
+        let spans = std::iter::once((s.as_str(), attrs)).chain(names.iter().map(|(name, n)| {
+            let [ r, g, b ] = highlight_color(*n);
+            (name.as_str(), attrs.color(Color::rgb(r, g, b)))
+        }));
+        self.text_buffer.set_rich_text(&mut self.font_system, spans, attrs, Shaping::Advanced);
+    }
+
+    fn timing_text(&self) -> String {
+        let gpu = match (&self.gpu_timer, self.gpu_time) {
+            (None, _) => "GPU time not available".to_string(),
+            (Some(_), None) => "GPU time pending".to_string(),
+            (Some(_), Some(time)) => format!("GPU {:.2} ms", time.as_secs_f64() * 1000.0),
+        };
+        match self.frame_times.stats() {
+            Some(stats) => format!("fps: {:.1}, 1% low {:.1}, {} frames, {}", stats.fps, stats.low_fps, stats.frames, gpu),
+            None => format!("fps: -, {}", gpu),
+        }
+    }
+
+    fn update_render(&mut self) {
+        self.pose = self.current_pose();
+        self.instances = self.pose.parts.iter().map(|part| Instance{ transform: part.transform, tint: self.part_tint(part.submesh) }).collect();
+        self.update_text();
 
-        self.instances = instances;
         let instance_data = self.instances.iter().map(Instance::to_raw).collect::<Vec<_>>();
         let instance_buffer = self.device.create_buffer_init(
             &wgpu::util::BufferInitDescriptor {
@@ -565,9 +1263,159 @@ impl<'a> State<'a> {
             }
         );
         self.instance_buffer = instance_buffer;
+        self.update_gizmos();
+    }
+
+    /// Builds the gizmo lines from the same transforms as the instances, so that they always agree
+    fn update_gizmos(&mut self) {
+        let mut vertices = Vec::new();
+        if self.gizmos != Gizmos::Off && matches!(self.render_what, RenderWhat::AnmIndex(_)) {
+            let size = self.mdl.bounds().map_or(1.0, |b| { let s = b.size(); s.x.max(s.y).max(s.z) });
+            let length = if size > 0.0 { size * GIZMO_SCALE } else { 1.0 };
+            for instance in &self.instances {
+                vertices.extend_from_slice(&lines::axis_triad(&instance.transform, length));
+            }
+            if self.gizmos == Gizmos::AxesAndLinks {
+                use cgmath::{Point3, Transform};
+                let origins: Vec<[f32; 3]> = self.instances.iter().map(|instance| {
+                    instance.transform.transform_point(Point3{ x: 0.0, y: 0.0, z: 0.0 }).into()
+                }).collect();
+                for pair in origins.windows(2) {
+                    vertices.push(lines::LineVertex{ position: pair[0], color: GIZMO_LINK_COLOR });
+                    vertices.push(lines::LineVertex{ position: pair[1], color: GIZMO_LINK_COLOR });
+                }
+            }
+        }
+        self.line_renderer.set_lines(&self.device, &vertices);
+    }
+
+    fn export_path(&self) -> PathBuf {
+        let stem = |path: &Path| path.file_stem().unwrap_or_default().to_string_lossy().to_string();
+        let name = match self.render_what {
+            RenderWhat::MeshIndex(index) => format!("{}-mesh{}.obj", stem(&self.mdl_path), index),
+            RenderWhat::AnmIndex(index) => format!("{}-{}-frame{}.obj", stem(&self.mdl_path), stem(&self.anm_path), index),
+            RenderWhat::Exploded(_) => format!("{}-exploded.obj", stem(&self.mdl_path)),
+        };
+        self.mdl_path.with_file_name(name)
+    }
+
+    /// Writes what is currently displayed (self.pose) as OBJ next to the input model
+    fn export_pose(&mut self) {
+        let path = self.export_path();
+        self.status = match obj::export(&self.mdl, &self.pose, &path) {
+            Ok(_) => format!("exported to {}", path.display()),
+            Err(e) => format!("export failed: {}", e),
+        };
+        self.update_text();
+    }
+
+    /// Sets up recording; the model is shown as an animation when recording one
+    fn start_recording(&mut self, options: RecordOptions) -> Result<()> {
+        std::fs::create_dir_all(&options.out)?;
+        if let RecordMode::Animation = options.mode {
+            self.render_what = RenderWhat::AnmIndex(0);
+            self.interpolated = true;
+            self.playing = false;
+        }
+        self.recording = Some(Recording{ mode: options.mode, frames: options.frames, out: options.out, frame: 0, start_eye: self.camera.eye });
+        Ok(())
+    }
+
+    /// Renders and saves the next frame of the recording; returns false once all frames are saved
+    fn record_frame(&mut self) -> Result<bool> {
+        let Some(recording) = &self.recording else { return Ok(false); };
+        if recording.frame >= recording.frames {
+            return Ok(false);
+        }
+        let (mode, frame) = (recording.mode, recording.frame);
+        let progress = frame as f32 / recording.frames as f32;
+        match mode {
+            RecordMode::Turntable => {
+                // Orbit around the target at the height and distance the camera started at
+                let offset = recording.start_eye - self.camera.target;
+                let radius = (offset.x * offset.x + offset.z * offset.z).sqrt();
+                let angle = offset.x.atan2(offset.z) + std::f32::consts::TAU * progress;
+                self.camera.eye = self.camera.target + cgmath::Vector3{ x: radius * angle.sin(), y: offset.y, z: radius * angle.cos() };
+            },
+            RecordMode::Animation => {
+                let duration = self.anm.frame_duration().as_secs_f32() * self.anm.num_frames() as f32;
+                self.anm_time = duration * progress;
+                let frame_time = self.anm.frame_duration().as_secs_f32();
+                let current = if frame_time > 0.0 { ((self.anm_time / frame_time) as usize).min(self.anm.num_frames().saturating_sub(1)) } else { 0 };
+                self.render_what = RenderWhat::AnmIndex(current);
+            },
+        }
+        self.camera_uniform.update_view_proj(&self.camera);
+        self.queue.write_buffer(&self.camera_buffer, 0, bytemuck::cast_slice(&[self.camera_uniform]));
+        self.update_render();
+
+        let target = screenshot::create_target(&self.device, self.config.width, self.config.height, self.config.format);
+        let view = target.create_view(&wgpu::TextureViewDescriptor::default());
+        let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Record Encoder"),
+        });
+        self.encode_scene(&mut encoder, &view, None);
+        self.queue.submit(std::iter::once(encoder.finish()));
+        let image = screenshot::read_texture(&self.device, &self.queue, &target)?;
+
+        let recording = self.recording.as_mut().unwrap();
+        image.save(recording.out.join(format!("frame-{:04}.png", frame)))?;
+        recording.frame += 1;
+        Ok(true)
+    }
+
+    /// Draws the model and gizmos, without the overlay
+    fn encode_scene(&self, encoder: &mut wgpu::CommandEncoder, view: &wgpu::TextureView, timestamp_writes: Option<wgpu::RenderPassTimestampWrites>) {
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Render Pass"),
+            // This is what @location(0) in the fragment shader targets
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color {
+                        r: 0.1,
+                        g: 0.2,
+                        b: 0.3,
+                        a: 1.0,
+                    }),
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: &self.depth_texture.view,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(1.0),
+                    store: wgpu::StoreOp::Store,
+                }),
+                stencil_ops: None,
+            }),
+            occlusion_query_set: None,
+            timestamp_writes,
+        });
+
+        render_pass.set_vertex_buffer(1, self.instance_buffer.slice(..));
+        render_pass.set_bind_group(2, &self.light_binding.bind_group, &[]);
+        render_pass.set_pipeline(&self.render_pipelines[self.shading.index()][self.cull_mode.index()]);
+        use qfg5reenigne::threed::model::DrawModel;
+        match self.render_what {
+            RenderWhat::MeshIndex(index) => {
+                render_pass.draw_mesh(&self.obj_model, index, &self.camera_bind_group);
+            },
+            RenderWhat::AnmIndex(_) | RenderWhat::Exploded(_) => {
+                // Every submesh has its own transform, stored in the instance with the same index
+                let parts = self.pose.parts.iter().enumerate().map(|(n, part)| (part.submesh, n as u32..(n + 1) as u32));
+                render_pass.draw_meshes_instanced(&self.obj_model, parts, &self.camera_bind_group);
+            },
+        }
+        self.line_renderer.draw(&mut render_pass, &self.camera_bind_group);
     }
 
     fn render(&mut self) -> Result<(), wgpu::SurfaceError> {
+        self.frame_times.tick();
+        if let Some(timer) = &mut self.gpu_timer {
+            timer.begin_frame();
+        }
         self.viewport.update(
             &self.queue,
             Resolution {
@@ -591,8 +1439,8 @@ impl<'a> State<'a> {
                     bounds: TextBounds {
                         left: 0,
                         top: 0,
-                        right: 600,
-                        bottom: 160,
+                        right: self.config.width as i32,
+                        bottom: self.config.height as i32,
                     },
                     default_color: Color::rgb(255, 255, 255),
                     custom_glyphs: &[],
@@ -607,71 +1455,36 @@ impl<'a> State<'a> {
             label: Some("Render Encoder"),
         });
 
-        {
-            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                label: Some("Render Pass"),
-                // This is what @location(0) in the fragment shader targets
+        self.encode_scene(&mut encoder, &view, self.gpu_timer.as_ref().and_then(|timer| timer.timestamp_writes()));
+        if let Some(timer) = &self.gpu_timer {
+            timer.resolve(&mut encoder);
+        }
+
+        // The overlay is left out of recordings
+        if self.recording.is_none() {
+            let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: None,
                 color_attachments: &[Some(wgpu::RenderPassColorAttachment {
                     view: &view,
                     resolve_target: None,
                     ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(wgpu::Color {
-                            r: 0.1,
-                            g: 0.2,
-                            b: 0.3,
-                            a: 1.0,
-                        }),
+                        load: wgpu::LoadOp::Load,
                         store: wgpu::StoreOp::Store,
                     },
                 })],
-                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
-                    view: &self.depth_texture.view,
-                    depth_ops: Some(wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(1.0),
-                        store: wgpu::StoreOp::Store,
-                    }),
-                    stencil_ops: None,
-                }),
-                occlusion_query_set: None,
+                depth_stencil_attachment: None,
                 timestamp_writes: None,
+                occlusion_query_set: None,
             });
 
-            render_pass.set_vertex_buffer(1, self.instance_buffer.slice(..));
-            render_pass.set_pipeline(&self.render_pipeline);
-            use qfg5reenigne::threed::model::DrawModel;
-            match self.render_what {
-                RenderWhat::MeshIndex(index) => {
-                    render_pass.draw_single_mesh_instanced(&self.obj_model, index, 0..self.instances.len() as u32, &self.camera_bind_group);
-                },
-                RenderWhat::AnmIndex(_) => {
-                    for (n, _) in self.instances.iter().enumerate() {
-                        render_pass.draw_single_mesh_instanced(&self.obj_model, n, n as u32..(n + 1) as u32, &self.camera_bind_group);
-                    }
-                }
-            }
+            self.text_renderer.render(&self.atlas, &self.viewport, &mut pass).unwrap();
         }
 
-        {
-            let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                    label: None,
-                    color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                        view: &view,
-                        resolve_target: None,
-                        ops: wgpu::Operations {
-                            load: wgpu::LoadOp::Load,
-                            store: wgpu::StoreOp::Store,
-                        },
-                    })],
-                    depth_stencil_attachment: None,
-                    timestamp_writes: None,
-                    occlusion_query_set: None,
-                });
-
-                self.text_renderer.render(&self.atlas, &self.viewport, &mut pass).unwrap();
-            }
-    
         // submit will accept anything that implements IntoIter
         self.queue.submit(std::iter::once(encoder.finish()));
+        if let Some(timer) = &mut self.gpu_timer {
+            timer.submitted();
+        }
         output.present();
         self.atlas.trim();
     
@@ -679,12 +1492,33 @@ impl<'a> State<'a> {
     }
 }
 
-async fn run(mdl_fname: &str, anm_fname: &str) -> Result<()> {
+async fn run(mdl_fname: &str, anm_fname: &str, projection: ProjectionKind, record: Option<RecordOptions>) -> Result<()> {
+    #[cfg(not(target_arch = "wasm32"))]
     env_logger::init();
     let event_loop = EventLoop::new()?;
     let window = WindowBuilder::new().build(&event_loop)?;
 
+    // In the browser, the window is a canvas that must be placed on the page
+    #[cfg(target_arch = "wasm32")]
+    {
+        use winit::platform::web::WindowExtWebSys;
+        let _ = window.request_inner_size(winit::dpi::PhysicalSize::new(800, 600));
+        let canvas = window.canvas().ok_or_else(|| anyhow::anyhow!("window has no canvas"))?;
+        web_sys::window()
+            .and_then(|win| win.document())
+            .and_then(|doc| doc.get_element_by_id(WASM_CONTAINER_ID))
+            .and_then(|container| container.append_child(&canvas).ok())
+            .ok_or_else(|| anyhow::anyhow!("cannot attach canvas to element '{}'", WASM_CONTAINER_ID))?;
+    }
+
     let mut state = State::new(&window, mdl_fname, anm_fname).await;
+    if let ProjectionKind::Orthographic = projection {
+        state.camera.toggle_projection();
+        state.update_text();
+    }
+    if let Some(options) = record {
+        state.start_recording(options)?;
+    }
 
     event_loop.run(move |event, control_flow| {
         match event {
@@ -709,8 +1543,23 @@ async fn run(mdl_fname: &str, anm_fname: &str) -> Result<()> {
                     WindowEvent::RedrawRequested => {
                         // This tells winit that we want another frame after this one
                         state.window().request_redraw();
-            
-                        state.update();
+
+                        if state.recording.is_some() {
+                            match state.record_frame() {
+                                Ok(true) => {},
+                                Ok(false) => {
+                                    control_flow.exit();
+                                    return;
+                                },
+                                Err(e) => {
+                                    log::error!("recording failed: {}", e);
+                                    control_flow.exit();
+                                    return;
+                                },
+                            }
+                        } else {
+                            state.update();
+                        }
                         match state.render() {
                             Ok(_) => {}
                             // Reconfigure the surface if it's lost or outdated
@@ -738,12 +1587,105 @@ async fn run(mdl_fname: &str, anm_fname: &str) -> Result<()> {
     Ok(())
 }
 
+/// Displays a Quest for Glory 5 model and animation
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(clap::Parser)]
+struct Cli {
+    /// Input MDL file
+    in_mdl: String,
+    /// Input ANM file; defaults to the first animation of the model, i.e. anm/06300.anm for mdl/063.mdl
+    in_anm: Option<String>,
+    /// Camera projection to start with; O toggles it
+    #[arg(long, value_enum, default_value_t = ProjectionKind::Perspective)]
+    projection: ProjectionKind,
+    /// Render frames to numbered PNG files in --out and exit
+    #[arg(long, value_enum, requires = "out")]
+    record: Option<RecordMode>,
+    /// Number of frames to record
+    #[arg(long, default_value_t = 120)]
+    frames: usize,
+    /// Directory to write recorded frames to
+    #[arg(long)]
+    out: Option<PathBuf>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
 fn main() -> Result<()> {
-    let args: Vec<_> = std::env::args().into_iter().collect();
-    if args.len() != 3 {
-        println!("usage: {} file.mdl file.anm", args[0]);
-    } else {
-        pollster::block_on(run(&args[1], &args[2]))?;
+    use clap::Parser;
+    let args = Cli::parse();
+    let record = args.record.zip(args.out).map(|(mode, out)| RecordOptions{ mode, frames: args.frames.max(1), out });
+    let in_anm = match args.in_anm {
+        Some(in_anm) => in_anm,
+        None => find_anim(Path::new(&args.in_mdl))?.to_string_lossy().into_owned(),
+    };
+    pollster::block_on(run(&args.in_mdl, &in_anm, args.projection, record))
+}
+
+/// Names of the directories of the model and the animation in the resource manager
+#[cfg(not(target_arch = "wasm32"))]
+const MDL_SOURCE: &str = "mdl";
+#[cfg(not(target_arch = "wasm32"))]
+const ANM_SOURCE: &str = "anm";
+
+/// Resource manager that reads the files of the directories of `mdl` and `anm`
+#[cfg(not(target_arch = "wasm32"))]
+fn file_manager(mdl: &Path, anm: &Path) -> ResourceManager {
+    let dir = |path: &Path| path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new(".")).to_path_buf();
+    let mut manager = ResourceManager::new(CACHE_BYTES);
+    manager.add_directory(MDL_SOURCE, dir(mdl));
+    manager.add_directory(ANM_SOURCE, dir(anm));
+    manager
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn read_file(manager: &ResourceManager, source: &str, path: &Path) -> Result<std::sync::Arc<Vec<u8>>> {
+    let name = path.file_name().ok_or_else(|| anyhow::anyhow!("{} is not a file", path.display()))?;
+    manager.read(source, &name.to_string_lossy())?.ok_or_else(|| anyhow::anyhow!("{} not found", path.display()))
+}
+
+/// Looks for the first animation of model `mdl` in the anm/ directory next to the model's
+/// directory, as in the extracted data tree, and in the model's own directory
+#[cfg(not(target_arch = "wasm32"))]
+fn find_anim(mdl: &Path) -> Result<PathBuf> {
+    let mdl_dir = mdl.parent().unwrap_or(Path::new(""));
+    let mdl_dir = if mdl_dir.as_os_str().is_empty() { Path::new(".") } else { mdl_dir };
+    for dir in [ mdl_dir.join("../anm"), mdl_dir.to_path_buf() ] {
+        let Ok(entries) = std::fs::read_dir(&dir) else { continue };
+        let paths = entries.filter_map(|entry| entry.ok()).map(|entry| entry.path());
+        if let Some(anm) = names::anims_for_model(mdl, paths).into_iter().next() {
+            log::info!("using animation {}", anm.display());
+            return Ok(anm);
+        }
     }
-    Ok(())
+    Err(anyhow::anyhow!("no animation found for {}, please specify one", mdl.display()))
 }
+
+// Element of the hosting page that receives the viewer's canvas
+#[cfg(target_arch = "wasm32")]
+const WASM_CONTAINER_ID: &str = "qfg5-viewer";
+
+// Browser build, for a page with a <div id="qfg5-viewer"> next to the model files:
+//   cargo build --target wasm32-unknown-unknown --bin mdl_anm_viewer
+//   wasm-bindgen --target web --out-dir pkg target/wasm32-unknown-unknown/debug/mdl_anm_viewer.wasm
+// The page then does `import init from "./pkg/mdl_anm_viewer.js"; init();`. The model and
+// animation are fetched relative to the page, as given by the 'mdl' and 'anm' query parameters.
+#[cfg(target_arch = "wasm32")]
+fn main() {
+    std::panic::set_hook(Box::new(console_error_panic_hook::hook));
+    console_log::init_with_level(log::Level::Warn).expect("cannot initialize logger");
+
+    let param = |name: &str, default: &str| {
+        web_sys::window()
+            .and_then(|win| win.location().search().ok())
+            .and_then(|search| web_sys::UrlSearchParams::new_with_str(&search).ok())
+            .and_then(|params| params.get(name))
+            .unwrap_or_else(|| default.to_string())
+    };
+    let mdl_fname = param("mdl", "model.mdl");
+    let anm_fname = param("anm", "model.anm");
+    wasm_bindgen_futures::spawn_local(async move {
+        if let Err(e) = run(&mdl_fname, &anm_fname, ProjectionKind::Perspective, None).await {
+            log::error!("{:?}", e);
+        }
+    });
+}
\ No newline at end of file
@@ -0,0 +1,30 @@
+/*-
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * Copyright (c) 2024 Rink Springer <rink@rink.nu>
+ * For conditions of distribution and use, see LICENSE file
+ */
+use anyhow::Result;
+use std::env;
+use std::path::Path;
+use qfg5reenigne::export::obj;
+
+fn main() -> Result<()> {
+    env_logger::init();
+    let args: Vec<_> = env::args().collect();
+    let [ obj_path, texture_path, out ] = &args[1..] else {
+        println!("usage: {} in.obj texture.png out.mdl", args[0]);
+        return Ok(())
+    };
+
+    let text = std::fs::read_to_string(obj_path)?;
+    let texture = image::open(texture_path)?.to_rgba8();
+    let name = Path::new(obj_path).file_stem().unwrap_or_default().to_string_lossy();
+    let mdl = obj::import(&name, &text, &texture)?;
+    for submesh in &mdl.submeshes {
+        println!("submesh '{}': {} vertices, {} texcoords, {} faces", submesh.name, submesh.vertices.len(), submesh.texcoords.len(), submesh.faces.len());
+    }
+    std::fs::write(out, mdl.encode()?)?;
+    println!("wrote {}", out);
+    Ok(())
+}
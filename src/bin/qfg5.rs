@@ -0,0 +1,851 @@
+/*-
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * Copyright (c) 2024 Rink Springer <rink@rink.nu>
+ * For conditions of distribution and use, see LICENSE file
+ */
+use anyhow::{anyhow, Context, Result};
+use clap::{Parser, Subcommand};
+use std::io::Write;
+use std::path::PathBuf;
+use qfg5reenigne::commands::{self, decode_as, decode_input, read_input, text_output, ImageFormat};
+use qfg5reenigne::qfg5resource::{qfg5anm, qfg5aud, qfg5gra, qfg5img, qfg5mdl, qfg5nod, qfg5qgf, qfg5qgm, qfg5rgd, qfg5spk, room, ResourceKind};
+
+/// Inspects and converts Quest for Glory 5 resources
+#[derive(Parser)]
+#[command(name = "qfg5")]
+struct Cli {
+    /// Log what is being done
+    #[arg(short, long, global = true)]
+    verbose: bool,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Guesses the kind of resource from the contents of a file
+    Identify {
+        file: PathBuf,
+        /// Write to this file instead of stdout
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+    /// Hex dump of a resource with the fields the decoder knows labeled and the bytes it does
+    /// not read marked with '!'
+    Inspect {
+        file: PathBuf,
+        /// Show all bytes instead of the first lines of every field
+        #[arg(long)]
+        full: bool,
+        /// Write to this file instead of stdout
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+    /// Archives (*.SPK)
+    Spk {
+        /// Input SPK file, or - to read it from stdin; the output for pack
+        spk: PathBuf,
+        #[command(subcommand)]
+        command: SpkCommand,
+    },
+    /// Room backgrounds (*.IMG)
+    Img {
+        /// Input IMG file
+        img: PathBuf,
+        #[command(subcommand)]
+        command: ImgCommand,
+    },
+    /// Models (*.MDL)
+    Mdl {
+        /// Input MDL file
+        mdl: PathBuf,
+        #[command(subcommand)]
+        command: MdlCommand,
+    },
+    /// Animations (*.ANM)
+    Anm {
+        /// Input ANM file
+        anm: PathBuf,
+        #[command(subcommand)]
+        command: AnmCommand,
+    },
+    /// Speech (*.AUD) and sound effects (*.WAV)
+    Aud {
+        /// Input AUD or WAV file
+        aud: PathBuf,
+        #[command(subcommand)]
+        command: AudCommand,
+    },
+    /// Sprites (*.GRA)
+    Gra {
+        /// Input GRA file
+        gra: PathBuf,
+        #[command(subcommand)]
+        command: GraCommand,
+    },
+    /// Messages (*.QGM)
+    Qgm {
+        /// Input QGM file
+        qgm: PathBuf,
+        /// Use the messages before the point where the file ends early, instead of failing
+        #[arg(long)]
+        lenient: bool,
+        #[command(subcommand)]
+        command: QgmCommand,
+    },
+    /// Fonts (*.QGF)
+    Qgf {
+        /// Input QGF file
+        qgf: PathBuf,
+        #[command(subcommand)]
+        command: QgfCommand,
+    },
+    /// Room regions (*.RGD)
+    Rgd {
+        /// Input RGD file
+        rgd: PathBuf,
+        #[command(subcommand)]
+        command: RgdCommand,
+    },
+    /// Rooms, made up of img/, nod/, zzz/ and rgd/ files
+    Room {
+        /// Extracted data directory
+        data_dir: PathBuf,
+        /// Room number
+        id: u32,
+        #[command(subcommand)]
+        command: RoomCommand,
+    },
+    /// Converts all resources of an extracted data tree, including SPK archives in it, to a
+    /// mirrored tree; files whose outputs are newer are skipped
+    ConvertAll {
+        /// Extracted data directory
+        data_dir: PathBuf,
+        /// Output directory
+        #[arg(long)]
+        out: PathBuf,
+        /// Number of files to convert at the same time; defaults to the number of CPUs
+        #[arg(long)]
+        jobs: Option<usize>,
+        /// Also convert files whose outputs are up to date
+        #[arg(long)]
+        force: bool,
+        /// Also write thumbnails of room backgrounds and model textures, as <name>-thumb.png
+        #[arg(long)]
+        thumbs: bool,
+        /// Write the summary to this file instead of stdout
+        #[arg(long)]
+        report: Option<PathBuf>,
+    },
+    /// Decodes and checks every resource of extracted data trees and SPK archives, and reports
+    /// the result per file; fails if any resource does not decode
+    Check {
+        /// Extracted data directories and SPK archives
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+        /// Number of files to check at the same time; defaults to the number of CPUs
+        #[arg(long)]
+        jobs: Option<usize>,
+        #[command(flatten)]
+        output: ReportOutput,
+    },
+}
+
+/// Output of the commands that show a decoded resource
+#[derive(clap::Args)]
+struct DumpOutput {
+    /// Write to this file instead of stdout
+    #[arg(long)]
+    out: Option<PathBuf>,
+    /// With json, write the whole decoded resource instead of a summary
+    #[cfg(feature = "serde")]
+    #[arg(long, value_enum, default_value_t)]
+    format: ReportFormat,
+}
+
+impl DumpOutput {
+    #[cfg(feature = "serde")]
+    fn write<T: serde::Serialize + ?Sized>(&self, value: &T, text: impl FnOnce(&mut dyn Write) -> Result<()>) -> Result<()> {
+        let mut w = text_output(self.out.as_deref())?;
+        match self.format {
+            ReportFormat::Json => commands::write_json(value, &mut w),
+            ReportFormat::Text => text(&mut w),
+        }
+    }
+
+    #[cfg(not(feature = "serde"))]
+    fn write<T: ?Sized>(&self, _value: &T, text: impl FnOnce(&mut dyn Write) -> Result<()>) -> Result<()> {
+        text(&mut text_output(self.out.as_deref())?)
+    }
+}
+
+/// `--format` of the commands that show a resource or a report
+#[cfg(feature = "serde")]
+#[derive(Clone, Copy, Default, clap::ValueEnum)]
+enum ReportFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// Output of the check command
+#[derive(clap::Args)]
+struct ReportOutput {
+    /// Write the report to this file instead of stdout
+    #[arg(long)]
+    report: Option<PathBuf>,
+    #[cfg(feature = "serde")]
+    #[arg(long, value_enum, default_value_t)]
+    format: ReportFormat,
+}
+
+impl ReportOutput {
+    fn write(&self, report: &commands::check::Report) -> Result<()> {
+        let mut w = text_output(self.report.as_deref())?;
+        #[cfg(feature = "serde")]
+        if let ReportFormat::Json = self.format {
+            return commands::write_json(report, &mut w);
+        }
+        report.write(&mut w)
+    }
+}
+
+#[derive(Subcommand)]
+enum SpkCommand {
+    /// Lists all resources
+    List {
+        /// Only list resources whose name matches this pattern, such as *.qgm
+        #[arg(long)]
+        filter: Option<String>,
+        #[command(flatten)]
+        output: DumpOutput,
+    },
+    /// Extracts all resources
+    Extract {
+        /// Output directory
+        #[arg(long)]
+        out: PathBuf,
+        /// Also convert models to .obj, sprites and fonts to .png and other resources to .txt
+        #[arg(long)]
+        convert: bool,
+        /// Only extract resources whose name matches this pattern, such as *.qgm
+        #[arg(long)]
+        filter: Option<String>,
+        /// Keep the case of the names in the archive instead of using lowercase
+        #[arg(long)]
+        keep_case: bool,
+    },
+    /// Checks the CRC of every resource, failing if any differs
+    Verify,
+    /// Writes a copy of the archive with one resource replaced by the contents of a file
+    Replace {
+        /// Name of the resource, such as QGM/160.QGM; the directory can be left out
+        name: String,
+        /// File with the new contents, or - for stdin
+        file: PathBuf,
+        /// Output SPK file
+        #[arg(long)]
+        out: PathBuf,
+    },
+    /// Creates the archive from the files below a directory, named in uppercase
+    Pack {
+        /// Directory with the resources, such as an extracted archive
+        dir: PathBuf,
+    },
+    /// Writes a single resource as it is, to stdout by default
+    Cat {
+        /// Name of the resource, such as IMG/2000.IMG; the directory can be left out
+        name: String,
+        /// Write to this file instead of stdout
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand)]
+enum ImgCommand {
+    /// Writes the background to an image
+    Render {
+        /// Output image, or - for stdout
+        #[arg(long)]
+        out: PathBuf,
+        #[arg(long, value_enum, default_value_t)]
+        format: ImageFormat,
+        /// Palette of the room; without it, the palette indices are shown as shades of grey
+        #[arg(long)]
+        nod: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand)]
+enum MdlCommand {
+    /// Shows submeshes, subbitmaps and bounds
+    Dump {
+        /// Also show the header values of every submesh whose meaning is not known yet
+        #[arg(long)]
+        verbose: bool,
+        /// Also show the smallest, largest and mean lighting values per component
+        #[arg(long)]
+        lighting: bool,
+        #[command(flatten)]
+        output: DumpOutput,
+    },
+    /// Shows degenerate, duplicate and unused geometry of every submesh
+    Stats {
+        #[command(flatten)]
+        output: DumpOutput,
+    },
+    /// Exports to Wavefront .obj, with .mtl and textures next to it
+    ExportObj {
+        /// Output .obj file
+        #[arg(long)]
+        out: PathBuf,
+        /// Pose the model using this animation; without it, all submeshes are laid out side by side
+        #[arg(long)]
+        anm: Option<PathBuf>,
+        /// Animation frame to use
+        #[arg(long, default_value_t = 0, requires = "anm")]
+        frame: usize,
+        /// Export only this submesh, by name (ignoring case) or index
+        #[arg(long, conflicts_with = "anm")]
+        submesh: Option<String>,
+    },
+    /// Writes every subbitmap as an image in the colors of the model's palette
+    ExportTextures {
+        /// Output directory
+        #[arg(long)]
+        out: PathBuf,
+        #[arg(long, value_enum, default_value_t)]
+        format: ImageFormat,
+        /// Make pixels with this palette index transparent
+        #[arg(long, value_name = "INDEX")]
+        with_alpha: Option<u8>,
+    },
+    /// Draws the texture coordinates of every submesh over the subbitmaps it uses, as PNG
+    ExportUvmap {
+        /// Output directory
+        #[arg(long)]
+        out: PathBuf,
+    },
+    /// Compares the submeshes with those of another MDL file by name and the subbitmaps by index,
+    /// failing if anything differs
+    Diff {
+        /// The MDL file to compare with
+        other: PathBuf,
+        /// How far vertices may move and other values may change while counting as the same
+        #[arg(long, default_value_t = 0.0)]
+        tolerance: f32,
+        /// Write to this file instead of stdout
+        #[arg(long)]
+        out: Option<PathBuf>,
+        #[cfg(feature = "serde")]
+        #[arg(long, value_enum, default_value_t)]
+        format: ReportFormat,
+    },
+}
+
+#[derive(Subcommand)]
+enum AnmCommand {
+    /// Shows the translation and rotation of every block
+    Dump {
+        #[command(flatten)]
+        output: DumpOutput,
+    },
+}
+
+#[derive(Subcommand)]
+enum GraCommand {
+    /// Shows the sprite collections
+    Dump {
+        #[command(flatten)]
+        output: DumpOutput,
+    },
+    /// Writes every frame to an image
+    Export {
+        /// Output directory
+        #[arg(long)]
+        out: PathBuf,
+        #[arg(long, value_enum, default_value_t)]
+        format: ImageFormat,
+        /// Use the palette of this NOD file instead of the one in the GRA file
+        #[arg(long)]
+        nod: Option<PathBuf>,
+    },
+    /// Writes every sprite collection as an animated GIF
+    Gif {
+        /// Output directory
+        #[arg(long)]
+        out: PathBuf,
+        /// Use the palette of this NOD file, of the room the sprite belongs to, instead of the
+        /// one in the GRA file
+        #[arg(long)]
+        nod: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand)]
+enum AudCommand {
+    /// Shows the sample format and duration
+    Dump {
+        #[command(flatten)]
+        output: DumpOutput,
+    },
+    /// Decodes the sound to a 16-bit PCM WAV file
+    Export {
+        /// Output WAV file
+        #[arg(long)]
+        wav: PathBuf,
+    },
+}
+
+#[derive(Subcommand)]
+enum QgmCommand {
+    /// Lists all messages
+    List {
+        /// Also show the header fields whose meaning is not known yet
+        #[arg(long)]
+        unknown: bool,
+        /// CSV file with a line `id,name` per speaker, to show their names
+        #[arg(long)]
+        speakers: Option<PathBuf>,
+        /// Only list the messages of this speaker, by name or id
+        #[arg(long)]
+        speaker: Option<String>,
+        /// Show the name of the speech resource of every message; with a directory of extracted
+        /// resources or an SPK archive, also whether it exists there, and from an archive its duration
+        #[arg(long, value_name = "DIR_OR_SPK", num_args = 0..=1)]
+        with_audio: Option<Option<PathBuf>>,
+        #[command(flatten)]
+        output: DumpOutput,
+    },
+    /// Writes the messages as CSV with a header row, for spreadsheets
+    ExportCsv {
+        /// Write to this file instead of stdout
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+    /// Writes the conversations as a Graphviz DOT graph
+    Graph {
+        /// Output DOT file
+        #[arg(long)]
+        out: Option<PathBuf>,
+        /// Further QGM files whose messages are included, so that references between files are followed
+        other: Vec<PathBuf>,
+    },
+    /// Writes the messages as a PO file for translating them
+    ExportPo {
+        /// Write to this file instead of stdout
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+    /// Writes a QGM file with the translations of a PO file; untranslated messages keep their text
+    ImportPo {
+        /// PO file, as written by export-po
+        po: PathBuf,
+        /// Output QGM file
+        #[arg(long)]
+        out: PathBuf,
+    },
+    /// Compares the messages with those of another QGM file by label, such as of another release
+    Diff {
+        /// The QGM file to compare with
+        other: PathBuf,
+        /// Also list the messages that are the same
+        #[arg(long)]
+        verbose: bool,
+        /// Write to this file instead of stdout
+        #[arg(long)]
+        out: Option<PathBuf>,
+        #[cfg(feature = "serde")]
+        #[arg(long, value_enum, default_value_t)]
+        format: ReportFormat,
+    },
+    /// Writes a QGM file with the text of one message replaced
+    Patch {
+        /// Label of the message, such as 0BJ0102.034
+        label: String,
+        /// New text of the message
+        text: String,
+        /// Output QGM file
+        #[arg(long)]
+        out: PathBuf,
+    },
+    /// Reports the dialog options and message labels that refer to a label none of the files has;
+    /// fails if there are any. The QGM file and the others may be directories of QGM files
+    Validate {
+        /// Further QGM files or directories whose labels can be referred to
+        other: Vec<PathBuf>,
+    },
+}
+
+#[derive(Subcommand)]
+enum QgfCommand {
+    /// Shows the font metrics
+    Dump {
+        #[command(flatten)]
+        output: DumpOutput,
+    },
+    /// Renders text, or all glyphs, to an image
+    Render {
+        /// Output image
+        #[arg(long)]
+        out: PathBuf,
+        #[arg(long, value_enum, default_value_t)]
+        format: ImageFormat,
+        /// Text to render
+        #[arg(long)]
+        text: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum RgdCommand {
+    /// Shows the regions
+    Dump {
+        #[command(flatten)]
+        output: DumpOutput,
+    },
+}
+
+#[derive(Subcommand)]
+enum RoomCommand {
+    /// Shows the size and regions
+    Dump {
+        /// Write to this file instead of stdout
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+    /// Writes the background, and optionally the depth map, to images
+    Render {
+        /// Output directory
+        #[arg(long)]
+        out: PathBuf,
+        #[arg(long, value_enum, default_value_t)]
+        format: ImageFormat,
+        /// Also write the depth map
+        #[arg(long)]
+        depth: bool,
+    },
+}
+
+fn run(command: Command) -> Result<()> {
+    match command {
+        Command::Identify{ file, out } => commands::identify::identify(&read_input(&file)?, &mut text_output(out.as_deref())?)?,
+        Command::Inspect{ file, full, out } => {
+            let options = commands::inspect::InspectOptions{ full };
+            commands::inspect::inspect(&read_input(&file)?, &options, &mut text_output(out.as_deref())?)?
+        },
+        Command::Spk{ spk, command: SpkCommand::Pack{ dir } } => {
+            commands::spk::pack(&dir, &spk)?;
+        },
+        Command::Spk{ spk, command: SpkCommand::Replace{ name, file, out } } => {
+            commands::spk::replace(&spk, &name, &file, &out)?;
+        },
+        Command::Spk{ spk, command } => {
+            let archive = commands::spk::open(&spk)?;
+            for diagnostic in archive.diagnostics() {
+                log::warn!("{}", diagnostic);
+            }
+            match command {
+                SpkCommand::List{ filter, output } => {
+                    let items = commands::spk::filter(&archive, filter.as_deref());
+                    output.write(&items, |w| commands::spk::list(&archive, filter.as_deref(), w))?
+                },
+                SpkCommand::Cat{ name, out } => commands::spk::cat(&archive, &name, &mut text_output(out.as_deref())?)?,
+                SpkCommand::Extract{ out, convert, filter, keep_case } => {
+                    let case = if keep_case { qfg5spk::FileNameCase::Original } else { qfg5spk::FileNameCase::Lowercase };
+                    let options = commands::spk::ExtractOptions{ convert, filter: filter.as_deref(), case };
+                    let count = commands::spk::extract(&archive, &out, &options, |_, _, _| { })?;
+                    log::info!("extracted {} items to {}", count, out.display());
+                },
+                SpkCommand::Verify => {
+                    let failed = commands::spk::verify(&archive, &mut std::io::stdout())?;
+                    if failed > 0 {
+                        return Err(anyhow!("{} items failed verification", failed));
+                    }
+                },
+                SpkCommand::Pack{ .. } | SpkCommand::Replace{ .. } => unreachable!(),
+            }
+        },
+        Command::Img{ img, command } => {
+            let img = decode_input(&img, ResourceKind::Img, qfg5img::ImageDecoder::new)?;
+            match command {
+                ImgCommand::Render{ out, format, nod } => {
+                    let nod = nod.map(|path| decode_input(&path, ResourceKind::Nod, qfg5nod::NodDecoder::new)).transpose()?;
+                    commands::img::render(&img, nod.as_ref().map(|nod| &nod.get_palette()[..]), &out, format)?;
+                },
+            }
+        },
+        Command::Mdl{ mdl, command } => {
+            let mdl = decode_input(&mdl, ResourceKind::Mdl, qfg5mdl::Qfg5Model::new)?;
+            match command {
+                MdlCommand::Dump{ verbose, lighting, output } => output.write(&mdl, |w| {
+                    commands::mdl::dump(&mdl, verbose, w)?;
+                    if lighting { commands::mdl::dump_lighting(&mdl, w) } else { Ok(()) }
+                })?,
+                MdlCommand::Stats{ output } => output.write(&mdl.analyze(), |w| commands::mdl::dump_stats(&mdl, w))?,
+                MdlCommand::ExportObj{ out, anm, frame, submesh } => {
+                    let anm = anm.map(|path| decode_input(&path, ResourceKind::Anm, qfg5anm::AnmDecoder::new)).transpose()?;
+                    let pose = match (&anm, submesh) {
+                        (Some(anm), _) => commands::mdl::ExportPose::AnmFrame(anm, frame),
+                        (None, Some(selector)) => commands::mdl::ExportPose::Submesh(commands::mdl::find_submesh(&mdl, &selector)?),
+                        (None, None) => commands::mdl::ExportPose::Exploded,
+                    };
+                    for path in commands::mdl::export_obj(&mdl, pose, &out)? {
+                        log::info!("wrote {}", path.display());
+                    }
+                },
+                MdlCommand::ExportTextures{ out, format, with_alpha } => {
+                    for path in commands::mdl::export_textures(&mdl, &out, format, with_alpha)? {
+                        log::info!("wrote {}", path.display());
+                    }
+                },
+                MdlCommand::ExportUvmap{ out } => {
+                    for path in commands::mdl::export_uvmaps(&mdl, &out)? {
+                        log::info!("wrote {}", path.display());
+                    }
+                },
+                MdlCommand::Diff{ other, tolerance, out, #[cfg(feature = "serde")] format } => {
+                    let other = decode_input(&other, ResourceKind::Mdl, qfg5mdl::Qfg5Model::new)?;
+                    let result = commands::mdl::diff(&mdl, &other, tolerance);
+                    let mut w = text_output(out.as_deref())?;
+                    #[cfg(feature = "serde")]
+                    if let ReportFormat::Json = format {
+                        commands::write_json(&result, &mut w)?;
+                    } else {
+                        result.write(&mut w)?;
+                    }
+                    #[cfg(not(feature = "serde"))]
+                    result.write(&mut w)?;
+                    if !result.is_same() {
+                        return Err(anyhow!("the models differ"));
+                    }
+                },
+            }
+        },
+        Command::Anm{ anm, command } => {
+            let anm = decode_input(&anm, ResourceKind::Anm, qfg5anm::AnmDecoder::new)?;
+            match command {
+                AnmCommand::Dump{ output } => output.write(&anm, |w| commands::anm::dump(&anm, w))?,
+            }
+        },
+        Command::Aud{ aud: path, command } => {
+            let aud = qfg5aud::AudDecoder::new(&read_input(&path)?).with_context(|| path.display().to_string())?;
+            match command {
+                AudCommand::Dump{ output } => output.write(&aud, |w| commands::aud::dump(&aud, w))?,
+                AudCommand::Export{ wav } => commands::aud::export_wav(&aud, &wav)?,
+            }
+        },
+        Command::Gra{ gra, command } => {
+            let gra = decode_input(&gra, ResourceKind::Gra, qfg5gra::GraDecoder::new)?;
+            match command {
+                GraCommand::Dump{ output } => output.write(&gra, |w| commands::gra::dump(&gra, w))?,
+                GraCommand::Export{ out, format, nod } => {
+                    let nod = nod.map(|path| decode_input(&path, ResourceKind::Nod, qfg5nod::NodDecoder::new)).transpose()?;
+                    let palette = nod.as_ref().map(|nod| &nod.get_palette()[..]);
+                    for path in commands::gra::export(&gra, palette, &out, format)? {
+                        log::info!("wrote {}", path.display());
+                    }
+                },
+                GraCommand::Gif{ out, nod } => {
+                    let nod = nod.map(|path| decode_input(&path, ResourceKind::Nod, qfg5nod::NodDecoder::new)).transpose()?;
+                    let palette = nod.as_ref().map(|nod| &nod.get_palette()[..]);
+                    for path in commands::gra::export_gif(&gra, palette, &out)? {
+                        log::info!("wrote {}", path.display());
+                    }
+                },
+            }
+        },
+        Command::Qgm{ qgm: path, command: QgmCommand::Validate{ other }, .. } => {
+            let paths: Vec<_> = std::iter::once(path).chain(other).collect();
+            let dangling = commands::qgm::validate(&paths, &mut std::io::stdout())?;
+            if dangling > 0 {
+                return Err(anyhow!("{} dangling references", dangling));
+            }
+        },
+        Command::Qgm{ qgm: path, lenient, command } => {
+            // Importing a PO file and patching encode the messages again, which needs the original data
+            let data = read_input(&path)?;
+            let decode = if lenient { qfg5qgm::QgmDecoder::new_lenient } else { qfg5qgm::QgmDecoder::new };
+            let qgm = decode_as(&data, ResourceKind::Qgm, decode).with_context(|| path.display().to_string())?;
+            if let Some(at) = qgm.truncated_at {
+                log::warn!("{}: data ends within the message at offset {:#x}, decoded {} messages", path.display(), at, qgm.messages.len());
+            }
+            match command {
+                QgmCommand::List{ unknown, speakers, speaker, with_audio, output } => {
+                    let speakers = speakers.as_deref().map(commands::qgm::read_speakers).transpose()?;
+                    let speaker = speaker.map(|speaker| commands::qgm::resolve_speaker(speakers.as_ref(), &speaker)).transpose()?;
+                    let audio = with_audio.as_ref().and_then(|path| path.as_deref()).map(commands::qgm::read_audio).transpose()?;
+                    let options = commands::qgm::ListOptions{ unknown, speakers: speakers.as_ref(), speaker,
+                        audio: with_audio.is_some(), audio_names: audio.as_ref().map(|(names, _)| names),
+                        audio_durations: audio.as_ref().map(|(_, durations)| durations) };
+                    output.write(&qgm, |w| commands::qgm::list(&qgm, &options, w))?
+                },
+                QgmCommand::ExportCsv{ out } => commands::qgm::export_csv(&qgm, &mut text_output(out.as_deref())?)?,
+                QgmCommand::Graph{ out, other } => {
+                    let mut qgms = vec![ qgm ];
+                    for path in other {
+                        qgms.push(decode_input(&path, ResourceKind::Qgm, qfg5qgm::QgmDecoder::new)?);
+                    }
+                    commands::qgm::graph(&qgms.iter().collect::<Vec<_>>(), &mut text_output(out.as_deref())?)?;
+                },
+                QgmCommand::ExportPo{ out } => commands::qgm::export_po(&qgm, &mut text_output(out.as_deref())?)?,
+                QgmCommand::ImportPo{ po, out } => {
+                    for warning in commands::qgm::import_po(&data, &po, &out)? {
+                        log::warn!("{}", warning);
+                    }
+                },
+                QgmCommand::Diff{ other, verbose, out, #[cfg(feature = "serde")] format } => {
+                    let other = decode_input(&other, ResourceKind::Qgm, decode)?;
+                    let result = commands::qgm::diff(&qgm, &other, verbose);
+                    let mut w = text_output(out.as_deref())?;
+                    #[cfg(feature = "serde")]
+                    if let ReportFormat::Json = format {
+                        return commands::write_json(&result, &mut w);
+                    }
+                    result.write(&mut w)?
+                },
+                QgmCommand::Patch{ label, text, out } => commands::qgm::patch(&data, &label, &text, &out)?,
+                QgmCommand::Validate{ .. } => unreachable!(),
+            }
+        },
+        Command::Qgf{ qgf, command } => {
+            let qgf = decode_input(&qgf, ResourceKind::Qgf, qfg5qgf::QgfDecoder::new)?;
+            match command {
+                QgfCommand::Dump{ output } => output.write(&qgf, |w| commands::qgf::dump(&qgf, w))?,
+                QgfCommand::Render{ out, format, text } => commands::qgf::render(&qgf, text.as_deref(), &out, format)?,
+            }
+        },
+        Command::Rgd{ rgd, command } => {
+            let rgd = decode_input(&rgd, ResourceKind::Rgd, qfg5rgd::RgdDecoder::new)?;
+            match command {
+                RgdCommand::Dump{ output } => output.write(&rgd, |w| commands::rgd::dump(&rgd, w))?,
+            }
+        },
+        Command::Room{ data_dir, id, command } => {
+            let room = room::Room::load(&data_dir, id)?;
+            match command {
+                RoomCommand::Dump{ out } => commands::room::dump(&room, &mut text_output(out.as_deref())?)?,
+                RoomCommand::Render{ out, format, depth } => {
+                    for path in commands::room::render(&room, depth, &out, format)? {
+                        log::info!("wrote {}", path.display());
+                    }
+                },
+            }
+        },
+        Command::ConvertAll{ data_dir, out, jobs, force, thumbs, report } => {
+            let jobs = jobs.unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+            let summary = commands::convert::convert_all(&data_dir, &out, &commands::convert::ConvertOptions{ jobs, force, thumbs })?;
+            summary.write(&mut text_output(report.as_deref())?)?;
+            if !summary.failures.is_empty() {
+                return Err(anyhow::anyhow!("{} files could not be converted", summary.failures.len()));
+            }
+        },
+        Command::Check{ paths, jobs, output } => {
+            let jobs = jobs.unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+            let report = commands::check::check_all(&paths, &commands::check::CheckOptions{ jobs })?;
+            output.write(&report)?;
+            if report.failed() > 0 {
+                return Err(anyhow::anyhow!("{} resources failed", report.failed()));
+            }
+        },
+    }
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let args = Cli::parse();
+    // Logs go to stderr, as stdout may be binary output going into a pipe
+    env_logger::Builder::from_env(env_logger::Env::default())
+        .target(env_logger::Target::Stderr)
+        .filter_level(if args.verbose { log::LevelFilter::Debug } else { log::LevelFilter::Warn })
+        .parse_default_env()
+        .init();
+    match run(args.command) {
+        // The reader of a pipe, such as head, has seen enough
+        Err(e) if e.chain().any(|e| e.downcast_ref::<std::io::Error>().is_some_and(|e| e.kind() == std::io::ErrorKind::BrokenPipe)) => Ok(()),
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn test_cli() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn test_help() {
+        for args in [ &[ "identify" ][..], &[ "spk", "x", "list" ], &[ "spk", "x", "extract" ], &[ "spk", "x", "cat" ], &[ "img", "x", "render" ], &[ "mdl", "x", "dump" ], &[ "mdl", "x", "export-obj" ], &[ "mdl", "x", "export-textures" ],
+                      &[ "anm", "x", "dump" ], &[ "aud", "x", "dump" ], &[ "aud", "x", "export" ], &[ "gra", "x", "dump" ], &[ "gra", "x", "export" ], &[ "qgm", "x", "list" ], &[ "qgm", "x", "import-po" ], &[ "qgm", "x", "graph" ], &[ "qgm", "x", "export-csv" ], &[ "qgm", "x", "validate" ], &[ "qgm", "x", "patch" ], &[ "qgm", "x", "diff" ],
+                      &[ "qgf", "x", "dump" ], &[ "qgf", "x", "render" ], &[ "rgd", "x", "dump" ], &[ "room", "x", "1", "dump" ],
+                      &[ "room", "x", "1", "render" ], &[ "convert-all", "x" ], &[ "check" ] ] {
+            let args = [ "qfg5" ].iter().chain(args).chain(&[ "--help" ]);
+            let err = Cli::try_parse_from(args).err().unwrap();
+            assert_eq!(err.kind(), clap::error::ErrorKind::DisplayHelp);
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let cli = Cli::try_parse_from([ "qfg5", "-v", "mdl", "a.mdl", "export-obj", "--out", "a.obj", "--anm", "a.anm", "--frame", "3" ]).unwrap();
+        assert!(cli.verbose);
+        assert!(matches!(cli.command, Command::Mdl{ command: MdlCommand::ExportObj{ frame: 3, submesh: None, .. }, .. }));
+        assert!(Cli::try_parse_from([ "qfg5", "mdl", "a.mdl", "export-obj", "--out", "a.obj", "--frame", "3" ]).is_err());
+        let cli = Cli::try_parse_from([ "qfg5", "mdl", "a.mdl", "export-textures", "--out", "textures", "--with-alpha", "0" ]).unwrap();
+        assert!(matches!(cli.command, Command::Mdl{ command: MdlCommand::ExportTextures{ format: ImageFormat::Png, with_alpha: Some(0), .. }, .. }));
+        assert!(Cli::try_parse_from([ "qfg5", "mdl", "a.mdl", "export-textures", "--out", "textures", "--with-alpha", "256" ]).is_err());
+        let cli = Cli::try_parse_from([ "qfg5", "mdl", "a.mdl", "dump", "--lighting" ]).unwrap();
+        assert!(matches!(cli.command, Command::Mdl{ command: MdlCommand::Dump{ verbose: false, lighting: true, .. }, .. }));
+        let cli = Cli::try_parse_from([ "qfg5", "mdl", "a.mdl", "export-uvmap", "--out", "uv" ]).unwrap();
+        assert!(matches!(cli.command, Command::Mdl{ command: MdlCommand::ExportUvmap{ .. }, .. }));
+        let cli = Cli::try_parse_from([ "qfg5", "mdl", "a.mdl", "stats" ]).unwrap();
+        assert!(matches!(cli.command, Command::Mdl{ command: MdlCommand::Stats{ .. }, .. }));
+        let cli = Cli::try_parse_from([ "qfg5", "mdl", "demo/hero.mdl", "diff", "hero.mdl", "--tolerance", "0.01" ]).unwrap();
+        assert!(matches!(cli.command, Command::Mdl{ command: MdlCommand::Diff{ tolerance, out: None, .. }, .. } if tolerance == 0.01));
+        let cli = Cli::try_parse_from([ "qfg5", "rgd", "a.rgd", "dump", "--out", "a.txt" ]).unwrap();
+        assert!(matches!(cli.command, Command::Rgd{ command: RgdCommand::Dump{ output: DumpOutput{ out: Some(_), .. } }, .. }));
+        let cli = Cli::try_parse_from([ "qfg5", "spk", "-", "cat", "2000.img" ]).unwrap();
+        assert!(matches!(cli.command, Command::Spk{ ref spk, command: SpkCommand::Cat{ out: None, .. } } if spk.as_os_str() == "-"));
+        let cli = Cli::try_parse_from([ "qfg5", "spk", "new.spk", "pack", "data" ]).unwrap();
+        assert!(matches!(cli.command, Command::Spk{ command: SpkCommand::Pack{ ref dir }, .. } if dir.as_os_str() == "data"));
+        let cli = Cli::try_parse_from([ "qfg5", "qgm", "160.qgm", "list", "--speakers", "speakers.csv", "--speaker", "Erasmus" ]).unwrap();
+        assert!(matches!(cli.command, Command::Qgm{ command: QgmCommand::List{ speakers: Some(_), speaker: Some(ref speaker), .. }, .. } if speaker == "Erasmus"));
+        let cli = Cli::try_parse_from([ "qfg5", "qgm", "160.qgm", "list", "--with-audio" ]).unwrap();
+        assert!(matches!(cli.command, Command::Qgm{ command: QgmCommand::List{ with_audio: Some(None), .. }, .. }));
+        let cli = Cli::try_parse_from([ "qfg5", "qgm", "160.qgm", "list", "--with-audio", "data/aud" ]).unwrap();
+        assert!(matches!(cli.command, Command::Qgm{ command: QgmCommand::List{ with_audio: Some(Some(_)), .. }, .. }));
+        let cli = Cli::try_parse_from([ "qfg5", "qgm", "160.qgm", "--lenient", "list" ]).unwrap();
+        assert!(matches!(cli.command, Command::Qgm{ lenient: true, .. }));
+        let cli = Cli::try_parse_from([ "qfg5", "qgm", "data/qgm", "validate", "mod/161.qgm" ]).unwrap();
+        assert!(matches!(cli.command, Command::Qgm{ command: QgmCommand::Validate{ ref other }, .. } if other.len() == 1));
+        let cli = Cli::try_parse_from([ "qfg5", "qgm", "160.qgm", "import-po", "de.po", "--out", "de.qgm" ]).unwrap();
+        assert!(matches!(cli.command, Command::Qgm{ command: QgmCommand::ImportPo{ ref po, .. }, .. } if po.as_os_str() == "de.po"));
+        let cli = Cli::try_parse_from([ "qfg5", "img", "-", "render", "--out", "-" ]).unwrap();
+        assert!(matches!(cli.command, Command::Img{ command: ImgCommand::Render{ nod: None, .. }, .. }));
+        let cli = Cli::try_parse_from([ "qfg5", "aud", "a.aud", "export", "--wav", "a.wav" ]).unwrap();
+        assert!(matches!(cli.command, Command::Aud{ command: AudCommand::Export{ .. }, .. }));
+        let cli = Cli::try_parse_from([ "qfg5", "convert-all", "data", "--out", "out", "--jobs", "4" ]).unwrap();
+        assert!(matches!(cli.command, Command::ConvertAll{ jobs: Some(4), force: false, thumbs: false, report: None, .. }));
+        let cli = Cli::try_parse_from([ "qfg5", "check", "data", "cdn.spk", "--report", "report.txt" ]).unwrap();
+        assert!(matches!(cli.command, Command::Check{ ref paths, jobs: None, output: ReportOutput{ report: Some(_), .. } } if paths.len() == 2));
+        assert!(Cli::try_parse_from([ "qfg5", "check" ]).is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_parse_json() {
+        let cli = Cli::try_parse_from([ "qfg5", "qgm", "a.qgm", "list", "--format", "json" ]).unwrap();
+        assert!(matches!(cli.command, Command::Qgm{ command: QgmCommand::List{ output: DumpOutput{ format: ReportFormat::Json, .. }, .. }, .. }));
+        assert!(Cli::try_parse_from([ "qfg5", "qgm", "a.qgm", "list", "--json" ]).is_err());
+        let cli = Cli::try_parse_from([ "qfg5", "qgm", "demo.qgm", "diff", "retail.qgm", "--format", "json" ]).unwrap();
+        assert!(matches!(cli.command, Command::Qgm{ command: QgmCommand::Diff{ format: ReportFormat::Json, verbose: false, .. }, .. }));
+        let cli = Cli::try_parse_from([ "qfg5", "check", "data", "--format", "json" ]).unwrap();
+        assert!(matches!(cli.command, Command::Check{ output: ReportOutput{ format: ReportFormat::Json, .. }, .. }));
+    }
+}
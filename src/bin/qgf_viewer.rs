@@ -0,0 +1,391 @@
+/*-
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * Copyright (c) 2024 Rink Springer <rink@rink.nu>
+ * For conditions of distribution and use, see LICENSE file
+ */
+use qfg5reenigne::{
+    threed::{quad, texture},
+    qfg5resource::qfg5qgf,
+};
+
+use anyhow::Result;
+use clap::Parser;
+use std::path::PathBuf;
+
+use winit::{
+    event::*,
+    event_loop::EventLoop,
+    keyboard::{KeyCode, PhysicalKey},
+    window::{Window, WindowBuilder},
+};
+use glyphon::{
+    Attrs, Buffer, Cache, Color, Family, FontSystem, Metrics, Resolution, Shaping, SwashCache,
+    TextArea, TextAtlas, TextBounds, TextRenderer, Viewport,
+};
+
+const PANGRAM: &str = "The quick brown fox jumps over the lazy dog.\nTHE QUICK BROWN FOX JUMPS OVER THE LAZY DOG!\n0123456789 ,.;:'\"?!()-+*/";
+const SHEET_CHARS_PER_LINE: u32 = 32;
+const MAX_SCALE: u32 = 4;
+const PREVIEW_TOP: f32 = 80.0;
+
+/// Previews a Quest for Glory 5 *.QGF font
+#[derive(Parser)]
+struct Cli {
+    /// Input QGF file
+    in_qgf: PathBuf,
+}
+
+struct State<'a> {
+    surface: wgpu::Surface<'a>,
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    config: wgpu::SurfaceConfiguration,
+    size: winit::dpi::PhysicalSize<u32>,
+    quad: quad::QuadRenderer,
+    qgf: qfg5qgf::QgfDecoder,
+    text: String,
+    alpha_3d: bool,
+    outline: bool,
+    scale: u32,
+    preview_size: (u32, u32),
+    preview_bind_group: Option<wgpu::BindGroup>,
+    // Font
+    font_system: FontSystem,
+    swash_cache: SwashCache,
+    viewport: glyphon::Viewport,
+    atlas: glyphon::TextAtlas,
+    text_renderer: glyphon::TextRenderer,
+    text_buffer: glyphon::Buffer,
+    // The window must be declared after the surface so
+    // it gets dropped after it as the surface contains
+    // unsafe references to the window's resources.
+    window: &'a Window,
+}
+
+/// Converts rendered glyphs to white text; with `outline`, background pixels next to a glyph turn black
+fn bitmap_to_rgba(qgf: &qfg5qgf::QgfDecoder, bitmap: &qfg5qgf::QgfBitmap, alpha_3d: bool, outline: bool) -> image::RgbaImage {
+    let value_at = |x: i64, y: i64| -> u8 {
+        if x < 0 || y < 0 || x >= bitmap.width as i64 || y >= bitmap.height as i64 { return 0; }
+        bitmap.pixels[(y as u32 * bitmap.width + x as u32) as usize]
+    };
+    image::RgbaImage::from_fn(bitmap.width, bitmap.height, |x, y| {
+        let (x, y) = (x as i64, y as i64);
+        let value = value_at(x, y);
+        if value != 0 {
+            let alpha = if alpha_3d { qgf.coverage(value) } else { 255 };
+            return image::Rgba([ 255, 255, 255, alpha ]);
+        }
+        if outline {
+            let touches_glyph = (-1..=1).any(|dy| (-1..=1).any(|dx| value_at(x + dx, y + dy) != 0));
+            if touches_glyph {
+                return image::Rgba([ 0, 0, 0, 255 ]);
+            }
+        }
+        image::Rgba([ 0, 0, 0, 0 ])
+    })
+}
+
+impl<'a> State<'a> {
+    async fn new(window: &'a Window, qgf: qfg5qgf::QgfDecoder) -> State<'a> {
+        let size = window.inner_size();
+
+        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+            backends: wgpu::Backends::PRIMARY,
+            ..Default::default()
+        });
+        let surface = instance.create_surface(window).unwrap();
+        let adapter = instance.request_adapter(
+            &wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::default(),
+                compatible_surface: Some(&surface),
+                force_fallback_adapter: false,
+            },
+        ).await.unwrap();
+        let (device, queue) = adapter.request_device(
+            &wgpu::DeviceDescriptor {
+                required_features: wgpu::Features::empty(),
+                required_limits: wgpu::Limits::default(),
+                label: None,
+                memory_hints: Default::default(),
+            },
+            None, // Trace path
+        ).await.unwrap();
+
+        let surface_caps = surface.get_capabilities(&adapter);
+        let surface_format = surface_caps.formats.iter()
+            .find(|f| f.is_srgb())
+            .copied()
+            .unwrap_or(surface_caps.formats[0]);
+        let config = wgpu::SurfaceConfiguration {
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+            format: surface_format,
+            width: size.width,
+            height: size.height,
+            present_mode: surface_caps.present_modes[0],
+            alpha_mode: surface_caps.alpha_modes[0],
+            view_formats: vec![],
+            desired_maximum_frame_latency: 2,
+        };
+
+        let quad = quad::QuadRenderer::new(&device, config.format);
+
+        let mut font_system = FontSystem::new();
+        let swash_cache = SwashCache::new();
+        let cache = Cache::new(&device);
+        let viewport = Viewport::new(&device, &cache);
+        let mut atlas = TextAtlas::new(&device, &queue, &cache, config.format);
+        let text_renderer =
+            TextRenderer::new(&mut atlas, &device, wgpu::MultisampleState::default(), None);
+        let mut text_buffer = Buffer::new(&mut font_system, Metrics::new(20.0, 26.0));
+        text_buffer.set_size(&mut font_system, Some(size.width as f32), Some(size.height as f32));
+
+        let alpha_3d = qgf.is_3d;
+        let mut result = Self {
+            window,
+            surface,
+            device,
+            queue,
+            config,
+            size,
+            quad,
+            qgf,
+            text: PANGRAM.to_string(),
+            alpha_3d,
+            outline: false,
+            scale: 2,
+            preview_size: (0, 0),
+            preview_bind_group: None,
+            font_system,
+            swash_cache,
+            viewport,
+            atlas,
+            text_renderer,
+            text_buffer,
+        };
+        result.update_preview();
+        result
+    }
+
+    pub fn window(&self) -> &Window {
+        self.window
+    }
+
+    pub fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
+        if new_size.width > 0 && new_size.height > 0 {
+            self.size = new_size;
+            self.config.width = new_size.width;
+            self.config.height = new_size.height;
+            self.surface.configure(&self.device, &self.config);
+            self.text_buffer.set_size(&mut self.font_system, Some(new_size.width as f32), Some(new_size.height as f32));
+            self.update_rect();
+        }
+    }
+
+    fn process_input(&mut self, event: &WindowEvent) -> bool {
+        let WindowEvent::KeyboardInput {
+            event: KeyEvent { state: ElementState::Pressed, physical_key, text, .. },
+            ..
+        } = event else {
+            return false;
+        };
+        match physical_key {
+            PhysicalKey::Code(KeyCode::F1) => {
+                self.alpha_3d = !self.alpha_3d;
+            },
+            PhysicalKey::Code(KeyCode::F2) => {
+                self.outline = !self.outline;
+            },
+            PhysicalKey::Code(KeyCode::F3) => {
+                self.scale = self.scale % MAX_SCALE + 1;
+            },
+            PhysicalKey::Code(KeyCode::Backspace) => {
+                self.text.pop();
+            },
+            PhysicalKey::Code(KeyCode::Enter | KeyCode::NumpadEnter) => {
+                self.text.push('\n');
+            },
+            PhysicalKey::Code(KeyCode::Delete) => {
+                self.text.clear();
+            },
+            _ => {
+                let Some(text) = text else { return false; };
+                let typed: String = text.chars().filter(|ch| !ch.is_control()).collect();
+                if typed.is_empty() { return false; }
+                self.text.push_str(&typed);
+            },
+        }
+        self.update_preview();
+        true
+    }
+
+    fn input(&mut self, event: &WindowEvent) -> bool {
+        self.process_input(event)
+    }
+
+    /// Renders the text and glyph sheet and refreshes the overlay
+    fn update_preview(&mut self) {
+        let text = self.qgf.render_text(&self.text);
+        let sheet = self.qgf.render_sheet(SHEET_CHARS_PER_LINE);
+        // Put the text above the glyph sheet, separated by a line of empty space
+        let gap = self.qgf.char_height;
+        let width = text.width.max(sheet.width).max(1);
+        let height = text.height + gap + sheet.height;
+        let mut preview = image::RgbaImage::new(width, height);
+        image::imageops::overlay(&mut preview, &bitmap_to_rgba(&self.qgf, &text, self.alpha_3d, self.outline), 0, 0);
+        image::imageops::overlay(&mut preview, &bitmap_to_rgba(&self.qgf, &sheet, self.alpha_3d, self.outline), 0, (text.height + gap) as i64);
+
+        let img = image::DynamicImage::ImageRgba8(preview);
+        let texture = texture::Texture::from_image(&self.device, &self.queue, &img, Some("qgf preview")).unwrap();
+        self.preview_bind_group = Some(self.quad.create_bind_group(&self.device, &texture.view));
+        self.preview_size = (width, height);
+
+        let overlay = format!("{} chars, max width {}, height {}, spacing {}{}\n3D alpha {} (F1), outline {} (F2), scale {}x (F3); type to edit, Delete clears",
+            self.qgf.chars.len(), self.qgf.max_char_width, self.qgf.char_height, self.qgf.char_space,
+            if self.qgf.is_3d { ", 3D" } else { "" },
+            if self.alpha_3d { "on" } else { "off" },
+            if self.outline { "on" } else { "off" },
+            self.scale);
+        self.text_buffer.set_text(&mut self.font_system, &overlay, Attrs::new().family(Family::SansSerif), Shaping::Advanced);
+        self.update_rect();
+    }
+
+    /// Places the preview below the overlay at the current scale
+    fn update_rect(&mut self) {
+        let width = (self.preview_size.0 * self.scale) as f32;
+        let height = (self.preview_size.1 * self.scale) as f32;
+        self.quad.set_rect(&self.queue, (self.config.width, self.config.height), 10.0, PREVIEW_TOP, width, height);
+    }
+
+    fn render(&mut self) -> Result<(), wgpu::SurfaceError> {
+        self.viewport.update(
+            &self.queue,
+            Resolution {
+                width: self.config.width,
+                height: self.config.height,
+            },
+        );
+        self.text_renderer
+            .prepare(
+                &self.device,
+                &self.queue,
+                &mut self.font_system,
+                &mut self.atlas,
+                &self.viewport,
+                [TextArea {
+                    buffer: &self.text_buffer,
+                    left: 10.0,
+                    top: 10.0,
+                    scale: 1.0,
+                    bounds: TextBounds {
+                        left: 0,
+                        top: 0,
+                        right: self.config.width as i32,
+                        bottom: PREVIEW_TOP as i32,
+                    },
+                    default_color: Color::rgb(255, 255, 255),
+                    custom_glyphs: &[],
+                }],
+                &mut self.swash_cache,
+            )
+            .unwrap();
+
+        let output = self.surface.get_current_texture()?;
+        let view = output.texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Render Encoder"),
+        });
+
+        {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Render Pass"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: &view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color {
+                            r: 0.1,
+                            g: 0.2,
+                            b: 0.3,
+                            a: 1.0,
+                        }),
+                        store: wgpu::StoreOp::Store,
+                    },
+                })],
+                depth_stencil_attachment: None,
+                occlusion_query_set: None,
+                timestamp_writes: None,
+            });
+
+            if let Some(bind_group) = &self.preview_bind_group {
+                self.quad.draw(&mut render_pass, bind_group);
+            }
+            self.text_renderer.render(&self.atlas, &self.viewport, &mut render_pass).unwrap();
+        }
+
+        self.queue.submit(std::iter::once(encoder.finish()));
+        output.present();
+        self.atlas.trim();
+        Ok(())
+    }
+}
+
+async fn run(qgf: qfg5qgf::QgfDecoder) -> Result<()> {
+    env_logger::init();
+    let event_loop = EventLoop::new()?;
+    let window = WindowBuilder::new().with_title("QGF viewer").build(&event_loop)?;
+
+    let mut state = State::new(&window, qgf).await;
+
+    event_loop.run(move |event, control_flow| {
+        match event {
+            Event::WindowEvent {
+                ref event,
+                window_id,
+            } if window_id == state.window().id() && !state.input(event) => {
+                match event {
+                    WindowEvent::CloseRequested
+                    | WindowEvent::KeyboardInput {
+                        event:
+                            KeyEvent {
+                                state: ElementState::Pressed,
+                                physical_key: PhysicalKey::Code(KeyCode::Escape),
+                                ..
+                            },
+                        ..
+                    } => control_flow.exit(),
+                    WindowEvent::Resized(physical_size) => {
+                        state.resize(*physical_size);
+                    },
+                    WindowEvent::RedrawRequested => {
+                        state.window().request_redraw();
+
+                        match state.render() {
+                            Ok(_) => {}
+                            Err(
+                                wgpu::SurfaceError::Lost | wgpu::SurfaceError::Outdated,
+                            ) => state.resize(state.size),
+                            Err(wgpu::SurfaceError::OutOfMemory) => {
+                                log::error!("OutOfMemory");
+                                control_flow.exit();
+                            }
+                            Err(wgpu::SurfaceError::Timeout) => {
+                                log::warn!("Surface timeout")
+                            }
+                        }
+                    },
+                    _ => {}
+                }
+            },
+            _ => {}
+        }
+    })?;
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let args = Cli::parse();
+    let qgf_data = std::fs::read(&args.in_qgf)?;
+    let qgf = qfg5qgf::QgfDecoder::new(&qgf_data)?;
+    pollster::block_on(run(qgf))
+}
@@ -0,0 +1,424 @@
+/*-
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * Copyright (c) 2024 Rink Springer <rink@rink.nu>
+ * For conditions of distribution and use, see LICENSE file
+ */
+use qfg5reenigne::{
+    export::uvmap,
+    threed::{quad, texture},
+    qfg5resource::room,
+};
+
+use anyhow::Result;
+use clap::Parser;
+use std::path::PathBuf;
+
+use winit::{
+    event::*,
+    event_loop::EventLoop,
+    keyboard::{KeyCode, PhysicalKey},
+    window::{Window, WindowBuilder},
+};
+use glyphon::{
+    Attrs, Buffer, Cache, Color, Family, FontSystem, Metrics, Resolution, Shaping, SwashCache,
+    TextArea, TextAtlas, TextBounds, TextRenderer, Viewport,
+};
+
+/// Displays a Quest for Glory 5 room background with its depth map and regions
+#[derive(Parser)]
+struct Cli {
+    /// Extracted data directory, containing img/, nod/, zzz/ and rgd/
+    data_dir: PathBuf,
+    /// Room number, i.e. 2000 for img/2000.img
+    room_id: u32,
+}
+
+/// Maps a depth value to a blue (near 0) - green - red (near 255) ramp
+fn depth_color(depth: u8) -> [ u8; 3 ] {
+    let t = depth as f32 / 255.0;
+    if t < 0.5 {
+        let t = t * 2.0;
+        [ 0, (t * 255.0) as u8, ((1.0 - t) * 255.0) as u8 ]
+    } else {
+        let t = (t - 0.5) * 2.0;
+        [ (t * 255.0) as u8, ((1.0 - t) * 255.0) as u8, 0 ]
+    }
+}
+
+fn blend(pixel: &mut image::Rgba<u8>, color: [ u8; 3 ], alpha: f32) {
+    for (channel, color) in pixel.0.iter_mut().zip(color) {
+        *channel = (*channel as f32 * (1.0 - alpha) + color as f32 * alpha) as u8;
+    }
+}
+
+struct State<'a> {
+    surface: wgpu::Surface<'a>,
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    config: wgpu::SurfaceConfiguration,
+    size: winit::dpi::PhysicalSize<u32>,
+    quad: quad::QuadRenderer,
+    room: room::Room,
+    show_depth: bool,
+    show_regions: bool,
+    // Region index per pixel, computed when the regions are first shown
+    region_map: Option<Vec<Option<usize>>>,
+    cursor: Option<(u32, u32)>,
+    room_bind_group: Option<wgpu::BindGroup>,
+    // Font
+    font_system: FontSystem,
+    swash_cache: SwashCache,
+    viewport: glyphon::Viewport,
+    atlas: glyphon::TextAtlas,
+    text_renderer: glyphon::TextRenderer,
+    text_buffer: glyphon::Buffer,
+    // The window must be declared after the surface so
+    // it gets dropped after it as the surface contains
+    // unsafe references to the window's resources.
+    window: &'a Window,
+}
+
+impl<'a> State<'a> {
+    async fn new(window: &'a Window, room: room::Room) -> State<'a> {
+        let size = window.inner_size();
+
+        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+            backends: wgpu::Backends::PRIMARY,
+            ..Default::default()
+        });
+        let surface = instance.create_surface(window).unwrap();
+        let adapter = instance.request_adapter(
+            &wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::default(),
+                compatible_surface: Some(&surface),
+                force_fallback_adapter: false,
+            },
+        ).await.unwrap();
+        let (device, queue) = adapter.request_device(
+            &wgpu::DeviceDescriptor {
+                required_features: wgpu::Features::empty(),
+                required_limits: wgpu::Limits::default(),
+                label: None,
+                memory_hints: Default::default(),
+            },
+            None, // Trace path
+        ).await.unwrap();
+
+        let surface_caps = surface.get_capabilities(&adapter);
+        let surface_format = surface_caps.formats.iter()
+            .find(|f| f.is_srgb())
+            .copied()
+            .unwrap_or(surface_caps.formats[0]);
+        let config = wgpu::SurfaceConfiguration {
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+            format: surface_format,
+            width: size.width,
+            height: size.height,
+            present_mode: surface_caps.present_modes[0],
+            alpha_mode: surface_caps.alpha_modes[0],
+            view_formats: vec![],
+            desired_maximum_frame_latency: 2,
+        };
+
+        let quad = quad::QuadRenderer::new(&device, config.format);
+
+        let mut font_system = FontSystem::new();
+        let swash_cache = SwashCache::new();
+        let cache = Cache::new(&device);
+        let viewport = Viewport::new(&device, &cache);
+        let mut atlas = TextAtlas::new(&device, &queue, &cache, config.format);
+        let text_renderer =
+            TextRenderer::new(&mut atlas, &device, wgpu::MultisampleState::default(), None);
+        let mut text_buffer = Buffer::new(&mut font_system, Metrics::new(18.0, 24.0));
+        text_buffer.set_size(&mut font_system, Some(size.width as f32), Some(size.height as f32));
+
+        let mut result = Self {
+            window,
+            surface,
+            device,
+            queue,
+            config,
+            size,
+            quad,
+            room,
+            show_depth: false,
+            show_regions: false,
+            region_map: None,
+            cursor: None,
+            room_bind_group: None,
+            font_system,
+            swash_cache,
+            viewport,
+            atlas,
+            text_renderer,
+            text_buffer,
+        };
+        result.update_image();
+        result
+    }
+
+    pub fn window(&self) -> &Window {
+        self.window
+    }
+
+    pub fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
+        if new_size.width > 0 && new_size.height > 0 {
+            self.size = new_size;
+            self.config.width = new_size.width;
+            self.config.height = new_size.height;
+            self.surface.configure(&self.device, &self.config);
+            self.text_buffer.set_size(&mut self.font_system, Some(new_size.width as f32), Some(new_size.height as f32));
+            self.update_rect();
+        }
+    }
+
+    fn width(&self) -> u32 { self.room.img.get_width() as u32 }
+    fn height(&self) -> u32 { self.room.img.get_height() as u32 }
+
+    fn process_input(&mut self, event: &WindowEvent) -> bool {
+        match event {
+            WindowEvent::KeyboardInput {
+                event: KeyEvent { state: ElementState::Pressed, physical_key: PhysicalKey::Code(keycode), .. },
+                ..
+            } => {
+                match keycode {
+                    KeyCode::KeyZ => self.show_depth = !self.show_depth,
+                    KeyCode::KeyR => self.show_regions = !self.show_regions,
+                    _ => return false,
+                }
+                self.update_image();
+                true
+            },
+            WindowEvent::CursorMoved { position, .. } => {
+                // The room is drawn at native size in the top-left corner
+                let (x, y) = (position.x as i64, position.y as i64);
+                self.cursor = if x >= 0 && y >= 0 && (x as u32) < self.width() && (y as u32) < self.height() {
+                    Some((x as u32, y as u32))
+                } else {
+                    None
+                };
+                self.update_text();
+                true
+            },
+            _ => false,
+        }
+    }
+
+    fn input(&mut self, event: &WindowEvent) -> bool {
+        self.process_input(event)
+    }
+
+    fn compute_region_map(&self) -> Vec<Option<usize>> {
+        let (width, height) = (self.width(), self.height());
+        let mut map = vec![ None; (width * height) as usize ];
+        if let Some(rgd) = &self.room.rgd {
+            for y in 0..height {
+                for x in 0..width {
+                    map[(y * width + x) as usize] = rgd.region_at(x as f64 + 0.5, y as f64 + 0.5);
+                }
+            }
+        }
+        map
+    }
+
+    /// Composes the background with the enabled overlays and uploads it
+    fn update_image(&mut self) {
+        let (width, height) = (self.width(), self.height());
+        let palette = self.room.nod.get_palette();
+        let pixels = self.room.img.get_pixels();
+        let mut rgba = image::RgbaImage::from_fn(width, height, |x, y| {
+            let (r, g, b) = palette[pixels[(y * width + x) as usize] as usize];
+            image::Rgba([ r, g, b, 255 ])
+        });
+
+        if self.show_depth {
+            let depth = self.room.zzz.get_pixels();
+            for (x, y, pixel) in rgba.enumerate_pixels_mut() {
+                blend(pixel, depth_color(depth[(y * width + x) as usize]), 0.6);
+            }
+        }
+
+        if self.show_regions && self.region_map.is_none() {
+            self.region_map = Some(self.compute_region_map());
+        }
+        if let (true, Some(rgd), Some(region_map)) = (self.show_regions, &self.room.rgd, &self.region_map) {
+            for (x, y, pixel) in rgba.enumerate_pixels_mut() {
+                if let Some(region) = region_map[(y * width + x) as usize] {
+                    if rgd.is_special(region) {
+                        blend(pixel, [ 0, 255, 0 ], 0.3);
+                    }
+                }
+            }
+            for region in &rgd.regions {
+                for segment in region.segment_ids.iter().filter_map(|id| rgd.segments.get(*id)) {
+                    let (Some(a), Some(b)) = (rgd.points.get(segment.point1), rgd.points.get(segment.point2)) else { continue };
+                    uvmap::draw_line(&mut rgba, (a.x as i64, a.y as i64), (b.x as i64, b.y as i64), image::Rgba([ 255, 255, 0, 255 ]), false);
+                }
+            }
+        }
+
+        let img = image::DynamicImage::ImageRgba8(rgba);
+        let texture = texture::Texture::from_image(&self.device, &self.queue, &img, Some("room")).unwrap();
+        self.room_bind_group = Some(self.quad.create_bind_group(&self.device, &texture.view));
+        self.update_rect();
+        self.update_text();
+    }
+
+    fn update_text(&mut self) {
+        let on_off = |b: bool| if b { "on" } else { "off" };
+        let mut text = format!("room {}: {}x{}\n[Z] depth {}, [R] regions {}{}",
+            self.room.id, self.width(), self.height(),
+            on_off(self.show_depth), on_off(self.show_regions),
+            if self.room.rgd.is_none() { " (no region data)" } else { "" });
+        if let Some((x, y)) = self.cursor {
+            let depth = self.room.zzz.get_pixels()[(y * self.width() + x) as usize];
+            let region = match self.room.rgd.as_ref().and_then(|rgd| rgd.region_at(x as f64 + 0.5, y as f64 + 0.5)) {
+                Some(index) => {
+                    let rgd = self.room.rgd.as_ref().unwrap();
+                    match rgd.region_ids.get(index) {
+                        Some(id) => format!("{} (id {})", index, id),
+                        None => format!("{}", index),
+                    }
+                },
+                None => "-".to_string(),
+            };
+            text = format!("{}\n({}, {}): depth {}, region {}", text, x, y, depth, region);
+        }
+        self.text_buffer.set_text(&mut self.font_system, &text, Attrs::new().family(Family::SansSerif), Shaping::Advanced);
+    }
+
+    fn update_rect(&mut self) {
+        let (width, height) = (self.width() as f32, self.height() as f32);
+        self.quad.set_rect(&self.queue, (self.config.width, self.config.height), 0.0, 0.0, width, height);
+    }
+
+    fn render(&mut self) -> Result<(), wgpu::SurfaceError> {
+        self.viewport.update(
+            &self.queue,
+            Resolution {
+                width: self.config.width,
+                height: self.config.height,
+            },
+        );
+        self.text_renderer
+            .prepare(
+                &self.device,
+                &self.queue,
+                &mut self.font_system,
+                &mut self.atlas,
+                &self.viewport,
+                [TextArea {
+                    buffer: &self.text_buffer,
+                    left: 10.0,
+                    top: 10.0,
+                    scale: 1.0,
+                    bounds: TextBounds {
+                        left: 0,
+                        top: 0,
+                        right: self.config.width as i32,
+                        bottom: self.config.height as i32,
+                    },
+                    default_color: Color::rgb(255, 255, 255),
+                    custom_glyphs: &[],
+                }],
+                &mut self.swash_cache,
+            )
+            .unwrap();
+
+        let output = self.surface.get_current_texture()?;
+        let view = output.texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Render Encoder"),
+        });
+
+        {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Render Pass"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: &view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                        store: wgpu::StoreOp::Store,
+                    },
+                })],
+                depth_stencil_attachment: None,
+                occlusion_query_set: None,
+                timestamp_writes: None,
+            });
+
+            if let Some(bind_group) = &self.room_bind_group {
+                self.quad.draw(&mut render_pass, bind_group);
+            }
+            self.text_renderer.render(&self.atlas, &self.viewport, &mut render_pass).unwrap();
+        }
+
+        self.queue.submit(std::iter::once(encoder.finish()));
+        output.present();
+        self.atlas.trim();
+        Ok(())
+    }
+}
+
+async fn run(room: room::Room) -> Result<()> {
+    let event_loop = EventLoop::new()?;
+    let size = winit::dpi::PhysicalSize::new(room.img.get_width() as u32, room.img.get_height() as u32);
+    let window = WindowBuilder::new()
+        .with_title(format!("Room {}", room.id))
+        .with_inner_size(size)
+        .build(&event_loop)?;
+
+    let mut state = State::new(&window, room).await;
+
+    event_loop.run(move |event, control_flow| {
+        match event {
+            Event::WindowEvent {
+                ref event,
+                window_id,
+            } if window_id == state.window().id() && !state.input(event) => {
+                match event {
+                    WindowEvent::CloseRequested
+                    | WindowEvent::KeyboardInput {
+                        event:
+                            KeyEvent {
+                                state: ElementState::Pressed,
+                                physical_key: PhysicalKey::Code(KeyCode::Escape),
+                                ..
+                            },
+                        ..
+                    } => control_flow.exit(),
+                    WindowEvent::Resized(physical_size) => {
+                        state.resize(*physical_size);
+                    },
+                    WindowEvent::RedrawRequested => {
+                        state.window().request_redraw();
+                        match state.render() {
+                            Ok(_) => {}
+                            Err(
+                                wgpu::SurfaceError::Lost | wgpu::SurfaceError::Outdated,
+                            ) => state.resize(state.size),
+                            Err(wgpu::SurfaceError::OutOfMemory) => {
+                                log::error!("OutOfMemory");
+                                control_flow.exit();
+                            }
+                            Err(wgpu::SurfaceError::Timeout) => {
+                                log::warn!("Surface timeout")
+                            }
+                        }
+                    },
+                    _ => {}
+                }
+            },
+            _ => {}
+        }
+    })?;
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    env_logger::init();
+    let args = Cli::parse();
+    let room = room::Room::load(&args.data_dir, args.room_id)?;
+    pollster::block_on(run(room))
+}
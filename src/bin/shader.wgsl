@@ -9,6 +9,8 @@ struct InstanceInput {
     @location(6) model_matrix_1: vec4<f32>,
     @location(7) model_matrix_2: vec4<f32>,
     @location(8) model_matrix_3: vec4<f32>,
+    // Blended over the texture by its alpha, used to highlight submeshes
+    @location(9) tint: vec4<f32>,
 };
 
 // Vertex shader
@@ -21,19 +23,24 @@ var<uniform> camera: CameraUniform;
 struct VertexInput {
     @location(0) position: vec3<f32>,
     @location(1) tex_coords: vec2<f32>,
+    @location(2) normal: vec3<f32>,
+}
+
+// Optional per-vertex color, in a separate vertex buffer
+struct ColorInput {
+    @location(3) color: vec4<f32>,
 }
 
 struct VertexOutput {
     @builtin(position) clip_position: vec4<f32>,
     @location(0) tex_coords: vec2<f32>,
+    @location(1) tint: vec4<f32>,
+    @location(2) color: vec4<f32>,
+    @location(3) normal: vec3<f32>,
 }
 
-@vertex
-fn vs_main(
-    model: VertexInput,
-    instance: InstanceInput,
-) -> VertexOutput {
-     let model_matrix = mat4x4<f32>(
+fn transform_vertex(model: VertexInput, instance: InstanceInput) -> VertexOutput {
+    let model_matrix = mat4x4<f32>(
         instance.model_matrix_0,
         instance.model_matrix_1,
         instance.model_matrix_2,
@@ -42,10 +49,33 @@ fn vs_main(
 
     var out: VertexOutput;
     out.tex_coords = model.tex_coords;
+    out.tint = instance.tint;
+    out.color = vec4<f32>(1.0);
+    // The models are only rotated and translated, so this keeps normals perpendicular
+    out.normal = (model_matrix * vec4<f32>(model.normal, 0.0)).xyz;
     out.clip_position = camera.view_proj * model_matrix * vec4<f32>(model.position, 1.0);
     return out;
 }
 
+@vertex
+fn vs_main(
+    model: VertexInput,
+    instance: InstanceInput,
+) -> VertexOutput {
+    return transform_vertex(model, instance);
+}
+
+@vertex
+fn vs_color(
+    model: VertexInput,
+    instance: InstanceInput,
+    color: ColorInput,
+) -> VertexOutput {
+    var out = transform_vertex(model, instance);
+    out.color = color.color;
+    return out;
+}
+
 // Fragment shader
 
 @group(0) @binding(0)
@@ -53,7 +83,42 @@ var t_diffuse: texture_2d<f32>;
 @group(0) @binding(1)
 var s_diffuse: sampler;
 
+// Directional light, see threed::light
+struct LightUniform {
+    // Towards the light
+    direction: vec3<f32>,
+    ambient: f32,
+    color: vec3<f32>,
+    // 0.0 renders unlit
+    enabled: f32,
+}
+@group(2) @binding(0)
+var<uniform> light: LightUniform;
+
+fn apply_light(color: vec4<f32>, normal: vec3<f32>) -> vec4<f32> {
+    let diffuse = max(dot(normalize(normal), light.direction), 0.0);
+    let lit = color.rgb * light.color * (light.ambient + (1.0 - light.ambient) * diffuse);
+    return vec4<f32>(mix(color.rgb, lit, light.enabled), color.a);
+}
+
+fn apply_tint(color: vec4<f32>, tint: vec4<f32>) -> vec4<f32> {
+    return vec4<f32>(mix(color.rgb, tint.rgb, tint.a), color.a);
+}
+
 @fragment
 fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
-    return textureSample(t_diffuse, s_diffuse, in.tex_coords);
+    let color = textureSample(t_diffuse, s_diffuse, in.tex_coords);
+    return apply_tint(apply_light(color, in.normal), in.tint);
+}
+
+// Only the vertex color; its alpha is not shown
+@fragment
+fn fs_color(in: VertexOutput) -> @location(0) vec4<f32> {
+    return apply_tint(apply_light(vec4<f32>(in.color.rgb, 1.0), in.normal), in.tint);
+}
+
+@fragment
+fn fs_combined(in: VertexOutput) -> @location(0) vec4<f32> {
+    let color = textureSample(t_diffuse, s_diffuse, in.tex_coords);
+    return apply_tint(apply_light(vec4<f32>(color.rgb * in.color.rgb, color.a), in.normal), in.tint);
 }
@@ -4,12 +4,13 @@
  * Copyright (c) 2024 Rink Springer <rink@rink.nu>
  * For conditions of distribution and use, see LICENSE file
  */
-use std::path::{Path, PathBuf};
-use std::io::Write;
-use anyhow::Result;
+use std::path::PathBuf;
+use anyhow::{anyhow, Result};
 use std::fs::File;
+use std::io::IsTerminal;
 use clap::{Parser, Subcommand};
 
+use qfg5reenigne::commands;
 use qfg5reenigne::qfg5resource::qfg5spk;
 
 #[derive(Subcommand)]
@@ -18,59 +19,132 @@ enum CliCommands {
     Extract {
         /// Output directory
         out_dir: PathBuf,
+        /// Only extract resources whose name matches this pattern, such as *.qgm
+        #[arg(long)]
+        filter: Option<String>,
+        /// Do not show which resource is being extracted
+        #[arg(long)]
+        quiet: bool,
+        /// Keep the case of the names in the archive instead of using lowercase
+        #[arg(long)]
+        keep_case: bool,
+    },
+    /// Extracts a single resource
+    ExtractOne {
+        /// Name of the resource, such as MDL/HERO.MDL; the directory can be left out
+        name: String,
+        /// Output file
+        out_file: PathBuf,
+    },
+    /// Writes a single resource as it is to stdout
+    Cat {
+        /// Name of the resource, such as IMG/2000.IMG; the directory can be left out
+        name: String,
+    },
+    /// Checks the CRC of every resource, failing if any differs
+    Verify,
+    /// Writes a copy of the SPK file with one resource replaced by the contents of a file
+    Replace {
+        /// Name of the resource, such as QGM/160.QGM; the directory can be left out
+        name: String,
+        /// File with the new contents
+        file: PathBuf,
+        /// Output SPK file
+        out_spk: PathBuf,
+    },
+    /// Packs the files below a directory into the SPK file, which is created
+    Pack {
+        /// Directory with the resources, such as an extracted archive
+        dir: PathBuf,
     },
     /// Lists all resources
-    List,
+    List {
+        /// Only list resources whose name matches this pattern, such as *.qgm
+        #[arg(long)]
+        filter: Option<String>,
+        #[arg(long, value_enum, default_value_t)]
+        format: ListFormat,
+    },
+}
+
+#[derive(Clone, Copy, Default, clap::ValueEnum)]
+enum ListFormat {
+    #[default]
+    Text,
+    /// Needs the serde feature
+    #[cfg(feature = "serde")]
+    Json,
+    Csv,
 }
 
-/// Extracts Quest for Glory 5 resources from *.SPK to individual files
+/// Extracts Quest for Glory 5 resources from *.SPK to individual files; superseded by `qfg5 spk`
 #[derive(Parser)]
 struct Cli {
-    /// Input SPK file
+    /// Input SPK file, or the output for pack
     in_spk: PathBuf,
     #[command(subcommand)]
     command: Option<CliCommands>
 }
 
-fn list(archive: &qfg5spk::SpkArchive) -> Result<()> {
-    for item in archive.get_items() {
-        println!("  {:>20}, {:>8} bytes @ offset 0x{:x}", item.filename, item.length, item.offset);
-    }
-    println!("{} total", archive.get_items().len());
-    Ok(())
-}
-
-fn extract(out_dir: &Path, archive: &qfg5spk::SpkArchive) -> Result<()> {
-    for item in archive.get_items() {
-        let data = archive.read_item(item)?;
-
-        let lower_filename = item.filename.to_lowercase();
-        let path = Path::new(&lower_filename);
-
-        let dirname = out_dir.join(path.parent().unwrap());
-        let filename = path.file_name().unwrap();
-        std::fs::create_dir_all(&dirname)?;
-
-        let out_path = dirname.join(filename);
-        let mut ff = File::create(out_path)?;
-        ff.write(&data)?;
-    }
-    Ok(())
-}
-
 fn main() -> Result<()> {
     let args = Cli::parse();
+    match &args.command {
+        Some(CliCommands::Pack { dir }) => {
+            commands::spk::pack(dir, &args.in_spk)?;
+            return Ok(());
+        },
+        Some(CliCommands::Replace { name, file, out_spk }) => {
+            commands::spk::replace(&args.in_spk, name, file, out_spk)?;
+            return Ok(());
+        },
+        _ => { }
+    }
     let f = File::open(args.in_spk)?;
     let archive = qfg5spk::SpkArchive::new(f)?;
+    if matches!(args.command, Some(CliCommands::List { .. } | CliCommands::Extract { .. } | CliCommands::ExtractOne { .. })) {
+        for diagnostic in archive.diagnostics() {
+            eprintln!("warning: {}", diagnostic);
+        }
+    }
 
     match &args.command {
-        Some(CliCommands::Extract { out_dir }) => {
-            extract(&out_dir, &archive)?;
+        Some(CliCommands::Extract { out_dir, filter, quiet, keep_case }) => {
+            // On a terminal the line is overwritten for every resource
+            let terminal = std::io::stderr().is_terminal();
+            let progress = |item: &qfg5spk::SpkItem, n: usize, total: usize| if !quiet {
+                let line = format!("[{}/{}] {}", n + 1, total, item.filename.to_lowercase());
+                if terminal { eprint!("\r\x1b[K{}", line) } else { eprintln!("{}", line) }
+            };
+            let case = if *keep_case { qfg5spk::FileNameCase::Original } else { qfg5spk::FileNameCase::Lowercase };
+            let options = commands::spk::ExtractOptions{ convert: false, filter: filter.as_deref(), case };
+            let count = commands::spk::extract(&archive, out_dir, &options, progress)?;
+            if terminal && !quiet && count > 0 {
+                eprintln!();
+            }
+        },
+        Some(CliCommands::ExtractOne { name, out_file }) => {
+            commands::spk::extract_one(&archive, name, out_file)?;
+        },
+        Some(CliCommands::Verify) => {
+            let failed = commands::spk::verify(&archive, &mut std::io::stdout())?;
+            if failed > 0 {
+                return Err(anyhow!("{} items failed verification", failed));
+            }
+        },
+        Some(CliCommands::Cat { name }) => {
+            commands::spk::cat(&archive, name, &mut std::io::stdout().lock())?;
         },
-        Some(CliCommands::List) => {
-            list(&archive)?;
+        Some(CliCommands::List { filter, format }) => {
+            let filter = filter.as_deref();
+            let w = &mut std::io::stdout().lock();
+            match format {
+                ListFormat::Text => commands::spk::list(&archive, filter, w)?,
+                #[cfg(feature = "serde")]
+                ListFormat::Json => commands::write_json(&commands::spk::filter(&archive, filter), w)?,
+                ListFormat::Csv => commands::spk::list_csv(&archive, filter, w)?,
+            }
         },
-        None => { }
+        Some(CliCommands::Pack { .. } | CliCommands::Replace { .. }) | None => { }
     }
     Ok(())
 }
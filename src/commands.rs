@@ -0,0 +1,166 @@
+/*-
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * Copyright (c) 2024 Rink Springer <rink@rink.nu>
+ * For conditions of distribution and use, see LICENSE file
+ */
+//! The implementation of the `qfg5` command line tool. Text output is written to any `Write`
+//! so that it can go to stdout or a file (--out); images are written in the --format given.
+//! A path of `-` reads input from stdin or writes output to stdout, for use in pipelines.
+use anyhow::{anyhow, Context, Result};
+use std::io::Write;
+use std::path::Path;
+use crate::qfg5resource::{self, detect, Confidence, Qfg5Resource, ResourceKind};
+
+pub mod anm;
+pub mod aud;
+pub mod check;
+pub mod convert;
+pub mod gra;
+pub mod identify;
+pub mod img;
+pub mod inspect;
+pub mod mdl;
+pub mod qgf;
+pub mod qgm;
+pub mod rgd;
+pub mod room;
+pub mod spk;
+
+/// File format of images written by commands
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+pub enum ImageFormat {
+    #[default]
+    Png,
+    /// 24-bit, so transparency is lost
+    Bmp,
+}
+
+impl ImageFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Bmp => "bmp",
+        }
+    }
+}
+
+pub fn save_image(img: &image::RgbaImage, path: &Path, format: ImageFormat) -> Result<()> {
+    let mut w = binary_output(path)?;
+    match format {
+        ImageFormat::Png => {
+            // The encoder needs to seek, which stdout cannot
+            let mut png = std::io::Cursor::new(Vec::new());
+            img.write_to(&mut png, image::ImageFormat::Png)?;
+            w.write_all(png.get_ref())?;
+        },
+        ImageFormat::Bmp => {
+            let mut bmp = bmp::Image::new(img.width(), img.height());
+            for (x, y, pixel) in img.enumerate_pixels() {
+                bmp.set_pixel(x, y, bmp::Pixel::new(pixel[0], pixel[1], pixel[2]));
+            }
+            bmp.to_writer(&mut w)?;
+        },
+    }
+    w.flush()?;
+    Ok(())
+}
+
+/// Whether `path` is `-`, which stands for stdin or stdout
+pub fn is_stdio(path: &Path) -> bool {
+    path.as_os_str() == "-"
+}
+
+/// Binary output goes to `path`, or to stdout if it is `-`. Rust writes bytes to stdout as they
+/// are on every platform, so nothing needs to be switched to binary mode on Windows.
+pub fn binary_output(path: &Path) -> Result<Box<dyn Write>> {
+    if is_stdio(path) {
+        return Ok(Box::new(std::io::stdout().lock()));
+    }
+    Ok(Box::new(std::io::BufWriter::new(std::fs::File::create(path)
+        .with_context(|| format!("cannot create {}", path.display()))?)))
+}
+
+/// Text output goes to `out` if given, otherwise to stdout
+pub fn text_output(out: Option<&Path>) -> Result<Box<dyn Write>> {
+    binary_output(out.unwrap_or(Path::new("-")))
+}
+
+/// Writes `value` as pretty-printed JSON
+#[cfg(feature = "serde")]
+pub fn write_json<T: serde::Serialize + ?Sized>(value: &T, w: &mut dyn Write) -> Result<()> {
+    serde_json::to_writer_pretty(&mut *w, value)?;
+    writeln!(w)?;
+    Ok(())
+}
+
+/// Reads an input file, or all of stdin if `path` is `-`, mentioning its name on failure
+pub fn read_input(path: &Path) -> Result<Vec<u8>> {
+    if is_stdio(path) {
+        let mut data = Vec::new();
+        std::io::Read::read_to_end(&mut std::io::stdin().lock(), &mut data).context("cannot read stdin")?;
+        return Ok(data);
+    }
+    std::fs::read(path).with_context(|| format!("cannot read {}", path.display()))
+}
+
+/// Decodes `data` as a resource of the given kind. Data that clearly is another kind of resource
+/// is rejected up front, as the decoders do not validate everything; decoding errors mention what
+/// the data looks like instead.
+pub fn decode_as<T>(data: &[u8], kind: ResourceKind, decode: impl FnOnce(&[u8]) -> qfg5resource::Result<T>) -> Result<T> {
+    let detection = detect(data);
+    if let Some(detection) = detection.filter(|d| d.kind != kind && d.confidence == Confidence::High) {
+        return Err(anyhow!("this is {} data, not {}", detection.kind, kind));
+    }
+    decode(data).map_err(anyhow::Error::from).map_err(|e| match detection {
+        Some(detection) if detection.kind != kind => e.context(format!("cannot decode as {}; it looks like {} ({} confidence)",
+            kind, detection.kind, detection.confidence)),
+        Some(_) => e.context(format!("cannot decode {}", kind)),
+        None => e.context(format!("cannot decode as {}; it does not look like any known resource", kind)),
+    })
+}
+
+/// Decodes `data` as a `T`, see decode_as()
+pub fn decode<T: Qfg5Resource>(data: &[u8]) -> Result<T> {
+    decode_as(data, T::KIND, T::parse)
+}
+
+/// Reads and decodes an input file, see decode_as()
+pub fn decode_input<T>(path: &Path, kind: ResourceKind, decode: impl FnOnce(&[u8]) -> qfg5resource::Result<T>) -> Result<T> {
+    decode_as(&read_input(path)?, kind, decode).with_context(|| path.display().to_string())
+}
+
+/// A CSV field as RFC 4180 has it: quoted if it has a separator, quote or line break, with
+/// quotes doubled
+fn csv_field(s: &str) -> std::borrow::Cow<'_, str> {
+    if s.contains([ ',', '"', '\n', '\r' ]) {
+        format!("\"{}\"", s.replace('"', "\"\"")).into()
+    } else {
+        s.into()
+    }
+}
+
+#[cfg(test)]
+fn test_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("qfg5-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qfg5resource::{fixtures, qfg5anm, qfg5mdl};
+
+    #[test]
+    fn test_decode_as() {
+        assert!(decode_as(&fixtures::anm(), ResourceKind::Anm, qfg5anm::AnmDecoder::new).is_ok());
+        let err = decode_as(&fixtures::anm(), ResourceKind::Mdl, qfg5mdl::Qfg5Model::new).err().unwrap();
+        assert_eq!(err.to_string(), "this is ANM data, not MDL");
+        let err = decode_as(&fixtures::gra(), ResourceKind::Anm, qfg5anm::AnmDecoder::new).err().unwrap();
+        assert_eq!(err.to_string(), "cannot decode as ANM; it looks like GRA (medium confidence)");
+        let err = decode_as(b"", ResourceKind::Anm, qfg5anm::AnmDecoder::new).err().unwrap();
+        assert_eq!(err.to_string(), "cannot decode as ANM; it does not look like any known resource");
+    }
+}
@@ -0,0 +1,52 @@
+/*-
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * Copyright (c) 2024 Rink Springer <rink@rink.nu>
+ * For conditions of distribution and use, see LICENSE file
+ */
+use anyhow::Result;
+use std::io::Write;
+use crate::qfg5resource::qfg5anm;
+
+pub fn dump(anm: &qfg5anm::AnmDecoder, w: &mut dyn Write) -> Result<()> {
+    writeln!(w, "animation '{}' delay {}", anm.name, anm.delay)?;
+    writeln!(w, "  {} animations, {} blocks each", anm.anims.len(), anm.num_frames())?;
+    for (n, anim) in anm.anims.iter().enumerate() {
+        writeln!(w, "  animation {}", n)?;
+        for (n, block) in anim.blocks.iter().enumerate() {
+            let r = &block.rotation;
+            writeln!(w, "    block {}: translation {}, {}, {}", n, block.translation[0], block.translation[1], block.translation[2])?;
+            writeln!(w, "    rotation:")?;
+            for row in r.chunks(3) {
+                writeln!(w, "      {:>20}, {:>20}, {:>20}", row[0], row[1], row[2])?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qfg5resource::fixtures;
+
+    #[test]
+    fn test_dump() {
+        let anm = qfg5anm::AnmDecoder::new(&fixtures::anm()).unwrap();
+        let mut out = Vec::new();
+        dump(&anm, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("animation 'fixture' delay 100\n  1 animations, 2 blocks each\n"));
+        assert!(out.contains("    block 1: translation 1, 0, 0\n"));
+        assert!(out.contains("\n                         1,                    0,                    0\n"));
+
+        // A name with a Latin-1 character and something after its zero byte
+        let mut data = fixtures::anm();
+        data[8 + 6] = 0xe9;
+        data[8 + 10] = b'x';
+        let anm = qfg5anm::AnmDecoder::new(&data).unwrap();
+        let mut out = Vec::new();
+        dump(&anm, &mut out).unwrap();
+        assert!(String::from_utf8(out).unwrap().starts_with("animation 'fixtur\u{e9}' delay 100\n"));
+    }
+}
@@ -0,0 +1,53 @@
+/*-
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * Copyright (c) 2024 Rink Springer <rink@rink.nu>
+ * For conditions of distribution and use, see LICENSE file
+ */
+use anyhow::Result;
+use std::io::Write;
+use std::path::Path;
+use crate::qfg5resource::qfg5aud;
+
+pub fn dump(aud: &qfg5aud::AudDecoder, w: &mut dyn Write) -> Result<()> {
+    let codec = match aud.codec {
+        qfg5aud::AudCodec::Pcm => "PCM",
+        qfg5aud::AudCodec::ImaAdpcm => "IMA ADPCM",
+    };
+    writeln!(w, "{}, {} Hz, {} channels, {} bits per sample", codec, aud.sample_rate, aud.channels, aud.bits_per_sample)?;
+    writeln!(w, "  {} samples, {:.3} seconds", aud.num_frames(), aud.duration().as_secs_f64())?;
+    Ok(())
+}
+
+/// Writes the sound as 16-bit PCM
+pub fn export_wav(aud: &qfg5aud::AudDecoder, path: &Path) -> Result<()> {
+    let mut w = super::binary_output(path)?;
+    aud.write_wav(&mut w)?;
+    w.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qfg5resource::fixtures;
+
+    #[test]
+    fn test_dump() {
+        let aud = qfg5aud::AudDecoder::new(&fixtures::aud()).unwrap();
+        let mut out = Vec::new();
+        dump(&aud, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "IMA ADPCM, 22050 Hz, 1 channels, 4 bits per sample\n  17 samples, 0.001 seconds\n");
+    }
+
+    #[test]
+    fn test_export_wav() {
+        let dir = crate::commands::test_dir("aud");
+        let aud = qfg5aud::AudDecoder::new(&fixtures::aud()).unwrap();
+        let path = dir.join("out.wav");
+        export_wav(&aud, &path).unwrap();
+        let wav = qfg5aud::AudDecoder::new(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(wav.samples, aud.samples);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,244 @@
+/*-
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * Copyright (c) 2024 Rink Springer <rink@rink.nu>
+ * For conditions of distribution and use, see LICENSE file
+ */
+//! Decodes every resource of an extracted data tree, or of SPK archives, and runs the checks of
+//! the decoders on it, so that a change to a decoder can be tried against all of the game data.
+//! The items of SPK archives are checked without extracting them.
+//!
+//! The kind of a resource is taken from its extension, so that a file that does not decode as
+//! what it is named fails instead of being skipped; files without a known extension are detected.
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use crate::qfg5resource::{detect, qfg5spk, registration, ResourceKind};
+
+pub struct CheckOptions {
+    /// Number of threads
+    pub jobs: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize), serde(rename_all = "lowercase"))]
+pub enum Status {
+    Pass,
+    /// Decoded, but the checks found problems
+    Warn,
+    /// Could not be read or decoded
+    Fail,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", match self {
+            Status::Pass => "pass",
+            Status::Warn => "warn",
+            Status::Fail => "fail",
+        })
+    }
+}
+
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct FileResult {
+    /// Items of an archive are named after the archive, followed by '/' and the item name
+    pub path: String,
+    /// Kind of resource, or "SPK" for an archive that cannot be read
+    pub kind: String,
+    pub status: Status,
+    /// The error, or the problems found by the checks
+    pub messages: Vec<String>,
+}
+
+impl FileResult {
+    fn failed(path: String, kind: &str, e: anyhow::Error) -> Self {
+        FileResult{ path, kind: kind.to_string(), status: Status::Fail, messages: vec![ format!("{:#}", e) ] }
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Counts {
+    pub pass: usize,
+    pub warn: usize,
+    pub fail: usize,
+}
+
+#[derive(Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Report {
+    /// Sorted by path
+    pub files: Vec<FileResult>,
+    /// Per kind of resource
+    pub counts: BTreeMap<String, Counts>,
+    /// Files that are not a resource, or one that cannot be decoded on its own
+    pub skipped: usize,
+}
+
+impl Report {
+    pub fn failed(&self) -> usize {
+        self.counts.values().map(|counts| counts.fail).sum()
+    }
+
+    pub fn write(&self, w: &mut dyn Write) -> Result<()> {
+        for file in &self.files {
+            writeln!(w, "{} {} ({})", file.status, file.path, file.kind)?;
+            for message in &file.messages {
+                writeln!(w, "  {}", message)?;
+            }
+        }
+        writeln!(w, "checked {} files, {} skipped", self.files.len(), self.skipped)?;
+        for (kind, counts) in &self.counts {
+            writeln!(w, "  {}: {} passed, {} with warnings, {} failed", kind, counts.pass, counts.warn, counts.fail)?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks `paths`, which are directories or SPK archives
+pub fn check_all(paths: &[PathBuf], options: &CheckOptions) -> Result<Report> {
+    let mut files = Vec::new();
+    for path in paths {
+        if path.is_dir() {
+            let mut found = Vec::new();
+            super::convert::walk(path, Path::new(""), None, &mut found)?;
+            files.extend(found.iter().map(|file| path.join(file)));
+        } else {
+            files.push(path.clone());
+        }
+    }
+
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new((Vec::new(), 0));
+    std::thread::scope(|scope| {
+        for _ in 0..options.jobs.clamp(1, files.len().max(1)) {
+            scope.spawn(|| {
+                while let Some(file) = files.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    let (checked, skipped) = check_file(file);
+                    let mut results = results.lock().unwrap();
+                    results.0.extend(checked);
+                    results.1 += skipped;
+                }
+            });
+        }
+    });
+
+    let (mut files, skipped) = results.into_inner().unwrap();
+    files.sort_by(|a: &FileResult, b| a.path.cmp(&b.path));
+    let mut report = Report{ skipped, ..Default::default() };
+    for file in &files {
+        let counts = report.counts.entry(file.kind.clone()).or_default();
+        match file.status {
+            Status::Pass => counts.pass += 1,
+            Status::Warn => counts.warn += 1,
+            Status::Fail => counts.fail += 1,
+        }
+    }
+    report.files = files;
+    Ok(report)
+}
+
+/// Checks a file, or every item of an archive; returns the results and the number skipped
+fn check_file(path: &Path) -> (Vec<FileResult>, usize) {
+    let name = path.display().to_string();
+    if path.extension().is_some_and(|e| e.eq_ignore_ascii_case("spk")) {
+        let archive = match open_archive(path) {
+            Ok(archive) => archive,
+            Err(e) => return (vec![ FileResult::failed(name, "SPK", e) ], 0),
+        };
+        let (mut results, mut skipped) = (Vec::new(), 0);
+        for item in archive.get_items() {
+            let item_name = format!("{}/{}", name, item.filename);
+            let result = match archive.item_data(item) {
+                Ok(data) => check_data(&item_name, &data),
+                Err(e) => Some(FileResult::failed(item_name, "SPK", e.into())),
+            };
+            match result {
+                Some(result) => results.push(result),
+                None => skipped += 1,
+            }
+        }
+        return (results, skipped);
+    }
+    let data = match std::fs::read(path) {
+        Ok(data) => data,
+        Err(e) => return (vec![ FileResult::failed(name, "other", e.into()) ], 0),
+    };
+    match check_data(&name, &data) {
+        Some(result) => (vec![ result ], 0),
+        None => (Vec::new(), 1),
+    }
+}
+
+fn open_archive(path: &Path) -> Result<qfg5spk::SpkArchive> {
+    Ok(qfg5spk::SpkArchive::new(std::fs::File::open(path)?)?)
+}
+
+/// Decodes and checks the resource `name`; None if it is not a resource that can be decoded on
+/// its own
+fn check_data(name: &str, data: &[u8]) -> Option<FileResult> {
+    let kind = Path::new(name).extension().and_then(|e| ResourceKind::from_extension(&e.to_string_lossy()))
+        .or_else(|| detect(data).map(|d| d.kind))?;
+    let registration = registration(kind)?;
+    let (status, messages) = match (registration.validate)(data) {
+        Ok(problems) if problems.is_empty() => (Status::Pass, problems),
+        Ok(problems) => (Status::Warn, problems),
+        Err(e) => (Status::Fail, vec![ e.to_string() ]),
+    };
+    log::debug!("{}: {}", name, status);
+    Some(FileResult{ path: name.to_string(), kind: kind.to_string(), status, messages })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qfg5resource::fixtures;
+
+    fn write(dir: &Path, file: &str, data: &[u8]) {
+        let path = dir.join(file);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, data).unwrap();
+    }
+
+    #[test]
+    fn test_check_all() {
+        let dir = crate::commands::test_dir("check");
+        let mdl = fixtures::mdl();
+        write(&dir, "mdl/1.mdl", &mdl);
+        write(&dir, "mdl/2.mdl", &mdl[..mdl.len() - 1]);
+        let mut qgm = fixtures::qgm();
+        qgm[17] = 0xff;
+        write(&dir, "qgm/3.qgm", &qgm);
+        write(&dir, "zzz/7.zzz", &fixtures::zzz());
+        write(&dir, "notes.txt", b"hello");
+        write(&dir, "extra.spk", &fixtures::spk(&[ ("ANM/1.ANM", &fixtures::anm()), ("README", b"hello") ]));
+
+        let report = check_all(std::slice::from_ref(&dir), &CheckOptions{ jobs: 2 }).unwrap();
+        let results: Vec<_> = report.files.iter()
+            .map(|file| (file.path.strip_prefix(&dir.display().to_string()).unwrap(), file.status)).collect();
+        assert_eq!(results, [ ("/extra.spk/ANM/1.ANM", Status::Pass), ("/mdl/1.mdl", Status::Pass), ("/mdl/2.mdl", Status::Fail),
+                              ("/qgm/3.qgm", Status::Warn) ]);
+        assert_eq!(report.skipped, 3);
+        assert_eq!(report.counts["MDL"], Counts{ pass: 1, warn: 0, fail: 1 });
+        assert_eq!(report.failed(), 1);
+        assert_eq!(report.files[3].messages, [ "message 0: ids [65281, 2, 3, 4] do not fit in a label" ]);
+
+        let mut out = Vec::new();
+        report.write(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains(&format!("fail {}/mdl/2.mdl (MDL)\n  truncated at offset ", dir.display())), "{}", out);
+        assert!(out.ends_with("checked 4 files, 3 skipped\n  ANM: 1 passed, 0 with warnings, 0 failed\n  \
+            MDL: 1 passed, 0 with warnings, 1 failed\n  QGM: 0 passed, 1 with warnings, 0 failed\n"), "{}", out);
+
+        // An archive on its own
+        let report = check_all(&[ dir.join("extra.spk") ], &CheckOptions{ jobs: 1 }).unwrap();
+        assert_eq!((report.files.len(), report.skipped), (1, 1));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
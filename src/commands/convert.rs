@@ -0,0 +1,375 @@
+/*-
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * Copyright (c) 2024 Rink Springer <rink@rink.nu>
+ * For conditions of distribution and use, see LICENSE file
+ */
+//! Converts every resource of an extracted data tree into a mirrored output tree, in formats other
+//! tools read: sprites to a sheet and GIF animations, models to glTF, messages to JSON, room
+//! backgrounds and their depth maps to PNG, palettes to PNG, fonts to BMFont and regions to SVG.
+//! The other resources get the conversions of `qfg5 spk extract --convert`. SPK archives in the
+//! tree are converted into a directory named after the archive.
+//!
+//! Files are converted in parallel: every thread takes the next file as soon as it is done, so a
+//! few large files do not hold up the rest. Files whose outputs are newer than the file itself are
+//! skipped, so an interrupted run can be resumed.
+//!
+//! Optionally, thumbnails are written next to room backgrounds and model textures, for browsing
+//! the converted tree.
+use anyhow::{anyhow, Result};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+use crate::export::gltf;
+use crate::qfg5resource::{detect, names, qfg5gra, qfg5mdl, qfg5nod, qfg5qgf, qfg5rgd, qfg5spk, room::Room, ResourceKind};
+use super::{decode, ImageFormat};
+
+pub struct ConvertOptions {
+    /// Number of threads
+    pub jobs: usize,
+    /// Convert files even if their outputs are up to date
+    pub force: bool,
+    /// Also write thumbnails of room backgrounds and model textures, as `<name>-thumb.png`
+    pub thumbs: bool,
+}
+
+/// Longer side of the thumbnails, in pixels
+pub const THUMBNAIL_SIZE: u32 = 128;
+
+enum Outcome {
+    /// Kind of file and the number of files written
+    Converted(String, usize),
+    Failed(String, anyhow::Error),
+    UpToDate,
+    /// Not a resource, or one without a conversion
+    NotConverted,
+}
+
+#[derive(Debug, Default)]
+pub struct Summary {
+    /// Number of files converted and failed, per kind of file
+    pub counts: BTreeMap<String, (usize, usize)>,
+    pub written: usize,
+    pub up_to_date: usize,
+    pub not_converted: usize,
+    /// Input files that could not be converted, relative to the data directory, with the reason
+    pub failures: Vec<(PathBuf, String)>,
+    pub elapsed: Duration,
+}
+
+impl Summary {
+    pub fn write(&self, w: &mut dyn Write) -> Result<()> {
+        let converted: usize = self.counts.values().map(|(converted, _)| converted).sum();
+        writeln!(w, "converted {} files ({} files written) in {:.2}s", converted, self.written, self.elapsed.as_secs_f32())?;
+        for (kind, (converted, failed)) in &self.counts {
+            writeln!(w, "  {}: {} converted, {} failed", kind, converted, failed)?;
+        }
+        writeln!(w, "  {} up to date, {} not converted", self.up_to_date, self.not_converted)?;
+        if !self.failures.is_empty() {
+            writeln!(w, "{} failed:", self.failures.len())?;
+            for (path, error) in &self.failures {
+                writeln!(w, "  {}: {}", path.display(), error)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Converts all files in `data_dir` to `out_dir`, which may be inside `data_dir`
+pub fn convert_all(data_dir: &Path, out_dir: &Path, options: &ConvertOptions) -> Result<Summary> {
+    let start = Instant::now();
+    let mut files = Vec::new();
+    walk(data_dir, Path::new(""), out_dir.canonicalize().ok().as_deref(), &mut files)?;
+
+    let next = AtomicUsize::new(0);
+    let outcomes = Mutex::new(Vec::new());
+    std::thread::scope(|scope| {
+        for _ in 0..options.jobs.clamp(1, files.len().max(1)) {
+            scope.spawn(|| {
+                while let Some(file) = files.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    let outcome = convert_file(data_dir, file, &out_dir.join(file), options);
+                    outcomes.lock().unwrap().push((file, outcome));
+                }
+            });
+        }
+    });
+
+    let mut summary = Summary::default();
+    for (file, outcome) in outcomes.into_inner().unwrap() {
+        match outcome {
+            Outcome::Converted(kind, written) => {
+                summary.counts.entry(kind).or_default().0 += 1;
+                summary.written += written;
+            },
+            Outcome::Failed(kind, e) => {
+                log::warn!("cannot convert {}: {:#}", file.display(), e);
+                summary.counts.entry(kind).or_default().1 += 1;
+                summary.failures.push((file.clone(), format!("{:#}", e)));
+            },
+            Outcome::UpToDate => summary.up_to_date += 1,
+            Outcome::NotConverted => summary.not_converted += 1,
+        }
+    }
+    summary.failures.sort();
+    summary.elapsed = start.elapsed();
+    Ok(summary)
+}
+
+/// Collects the files below `dir` relative to the data directory, skipping directory `skip`
+pub(super) fn walk(base: &Path, dir: &Path, skip: Option<&Path>, files: &mut Vec<PathBuf>) -> Result<()> {
+    let mut entries = std::fs::read_dir(base.join(dir))?.collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let path = dir.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            if skip.is_none() || entry.path().canonicalize().ok().as_deref() != skip {
+                walk(base, &path, skip, files)?;
+            }
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn convert_file(data_dir: &Path, file: &Path, out_path: &Path, options: &ConvertOptions) -> Outcome {
+    let input = data_dir.join(file);
+    if !options.force && up_to_date(&input, out_path) {
+        log::debug!("{}: up to date", file.display());
+        return Outcome::UpToDate;
+    }
+    if out_path.extension().is_some_and(|e| e.eq_ignore_ascii_case("spk")) {
+        return match convert_archive(&input, &out_path.with_extension(""), options.thumbs) {
+            Ok(written) => Outcome::Converted("SPK".to_string(), written),
+            Err(e) => Outcome::Failed("SPK".to_string(), e),
+        };
+    }
+
+    let data = match std::fs::read(&input) {
+        Ok(data) => data,
+        Err(e) => return Outcome::Failed("other".to_string(), e.into()),
+    };
+    let Some(kind) = detect(&data).map(|d| d.kind) else {
+        return Outcome::NotConverted;
+    };
+    let written = match (kind, names::parse_room_id(file)) {
+        (ResourceKind::Img, Some(id)) => {
+            // The rest of the room is found next to the img/ directory
+            let room_dir = input.parent().and_then(Path::parent).unwrap_or(data_dir);
+            let out_dir = out_path.parent().unwrap_or(Path::new(""));
+            Room::load(room_dir, id).and_then(|room| {
+                let mut written = super::room::render(&room, true, out_dir, ImageFormat::Png)?;
+                if options.thumbs {
+                    written.push(super::room::thumbnail(&room, out_dir, THUMBNAIL_SIZE)?);
+                }
+                Ok(written)
+            })
+        },
+        _ => out_path.parent().map_or(Ok(()), std::fs::create_dir_all).map_err(anyhow::Error::from)
+            .and_then(|_| convert_data(&data, out_path, options.thumbs)),
+    };
+    match written {
+        Ok(written) if written.is_empty() => Outcome::NotConverted,
+        Ok(written) => {
+            log::info!("converted {}", file.display());
+            Outcome::Converted(kind.to_string(), written.len())
+        },
+        Err(e) => Outcome::Failed(kind.to_string(), e),
+    }
+}
+
+/// Converts a resource that is not a room background to the formats of the module description,
+/// next to `out_path`, and writes its thumbnails; returns the paths written
+fn convert_data(data: &[u8], out_path: &Path, thumbs: bool) -> Result<Vec<PathBuf>> {
+    let mut written = match detect(data).map(|d| d.kind) {
+        Some(ResourceKind::Gra) => {
+            let gra = decode::<qfg5gra::GraDecoder>(data)?;
+            let sheet_path = out_path.with_extension("png");
+            super::gra::export_sheet(&gra, None, &sheet_path, ImageFormat::Png)?;
+            let mut written = vec![ sheet_path ];
+            written.extend(super::gra::export_gif(&gra, None, &out_path.with_extension(""))?);
+            written
+        },
+        Some(ResourceKind::Mdl) => gltf::export(&decode::<qfg5mdl::Qfg5Model>(data)?, None, &out_path.with_extension("gltf"))?,
+        // Without serde, the messages are listed as text instead
+        #[cfg(feature = "serde")]
+        Some(ResourceKind::Qgm) => {
+            let json_path = out_path.with_extension("json");
+            let mut w = std::io::BufWriter::new(std::fs::File::create(&json_path)?);
+            super::write_json(&decode::<crate::qfg5resource::qfg5qgm::QgmDecoder>(data)?, &mut w)?;
+            w.flush()?;
+            vec![ json_path ]
+        },
+        Some(ResourceKind::Nod) => {
+            let png_path = out_path.with_extension("png");
+            super::room::render_palette(&decode::<qfg5nod::NodDecoder>(data)?, &png_path, ImageFormat::Png)?;
+            vec![ png_path ]
+        },
+        Some(ResourceKind::Qgf) => super::qgf::export_bmfont(&decode::<qfg5qgf::QgfDecoder>(data)?, &out_path.with_extension("fnt"))?,
+        Some(ResourceKind::Rgd) => {
+            let svg_path = out_path.with_extension("svg");
+            super::rgd::export_svg(&decode::<qfg5rgd::RgdDecoder>(data)?, &svg_path)?;
+            vec![ svg_path ]
+        },
+        _ => super::spk::convert(data, out_path)?,
+    };
+    if thumbs && !written.is_empty() {
+        written.extend(super::spk::thumbnails(data, out_path, THUMBNAIL_SIZE)?);
+    }
+    Ok(written)
+}
+
+/// Converts every item of an archive to `out_dir`; returns the number of files written
+fn convert_archive(input: &Path, out_dir: &Path, thumbs: bool) -> Result<usize> {
+    let archive = qfg5spk::SpkArchive::new(std::fs::File::open(input)?)?;
+    let (mut written, mut failed) = (0, 0);
+    for item in archive.get_items() {
+        let converted = item.extract_path(out_dir, qfg5spk::FileNameCase::Lowercase).map_err(anyhow::Error::from).and_then(|path| {
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            convert_data(&archive.item_data(item)?, &path, thumbs)
+        });
+        match converted {
+            Ok(paths) => written += paths.len(),
+            Err(e) => {
+                log::warn!("{}: cannot convert {}: {:#}", input.display(), item.filename, e);
+                failed += 1;
+            },
+        }
+    }
+    if failed > 0 {
+        return Err(anyhow!("{} of {} items could not be converted", failed, archive.get_items().len()));
+    }
+    Ok(written)
+}
+
+/// Whether there are outputs for `input` and all of them are newer. The outputs of a file are
+/// the files next to `out_path` with the same stem, optionally followed by '-' and a suffix,
+/// and the files in the directory of that name, as written by the GRA and SPK conversions.
+fn up_to_date(input: &Path, out_path: &Path) -> bool {
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let (Some(input_modified), Some(dir), Some(stem)) = (modified(input), out_path.parent(), out_path.file_stem()) else {
+        return false;
+    };
+    let Ok(entries) = std::fs::read_dir(dir) else { return false };
+    let stem = stem.to_string_lossy();
+    let mut outputs = Vec::new();
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        if path.is_dir() {
+            let mut files = Vec::new();
+            if entry.file_name().to_string_lossy() == stem {
+                if walk(&path, Path::new(""), None, &mut files).is_err() {
+                    return false;
+                }
+                outputs.extend(files.iter().map(|file| path.join(file)));
+            }
+            continue;
+        }
+        let (name_stem, extension) = (path.file_stem().map(|s| s.to_string_lossy()), path.extension());
+        let same_stem = name_stem.is_some_and(|s| s == stem || s.strip_prefix(&*stem).is_some_and(|rest| rest.starts_with('-')));
+        // With the output inside the data directory, the input itself must not count
+        if same_stem && extension != out_path.extension() {
+            outputs.push(path);
+        }
+    }
+    !outputs.is_empty() && outputs.iter().all(|path| modified(path).unwrap_or(SystemTime::UNIX_EPOCH) >= input_modified)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qfg5resource::fixtures;
+
+    fn write(dir: &Path, file: &str, data: &[u8]) {
+        let path = dir.join(file);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, data).unwrap();
+    }
+
+    #[test]
+    fn test_convert_all() {
+        let dir = crate::commands::test_dir("convert-all");
+        let data_dir = dir.join("data");
+        let mdl = fixtures::mdl();
+        write(&data_dir, "mdl/1.mdl", &mdl);
+        write(&data_dir, "mdl/2.mdl", &mdl[..mdl.len() - 1]);
+        write(&data_dir, "gra/3.gra", &fixtures::gra());
+        write(&data_dir, "qgm/4.qgm", &fixtures::qgm());
+        write(&data_dir, "qgf/8.qgf", &fixtures::qgf());
+        for (kind, data) in [ ("img", fixtures::img()), ("nod", fixtures::nod()), ("zzz", fixtures::zzz()), ("rgd", fixtures::rgd()) ] {
+            write(&data_dir, &format!("{}/7.{}", kind, kind), &data);
+        }
+        write(&data_dir, "notes.txt", b"hello");
+        write(&data_dir, "extra.spk", &fixtures::spk(&[ ("QGM/5.QGM", &fixtures::qgm()) ]));
+
+        let out_dir = dir.join("out");
+        let options = ConvertOptions{ jobs: 3, force: false, thumbs: false };
+        let summary = convert_all(&data_dir, &out_dir, &options).unwrap();
+        assert_eq!(summary.counts, BTreeMap::from([
+            ("GRA".to_string(), (1, 0)), ("IMG".to_string(), (1, 0)), ("MDL".to_string(), (1, 1)), ("NOD".to_string(), (1, 0)),
+            ("QGF".to_string(), (1, 0)), ("QGM".to_string(), (1, 0)), ("RGD".to_string(), (1, 0)), ("SPK".to_string(), (1, 0)),
+        ]));
+        assert_eq!((summary.up_to_date, summary.not_converted), (0, 2));
+        assert_eq!(summary.failures.len(), 1);
+        assert_eq!(summary.failures[0].0, Path::new("mdl/2.mdl"));
+        let qgm = if cfg!(feature = "serde") { "json" } else { "txt" };
+        for output in [ "mdl/1.gltf", "mdl/1.bin", "gra/3.png", "gra/3/0.gif", &format!("qgm/4.{}", qgm), "img/7.png", "img/7-depth.png",
+                        "nod/7.png", "qgf/8.png", "qgf/8.fnt", "rgd/7.svg", &format!("extra/qgm/5.{}", qgm) ] {
+            assert!(out_dir.join(output).exists(), "{} is missing", output);
+        }
+        let mut report = Vec::new();
+        summary.write(&mut report).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        assert!(report.starts_with("converted 8 files"));
+        assert!(report.contains("  MDL: 1 converted, 1 failed\n  NOD: 1 converted, 0 failed\n"));
+        assert!(report.contains("1 failed:\n  mdl/2.mdl: "));
+
+        // Only the file that failed is tried again
+        let summary = convert_all(&data_dir, &out_dir, &options).unwrap();
+        assert_eq!(summary.counts, BTreeMap::from([ ("MDL".to_string(), (0, 1)) ]));
+        assert_eq!((summary.up_to_date, summary.not_converted), (8, 2));
+        let summary = convert_all(&data_dir, &out_dir, &ConvertOptions{ jobs: 1, force: true, thumbs: false }).unwrap();
+        assert_eq!(summary.up_to_date, 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_output_inside_data_dir() {
+        let dir = crate::commands::test_dir("convert-inside");
+        write(&dir, "qgm/4.qgm", &fixtures::qgm());
+        let options = ConvertOptions{ jobs: 2, force: false, thumbs: false };
+        let summary = convert_all(&dir, &dir.join("out"), &options).unwrap();
+        assert_eq!(summary.counts, BTreeMap::from([ ("QGM".to_string(), (1, 0)) ]));
+        assert!(dir.join(if cfg!(feature = "serde") { "out/qgm/4.json" } else { "out/qgm/4.txt" }).exists());
+        // The outputs of the first run are not converted themselves
+        let summary = convert_all(&dir, &dir.join("out"), &options).unwrap();
+        assert_eq!((summary.up_to_date, summary.not_converted), (1, 0));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_thumbs() {
+        let dir = crate::commands::test_dir("convert-thumbs");
+        let data_dir = dir.join("data");
+        write(&data_dir, "mdl/1.mdl", &fixtures::mdl());
+        for (kind, data) in [ ("img", fixtures::img()), ("nod", fixtures::nod()), ("zzz", fixtures::zzz()) ] {
+            write(&data_dir, &format!("{}/7.{}", kind, kind), &data);
+        }
+        write(&data_dir, "extra.spk", &fixtures::spk(&[ ("MDL/2.MDL", &fixtures::mdl()) ]));
+        let out_dir = dir.join("out");
+        let summary = convert_all(&data_dir, &out_dir, &ConvertOptions{ jobs: 2, force: false, thumbs: true }).unwrap();
+        assert!(summary.failures.is_empty(), "{:?}", summary.failures);
+        for output in [ "mdl/1-0-thumb.png", "img/7-thumb.png", "extra/mdl/2-0-thumb.png" ] {
+            assert!(out_dir.join(output).exists(), "{} is missing", output);
+        }
+        // The thumbnails count as outputs
+        let summary = convert_all(&data_dir, &out_dir, &ConvertOptions{ jobs: 2, force: false, thumbs: true }).unwrap();
+        assert_eq!(summary.up_to_date, 4);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
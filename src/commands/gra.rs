@@ -0,0 +1,140 @@
+/*-
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * Copyright (c) 2024 Rink Springer <rink@rink.nu>
+ * For conditions of distribution and use, see LICENSE file
+ */
+use anyhow::Result;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use crate::export::gif;
+use crate::qfg5resource::{qfg5gra, qfg5nod::PaletteEntry};
+use crate::pixels;
+use super::{save_image, ImageFormat};
+
+pub fn dump(gra: &qfg5gra::GraDecoder, w: &mut dyn Write) -> Result<()> {
+    writeln!(w, "{} sprite collections", gra.sprite_collections.len())?;
+    for (n, sc) in gra.sprite_collections.iter().enumerate() {
+        writeln!(w, "  collection {}: {}x{} at ({}, {}), {} frames, delay {}",
+            n, sc.width, sc.height, sc.x_position, sc.y_position, sc.sprites.len(), sc.frame_delay)?;
+    }
+    Ok(())
+}
+
+/// Writes every frame as `<collection>-<frame>.<ext>` to `out_dir`, with index 0 transparent.
+/// The palette of the GRA file is used unless another one is given; returns the paths written
+pub fn export(gra: &qfg5gra::GraDecoder, palette: Option<&[PaletteEntry]>, out_dir: &Path, format: ImageFormat) -> Result<Vec<PathBuf>> {
+    let palette = palette.unwrap_or(&gra.palette);
+    std::fs::create_dir_all(out_dir)?;
+    let mut written = Vec::new();
+    for (n, sc) in gra.sprite_collections.iter().enumerate() {
+        for (f, sprite) in sc.sprites.iter().enumerate() {
+            let img = pixels::indexed_to_rgba(&sprite.pixels, palette, sc.width, sc.height, Some(0));
+            let path = out_dir.join(format!("{}-{}.{}", n, f, format.extension()));
+            save_image(&img, &path, format)?;
+            written.push(path);
+        }
+    }
+    Ok(written)
+}
+
+/// Writes all frames into one image at `path`, a row of frames per sprite collection, with index
+/// 0 transparent. The palette of the GRA file is used unless another one is given
+pub fn export_sheet(gra: &qfg5gra::GraDecoder, palette: Option<&[PaletteEntry]>, path: &Path, format: ImageFormat) -> Result<()> {
+    let palette = palette.unwrap_or(&gra.palette);
+    let width = gra.sprite_collections.iter().map(|sc| sc.width * sc.sprites.len() as u32).max().unwrap_or(0);
+    let height: u32 = gra.sprite_collections.iter().map(|sc| sc.height).sum();
+    let mut sheet = image::RgbaImage::new(width.max(1), height.max(1));
+    let mut y = 0;
+    for sc in &gra.sprite_collections {
+        for (f, sprite) in sc.sprites.iter().enumerate() {
+            let img = pixels::indexed_to_rgba(&sprite.pixels, palette, sc.width, sc.height, Some(0));
+            image::imageops::replace(&mut sheet, &img, (f as u32 * sc.width) as i64, y as i64);
+        }
+        y += sc.height;
+    }
+    save_image(&sheet, path, format)
+}
+
+/// Writes every sprite collection as an animation `<collection>.gif` to `out_dir`, with index 0
+/// transparent. The GIF palette is the palette of the GRA file unless another one is given, such
+/// as the one of the room the sprite is shown in; returns the paths written
+pub fn export_gif(gra: &qfg5gra::GraDecoder, palette: Option<&[PaletteEntry]>, out_dir: &Path) -> Result<Vec<PathBuf>> {
+    let palette = palette.unwrap_or(&gra.palette);
+    std::fs::create_dir_all(out_dir)?;
+    let mut written = Vec::new();
+    for (n, sc) in gra.sprite_collections.iter().enumerate() {
+        // frame_delay is assumed to be in milliseconds, GIF delays are in hundredths
+        let delay = (sc.frame_delay / 10).clamp(1, u16::MAX as u32) as u16;
+        let frames: Vec<_> = sc.sprites.iter().map(|sprite| gif::GifFrame{ pixels: &sprite.pixels, delay }).collect();
+        let path = out_dir.join(format!("{}.gif", n));
+        let mut w = std::io::BufWriter::new(std::fs::File::create(&path)?);
+        gif::write_gif(&mut w, sc.width.try_into()?, sc.height.try_into()?, palette, Some(0), &frames)?;
+        w.flush()?;
+        written.push(path);
+    }
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qfg5resource::{fixtures, qfg5nod};
+
+    #[test]
+    fn test_dump() {
+        let gra = qfg5gra::GraDecoder::new(&fixtures::gra()).unwrap();
+        let mut out = Vec::new();
+        dump(&gra, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "1 sprite collections\n  collection 0: 2x2 at (10, 20), 2 frames, delay 100\n");
+    }
+
+    #[test]
+    fn test_export() {
+        let dir = crate::commands::test_dir("gra");
+        let gra = qfg5gra::GraDecoder::new(&fixtures::gra()).unwrap();
+        let written = export(&gra, None, &dir, ImageFormat::Png).unwrap();
+        assert_eq!(written, vec![ dir.join("0-0.png"), dir.join("0-1.png") ]);
+        let img = image::open(&written[0]).unwrap().to_rgba8();
+        assert_eq!(img.get_pixel(0, 0).0, [ 0, 0, 0, 0 ]);
+        assert_eq!(img.get_pixel(1, 0).0, [ 255, 0, 0, 255 ]);
+
+        let nod = qfg5nod::NodDecoder::new(&fixtures::nod()).unwrap();
+        let written = export(&gra, Some(nod.get_palette()), &dir, ImageFormat::Bmp).unwrap();
+        assert_eq!(written[1], dir.join("0-1.bmp"));
+        let img = bmp::open(&written[1]).unwrap();
+        assert_eq!(img.get_pixel(0, 0), bmp::Pixel::new(1, 254, 0));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_export_sheet() {
+        let dir = crate::commands::test_dir("gra-sheet");
+        let gra = qfg5gra::GraDecoder::new(&fixtures::gra()).unwrap();
+        export_sheet(&gra, None, &dir.join("sheet.png"), ImageFormat::Png).unwrap();
+        let img = image::open(dir.join("sheet.png")).unwrap().to_rgba8();
+        // Both 2x2 frames side by side
+        assert_eq!(img.dimensions(), (4, 2));
+        assert_eq!(img.get_pixel(0, 0).0, [ 0, 0, 0, 0 ]);
+        assert_eq!(img.get_pixel(1, 0).0, [ 255, 0, 0, 255 ]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_export_gif() {
+        let dir = crate::commands::test_dir("gra-gif");
+        let gra = qfg5gra::GraDecoder::new(&fixtures::gra()).unwrap();
+        let nod = qfg5nod::NodDecoder::new(&fixtures::nod()).unwrap();
+        let written = export_gif(&gra, Some(nod.get_palette()), &dir).unwrap();
+        assert_eq!(written, vec![ dir.join("0.gif") ]);
+        let data = std::fs::read(&written[0]).unwrap();
+        assert!(data.starts_with(b"GIF89a\x02\x00\x02\x00"));
+        // The global color table is the palette of the room
+        let (r, g, b) = nod.get_palette()[1];
+        assert_eq!(data[16..19], [ r, g, b ]);
+        // Delay of 100ms, with index 0 transparent
+        let gce = 13 + 768 + 19;
+        assert_eq!(data[gce..gce + 8], [ 0x21, 0xf9, 4, 0x09, 10, 0, 0, 0 ]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
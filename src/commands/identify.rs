@@ -0,0 +1,40 @@
+/*-
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * Copyright (c) 2024 Rink Springer <rink@rink.nu>
+ * For conditions of distribution and use, see LICENSE file
+ */
+use anyhow::Result;
+use std::io::Write;
+use crate::qfg5resource::{detect, registration};
+
+/// Writes the kind of resource and, if it decodes as that kind, what it contains
+pub fn identify(data: &[u8], w: &mut dyn Write) -> Result<()> {
+    let Some(detection) = detect(data) else {
+        writeln!(w, "unknown")?;
+        return Ok(())
+    };
+    write!(w, "{} ({} confidence)", detection.kind, detection.confidence)?;
+    match registration(detection.kind).map(|registration| (registration.summarize)(data)) {
+        Some(Ok(summary)) if summary != Default::default() => writeln!(w, ": {}", summary)?,
+        Some(Err(e)) => writeln!(w, ", but cannot decode it: {}", e)?,
+        _ => writeln!(w)?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qfg5resource::fixtures;
+
+    #[test]
+    fn test_identify() {
+        let mut out = Vec::new();
+        identify(&fixtures::mdl(), &mut out).unwrap();
+        identify(&fixtures::gra(), &mut out).unwrap();
+        identify(b"", &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "MDL (high confidence): 'fixture', 1 submeshes, 4 vertices, 2 faces, 1 subbitmaps\n\
+            GRA (medium confidence): 1 sprite collections, 2 frames\nunknown\n");
+    }
+}
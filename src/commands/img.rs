@@ -0,0 +1,39 @@
+/*-
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * Copyright (c) 2024 Rink Springer <rink@rink.nu>
+ * For conditions of distribution and use, see LICENSE file
+ */
+use anyhow::Result;
+use std::path::Path;
+use crate::pixels;
+use crate::qfg5resource::{qfg5img, qfg5nod::PaletteEntry};
+use super::{save_image, ImageFormat};
+
+/// Renders a background with `palette`, which is the palette of the room; without it, the palette
+/// indices are shown as shades of grey
+pub fn render(img: &qfg5img::ImageDecoder, palette: Option<&[PaletteEntry]>, path: &Path, format: ImageFormat) -> Result<()> {
+    let grey: Vec<PaletteEntry> = (0..=255).map(|n| (n, n, n)).collect();
+    let palette = palette.unwrap_or(&grey);
+    let image = pixels::indexed_to_rgba(img.get_pixels(), palette, img.get_width() as u32, img.get_height() as u32, None);
+    save_image(&image, path, format)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qfg5resource::{fixtures, qfg5nod};
+
+    #[test]
+    fn test_render() {
+        let dir = crate::commands::test_dir("img");
+        let img = qfg5img::ImageDecoder::new(&fixtures::img()).unwrap();
+        let path = dir.join("bg.png");
+        render(&img, None, &path, ImageFormat::Png).unwrap();
+        assert_eq!(image::open(&path).unwrap().to_rgba8().get_pixel(0, 0).0, [ 1, 1, 1, 255 ]);
+        let nod = qfg5nod::NodDecoder::new(&fixtures::nod()).unwrap();
+        render(&img, Some(nod.get_palette()), &path, ImageFormat::Bmp).unwrap();
+        assert_eq!(bmp::open(&path).unwrap().get_pixel(2, 0), bmp::Pixel::new(2, 253, 0));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
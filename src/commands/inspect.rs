@@ -0,0 +1,115 @@
+/*-
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * Copyright (c) 2024 Rink Springer <rink@rink.nu>
+ * For conditions of distribution and use, see LICENSE file
+ */
+//! A hex dump of a resource in which every field the decoder knows is labeled with its name and
+//! value, and the bytes no decoder reads are marked, for working out the fields that are still
+//! unknown.
+use anyhow::Result;
+use std::io::Write;
+use crate::qfg5resource::{detect, registration, trace::{ParseTrace, Region}};
+
+/// Bytes per line of the dump
+const BYTES_PER_LINE: usize = 16;
+/// Lines shown of every region unless the whole dump is asked for
+const MAX_LINES: usize = 4;
+
+pub struct InspectOptions {
+    /// Show all bytes of every region instead of the first few lines
+    pub full: bool,
+}
+
+pub fn inspect(data: &[u8], options: &InspectOptions, w: &mut dyn Write) -> Result<()> {
+    let Some(detection) = detect(data) else {
+        writeln!(w, "unknown, {} bytes", data.len())?;
+        return write_regions(data, &ParseTrace::default(), options, w);
+    };
+    let traced = registration(detection.kind).and_then(|registration| (registration.trace)(data));
+    let Some((result, trace)) = traced else {
+        writeln!(w, "{}, {} bytes; its decoder does not label its fields yet", detection.kind, data.len())?;
+        return write_regions(data, &ParseTrace::default(), options, w);
+    };
+    let unparsed = trace.unparsed(data.len() as u64);
+    writeln!(w, "{}, {} bytes, {} not parsed", detection.kind, data.len(), unparsed)?;
+    write_regions(data, &trace, options, w)?;
+    match result {
+        Ok(summary) => writeln!(w, "decoded: {}", summary)?,
+        Err(e) => writeln!(w, "cannot decode: {}", e)?,
+    }
+    Ok(())
+}
+
+/// Writes every region with its label, followed by its bytes. The bytes of gaps start with '!'.
+fn write_regions(data: &[u8], trace: &ParseTrace, options: &InspectOptions, w: &mut dyn Write) -> Result<()> {
+    for region in trace.regions(data.len() as u64) {
+        let (offset, length, label, marker) = match region {
+            Region::Parsed(record) => (record.offset, record.length, record.label.as_str(), ' '),
+            Region::Gap{ offset, length } => (offset, length, "not parsed", '!'),
+        };
+        writeln!(w, "{:08x}+{:x}  {}", offset, length, label)?;
+        let start = (offset as usize).min(data.len());
+        let bytes = &data[start..(offset.saturating_add(length) as usize).min(data.len())];
+        let lines = bytes.chunks(BYTES_PER_LINE);
+        let shown = if options.full { lines.len() } else { MAX_LINES };
+        for (n, line) in lines.take(shown).enumerate() {
+            write_line(w, marker, start + n * BYTES_PER_LINE, line)?;
+        }
+        let hidden = bytes.len().saturating_sub(shown * BYTES_PER_LINE);
+        if hidden > 0 {
+            writeln!(w, "{}   ... {} more bytes", marker, hidden)?;
+        }
+    }
+    Ok(())
+}
+
+fn write_line(w: &mut dyn Write, marker: char, offset: usize, line: &[u8]) -> Result<()> {
+    let hex: Vec<_> = line.iter().map(|b| format!("{:02x}", b)).collect();
+    let text: String = line.iter().map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' }).collect();
+    writeln!(w, "{}  {:08x}  {:<width$}  {}", marker, offset, hex.join(" "), text, width = BYTES_PER_LINE * 3 - 1)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qfg5resource::fixtures;
+
+    fn inspect_to_string(data: &[u8], full: bool) -> String {
+        let mut out = Vec::new();
+        inspect(data, &InspectOptions{ full }, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_inspect() {
+        let out = inspect_to_string(&fixtures::mdl(), false);
+        let lines: Vec<_> = out.lines().collect();
+        assert!(lines[0].starts_with("MDL, "), "{}", out);
+        // The start of the header is not known
+        assert_eq!(lines[1], "00000000+c  not parsed");
+        assert!(lines[2].starts_with("!  00000000  00 00 00 00"), "{}", out);
+        assert_eq!(lines[3], "0000000c+10  name 'fixture'");
+        assert!(out.contains("  palette\n"), "{}", out);
+        assert!(out.contains("   ... "), "{}", out);
+        assert!(out.ends_with("decoded: 'fixture', 1 submeshes, 4 vertices, 2 faces, 1 subbitmaps\n"), "{}", out);
+        assert!(!inspect_to_string(&fixtures::mdl(), true).contains("more bytes"));
+
+        // What was read before the error is still shown
+        let mdl = fixtures::mdl();
+        let out = inspect_to_string(&mdl[..mdl.len() - 1], false);
+        assert!(out.contains("0000001c+2  number of submeshes 1\n"), "{}", out);
+        assert!(out.contains("000005a8+3  not parsed\n!  000005a8  01 00 00 "), "{}", out);
+        assert!(out.ends_with("\ncannot decode: truncated at offset 0x5a8: needed 4 bytes, got 3\n"), "{}", out);
+    }
+
+    #[test]
+    fn test_not_traced() {
+        let out = inspect_to_string(&fixtures::gra(), false);
+        assert!(out.starts_with("GRA, 568 bytes; its decoder does not label its fields yet\n00000000+238  not parsed\n"), "{}", out);
+        let out = inspect_to_string(b"hello", false);
+        assert!(out.starts_with("unknown, 5 bytes\n00000000+5  not parsed\n!  00000000  68 65 6c 6c 6f "), "{}", out);
+        assert!(out.ends_with("  hello\n"), "{}", out);
+    }
+}
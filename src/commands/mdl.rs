@@ -0,0 +1,646 @@
+/*-
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * Copyright (c) 2024 Rink Springer <rink@rink.nu>
+ * For conditions of distribution and use, see LICENSE file
+ */
+use anyhow::{anyhow, Result};
+use std::collections::{HashMap, VecDeque};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use crate::export::{obj, uvmap};
+use crate::qfg5resource::{qfg5anm, qfg5mdl};
+use crate::threed::pose::Pose;
+use super::{save_image, ImageFormat};
+use super::qgm::DiffStatus;
+
+/// With `verbose`, also shows the header values of every submesh whose meaning is not known yet
+pub fn dump(mdl: &qfg5mdl::Qfg5Model, verbose: bool, w: &mut dyn Write) -> Result<()> {
+    writeln!(w, "model '{}', {} submeshes", mdl.name, mdl.submeshes.len())?;
+    for sm in &mdl.submeshes {
+        writeln!(w, "  submesh '{}', {} vertices, {} texcoords, {} faces, {} lighting vertices",
+            sm.name, sm.vertices.len(), sm.texcoords.len(), sm.faces.len(), sm.lighting_vertices.len())?;
+        if let Some(bounds) = sm.bounds() {
+            writeln!(w, "    {}", format_bounds(&bounds))?;
+        }
+        if verbose {
+            for (n, values) in sm.header_floats.chunks(5).enumerate() {
+                let values: Vec<_> = values.iter().map(|v| v.to_string()).collect();
+                writeln!(w, "    unknown floats {}-{}: {}", n * 5, n * 5 + 4, values.join(" "))?;
+            }
+        }
+    }
+    for (n, subbitmap) in mdl.subbitmaps.iter().enumerate() {
+        writeln!(w, "  subbitmap {}: {}x{}", n, subbitmap.width, subbitmap.height)?;
+    }
+    if let Some(bounds) = mdl.bounds() {
+        writeln!(w, "  {}", format_bounds(&bounds))?;
+    }
+    for warning in &mdl.warnings {
+        writeln!(w, "  warning: {}", warning)?;
+    }
+    Ok(())
+}
+
+/// Statistics of the lighting values of every submesh and of all of them, to help finding out
+/// what they mean
+pub fn dump_lighting(mdl: &qfg5mdl::Qfg5Model, w: &mut dyn Write) -> Result<()> {
+    for (n, sm) in mdl.submeshes.iter().enumerate() {
+        writeln!(w, "submesh {} '{}' lighting:", n, sm.name)?;
+        write_lighting_stats(sm.lighting_stats(), w)?;
+    }
+    writeln!(w, "all lighting:")?;
+    write_lighting_stats(mdl.lighting_stats(), w)
+}
+
+/// Most indices listed per kind of problem by dump_stats()
+const MAX_LISTED: usize = 10;
+
+/// What SubMesh::analyze() finds in every submesh, for judging whether a model can be exported
+/// and written back as it is
+pub fn dump_stats(mdl: &qfg5mdl::Qfg5Model, w: &mut dyn Write) -> Result<()> {
+    for (n, (sm, report)) in mdl.submeshes.iter().zip(mdl.analyze()).enumerate() {
+        writeln!(w, "submesh {} '{}': {} faces, {} vertices, {} texcoords", n, sm.name, sm.faces.len(), sm.vertices.len(), sm.texcoords.len())?;
+        if report.is_clean() {
+            writeln!(w, "  no problems found")?;
+            continue;
+        }
+        for (what, indices) in [
+            ("degenerate faces", &report.degenerate_faces),
+            ("unused vertices", &report.unused_vertices),
+            ("unused texcoords", &report.unused_texcoords),
+            ("texcoords outside 0-1", &report.texcoords_out_of_range),
+            ("faces with a missing subbitmap", &report.missing_subbitmap),
+            ("duplicate faces", &report.duplicate_faces),
+        ] {
+            if !indices.is_empty() {
+                let listed: Vec<_> = indices.iter().take(MAX_LISTED).map(|n| n.to_string()).collect();
+                let more = if indices.len() > MAX_LISTED { " ..." } else { "" };
+                writeln!(w, "  {} {}: {}{}", indices.len(), what, listed.join(" "), more)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn write_lighting_stats(stats: Option<qfg5mdl::LightingStats>, w: &mut dyn Write) -> Result<()> {
+    let Some(stats) = stats else {
+        writeln!(w, "  no values")?;
+        return Ok(());
+    };
+    let (min, max, mean) = (&stats.min, &stats.max, &stats.mean);
+    for (name, min, max, mean) in [ ("a", min.a, max.a, mean.a), ("b", min.b, max.b, mean.b), ("c", min.c, max.c, mean.c), ("d", min.d, max.d, mean.d) ] {
+        writeln!(w, "  {}: min {}, max {}, mean {}", name, min, max, mean)?;
+    }
+    writeln!(w, "  {} of {} have a, b and c of length 1", stats.unit_abc, stats.count)?;
+    Ok(())
+}
+
+fn format_bounds(bounds: &qfg5mdl::Qfg5Bounds) -> String {
+    let center = bounds.center();
+    format!("bounds ({}, {}, {}) - ({}, {}, {}), center ({}, {}, {}), radius {}", bounds.min.x, bounds.min.y, bounds.min.z,
+        bounds.max.x, bounds.max.y, bounds.max.z, center.x, center.y, center.z, bounds.radius())
+}
+
+/// The structure of a model as written by `dump_mdl --format json`, for diffing models and for
+/// scripts: the faces, but of the other lists only their sizes, and a CRC32 of the pixels of
+/// every subbitmap instead of the pixels themselves unless `full` is given
+#[cfg(feature = "serde")]
+pub fn json_summary<'a>(mdl: &'a qfg5mdl::Qfg5Model, full: bool) -> JsonModel<'a> {
+    use base64::Engine;
+    let submeshes = mdl.submeshes.iter().map(|sm| JsonSubMesh{
+        name: &sm.name, num_vertices: sm.vertices.len(), num_texcoords: sm.texcoords.len(), num_faces: sm.faces.len(), faces: &sm.faces,
+    }).collect();
+    let subbitmaps = mdl.subbitmaps.iter().map(|subbitmap| JsonSubBitmap{
+        width: subbitmap.width, height: subbitmap.height, crc32: crc32fast::hash(&subbitmap.bitmap),
+        pixels: full.then(|| base64::engine::general_purpose::STANDARD.encode(&subbitmap.bitmap)),
+    }).collect();
+    JsonModel{ name: &mdl.name, submeshes, subbitmaps, warnings: &mdl.warnings }
+}
+
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize)]
+pub struct JsonModel<'a> {
+    pub name: &'a str,
+    pub submeshes: Vec<JsonSubMesh<'a>>,
+    pub subbitmaps: Vec<JsonSubBitmap>,
+    pub warnings: &'a [String],
+}
+
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize)]
+pub struct JsonSubMesh<'a> {
+    pub name: &'a str,
+    pub num_vertices: usize,
+    pub num_texcoords: usize,
+    pub num_faces: usize,
+    pub faces: &'a [qfg5mdl::Qfg5Face],
+}
+
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize)]
+pub struct JsonSubBitmap {
+    pub width: u32,
+    pub height: u32,
+    pub crc32: u32,
+    /// Base64-encoded palette indices, only with `full`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pixels: Option<String>,
+}
+
+/// The name of the model as the start of a file name in an output directory. The name comes from
+/// the file, so separators, drive colons and control characters are replaced, as they could lead
+/// outside of the directory; an empty name becomes `model`.
+fn file_prefix(mdl: &qfg5mdl::Qfg5Model) -> String {
+    if mdl.name.is_empty() {
+        return "model".to_string();
+    }
+    mdl.name.chars().map(|ch| if matches!(ch, '/' | '\\' | ':') || ch.is_control() { '_' } else { ch }).collect()
+}
+
+/// Writes every subbitmap as `<model>-subbitmap-<n>.<ext>` to `out_dir`, in the colors of the
+/// model's palette, with `transparent_index` transparent; returns the paths written
+pub fn export_textures(mdl: &qfg5mdl::Qfg5Model, out_dir: &Path, format: ImageFormat, transparent_index: Option<u8>) -> Result<Vec<PathBuf>> {
+    std::fs::create_dir_all(out_dir)?;
+    let mut written = Vec::new();
+    for (n, subbitmap) in mdl.subbitmaps.iter().enumerate() {
+        let path = out_dir.join(format!("{}-subbitmap-{}.{}", file_prefix(mdl), n, format.extension()));
+        save_image(&subbitmap.to_rgba(&mdl.palette, transparent_index), &path, format)?;
+        written.push(path);
+    }
+    Ok(written)
+}
+
+/// Writes the texture coordinates of every submesh over every subbitmap it uses, see
+/// uvmap::render_uv_map(), as `<model>-submesh-<n>-subbitmap-<n>-uv.png`
+pub fn export_uvmaps(mdl: &qfg5mdl::Qfg5Model, out_dir: &Path) -> Result<Vec<PathBuf>> {
+    std::fs::create_dir_all(out_dir)?;
+    let mut written = Vec::new();
+    for (n, submesh) in mdl.submeshes.iter().enumerate() {
+        for subbitmap in uvmap::used_subbitmaps(submesh) {
+            let path = out_dir.join(format!("{}-submesh-{}-subbitmap-{}-uv.png", file_prefix(mdl), n, subbitmap));
+            uvmap::render_uv_map(mdl, submesh, subbitmap).save(&path)?;
+            written.push(path);
+        }
+    }
+    Ok(written)
+}
+
+/// Which pose to export
+pub enum ExportPose<'a> {
+    /// All submeshes side by side, see Pose::exploded()
+    Exploded,
+    Submesh(usize),
+    AnmFrame(&'a qfg5anm::AnmDecoder, usize),
+}
+
+/// The submesh named `selector`, ignoring case, or else the one with that index; the error lists
+/// the submeshes there are
+pub fn find_submesh(mdl: &qfg5mdl::Qfg5Model, selector: &str) -> Result<usize> {
+    if let Some(index) = mdl.submeshes.iter().position(|sm| sm.name.eq_ignore_ascii_case(selector)) {
+        return Ok(index);
+    }
+    match selector.parse::<usize>() {
+        Ok(index) if index < mdl.submeshes.len() => Ok(index),
+        _ => {
+            let names: Vec<_> = mdl.submeshes.iter().enumerate().map(|(n, sm)| format!("{} '{}'", n, sm.name)).collect();
+            Err(anyhow!("no submesh '{}'; there are {}", selector, names.join(", ")))
+        },
+    }
+}
+
+/// Exports the model as .obj, .mtl and textures; returns the paths of all files written
+pub fn export_obj(mdl: &qfg5mdl::Qfg5Model, pose: ExportPose, obj_path: &Path) -> Result<Vec<PathBuf>> {
+    let pose = match pose {
+        ExportPose::Exploded => Pose::exploded(mdl),
+        ExportPose::Submesh(index) => {
+            if index >= mdl.submeshes.len() {
+                return Err(anyhow!("no submesh {} (there are {})", index, mdl.submeshes.len()));
+            }
+            Pose::submesh(index)
+        },
+        ExportPose::AnmFrame(anm, frame) => {
+            if anm.anims.len() != mdl.submeshes.len() {
+                return Err(anyhow!("animation is for {} submeshes, model has {}", anm.anims.len(), mdl.submeshes.len()));
+            }
+            if frame >= anm.num_frames() {
+                return Err(anyhow!("no frame {} (there are {})", frame, anm.num_frames()));
+            }
+            Pose::anm_frame(anm, frame)
+        },
+    };
+    obj::export(mdl, &pose, obj_path)
+}
+
+/// Most indices listed per change by MdlDiff::write()
+const MAX_DIFF_LISTED: usize = 10;
+
+/// What differs of a submesh with the same name in both models
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize), serde(tag = "field", rename_all = "snake_case"))]
+pub enum SubMeshChange {
+    /// The number of vertices, texcoords, faces or lighting vertices
+    Count{ what: &'static str, old: usize, new: usize },
+    /// Vertices that both have which moved further than the tolerance, with how far
+    Moved{ vertices: Vec<(usize, f32)> },
+    /// Faces that both have with other vertices, texture coordinates or subbitmap
+    Faces{ faces: Vec<usize> },
+    /// Texture coordinates, normals, lighting values or header values that differ by more than
+    /// the tolerance, of those both have
+    Other{ fields: Vec<&'static str> },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct SubMeshDiff {
+    pub name: String,
+    pub status: DiffStatus,
+    /// Of a changed submesh
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Vec::is_empty"))]
+    pub changes: Vec<SubMeshChange>,
+}
+
+/// What differs of a subbitmap with the same index in both models
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize), serde(tag = "field", rename_all = "snake_case"))]
+pub enum SubBitmapChange {
+    Size{ old: (u32, u32), new: (u32, u32) },
+    /// The CRC-32 of the pixels
+    Pixels{ old: u32, new: u32 },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct SubBitmapDiff {
+    pub index: usize,
+    pub status: DiffStatus,
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Vec::is_empty"))]
+    pub changes: Vec<SubBitmapChange>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct MdlDiff {
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub name: Option<(String, String)>,
+    /// How many palette entries differ
+    pub palette_entries: usize,
+    /// The submeshes that are not the same, in the order of the first model with those only in
+    /// the second after them
+    pub submeshes: Vec<SubMeshDiff>,
+    pub same_submeshes: usize,
+    /// The subbitmaps that are not the same
+    pub subbitmaps: Vec<SubBitmapDiff>,
+    pub same_subbitmaps: usize,
+}
+
+impl MdlDiff {
+    /// Whether the models are the same, as far as compared
+    pub fn is_same(&self) -> bool {
+        self.name.is_none() && self.palette_entries == 0 && self.submeshes.is_empty() && self.subbitmaps.is_empty()
+    }
+
+    pub fn write(&self, w: &mut dyn Write) -> Result<()> {
+        let marker = |status| match status {
+            DiffStatus::Added => '+',
+            DiffStatus::Removed => '-',
+            DiffStatus::Changed => '~',
+            DiffStatus::Same => '=',
+        };
+        let list = |indices: &mut dyn Iterator<Item = String>, count: usize| {
+            let listed: Vec<_> = indices.take(MAX_DIFF_LISTED).collect();
+            format!("{}{}", listed.join(" "), if count > MAX_DIFF_LISTED { " ..." } else { "" })
+        };
+        if let Some((old, new)) = &self.name {
+            writeln!(w, "name: '{}' -> '{}'", old, new)?;
+        }
+        if self.palette_entries > 0 {
+            writeln!(w, "palette: {} entries differ", self.palette_entries)?;
+        }
+        for submesh in &self.submeshes {
+            writeln!(w, "{} submesh '{}'", marker(submesh.status), submesh.name)?;
+            for change in &submesh.changes {
+                match change {
+                    SubMeshChange::Count{ what, old, new } => writeln!(w, "    {}: {} -> {}", what, old, new)?,
+                    SubMeshChange::Moved{ vertices } => {
+                        let furthest = vertices.iter().map(|&(_, distance)| distance).fold(0.0, f32::max);
+                        writeln!(w, "    {} vertices moved, at most {}: {}", vertices.len(), furthest,
+                            list(&mut vertices.iter().map(|(n, _)| n.to_string()), vertices.len()))?
+                    },
+                    SubMeshChange::Faces{ faces } => writeln!(w, "    {} faces changed: {}", faces.len(), list(&mut faces.iter().map(|n| n.to_string()), faces.len()))?,
+                    SubMeshChange::Other{ fields } => writeln!(w, "    also differs: {}", fields.join(", "))?,
+                }
+            }
+        }
+        for subbitmap in &self.subbitmaps {
+            writeln!(w, "{} subbitmap {}", marker(subbitmap.status), subbitmap.index)?;
+            for change in &subbitmap.changes {
+                match change {
+                    SubBitmapChange::Size{ old, new } => writeln!(w, "    size: {}x{} -> {}x{}", old.0, old.1, new.0, new.1)?,
+                    SubBitmapChange::Pixels{ old, new } => writeln!(w, "    pixels: crc32 {:08x} -> {:08x}", old, new)?,
+                }
+            }
+        }
+        let count = |diffs: &mut dyn Iterator<Item = DiffStatus>, status| diffs.filter(|&s| s == status).count();
+        for (what, statuses, same) in [
+            ("submeshes", self.submeshes.iter().map(|s| s.status).collect::<Vec<_>>(), self.same_submeshes),
+            ("subbitmaps", self.subbitmaps.iter().map(|s| s.status).collect(), self.same_subbitmaps),
+        ] {
+            writeln!(w, "{}: {} added, {} removed, {} changed, {} the same", what, count(&mut statuses.iter().copied(), DiffStatus::Added),
+                count(&mut statuses.iter().copied(), DiffStatus::Removed), count(&mut statuses.iter().copied(), DiffStatus::Changed), same)?;
+        }
+        Ok(())
+    }
+}
+
+fn submesh_changes(old: &qfg5mdl::SubMesh, new: &qfg5mdl::SubMesh, tolerance: f32) -> Vec<SubMeshChange> {
+    let mut changes = Vec::new();
+    for (what, old, new) in [
+        ("vertices", old.vertices.len(), new.vertices.len()),
+        ("texcoords", old.texcoords.len(), new.texcoords.len()),
+        ("faces", old.faces.len(), new.faces.len()),
+        ("lighting vertices", old.lighting_vertices.len(), new.lighting_vertices.len()),
+    ] {
+        if old != new {
+            changes.push(SubMeshChange::Count{ what, old, new });
+        }
+    }
+    let moved: Vec<_> = old.vertices.iter().zip(&new.vertices).enumerate().filter_map(|(n, (a, b))| {
+        let distance = ((a.x - b.x).powi(2) + (a.y - b.y).powi(2) + (a.z - b.z).powi(2)).sqrt();
+        // NaN counts as moved
+        (distance > tolerance || distance.is_nan()).then_some((n, distance))
+    }).collect();
+    if !moved.is_empty() {
+        changes.push(SubMeshChange::Moved{ vertices: moved });
+    }
+    let indices = |f: &qfg5mdl::Qfg5Face| [ f.vertex1, f.vertex2, f.vertex3, f.uv1, f.uv2, f.uv3, f.subbitmap ];
+    let faces: Vec<_> = old.faces.iter().zip(&new.faces).enumerate()
+        .filter(|(_, (a, b))| indices(a) != indices(b)).map(|(n, _)| n).collect();
+    if !faces.is_empty() {
+        changes.push(SubMeshChange::Faces{ faces });
+    }
+
+    let differ = |a: &[f32], b: &[f32]| a.iter().zip(b).any(|(a, b)| (a - b).abs() > tolerance || (a - b).is_nan());
+    let fields = [
+        ("texture coordinates", old.texcoords.iter().zip(&new.texcoords).any(|(a, b)| differ(&[ a.u, a.v ], &[ b.u, b.v ]))),
+        ("face normals", old.faces.iter().zip(&new.faces).any(|(a, b)| differ(&[ a.normal_x, a.normal_y, a.normal_z ], &[ b.normal_x, b.normal_y, b.normal_z ]))),
+        ("lighting values", old.lighting_vertices.iter().zip(&new.lighting_vertices).any(|(a, b)| differ(&[ a.a, a.b, a.c, a.d ], &[ b.a, b.b, b.c, b.d ]))),
+        ("header values", differ(&old.header_floats, &new.header_floats)),
+    ];
+    let fields: Vec<_> = fields.into_iter().filter(|(_, differs)| *differs).map(|(field, _)| field).collect();
+    if !fields.is_empty() {
+        changes.push(SubMeshChange::Other{ fields });
+    }
+    changes
+}
+
+fn subbitmap_changes(old: &qfg5mdl::SubBitmap, new: &qfg5mdl::SubBitmap) -> Vec<SubBitmapChange> {
+    let mut changes = Vec::new();
+    if (old.width, old.height) != (new.width, new.height) {
+        changes.push(SubBitmapChange::Size{ old: (old.width, old.height), new: (new.width, new.height) });
+    }
+    let (old_crc, new_crc) = (crc32fast::hash(&old.bitmap), crc32fast::hash(&new.bitmap));
+    if old_crc != new_crc {
+        changes.push(SubBitmapChange::Pixels{ old: old_crc, new: new_crc });
+    }
+    changes
+}
+
+/// Compares the submeshes of `old` and `new` by name and the subbitmaps by index. Submeshes with
+/// the same name in one model are paired in order. Floats count as the same when they differ by
+/// at most `tolerance`, and vertices when they are at most that far apart.
+pub fn diff(old: &qfg5mdl::Qfg5Model, new: &qfg5mdl::Qfg5Model, tolerance: f32) -> MdlDiff {
+    let mut result = MdlDiff::default();
+    if old.name != new.name {
+        result.name = Some((old.name.clone(), new.name.clone()));
+    }
+    result.palette_entries = old.palette.iter().zip(&new.palette).filter(|(a, b)| a != b).count();
+
+    let mut new_by_name: HashMap<&str, VecDeque<usize>> = HashMap::new();
+    for (n, submesh) in new.submeshes.iter().enumerate() {
+        new_by_name.entry(&submesh.name).or_default().push_back(n);
+    }
+    let mut paired = vec![ false; new.submeshes.len() ];
+    for submesh in &old.submeshes {
+        let name = submesh.name.clone();
+        let Some(other) = new_by_name.get_mut(submesh.name.as_str()).and_then(|indices| indices.pop_front()) else {
+            result.submeshes.push(SubMeshDiff{ name, status: DiffStatus::Removed, changes: Vec::new() });
+            continue;
+        };
+        paired[other] = true;
+        let changes = submesh_changes(submesh, &new.submeshes[other], tolerance);
+        if changes.is_empty() {
+            result.same_submeshes += 1;
+        } else {
+            result.submeshes.push(SubMeshDiff{ name, status: DiffStatus::Changed, changes });
+        }
+    }
+    for (submesh, _) in new.submeshes.iter().zip(&paired).filter(|(_, paired)| !**paired) {
+        result.submeshes.push(SubMeshDiff{ name: submesh.name.clone(), status: DiffStatus::Added, changes: Vec::new() });
+    }
+
+    for index in 0..old.subbitmaps.len().max(new.subbitmaps.len()) {
+        let (status, changes) = match (old.subbitmaps.get(index), new.subbitmaps.get(index)) {
+            (Some(a), Some(b)) => {
+                let changes = subbitmap_changes(a, b);
+                if changes.is_empty() {
+                    result.same_subbitmaps += 1;
+                    continue;
+                }
+                (DiffStatus::Changed, changes)
+            },
+            (Some(_), None) => (DiffStatus::Removed, Vec::new()),
+            _ => (DiffStatus::Added, Vec::new()),
+        };
+        result.subbitmaps.push(SubBitmapDiff{ index, status, changes });
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qfg5resource::fixtures;
+
+    #[test]
+    fn test_dump() {
+        let mdl = qfg5mdl::Qfg5Model::new(&fixtures::mdl()).unwrap();
+        let mut out = Vec::new();
+        dump(&mdl, false, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), concat!("model 'fixture', 1 submeshes\n",
+            "  submesh 'quad', 4 vertices, 4 texcoords, 2 faces, 4 lighting vertices\n",
+            "    bounds (0, 0, 0) - (1, 1, 0), center (0.5, 0.5, 0), radius 0.70710677\n",
+            "  subbitmap 0: 2x2\n",
+            "  bounds (0, 0, 0) - (1, 1, 0), center (0.5, 0.5, 0), radius 0.70710677\n"));
+
+        let mut mdl = mdl;
+        mdl.submeshes[0].header_floats[6] = -0.5;
+        let mut out = Vec::new();
+        dump(&mdl, true, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("  submesh 'quad', 4 vertices, 4 texcoords, 2 faces, 4 lighting vertices\n    bounds (0, 0, 0) - (1, 1, 0), center (0.5, 0.5, 0), radius 0.70710677\n    unknown floats 0-4: 0 0 0 0 0\n    unknown floats 5-9: 0 -0.5 0 0 0\n"), "{}", out);
+        assert!(out.contains("    unknown floats 15-19: "), "{}", out);
+
+        // A name with a Latin-1 character and something after its zero byte
+        let mut data = fixtures::mdl();
+        data[0x430 + 3] = 0xe9;
+        data[0x430 + 8] = b'x';
+        let mdl = qfg5mdl::Qfg5Model::new(&data).unwrap();
+        let mut out = Vec::new();
+        dump(&mdl, false, &mut out).unwrap();
+        assert!(String::from_utf8(out).unwrap().contains("  submesh 'qua\u{e9}', 4 vertices"));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_json_summary() {
+        let mdl = qfg5mdl::Qfg5Model::new(&fixtures::mdl()).unwrap();
+        let json = serde_json::to_value(json_summary(&mdl, false)).unwrap();
+        assert_eq!(json["name"], "fixture");
+        assert_eq!(json["submeshes"][0]["name"], "quad");
+        assert_eq!(json["submeshes"][0]["num_vertices"], 4);
+        assert_eq!(json["submeshes"][0]["faces"][1]["vertex3"], 3);
+        assert_eq!(json["submeshes"][0]["faces"].as_array().unwrap().len(), 2);
+        assert_eq!(json["subbitmaps"][0], serde_json::json!({ "width": 2, "height": 2, "crc32": crc32fast::hash(&[ 1, 0, 0, 1 ]) }));
+
+        let json = serde_json::to_value(json_summary(&mdl, true)).unwrap();
+        assert_eq!(json["subbitmaps"][0]["pixels"], "AQAAAQ==");
+    }
+
+    #[test]
+    fn test_dump_lighting() {
+        let mdl = qfg5mdl::Qfg5Model::new(&fixtures::mdl()).unwrap();
+        let mut out = Vec::new();
+        dump_lighting(&mdl, &mut out).unwrap();
+        // The fixture has a = n, b = c = 0 and d = 1 for vertex n
+        let stats = concat!("  a: min 0, max 3, mean 1.5\n",
+            "  b: min 0, max 0, mean 0\n",
+            "  c: min 0, max 0, mean 0\n",
+            "  d: min 1, max 1, mean 1\n",
+            "  1 of 4 have a, b and c of length 1\n");
+        assert_eq!(String::from_utf8(out).unwrap(), format!("submesh 0 'quad' lighting:\n{}all lighting:\n{}", stats, stats));
+    }
+
+    #[test]
+    fn test_dump_stats() {
+        let mut mdl = qfg5mdl::Qfg5Model::new(&fixtures::mdl()).unwrap();
+        let mut out = Vec::new();
+        dump_stats(&mdl, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "submesh 0 'quad': 2 faces, 4 vertices, 4 texcoords\n  no problems found\n");
+
+        let sm = &mut mdl.submeshes[0];
+        sm.faces[1].vertex3 = 2;
+        sm.faces.extend(std::iter::repeat_n(sm.faces[0].clone(), 11));
+        let mut out = Vec::new();
+        dump_stats(&mdl, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), concat!("submesh 0 'quad': 13 faces, 4 vertices, 4 texcoords\n",
+            "  1 degenerate faces: 1\n",
+            "  1 unused vertices: 3\n",
+            "  11 duplicate faces: 2 3 4 5 6 7 8 9 10 11 ...\n"));
+    }
+
+    #[test]
+    fn test_export_textures() {
+        let dir = crate::commands::test_dir("mdl-textures");
+        let mdl = qfg5mdl::Qfg5Model::new(&fixtures::mdl()).unwrap();
+        let written = export_textures(&mdl, &dir, ImageFormat::Png, None).unwrap();
+        assert_eq!(written, [ dir.join("fixture-subbitmap-0.png") ]);
+        let img = image::open(&written[0]).unwrap().to_rgba8();
+        assert_eq!(img.dimensions(), (2, 2));
+        assert_eq!((img.get_pixel(0, 0).0, img.get_pixel(1, 0).0), ([ 255, 0, 0, 255 ], [ 0, 0, 0, 255 ]));
+
+        let written = export_textures(&mdl, &dir, ImageFormat::Png, Some(1)).unwrap();
+        let img = image::open(&written[0]).unwrap().to_rgba8();
+        assert_eq!(img.get_pixel(0, 0).0, [ 0, 0, 0, 0 ]);
+        let written = export_textures(&mdl, &dir, ImageFormat::Bmp, None).unwrap();
+        assert_eq!(written, [ dir.join("fixture-subbitmap-0.bmp") ]);
+
+        // Names from the file stay inside the directory
+        let mut mdl = mdl;
+        mdl.name = "../C:\\x".to_string();
+        assert_eq!(export_textures(&mdl, &dir, ImageFormat::Png, None).unwrap(), [ dir.join(".._C__x-subbitmap-0.png") ]);
+        mdl.name.clear();
+        assert_eq!(export_textures(&mdl, &dir, ImageFormat::Png, None).unwrap(), [ dir.join("model-subbitmap-0.png") ]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_export_uvmaps() {
+        let dir = crate::commands::test_dir("mdl-uvmaps");
+        let mut mdl = qfg5mdl::Qfg5Model::new(&fixtures::mdl()).unwrap();
+        mdl.submeshes[0].faces[1].subbitmap = 3;
+        let written = export_uvmaps(&mdl, &dir).unwrap();
+        assert_eq!(written, [ dir.join("fixture-submesh-0-subbitmap-0-uv.png"), dir.join("fixture-submesh-0-subbitmap-3-uv.png") ]);
+        assert_eq!(image::open(&written[0]).unwrap().to_rgba8().dimensions(), (256, 256));
+        mdl.name = "/tmp/x".to_string();
+        assert_eq!(export_uvmaps(&mdl, &dir).unwrap()[0], dir.join("_tmp_x-submesh-0-subbitmap-0-uv.png"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_export_obj() {
+        let dir = crate::commands::test_dir("mdl");
+        let mdl = qfg5mdl::Qfg5Model::new(&fixtures::mdl()).unwrap();
+        let anm = qfg5anm::AnmDecoder::new(&fixtures::anm()).unwrap();
+        let written = export_obj(&mdl, ExportPose::AnmFrame(&anm, 1), &dir.join("quad.obj")).unwrap();
+        assert_eq!(written.len(), 3);
+        let obj = std::fs::read_to_string(dir.join("quad.obj")).unwrap();
+        // Frame 1 moves the quad one unit along X
+        assert!(obj.contains("v 2 1 0\n"));
+        assert!(export_obj(&mdl, ExportPose::AnmFrame(&anm, 2), &dir.join("quad.obj")).is_err());
+        assert!(export_obj(&mdl, ExportPose::Submesh(1), &dir.join("quad.obj")).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_find_submesh() {
+        let mut mdl = qfg5mdl::Qfg5Model::new(&fixtures::mdl()).unwrap();
+        mdl.submeshes.push(qfg5mdl::SubMesh{ name: "Head".to_string(), ..Default::default() });
+        assert_eq!(find_submesh(&mdl, "head").unwrap(), 1);
+        assert_eq!(find_submesh(&mdl, "QUAD").unwrap(), 0);
+        assert_eq!(find_submesh(&mdl, "1").unwrap(), 1);
+        assert_eq!(find_submesh(&mdl, "2").unwrap_err().to_string(), "no submesh '2'; there are 0 'quad', 1 'Head'");
+        assert!(find_submesh(&mdl, "arm").is_err());
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = qfg5mdl::Qfg5Model::new(&fixtures::mdl()).unwrap();
+        let same = diff(&old, &old, 0.0);
+        assert!(same.is_same());
+        assert_eq!((same.same_submeshes, same.same_subbitmaps), (1, 1));
+
+        let mut new = old.clone();
+        new.submeshes[0].vertices[1].x += 0.5;
+        new.submeshes[0].vertices[2].y += 1e-4;
+        new.submeshes[0].faces[1].vertex2 = 0;
+        new.submeshes[0].texcoords[0].u += 0.25;
+        new.submeshes.push(qfg5mdl::SubMesh{ name: "Head".to_string(), ..Default::default() });
+        new.subbitmaps[0].bitmap.to_mut()[0] ^= 1;
+        let d = diff(&old, &new, 1e-3);
+        assert!(!d.is_same());
+        assert_eq!(d.submeshes, [
+            SubMeshDiff{ name: "quad".to_string(), status: DiffStatus::Changed, changes: vec![
+                SubMeshChange::Moved{ vertices: vec![ (1, 0.5) ] },
+                SubMeshChange::Faces{ faces: vec![ 1 ] },
+                SubMeshChange::Other{ fields: vec![ "texture coordinates" ] },
+            ] },
+            SubMeshDiff{ name: "Head".to_string(), status: DiffStatus::Added, changes: Vec::new() },
+        ]);
+        assert!(matches!(d.subbitmaps[..], [ SubBitmapDiff{ index: 0, status: DiffStatus::Changed, ref changes } ] if matches!(changes[..], [ SubBitmapChange::Pixels{ .. } ])));
+        // Within the tolerance, the second vertex moved too
+        assert!(matches!(&diff(&old, &new, 0.0).submeshes[0].changes[0], SubMeshChange::Moved{ vertices } if vertices.len() == 2));
+
+        let mut out = Vec::new();
+        diff(&new, &old, 1e-3).write(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("~ submesh 'quad'\n    1 vertices moved, at most 0.5: 1\n    1 faces changed: 1\n    also differs: texture coordinates\n- submesh 'Head'\n~ subbitmap 0\n    pixels: crc32 "));
+        assert!(out.ends_with("submeshes: 0 added, 1 removed, 1 changed, 0 the same\nsubbitmaps: 0 added, 0 removed, 1 changed, 0 the same\n"));
+
+        new.subbitmaps.clear();
+        new.submeshes[0].faces.pop();
+        let d = diff(&old, &new, 1e-3);
+        assert_eq!(d.submeshes[0].changes[0], SubMeshChange::Count{ what: "faces", old: 2, new: 1 });
+        assert_eq!(d.subbitmaps, [ SubBitmapDiff{ index: 0, status: DiffStatus::Removed, changes: Vec::new() } ]);
+    }
+}
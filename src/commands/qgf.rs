@@ -0,0 +1,89 @@
+/*-
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * Copyright (c) 2024 Rink Springer <rink@rink.nu>
+ * For conditions of distribution and use, see LICENSE file
+ */
+use anyhow::Result;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use crate::export::bmfont;
+use crate::pixels;
+use crate::qfg5resource::qfg5qgf;
+use super::{save_image, ImageFormat};
+
+/// Number of glyphs per line of the character sheet
+const SHEET_COLUMNS: u32 = 32;
+
+pub fn dump(qgf: &qfg5qgf::QgfDecoder, w: &mut dyn Write) -> Result<()> {
+    let defined = qgf.chars.iter().filter(|ch| ch.width != 0).count();
+    writeln!(w, "{} font, height {}, maximum width {}, spacing {}",
+        if qgf.is_3d { "3D" } else { "2D" }, qgf.char_height, qgf.max_char_width, qgf.char_space)?;
+    writeln!(w, "  {} characters, {} with a glyph", qgf.chars.len(), defined)?;
+    Ok(())
+}
+
+/// Renders `text`, or a sheet of all glyphs if there is none, as black on transparent
+pub fn render(qgf: &qfg5qgf::QgfDecoder, text: Option<&str>, path: &Path, format: ImageFormat) -> Result<()> {
+    let bitmap = match text {
+        Some(text) => qgf.render_text(text),
+        None => qgf.render_sheet(SHEET_COLUMNS),
+    };
+    save_image(&pixels::glyphs_to_rgba(qgf, &bitmap), path, format)
+}
+
+/// Writes the sheet of all glyphs to `fnt_path` with the extension .png, and a BMFont descriptor
+/// of it to `fnt_path`; returns the paths written
+pub fn export_bmfont(qgf: &qfg5qgf::QgfDecoder, fnt_path: &Path) -> Result<Vec<PathBuf>> {
+    let png_path = fnt_path.with_extension("png");
+    render(qgf, None, &png_path, ImageFormat::Png)?;
+    let face = fnt_path.file_stem().unwrap_or_default().to_string_lossy();
+    let page_file = png_path.file_name().unwrap_or_default().to_string_lossy();
+    let mut w = std::io::BufWriter::new(std::fs::File::create(fnt_path)?);
+    bmfont::write_bmfont(&mut w, qgf, &face, &page_file, SHEET_COLUMNS)?;
+    w.flush()?;
+    Ok(vec![ png_path, fnt_path.to_path_buf() ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qfg5resource::fixtures;
+
+    #[test]
+    fn test_dump() {
+        let qgf = qfg5qgf::QgfDecoder::new(&fixtures::qgf()).unwrap();
+        let mut out = Vec::new();
+        dump(&qgf, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "2D font, height 2, maximum width 2, spacing 1\n  512 characters, 1 with a glyph\n");
+    }
+
+    #[test]
+    fn test_render() {
+        let dir = crate::commands::test_dir("qgf");
+        let qgf = qfg5qgf::QgfDecoder::new(&fixtures::qgf()).unwrap();
+        render(&qgf, Some("AA"), &dir.join("text.png"), ImageFormat::Png).unwrap();
+        let img = image::open(dir.join("text.png")).unwrap().to_rgba8();
+        assert_eq!(img.dimensions(), (5, 2));
+        assert_eq!(img.get_pixel(3, 0).0, [ 0, 0, 0, 255 ]);
+        assert_eq!(img.get_pixel(2, 0).0, [ 0, 0, 0, 0 ]);
+
+        render(&qgf, None, &dir.join("sheet.png"), ImageFormat::Png).unwrap();
+        let img = image::open(dir.join("sheet.png")).unwrap().to_rgba8();
+        assert_eq!(img.dimensions(), (SHEET_COLUMNS * 3, 16 * 3));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_export_bmfont() {
+        let dir = crate::commands::test_dir("qgf-bmfont");
+        let qgf = qfg5qgf::QgfDecoder::new(&fixtures::qgf()).unwrap();
+        let written = export_bmfont(&qgf, &dir.join("1.fnt")).unwrap();
+        assert_eq!(written, vec![ dir.join("1.png"), dir.join("1.fnt") ]);
+        let fnt = std::fs::read_to_string(dir.join("1.fnt")).unwrap();
+        assert!(fnt.contains("\npage id=0 file=\"1.png\"\n"), "{}", fnt);
+        let img = image::open(dir.join("1.png")).unwrap().to_rgba8();
+        assert_eq!(img.dimensions(), (SHEET_COLUMNS * 3, 16 * 3));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
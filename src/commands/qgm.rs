@@ -0,0 +1,672 @@
+/*-
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * Copyright (c) 2024 Rink Springer <rink@rink.nu>
+ * For conditions of distribution and use, see LICENSE file
+ */
+use anyhow::{anyhow, Context, Result};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use crate::export::{dot, po};
+use crate::qfg5resource::{qfg5aud, qfg5qgm, qfg5spk, ResourceKind};
+
+#[derive(Default)]
+pub struct ListOptions<'a> {
+    /// Also show the header fields whose meaning is not known yet
+    pub unknown: bool,
+    /// Show the names of the speakers
+    pub speakers: Option<&'a qfg5qgm::SpeakerTable>,
+    /// Only list the messages of this speaker
+    pub speaker: Option<u16>,
+    /// Show the name of the speech resource of every message
+    pub audio: bool,
+    /// Speech resources that exist, see read_audio_names(); messages whose resource is not among
+    /// them are marked missing
+    pub audio_names: Option<&'a HashSet<String>>,
+    /// Durations of the speech resources by the same names, shown for those that have one
+    pub audio_durations: Option<&'a HashMap<String, Duration>>,
+}
+
+/// Shown by list() instead of the label and speech resource of a message whose ids do not fit
+const NO_LABEL: &str = "(ids do not fit in a label)";
+
+pub fn list(qgm: &qfg5qgm::QgmDecoder, options: &ListOptions, w: &mut dyn Write) -> Result<()> {
+    writeln!(w, "qgm file id: {}", qgm.file_id)?;
+    if options.unknown {
+        writeln!(w, "version {}, unknown 1: {}", qgm.version, qgm.unknown1)?;
+    }
+    let messages: Box<dyn Iterator<Item = &qfg5qgm::QgmMessage>> = match options.speaker {
+        Some(speaker_id) => Box::new(qgm.messages_by_speaker(speaker_id)),
+        None => Box::new(qgm.messages.iter()),
+    };
+    for m in messages {
+        // The decoder accepts ids that do not fit, see QgmDecoder::validate()
+        let label = qfg5qgm::QgmLabel::try_encode(qgm, m);
+        writeln!(w, "{} message {}: '{}'", label.as_deref().unwrap_or(NO_LABEL), m.msg_id, m.text)?;
+        if let Some(speakers) = options.speakers {
+            match speakers.name(m.speaker_id) {
+                Some(name) => writeln!(w, "  speaker: {} ({})", name, m.speaker_id)?,
+                None => writeln!(w, "  speaker: {}", m.speaker_id)?,
+            }
+        }
+        if options.audio {
+            match (m.try_audio_name(qgm.file_id), options.audio_names) {
+                (None, _) => writeln!(w, "  audio: {}", NO_LABEL)?,
+                (Some(name), Some(names)) if names.contains(&name.to_ascii_lowercase()) => {
+                    match options.audio_durations.and_then(|durations| durations.get(&name.to_ascii_lowercase())) {
+                        Some(duration) => writeln!(w, "  audio: {}.aud ({:.3} seconds)", name, duration.as_secs_f64())?,
+                        None => writeln!(w, "  audio: {}.aud", name)?,
+                    }
+                },
+                (Some(name), Some(_)) => writeln!(w, "  audio: {}.aud (missing)", name)?,
+                (Some(name), None) => writeln!(w, "  audio: {}.aud", name)?,
+            }
+        }
+        if options.unknown {
+            writeln!(w, "  speaker {}, flags {:#x}{}, text flag {}", m.speaker_id, m.flags,
+                if m.is_mangled() { " (mangled)" } else { "" }, m.text_flag)?;
+            let [ unk2, unk3, unk4, unk5, unk6 ] = m.unknown;
+            writeln!(w, "  unknown 2-6: {} {} {} {} {}, unknown 8: {}", unk2, unk3, unk4, unk5, unk6, m.unknown8)?;
+        }
+        if let Some(ml) = &m.message_label {
+            writeln!(w, "  message label: {}", ml)?;
+        }
+        for dlo in &m.dialog_options {
+            writeln!(w, "  dialog option: {}", dlo)?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes the messages as CSV with a header row, for spreadsheets. Dialog options are joined
+/// with `;`; labels that are missing are empty.
+pub fn export_csv(qgm: &qfg5qgm::QgmDecoder, w: &mut dyn Write) -> Result<()> {
+    writeln!(w, "file_id,label,id1,id2,id3,id4,speaker_id,msg_id,message_label,dialog_options,text")?;
+    for m in &qgm.messages {
+        let label = qfg5qgm::QgmLabel::try_encode(qgm, m).unwrap_or_default();
+        let message_label = m.message_label.as_ref().map(|label| label.to_string()).unwrap_or_default();
+        let dialog_options: Vec<_> = m.dialog_options.iter().map(|label| label.to_string()).collect();
+        writeln!(w, "{},{},{},{},{},{},{},{},{},{},{}", qgm.file_id, label, m.id[0], m.id[1], m.id[2], m.id[3],
+            m.speaker_id, m.msg_id, super::csv_field(&message_label), super::csv_field(&dialog_options.join(";")),
+            super::csv_field(&m.text))?;
+    }
+    Ok(())
+}
+
+/// Reads a speaker table, see SpeakerTable::parse()
+pub fn read_speakers(path: &Path) -> Result<qfg5qgm::SpeakerTable> {
+    let text = String::from_utf8(super::read_input(path)?).with_context(|| format!("{} is not UTF-8", path.display()))?;
+    qfg5qgm::SpeakerTable::parse(&text).with_context(|| path.display().to_string())
+}
+
+/// The names of the speech resources below the directory `dir`, such as the AUD directory of
+/// extracted data, in lowercase and without their extension
+pub fn read_audio_names(dir: &Path) -> Result<HashSet<String>> {
+    let mut files = Vec::new();
+    super::convert::walk(dir, Path::new(""), None, &mut files).with_context(|| dir.display().to_string())?;
+    Ok(files.iter()
+        .filter(|file| file.extension().is_some_and(|e| e.eq_ignore_ascii_case("aud")))
+        .filter_map(|file| file.file_stem()?.to_str().map(|stem| stem.to_ascii_lowercase()))
+        .collect())
+}
+
+/// The speech resources of `path` like read_audio_names(), where `path` is a directory of
+/// extracted resources or an SPK archive; for an archive also their durations. Resources that do
+/// not decode are listed without a duration.
+pub fn read_audio(path: &Path) -> Result<(HashSet<String>, HashMap<String, Duration>)> {
+    if path.is_dir() {
+        return Ok((read_audio_names(path)?, HashMap::new()));
+    }
+    let file = std::fs::File::open(path).with_context(|| path.display().to_string())?;
+    let archive = qfg5spk::SpkArchive::new(file).with_context(|| path.display().to_string())?;
+    let (mut names, mut durations) = (HashSet::new(), HashMap::new());
+    for item in archive.get_items() {
+        let item_path = Path::new(&item.filename);
+        if !item_path.extension().is_some_and(|e| e.eq_ignore_ascii_case("aud")) {
+            continue;
+        }
+        let Some(name) = item_path.file_stem().and_then(|stem| stem.to_str()).map(|stem| stem.to_ascii_lowercase()) else { continue };
+        match archive.read_item(item).map_err(anyhow::Error::from).and_then(|data| Ok(qfg5aud::AudDecoder::new(&data)?)) {
+            Ok(aud) => { durations.insert(name.clone(), aud.duration()); },
+            Err(err) => log::warn!("{}: {}", item.filename, err),
+        }
+        names.insert(name);
+    }
+    Ok((names, durations))
+}
+
+/// The id of a speaker given by name, which needs `speakers`, or by id
+pub fn resolve_speaker(speakers: Option<&qfg5qgm::SpeakerTable>, name_or_id: &str) -> Result<u16> {
+    let id = match speakers {
+        Some(speakers) => speakers.resolve(name_or_id),
+        None => name_or_id.parse().ok(),
+    };
+    id.ok_or_else(|| anyhow!("unknown speaker '{}'", name_or_id))
+}
+
+/// Writes the conversations of `qgms` as a Graphviz DOT graph
+pub fn graph(qgms: &[&qfg5qgm::QgmDecoder], w: &mut dyn Write) -> Result<()> {
+    dot::write_dot(w, qgms)
+}
+
+/// The files of `paths`, with the QGM files below every directory among them
+fn qgm_files(paths: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for path in paths {
+        if !path.is_dir() {
+            files.push(path.clone());
+            continue;
+        }
+        let mut found = Vec::new();
+        super::convert::walk(path, Path::new(""), None, &mut found).with_context(|| path.display().to_string())?;
+        let qgms = found.into_iter().filter(|file| file.extension().is_some_and(|e| e.eq_ignore_ascii_case("qgm")));
+        files.extend(qgms.map(|file| path.join(file)));
+    }
+    Ok(files)
+}
+
+/// Checks the dialog options and message labels of the QGM files in `paths`, which may be
+/// directories, against the labels of all of them, and writes the ones that do not resolve by
+/// file. Returns their number.
+pub fn validate(paths: &[PathBuf], w: &mut dyn Write) -> Result<usize> {
+    let files = qgm_files(paths)?;
+    if files.is_empty() {
+        return Err(anyhow!("no QGM files found"));
+    }
+    let mut qgms = Vec::new();
+    for path in &files {
+        qgms.push(super::decode_input(path, ResourceKind::Qgm, qfg5qgm::QgmDecoder::new)?);
+    }
+    let dangling = qfg5qgm::dangling_references(&qgms.iter().collect::<Vec<_>>());
+    let mut last = None;
+    for reference in &dangling {
+        if last != Some(reference.file) {
+            writeln!(w, "{}:", files[reference.file].display())?;
+            last = Some(reference.file);
+        }
+        writeln!(w, "  {}", reference)?;
+    }
+    let broken: std::collections::HashSet<_> = dangling.iter().map(|reference| reference.file).collect();
+    writeln!(w, "{} dangling references in {} of {} files", dangling.len(), broken.len(), files.len())?;
+    Ok(dangling.len())
+}
+
+/// Writes the messages as a PO file for translating them
+pub fn export_po(qgm: &qfg5qgm::QgmDecoder, w: &mut dyn Write) -> Result<()> {
+    po::write_po(w, qgm)
+}
+
+/// Writes `original` with the translations of the PO file `po_path` to `out`; returns the
+/// warnings about entries that do not match a message
+pub fn import_po(original: &[u8], po_path: &Path, out: &Path) -> Result<Vec<String>> {
+    let text = String::from_utf8(super::read_input(po_path)?).with_context(|| format!("{} is not UTF-8", po_path.display()))?;
+    let (data, warnings) = po::import_po(original, &text).with_context(|| po_path.display().to_string())?;
+    let mut w = super::binary_output(out)?;
+    w.write_all(&data)?;
+    w.flush()?;
+    Ok(warnings)
+}
+
+/// Writes `original` to `out` with the text of the message `label` replaced, mangled again if
+/// it was; the other messages are written as they were. Fails if no message or more than one
+/// has the label.
+pub fn patch(original: &[u8], label: &str, text: &str, out: &Path) -> Result<()> {
+    let mut qgm = qfg5qgm::QgmDecoder::new(original)?;
+    let (file_id, id) = qfg5qgm::QgmLabel::parse(label).ok_or_else(|| anyhow!("'{}' is not a label such as 0BJ0102.034", label))?;
+    if file_id != qgm.file_id {
+        return Err(anyhow!("label {} is not of file {}", label, qgm.file_id));
+    }
+    let matching: Vec<_> = qgm.messages.iter().enumerate().filter(|(_, m)| m.id == id).map(|(n, _)| n).collect();
+    let n = match matching[..] {
+        [ n ] => n,
+        [] => return Err(anyhow!("no message has label {}", label)),
+        _ => return Err(anyhow!("label {} is used by {} messages", label, matching.len())),
+    };
+    qgm.messages[n].text = text.to_string();
+    let data = qgm.encode()?;
+    let mut w = super::binary_output(out)?;
+    w.write_all(&data)?;
+    w.flush()?;
+    Ok(())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize), serde(rename_all = "lowercase"))]
+pub enum DiffStatus {
+    Added,
+    Removed,
+    Changed,
+    Same,
+}
+
+/// What differs of a message with the same label in both files
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize), serde(tag = "field", rename_all = "snake_case"))]
+pub enum Change {
+    Text{ old: String, new: String },
+    Speaker{ old: u16, new: u16 },
+    /// Labels that only one of the messages offers
+    DialogOptions{ added: Vec<String>, removed: Vec<String> },
+    MessageLabel{ old: Option<String>, new: Option<String> },
+    /// The other fields that differ, such as the flags or the order of the dialog options
+    Other{ fields: Vec<&'static str> },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct MessageDiff {
+    pub label: String,
+    pub status: DiffStatus,
+    /// The text of an added or removed message
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub text: Option<String>,
+    /// Of a changed message
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Vec::is_empty"))]
+    pub changes: Vec<Change>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct QgmDiff {
+    /// By label; messages that are the same are only included if asked for
+    pub messages: Vec<MessageDiff>,
+    pub added: usize,
+    pub removed: usize,
+    pub changed: usize,
+    pub same: usize,
+}
+
+impl QgmDiff {
+    pub fn write(&self, w: &mut dyn Write) -> Result<()> {
+        for m in &self.messages {
+            let marker = match m.status {
+                DiffStatus::Added => '+',
+                DiffStatus::Removed => '-',
+                DiffStatus::Changed => '~',
+                DiffStatus::Same => '=',
+            };
+            match &m.text {
+                Some(text) => writeln!(w, "{} {} '{}'", marker, m.label, text)?,
+                None => writeln!(w, "{} {}", marker, m.label)?,
+            }
+            for change in &m.changes {
+                match change {
+                    Change::Text{ old, new } => writeln!(w, "    text: '{}' -> '{}'", old, new)?,
+                    Change::Speaker{ old, new } => writeln!(w, "    speaker: {} -> {}", old, new)?,
+                    Change::DialogOptions{ added, removed } => {
+                        let options: Vec<_> = added.iter().map(|label| format!("+{}", label))
+                            .chain(removed.iter().map(|label| format!("-{}", label))).collect();
+                        writeln!(w, "    dialog options: {}", options.join(" "))?
+                    },
+                    Change::MessageLabel{ old, new } => writeln!(w, "    message label: {} -> {}",
+                        old.as_deref().unwrap_or("none"), new.as_deref().unwrap_or("none"))?,
+                    Change::Other{ fields } => writeln!(w, "    also differs: {}", fields.join(", "))?,
+                }
+            }
+        }
+        writeln!(w, "{} added, {} removed, {} changed, {} the same", self.added, self.removed, self.changed, self.same)?;
+        Ok(())
+    }
+}
+
+/// A label as it is compared, without the zero bytes and spaces after it
+fn label_text(label: &qfg5qgm::QgmLabel) -> String {
+    label.to_string().trim_end_matches([ '\0', ' ' ]).to_string()
+}
+
+/// The label of message `n`, or its index if its ids do not fit in a label
+fn message_label(qgm: &qfg5qgm::QgmDecoder, n: usize) -> String {
+    qfg5qgm::QgmLabel::try_encode(qgm, &qgm.messages[n]).unwrap_or_else(|| format!("message {}", n))
+}
+
+fn changes(old: &qfg5qgm::QgmMessage, new: &qfg5qgm::QgmMessage) -> Vec<Change> {
+    let mut changes = Vec::new();
+    if old.text != new.text {
+        changes.push(Change::Text{ old: old.text.clone(), new: new.text.clone() });
+    }
+    if old.speaker_id != new.speaker_id {
+        changes.push(Change::Speaker{ old: old.speaker_id, new: new.speaker_id });
+    }
+    let old_options: Vec<_> = old.dialog_options.iter().map(label_text).collect();
+    let new_options: Vec<_> = new.dialog_options.iter().map(label_text).collect();
+    let added: Vec<_> = new_options.iter().filter(|label| !old_options.contains(label)).cloned().collect();
+    let removed: Vec<_> = old_options.iter().filter(|label| !new_options.contains(label)).cloned().collect();
+    if !added.is_empty() || !removed.is_empty() {
+        changes.push(Change::DialogOptions{ added, removed });
+    }
+    let (old_label, new_label) = (old.message_label.as_ref().map(label_text), new.message_label.as_ref().map(label_text));
+    if old_label != new_label {
+        changes.push(Change::MessageLabel{ old: old_label, new: new_label });
+    }
+    let fields = [
+        ("message id", old.msg_id != new.msg_id),
+        ("dialog option order", old_options != new_options && changes.iter().all(|change| !matches!(change, Change::DialogOptions{ .. }))),
+        ("flags", old.flags != new.flags),
+        ("unknown 2-6", old.unknown != new.unknown),
+        ("text flag", old.text_flag != new.text_flag),
+        ("unknown 8", old.unknown8 != new.unknown8),
+    ];
+    let mut fields: Vec<_> = fields.into_iter().filter(|(_, differs)| *differs).map(|(field, _)| field).collect();
+    // What is left are the bytes after the labels
+    if changes.is_empty() && fields.is_empty() && old != new {
+        fields.push("label padding");
+    }
+    if !fields.is_empty() {
+        changes.push(Change::Other{ fields });
+    }
+    changes
+}
+
+/// Compares the messages of `old` and `new` by label. Messages with the same label in one file
+/// are paired in the order of the files. Messages that are the same in both are only listed
+/// if `same` is set, but always counted.
+pub fn diff(old: &qfg5qgm::QgmDecoder, new: &qfg5qgm::QgmDecoder, same: bool) -> QgmDiff {
+    let mut new_by_label: HashMap<String, VecDeque<usize>> = HashMap::new();
+    for n in 0..new.messages.len() {
+        new_by_label.entry(message_label(new, n)).or_default().push_back(n);
+    }
+    let mut result = QgmDiff::default();
+    let mut paired = vec![ false; new.messages.len() ];
+    for (n, m) in old.messages.iter().enumerate() {
+        let label = message_label(old, n);
+        let Some(other) = new_by_label.get_mut(&label).and_then(|indices| indices.pop_front()) else {
+            result.removed += 1;
+            result.messages.push(MessageDiff{ label, status: DiffStatus::Removed, text: Some(m.text.clone()), changes: Vec::new() });
+            continue;
+        };
+        paired[other] = true;
+        let changes = changes(m, &new.messages[other]);
+        if changes.is_empty() {
+            result.same += 1;
+            if same {
+                result.messages.push(MessageDiff{ label, status: DiffStatus::Same, text: None, changes });
+            }
+        } else {
+            result.changed += 1;
+            result.messages.push(MessageDiff{ label, status: DiffStatus::Changed, text: None, changes });
+        }
+    }
+    for (n, m) in new.messages.iter().enumerate().filter(|(n, _)| !paired[*n]) {
+        result.added += 1;
+        result.messages.push(MessageDiff{ label: message_label(new, n), status: DiffStatus::Added, text: Some(m.text.clone()), changes: Vec::new() });
+    }
+    result.messages.sort_by(|a, b| a.label.cmp(&b.label));
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qfg5resource::fixtures;
+
+    #[test]
+    fn test_list() {
+        let qgm = qfg5qgm::QgmDecoder::new(&fixtures::qgm()).unwrap();
+        let mut out = Vec::new();
+        list(&qgm, &ListOptions::default(), &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), concat!("qgm file id: 415\n",
+            "0BJ0102.034 message 7: 'Hello'\n",
+            "  message label: LABEL0000001\n",
+            "  dialog option: OPTION000001\n"));
+
+        let mut qgm = qgm;
+        qgm.messages[0].unknown = [ 1, 2, 3, 4, 5 ];
+        qgm.messages[0].flags = qfg5qgm::FLAG_TEXT_MANGLED;
+        let mut out = Vec::new();
+        list(&qgm, &ListOptions{ unknown: true, ..Default::default() }, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), concat!("qgm file id: 415\n",
+            "version 1, unknown 1: 0\n",
+            "0BJ0102.034 message 7: 'Hello'\n",
+            "  speaker 5, flags 0x4 (mangled), text flag 0\n",
+            "  unknown 2-6: 1 2 3 4 5, unknown 8: 0\n",
+            "  message label: LABEL0000001\n",
+            "  dialog option: OPTION000001\n"));
+    }
+
+    #[test]
+    fn test_list_audio() {
+        let qgm = qfg5qgm::QgmDecoder::new(&fixtures::qgm()).unwrap();
+        let list_to_string = |options: &ListOptions| {
+            let mut out = Vec::new();
+            list(&qgm, options, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        let out = list_to_string(&ListOptions{ audio: true, ..Default::default() });
+        assert!(out.contains("'Hello'\n  audio: 0BJ0102034.aud\n"), "{}", out);
+
+        let dir = crate::commands::test_dir("qgm-audio");
+        std::fs::create_dir(dir.join("AUD")).unwrap();
+        std::fs::write(dir.join("AUD").join("0BJ0102034.AUD"), b"").unwrap();
+        std::fs::write(dir.join("0BJ0102035.wav"), b"").unwrap();
+        let names = read_audio_names(&dir).unwrap();
+        assert_eq!(names, HashSet::from([ "0bj0102034".to_string() ]));
+        let out = list_to_string(&ListOptions{ audio: true, audio_names: Some(&names), ..Default::default() });
+        assert!(out.contains("  audio: 0BJ0102034.aud\n"), "{}", out);
+        let out = list_to_string(&ListOptions{ audio: true, audio_names: Some(&HashSet::new()), ..Default::default() });
+        assert!(out.contains("  audio: 0BJ0102034.aud (missing)\n"), "{}", out);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // A file id past ZZZ
+        let mut qgm = qgm;
+        qgm.file_id = 46656;
+        let mut out = Vec::new();
+        list(&qgm, &ListOptions{ audio: true, audio_names: Some(&names), ..Default::default() }, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("(ids do not fit in a label) message 7: 'Hello'\n  audio: (ids do not fit in a label)\n"), "{}", out);
+    }
+
+    #[test]
+    fn test_list_audio_spk() {
+        let qgm = qfg5qgm::QgmDecoder::new(&fixtures::qgm()).unwrap();
+        let dir = crate::commands::test_dir("qgm-audio-spk");
+        let path = dir.join("cdn.spk");
+        std::fs::write(&path, fixtures::spk(&[ ("AUD/0BJ0102034.AUD", &fixtures::aud()), ("AUD/BROKEN.AUD", b"RIFF"), ("QGM/415.QGM", b"") ])).unwrap();
+        let (names, durations) = read_audio(&path).unwrap();
+        assert_eq!(names, HashSet::from([ "0bj0102034".to_string(), "broken".to_string() ]));
+        assert_eq!(durations.keys().collect::<Vec<_>>(), [ "0bj0102034" ]);
+        let mut out = Vec::new();
+        let options = ListOptions{ audio: true, audio_names: Some(&names), audio_durations: Some(&durations), ..Default::default() };
+        list(&qgm, &options, &mut out).unwrap();
+        // 17 samples at 22050 Hz
+        assert!(String::from_utf8(out).unwrap().contains("'Hello'\n  audio: 0BJ0102034.aud (0.001 seconds)\n"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_list_speaker() {
+        let mut qgm = qfg5qgm::QgmDecoder::new(&fixtures::qgm()).unwrap();
+        qgm.messages[0].dialog_options.clear();
+        qgm.messages[0].message_label = None;
+        qgm.messages.push(qfg5qgm::QgmMessage{ speaker_id: 6, id: [ 1, 2, 3, 5 ], text: "Bye".to_string(), ..qgm.messages[0].clone() });
+        let speakers = qfg5qgm::SpeakerTable::parse("5,Erasmus\n").unwrap();
+        let mut out = Vec::new();
+        list(&qgm, &ListOptions{ speakers: Some(&speakers), ..Default::default() }, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), concat!("qgm file id: 415\n",
+            "0BJ0102.034 message 7: 'Hello'\n",
+            "  speaker: Erasmus (5)\n",
+            "0BJ0102.035 message 7: 'Bye'\n",
+            "  speaker: 6\n"));
+
+        let speaker = resolve_speaker(Some(&speakers), "erasmus").unwrap();
+        let mut out = Vec::new();
+        list(&qgm, &ListOptions{ speaker: Some(speaker), ..Default::default() }, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "qgm file id: 415\n0BJ0102.034 message 7: 'Hello'\n");
+        assert_eq!(resolve_speaker(None, "6").unwrap(), 6);
+        assert_eq!(resolve_speaker(None, "Erasmus").unwrap_err().to_string(), "unknown speaker 'Erasmus'");
+    }
+
+    /// Splits CSV into records of fields, as a spreadsheet would
+    fn parse_csv(text: &str) -> Vec<Vec<String>> {
+        let mut records = Vec::new();
+        let (mut record, mut field) = (Vec::new(), String::new());
+        let mut chars = text.chars().peekable();
+        let mut quoted = false;
+        while let Some(ch) = chars.next() {
+            match ch {
+                '"' if quoted && chars.peek() == Some(&'"') => { field.push('"'); chars.next(); },
+                '"' => quoted = !quoted,
+                ',' if !quoted => record.push(std::mem::take(&mut field)),
+                '\n' if !quoted => {
+                    record.push(std::mem::take(&mut field));
+                    records.push(std::mem::take(&mut record));
+                },
+                _ => field.push(ch),
+            }
+        }
+        records
+    }
+
+    #[test]
+    fn test_export_csv() {
+        let mut qgm = qfg5qgm::QgmDecoder::new(&fixtures::qgm()).unwrap();
+        qgm.messages.push(qfg5qgm::QgmMessage{ id: [ 1, 2, 3, 5 ], message_label: None, dialog_options: Vec::new(),
+            text: "Say \"hi\",\nthen leave".to_string(), ..qgm.messages[0].clone() });
+        let option = qgm.messages[0].dialog_options[0].clone();
+        qgm.messages[0].dialog_options.push(option);
+        let mut out = Vec::new();
+        export_csv(&qgm, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out, concat!("file_id,label,id1,id2,id3,id4,speaker_id,msg_id,message_label,dialog_options,text\n",
+            "415,0BJ0102.034,1,2,3,4,5,7,LABEL0000001,OPTION000001;OPTION000001,Hello\n",
+            "415,0BJ0102.035,1,2,3,5,5,7,,,\"Say \"\"hi\"\",\nthen leave\"\n"));
+
+        let records = parse_csv(&out);
+        assert_eq!(records.len(), 3);
+        assert!(records.iter().all(|record| record.len() == 11));
+        let texts: Vec<_> = records[1..].iter().map(|record| record[10].as_str()).collect();
+        assert_eq!(texts, qgm.messages.iter().map(|m| m.text.as_str()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_po() {
+        let data = fixtures::qgm();
+        let qgm = qfg5qgm::QgmDecoder::new(&data).unwrap();
+        let mut out = Vec::new();
+        export_po(&qgm, &mut out).unwrap();
+        let translated = String::from_utf8(out).unwrap().replace("msgstr \"\"\n", "msgstr \"Hallo\"\n");
+
+        let dir = crate::commands::test_dir("qgm-po");
+        std::fs::write(dir.join("de.po"), translated).unwrap();
+        let warnings = import_po(&data, &dir.join("de.po"), &dir.join("de.qgm")).unwrap();
+        assert!(warnings.is_empty());
+        let out = qfg5qgm::QgmDecoder::new(&std::fs::read(dir.join("de.qgm")).unwrap()).unwrap();
+        assert_eq!(out.messages[0].text, "Hallo");
+
+        std::fs::write(dir.join("bad.po"), "msgid \"x\"\nmsgstr 1\n").unwrap();
+        let err = import_po(&data, &dir.join("bad.po"), &dir.join("bad.qgm")).unwrap_err();
+        assert!(format!("{:#}", err).ends_with("bad.po: line 2: bad string 1"), "{:#}", err);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_validate() {
+        let qgm = qfg5qgm::QgmDecoder::new(&fixtures::qgm()).unwrap();
+        let mut answer = qgm.clone();
+        answer.file_id = 416;
+        answer.messages[0].dialog_options.clear();
+        answer.messages[0].message_label = None;
+        let mut question = qgm;
+        question.messages[0].dialog_options[0] = "0BK0102.034\0".to_string().try_into().unwrap();
+
+        let dir = crate::commands::test_dir("qgm-validate");
+        std::fs::create_dir(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("160.qgm"), question.encode().unwrap()).unwrap();
+        std::fs::write(dir.join("sub").join("161.QGM"), answer.encode().unwrap()).unwrap();
+        std::fs::write(dir.join("notes.txt"), "not a QGM file").unwrap();
+        let mut out = Vec::new();
+        assert_eq!(validate(std::slice::from_ref(&dir), &mut out).unwrap(), 1);
+        assert_eq!(String::from_utf8(out).unwrap(), format!("{}:\n  0BJ0102.034 message label: LABEL0000001 does not exist\n1 dangling references in 1 of 2 files\n",
+            dir.join("160.qgm").display()));
+
+        // Without the file that has the answer
+        let mut out = Vec::new();
+        assert_eq!(validate(&[ dir.join("160.qgm") ], &mut out).unwrap(), 2);
+        assert!(String::from_utf8(out).unwrap().contains("  0BJ0102.034 dialog option 1: 0BK0102.034 does not exist\n"));
+
+        assert_eq!(validate(&[ dir.join("sub").join("161.QGM") ], &mut Vec::new()).unwrap(), 0);
+        assert!(validate(&[ dir.join("notes.txt") ], &mut Vec::new()).is_err());
+        std::fs::remove_file(dir.join("160.qgm")).unwrap();
+        std::fs::remove_dir_all(dir.join("sub")).unwrap();
+        assert_eq!(validate(&[ dir ], &mut Vec::new()).unwrap_err().to_string(), "no QGM files found");
+    }
+
+    #[test]
+    fn test_patch() {
+        let mut qgm = qfg5qgm::QgmDecoder::new(&fixtures::qgm()).unwrap();
+        qgm.messages.push(qfg5qgm::QgmMessage{ id: [ 1, 2, 3, 5 ], flags: qfg5qgm::FLAG_TEXT_MANGLED, ..qgm.messages[0].clone() });
+        let data = qgm.encode().unwrap();
+
+        let dir = crate::commands::test_dir("qgm-patch");
+        patch(&data, "0BJ0102.035", "Goodbye", &dir.join("out.qgm")).unwrap();
+        let out = std::fs::read(dir.join("out.qgm")).unwrap();
+        let patched = qfg5qgm::QgmDecoder::new(&out).unwrap();
+        assert_eq!(patched.messages[1].text, "Goodbye");
+        assert!(patched.messages[1].is_mangled());
+        // Up to the patched message the file is the same
+        let first = qfg5qgm::QgmDecoder{ messages: qgm.messages[..1].to_vec(), ..qgm.clone() }.encode().unwrap().len();
+        assert_eq!(out[..first], data[..first]);
+        qgm.messages[1].text = "Goodbye".to_string();
+        assert_eq!(patched, qgm);
+
+        let err = |label: &str| patch(&data, label, "x", &dir.join("err.qgm")).unwrap_err().to_string();
+        assert_eq!(err("0BJ0102.036"), "no message has label 0BJ0102.036");
+        assert_eq!(err("0BK0102.034"), "label 0BK0102.034 is not of file 415");
+        assert_eq!(err("hello"), "'hello' is not a label such as 0BJ0102.034");
+        qgm.messages.push(qgm.messages[0].clone());
+        let data = qgm.encode().unwrap();
+        assert_eq!(patch(&data, "0BJ0102.034", "x", &dir.join("err.qgm")).unwrap_err().to_string(), "label 0BJ0102.034 is used by 2 messages");
+        assert!(!dir.join("err.qgm").exists());
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = qfg5qgm::QgmDecoder::new(&fixtures::qgm()).unwrap();
+        let mut new = old.clone();
+        assert_eq!(diff(&old, &new, false), QgmDiff{ same: 1, ..Default::default() });
+        assert_eq!(diff(&old, &new, true).messages[0].status, DiffStatus::Same);
+
+        let m = &mut new.messages[0];
+        m.text = "Hi".to_string();
+        m.speaker_id = 6;
+        m.dialog_options[0] = "OPTION000002".to_string().try_into().unwrap();
+        m.message_label = None;
+        m.flags = qfg5qgm::FLAG_TEXT_MANGLED;
+        new.messages.push(qfg5qgm::QgmMessage{ id: [ 1, 2, 3, 5 ], ..old.messages[0].clone() });
+        let result = diff(&old, &new, false);
+        assert_eq!((result.added, result.removed, result.changed, result.same), (1, 0, 1, 0));
+        assert_eq!(result.messages[0].changes, [
+            Change::Text{ old: "Hello".to_string(), new: "Hi".to_string() },
+            Change::Speaker{ old: 5, new: 6 },
+            Change::DialogOptions{ added: vec![ "OPTION000002".to_string() ], removed: vec![ "OPTION000001".to_string() ] },
+            Change::MessageLabel{ old: Some("LABEL0000001".to_string()), new: None },
+            Change::Other{ fields: vec![ "flags" ] },
+        ]);
+        let mut out = Vec::new();
+        result.write(&mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), concat!("~ 0BJ0102.034\n",
+            "    text: 'Hello' -> 'Hi'\n",
+            "    speaker: 5 -> 6\n",
+            "    dialog options: +OPTION000002 -OPTION000001\n",
+            "    message label: LABEL0000001 -> none\n",
+            "    also differs: flags\n",
+            "+ 0BJ0102.035 'Hello'\n",
+            "1 added, 0 removed, 1 changed, 0 the same\n"));
+
+        let result = diff(&new, &old, false);
+        assert_eq!(result.messages[1], MessageDiff{ label: "0BJ0102.035".to_string(), status: DiffStatus::Removed, text: Some("Hello".to_string()), changes: Vec::new() });
+
+        // Only the order of the dialog options, or what follows a label
+        let mut options = old.clone();
+        options.messages[0].dialog_options.push("OPTION000002".to_string().try_into().unwrap());
+        let mut reordered = options.clone();
+        reordered.messages[0].dialog_options.reverse();
+        assert_eq!(diff(&options, &reordered, false).messages[0].changes, [ Change::Other{ fields: vec![ "dialog option order" ] } ]);
+        let mut padded = old.clone();
+        padded.messages[0].dialog_options[0] = "OPTION00001\0".to_string().try_into().unwrap();
+        let mut padded2 = padded.clone();
+        padded2.messages[0].dialog_options[0] = "OPTION00001 ".to_string().try_into().unwrap();
+        assert_eq!(diff(&padded, &padded2, false).messages[0].changes, [ Change::Other{ fields: vec![ "label padding" ] } ]);
+    }
+}
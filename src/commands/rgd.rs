@@ -0,0 +1,44 @@
+/*-
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * Copyright (c) 2024 Rink Springer <rink@rink.nu>
+ * For conditions of distribution and use, see LICENSE file
+ */
+use anyhow::Result;
+use std::io::Write;
+use std::path::Path;
+use crate::export::svg;
+use crate::qfg5resource::qfg5rgd;
+
+pub fn dump(rgd: &qfg5rgd::RgdDecoder, w: &mut dyn Write) -> Result<()> {
+    writeln!(w, "{} points, {} vectors, {} segments, {} regions, {} special",
+        rgd.points.len(), rgd.vectors.len(), rgd.segments.len(), rgd.regions.len(), rgd.special_region_ids.len())?;
+    for (n, region) in rgd.regions.iter().enumerate() {
+        let id = rgd.region_ids.get(n).map_or("?".to_string(), |id| id.to_string());
+        writeln!(w, "  region {}: id {}, {} segments{}", n, id, region.segment_ids.len(),
+            if rgd.is_special(n) { ", special" } else { "" })?;
+    }
+    Ok(())
+}
+
+/// Draws the regions to `path`, see svg::write_svg()
+pub fn export_svg(rgd: &qfg5rgd::RgdDecoder, path: &Path) -> Result<()> {
+    let mut w = std::io::BufWriter::new(std::fs::File::create(path)?);
+    svg::write_svg(&mut w, rgd)?;
+    w.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qfg5resource::fixtures;
+
+    #[test]
+    fn test_dump() {
+        let rgd = qfg5rgd::RgdDecoder::new(&fixtures::rgd()).unwrap();
+        let mut out = Vec::new();
+        dump(&rgd, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "3 points, 1 vectors, 3 segments, 1 regions, 1 special\n  region 0: id 42, 3 segments, special\n");
+    }
+}
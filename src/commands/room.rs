@@ -0,0 +1,128 @@
+/*-
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * Copyright (c) 2024 Rink Springer <rink@rink.nu>
+ * For conditions of distribution and use, see LICENSE file
+ */
+use anyhow::Result;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use crate::qfg5resource::{qfg5nod, room::Room};
+use super::{save_image, ImageFormat};
+
+pub fn dump(room: &Room, w: &mut dyn Write) -> Result<()> {
+    writeln!(w, "room {}: {}x{}, nod version {}", room.id, room.img.get_width(), room.img.get_height(), room.nod.get_version())?;
+    match &room.rgd {
+        Some(rgd) => writeln!(w, "  {} regions, {} special", rgd.regions.len(), rgd.special_region_ids.len())?,
+        None => writeln!(w, "  no regions")?,
+    }
+    Ok(())
+}
+
+/// Writes the background as `<id>.<ext>` and, if requested, the depth map as `<id>-depth.<ext>`
+/// to `out_dir`; returns the paths written
+pub fn render(room: &Room, depth: bool, out_dir: &Path, format: ImageFormat) -> Result<Vec<PathBuf>> {
+    let (width, height) = (room.img.get_width() as u32, room.img.get_height() as u32);
+    std::fs::create_dir_all(out_dir)?;
+    let palette = room.nod.get_palette();
+    let background = image::RgbaImage::from_fn(width, height, |x, y| {
+        let (r, g, b) = palette[room.img.get_pixels()[(y * width + x) as usize] as usize];
+        image::Rgba([ r, g, b, 255 ])
+    });
+    let path = out_dir.join(format!("{}.{}", room.id, format.extension()));
+    save_image(&background, &path, format)?;
+    let mut written = vec![ path ];
+
+    if depth {
+        let (width, height) = (room.zzz.get_width() as u32, room.zzz.get_height() as u32);
+        let depth_map = image::RgbaImage::from_fn(width, height, |x, y| {
+            let value = room.zzz.get_pixels()[(y * width + x) as usize];
+            image::Rgba([ value, value, value, 255 ])
+        });
+        let path = out_dir.join(format!("{}-depth.{}", room.id, format.extension()));
+        save_image(&depth_map, &path, format)?;
+        written.push(path);
+    }
+    Ok(written)
+}
+
+/// Writes the background scaled down to fit in `max_dim` x `max_dim` as `<id>-thumb.png` to
+/// `out_dir`
+pub fn thumbnail(room: &Room, out_dir: &Path, max_dim: u32) -> Result<PathBuf> {
+    std::fs::create_dir_all(out_dir)?;
+    let path = out_dir.join(format!("{}-thumb.png", room.id));
+    save_image(&room.img.thumbnail(room.nod.get_palette(), max_dim), &path, ImageFormat::Png)?;
+    Ok(path)
+}
+
+/// Size of the squares of the palette image, in pixels
+const SWATCH_SIZE: u32 = 8;
+
+/// Writes the palette of the room as an image of 16x16 squares, one per entry, to `path`
+pub fn render_palette(nod: &qfg5nod::NodDecoder, path: &Path, format: ImageFormat) -> Result<()> {
+    let palette = nod.get_palette();
+    let image = image::RgbaImage::from_fn(16 * SWATCH_SIZE, 16 * SWATCH_SIZE, |x, y| {
+        let (r, g, b) = palette[((y / SWATCH_SIZE) * 16 + x / SWATCH_SIZE) as usize];
+        image::Rgba([ r, g, b, 255 ])
+    });
+    save_image(&image, path, format)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qfg5resource::fixtures;
+
+    #[test]
+    fn test_render_palette() {
+        let dir = crate::commands::test_dir("room-palette");
+        let nod = qfg5nod::NodDecoder::new(&fixtures::nod()).unwrap();
+        render_palette(&nod, &dir.join("7.png"), ImageFormat::Png).unwrap();
+        let img = image::open(dir.join("7.png")).unwrap().to_rgba8();
+        assert_eq!(img.dimensions(), (16 * SWATCH_SIZE, 16 * SWATCH_SIZE));
+        assert_eq!(img.get_pixel(SWATCH_SIZE, SWATCH_SIZE - 1).0, [ 1, 254, 0, 255 ]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn load_room(dir: &Path) -> Room {
+        for (kind, data) in [ ("img", fixtures::img()), ("nod", fixtures::nod()), ("zzz", fixtures::zzz()), ("rgd", fixtures::rgd()) ] {
+            std::fs::create_dir_all(dir.join(kind)).unwrap();
+            std::fs::write(dir.join(kind).join(format!("7.{}", kind)), data).unwrap();
+        }
+        Room::load(dir, 7).unwrap()
+    }
+
+    #[test]
+    fn test_dump() {
+        let dir = crate::commands::test_dir("room-dump");
+        let room = load_room(&dir);
+        let mut out = Vec::new();
+        dump(&room, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "room 7: 4x2, nod version 4\n  1 regions, 1 special\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_render() {
+        let dir = crate::commands::test_dir("room-render");
+        let room = load_room(&dir);
+        let written = render(&room, true, &dir.join("out"), ImageFormat::Png).unwrap();
+        assert_eq!(written, vec![ dir.join("out/7.png"), dir.join("out/7-depth.png") ]);
+        let img = image::open(&written[0]).unwrap().to_rgba8();
+        assert_eq!(img.get_pixel(0, 0).0, [ 1, 254, 0, 255 ]);
+        assert_eq!(img.get_pixel(3, 1).0, [ 2, 253, 0, 255 ]);
+        let depth = image::open(&written[1]).unwrap().to_rgba8();
+        assert_eq!(depth.get_pixel(0, 1).0, [ 255, 255, 255, 255 ]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_thumbnail() {
+        let dir = crate::commands::test_dir("room-thumbnail");
+        let room = load_room(&dir);
+        let path = thumbnail(&room, &dir.join("out"), 2).unwrap();
+        assert_eq!(path, dir.join("out/7-thumb.png"));
+        assert_eq!(image::open(&path).unwrap().to_rgba8().dimensions(), (2, 1));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
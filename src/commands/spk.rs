@@ -0,0 +1,474 @@
+/*-
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * Copyright (c) 2024 Rink Springer <rink@rink.nu>
+ * For conditions of distribution and use, see LICENSE file
+ */
+use anyhow::{anyhow, Context, Result};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use crate::qfg5resource::{detect, names, qfg5anm, qfg5gra, qfg5mdl, qfg5qgf, qfg5qgm, qfg5rgd, qfg5spk, Qfg5Resource, ResourceKind};
+use super::{decode, ImageFormat};
+
+/// Opens the archive at `path`; `-` reads it from stdin into memory, as the archive needs to seek
+pub fn open(path: &Path) -> Result<qfg5spk::SpkArchive> {
+    if super::is_stdio(path) {
+        return Ok(qfg5spk::SpkArchive::from_bytes(super::read_input(path)?)?);
+    }
+    let file = std::fs::File::open(path).with_context(|| format!("cannot open {}", path.display()))?;
+    Ok(qfg5spk::SpkArchive::new(file)?)
+}
+
+/// Whether item `filename` matches `pattern`, without regard to case. `*` matches any number of
+/// characters and `?` a single one. A pattern without a directory is matched against the file
+/// name alone, so "*.qgm" finds "QGM/1.QGM"
+pub fn matches(pattern: &str, filename: &str) -> bool {
+    let name = if pattern.contains('/') { filename } else { filename.rsplit('/').next().unwrap_or("") };
+    let pattern: Vec<_> = pattern.chars().flat_map(char::to_lowercase).collect();
+    let name: Vec<_> = name.chars().flat_map(char::to_lowercase).collect();
+    // Where to continue after the last '*' if what follows it does not match
+    let mut backtrack = None;
+    let (mut p, mut n) = (0, 0);
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            },
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            },
+            _ => match backtrack {
+                Some((star, start)) => {
+                    backtrack = Some((star, start + 1));
+                    (p, n) = (star + 1, start + 1);
+                },
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// The items that match `filter`, or all items without one
+pub fn filter<'a>(archive: &'a qfg5spk::SpkArchive, filter: Option<&str>) -> Vec<&'a qfg5spk::SpkItem> {
+    archive.get_items().iter().filter(|item| filter.is_none_or(|pattern| matches(pattern, &item.filename))).collect()
+}
+
+pub fn list(archive: &qfg5spk::SpkArchive, filter: Option<&str>, w: &mut dyn Write) -> Result<()> {
+    let items = self::filter(archive, filter);
+    for item in &items {
+        let compression = match item.compression {
+            qfg5spk::SpkCompression::Stored => "stored".to_string(),
+            qfg5spk::SpkCompression::Deflate => format!("deflated to {} bytes", item.stored_length),
+        };
+        writeln!(w, "  {:>20}, {:>8} bytes @ offset 0x{:x}, {}", item.filename, item.length, item.offset, compression)?;
+    }
+    writeln!(w, "{} total", items.len())?;
+    Ok(())
+}
+
+/// Like list(), as CSV with a header row
+pub fn list_csv(archive: &qfg5spk::SpkArchive, filter: Option<&str>, w: &mut dyn Write) -> Result<()> {
+    writeln!(w, "filename,offset,length,compression,stored_length")?;
+    for item in self::filter(archive, filter) {
+        let compression = match item.compression {
+            qfg5spk::SpkCompression::Stored => "stored",
+            qfg5spk::SpkCompression::Deflate => "deflate",
+        };
+        writeln!(w, "{},{},{},{},{}", super::csv_field(&item.filename), item.offset, item.length, compression, item.stored_length)?;
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+pub struct ExtractOptions<'a> {
+    /// Also convert resources to common formats where possible, see convert()
+    pub convert: bool,
+    /// Only extract the items that match this pattern, see matches()
+    pub filter: Option<&'a str>,
+    pub case: qfg5spk::FileNameCase,
+}
+
+/// Extracts the items to `out_dir`, see SpkArchive::extract_items(); returns the number of items
+pub fn extract(archive: &qfg5spk::SpkArchive, out_dir: &Path, options: &ExtractOptions,
+               progress: impl FnMut(&qfg5spk::SpkItem, usize, usize)) -> Result<usize> {
+    let items = self::filter(archive, options.filter);
+    let paths = archive.extract_items(&items, out_dir, options.case, progress)?;
+    for (item, path) in items.iter().zip(paths) {
+        log::info!("extracted {}", path.display());
+        if options.convert {
+            // Resources that can be converted are small enough to read again
+            let data = archive.read_item(item)?;
+            match self::convert(&data, &path) {
+                Ok(written) => for path in written {
+                    log::info!("converted to {}", path.display());
+                },
+                Err(e) => log::warn!("cannot convert {}: {:#}", path.display(), e),
+            }
+        }
+    }
+    Ok(items.len())
+}
+
+/// Compares the CRC-32 of every item with the one in the directory, writing the items that differ
+/// or cannot be read; returns the number of those
+pub fn verify(archive: &qfg5spk::SpkArchive, w: &mut dyn Write) -> Result<usize> {
+    let mut failed = 0;
+    for item in archive.get_items() {
+        match archive.item_crc32(item) {
+            Ok(crc32) if crc32 == item.crc32 => { },
+            Ok(crc32) => {
+                writeln!(w, "  {}: expected CRC {:08x}, got {:08x}", item.filename, item.crc32, crc32)?;
+                failed += 1;
+            },
+            Err(e) => {
+                writeln!(w, "  {}: {}", item.filename, e)?;
+                failed += 1;
+            },
+        }
+    }
+    writeln!(w, "{} of {} items failed", failed, archive.get_items().len())?;
+    Ok(failed)
+}
+
+/// Item `name`. The name is compared without regard to case, and may leave out the directory if
+/// only one item has that file name, so "2000.img" finds "IMG/2000.IMG"
+pub fn find<'a>(archive: &'a qfg5spk::SpkArchive, name: &str) -> Result<&'a qfg5spk::SpkItem> {
+    let items = archive.get_items();
+    let file_name = |item: &&qfg5spk::SpkItem| item.filename.rsplit('/').next().unwrap_or("").eq_ignore_ascii_case(name);
+    let item = match archive.find(name) {
+        Some(item) => item,
+        None => match items.iter().filter(file_name).collect::<Vec<_>>()[..] {
+            [ item ] => item,
+            [] => return Err(anyhow!("there is no item {}", name)),
+            ref found => return Err(anyhow!("{} items are named {}, give the directory as well", found.len(), name)),
+        },
+    };
+    Ok(item)
+}
+
+/// Writes item `name` to `w`, see find()
+pub fn cat(archive: &qfg5spk::SpkArchive, name: &str, w: &mut dyn Write) -> Result<()> {
+    archive.read_item_to(find(archive, name)?, w)?;
+    w.flush()?;
+    Ok(())
+}
+
+/// Writes item `name` to `out_file`, see find()
+pub fn extract_one(archive: &qfg5spk::SpkArchive, name: &str, out_file: &Path) -> Result<()> {
+    let item = find(archive, name)?;
+    archive.extract_item(item, out_file)?;
+    log::info!("extracted {} to {}", item.filename, out_file.display());
+    Ok(())
+}
+
+/// Writes a copy of the archive at `spk_path` to `out` with item `name` (see find()) replaced by
+/// the contents of `file`
+pub fn replace(spk_path: &Path, name: &str, file: &Path, out: &Path) -> Result<()> {
+    if out.canonicalize().ok().is_some_and(|out| spk_path.canonicalize().ok() == Some(out)) {
+        return Err(anyhow!("{} would overwrite the archive it is read from", out.display()));
+    }
+    let archive = open(spk_path)?;
+    let item = find(&archive, name)?;
+    let data = super::read_input(file)?;
+    let f = std::fs::File::create(out).with_context(|| format!("cannot create {}", out.display()))?;
+    archive.replace_item(&item.filename, &data, std::io::BufWriter::new(f))?;
+    log::info!("replaced {} by {} in {}", item.filename, file.display(), out.display());
+    Ok(())
+}
+
+/// Writes the files below `dir` to a new archive `out`, the reverse of extract(); returns the
+/// number of items. Items are named after their path relative to `dir` in uppercase, like in the
+/// archives of the game.
+pub fn pack(dir: &Path, out: &Path) -> Result<usize> {
+    let mut files = Vec::new();
+    super::convert::walk(dir, Path::new(""), None, &mut files).with_context(|| format!("cannot read {}", dir.display()))?;
+    // The archive itself may be written into the directory, by a previous run for instance
+    let out_path = out.canonicalize().ok();
+    files.retain(|file| out_path.is_none() || dir.join(file).canonicalize().ok() != out_path);
+    let mut items = Vec::with_capacity(files.len());
+    for file in &files {
+        let components: Vec<_> = file.iter().map(|c| c.to_string_lossy().to_uppercase()).collect();
+        let data = std::fs::read(dir.join(file)).with_context(|| format!("cannot read {}", dir.join(file).display()))?;
+        items.push((components.join("/"), data));
+    }
+    let items: Vec<_> = items.iter().map(|(name, data)| (name.as_str(), &data[..])).collect();
+    let f = std::fs::File::create(out).with_context(|| format!("cannot create {}", out.display()))?;
+    qfg5spk::SpkArchive::create(std::io::BufWriter::new(f), &items)?;
+    log::info!("packed {} files into {}", items.len(), out.display());
+    Ok(items.len())
+}
+
+/// Writes a text dump next to `path`
+fn write_text(path: &Path, dump: impl FnOnce(&mut dyn Write) -> Result<()>) -> Result<Vec<PathBuf>> {
+    let text_path = path.with_extension("txt");
+    let mut w = std::io::BufWriter::new(std::fs::File::create(&text_path)?);
+    dump(&mut w)?;
+    w.flush()?;
+    Ok(vec![ text_path ])
+}
+
+/// A resource that can be converted to a common format
+trait Convert: Qfg5Resource {
+    /// Converts the resource extracted to `path`; returns the paths written
+    fn convert(&self, path: &Path) -> Result<Vec<PathBuf>>;
+}
+
+impl Convert for qfg5anm::AnmDecoder {
+    fn convert(&self, path: &Path) -> Result<Vec<PathBuf>> {
+        write_text(path, |w| super::anm::dump(self, w))
+    }
+}
+
+impl Convert for qfg5gra::GraDecoder<'static> {
+    fn convert(&self, path: &Path) -> Result<Vec<PathBuf>> {
+        super::gra::export(self, None, &path.with_extension(""), ImageFormat::Png)
+    }
+}
+
+impl Convert for qfg5mdl::Qfg5Model<'static> {
+    fn convert(&self, path: &Path) -> Result<Vec<PathBuf>> {
+        super::mdl::export_obj(self, super::mdl::ExportPose::Exploded, &path.with_extension("obj"))
+    }
+}
+
+impl Convert for qfg5qgf::QgfDecoder {
+    fn convert(&self, path: &Path) -> Result<Vec<PathBuf>> {
+        let png_path = path.with_extension("png");
+        super::qgf::render(self, None, &png_path, ImageFormat::Png)?;
+        Ok(vec![ png_path ])
+    }
+}
+
+impl Convert for qfg5qgm::QgmDecoder {
+    fn convert(&self, path: &Path) -> Result<Vec<PathBuf>> {
+        write_text(path, |w| super::qgm::list(self, &Default::default(), w))
+    }
+}
+
+impl Convert for qfg5rgd::RgdDecoder {
+    fn convert(&self, path: &Path) -> Result<Vec<PathBuf>> {
+        write_text(path, |w| super::rgd::dump(self, w))
+    }
+}
+
+type Converter = fn(&[u8], &Path) -> Result<Vec<PathBuf>>;
+
+const fn converter<T: Convert>() -> (ResourceKind, Converter) {
+    (T::KIND, |data, path| decode::<T>(data)?.convert(path))
+}
+
+/// The resources convert() handles, by kind
+static CONVERTERS: [ (ResourceKind, Converter); 6 ] = [
+    converter::<qfg5anm::AnmDecoder>(),
+    converter::<qfg5gra::GraDecoder>(),
+    converter::<qfg5mdl::Qfg5Model>(),
+    converter::<qfg5qgf::QgfDecoder>(),
+    converter::<qfg5qgm::QgmDecoder>(),
+    converter::<qfg5rgd::RgdDecoder>(),
+];
+
+/// Converts a resource extracted to `path` based on its contents: models to .obj, sprites and
+/// fonts to .png and the other resources to a .txt dump. Room backgrounds and depth maps need
+/// the rest of the room, so they are left to `qfg5 room`. Returns the paths written
+pub fn convert(data: &[u8], path: &Path) -> Result<Vec<PathBuf>> {
+    let Some(detection) = detect(data) else {
+        log::debug!("{}: not a known resource", path.display());
+        return Ok(Vec::new())
+    };
+    let extension = path.extension().and_then(|e| e.to_str()).and_then(ResourceKind::from_extension);
+    if extension.is_some_and(|kind| kind != detection.kind) {
+        log::warn!("{}: contents look like {} ({} confidence)", path.display(), detection.kind, detection.confidence);
+    }
+
+    match CONVERTERS.iter().find(|(kind, _)| *kind == detection.kind) {
+        Some((_, convert)) => convert(data, path),
+        None => {
+            if let Some(id) = names::parse_room_id(path) {
+                log::debug!("{}: part of room {}, see `qfg5 room`", path.display(), id);
+            }
+            Ok(Vec::new())
+        },
+    }
+}
+
+/// Writes thumbnails of the textures of a model extracted to `path`, as `<stem>-<n>-thumb.png`
+/// next to the textures convert() writes; returns the paths written. Other resources have no
+/// textures, and get no thumbnails.
+pub fn thumbnails(data: &[u8], path: &Path, max_dim: u32) -> Result<Vec<PathBuf>> {
+    if detect(data).map(|d| d.kind) != Some(ResourceKind::Mdl) {
+        return Ok(Vec::new());
+    }
+    let model = decode::<qfg5mdl::Qfg5Model>(data)?;
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let mut written = Vec::new();
+    for (n, subbitmap) in model.subbitmaps.iter().enumerate() {
+        let thumb_path = path.with_file_name(format!("{}-{}-thumb.png", stem, n));
+        super::save_image(&subbitmap.thumbnail(&model.palette, max_dim), &thumb_path, ImageFormat::Png)?;
+        written.push(thumb_path);
+    }
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qfg5resource::fixtures;
+
+    #[test]
+    fn test_list_extract() {
+        let dir = crate::commands::test_dir("spk");
+        let spk_path = dir.join("test.spk");
+        std::fs::write(&spk_path, fixtures::spk(&[ ("MDL/1.MDL", b"abc") ])).unwrap();
+        let archive = qfg5spk::SpkArchive::new(std::fs::File::open(&spk_path).unwrap()).unwrap();
+
+        let mut out = Vec::new();
+        list(&archive, None, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("MDL/1.MDL,        3 bytes @ offset 0x4b, stored\n"), "{}", out);
+        assert!(out.ends_with("1 total\n"));
+
+        let compressed = qfg5spk::SpkArchive::from_bytes(fixtures::spk_compressed(&[ ("A/1.NOD", &[ 0u8; 1000 ], true) ])).unwrap();
+        let mut out = Vec::new();
+        list(&compressed, None, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("A/1.NOD,     1000 bytes @ offset 0x49, deflated to "), "{}", out);
+
+        assert_eq!(extract(&archive, &dir.join("out"), &ExtractOptions::default(), |_, _, _| { }).unwrap(), 1);
+        assert_eq!(std::fs::read(dir.join("out/mdl/1.mdl")).unwrap(), b"abc");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_cat() {
+        let archive = qfg5spk::SpkArchive::from_bytes(fixtures::spk(&[ ("IMG/2000.IMG", b"abc"), ("A/1.NOD", b"1"), ("B/1.NOD", b"2") ])).unwrap();
+        for name in [ "IMG/2000.IMG", "img/2000.img", "2000.IMG" ] {
+            let mut out = Vec::new();
+            cat(&archive, name, &mut out).unwrap();
+            assert_eq!(out, b"abc");
+        }
+        let mut out = Vec::new();
+        cat(&archive, "b/1.nod", &mut out).unwrap();
+        assert_eq!(out, b"2");
+        assert_eq!(cat(&archive, "1.nod", &mut out).unwrap_err().to_string(), "2 items are named 1.nod, give the directory as well");
+        assert_eq!(cat(&archive, "3.nod", &mut out).unwrap_err().to_string(), "there is no item 3.nod");
+    }
+
+    #[test]
+    fn test_list_csv() {
+        let archive = qfg5spk::SpkArchive::from_bytes(fixtures::spk(&[ ("MDL/1.MDL", b"abc"), ("A,\"B\"", b"") ])).unwrap();
+        let mut out = Vec::new();
+        list_csv(&archive, None, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "filename,offset,length,compression,stored_length\n\
+            MDL/1.MDL,75,3,stored,3\n\"A,\"\"B\"\"\",149,0,stored,0\n");
+    }
+
+    #[test]
+    fn test_verify() {
+        let mut data = fixtures::spk(&[ ("MDL/1.MDL", b"abc"), ("QGM/2.QGM", b"de") ]);
+        let archive = qfg5spk::SpkArchive::from_bytes(data.clone()).unwrap();
+        let mut out = Vec::new();
+        assert_eq!(verify(&archive, &mut out).unwrap(), 0);
+        assert_eq!(String::from_utf8(out).unwrap(), "0 of 2 items failed\n");
+
+        data[archive.get_items()[1].offset as usize] = b'x';
+        let archive = qfg5spk::SpkArchive::from_bytes(data).unwrap();
+        let mut out = Vec::new();
+        assert_eq!(verify(&archive, &mut out).unwrap(), 1);
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out, format!("  QGM/2.QGM: expected CRC {:08x}, got {:08x}\n1 of 2 items failed\n", crc32fast::hash(b"de"), crc32fast::hash(b"xe")));
+    }
+
+    #[test]
+    fn test_filter() {
+        for (pattern, filename) in [ ("*.qgm", "QGM/1.QGM"), ("hero.mdl", "MDL/HERO.MDL"), ("mdl/h*", "MDL/HERO.MDL"), ("*", "A"),
+                                     ("?.n*d", "A/1.NOD"), ("*e*o*", "MDL/HERO.MDL"), ("a/*", "A/1/2.NOD") ] {
+            assert!(matches(pattern, filename), "{} {}", pattern, filename);
+        }
+        for (pattern, filename) in [ ("*.qgm", "QGM/1.QGM.BAK"), ("1.nod", "A/11.NOD"), ("?", "A/12"), ("b/*", "A/1.NOD"), ("*.mdl", "MDL/") ] {
+            assert!(!matches(pattern, filename), "{} {}", pattern, filename);
+        }
+
+        let dir = crate::commands::test_dir("spk-filter");
+        let archive = qfg5spk::SpkArchive::from_bytes(fixtures::spk(&[ ("QGM/1.QGM", b"1"), ("MDL/HERO.MDL", b"2"), ("QGM/2.QGM", b"3") ])).unwrap();
+        let mut out = Vec::new();
+        list(&archive, Some("*.qgm"), &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("QGM/2.QGM") && !out.contains("HERO"), "{}", out);
+        assert!(out.ends_with("2 total\n"), "{}", out);
+
+        assert_eq!(extract(&archive, &dir.join("out"), &ExtractOptions{ filter: Some("*.QGM"), ..Default::default() }, |_, _, _| { }).unwrap(), 2);
+        assert!(dir.join("out/qgm/2.qgm").exists());
+        assert!(!dir.join("out/mdl").exists());
+
+        extract_one(&archive, "hero.mdl", &dir.join("hero")).unwrap();
+        assert_eq!(std::fs::read(dir.join("hero")).unwrap(), b"2");
+        assert_eq!(extract_one(&archive, "3.qgm", &dir.join("x")).unwrap_err().to_string(), "there is no item 3.qgm");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_pack() {
+        let dir = crate::commands::test_dir("spk-pack");
+        let archive = qfg5spk::SpkArchive::from_bytes(fixtures::spk(&[ ("MDL/1.MDL", b"abc"), ("QGM/SUB/2.QGM", b"de"), ("README", b"") ])).unwrap();
+        extract(&archive, &dir.join("data"), &ExtractOptions::default(), |_, _, _| { }).unwrap();
+        // Written into the directory that is packed, which must not include it
+        let spk_path = dir.join("data/new.spk");
+        assert_eq!(pack(&dir.join("data"), &spk_path).unwrap(), 3);
+        assert_eq!(pack(&dir.join("data"), &spk_path).unwrap(), 3);
+
+        let packed = open(&spk_path).unwrap();
+        let names: Vec<_> = packed.get_items().iter().map(|item| item.filename.as_str()).collect();
+        assert_eq!(names, [ "MDL/1.MDL", "QGM/SUB/2.QGM", "README" ]);
+        for (item, original) in packed.get_items().iter().zip(archive.get_items()) {
+            assert_eq!(packed.read_item(item).unwrap(), archive.read_item(original).unwrap());
+        }
+        assert!(pack(&dir.join("missing"), &dir.join("x.spk")).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_replace() {
+        let dir = crate::commands::test_dir("spk-replace");
+        let spk_path = dir.join("test.spk");
+        std::fs::write(&spk_path, fixtures::spk(&[ ("QGM/1.QGM", b"old"), ("MDL/2.MDL", b"model") ])).unwrap();
+        std::fs::write(dir.join("new.qgm"), b"new text").unwrap();
+        replace(&spk_path, "1.qgm", &dir.join("new.qgm"), &dir.join("new.spk")).unwrap();
+        let replaced = open(&dir.join("new.spk")).unwrap();
+        assert_eq!(replaced.read_item(replaced.find("QGM/1.QGM").unwrap()).unwrap(), b"new text");
+        assert_eq!(replaced.read_item(replaced.find("MDL/2.MDL").unwrap()).unwrap(), b"model");
+
+        let err = replace(&spk_path, "1.qgm", &dir.join("new.qgm"), &spk_path).unwrap_err().to_string();
+        assert!(err.ends_with("would overwrite the archive it is read from"), "{}", err);
+        assert_eq!(open(&spk_path).unwrap().get_items()[0].length, 3);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_open() {
+        let dir = crate::commands::test_dir("spk-open");
+        let spk_path = dir.join("test.spk");
+        std::fs::write(&spk_path, fixtures::spk(&[ ("MDL/1.MDL", b"abc") ])).unwrap();
+        assert_eq!(open(&spk_path).unwrap().get_items().len(), 1);
+        assert!(open(&dir.join("missing.spk")).err().unwrap().to_string().starts_with("cannot open "));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_extract_convert() {
+        let dir = crate::commands::test_dir("spk-convert");
+        let spk_path = dir.join("test.spk");
+        let (mdl, qgm, gra) = (fixtures::mdl(), fixtures::qgm(), fixtures::gra());
+        std::fs::write(&spk_path, fixtures::spk(&[ ("MDL/1.MDL", &mdl), ("QGM/2.QGM", &qgm), ("GRA/3.GRA", &gra), ("WAV/4.WAV", b"RIFF") ])).unwrap();
+        let archive = qfg5spk::SpkArchive::new(std::fs::File::open(&spk_path).unwrap()).unwrap();
+
+        assert_eq!(extract(&archive, &dir.join("out"), &ExtractOptions{ convert: true, ..Default::default() }, |_, _, _| { }).unwrap(), 4);
+        assert!(dir.join("out/mdl/1.obj").exists());
+        assert!(std::fs::read_to_string(dir.join("out/qgm/2.txt")).unwrap().contains("'Hello'"));
+        assert!(dir.join("out/gra/3/0-1.png").exists());
+        assert!(dir.join("out/wav/4.wav").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,14 @@
+/*-
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * Copyright (c) 2024 Rink Springer <rink@rink.nu>
+ * For conditions of distribution and use, see LICENSE file
+ */
+pub mod bmfont;
+pub mod dot;
+pub mod gif;
+pub mod gltf;
+pub mod obj;
+pub mod po;
+pub mod svg;
+pub mod uvmap;
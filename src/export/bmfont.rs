@@ -0,0 +1,57 @@
+/*-
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * Copyright (c) 2024 Rink Springer <rink@rink.nu>
+ * For conditions of distribution and use, see LICENSE file
+ */
+//! AngelCode BMFont descriptors in the text format, which most game engines and font tools read.
+//! The glyphs are those of the character sheet of QgfDecoder::render_sheet(), so the descriptor
+//! goes with an image of that sheet.
+use anyhow::Result;
+use std::io::Write;
+use crate::qfg5resource::qfg5qgf;
+
+/// Writes the descriptor of the sheet of `columns` glyphs per line stored in `page_file`, for
+/// the first 256 characters: text is single-byte, see QgfDecoder::glyph_index()
+pub fn write_bmfont<W: Write + ?Sized>(w: &mut W, qgf: &qfg5qgf::QgfDecoder, face: &str, page_file: &str, columns: u32) -> Result<()> {
+    let columns = columns.max(1);
+    let (cell_width, cell_height) = (qgf.max_char_width + 1, qgf.char_height + 1);
+    let num_lines = (qgf.chars.len() as u32).div_ceil(columns);
+    let chars = &qgf.chars[..qgf.chars.len().min(256)];
+    writeln!(w, "info face={} size={} bold=0 italic=0 charset=\"\" unicode=1 stretchH=100 smooth=0 aa={} padding=0,0,0,0 spacing=1,1",
+        string(face), qgf.char_height, if qgf.is_3d { 4 } else { 1 })?;
+    writeln!(w, "common lineHeight={} base={} scaleW={} scaleH={} pages=1 packed=0",
+        qgf.char_height, qgf.char_height, columns * cell_width, num_lines * cell_height)?;
+    writeln!(w, "page id=0 file={}", string(page_file))?;
+    writeln!(w, "chars count={}", chars.len())?;
+    for (n, ch) in chars.iter().enumerate() {
+        let (x, y) = ((n as u32 % columns) * cell_width, (n as u32 / columns) * cell_height);
+        writeln!(w, "char id={} x={} y={} width={} height={} xoffset=0 yoffset=0 xadvance={} page=0 chnl=15",
+            n, x, y, ch.width, qgf.char_height, ch.width + qgf.char_space)?;
+    }
+    Ok(())
+}
+
+/// Quoted, with the quotes BMFont cannot escape left out
+fn string(s: &str) -> String {
+    format!("\"{}\"", s.replace('"', ""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qfg5resource::fixtures;
+
+    #[test]
+    fn test_write_bmfont() {
+        let qgf = qfg5qgf::QgfDecoder::new(&fixtures::qgf()).unwrap();
+        let mut out = Vec::new();
+        write_bmfont(&mut out, &qgf, "font \"1\"", "1.png", 32).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("info face=\"font 1\" size=2 "), "{}", out);
+        assert!(out.contains("\ncommon lineHeight=2 base=2 scaleW=96 scaleH=48 pages=1 packed=0\npage id=0 file=\"1.png\"\nchars count=256\n"), "{}", out);
+        // 'A' is the second character of the third line
+        assert!(out.contains("\nchar id=65 x=3 y=6 width=2 height=2 xoffset=0 yoffset=0 xadvance=3 page=0 chnl=15\n"), "{}", out);
+        assert!(out.contains("\nchar id=32 x=0 y=3 width=0 height=2 xoffset=0 yoffset=0 xadvance=1 page=0 chnl=15\n"), "{}", out);
+    }
+}
@@ -0,0 +1,138 @@
+/*-
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * Copyright (c) 2024 Rink Springer <rink@rink.nu>
+ * For conditions of distribution and use, see LICENSE file
+ */
+//! The conversations of QGM files as a Graphviz DOT graph: every message is a node named after its
+//! label, with edges to its dialog options and its message label.
+use anyhow::Result;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::io::Write;
+use crate::qfg5resource::qfg5qgm;
+
+/// Characters of the text shown in a node
+const MAX_TEXT: usize = 40;
+
+/// Writes the messages of all `qgms` as one graph. References to labels that are in none of
+/// them become dashed nodes. Messages whose ids do not fit in a label are left out, and so are
+/// messages with the same label as an earlier one.
+pub fn write_dot<W: Write + ?Sized>(w: &mut W, qgms: &[&qfg5qgm::QgmDecoder]) -> Result<()> {
+    let mut nodes = HashMap::new();
+    for qgm in qgms {
+        for m in &qgm.messages {
+            if let Some(label) = qfg5qgm::QgmLabel::try_encode(qgm, m) {
+                nodes.entry((qgm.file_id, m.id)).or_insert(label);
+            }
+        }
+    }
+    // A label refers to the message with its ids, or else to nothing loaded
+    let target = |label: &qfg5qgm::QgmLabel| -> (String, bool) {
+        let text = label.to_string();
+        let text = text.trim_end_matches([ '\0', ' ' ]);
+        match qfg5qgm::QgmLabel::parse(text).and_then(|ids| nodes.get(&ids)) {
+            Some(node) => (node.clone(), true),
+            None => (text.to_string(), false),
+        }
+    };
+
+    writeln!(w, "digraph messages {{")?;
+    writeln!(w, "    node [shape=box];")?;
+    let mut dangling = BTreeSet::new();
+    let mut written = HashSet::new();
+    for qgm in qgms {
+        for m in &qgm.messages {
+            let Some(node) = nodes.get(&(qgm.file_id, m.id)) else { continue };
+            if !written.insert(node) {
+                continue;
+            }
+            let mut text: String = m.text.chars().take(MAX_TEXT).collect();
+            if text.len() < m.text.len() {
+                text.push_str("...");
+            }
+            writeln!(w, "    {} [label={}];", quote(node), quote(&format!("{}\nspeaker {}: {}", node, m.speaker_id, text)))?;
+            for (n, option) in m.dialog_options.iter().enumerate() {
+                let (to, found) = target(option);
+                writeln!(w, "    {} -> {} [label=\"{}\"];", quote(node), quote(&to), n + 1)?;
+                if !found {
+                    dangling.insert(to);
+                }
+            }
+            if let Some(label) = &m.message_label {
+                let (to, found) = target(label);
+                writeln!(w, "    {} -> {} [style=dotted];", quote(node), quote(&to))?;
+                if !found {
+                    dangling.insert(to);
+                }
+            }
+        }
+    }
+    for node in dangling {
+        writeln!(w, "    {} [style=dashed];", quote(&node))?;
+    }
+    writeln!(w, "}}")?;
+    Ok(())
+}
+
+/// A DOT string; line breaks become centered lines
+fn quote(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for ch in s.chars() {
+        match ch {
+            '"' | '\\' => { quoted.push('\\'); quoted.push(ch) },
+            '\n' => quoted.push_str("\\n"),
+            '\r' => { },
+            _ => quoted.push(ch),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qfg5resource::fixtures;
+
+    fn dot(qgms: &[&qfg5qgm::QgmDecoder]) -> String {
+        let mut out = Vec::new();
+        write_dot(&mut out, qgms).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_write_dot() {
+        let qgm = qfg5qgm::QgmDecoder::new(&fixtures::qgm()).unwrap();
+        assert_eq!(dot(&[ &qgm ]), concat!("digraph messages {\n",
+            "    node [shape=box];\n",
+            "    \"0BJ0102.034\" [label=\"0BJ0102.034\\nspeaker 5: Hello\"];\n",
+            "    \"0BJ0102.034\" -> \"OPTION000001\" [label=\"1\"];\n",
+            "    \"0BJ0102.034\" -> \"LABEL0000001\" [style=dotted];\n",
+            "    \"LABEL0000001\" [style=dashed];\n",
+            "    \"OPTION000001\" [style=dashed];\n",
+            "}\n"));
+    }
+
+    #[test]
+    fn test_references() {
+        let qgm = qfg5qgm::QgmDecoder::new(&fixtures::qgm()).unwrap();
+        // An answer in another file that the first message offers as its option
+        let mut other = qgm.clone();
+        other.file_id = 416;
+        other.messages[0].text = format!("Say \"{}\"", "x".repeat(50));
+        other.messages[0].dialog_options.clear();
+        other.messages[0].message_label = None;
+        let mut qgm = qgm;
+        qgm.messages[0].dialog_options[0] = "0BK0102.034\0".to_string().try_into().unwrap();
+        let out = dot(&[ &qgm, &other ]);
+        assert!(out.contains("    \"0BJ0102.034\" -> \"0BK0102.034\" [label=\"1\"];\n"), "{}", out);
+        assert!(out.contains(&format!("    \"0BK0102.034\" [label=\"0BK0102.034\\nspeaker 5: Say \\\"{}...\"];\n", "x".repeat(35))), "{}", out);
+        assert!(!out.contains("\"0BK0102.034\" [style=dashed]"), "{}", out);
+        assert!(out.contains("\"LABEL0000001\" [style=dashed]"), "{}", out);
+
+        // A file given twice does not repeat its messages
+        let twice = dot(&[ &other, &other ]);
+        assert_eq!(twice, dot(&[ &other ]));
+    }
+}
@@ -0,0 +1,202 @@
+/*-
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * Copyright (c) 2024 Rink Springer <rink@rink.nu>
+ * For conditions of distribution and use, see LICENSE file
+ */
+//! Animated GIF. The frames are palette indices already, so the palette becomes the global color
+//! table as it is: the colors come out exactly as in the game and nothing needs to be quantized.
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::io::Write;
+use crate::qfg5resource::qfg5nod::PaletteEntry;
+
+/// GIF codes are at most 12 bits
+const MAX_CODE: u16 = 4095;
+
+pub struct GifFrame<'a> {
+    /// Palette indices, width * height of them
+    pub pixels: &'a [u8],
+    /// In hundredths of a second
+    pub delay: u16,
+}
+
+/// Writes `frames` as an endlessly looping animation. Pixels with `transparent_index` show the
+/// background, not the frame before
+pub fn write_gif<W: Write>(w: &mut W, width: u16, height: u16, palette: &[PaletteEntry], transparent_index: Option<u8>, frames: &[GifFrame]) -> Result<()> {
+    if palette.len() > 256 {
+        return Err(anyhow!("a GIF palette has at most 256 entries, not {}", palette.len()));
+    }
+    w.write_all(b"GIF89a")?;
+    w.write_all(&width.to_le_bytes())?;
+    w.write_all(&height.to_le_bytes())?;
+    // Global color table of 256 entries, 8 bits per primary
+    w.write_all(&[ 0xf7, 0, 0 ])?;
+    for n in 0..256 {
+        let (r, g, b) = palette.get(n).copied().unwrap_or_default();
+        w.write_all(&[ r, g, b ])?;
+    }
+    w.write_all(b"\x21\xff\x0bNETSCAPE2.0\x03\x01\x00\x00\x00")?;
+
+    for frame in frames {
+        if frame.pixels.len() != width as usize * height as usize {
+            return Err(anyhow!("frame has {} pixels instead of {}x{}", frame.pixels.len(), width, height));
+        }
+        // Graphic control extension: restore to the background after the frame
+        w.write_all(&[ 0x21, 0xf9, 4, 0x08 | transparent_index.is_some() as u8 ])?;
+        w.write_all(&frame.delay.to_le_bytes())?;
+        w.write_all(&[ transparent_index.unwrap_or(0), 0 ])?;
+        // Image descriptor covering the whole screen, without a local color table
+        w.write_all(&[ 0x2c, 0, 0, 0, 0 ])?;
+        w.write_all(&width.to_le_bytes())?;
+        w.write_all(&height.to_le_bytes())?;
+        w.write_all(&[ 0, 8 ])?;
+        for block in lzw_encode(frame.pixels, 8).chunks(255) {
+            w.write_all(&[ block.len() as u8 ])?;
+            w.write_all(block)?;
+        }
+        w.write_all(&[ 0 ])?;
+    }
+    w.write_all(&[ 0x3b ])?;
+    Ok(())
+}
+
+/// Codes are packed starting at the least significant bit
+struct BitWriter {
+    out: Vec<u8>,
+    bits: u32,
+    count: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, code: u16, size: u32) {
+        self.bits |= (code as u32) << self.count;
+        self.count += size;
+        while self.count >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.out.push(self.bits as u8);
+        }
+        self.out
+    }
+}
+
+/// Variable-length LZW as GIF uses it. The code size grows once the code about to be assigned
+/// no longer fits, and the table is started over when it is full.
+fn lzw_encode(pixels: &[u8], min_code_size: u32) -> Vec<u8> {
+    let clear = 1u16 << min_code_size;
+    let end = clear + 1;
+    let mut w = BitWriter{ out: Vec::new(), bits: 0, count: 0 };
+    let mut table: HashMap<(u16, u8), u16> = HashMap::new();
+    let (mut code_size, mut next) = (min_code_size + 1, end + 1);
+    w.write(clear, code_size);
+
+    let Some((&first, rest)) = pixels.split_first() else {
+        w.write(end, code_size);
+        return w.finish();
+    };
+    let mut prefix = first as u16;
+    for &pixel in rest {
+        if let Some(&code) = table.get(&(prefix, pixel)) {
+            prefix = code;
+            continue;
+        }
+        w.write(prefix, code_size);
+        if next >= MAX_CODE {
+            w.write(clear, code_size);
+            table.clear();
+            (code_size, next) = (min_code_size + 1, end + 1);
+        } else {
+            if next >= 1 << code_size {
+                code_size += 1;
+            }
+            table.insert((prefix, pixel), next);
+            next += 1;
+        }
+        prefix = pixel as u16;
+    }
+    w.write(prefix, code_size);
+    if next >= 1 << code_size && code_size < 12 {
+        code_size += 1;
+    }
+    w.write(end, code_size);
+    w.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decodes GIF LZW data as a decoder would
+    fn lzw_decode(data: &[u8], min_code_size: usize) -> Vec<u8> {
+        let clear = 1usize << min_code_size;
+        let mut table: Vec<Vec<u8>> = Vec::new();
+        let (mut code_size, mut bit) = (min_code_size + 1, 0);
+        let mut prev: Option<usize> = None;
+        let mut out = Vec::new();
+        loop {
+            let code = (0..code_size).map(|n| ((data[(bit + n) / 8] >> ((bit + n) % 8)) & 1) as usize)
+                .enumerate().fold(0, |code, (n, b)| code | b << n);
+            bit += code_size;
+            if code == clear {
+                table = (0..clear).map(|n| vec![ n as u8 ]).chain([ vec![], vec![] ]).collect();
+                (code_size, prev) = (min_code_size + 1, None);
+                continue;
+            }
+            if code == clear + 1 {
+                return out;
+            }
+            let entry = match table.get(code) {
+                Some(entry) => entry.clone(),
+                None => {
+                    assert_eq!(code, table.len());
+                    let prev = &table[prev.unwrap()];
+                    [ &prev[..], &prev[..1] ].concat()
+                },
+            };
+            out.extend(&entry);
+            if let Some(prev) = prev.filter(|_| table.len() < 4096) {
+                table.push([ &table[prev][..], &entry[..1] ].concat());
+            }
+            if table.len() == 1 << code_size && code_size < 12 {
+                code_size += 1;
+            }
+            prev = Some(code);
+        }
+    }
+
+    #[test]
+    fn test_lzw() {
+        for pixels in [ vec![], vec![ 7 ], vec![ 1, 1, 1, 1, 1, 1, 1 ], (0..=255).collect() ] {
+            assert_eq!(lzw_decode(&lzw_encode(&pixels, 8), 8), pixels);
+        }
+        // Long enough to grow to 12 bits and start the table over
+        let mut seed = 1u32;
+        let pixels: Vec<u8> = (0..100_000).map(|_| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 28) as u8
+        }).collect();
+        assert_eq!(lzw_decode(&lzw_encode(&pixels, 8), 8), pixels);
+    }
+
+    #[test]
+    fn test_write_gif() {
+        let mut out = Vec::new();
+        let frames = [ GifFrame{ pixels: &[ 0, 1, 1, 0 ], delay: 10 }, GifFrame{ pixels: &[ 1, 0, 0, 1 ], delay: 20 } ];
+        write_gif(&mut out, 2, 2, &[ (0, 0, 0), (255, 0, 0) ], Some(0), &frames).unwrap();
+        assert!(out.starts_with(b"GIF89a\x02\x00\x02\x00\xf7\x00\x00\x00\x00\x00\xff\x00\x00\x00\x00\x00"));
+        // Each frame is 8 bytes of control extension, 11 of descriptor and 9 of image data
+        assert_eq!(out.len(), 13 + 768 + 19 + 2 * 28 + 1);
+        let gce = 13 + 768 + 19;
+        assert_eq!(out[gce..gce + 8], [ 0x21, 0xf9, 4, 0x09, 10, 0, 0, 0 ]);
+        assert_eq!(out.last(), Some(&0x3b));
+
+        assert!(write_gif(&mut Vec::new(), 2, 2, &[], None, &[ GifFrame{ pixels: &[ 0 ], delay: 0 } ]).is_err());
+    }
+}
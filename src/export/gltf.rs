@@ -0,0 +1,321 @@
+/*-
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * Copyright (c) 2024 Rink Springer <rink@rink.nu>
+ * For conditions of distribution and use, see LICENSE file
+ */
+//! glTF 2.0, which unlike OBJ keeps a material per face and can carry the animation. The model
+//! becomes a node with a child node per submesh; the subbitmaps are embedded in the buffer as PNG.
+use anyhow::{anyhow, Result};
+use cgmath::{InnerSpace, Vector3};
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{Cursor, Write};
+use std::path::{Path, PathBuf};
+use crate::qfg5resource::{qfg5anm, qfg5mdl};
+use crate::threed::pose::Pose;
+
+const FLOAT: u32 = 5126;
+const ARRAY_BUFFER: u32 = 34962;
+const NEAREST: u32 = 9728;
+/// Turns the model's Z-up axis into the Y-up of glTF, as Pose::submesh() does
+const Z_UP_ROTATION: [ f32; 4 ] = [ -std::f32::consts::FRAC_1_SQRT_2, 0.0, 0.0, std::f32::consts::FRAC_1_SQRT_2 ];
+
+/// A .gltf file and the buffer it refers to
+#[derive(Debug)]
+pub struct Gltf {
+    pub json: String,
+    pub bin: Vec<u8>,
+}
+
+/// Collects the buffer and the buffer views and accessors into it
+#[derive(Default)]
+struct Builder {
+    bin: Vec<u8>,
+    buffer_views: Vec<String>,
+    accessors: Vec<String>,
+}
+
+impl Builder {
+    /// Appends `data` as a buffer view, keeping everything after it aligned to 4 bytes
+    fn buffer_view(&mut self, data: &[u8], target: Option<u32>) -> usize {
+        let offset = self.bin.len();
+        self.bin.extend(data);
+        self.bin.resize(self.bin.len().next_multiple_of(4), 0);
+        let target = target.map(|target| format!(",\"target\":{}", target)).unwrap_or_default();
+        self.buffer_views.push(format!("{{\"buffer\":0,\"byteOffset\":{},\"byteLength\":{}{}}}", offset, data.len(), target));
+        self.buffer_views.len() - 1
+    }
+
+    /// Adds an accessor to `values`, `components` floats per element; with `bounds`, it has the
+    /// min and max that glTF requires of positions and animation times
+    fn floats(&mut self, values: &[f32], components: usize, target: Option<u32>, bounds: bool) -> usize {
+        let data: Vec<u8> = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+        let view = self.buffer_view(&data, target);
+        let kind = match components { 1 => "SCALAR", 2 => "VEC2", 3 => "VEC3", _ => "VEC4" };
+        let mut accessor = format!("{{\"bufferView\":{},\"componentType\":{},\"count\":{},\"type\":\"{}\"", view, FLOAT, values.len() / components, kind);
+        if bounds {
+            let (mut min, mut max) = (vec![ f32::MAX; components ], vec![ f32::MIN; components ]);
+            for element in values.chunks_exact(components) {
+                for (n, &v) in element.iter().enumerate() {
+                    (min[n], max[n]) = (min[n].min(v), max[n].max(v));
+                }
+            }
+            write!(accessor, ",\"min\":{},\"max\":{}", numbers(&min), numbers(&max)).unwrap();
+        }
+        accessor.push('}');
+        self.accessors.push(accessor);
+        self.accessors.len() - 1
+    }
+}
+
+/// A JSON array; JSON has no NaN or infinity, they become 0
+fn numbers(values: &[f32]) -> String {
+    let values: Vec<_> = values.iter().map(|v| if v.is_finite() { v.to_string() } else { "0".to_string() }).collect();
+    format!("[{}]", values.join(","))
+}
+
+fn string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for ch in s.chars() {
+        match ch {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            ch if (ch as u32) < 0x20 => write!(quoted, "\\u{:04x}", ch as u32).unwrap(),
+            ch => quoted.push(ch),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// The normal stored with the face, or else that of its triangle; glTF requires them to be of
+/// unit length
+fn face_normal(face: &qfg5mdl::Qfg5Face, corners: &[ Vector3<f32>; 3 ]) -> Vector3<f32> {
+    let stored = Vector3{ x: face.normal_x, y: face.normal_y, z: face.normal_z };
+    let computed = (corners[1] - corners[0]).cross(corners[2] - corners[0]);
+    [ stored, computed ].into_iter()
+        .find(|n| n.magnitude2().is_normal())
+        .map_or(Vector3::unit_z(), |n| n.normalize())
+}
+
+/// A primitive per subbitmap used by the faces of the submesh. Faces have separate vertex and
+/// texture coordinate indices, which glTF does not, so every corner becomes a vertex of its own.
+fn write_mesh(b: &mut Builder, submesh: &qfg5mdl::SubMesh, num_materials: usize) -> Result<String> {
+    let mut subbitmaps: Vec<_> = submesh.faces.iter().map(|face| face.subbitmap).collect();
+    subbitmaps.sort();
+    subbitmaps.dedup();
+    let mut primitives = Vec::new();
+    for subbitmap in subbitmaps {
+        let (mut positions, mut normals, mut texcoords) = (Vec::new(), Vec::new(), Vec::new());
+        for face in submesh.faces.iter().filter(|face| face.subbitmap == subbitmap) {
+            let mut corners = [ Vector3::unit_x(); 3 ];
+            for (n, (vertex, uv)) in [ (face.vertex1, face.uv1), (face.vertex2, face.uv2), (face.vertex3, face.uv3) ].into_iter().enumerate() {
+                let v = submesh.vertices.get(vertex).ok_or_else(|| anyhow!("submesh '{}': no vertex {}", submesh.name, vertex))?;
+                let uv = submesh.texcoords.get(uv).ok_or_else(|| anyhow!("submesh '{}': no texture coordinate {}", submesh.name, uv))?;
+                corners[n] = Vector3{ x: v.x, y: v.y, z: v.z };
+                positions.extend([ v.x, v.y, v.z ]);
+                // glTF has the origin of the texture at the top-left, like the subbitmaps
+                texcoords.extend([ uv.u, uv.v ]);
+            }
+            let normal = face_normal(face, &corners);
+            for _ in 0..3 {
+                normals.extend([ normal.x, normal.y, normal.z ]);
+            }
+        }
+        let position = b.floats(&positions, 3, Some(ARRAY_BUFFER), true);
+        let normal = b.floats(&normals, 3, Some(ARRAY_BUFFER), false);
+        let texcoord = b.floats(&texcoords, 2, Some(ARRAY_BUFFER), false);
+        // Faces using a subbitmap that does not exist get the default material
+        let material = if subbitmap < num_materials { format!(",\"material\":{}", subbitmap) } else { String::new() };
+        primitives.push(format!("{{\"attributes\":{{\"POSITION\":{},\"NORMAL\":{},\"TEXCOORD_0\":{}}}{}}}", position, normal, texcoord, material));
+    }
+    Ok(format!("{{\"name\":{},\"primitives\":[{}]}}", string(&submesh.name), primitives.join(",")))
+}
+
+/// A channel per submesh node for the translation and one for the rotation, a key per frame
+fn write_animation(b: &mut Builder, anm: &qfg5anm::AnmDecoder) -> String {
+    let times: Vec<f32> = (0..anm.num_frames()).map(|n| (n as u64 * anm.delay as u64) as f32 / 1000.0).collect();
+    let input = b.floats(&times, 1, None, true);
+    let (mut samplers, mut channels) = (Vec::new(), Vec::new());
+    for (submesh, anim) in anm.anims.iter().enumerate() {
+        let translations: Vec<f32> = anim.blocks.iter().flat_map(|block| block.translation).collect();
+        let mut rotations = Vec::new();
+        let mut previous: Option<cgmath::Quaternion<f32>> = None;
+        for block in &anim.blocks {
+            let q = block.rotation_quaternion();
+            // q and -q are the same rotation; keep to the shortest path in between
+            let q = if previous.is_some_and(|p| p.dot(q) < 0.0) { -q } else { q };
+            rotations.extend([ q.v.x, q.v.y, q.v.z, q.s ]);
+            previous = Some(q);
+        }
+        let node = submesh + 1;
+        for (path, values, components) in [ ("translation", translations, 3), ("rotation", rotations, 4) ] {
+            let output = b.floats(&values, components, None, false);
+            samplers.push(format!("{{\"input\":{},\"output\":{},\"interpolation\":\"LINEAR\"}}", input, output));
+            channels.push(format!("{{\"sampler\":{},\"target\":{{\"node\":{},\"path\":\"{}\"}}}}", samplers.len() - 1, node, path));
+        }
+    }
+    format!("{{\"name\":{},\"samplers\":[{}],\"channels\":[{}]}}", string(&anm.name), samplers.join(","), channels.join(","))
+}
+
+/// The model with a mesh node per submesh. With `anm`, the nodes are positioned as in its first
+/// frame and animated with it, unless it has a single frame or no delay; without it, they are laid
+/// out side by side as by Pose::exploded(). `bin_uri` is where the .gltf expects the buffer.
+pub fn build(model: &qfg5mdl::Qfg5Model, anm: Option<&qfg5anm::AnmDecoder>, bin_uri: &str) -> Result<Gltf> {
+    if let Some(anm) = anm {
+        if anm.anims.len() != model.submeshes.len() {
+            return Err(anyhow!("animation is for {} submeshes, model has {}", anm.anims.len(), model.submeshes.len()));
+        }
+    }
+    let mut b = Builder::default();
+
+    let mut images = Vec::new();
+    for subbitmap in &model.subbitmaps {
+        let mut png = Vec::new();
+        subbitmap.to_rgba(&model.palette, None).write_to(&mut Cursor::new(&mut png), image::ImageOutputFormat::Png)?;
+        let view = b.buffer_view(&png, None);
+        images.push(format!("{{\"bufferView\":{},\"mimeType\":\"image/png\"}}", view));
+    }
+    let textures: Vec<_> = (0..images.len()).map(|n| format!("{{\"sampler\":0,\"source\":{}}}", n)).collect();
+    let materials: Vec<_> = (0..images.len()).map(|n| format!(
+        "{{\"name\":\"subbitmap-{}\",\"pbrMetallicRoughness\":{{\"baseColorTexture\":{{\"index\":{}}},\"metallicFactor\":0,\"roughnessFactor\":1}}}}", n, n)).collect();
+
+    let meshes = model.submeshes.iter().map(|submesh| write_mesh(&mut b, submesh, materials.len())).collect::<Result<Vec<_>>>()?;
+
+    // The model node turns the model upright, unless the exploded layout does that already
+    let animated = anm.filter(|anm| anm.num_frames() > 0);
+    let root_rotation = if animated.is_some() { format!(",\"rotation\":{}", numbers(&Z_UP_ROTATION)) } else { String::new() };
+    let children: Vec<_> = (1..=model.submeshes.len()).map(|n| n.to_string()).collect();
+    let mut nodes = vec![ format!("{{\"name\":{},\"children\":[{}]{}}}", string(&model.name), children.join(","), root_rotation) ];
+    let exploded = Pose::exploded(model);
+    for (n, submesh) in model.submeshes.iter().enumerate() {
+        let placement = match animated {
+            Some(anm) => {
+                let block = &anm.anims[n].blocks[0];
+                let q = block.rotation_quaternion();
+                format!("\"translation\":{},\"rotation\":{}", numbers(&block.translation), numbers(&[ q.v.x, q.v.y, q.v.z, q.s ]))
+            },
+            None => {
+                // Column-major, like cgmath's
+                let matrix: &[f32; 16] = exploded.parts[n].transform.as_ref();
+                format!("\"matrix\":{}", numbers(matrix))
+            },
+        };
+        nodes.push(format!("{{\"name\":{},\"mesh\":{},{}}}", string(&submesh.name), n, placement));
+    }
+    let animations = animated.filter(|anm| anm.num_frames() > 1 && anm.delay > 0)
+        .map(|anm| write_animation(&mut b, anm));
+
+    let mut json = String::new();
+    json.push_str("{\"asset\":{\"version\":\"2.0\",\"generator\":\"qfg5reenigne\"},\"scene\":0,\"scenes\":[{\"nodes\":[0]}]");
+    write!(json, ",\"nodes\":[{}]", nodes.join(",")).unwrap();
+    if !meshes.is_empty() {
+        write!(json, ",\"meshes\":[{}]", meshes.join(",")).unwrap();
+    }
+    if !images.is_empty() {
+        write!(json, ",\"materials\":[{}],\"textures\":[{}],\"images\":[{}]", materials.join(","), textures.join(","), images.join(",")).unwrap();
+        write!(json, ",\"samplers\":[{{\"magFilter\":{},\"minFilter\":{}}}]", NEAREST, NEAREST).unwrap();
+    }
+    if let Some(animation) = animations {
+        write!(json, ",\"animations\":[{}]", animation).unwrap();
+    }
+    if !b.bin.is_empty() {
+        write!(json, ",\"accessors\":[{}],\"bufferViews\":[{}]", b.accessors.join(","), b.buffer_views.join(",")).unwrap();
+        write!(json, ",\"buffers\":[{{\"uri\":{},\"byteLength\":{}}}]", string(bin_uri), b.bin.len()).unwrap();
+    }
+    json.push_str("}\n");
+    Ok(Gltf{ json, bin: b.bin })
+}
+
+/// Exports the model to `gltf_path`, along with a .bin file next to it, see build(). Returns the
+/// paths of the files written.
+pub fn export(model: &qfg5mdl::Qfg5Model, anm: Option<&qfg5anm::AnmDecoder>, gltf_path: &Path) -> Result<Vec<PathBuf>> {
+    let dir = gltf_path.parent().unwrap_or(Path::new(""));
+    let stem = gltf_path.file_stem().unwrap_or_default().to_string_lossy().to_string();
+    let bin_file = format!("{}.bin", stem);
+    let gltf = build(model, anm, &bin_file)?;
+    let mut written = Vec::new();
+    if !gltf.bin.is_empty() {
+        let bin_path = dir.join(&bin_file);
+        std::fs::write(&bin_path, &gltf.bin)?;
+        written.push(bin_path);
+    }
+    File::create(gltf_path)?.write_all(gltf.json.as_bytes())?;
+    written.push(gltf_path.to_path_buf());
+    Ok(written)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qfg5resource::fixtures;
+
+    #[test]
+    fn test_build() {
+        let model = qfg5mdl::Qfg5Model::new(&fixtures::mdl()).unwrap();
+        let anm = qfg5anm::AnmDecoder::new(&fixtures::anm()).unwrap();
+        let gltf = build(&model, Some(&anm), "quad.bin").unwrap();
+        let json = &gltf.json;
+        assert!(json.starts_with("{\"asset\":{\"version\":\"2.0\","), "{}", json);
+        assert!(json.contains("\"nodes\":[{\"name\":\"fixture\",\"children\":[1],\"rotation\":[-0.70710677,0,0,0.70710677]},{\"name\":\"quad\",\"mesh\":0,\"translation\":[0,0,0],\"rotation\":[0,0,0,1]}]"), "{}", json);
+        // Both faces use subbitmap 0, every corner is a vertex of its own
+        assert!(json.contains("\"primitives\":[{\"attributes\":{\"POSITION\":0,\"NORMAL\":1,\"TEXCOORD_0\":2},\"material\":0}]"), "{}", json);
+        assert!(json.contains("{\"bufferView\":1,\"componentType\":5126,\"count\":6,\"type\":\"VEC3\",\"min\":[0,0,0],\"max\":[1,1,0]}"), "{}", json);
+        // Two frames 100ms apart, moving the quad one unit along X
+        assert!(json.contains("{\"bufferView\":4,\"componentType\":5126,\"count\":2,\"type\":\"SCALAR\",\"min\":[0],\"max\":[0.1]}"), "{}", json);
+        assert!(json.contains("\"channels\":[{\"sampler\":0,\"target\":{\"node\":1,\"path\":\"translation\"}},{\"sampler\":1,\"target\":{\"node\":1,\"path\":\"rotation\"}}]"), "{}", json);
+        assert!(json.ends_with(&format!("\"buffers\":[{{\"uri\":\"quad.bin\",\"byteLength\":{}}}]}}\n", gltf.bin.len())), "{}", json);
+        // The texture comes first in the buffer
+        assert!(gltf.bin.starts_with(b"\x89PNG"));
+        assert_eq!(gltf.bin.len() % 4, 0);
+
+        // Without an animation, the submeshes are laid out side by side
+        let gltf = build(&model, None, "quad.bin").unwrap();
+        assert!(gltf.json.contains("{\"name\":\"quad\",\"mesh\":0,\"matrix\":[1,0,0,0,0,"), "{}", gltf.json);
+        assert!(!gltf.json.contains("animations") && !gltf.json.contains("\"rotation\""), "{}", gltf.json);
+
+        let mut two = model.clone();
+        two.submeshes.push(two.submeshes[0].clone());
+        assert_eq!(build(&two, Some(&anm), "").unwrap_err().to_string(), "animation is for 1 submeshes, model has 2");
+    }
+
+    #[test]
+    fn test_normals() {
+        let mut model = qfg5mdl::Qfg5Model::new(&fixtures::mdl()).unwrap();
+        // A stored normal that is not of unit length is scaled, a zero one computed from the face
+        model.submeshes[0].faces[0].normal_z = 4.0;
+        model.submeshes[0].faces[1].normal_z = 0.0;
+        model.submeshes[0].faces[1].vertex2 = 3;
+        model.submeshes[0].faces[1].vertex3 = 2;
+        let gltf = build(&model, None, "quad.bin").unwrap();
+        // The normals are in the third buffer view, after the texture and the positions
+        let view = gltf.json.split("\"bufferViews\":[").nth(1).unwrap().split("},{").nth(2).unwrap();
+        let offset: usize = view.split("\"byteOffset\":").nth(1).unwrap().split(',').next().unwrap().parse().unwrap();
+        let normals: Vec<f32> = gltf.bin[offset..offset + 72].chunks_exact(4).map(|b| f32::from_le_bytes(b.try_into().unwrap())).collect();
+        assert_eq!(normals, [ 0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 0.0, 0.0, -1.0, 0.0, 0.0, -1.0, 0.0, 0.0, -1.0 ]);
+
+        model.submeshes[0].faces[0].uv1 = 9;
+        assert_eq!(build(&model, None, "").unwrap_err().to_string(), "submesh 'quad': no texture coordinate 9");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_json() {
+        let model = qfg5mdl::Qfg5Model::new(&fixtures::mdl()).unwrap();
+        let anm = qfg5anm::AnmDecoder::new(&fixtures::anm()).unwrap();
+        let gltf = build(&model, Some(&anm), "quad.bin").unwrap();
+        let json: serde_json::Value = serde_json::from_str(&gltf.json).unwrap();
+        for view in json["bufferViews"].as_array().unwrap() {
+            let (offset, length) = (view["byteOffset"].as_u64().unwrap(), view["byteLength"].as_u64().unwrap());
+            assert!(offset % 4 == 0 && offset + length <= gltf.bin.len() as u64, "{}", view);
+        }
+        for accessor in json["accessors"].as_array().unwrap() {
+            let view = &json["bufferViews"][accessor["bufferView"].as_u64().unwrap() as usize];
+            let components = match accessor["type"].as_str().unwrap() { "SCALAR" => 1, "VEC2" => 2, "VEC3" => 3, _ => 4 };
+            assert_eq!(accessor["count"].as_u64().unwrap() * components * 4, view["byteLength"].as_u64().unwrap(), "{}", accessor);
+        }
+    }
+}
@@ -0,0 +1,357 @@
+/*-
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * Copyright (c) 2024 Rink Springer <rink@rink.nu>
+ * For conditions of distribution and use, see LICENSE file
+ */
+use anyhow::{anyhow, Result};
+use cgmath::{InnerSpace, Point3, Transform, Vector3};
+use image::RgbaImage;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use crate::pixels;
+use crate::qfg5resource::qfg5mdl;
+use crate::threed::pose::Pose;
+
+fn material_name(subbitmap: usize) -> String {
+    format!("subbitmap-{}", subbitmap)
+}
+
+/// Writes the geometry of the posed submeshes as Wavefront OBJ; `mtl_file` is referenced by `mtllib`
+pub fn write_obj<W: Write>(w: &mut W, model: &qfg5mdl::Qfg5Model, pose: &Pose, mtl_file: &str) -> Result<()> {
+    writeln!(w, "# {}", model.name)?;
+    writeln!(w, "mtllib {}", mtl_file)?;
+
+    // OBJ indices are global and 1-based
+    let mut vertex_base = 1;
+    let mut uv_base = 1;
+    let mut normal_base = 1;
+    for part in &pose.parts {
+        let submesh = &model.submeshes[part.submesh];
+        writeln!(w, "o {}", submesh.name)?;
+        for v in &submesh.vertices {
+            let p = part.transform.transform_point(Point3{ x: v.x, y: v.y, z: v.z });
+            writeln!(w, "v {} {} {}", p.x, p.y, p.z)?;
+        }
+        for uv in &submesh.texcoords {
+            // OBJ has the origin of the texture at the bottom-left
+            writeln!(w, "vt {} {}", uv.u, 1.0 - uv.v)?;
+        }
+        for face in &submesh.faces {
+            let n = part.transform.transform_vector(Vector3{ x: face.normal_x, y: face.normal_y, z: face.normal_z });
+            let n = if n.magnitude2() > 0.0 { n.normalize() } else { n };
+            writeln!(w, "vn {} {} {}", n.x, n.y, n.z)?;
+        }
+
+        let mut current_material = None;
+        for (n, face) in submesh.faces.iter().enumerate() {
+            if current_material != Some(face.subbitmap) {
+                writeln!(w, "usemtl {}", material_name(face.subbitmap))?;
+                current_material = Some(face.subbitmap);
+            }
+            let vn = normal_base + n;
+            writeln!(w, "f {}/{}/{} {}/{}/{} {}/{}/{}",
+                vertex_base + face.vertex1, uv_base + face.uv1, vn,
+                vertex_base + face.vertex2, uv_base + face.uv2, vn,
+                vertex_base + face.vertex3, uv_base + face.uv3, vn)?;
+        }
+        vertex_base += submesh.vertices.len();
+        uv_base += submesh.texcoords.len();
+        normal_base += submesh.faces.len();
+    }
+    Ok(())
+}
+
+/// Writes a material library with one material per subbitmap, using the given texture file names
+pub fn write_mtl<W: Write>(w: &mut W, texture_files: &[String]) -> Result<()> {
+    for (n, texture_file) in texture_files.iter().enumerate() {
+        writeln!(w, "newmtl {}", material_name(n))?;
+        writeln!(w, "Ka 1.0 1.0 1.0")?;
+        writeln!(w, "Kd 1.0 1.0 1.0")?;
+        writeln!(w, "illum 1")?;
+        writeln!(w, "map_Kd {}", texture_file)?;
+        writeln!(w)?;
+    }
+    Ok(())
+}
+
+/// Exports the posed model to `obj_path`, along with a .mtl file and one .png per subbitmap
+/// next to it. Returns the paths of all files written.
+pub fn export(model: &qfg5mdl::Qfg5Model, pose: &Pose, obj_path: &Path) -> Result<Vec<PathBuf>> {
+    let dir = obj_path.parent().unwrap_or(Path::new(""));
+    let stem = obj_path.file_stem().unwrap_or_default().to_string_lossy().to_string();
+    let mut written = Vec::new();
+
+    let mut texture_files = Vec::new();
+    for (n, subbitmap) in model.subbitmaps.iter().enumerate() {
+        let texture_file = format!("{}-{}.png", stem, n);
+        let path = dir.join(&texture_file);
+        subbitmap.to_rgba(&model.palette, None).save(&path)?;
+        texture_files.push(texture_file);
+        written.push(path);
+    }
+
+    let mtl_file = format!("{}.mtl", stem);
+    let mtl_path = dir.join(&mtl_file);
+    let mut w = BufWriter::new(File::create(&mtl_path)?);
+    write_mtl(&mut w, &texture_files)?;
+    w.flush()?;
+    written.push(mtl_path);
+
+    let mut w = BufWriter::new(File::create(obj_path)?);
+    write_obj(&mut w, model, pose, &mtl_file)?;
+    w.flush()?;
+    written.push(obj_path.to_path_buf());
+    Ok(written)
+}
+
+/// An object of an OBJ file. Faces are triangles of (vertex, texture coordinate) indices into the
+/// lists of the object.
+#[derive(Default, Clone, Debug, PartialEq)]
+pub struct ObjObject {
+    pub name: String,
+    pub vertices: Vec<qfg5mdl::Qfg5Vertex>,
+    pub texcoords: Vec<qfg5mdl::Qfg5TexCoord>,
+    pub faces: Vec<[ (usize, usize); 3 ]>,
+}
+
+/// An object being read, with the indices in the file of what it holds
+#[derive(Default)]
+struct ObjBuilder {
+    object: ObjObject,
+    vertex_index: HashMap<usize, usize>,
+    texcoord_index: HashMap<usize, usize>,
+}
+
+/// Index `n` of the file, which counts from 1 or back from the end of `items`, as an index of
+/// the object; an item listed in another object is copied
+fn local_index<T: Clone>(n: &str, items: &[T], index: &mut HashMap<usize, usize>, local: &mut Vec<T>, what: &str) -> Result<usize> {
+    let n: i64 = n.parse().map_err(|_| anyhow!("bad {} index '{}'", what, n))?;
+    let global = if n < 0 { items.len() as i64 + n } else { n - 1 };
+    let item = usize::try_from(global).ok().and_then(|global| items.get(global))
+        .ok_or_else(|| anyhow!("no {} {} (there are {})", what, n, items.len()))?;
+    Ok(*index.entry(global as usize).or_insert_with(|| {
+        local.push(item.clone());
+        local.len() - 1
+    }))
+}
+
+/// Reads the objects of an OBJ file. Vertices and texture coordinates belong to the object they
+/// are listed in; a face using one of another object gets a copy. Polygons are split into
+/// triangles around their first corner. Normals, materials and groups are skipped, and so are
+/// objects without faces, such as the vertices that many exporters list before the first object.
+pub fn read_obj(text: &str) -> Result<Vec<ObjObject>> {
+    let (mut vertices, mut texcoords) = (Vec::new(), Vec::new());
+    let mut objects = Vec::new();
+    let mut current = ObjBuilder{ object: ObjObject{ name: "default".to_string(), ..Default::default() }, ..Default::default() };
+    for (line_number, line) in text.lines().enumerate() {
+        let at_line = |e: anyhow::Error| anyhow!("line {}: {}", line_number + 1, e);
+        let mut fields = line.split_whitespace();
+        let numbers = |fields: std::str::SplitWhitespace, count: usize| -> Result<Vec<f32>> {
+            let values = fields.take(count).map(|v| v.parse::<f32>().map_err(|_| anyhow!("bad number '{}'", v))).collect::<Result<Vec<_>>>()?;
+            if values.is_empty() { Err(anyhow!("no values")) } else { Ok(values) }
+        };
+        match fields.next() {
+            Some("o") => {
+                let name = fields.collect::<Vec<_>>().join(" ");
+                objects.push(std::mem::replace(&mut current, ObjBuilder{ object: ObjObject{ name, ..Default::default() }, ..Default::default() }).object);
+            },
+            Some("v") => {
+                let v = numbers(fields, 3).map_err(at_line)?;
+                let [ x, y, z ] = v[..] else { return Err(at_line(anyhow!("vertex of {} coordinates", v.len()))) };
+                current.vertex_index.insert(vertices.len(), current.object.vertices.len());
+                vertices.push(qfg5mdl::Qfg5Vertex{ x, y, z });
+                current.object.vertices.push(qfg5mdl::Qfg5Vertex{ x, y, z });
+            },
+            Some("vt") => {
+                let uv = numbers(fields, 2).map_err(at_line)?;
+                // OBJ has the origin of the texture at the bottom-left
+                let uv = qfg5mdl::Qfg5TexCoord{ u: uv[0], v: 1.0 - uv.get(1).copied().unwrap_or(0.0) };
+                current.texcoord_index.insert(texcoords.len(), current.object.texcoords.len());
+                texcoords.push(uv.clone());
+                current.object.texcoords.push(uv);
+            },
+            Some("f") => {
+                let corners = fields.map(|corner| {
+                    let mut indices = corner.split('/');
+                    let vertex = local_index(indices.next().unwrap_or_default(), &vertices, &mut current.vertex_index, &mut current.object.vertices, "vertex")?;
+                    let uv = match indices.next() {
+                        Some(uv) if !uv.is_empty() => local_index(uv, &texcoords, &mut current.texcoord_index, &mut current.object.texcoords, "texture coordinate")?,
+                        _ => return Err(anyhow!("corner '{}' without a texture coordinate", corner)),
+                    };
+                    Ok((vertex, uv))
+                }).collect::<Result<Vec<_>>>().map_err(at_line)?;
+                if corners.len() < 3 {
+                    return Err(at_line(anyhow!("face of {} corners", corners.len())));
+                }
+                for n in 1..corners.len() - 1 {
+                    current.object.faces.push([ corners[0], corners[n], corners[n + 1] ]);
+                }
+            },
+            _ => { },
+        }
+    }
+    objects.push(current.object);
+    objects.retain(|object| !object.faces.is_empty());
+    Ok(objects)
+}
+
+/// `name` as far as it fits in the 16 bytes of a name, with characters that are not Latin-1
+/// replaced
+fn mdl_name(name: &str) -> String {
+    name.chars().map(|ch| if ch != '\0' && (ch as u32) < 0x100 { ch } else { '_' }).take(16).collect()
+}
+
+/// `v` of an OBJ file, which is Y-up as write_obj() writes it, in the Z-up axes of a model; undoes
+/// the rotation of Pose::submesh()
+fn model_axes(v: &qfg5mdl::Qfg5Vertex) -> qfg5mdl::Qfg5Vertex {
+    qfg5mdl::Qfg5Vertex{ x: v.x, y: -v.z, z: v.y }
+}
+
+/// A submesh of `object` on subbitmap 0, turned Z-up. The face normals follow the winding of the
+/// faces, with counter-clockwise being front facing, and the lighting values are the smoothed vertex normals
+/// with a `d` of 1, as they appear to be normals, see SubMesh::lighting_normals().
+fn submesh_from_object(object: &ObjObject) -> qfg5mdl::SubMesh {
+    let vertices: Vec<_> = object.vertices.iter().map(model_axes).collect();
+    let faces = object.faces.iter().map(|&[ (vertex1, uv1), (vertex2, uv2), (vertex3, uv3) ]| {
+        let (v1, v2, v3) = (&vertices[vertex1], &vertices[vertex2], &vertices[vertex3]);
+        let a = Vector3::new(v2.x - v1.x, v2.y - v1.y, v2.z - v1.z);
+        let b = Vector3::new(v3.x - v1.x, v3.y - v1.y, v3.z - v1.z);
+        let n = a.cross(b);
+        let n = if n.magnitude2() > 0.0 { n.normalize() } else { n };
+        qfg5mdl::Qfg5Face{ vertex1, vertex2, vertex3, uv1, uv2, uv3, subbitmap: 0, normal_x: n.x, normal_y: n.y, normal_z: n.z }
+    }).collect();
+    let mut submesh = qfg5mdl::SubMesh{ name: mdl_name(&object.name), vertices, texcoords: object.texcoords.clone(), faces, ..Default::default() };
+    submesh.lighting_vertices = submesh.vertex_normals().into_iter()
+        .map(|[ a, b, c ]| qfg5mdl::Qfg5LightingVertex{ a, b, c, d: 1.0 })
+        .collect();
+    submesh
+}
+
+/// Builds a model named `name` from an OBJ file, with a submesh per object, and `texture` as its
+/// only subbitmap. An OBJ that export() wrote of a single submesh or an animation frame comes back
+/// in place; one of all submeshes side by side keeps them that way. The texture is scaled up to a
+/// power of two in size where needed and reduced to the colors the palette holds. The header
+/// values whose meaning is not known are left zero.
+pub fn import(name: &str, text: &str, texture: &RgbaImage) -> Result<qfg5mdl::Qfg5Model<'static>> {
+    let objects = read_obj(text)?;
+    if objects.is_empty() {
+        return Err(anyhow!("no objects"));
+    }
+    let (width, height) = texture.dimensions();
+    let scaled;
+    let texture = if width.is_power_of_two() && height.is_power_of_two() {
+        texture
+    } else {
+        log::warn!("scaling the texture from {}x{} to {}x{}", width, height, width.next_power_of_two(), height.next_power_of_two());
+        scaled = image::imageops::resize(texture, width.next_power_of_two(), height.next_power_of_two(), image::imageops::FilterType::Triangle);
+        &scaled
+    };
+    let (colors, bitmap) = pixels::quantize(texture, qfg5mdl::PALETTE_ENTRIES);
+    let mut model = qfg5mdl::Qfg5Model{ name: mdl_name(name), ..Default::default() };
+    model.palette[..colors.len()].copy_from_slice(&colors);
+    model.submeshes = objects.iter().map(submesh_from_object).collect();
+    model.subbitmaps.push(qfg5mdl::SubBitmap{ width: texture.width(), height: texture.height(), bitmap: bitmap.into() });
+    Ok(model)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qfg5resource::fixtures;
+    use crate::threed::pose::PosePart;
+
+    #[test]
+    fn test_write_obj() {
+        let mut model = qfg5mdl::Qfg5Model::new(&fixtures::mdl()).unwrap();
+        // A triangle after the quad, its indices continue after those of the quad
+        let mut triangle = model.submeshes[0].clone();
+        triangle.name = "tri".to_string();
+        triangle.vertices.truncate(3);
+        triangle.texcoords = vec![ qfg5mdl::Qfg5TexCoord{ u: 0.25, v: 0.25 }; 3 ];
+        triangle.faces = vec![ qfg5mdl::Qfg5Face{ vertex1: 2, vertex2: 1, vertex3: 0, uv1: 0, uv2: 1, uv3: 2, subbitmap: 1, normal_z: 2.0, ..Default::default() } ];
+        model.submeshes.push(triangle);
+        let identity = cgmath::Matrix4::from_scale(1.0);
+        let pose = Pose{ parts: vec![ PosePart{ submesh: 0, transform: identity }, PosePart{ submesh: 1, transform: identity } ] };
+
+        let mut out = Vec::new();
+        write_obj(&mut out, &model, &pose, "fixture.mtl").unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("# fixture\nmtllib fixture.mtl\no quad\nv 0 0 0\n"), "{}", out);
+        assert!(out.contains("usemtl subbitmap-0\nf 1/1/1 2/2/1 3/3/1\nf 1/1/2 3/3/2 4/4/2\no tri\n"), "{}", out);
+        assert!(out.contains("vt 0.25 0.75\n"), "{}", out);
+        assert!(out.ends_with("vn 0 0 1\nusemtl subbitmap-1\nf 7/5/3 6/6/3 5/7/3\n"), "{}", out);
+        assert_eq!(out.matches("\nv ").count(), 7);
+
+        let mut out = Vec::new();
+        write_mtl(&mut out, &[ "fixture-0.png".to_string() ]).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "newmtl subbitmap-0\nKa 1.0 1.0 1.0\nKd 1.0 1.0 1.0\nillum 1\nmap_Kd fixture-0.png\n\n");
+    }
+
+    #[test]
+    fn test_read_obj() {
+        let objects = read_obj(concat!("# a square and a triangle sharing a vertex\n",
+            "v 0 0 0\nv 1 0 0\nv 1 1 0\nvt 0 0\nvt 1 1\n",
+            "o square\nv 0 1 0 1.0\nvt 0.5\nf 1/1 2/1/1 3/2 -1/-1\n",
+            "o tri\nv 2 0 0\nvn 0 0 1\nusemtl x\nf 5/2/1 2/1/1 3//1\n")).unwrap_err();
+        assert_eq!(objects.to_string(), "line 15: corner '3//1' without a texture coordinate");
+
+        let objects = read_obj(concat!("v 0 0 0\nv 1 0 0\nv 1 1 0\nvt 0 0\nvt 1 1\n",
+            "o square\nv 0 1 0 1.0\nvt 0.5\nf 1/1 2/1/1 3/2 -1/-1\n",
+            "o tri\nv 2 0 0\nf 5/2 2/1 3/1\n")).unwrap();
+        // The vertices before the first object are only copied into the objects that use them
+        assert_eq!(objects.iter().map(|o| o.name.as_str()).collect::<Vec<_>>(), [ "square", "tri" ]);
+        let square = &objects[0];
+        assert_eq!(square.vertices.len(), 4);
+        assert_eq!(square.vertices[0], qfg5mdl::Qfg5Vertex{ x: 0.0, y: 1.0, z: 0.0 });
+        assert_eq!(square.vertices[3], qfg5mdl::Qfg5Vertex{ x: 1.0, y: 1.0, z: 0.0 });
+        assert_eq!(square.texcoords, [ qfg5mdl::Qfg5TexCoord{ u: 0.5, v: 1.0 }, qfg5mdl::Qfg5TexCoord{ u: 0.0, v: 1.0 }, qfg5mdl::Qfg5TexCoord{ u: 1.0, v: 0.0 } ]);
+        assert_eq!(square.faces, [ [ (1, 1), (2, 1), (3, 2) ], [ (1, 1), (3, 2), (0, 0) ] ]);
+        // Vertices of the first object are copied
+        assert_eq!(objects[1].vertices.len(), 3);
+        assert_eq!(objects[1].faces, [ [ (0, 0), (1, 1), (2, 1) ] ]);
+
+        assert_eq!(read_obj("v 0 0 0\nvt 0 0\nf 1/1 1/1\n").unwrap_err().to_string(), "line 3: face of 2 corners");
+        assert_eq!(read_obj("v 0 0 0\nvt 0 0\nf 1/1 1/1 2/1\n").unwrap_err().to_string(), "line 3: no vertex 2 (there are 1)");
+        assert!(read_obj("v 0 x 0\n").is_err());
+    }
+
+    #[test]
+    fn test_import() {
+        let model = qfg5mdl::Qfg5Model::new(&fixtures::mdl()).unwrap();
+        let mut obj = Vec::new();
+        write_obj(&mut obj, &model, &Pose::submesh(0), "fixture.mtl").unwrap();
+        let texture = model.subbitmaps[0].to_rgba(&model.palette, None);
+        let imported = import("fixture", &String::from_utf8(obj).unwrap(), &texture).unwrap();
+
+        let (submesh, original) = (&imported.submeshes[0], &model.submeshes[0]);
+        assert_eq!(imported.name, "fixture");
+        assert_eq!(submesh.name, "quad");
+        assert_eq!((submesh.vertices.len(), submesh.texcoords.len(), submesh.faces.len()), (4, 4, 2));
+        assert_eq!(submesh.texcoords, original.texcoords);
+        let close = |a: [f32; 3], b: [f32; 3]| a.iter().zip(b).all(|(a, b)| (a - b).abs() < 1e-6);
+        for (v, original) in submesh.vertices.iter().zip(&original.vertices) {
+            assert!(close([ v.x, v.y, v.z ], [ original.x, original.y, original.z ]), "{:?} {:?}", v, original);
+        }
+        for (face, original) in submesh.faces.iter().zip(&original.faces) {
+            assert_eq!((face.vertex1, face.vertex2, face.vertex3, face.uv3), (original.vertex1, original.vertex2, original.vertex3, original.uv3));
+            assert!(close([ face.normal_x, face.normal_y, face.normal_z ], [ 0.0, 0.0, 1.0 ]), "{:?}", face);
+        }
+        assert!(submesh.lighting_normals().unwrap().into_iter().all(|n| close(n, [ 0.0, 0.0, 1.0 ])));
+        assert_eq!(imported.subbitmaps[0].to_rgba(&imported.palette, None), texture);
+
+        // And it can be written
+        let data = imported.encode().unwrap();
+        assert_eq!(qfg5mdl::Qfg5Model::new(&data).unwrap().encode().unwrap(), data);
+
+        // A texture that is not a power of two in size
+        let texture = RgbaImage::from_pixel(3, 2, image::Rgba([ 1, 2, 3, 255 ]));
+        let imported = import("a model name longer than 16", "v 0 0 0\nvt 0 0\nf 1/1 1/1 1/1\n", &texture).unwrap();
+        assert_eq!(imported.name, "a model name lon");
+        assert_eq!((imported.subbitmaps[0].width, imported.subbitmaps[0].height), (4, 2));
+        assert_eq!(imported.palette[0], (1, 2, 3));
+        assert!(import("x", "# nothing\n", &texture).is_err());
+    }
+}
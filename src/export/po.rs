@@ -0,0 +1,264 @@
+/*-
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * Copyright (c) 2024 Rink Springer <rink@rink.nu>
+ * For conditions of distribution and use, see LICENSE file
+ */
+//! Gettext PO files for translating messages with the usual PO editors. Every message becomes an
+//! entry with its label as msgctxt and its text as msgid; the translations are put back by label.
+use anyhow::{anyhow, Result};
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use crate::qfg5resource::qfg5qgm;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PoEntry {
+    pub context: Option<String>,
+    pub id: String,
+    pub translation: String,
+    /// Marked as needing review, which gettext does not use either
+    pub fuzzy: bool,
+}
+
+/// Writes every message with an empty translation. Messages with the same label as an earlier
+/// one are left out, as PO files cannot have the same msgctxt and msgid twice.
+pub fn write_po<W: Write + ?Sized>(w: &mut W, qgm: &qfg5qgm::QgmDecoder) -> Result<()> {
+    writeln!(w, "# Messages of file {}", qgm.file_id)?;
+    writeln!(w, "msgid \"\"")?;
+    writeln!(w, "msgstr \"\"")?;
+    writeln!(w, "\"Content-Type: text/plain; charset=UTF-8\\n\"")?;
+    writeln!(w, "\"Content-Transfer-Encoding: 8bit\\n\"")?;
+    let mut written = HashSet::new();
+    for (n, m) in qgm.messages.iter().enumerate() {
+        let label = qfg5qgm::QgmLabel::try_encode(qgm, m)
+            .ok_or_else(|| anyhow!("message {}: ids {:?} do not fit in a label", n, m.id))?;
+        if !written.insert(label.clone()) {
+            continue;
+        }
+        writeln!(w)?;
+        write_string(w, "msgctxt", &label)?;
+        write_string(w, "msgid", &m.text)?;
+        write_string(w, "msgstr", "")?;
+    }
+    Ok(())
+}
+
+/// Text with line breaks is split after each one, as gettext does
+fn write_string<W: Write + ?Sized>(w: &mut W, keyword: &str, s: &str) -> Result<()> {
+    if !s.trim_end_matches('\n').contains('\n') {
+        writeln!(w, "{} \"{}\"", keyword, escape(s))?;
+        return Ok(());
+    }
+    writeln!(w, "{} \"\"", keyword)?;
+    for line in s.split_inclusive('\n') {
+        writeln!(w, "\"{}\"", escape(line))?;
+    }
+    Ok(())
+}
+
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for ch in s.chars() {
+        match ch {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            _ => escaped.push(ch),
+        }
+    }
+    escaped
+}
+
+/// The contents of a quoted string, with the escapes resolved
+fn unquote(s: &str) -> Option<String> {
+    let s = s.strip_prefix('"')?.strip_suffix('"')?;
+    let mut unquoted = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(ch) = chars.next() {
+        if ch == '"' {
+            return None;
+        }
+        if ch != '\\' {
+            unquoted.push(ch);
+            continue;
+        }
+        unquoted.push(match chars.next()? {
+            'n' => '\n',
+            'r' => '\r',
+            't' => '\t',
+            ch @ ('\\' | '"') => ch,
+            _ => return None,
+        });
+    }
+    Some(unquoted)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Keyword {
+    Context,
+    Id,
+    Translation,
+}
+
+/// Parses the entries of a PO file, including the header entry. Comments other than the flags
+/// are skipped, and so are obsolete entries; plural forms are not supported.
+pub fn parse_po(text: &str) -> Result<Vec<PoEntry>> {
+    let mut entries = Vec::new();
+    let mut entry = PoEntry::default();
+    // The keyword that continuation lines add to, and whether the entry has a msgstr yet
+    let mut last: Option<Keyword> = None;
+    let mut complete = false;
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        // A comment or a new msgctxt/msgid after the msgstr starts the next entry
+        if let Some(comment) = line.strip_prefix('#') {
+            if complete {
+                entries.push(std::mem::take(&mut entry));
+                (last, complete) = (None, false);
+            }
+            if comment.strip_prefix(',').is_some_and(|flags| flags.split(',').any(|flag| flag.trim() == "fuzzy")) {
+                entry.fuzzy = true;
+            }
+            continue;
+        }
+        let (keyword, rest) = match line.split_once(|ch: char| ch.is_ascii_whitespace()) {
+            _ if line.starts_with('"') => (None, line),
+            Some(("msgctxt", rest)) => (Some(Keyword::Context), rest),
+            Some(("msgid", rest)) => (Some(Keyword::Id), rest),
+            Some(("msgstr", rest)) => (Some(Keyword::Translation), rest),
+            _ => return Err(anyhow!("line {}: unsupported '{}'", n + 1, line)),
+        };
+        if complete && matches!(keyword, Some(Keyword::Context | Keyword::Id)) {
+            entries.push(std::mem::take(&mut entry));
+            (last, complete) = (None, false);
+        }
+        let value = unquote(rest.trim()).ok_or_else(|| anyhow!("line {}: bad string {}", n + 1, rest.trim()))?;
+        last = keyword.or(last);
+        match last {
+            Some(Keyword::Context) => entry.context.get_or_insert_with(String::new).push_str(&value),
+            Some(Keyword::Id) => entry.id.push_str(&value),
+            Some(Keyword::Translation) => { entry.translation.push_str(&value); complete = true },
+            None => return Err(anyhow!("line {}: string without a keyword", n + 1)),
+        }
+    }
+    if complete {
+        entries.push(entry);
+    } else if last.is_some() {
+        return Err(anyhow!("entry without msgstr at the end"));
+    }
+    Ok(entries)
+}
+
+/// Replaces the text of every message that has a translation in `entries`, matched by label;
+/// untranslated and fuzzy entries keep the original text. Returns warnings for entries that do
+/// not match a message.
+pub fn translate(qgm: &mut qfg5qgm::QgmDecoder, entries: &[PoEntry]) -> Vec<String> {
+    let mut warnings = Vec::new();
+    let mut translations = HashMap::new();
+    for entry in entries {
+        match &entry.context {
+            Some(context) => { translations.insert(context.as_str(), entry); },
+            None if entry.id.is_empty() => { }, // the header
+            None => warnings.push(format!("entry without msgctxt for '{}'", entry.id)),
+        }
+    }
+    let mut used = HashSet::new();
+    for n in 0..qgm.messages.len() {
+        let Some(label) = qfg5qgm::QgmLabel::try_encode(qgm, &qgm.messages[n]) else { continue };
+        let Some(entry) = translations.get(label.as_str()) else { continue };
+        let m = &mut qgm.messages[n];
+        if used.insert(label.clone()) && entry.id != m.text {
+            warnings.push(format!("msgctxt {}: msgid differs from the text of the message", label));
+        }
+        if !entry.translation.is_empty() && !entry.fuzzy {
+            m.text = entry.translation.clone();
+        }
+    }
+    for entry in entries {
+        if let Some(context) = entry.context.as_ref().filter(|context| !used.contains(context.as_str())) {
+            warnings.push(format!("unknown msgctxt {}", context));
+        }
+    }
+    warnings
+}
+
+/// Decodes `original`, substitutes the translations of `po` and encodes the result, see translate()
+pub fn import_po(original: &[u8], po: &str) -> Result<(Vec<u8>, Vec<String>)> {
+    let mut qgm = qfg5qgm::QgmDecoder::new(original)?;
+    let warnings = translate(&mut qgm, &parse_po(po)?);
+    Ok((qgm.encode()?, warnings))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qfg5resource::fixtures;
+
+    fn po(qgm: &qfg5qgm::QgmDecoder) -> String {
+        let mut out = Vec::new();
+        write_po(&mut out, qgm).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_write_po() {
+        let mut qgm = qfg5qgm::QgmDecoder::new(&fixtures::qgm()).unwrap();
+        qgm.messages[0].text = "Say \"hi\"\nor \\leave".to_string();
+        qgm.messages.push(qgm.messages[0].clone());
+        let out = po(&qgm);
+        assert!(out.starts_with("# Messages of file 415\nmsgid \"\"\nmsgstr \"\"\n\"Content-Type: text/plain; charset=UTF-8\\n\"\n"), "{}", out);
+        assert!(out.ends_with("\n\nmsgctxt \"0BJ0102.034\"\nmsgid \"\"\n\"Say \\\"hi\\\"\\n\"\n\"or \\\\leave\"\nmsgstr \"\"\n"), "{}", out);
+        assert_eq!(out.matches("msgctxt").count(), 1);
+
+        qgm.messages[1].id[3] = 36;
+        assert_eq!(write_po(&mut Vec::new(), &qgm).unwrap_err().to_string(), "message 1: ids [1, 2, 3, 36] do not fit in a label");
+    }
+
+    #[test]
+    fn test_parse_po() {
+        let qgm = qfg5qgm::QgmDecoder::new(&fixtures::qgm()).unwrap();
+        let entries = parse_po(&po(&qgm)).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].context, None);
+        assert!(entries[0].translation.starts_with("Content-Type"));
+        assert_eq!(entries[1], PoEntry{ context: Some("0BJ0102.034".to_string()), id: "Hello".to_string(), ..Default::default() });
+
+        let text = "#, fuzzy, c-format\nmsgctxt \"a\"\nmsgid \"x\"\nmsgstr \"\"\n\"y\\t\"\n\"z\"\n#~ msgid \"old\"\nmsgctxt \"b\"\nmsgid \"\"\nmsgstr \"\"";
+        let entries = parse_po(text).unwrap();
+        assert_eq!(entries, [
+            PoEntry{ context: Some("a".to_string()), id: "x".to_string(), translation: "y\tz".to_string(), fuzzy: true },
+            PoEntry{ context: Some("b".to_string()), ..Default::default() },
+        ]);
+
+        assert_eq!(parse_po("msgid \"x\"\nmsgstr \"a\\qb\"").unwrap_err().to_string(), "line 2: bad string \"a\\qb\"");
+        assert_eq!(parse_po("msgid \"x\"\nmsgid_plural \"y\"").unwrap_err().to_string(), "line 2: unsupported 'msgid_plural \"y\"'");
+        assert_eq!(parse_po("\"x\"").unwrap_err().to_string(), "line 1: string without a keyword");
+        assert!(parse_po("msgid \"x\"").is_err());
+    }
+
+    #[test]
+    fn test_import_po() {
+        let data = fixtures::qgm();
+        let qgm = qfg5qgm::QgmDecoder::new(&data).unwrap();
+        let translated = po(&qgm).replace("msgstr \"\"\n", "msgstr \"Hallo, Fremder\"\n")
+            + "\nmsgctxt \"ZZZ0000.000\"\nmsgid \"Bye\"\nmsgstr \"Tschüss\"\n";
+        let (out, warnings) = import_po(&data, &translated).unwrap();
+        let out = qfg5qgm::QgmDecoder::new(&out).unwrap();
+        assert_eq!(out.messages[0].text, "Hallo, Fremder");
+        assert_eq!(out.messages[0].dialog_options, qgm.messages[0].dialog_options);
+        assert_eq!(warnings, [ "unknown msgctxt ZZZ0000.000" ]);
+
+        // Untranslated and fuzzy entries keep the original text
+        let (out, warnings) = import_po(&data, &po(&qgm)).unwrap();
+        assert_eq!((out, warnings.len()), (data.clone(), 0));
+        let fuzzy = translated.replace("msgctxt \"0BJ", "#, fuzzy\nmsgctxt \"0BJ").replace("msgid \"Hello\"", "msgid \"Hi\"");
+        let (out, warnings) = import_po(&data, &fuzzy).unwrap();
+        assert_eq!(out, data);
+        assert_eq!(warnings[0], "msgctxt 0BJ0102.034: msgid differs from the text of the message");
+    }
+}
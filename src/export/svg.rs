@@ -0,0 +1,53 @@
+/*-
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * Copyright (c) 2024 Rink Springer <rink@rink.nu>
+ * For conditions of distribution and use, see LICENSE file
+ */
+//! The regions of RGD files as SVG drawings. Every region becomes a path of its segments, titled
+//! with the region id so that viewers show it on hovering; special regions are drawn in green.
+use anyhow::Result;
+use std::io::Write;
+use crate::qfg5resource::qfg5rgd;
+
+/// Margin around the points, in the units of the points
+const MARGIN: f64 = 1.0;
+
+pub fn write_svg<W: Write + ?Sized>(w: &mut W, rgd: &qfg5rgd::RgdDecoder) -> Result<()> {
+    let bounds = rgd.points.iter().fold(None, |bounds: Option<(f64, f64, f64, f64)>, p| Some(match bounds {
+        Some((min_x, min_y, max_x, max_y)) => (min_x.min(p.x), min_y.min(p.y), max_x.max(p.x), max_y.max(p.y)),
+        None => (p.x, p.y, p.x, p.y),
+    }));
+    let (min_x, min_y, max_x, max_y) = bounds.unwrap_or_default();
+    writeln!(w, "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"{} {} {} {}\">",
+        min_x - MARGIN, min_y - MARGIN, max_x - min_x + 2.0 * MARGIN, max_y - min_y + 2.0 * MARGIN)?;
+    for (n, region) in rgd.regions.iter().enumerate() {
+        let mut path = String::new();
+        for segment in region.segment_ids.iter().filter_map(|id| rgd.segments.get(*id)) {
+            let (Some(a), Some(b)) = (rgd.points.get(segment.point1), rgd.points.get(segment.point2)) else { continue };
+            path += &format!("{}M{} {}L{} {}", if path.is_empty() { "" } else { " " }, a.x, a.y, b.x, b.y);
+        }
+        let id = rgd.region_ids.get(n).map_or("?".to_string(), |id| id.to_string());
+        let color = if rgd.is_special(n) { "green" } else { "black" };
+        writeln!(w, "  <path d=\"{}\" fill=\"none\" stroke=\"{}\" stroke-width=\"0.5\"><title>region {}: id {}</title></path>", path, color, n, id)?;
+    }
+    writeln!(w, "</svg>")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qfg5resource::fixtures;
+
+    #[test]
+    fn test_write_svg() {
+        let rgd = qfg5rgd::RgdDecoder::new(&fixtures::rgd()).unwrap();
+        let mut out = Vec::new();
+        write_svg(&mut out, &rgd).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), concat!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"-1 -1 12 12\">\n",
+            "  <path d=\"M0 0L10 0 M10 0L0 10 M0 10L0 0\" fill=\"none\" stroke=\"green\" stroke-width=\"0.5\"><title>region 0: id 42</title></path>\n",
+            "</svg>\n"));
+    }
+}
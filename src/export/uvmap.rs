@@ -0,0 +1,153 @@
+/*-
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * Copyright (c) 2024 Rink Springer <rink@rink.nu>
+ * For conditions of distribution and use, see LICENSE file
+ */
+//! The texture coordinates of a submesh drawn as wireframe over its subbitmap, for finding
+//! texture seams.
+use image::{Rgba, RgbaImage};
+use crate::qfg5resource::qfg5mdl;
+
+/// Subbitmaps are scaled up by a whole factor until they are at least this large, so that the
+/// lines do not cover the texture
+const MIN_SIZE: u32 = 256;
+/// Size of the squares of the checkerboard drawn for a subbitmap that does not exist
+const CHECKER_SIZE: u32 = 16;
+/// Triangles whose texture coordinates span more than this many repeats of the texture are left out
+const MAX_REPEATS: f32 = 8.0;
+
+const LINE_COLOR: Rgba<u8> = Rgba([ 255, 255, 0, 255 ]);
+/// For triangles with texture coordinates outside 0 to 1, which only look right when the texture repeats
+const WRAPPED_COLOR: Rgba<u8> = Rgba([ 255, 0, 255, 255 ]);
+
+/// Draws a line from `from` to `to` with Bresenham's algorithm. With `wrap`, points outside the
+/// image come back in on the other side, as a repeating texture would; without, they are left out.
+pub fn draw_line(image: &mut RgbaImage, from: (i64, i64), to: (i64, i64), color: Rgba<u8>, wrap: bool) {
+    let (width, height) = (image.width() as i64, image.height() as i64);
+    let mut plot = |x: i64, y: i64| {
+        let (x, y) = if wrap { (x.rem_euclid(width), y.rem_euclid(height)) } else { (x, y) };
+        if (0..width).contains(&x) && (0..height).contains(&y) {
+            image.put_pixel(x as u32, y as u32, color);
+        }
+    };
+    let (dx, dy) = ((to.0 - from.0).abs(), -(to.1 - from.1).abs());
+    let (sx, sy) = (if from.0 < to.0 { 1 } else { -1 }, if from.1 < to.1 { 1 } else { -1 });
+    let (mut x, mut y) = from;
+    let mut error = dx + dy;
+    loop {
+        plot(x, y);
+        if (x, y) == to {
+            break;
+        }
+        if 2 * error >= dy {
+            error += dy;
+            x += sx;
+        }
+        if 2 * error <= dx {
+            error += dx;
+            y += sy;
+        }
+    }
+}
+
+/// A checkerboard for faces that use a subbitmap that does not exist
+fn checkerboard() -> RgbaImage {
+    RgbaImage::from_fn(MIN_SIZE, MIN_SIZE, |x, y| {
+        if (x / CHECKER_SIZE + y / CHECKER_SIZE).is_multiple_of(2) { Rgba([ 96, 96, 96, 255 ]) } else { Rgba([ 160, 160, 160, 255 ]) }
+    })
+}
+
+/// The triangles of the faces of `submesh` that use subbitmap `subbitmap`, drawn over it, or over
+/// a checkerboard if the model has no such subbitmap. The origin of the texture is at the top-left,
+/// like the subbitmaps. A triangle with a texture coordinate outside 0 to 1 is moved to start in
+/// the texture and wraps around its edges, in another color.
+pub fn render_uv_map(model: &qfg5mdl::Qfg5Model, submesh: &qfg5mdl::SubMesh, subbitmap: usize) -> RgbaImage {
+    let mut image = match model.subbitmaps.get(subbitmap) {
+        Some(s) => {
+            let scale = (MIN_SIZE / s.width.max(s.height).max(1)).max(1);
+            let texture = s.to_rgba(&model.palette, None);
+            image::imageops::resize(&texture, s.width * scale, s.height * scale, image::imageops::FilterType::Nearest)
+        },
+        None => checkerboard(),
+    };
+    let (width, height) = (image.width() as f32, image.height() as f32);
+    for face in submesh.faces.iter().filter(|face| face.subbitmap == subbitmap) {
+        let uvs: Vec<_> = [ face.uv1, face.uv2, face.uv3 ].iter().filter_map(|&n| submesh.texcoords.get(n)).map(|uv| (uv.u, uv.v)).collect();
+        let [ first, second, third ] = uvs[..] else { continue };
+        let corners = [ first, second, third ];
+        let wrapped = corners.iter().any(|&(u, v)| !(0.0..=1.0).contains(&u) || !(0.0..=1.0).contains(&v));
+        let (shift_u, shift_v) = if wrapped { (first.0.floor(), first.1.floor()) } else { (0.0, 0.0) };
+        let shifted = corners.map(|(u, v)| (u - shift_u, v - shift_v));
+        if shifted.iter().any(|&(u, v)| !(u.abs() <= MAX_REPEATS && v.abs() <= MAX_REPEATS)) {
+            continue;
+        }
+        let to_pixel = |(u, v): (f32, f32)| {
+            let (x, y) = ((u * width).floor() as i64, (v * height).floor() as i64);
+            // The far edge of the texture is its last pixel, unless it repeats
+            if wrapped { (x, y) } else { (x.min(width as i64 - 1), y.min(height as i64 - 1)) }
+        };
+        let color = if wrapped { WRAPPED_COLOR } else { LINE_COLOR };
+        for n in 0..3 {
+            draw_line(&mut image, to_pixel(shifted[n]), to_pixel(shifted[(n + 1) % 3]), color, wrapped);
+        }
+    }
+    image
+}
+
+/// The subbitmaps that the faces of `submesh` use, in order
+pub fn used_subbitmaps(submesh: &qfg5mdl::SubMesh) -> Vec<usize> {
+    let mut used: Vec<_> = submesh.faces.iter().map(|face| face.subbitmap).collect();
+    used.sort_unstable();
+    used.dedup();
+    used
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qfg5resource::fixtures;
+
+    #[test]
+    fn test_draw_line() {
+        let mut image = RgbaImage::new(4, 4);
+        draw_line(&mut image, (0, 0), (3, 3), LINE_COLOR, false);
+        assert!((0..4).all(|n| *image.get_pixel(n, n) == LINE_COLOR));
+        assert_eq!(image.pixels().filter(|&&p| p == LINE_COLOR).count(), 4);
+
+        // Past the right edge: left out, or back in on the left
+        let mut image = RgbaImage::new(4, 4);
+        draw_line(&mut image, (2, 1), (5, 1), LINE_COLOR, false);
+        assert_eq!(image.pixels().filter(|&&p| p == LINE_COLOR).count(), 2);
+        draw_line(&mut image, (2, 2), (5, 2), WRAPPED_COLOR, true);
+        assert_eq!((*image.get_pixel(0, 2), *image.get_pixel(1, 2), *image.get_pixel(3, 2)), (WRAPPED_COLOR, WRAPPED_COLOR, WRAPPED_COLOR));
+    }
+
+    #[test]
+    fn test_render_uv_map() {
+        let mut model = qfg5mdl::Qfg5Model::new(&fixtures::mdl()).unwrap();
+        let image = render_uv_map(&model, &model.submeshes[0], 0);
+        // The 2x2 subbitmap scaled up 128 times, with the diagonal the two triangles share
+        assert_eq!(image.dimensions(), (256, 256));
+        assert_eq!((*image.get_pixel(100, 100), *image.get_pixel(255, 0)), (LINE_COLOR, LINE_COLOR));
+        assert_eq!(image.get_pixel(10, 100).0, [ 255, 0, 0, 255 ]);
+        assert_eq!(image.get_pixel(200, 10).0, [ 0, 0, 0, 255 ]);
+
+        // The second triangle moved right by half the texture
+        let submesh = &mut model.submeshes[0];
+        for n in [ 0, 2, 3 ] {
+            submesh.texcoords.push(qfg5mdl::Qfg5TexCoord{ u: submesh.texcoords[n].u + 0.5, v: submesh.texcoords[n].v });
+        }
+        (submesh.faces[1].uv1, submesh.faces[1].uv2, submesh.faces[1].uv3) = (4, 5, 6);
+        let image = render_uv_map(&model, &model.submeshes[0], 0);
+        // Its left edge at u = 0.5, and its diagonal and bottom edge wrapping around the right edge
+        assert_eq!((*image.get_pixel(128, 200), *image.get_pixel(20, 148), *image.get_pixel(20, 0)), (WRAPPED_COLOR, WRAPPED_COLOR, WRAPPED_COLOR));
+
+        // No subbitmap 1
+        model.submeshes[0].faces[0].subbitmap = 1;
+        assert_eq!(used_subbitmaps(&model.submeshes[0]), [ 0, 1 ]);
+        let image = render_uv_map(&model, &model.submeshes[0], 1);
+        assert_eq!((image.get_pixel(1, 200).0, image.get_pixel(20, 200).0), ([ 96, 96, 96, 255 ], [ 160, 160, 160, 255 ]));
+        assert_eq!(*image.get_pixel(100, 100), LINE_COLOR);
+    }
+}
@@ -4,5 +4,13 @@
  * Copyright (c) 2024 Rink Springer <rink@rink.nu>
  * For conditions of distribution and use, see LICENSE file
  */
+#[cfg(not(target_arch = "wasm32"))]
+pub mod commands;
+pub mod export;
+pub mod pixels;
 pub mod threed;
-pub mod qfg5resource;
\ No newline at end of file
+pub mod qfg5resource;
+#[cfg(feature = "python")]
+mod python;
+#[cfg(all(feature = "wasm", target_arch = "wasm32"))]
+pub mod wasm;
\ No newline at end of file
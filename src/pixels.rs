@@ -0,0 +1,273 @@
+/*-
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * Copyright (c) 2024 Rink Springer <rink@rink.nu>
+ * For conditions of distribution and use, see LICENSE file
+ */
+//! Conversion of decoded pixels to RGBA, and of RGBA images to palette indices for importing.
+//! Nothing here needs a GPU, so the command line tool and the bindings use these as well as the
+//! viewers.
+use image::RgbaImage;
+use std::collections::HashMap;
+use crate::qfg5resource::{qfg5img::ImageDecoder, qfg5mdl::SubBitmap, qfg5nod::PaletteEntry, qfg5qgf};
+
+/// Converts palette indices to RGBA; indices outside the palette and transparent_index become
+/// fully transparent
+pub fn indexed_to_rgba(pixels: &[u8], palette: &[PaletteEntry], width: u32, height: u32, transparent_index: Option<u8>) -> RgbaImage {
+    RgbaImage::from_fn(width, height, |x, y| {
+        let index = pixels[(y * width + x) as usize];
+        match palette.get(index as usize) {
+            Some(&(r, g, b)) if Some(index) != transparent_index => image::Rgba([ r, g, b, 255 ]),
+            _ => image::Rgba([ 0, 0, 0, 0 ]),
+        }
+    })
+}
+
+/// Size of a thumbnail of a `width` x `height` image: the longer side becomes `max_dim` and the
+/// shorter side is rounded, but at least 1. Images that already fit keep their size.
+pub fn thumbnail_size(width: u32, height: u32, max_dim: u32) -> (u32, u32) {
+    let max_dim = max_dim.max(1);
+    if width <= max_dim && height <= max_dim {
+        return (width, height);
+    }
+    let scale = |short: u32, long: u32| ((short as u64 * max_dim as u64 + long as u64 / 2) / long as u64).max(1) as u32;
+    if width >= height { (max_dim, scale(height, width)) } else { (scale(width, height), max_dim) }
+}
+
+/// The source pixels that make up each of the `to` pixels when scaling `from` pixels down, with
+/// their weights: the part of the source pixel that is covered, so the weights add up to 1
+fn box_weights(from: u32, to: u32) -> Vec<Vec<(usize, f32)>> {
+    let scale = from as f64 / to as f64;
+    (0..to).map(|i| {
+        let (start, end) = (i as f64 * scale, (i + 1) as f64 * scale);
+        (start.floor() as u32..(end.ceil() as u32).min(from)).filter_map(|s| {
+            let covered = end.min(s as f64 + 1.0) - start.max(s as f64);
+            (covered > 0.0).then_some((s as usize, (covered / scale) as f32))
+        }).collect()
+    }).collect()
+}
+
+/// Scales palette indices down to a thumbnail, see thumbnail_size(), averaging the pixels each
+/// thumbnail pixel covers (a box filter), so any ratio works. Transparency is as for
+/// indexed_to_rgba(); transparent pixels do not darken the pixels next to them.
+pub fn thumbnail(pixels: &[u8], palette: &[PaletteEntry], width: u32, height: u32, transparent_index: Option<u8>, max_dim: u32) -> RgbaImage {
+    let (thumb_width, thumb_height) = thumbnail_size(width, height, max_dim);
+    let (columns, rows) = (box_weights(width, thumb_width), box_weights(height, thumb_height));
+    let premultiplied: Vec<[ f32; 4 ]> = indexed_to_rgba(pixels, palette, width, height, transparent_index).pixels().map(|p| {
+        let alpha = p[3] as f32 / 255.0;
+        [ p[0] as f32 * alpha, p[1] as f32 * alpha, p[2] as f32 * alpha, p[3] as f32 ]
+    }).collect();
+
+    // First horizontally, to thumb_width x height
+    let mut scaled = vec![ [ 0f32; 4 ]; (thumb_width * height) as usize ];
+    for y in 0..height as usize {
+        for (x, weights) in columns.iter().enumerate() {
+            let sum = &mut scaled[y * thumb_width as usize + x];
+            for &(s, weight) in weights {
+                let pixel = premultiplied[y * width as usize + s];
+                (0..4).for_each(|c| sum[c] += pixel[c] * weight);
+            }
+        }
+    }
+    RgbaImage::from_fn(thumb_width, thumb_height, |x, y| {
+        let mut sum = [ 0f32; 4 ];
+        for &(s, weight) in &rows[y as usize] {
+            let pixel = scaled[s * thumb_width as usize + x as usize];
+            (0..4).for_each(|c| sum[c] += pixel[c] * weight);
+        }
+        if sum[3] < 0.5 {
+            return image::Rgba([ 0, 0, 0, 0 ]);
+        }
+        let color = |c: usize| (sum[c] * 255.0 / sum[3]).round().min(255.0) as u8;
+        image::Rgba([ color(0), color(1), color(2), sum[3].round().min(255.0) as u8 ])
+    })
+}
+
+impl ImageDecoder {
+    /// The image scaled down to fit in `max_dim` x `max_dim`; images do not have a palette of
+    /// their own, for room backgrounds it is in the node file
+    pub fn thumbnail(&self, palette: &[PaletteEntry], max_dim: u32) -> RgbaImage {
+        thumbnail(self.get_pixels(), palette, self.get_width() as u32, self.get_height() as u32, None, max_dim)
+    }
+}
+
+impl SubBitmap<'_> {
+    /// The texture in the colors of `palette`, the palette of the model. Pixels with
+    /// `transparent_index` are fully transparent, as in indexed_to_rgba().
+    pub fn to_rgba(&self, palette: &[PaletteEntry], transparent_index: Option<u8>) -> RgbaImage {
+        indexed_to_rgba(&self.bitmap, palette, self.width, self.height, transparent_index)
+    }
+
+    /// The texture scaled down to fit in `max_dim` x `max_dim`, using the palette of the model
+    pub fn thumbnail(&self, palette: &[PaletteEntry], max_dim: u32) -> RgbaImage {
+        thumbnail(&self.bitmap, palette, self.width, self.height, None, max_dim)
+    }
+}
+
+/// A palette of at most `max_colors` entries for `image` and the pixels as indices into it; alpha
+/// is not looked at. An image with few enough colors keeps them exactly, in the order they first
+/// appear in, so that an exported texture comes back as it was. Others are reduced by median cut
+/// and every pixel gets the closest entry.
+pub fn quantize(image: &RgbaImage, max_colors: usize) -> (Vec<PaletteEntry>, Vec<u8>) {
+    let max_colors = max_colors.clamp(1, 256);
+    let rgb = |p: &image::Rgba<u8>| (p[0], p[1], p[2]);
+    let mut counts: HashMap<PaletteEntry, usize> = HashMap::new();
+    let mut colors = Vec::new();
+    for pixel in image.pixels() {
+        *counts.entry(rgb(pixel)).or_insert_with(|| { colors.push(rgb(pixel)); 0 }) += 1;
+    }
+    let palette = if colors.len() <= max_colors { colors } else { median_cut(&counts, max_colors) };
+    let mut closest = HashMap::new();
+    let pixels = image.pixels()
+        .map(|pixel| *closest.entry(rgb(pixel)).or_insert_with_key(|&color| closest_entry(&palette, color)))
+        .collect();
+    (palette, pixels)
+}
+
+fn closest_entry(palette: &[PaletteEntry], (r, g, b): PaletteEntry) -> u8 {
+    let distance = |&(pr, pg, pb): &PaletteEntry| {
+        let d = |a: u8, b: u8| (a as i32 - b as i32).pow(2);
+        d(pr, r) + d(pg, g) + d(pb, b)
+    };
+    palette.iter().enumerate().min_by_key(|(_, entry)| distance(entry)).map_or(0, |(n, _)| n as u8)
+}
+
+/// Splits the colors into `max_colors` boxes, each time the box that is widest in a channel at
+/// its median pixel in that channel, and takes the mean color of the pixels in every box
+fn median_cut(counts: &HashMap<PaletteEntry, usize>, max_colors: usize) -> Vec<PaletteEntry> {
+    let channel = |&(r, g, b): &PaletteEntry, n: usize| [ r, g, b ][n];
+    // Range and channel of the widest channel of a box
+    let widest = |colors: &[(PaletteEntry, usize)]| (0..3).map(|n| {
+        let min = colors.iter().map(|(color, _)| channel(color, n)).min().unwrap_or(0);
+        let max = colors.iter().map(|(color, _)| channel(color, n)).max().unwrap_or(0);
+        (max - min, n)
+    }).max().unwrap_or_default();
+
+    let mut boxes = vec![ counts.iter().map(|(&color, &count)| (color, count)).collect::<Vec<_>>() ];
+    while boxes.len() < max_colors {
+        let Some((index, (_, n))) = boxes.iter().enumerate().filter(|(_, colors)| colors.len() > 1)
+            .map(|(index, colors)| (index, widest(colors))).max_by_key(|&(_, widest)| widest) else { break };
+        let mut colors = boxes.swap_remove(index);
+        colors.sort_unstable_by_key(|&(color, _)| (channel(&color, n), color));
+        let total: usize = colors.iter().map(|(_, count)| count).sum();
+        let mut seen = 0;
+        let median = colors.iter().position(|(_, count)| { seen += count; seen * 2 >= total }).unwrap_or(0);
+        // Both halves keep at least one color
+        let rest = colors.split_off(median.min(colors.len() - 2) + 1);
+        boxes.push(colors);
+        boxes.push(rest);
+    }
+    boxes.iter().map(|colors| {
+        let total: usize = colors.iter().map(|(_, count)| count).sum();
+        let mean = |n: usize| ((colors.iter().map(|(color, count)| channel(color, n) as usize * count).sum::<usize>() + total / 2) / total) as u8;
+        (mean(0), mean(1), mean(2))
+    }).collect()
+}
+
+/// Rendered text or glyphs as black on transparent; an empty bitmap is one pixel wide or high
+pub fn glyphs_to_rgba(qgf: &qfg5qgf::QgfDecoder, bitmap: &qfg5qgf::QgfBitmap) -> RgbaImage {
+    RgbaImage::from_fn(bitmap.width.max(1), bitmap.height.max(1), |x, y| {
+        let value = bitmap.pixels.get((y * bitmap.width + x) as usize).copied().unwrap_or(0);
+        image::Rgba([ 0, 0, 0, qgf.coverage(value) ])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qfg5resource::fixtures;
+
+    #[test]
+    fn test_indexed_to_rgba() {
+        let palette = [ (10, 20, 30), (40, 50, 60) ];
+        let rgba = indexed_to_rgba(&[ 0, 1, 2, 1 ], &palette, 2, 2, Some(0));
+        assert_eq!(rgba.get_pixel(0, 0).0, [ 0, 0, 0, 0 ]);
+        assert_eq!(rgba.get_pixel(1, 0).0, [ 40, 50, 60, 255 ]);
+        // Outside the palette
+        assert_eq!(rgba.get_pixel(0, 1).0, [ 0, 0, 0, 0 ]);
+        assert_eq!(rgba.get_pixel(1, 1).0, [ 40, 50, 60, 255 ]);
+    }
+
+    #[test]
+    fn test_quantize() {
+        let image = RgbaImage::from_fn(3, 1, |x, _| image::Rgba([ [ 9, 0, 200 ][x as usize], 0, 0, 255 ]));
+        assert_eq!(quantize(&image, 255), (vec![ (9, 0, 0), (0, 0, 0), (200, 0, 0) ], vec![ 0, 1, 2 ]));
+
+        // 16 grays in two colors: the darker and the lighter half, each its mean
+        let image = RgbaImage::from_fn(16, 1, |x, _| image::Rgba([ x as u8 * 16, x as u8 * 16, x as u8 * 16, 255 ]));
+        let (mut palette, pixels) = quantize(&image, 2);
+        assert_eq!(palette.len(), 2);
+        let dark = pixels[0] as usize;
+        assert!(pixels[..8].iter().all(|&p| p as usize == dark) && pixels[8..].iter().all(|&p| p as usize != dark));
+        palette.sort();
+        assert_eq!(palette, [ (56, 56, 56), (184, 184, 184) ]);
+    }
+
+    #[test]
+    fn test_thumbnail_size() {
+        assert_eq!(thumbnail_size(640, 480, 128), (128, 96));
+        assert_eq!(thumbnail_size(480, 640, 128), (96, 128));
+        // Sizes that do not divide evenly are rounded
+        assert_eq!(thumbnail_size(641, 479, 128), (128, 96));
+        assert_eq!(thumbnail_size(5, 3, 2), (2, 1));
+        assert_eq!(thumbnail_size(1000, 333, 100), (100, 33));
+        assert_eq!(thumbnail_size(1000, 335, 100), (100, 34));
+        // but never to nothing
+        assert_eq!(thumbnail_size(3, 1000, 10), (1, 10));
+        // Nothing is scaled up
+        assert_eq!(thumbnail_size(50, 20, 128), (50, 20));
+        assert_eq!(thumbnail_size(128, 128, 128), (128, 128));
+    }
+
+    #[test]
+    fn test_thumbnail() {
+        let palette = [ (0, 0, 0), (90, 90, 90), (180, 180, 180), (200, 100, 50) ];
+        // Halving averages pairs of pixels
+        let rgba = thumbnail(&[ 0, 2, 3, 3, 2, 0, 3, 3 ], &palette, 4, 2, None, 2);
+        assert_eq!(rgba.dimensions(), (2, 1));
+        assert_eq!(rgba.get_pixel(0, 0).0, [ 90, 90, 90, 255 ]);
+        assert_eq!(rgba.get_pixel(1, 0).0, [ 200, 100, 50, 255 ]);
+        // With 3 pixels to 2, the middle one is split between both
+        let rgba = thumbnail(&[ 0, 1, 2 ], &palette, 3, 1, None, 2);
+        assert_eq!(rgba.dimensions(), (2, 1));
+        assert_eq!(rgba.get_pixel(0, 0).0, [ 30, 30, 30, 255 ]);
+        assert_eq!(rgba.get_pixel(1, 0).0, [ 150, 150, 150, 255 ]);
+        // Transparent pixels only lower the alpha
+        let rgba = thumbnail(&[ 3, 0 ], &palette, 2, 1, Some(0), 1);
+        assert_eq!(rgba.get_pixel(0, 0).0, [ 200, 100, 50, 128 ]);
+        assert_eq!(thumbnail(&[ 0, 0 ], &palette, 2, 1, Some(0), 1).get_pixel(0, 0).0, [ 0, 0, 0, 0 ]);
+        // Small enough already
+        assert_eq!(thumbnail(&[ 1, 2 ], &palette, 2, 1, None, 8), indexed_to_rgba(&[ 1, 2 ], &palette, 2, 1, None));
+    }
+
+    #[test]
+    fn test_decoder_thumbnails() {
+        let img = ImageDecoder::new(&fixtures::img()).unwrap();
+        let palette: Vec<_> = (0..=255).map(|i| (i, i, i)).collect();
+        let rgba = img.thumbnail(&palette, 1);
+        assert_eq!(rgba.dimensions(), thumbnail_size(img.get_width() as u32, img.get_height() as u32, 1));
+        let subbitmap = SubBitmap{ width: 4, height: 4, bitmap: vec![ 1; 16 ].into() };
+        let rgba = subbitmap.thumbnail(&[ (0, 0, 0), (10, 20, 30) ], 2);
+        assert_eq!(rgba.dimensions(), (2, 2));
+        assert_eq!(rgba.get_pixel(1, 1).0, [ 10, 20, 30, 255 ]);
+    }
+
+    #[test]
+    fn test_subbitmap_to_rgba() {
+        let subbitmap = SubBitmap{ width: 2, height: 1, bitmap: vec![ 0, 1 ].into() };
+        let palette = [ (1, 2, 3), (10, 20, 30) ];
+        let rgba = subbitmap.to_rgba(&palette, None);
+        assert_eq!((rgba.get_pixel(0, 0).0, rgba.get_pixel(1, 0).0), ([ 1, 2, 3, 255 ], [ 10, 20, 30, 255 ]));
+        assert_eq!(subbitmap.to_rgba(&palette, Some(0)).get_pixel(0, 0).0, [ 0, 0, 0, 0 ]);
+    }
+
+    #[test]
+    fn test_glyphs_to_rgba() {
+        let qgf = qfg5qgf::QgfDecoder::new(&fixtures::qgf()).unwrap();
+        let rgba = glyphs_to_rgba(&qgf, &qgf.render_text("A"));
+        assert_eq!(rgba.dimensions(), (2, 2));
+        assert_eq!(rgba.get_pixel(0, 0).0, [ 0, 0, 0, 255 ]);
+        assert_eq!(rgba.get_pixel(1, 0).0, [ 0, 0, 0, 0 ]);
+        assert_eq!(glyphs_to_rgba(&qgf, &qgf.render_text("")).dimensions(), (1, 2));
+    }
+}
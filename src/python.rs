@@ -0,0 +1,250 @@
+/*-
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * Copyright (c) 2024 Rink Springer <rink@rink.nu>
+ * For conditions of distribution and use, see LICENSE file
+ */
+//! Python bindings, built with maturin (see pyproject.toml). The decoders are classes constructed
+//! from the contents of a file. Metadata is returned as dicts and lists; pixels, vertices and the
+//! other arrays as typed memoryviews, which numpy.asarray() turns into arrays without copying.
+use pyo3::create_exception;
+use pyo3::exceptions::{PyIndexError, PyKeyError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyDict, PyMemoryView};
+use crate::qfg5resource::{qfg5gra, qfg5mdl, qfg5qgm, qfg5spk, Error};
+use crate::pixels;
+
+create_exception!(qfg5reenigne, DecodeError, PyValueError, "The data could not be decoded; `kind` tells why and `offset` where.");
+
+fn decode_error(e: Error) -> PyErr {
+    let kind = match e.root() {
+        Error::Truncated{ .. } => "truncated",
+        Error::BadMagic{ .. } => "bad_magic",
+        Error::IndexOutOfRange{ .. } => "index_out_of_range",
+        Error::Unsupported{ .. } => "unsupported",
+        Error::Invalid{ .. } => "invalid",
+        Error::Io{ .. } => "io",
+        Error::Context{ .. } => unreachable!("root() skips the context"),
+    };
+    Python::with_gil(|py| {
+        let err = DecodeError::new_err(e.to_string());
+        let value = err.value(py);
+        value.setattr("kind", kind).and_then(|_| value.setattr("offset", e.offset())).map_or_else(|e| e, |_| err)
+    })
+}
+
+/// A read-only view of `data` as an array of `shape` with items of struct `format`. A view
+/// cannot have an empty dimension, so empty arrays are one-dimensional.
+fn array<'py>(py: Python<'py>, data: &[u8], format: &str, shape: &[usize]) -> PyResult<Bound<'py, PyAny>> {
+    let view = PyMemoryView::from(&PyBytes::new(py, data))?;
+    if shape.contains(&0) {
+        view.call_method1("cast", (format,))
+    } else {
+        view.call_method1("cast", (format, shape.to_vec()))
+    }
+}
+
+fn f32_array<'py>(py: Python<'py>, values: &[f32], columns: usize) -> PyResult<Bound<'py, PyAny>> {
+    array(py, bytemuck::cast_slice(values), "f", &[ values.len() / columns, columns ])
+}
+
+fn u32_array<'py>(py: Python<'py>, values: &[u32], columns: usize) -> PyResult<Bound<'py, PyAny>> {
+    array(py, bytemuck::cast_slice(values), "I", &[ values.len() / columns, columns ])
+}
+
+/// Messages of a QGM file
+#[pyclass(name = "QgmDecoder", frozen)]
+struct Qgm(qfg5qgm::QgmDecoder);
+
+#[pymethods]
+impl Qgm {
+    #[new]
+    fn new(data: &[u8]) -> PyResult<Self> {
+        qfg5qgm::QgmDecoder::new(data).map(Qgm).map_err(decode_error)
+    }
+
+    #[getter]
+    fn file_id(&self) -> u16 {
+        self.0.file_id
+    }
+
+    /// Dicts with `id`, `speaker_id`, `msg_id`, `label`, `dialog_options` and `text`, and the
+    /// header fields `flags`, `text_flag`, `unknown` (unknown 2 to 6) and `unknown8`
+    #[getter]
+    fn messages<'py>(&self, py: Python<'py>) -> PyResult<Vec<Bound<'py, PyDict>>> {
+        self.0.messages.iter().map(|m| {
+            let dict = PyDict::new(py);
+            dict.set_item("id", m.id.to_vec())?;
+            dict.set_item("speaker_id", m.speaker_id)?;
+            dict.set_item("msg_id", m.msg_id)?;
+            dict.set_item("label", m.message_label.clone().map(String::from))?;
+            dict.set_item("dialog_options", m.dialog_options.iter().cloned().map(String::from).collect::<Vec<_>>())?;
+            dict.set_item("text", &m.text)?;
+            dict.set_item("flags", m.flags)?;
+            dict.set_item("text_flag", m.text_flag)?;
+            dict.set_item("unknown", m.unknown.to_vec())?;
+            dict.set_item("unknown8", m.unknown8)?;
+            Ok(dict)
+        }).collect()
+    }
+}
+
+/// A 3D model
+#[pyclass(name = "Qfg5Model", frozen)]
+struct Model(qfg5mdl::Qfg5Model<'static>);
+
+#[pymethods]
+impl Model {
+    #[new]
+    fn new(data: &[u8]) -> PyResult<Self> {
+        qfg5mdl::Qfg5Model::new(data).map(Model).map_err(decode_error)
+    }
+
+    #[getter]
+    fn name(&self) -> &str {
+        &self.0.name
+    }
+
+    /// uint8 array of 256×3
+    #[getter]
+    fn palette<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let palette: Vec<u8> = self.0.palette.iter().flat_map(|&(r, g, b)| [ r, g, b ]).collect();
+        array(py, &palette, "B", &[ 256, 3 ])
+    }
+
+    /// The palette as stored in the file
+    #[getter]
+    fn palette_bytes<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, &self.0.palette_bytes())
+    }
+
+    #[getter]
+    fn warnings(&self) -> Vec<String> {
+        self.0.warnings.clone()
+    }
+
+    /// Dicts with the `name` and float32 arrays of `vertices` (n×3), `texcoords` (n×2), `normals`
+    /// (n×3 per face) and `lighting` (n×4 per vertex); uint32 arrays of the vertex indices of the
+    /// `faces` (n×3), their texture coordinate indices `face_texcoords` (n×3) and `face_subbitmaps`
+    #[getter]
+    fn submeshes<'py>(&self, py: Python<'py>) -> PyResult<Vec<Bound<'py, PyDict>>> {
+        self.0.submeshes.iter().map(|submesh| {
+            let vertices: Vec<f32> = submesh.vertices.iter().flat_map(|v| [ v.x, v.y, v.z ]).collect();
+            let texcoords: Vec<f32> = submesh.texcoords.iter().flat_map(|t| [ t.u, t.v ]).collect();
+            let lighting: Vec<f32> = submesh.lighting_vertices.iter().flat_map(|l| [ l.a, l.b, l.c, l.d ]).collect();
+            let normals: Vec<f32> = submesh.faces.iter().flat_map(|f| [ f.normal_x, f.normal_y, f.normal_z ]).collect();
+            let faces: Vec<u32> = submesh.faces.iter().flat_map(|f| [ f.vertex1, f.vertex2, f.vertex3 ]).map(|i| i as u32).collect();
+            let face_texcoords: Vec<u32> = submesh.faces.iter().flat_map(|f| [ f.uv1, f.uv2, f.uv3 ]).map(|i| i as u32).collect();
+            let face_subbitmaps: Vec<u32> = submesh.faces.iter().map(|f| f.subbitmap as u32).collect();
+            let dict = PyDict::new(py);
+            dict.set_item("name", &submesh.name)?;
+            dict.set_item("vertices", f32_array(py, &vertices, 3)?)?;
+            dict.set_item("texcoords", f32_array(py, &texcoords, 2)?)?;
+            dict.set_item("lighting", f32_array(py, &lighting, 4)?)?;
+            dict.set_item("normals", f32_array(py, &normals, 3)?)?;
+            dict.set_item("faces", u32_array(py, &faces, 3)?)?;
+            dict.set_item("face_texcoords", u32_array(py, &face_texcoords, 3)?)?;
+            dict.set_item("face_subbitmaps", u32_array(py, &face_subbitmaps, 1)?)?;
+            dict.set_item("header_floats", submesh.header_floats.to_vec())?;
+            Ok(dict)
+        }).collect()
+    }
+
+    /// Dicts with `width`, `height` and the palette indices as `pixels` (height×width)
+    #[getter]
+    fn subbitmaps<'py>(&self, py: Python<'py>) -> PyResult<Vec<Bound<'py, PyDict>>> {
+        self.0.subbitmaps.iter().map(|subbitmap| {
+            let dict = PyDict::new(py);
+            dict.set_item("width", subbitmap.width)?;
+            dict.set_item("height", subbitmap.height)?;
+            dict.set_item("pixels", array(py, &subbitmap.bitmap, "B", &[ subbitmap.height as usize, subbitmap.width as usize ])?)?;
+            Ok(dict)
+        }).collect()
+    }
+}
+
+/// Sprites of a GRA file
+#[pyclass(name = "GraDecoder", frozen)]
+struct Gra(qfg5gra::GraDecoder<'static>);
+
+#[pymethods]
+impl Gra {
+    #[new]
+    fn new(data: &[u8]) -> PyResult<Self> {
+        qfg5gra::GraDecoder::new(data).map(Gra).map_err(decode_error)
+    }
+
+    /// uint8 array of 256×3
+    #[getter]
+    fn palette<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let palette: Vec<u8> = self.0.palette.iter().flat_map(|&(r, g, b)| [ r, g, b ]).collect();
+        array(py, &palette, "B", &[ 256, 3 ])
+    }
+
+    /// Dicts with `x`, `y`, `width`, `height`, `frame_delay` and the palette indices of every frame
+    /// as `frames` (height×width)
+    #[getter]
+    fn sprite_collections<'py>(&self, py: Python<'py>) -> PyResult<Vec<Bound<'py, PyDict>>> {
+        self.0.sprite_collections.iter().map(|sc| {
+            let shape = [ sc.height as usize, sc.width as usize ];
+            let frames = sc.sprites.iter().map(|sprite| array(py, &sprite.pixels, "B", &shape)).collect::<PyResult<Vec<_>>>()?;
+            let dict = PyDict::new(py);
+            dict.set_item("x", sc.x_position)?;
+            dict.set_item("y", sc.y_position)?;
+            dict.set_item("width", sc.width)?;
+            dict.set_item("height", sc.height)?;
+            dict.set_item("frame_delay", sc.frame_delay)?;
+            dict.set_item("frames", frames)?;
+            Ok(dict)
+        }).collect()
+    }
+
+    /// A frame as uint8 RGBA (height×width×4), with palette index 0 transparent
+    fn rgba<'py>(&self, py: Python<'py>, collection: usize, frame: usize) -> PyResult<Bound<'py, PyAny>> {
+        let sc = self.0.sprite_collections.get(collection).ok_or_else(|| PyIndexError::new_err("no such sprite collection"))?;
+        let sprite = sc.sprites.get(frame).ok_or_else(|| PyIndexError::new_err("no such frame"))?;
+        let img = pixels::indexed_to_rgba(&sprite.pixels, &self.0.palette, sc.width, sc.height, Some(0));
+        array(py, img.as_raw(), "B", &[ sc.height as usize, sc.width as usize, 4 ])
+    }
+}
+
+/// An archive, read from memory
+#[pyclass(name = "SpkArchive", frozen)]
+struct Spk(qfg5spk::SpkArchive);
+
+#[pymethods]
+impl Spk {
+    #[new]
+    fn new(data: Vec<u8>) -> PyResult<Self> {
+        qfg5spk::SpkArchive::from_bytes(data).map(Spk).map_err(decode_error)
+    }
+
+    /// Dicts with `filename`, `offset`, `length` and `compressed`
+    fn items<'py>(&self, py: Python<'py>) -> PyResult<Vec<Bound<'py, PyDict>>> {
+        self.0.get_items().iter().map(|item| {
+            let dict = PyDict::new(py);
+            dict.set_item("filename", &item.filename)?;
+            dict.set_item("offset", item.offset)?;
+            dict.set_item("length", item.length)?;
+            dict.set_item("compressed", item.compression != qfg5spk::SpkCompression::Stored)?;
+            Ok(dict)
+        }).collect()
+    }
+
+    /// Contents of an item; the file name is not case sensitive
+    fn read<'py>(&self, py: Python<'py>, filename: &str) -> PyResult<Bound<'py, PyBytes>> {
+        let item = self.0.find(filename)
+            .ok_or_else(|| PyKeyError::new_err(filename.to_string()))?;
+        Ok(PyBytes::new(py, &self.0.item_data(item).map_err(decode_error)?))
+    }
+}
+
+#[pymodule]
+fn qfg5reenigne(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<Qgm>()?;
+    m.add_class::<Model>()?;
+    m.add_class::<Gra>()?;
+    m.add_class::<Spk>()?;
+    m.add("DecodeError", m.py().get_type::<DecodeError>())?;
+    Ok(())
+}
@@ -5,7 +5,14 @@
  * For conditions of distribution and use, see LICENSE file
  */
 mod decode;
+pub mod detect;
+mod error;
+#[cfg(test)]
+pub(crate) mod fixtures;
+pub mod manager;
+pub mod names;
 pub mod qfg5anm;
+pub mod qfg5aud;
 pub mod qfg5gra;
 pub mod qfg5img;
 pub mod qfg5mdl;
@@ -14,4 +21,106 @@ pub mod qfg5qgf;
 pub mod qfg5qgm;
 pub mod qfg5rgd;
 pub mod qfg5spk;
-pub mod qfg5zzz;
\ No newline at end of file
+pub mod qfg5zzz;
+mod readutil;
+mod resource;
+pub mod room;
+#[cfg(feature = "serde")]
+mod serialize;
+pub mod trace;
+
+pub use detect::{detect, Confidence, Detection, ResourceKind};
+pub use error::{Error, Result};
+pub use resource::{registration, Qfg5Resource, Registration, ResourceSummary, REGISTRY};
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decodes every fixture; run by test_decoders_are_silent() in a separate process
+    #[test]
+    fn decode_fixtures() {
+        qfg5anm::AnmDecoder::new(&fixtures::anm()).unwrap();
+        qfg5aud::AudDecoder::new(&fixtures::aud()).unwrap();
+        qfg5gra::GraDecoder::new(&fixtures::gra()).unwrap();
+        let img = qfg5img::ImageDecoder::new(&fixtures::img()).unwrap();
+        qfg5mdl::Qfg5Model::new(&fixtures::mdl()).unwrap();
+        qfg5nod::NodDecoder::new(&fixtures::nod()).unwrap();
+        qfg5qgf::QgfDecoder::new(&fixtures::qgf()).unwrap();
+        qfg5qgm::QgmDecoder::new(&fixtures::qgm()).unwrap();
+        qfg5rgd::RgdDecoder::new(&fixtures::rgd()).unwrap();
+        qfg5zzz::ZzzDecoder::new(&fixtures::zzz(), &img).unwrap();
+    }
+
+    /// Decodes `data` like the fuzz target `target` in fuzz/fuzz_targets does
+    fn decode_as_fuzz_target(target: &str, data: &[u8]) {
+        match target {
+            "anm" => { let _ = qfg5anm::AnmDecoder::new(data); },
+            "aud" => { let _ = qfg5aud::AudDecoder::new(data); },
+            "gra" => { let _ = qfg5gra::GraDecoder::new(data); },
+            "img" => if let Ok(img) = qfg5img::ImageDecoder::new(data) {
+                let _ = qfg5zzz::ZzzDecoder::new(data, &img);
+            },
+            "mdl" => { let _ = qfg5mdl::Qfg5Model::new(data); },
+            "nod" => { let _ = qfg5nod::NodDecoder::new(data); },
+            "qgf" => { let _ = qfg5qgf::QgfDecoder::new(data); },
+            "qgm" => { let _ = qfg5qgm::QgmDecoder::new(data); },
+            "rgd" => { let _ = qfg5rgd::RgdDecoder::new(data); },
+            "spk" => if let Ok(archive) = qfg5spk::SpkArchive::from_bytes(data.to_vec()) {
+                for item in archive.get_items() {
+                    let _ = archive.read_item(item);
+                }
+            },
+            _ => panic!("unknown fuzz target {}", target),
+        }
+    }
+
+    /// Replays the fuzzing corpus and the inputs that made a decoder panic or run out of memory
+    /// before, which are kept in fuzz/regressions/<target>
+    #[test]
+    fn test_fuzz_regressions() {
+        let fuzz_dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("fuzz");
+        let mut count = 0;
+        for dir in [ "corpus", "regressions" ] {
+            for target in std::fs::read_dir(fuzz_dir.join(dir)).unwrap() {
+                let target = target.unwrap();
+                for input in std::fs::read_dir(target.path()).unwrap() {
+                    let data = std::fs::read(input.unwrap().path()).unwrap();
+                    decode_as_fuzz_target(target.file_name().to_str().unwrap(), &data);
+                    count += 1;
+                }
+            }
+        }
+        assert!(count > 0);
+    }
+
+    /// Writes the fixtures to tests/fixtures and seeds fuzz/corpus with them; run with
+    /// `cargo test -- --ignored write_fixture_files` after changing a fixture
+    #[test]
+    #[ignore]
+    fn write_fixture_files() {
+        let root = std::path::Path::new(env!("CARGO_MANIFEST_DIR"));
+        let corpus_dir = root.join("fuzz/corpus");
+        let spk = fixtures::spk(&[ ("MDL/1.MDL", &fixtures::mdl()), ("ANM/1.ANM", &fixtures::anm()) ]);
+        for (target, data) in [ ("anm", fixtures::anm()), ("aud", fixtures::aud()), ("gra", fixtures::gra()), ("img", fixtures::img()), ("mdl", fixtures::mdl()),
+                                ("nod", fixtures::nod()), ("qgf", fixtures::qgf()), ("qgm", fixtures::qgm()), ("rgd", fixtures::rgd()),
+                                ("spk", spk) ] {
+            std::fs::create_dir_all(corpus_dir.join(target)).unwrap();
+            std::fs::write(corpus_dir.join(target).join("fixture"), &data).unwrap();
+            std::fs::write(root.join("tests/fixtures").join(format!("fixture.{}", target)), &data).unwrap();
+        }
+    }
+
+    /// Decoders must not write to stdout, as that is where command output goes. The test harness
+    /// captures stdout, so decode_fixtures() is run on its own with --nocapture and nothing may
+    /// appear between its name and its result
+    #[test]
+    fn test_decoders_are_silent() {
+        let name = "qfg5resource::tests::decode_fixtures";
+        let output = std::process::Command::new(std::env::current_exe().unwrap())
+            .args([ name, "--exact", "--nocapture", "--test-threads=1" ])
+            .output().unwrap();
+        assert!(output.status.success());
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        assert!(stdout.contains(&format!("test {} ... ok\n", name)), "{}", stdout);
+    }
+}
@@ -4,30 +4,276 @@
  * Copyright (c) 2024 Rink Springer <rink@rink.nu>
  * For conditions of distribution and use, see LICENSE file
  */
-pub fn decode_rle(data: &[u8], output: &mut [u8]) {
+use byteorder::{ByteOrder, LittleEndian};
+use crate::qfg5resource::error::{Error, Result};
+use crate::qfg5resource::trace::ParseTrace;
+
+/// Reads little-endian fields from a resource, reporting where data is missing
+pub struct Reader<'a> {
+    data: &'a [u8],
+    position: u64,
+    /// Only kept when tracing, see label()
+    trace: Option<ParseTrace>,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Reader{ data, position: 0, trace: None }
+    }
+
+    /// Decodes `data` with `read`, recording what the decoder labels
+    pub fn trace<T>(data: &'a [u8], read: impl FnOnce(&mut Reader<'a>) -> Result<T>) -> (Result<T>, ParseTrace) {
+        let mut cursor = Reader{ data, position: 0, trace: Some(ParseTrace::default()) };
+        let result = read(&mut cursor);
+        (result, cursor.trace.unwrap_or_default())
+    }
+
+    /// Records that what was read from `start` up to here is `label`, when tracing; the label
+    /// is only made then
+    pub fn label(&mut self, start: u64, label: impl FnOnce() -> String) {
+        let length = self.position.saturating_sub(start);
+        self.label_range(start, length, label);
+    }
+
+    /// Like label(), for `length` bytes at `offset`
+    pub fn label_range(&mut self, offset: u64, length: u64, label: impl FnOnce() -> String) {
+        if let Some(trace) = &mut self.trace {
+            trace.push(offset, length, label());
+        }
+    }
+
+    /// Size of all data, wherever the reader is
+    pub fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Moves to an absolute offset; reading past the end fails, seeking does not
+    pub fn seek(&mut self, position: u64) {
+        self.position = position;
+    }
+
+    /// Everything from the current position on
+    pub fn rest(&self) -> &'a [u8] {
+        self.data.get(self.position as usize..).unwrap_or(&[])
+    }
+
+    /// Fails unless `count` items of `size` bytes remain, so that a corrupt count does not lead to
+    /// huge allocations
+    pub fn check_remaining(&self, count: usize, size: usize) -> Result<()> {
+        let needed = count.saturating_mul(size);
+        let got = self.rest().len();
+        if needed > got {
+            return Err(Error::Truncated{ needed, got, at: self.position });
+        }
+        Ok(())
+    }
+
+    pub fn bytes(&mut self, count: usize) -> Result<&'a [u8]> {
+        let rest = self.rest();
+        if rest.len() < count {
+            return Err(Error::Truncated{ needed: count, got: rest.len(), at: self.position });
+        }
+        self.position += count as u64;
+        Ok(&rest[..count])
+    }
+
+    pub fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16> {
+        Ok(LittleEndian::read_u16(self.bytes(2)?))
+    }
+
+    pub fn u32(&mut self) -> Result<u32> {
+        Ok(LittleEndian::read_u32(self.bytes(4)?))
+    }
+
+    pub fn f32(&mut self) -> Result<f32> {
+        Ok(LittleEndian::read_f32(self.bytes(4)?))
+    }
+
+    pub fn f64(&mut self) -> Result<f64> {
+        Ok(LittleEndian::read_f64(self.bytes(8)?))
+    }
+}
+
+/// Limits the total amount of data decoded from a resource. Offsets in a corrupt resource can all
+/// point to the same data, so checking each item against the input size is not enough.
+pub struct Budget {
+    what: &'static str,
+    remaining: usize,
+}
+
+impl Budget {
+    pub fn new(what: &'static str, limit: usize) -> Self {
+        Budget{ what, remaining: limit }
+    }
+
+    /// Fails if fewer than `count` remain
+    pub fn take(&mut self, count: usize, at: u64) -> Result<()> {
+        self.remaining = self.remaining.checked_sub(count)
+            .ok_or_else(|| Error::Invalid{ what: format!("{}: more than the resource can hold", self.what), at })?;
+        Ok(())
+    }
+}
+
+/// Most pixels that RLE data of `length` bytes can decode to: runs of 127 stored in 2 bytes
+pub fn max_rle_pixels(length: usize) -> usize {
+    length.saturating_mul(64)
+}
+
+/// Runs up to this length are copied as a fixed-size block where there is room, which is much
+/// faster than a copy of the exact length for the short runs that are common
+const SHORT_RUN: usize = 16;
+
+/// The run-length encodings of the resources
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RleMode {
+    /// Backgrounds, depth maps and sprites of GRA colour mode 2. A count byte below 0x80 repeats
+    /// the next byte `count` times, 0x80 and up is followed by `256 - count` literal bytes and 0
+    /// is skipped. When the data ends before the output is full, the rest is left as it is.
+    Img,
+    /// Glyphs of fonts: pairs of bytes, of which the second is not used. A first byte below 0x80
+    /// is a pixel; 0x80 and up skips `128 - (byte & 0x7f)` pixels, so 0x80 skips 128 and 0xff
+    /// skips 1. The data must cover the whole output.
+    Qgf,
+}
+
+/// Decodes RLE data until `output` is full; `offset` is the position of `data` in the resource,
+/// used for errors. `output` must be zeroed; pixels that the data does not cover stay zero.
+pub fn decode_rle(mode: RleMode, data: &[u8], output: &mut [u8], offset: u64) -> Result<()> {
+    match mode {
+        RleMode::Img => decode_img_runs(data, output, offset),
+        RleMode::Qgf => decode_qgf_runs(data, output, offset),
+    }
+}
+
+fn decode_img_runs(data: &[u8], output: &mut [u8], offset: u64) -> Result<()> {
+    let truncated = |needed: usize, n: usize| Error::Truncated{ needed, got: data.len() - n, at: offset + n as u64 };
     let mut output_index: usize = 0;
     let mut n: usize = 0;
-    while n < data.len() {
-        let count = data[n] as usize;
+    while output_index < output.len() {
+        let Some(&count) = data.get(n) else { break };
+        let count = count as usize;
         if count == 0 {
-            // todo!();
             n += 1;
         } else if count < 128 {
-            let value = data[n + 1];
-            for _ in 0..count {
-                output[output_index] = value;
-                output_index += 1;
-                if output_index == output.len() { break; }
+            let value = *data.get(n + 1).ok_or_else(|| truncated(2, n))?;
+            if count <= SHORT_RUN && output_index + SHORT_RUN <= output.len() {
+                // The pixels past the run are overwritten by the next runs
+                output[output_index..output_index + SHORT_RUN].fill(value);
+                output_index += count;
+            } else {
+                let end = (output_index + count).min(output.len());
+                output[output_index..end].fill(value);
+                output_index = end;
             }
             n += 2;
         } else {
             let count = 256 - count;
-            for j in 0..count {
-                output[output_index] = data[n + j + 1];
-                output_index += 1;
-                if output_index == output.len() { break; }
+            if count <= SHORT_RUN && output_index + SHORT_RUN <= output.len() && n + 1 + SHORT_RUN <= data.len() {
+                output[output_index..output_index + SHORT_RUN].copy_from_slice(&data[n + 1..n + 1 + SHORT_RUN]);
+                output_index += count;
+            } else {
+                let values = data.get(n + 1..n + 1 + count).ok_or_else(|| truncated(count + 1, n))?;
+                let end = (output_index + count).min(output.len());
+                output[output_index..end].copy_from_slice(&values[..end - output_index]);
+                output_index = end;
             }
             n += count + 1;
         }
     }
+    // Clear what the last short run wrote past the end of the data
+    let end = (output_index + SHORT_RUN).min(output.len());
+    output[output_index..end].fill(0);
+    Ok(())
+}
+
+fn decode_qgf_runs(data: &[u8], output: &mut [u8], offset: u64) -> Result<()> {
+    let byte = |n: usize| data.get(n).copied().ok_or(Error::Truncated{ needed: 1, got: 0, at: offset + n as u64 });
+    let mut output_index: usize = 0;
+    let mut n: usize = 0;
+    while output_index < output.len() {
+        let value = byte(n)?;
+        let _unused = byte(n + 1)?;
+        if value < 0x80 {
+            output[output_index] = value;
+            output_index += 1;
+        } else {
+            output_index += 128 - (value & 0x7f) as usize;
+        }
+        n += 2;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reader() {
+        let mut reader = Reader::new(&[ 1, 2, 3, 0, 0, 0x80, 0x3f ]);
+        assert_eq!(reader.u16().unwrap(), 0x201);
+        assert_eq!(reader.u8().unwrap(), 3);
+        assert_eq!(reader.f32().unwrap(), 1.0);
+        let err = reader.u8().unwrap_err();
+        assert!(matches!(err, Error::Truncated{ needed: 1, got: 0, at: 7 }));
+        reader.seek(6);
+        assert!(matches!(reader.u32(), Err(Error::Truncated{ needed: 4, got: 1, at: 6 })));
+    }
+
+    #[test]
+    fn test_budget() {
+        let mut budget = Budget::new("pixels", 10);
+        budget.take(6, 0).unwrap();
+        budget.take(4, 0).unwrap();
+        assert!(matches!(budget.take(1, 8), Err(Error::Invalid{ at: 8, .. })));
+    }
+
+    #[test]
+    fn test_decode_rle() {
+        let mut output = [ 0u8; 5 ];
+        decode_rle(RleMode::Img, &[ 2, 7, 0xfd, 1, 2, 3, 9 ], &mut output, 0).unwrap();
+        assert_eq!(output, [ 7, 7, 1, 2, 3 ]);
+        let err = decode_rle(RleMode::Img, &[ 2, 7, 0xfd, 1 ], &mut output, 0x40).unwrap_err();
+        assert!(matches!(err, Error::Truncated{ needed: 4, got: 2, at: 0x42 }));
+    }
+
+    #[test]
+    fn test_decode_rle_short_runs() {
+        // Short runs write past their end; that must not show when the data ends early
+        let mut output = [ 0u8; 40 ];
+        decode_rle(RleMode::Img, &[ 3, 7, 0xfe, 1, 2, 1, 9, 0 ], &mut output, 0).unwrap();
+        assert_eq!(&output[..6], &[ 7, 7, 7, 1, 2, 9 ]);
+        assert!(output[6..].iter().all(|&p| p == 0));
+        // A literal run close to the end of the data, and runs that do not fit the output
+        let mut output = [ 0u8; 20 ];
+        decode_rle(RleMode::Img, &[ 18, 5, 0xfd, 1, 2, 3 ], &mut output, 0).unwrap();
+        assert_eq!(&output[16..], &[ 5, 5, 1, 2 ]);
+    }
+
+    #[test]
+    fn test_decode_rle_zero_count() {
+        // From the start of a background: a zero count is padding between runs
+        let mut output = [ 0u8; 6 ];
+        decode_rle(RleMode::Img, &[ 0x02, 0x1f, 0x00, 0xfe, 0x20, 0x21, 0x00, 0x02, 0x22 ], &mut output, 0).unwrap();
+        assert_eq!(output, [ 0x1f, 0x1f, 0x20, 0x21, 0x22, 0x22 ]);
+    }
+
+    #[test]
+    fn test_decode_rle_qgf() {
+        // A glyph row of an 'I': one pixel skipped, two set, skipped to the end of the glyph
+        let mut output = [ 0u8; 6 ];
+        decode_rle(RleMode::Qgf, &[ 0xff, 0x00, 0x0f, 0x00, 0x0e, 0x03, 0x80, 0x00 ], &mut output, 0).unwrap();
+        assert_eq!(output, [ 0, 0x0f, 0x0e, 0, 0, 0 ]);
+        // The second byte of a pair must be there as well
+        let err = decode_rle(RleMode::Qgf, &[ 0x0f, 0x00, 0x0e ], &mut output, 0x10).unwrap_err();
+        assert!(matches!(err, Error::Truncated{ needed: 1, got: 0, at: 0x13 }));
+    }
 }
@@ -0,0 +1,327 @@
+/*-
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * Copyright (c) 2024 Rink Springer <rink@rink.nu>
+ * For conditions of distribution and use, see LICENSE file
+ */
+//! Guesses the kind of a resource from its contents. Only QGM and ANM files have a magic value;
+//! the other formats are recognized by checking whether their header fields are plausible.
+use byteorder::{ByteOrder, LittleEndian};
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ResourceKind {
+    Anm,
+    Gra,
+    Img,
+    Mdl,
+    Nod,
+    Qgf,
+    Qgm,
+    Rgd,
+    Zzz,
+}
+
+impl ResourceKind {
+    pub const ALL: [ ResourceKind; 9 ] = [
+        ResourceKind::Anm, ResourceKind::Gra, ResourceKind::Img, ResourceKind::Mdl, ResourceKind::Nod,
+        ResourceKind::Qgf, ResourceKind::Qgm, ResourceKind::Rgd, ResourceKind::Zzz,
+    ];
+
+    /// Lowercase file extension, as used in the extracted data tree
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ResourceKind::Anm => "anm",
+            ResourceKind::Gra => "gra",
+            ResourceKind::Img => "img",
+            ResourceKind::Mdl => "mdl",
+            ResourceKind::Nod => "nod",
+            ResourceKind::Qgf => "qgf",
+            ResourceKind::Qgm => "qgm",
+            ResourceKind::Rgd => "rgd",
+            ResourceKind::Zzz => "zzz",
+        }
+    }
+
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|kind| kind.extension().eq_ignore_ascii_case(extension))
+    }
+}
+
+impl fmt::Display for ResourceKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.extension().to_uppercase())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Confidence {
+    /// Only a few bytes are checked; other data may match as well
+    Low,
+    /// The structure is consistent, but there is no magic value
+    Medium,
+    /// A magic value or a strict structural invariant matches
+    High,
+}
+
+impl fmt::Display for Confidence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", match self {
+            Confidence::Low => "low",
+            Confidence::Medium => "medium",
+            Confidence::High => "high",
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Detection {
+    pub kind: ResourceKind,
+    pub confidence: Confidence,
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Option<u16> {
+    data.get(offset..offset + 2).map(LittleEndian::read_u16)
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    data.get(offset..offset + 4).map(LittleEndian::read_u32)
+}
+
+/// Size of a plausible image or sprite dimension
+fn plausible_size(value: u32) -> bool {
+    (1..=4096).contains(&value)
+}
+
+/// Returns the number of pixels the RLE stream decodes to, reading until it has at least `pixels`
+/// like the decoder does, so bytes after that are allowed; None if a run is cut off
+fn rle_length(data: &[u8], pixels: usize) -> Option<usize> {
+    let (mut n, mut length) = (0, 0);
+    while n < data.len() && length < pixels {
+        let count = data[n] as usize;
+        if count == 0 {
+            // Padding between runs, see decode_img_runs()
+            n += 1;
+        } else if count < 128 {
+            length += count;
+            n += 2;
+        } else {
+            n += 256 - count + 1;
+            length += 256 - count;
+        }
+    }
+    (n <= data.len()).then_some(length)
+}
+
+fn detect_qgm(data: &[u8]) -> Option<Confidence> {
+    (u32_at(data, 0)? == 0x51474d20).then_some(Confidence::High)
+}
+
+fn detect_anm(data: &[u8]) -> Option<Confidence> {
+    const HEADER_SIZE: usize = 36;
+    const BLOCK_SIZE: usize = 56;
+    let magic = u32_at(data, 0)?;
+    if (magic != 0x564f5838 && magic != 0x5452494d) || u32_at(data, 4)? as usize != HEADER_SIZE {
+        return None;
+    }
+    let num_blocks = (u32_at(data, 24)? as usize).checked_mul(u32_at(data, 28)? as usize)?;
+    if num_blocks.checked_mul(BLOCK_SIZE)?.checked_add(HEADER_SIZE)? == data.len() {
+        Some(Confidence::High)
+    } else {
+        Some(Confidence::Medium)
+    }
+}
+
+fn detect_mdl(data: &[u8]) -> Option<Confidence> {
+    const SUBMESH_OFFSETS: usize = 0x42c;
+    const VERTEX_LIST_ADDRESS: usize = 0x6c;
+    let num_submeshes = u16_at(data, 0x1c)? as usize;
+    let header_end = SUBMESH_OFFSETS + 4 * num_submeshes;
+    if num_submeshes == 0 || u32_at(data, SUBMESH_OFFSETS)? as usize != header_end {
+        return None;
+    }
+    for n in 0..num_submeshes {
+        let offset = u32_at(data, SUBMESH_OFFSETS + 4 * n)? as usize;
+        if u32_at(data, offset + VERTEX_LIST_ADDRESS)? != 0x7c {
+            return None;
+        }
+    }
+    let bitmap_offset = u32_at(data, SUBMESH_OFFSETS - 4)? as usize;
+    (bitmap_offset >= header_end && bitmap_offset < data.len()).then_some(Confidence::High)
+}
+
+fn detect_rgd(data: &[u8]) -> Option<Confidence> {
+    let plausible = u32_at(data, 0)? == 0 && u32_at(data, 4)? == 2 && u32_at(data, 40)? == 0x5c && data.len() >= 0x5c;
+    plausible.then_some(Confidence::High)
+}
+
+fn detect_qgf(data: &[u8]) -> Option<Confidence> {
+    const NUM_CHARS: usize = 512;
+    const WIDTHS: usize = 24;
+    const OFFSETS: usize = WIDTHS + NUM_CHARS;
+    const HEADER_SIZE: usize = OFFSETS + 4 * NUM_CHARS;
+    let max_char_width = u32_at(data, 0)?;
+    let char_height = u32_at(data, 4)?;
+    if !(1..=255).contains(&max_char_width) || !(1..=255).contains(&char_height) || u32_at(data, 16)? > 1 {
+        return None;
+    }
+    let widths = data.get(WIDTHS..OFFSETS)?;
+    if widths.iter().any(|&width| width as u32 > max_char_width) {
+        return None;
+    }
+    for n in 0..NUM_CHARS {
+        let offset = u32_at(data, OFFSETS + 4 * n)? as usize;
+        if offset < HEADER_SIZE || offset > data.len() {
+            return None;
+        }
+    }
+    Some(Confidence::High)
+}
+
+fn detect_gra(data: &[u8]) -> Option<Confidence> {
+    const COLLECTION_OFFSETS: usize = 8 + 512;
+    let colour_mode = u32_at(data, 0)?;
+    let num_collections = u32_at(data, 4)? as usize;
+    if (colour_mode != 0 && colour_mode != 2) || !(1..=1024).contains(&num_collections) {
+        return None;
+    }
+    let header_end = COLLECTION_OFFSETS + 4 * num_collections;
+    for n in 0..num_collections {
+        let offset = u32_at(data, COLLECTION_OFFSETS + 4 * n)? as usize;
+        if offset < header_end {
+            return None;
+        }
+        let width = u32_at(data, offset + 8)?;
+        let height = u32_at(data, offset + 12)?;
+        let num_sprites = u32_at(data, offset + 16)? as usize;
+        if !plausible_size(width) || !plausible_size(height) || num_sprites > 1024 {
+            return None;
+        }
+        for s in 0..num_sprites {
+            let frame_offset = offset + u32_at(data, offset + 28 + 4 * s)? as usize;
+            let frame_size = if colour_mode == 0 { (width * height) as usize } else { 1 };
+            if frame_offset + frame_size > data.len() {
+                return None;
+            }
+        }
+    }
+    Some(Confidence::Medium)
+}
+
+fn detect_img(data: &[u8]) -> Option<Confidence> {
+    const DATA_OFFSET: usize = 64;
+    let width = u16_at(data, 32)? as u32;
+    let height = u16_at(data, 36)? as u32;
+    if !plausible_size(width) || !plausible_size(height) {
+        return None;
+    }
+    let pixels = (width * height) as usize;
+    (rle_length(data.get(DATA_OFFSET..)?, pixels)? >= pixels).then_some(Confidence::Medium)
+}
+
+fn detect_nod(data: &[u8]) -> Option<Confidence> {
+    const PALETTE_END: usize = 168 + 256 * 4;
+    let version = *data.get(6)?;
+    (data.len() >= PALETTE_END && (version == 0 || version == 4)).then_some(Confidence::Low)
+}
+
+fn detect_zzz(data: &[u8]) -> Option<Confidence> {
+    // Only the dimensions of the matching IMG tell whether the length is right
+    (rle_length(data, usize::MAX)? > 0).then_some(Confidence::Low)
+}
+
+type Detector = fn(&[u8]) -> Option<Confidence>;
+
+/// Returns the most likely kind of resource for `data`, or None if it matches no known format.
+/// Formats are tried from the most to the least specific check.
+pub fn detect(data: &[u8]) -> Option<Detection> {
+    let detectors: [ (ResourceKind, Detector); 9 ] = [
+        (ResourceKind::Qgm, detect_qgm),
+        (ResourceKind::Anm, detect_anm),
+        (ResourceKind::Mdl, detect_mdl),
+        (ResourceKind::Rgd, detect_rgd),
+        (ResourceKind::Qgf, detect_qgf),
+        (ResourceKind::Gra, detect_gra),
+        (ResourceKind::Img, detect_img),
+        (ResourceKind::Nod, detect_nod),
+        (ResourceKind::Zzz, detect_zzz),
+    ];
+    detectors.iter().find_map(|(kind, detector)| detector(data).map(|confidence| Detection{ kind: *kind, confidence }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qfg5resource::fixtures;
+
+    fn detected(data: &[u8]) -> Option<(ResourceKind, Confidence)> {
+        detect(data).map(|d| (d.kind, d.confidence))
+    }
+
+    #[test]
+    fn test_detect_fixtures() {
+        assert_eq!(detected(&fixtures::qgm()), Some((ResourceKind::Qgm, Confidence::High)));
+        assert_eq!(detected(&fixtures::anm()), Some((ResourceKind::Anm, Confidence::High)));
+        assert_eq!(detected(&fixtures::mdl()), Some((ResourceKind::Mdl, Confidence::High)));
+        assert_eq!(detected(&fixtures::rgd()), Some((ResourceKind::Rgd, Confidence::High)));
+        assert_eq!(detected(&fixtures::qgf()), Some((ResourceKind::Qgf, Confidence::High)));
+        assert_eq!(detected(&fixtures::gra()), Some((ResourceKind::Gra, Confidence::Medium)));
+        assert_eq!(detected(&fixtures::img()), Some((ResourceKind::Img, Confidence::Medium)));
+        assert_eq!(detected(&fixtures::nod()), Some((ResourceKind::Nod, Confidence::Low)));
+        assert_eq!(detected(&fixtures::zzz()), Some((ResourceKind::Zzz, Confidence::Low)));
+    }
+
+    #[test]
+    fn test_detect_padded_img() {
+        // Zero counts between the runs and after the last one, as in backgrounds of the game
+        let mut img = fixtures::img();
+        img.truncate(64);
+        img.extend([ 2, 1, 0, 2, 2, 0, 0, 2, 1, 2, 2, 0, 0, 0 ]);
+        assert_eq!(detected(&img), Some((ResourceKind::Img, Confidence::Medium)));
+        // Bytes after the last pixel are not read
+        img.extend([ 0xfe, 1 ]);
+        assert_eq!(detected(&img), Some((ResourceKind::Img, Confidence::Medium)));
+        // Too few pixels, or a run that is cut off
+        let mut short = fixtures::img();
+        short.truncate(70);
+        assert_ne!(detected(&short).map(|d| d.0), Some(ResourceKind::Img));
+        short.push(0xfd);
+        assert_ne!(detected(&short).map(|d| d.0), Some(ResourceKind::Img));
+
+        let mut zzz = fixtures::zzz();
+        zzz.insert(2, 0);
+        assert_eq!(detected(&zzz), Some((ResourceKind::Zzz, Confidence::Low)));
+    }
+
+    #[test]
+    fn test_detect_anm_length() {
+        let mut anm = fixtures::anm();
+        anm.push(0);
+        assert_eq!(detected(&anm), Some((ResourceKind::Anm, Confidence::Medium)));
+    }
+
+    #[test]
+    fn test_detect_unknown() {
+        assert_eq!(detect(&[]), None);
+        // A WAV file with a few samples; a header alone decodes as a depth map, which has no magic
+        // value and may have zero counts between its runs
+        let mut wav = b"RIFF\x2c\0\0\0WAVEfmt \x10\0\0\0\x01\0\x01\0\x22\x56\0\0\x44\xac\0\0\x02\0\x10\0data\x08\0\0\0".to_vec();
+        wav.extend([ 0, 1, 0, 2, 0, 3, 0, 4 ]);
+        assert_eq!(detect(&wav), None);
+        // Deterministic pseudo-random data
+        let mut state = 0x1234_5678u32;
+        let random: Vec<u8> = (0..4096).map(|_| {
+            state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+            (state >> 24) as u8
+        }).collect();
+        assert_eq!(detect(&random), None);
+    }
+
+    #[test]
+    fn test_extension() {
+        for kind in ResourceKind::ALL {
+            assert_eq!(ResourceKind::from_extension(&kind.to_string()), Some(kind));
+        }
+        assert_eq!(ResourceKind::from_extension("wav"), None);
+    }
+}
@@ -0,0 +1,75 @@
+/*-
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * Copyright (c) 2024 Rink Springer <rink@rink.nu>
+ * For conditions of distribution and use, see LICENSE file
+ */
+/// Errors of the resource decoders. Every error carries the byte offset where it was detected.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The data ends before a field
+    #[error("truncated at offset {at:#x}: needed {needed} bytes, got {got}")]
+    Truncated{ needed: usize, got: usize, at: u64 },
+    #[error("bad magic at offset {at:#x}: expected {expected}, found {found:#x}")]
+    BadMagic{ expected: &'static str, found: u32, at: u64 },
+    /// An index refers past the end of a list; `max` is the length of that list
+    #[error("{what} {index} out of range at offset {at:#x}, there are {max}")]
+    IndexOutOfRange{ what: &'static str, index: usize, max: usize, at: u64 },
+    /// Valid data using a variant of the format that is not understood yet
+    #[error("unsupported {what} at offset {at:#x}")]
+    Unsupported{ what: String, at: u64 },
+    /// A field has a value that the format does not allow
+    #[error("invalid {what} at offset {at:#x}")]
+    Invalid{ what: String, at: u64 },
+    /// Reading the underlying file, or writing the output, failed
+    #[error("I/O error at offset {at:#x}")]
+    Io{ #[source] source: std::io::Error, at: u64 },
+    /// An error within a part of the resource, such as a submesh, which starts at `at`
+    #[error("{what} at offset {at:#x}: {inner}")]
+    Context{ what: String, at: u64, inner: Box<Error> },
+}
+
+impl Error {
+    /// Byte offset where the error was detected
+    pub fn offset(&self) -> u64 {
+        match self {
+            Error::Truncated{ at, .. } | Error::BadMagic{ at, .. } | Error::IndexOutOfRange{ at, .. } |
+            Error::Unsupported{ at, .. } | Error::Invalid{ at, .. } | Error::Io{ at, .. } => *at,
+            Error::Context{ inner, .. } => inner.offset(),
+        }
+    }
+
+    /// Moves the offsets by `by`, for an error in data that was decoded apart from the rest of
+    /// the resource
+    pub(crate) fn move_by(&mut self, by: u64) {
+        match self {
+            Error::Truncated{ at, .. } | Error::BadMagic{ at, .. } | Error::IndexOutOfRange{ at, .. } |
+            Error::Unsupported{ at, .. } | Error::Invalid{ at, .. } | Error::Io{ at, .. } => *at += by,
+            Error::Context{ at, inner, .. } => {
+                *at += by;
+                inner.move_by(by);
+            },
+        }
+    }
+
+    /// The error itself, without the parts of the resource it was found in
+    pub fn root(&self) -> &Error {
+        match self {
+            Error::Context{ inner, .. } => inner.root(),
+            _ => self,
+        }
+    }
+}
+
+/// Adds the part of the resource that was being decoded to errors
+pub(crate) trait ErrorContext<T> {
+    fn with_context(self, at: u64, what: impl FnOnce() -> String) -> Result<T>;
+}
+
+impl<T> ErrorContext<T> for Result<T> {
+    fn with_context(self, at: u64, what: impl FnOnce() -> String) -> Result<T> {
+        self.map_err(|inner| Error::Context{ what: what(), at, inner: Box::new(inner) })
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
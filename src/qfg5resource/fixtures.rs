@@ -0,0 +1,401 @@
+/*-
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * Copyright (c) 2024 Rink Springer <rink@rink.nu>
+ * For conditions of distribution and use, see LICENSE file
+ */
+//! Small, synthetic resource files for tests; the game data itself cannot be distributed.
+
+use byteorder::{LittleEndian, WriteBytesExt};
+
+fn name16(name: &str) -> Vec<u8> {
+    let mut bytes = name.as_bytes().to_vec();
+    bytes.resize(16, 0);
+    bytes
+}
+
+/// A model named "fixture" with a single quad submesh using one 2x2 subbitmap
+pub fn mdl() -> Vec<u8> {
+    let mut data = vec![ 0u8; 0xc ];
+    data.extend(name16("fixture"));
+    data.write_u16::<LittleEndian>(1).unwrap();
+    data.extend([ 0u8; 0xf ]);
+    let mut palette = vec![ 0u8; 1019 ];
+    palette[4..8].copy_from_slice(&[ 255, 0, 0, 0 ]);
+    data.extend(palette);
+    let header_size = data.len() as u32 + 8;
+
+    let mut submesh = name16("quad");
+    submesh.extend([ 0u8; 80 ]);
+    let (num_vertices, num_uvs, num_faces) = (4u32, 4u32, 2u32);
+    for value in [ num_vertices, num_uvs, num_faces, 0x7c, 0x7c + 12 * num_vertices, 0x7c + 12 * num_vertices + 8 * num_uvs,
+                   0x7c + 12 * num_vertices + 8 * num_uvs + 40 * num_faces ] {
+        submesh.write_u32::<LittleEndian>(value).unwrap();
+    }
+    for (x, y) in [ (0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0) ] {
+        for v in [ x, y, 0.0f32 ] {
+            submesh.write_f32::<LittleEndian>(v).unwrap();
+        }
+    }
+    for (u, v) in [ (0.0f32, 0.0f32), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0) ] {
+        submesh.write_f32::<LittleEndian>(u).unwrap();
+        submesh.write_f32::<LittleEndian>(v).unwrap();
+    }
+    for (a, b, c) in [ (0, 1, 2), (0, 2, 3) ] {
+        for value in [ a, b, c, a, b, c, 0u32 ] {
+            submesh.write_u32::<LittleEndian>(value).unwrap();
+        }
+        for n in [ 0.0f32, 0.0, 1.0 ] {
+            submesh.write_f32::<LittleEndian>(n).unwrap();
+        }
+    }
+    for n in 0..num_vertices {
+        for l in [ n as f32, 0.0, 0.0, 1.0 ] {
+            submesh.write_f32::<LittleEndian>(l).unwrap();
+        }
+    }
+
+    let bitmap_offset = header_size + submesh.len() as u32;
+    data.write_u32::<LittleEndian>(bitmap_offset).unwrap();
+    data.write_u32::<LittleEndian>(header_size).unwrap();
+    data.extend(submesh);
+
+    data.write_u32::<LittleEndian>(4).unwrap();
+    data.write_f32::<LittleEndian>(2.0).unwrap();
+    data.write_f32::<LittleEndian>(2.0).unwrap();
+    for value in [ 1u32, 1, 1, 1 ] {
+        data.write_u32::<LittleEndian>(value).unwrap();
+    }
+    data.extend([ 1u8, 0, 0, 1 ]);
+    data
+}
+
+/// The fixture model with a second, 4x1 subbitmap that the second face uses. There are four
+/// bytes between the subbitmaps, so only the offset table tells where the second one is.
+pub fn mdl_two_subbitmaps() -> Vec<u8> {
+    let mut data = mdl();
+    let second_face = 0x430 + 0x7c + 12 * 4 + 8 * 4 + 40;
+    data[second_face + 24] = 1;
+    let first = data.split_off(data.len() - 24 - 4);
+    data.truncate(data.len() - 4);
+    for offset in [ 8u32, 8 + 28 + 4 ] {
+        data.write_u32::<LittleEndian>(offset).unwrap();
+    }
+    data.extend(first);
+    data.extend([ 0xee; 4 ]);
+    data.write_f32::<LittleEndian>(4.0).unwrap();
+    data.write_f32::<LittleEndian>(1.0).unwrap();
+    for value in [ 2u32, 0, 3, 0 ] {
+        data.write_u32::<LittleEndian>(value).unwrap();
+    }
+    data.extend([ 2u8, 3, 4, 5 ]);
+    data
+}
+
+/// An animation named "fixture" for one submesh, with two frames 100ms apart
+pub fn anm() -> Vec<u8> {
+    let mut data = Vec::new();
+    data.write_u32::<LittleEndian>(0x564f5838).unwrap();
+    data.write_u32::<LittleEndian>(36).unwrap();
+    data.extend(name16("fixture"));
+    for value in [ 1u32, 2, 100 ] {
+        data.write_u32::<LittleEndian>(value).unwrap();
+    }
+    for x in [ 0.0f32, 1.0 ] {
+        data.write_u32::<LittleEndian>(1).unwrap();
+        data.write_u32::<LittleEndian>(0).unwrap();
+        for v in [ x, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0 ] {
+            data.write_f32::<LittleEndian>(v).unwrap();
+        }
+    }
+    data
+}
+
+/// Sprites with one uncompressed collection of two 2x2 frames
+pub fn gra() -> Vec<u8> {
+    let mut data = Vec::new();
+    data.write_u32::<LittleEndian>(0).unwrap();
+    data.write_u32::<LittleEndian>(1).unwrap();
+    let mut palette = [ 0u8; 512 ];
+    // Index 1 is pure red in RGB555
+    palette[2..4].copy_from_slice(&(31u16 << 10).to_le_bytes());
+    data.extend(palette);
+    let collection_offset = data.len() as u32 + 4;
+    data.write_u32::<LittleEndian>(collection_offset).unwrap();
+
+    // x, y, width, height, number of sprites, frame delay, flags
+    for value in [ 10u32, 20, 2, 2, 2, 100, 0 ] {
+        data.write_u32::<LittleEndian>(value).unwrap();
+    }
+    data.write_u32::<LittleEndian>(36).unwrap();
+    data.write_u32::<LittleEndian>(40).unwrap();
+    data.extend([ 0u8, 1, 1, 0 ]);
+    data.extend([ 1u8, 1, 1, 1 ]);
+    data
+}
+
+/// Messages file 415 with a single message that has a label and one dialog option
+pub fn qgm() -> Vec<u8> {
+    let mut data = Vec::new();
+    data.write_u32::<LittleEndian>(0x51474d20).unwrap();
+    data.write_u32::<LittleEndian>(1).unwrap();
+    data.write_u32::<LittleEndian>(1).unwrap();
+    data.write_u16::<LittleEndian>(0).unwrap();
+    data.write_u16::<LittleEndian>(415).unwrap();
+
+    let text = b"Hello";
+    // ids, speaker, 3 unknown, dialog options, flags, unknown, message id, length, message flag, label flag, unknown
+    for value in [ 1u16, 2, 3, 4, 5, 0, 0, 0, 1, 0, 0, 7, text.len() as u16, 0, 1, 0 ] {
+        data.write_u16::<LittleEndian>(value).unwrap();
+    }
+    data.extend(b"LABEL0000001\0");
+    data.extend(b"OPTION000001\0");
+    data.extend(text);
+    data.write_u32::<LittleEndian>(0).unwrap();
+    data
+}
+
+/// A 2D font where only 'A' has a (2x2) glyph
+pub fn qgf() -> Vec<u8> {
+    const NUM_CHARS: usize = 512;
+    let mut data = Vec::new();
+    // maximum width, height, spacing, unknown, 3D flag, unknown
+    for value in [ 2u32, 2, 1, 0, 0, 0 ] {
+        data.write_u32::<LittleEndian>(value).unwrap();
+    }
+    let mut widths = [ 0u8; NUM_CHARS ];
+    widths['A' as usize] = 2;
+    data.extend(widths);
+    let glyph_offset = (data.len() + NUM_CHARS * 4) as u32;
+    for _ in 0..NUM_CHARS {
+        data.write_u32::<LittleEndian>(glyph_offset).unwrap();
+    }
+    // Pairs of a value and an unknown byte; 0xfe skips 2 pixels
+    data.extend([ 5u8, 0, 0xfe, 0, 5, 0 ]);
+    data
+}
+
+/// Regions of a room: one triangle, which is special
+pub fn rgd() -> Vec<u8> {
+    const DATA_START: u32 = 0x5c;
+    let points_offset = DATA_START;
+    let vectors_offset = points_offset + 3 * 16;
+    let segments_offset = vectors_offset + 24;
+    let region_ids_offset = segments_offset + 3 * 16;
+    let regions_offset = region_ids_offset + 4;
+    let segment_ids_offset = regions_offset + 8;
+    let special_offset = segment_ids_offset + 16;
+
+    let mut data = Vec::new();
+    for value in [ 0u32, 2, 1, regions_offset, 0, 0, 0, 1, region_ids_offset, 1, DATA_START, 3, segments_offset,
+                   3, points_offset, 1, vectors_offset, 1, 1, 0, 0, special_offset ] {
+        data.write_u32::<LittleEndian>(value).unwrap();
+    }
+    data.resize(DATA_START as usize, 0);
+    for (x, y) in [ (0.0f64, 0.0f64), (10.0, 0.0), (0.0, 10.0) ] {
+        data.write_f64::<LittleEndian>(x).unwrap();
+        data.write_f64::<LittleEndian>(y).unwrap();
+    }
+    for v in [ 1.0f64, 2.0, 3.0 ] {
+        data.write_f64::<LittleEndian>(v).unwrap();
+    }
+    for (a, b) in [ (0u32, 1u32), (1, 2), (2, 0) ] {
+        data.write_u32::<LittleEndian>(a).unwrap();
+        data.write_u32::<LittleEndian>(b).unwrap();
+        data.write_f64::<LittleEndian>(0.0).unwrap();
+    }
+    data.write_u32::<LittleEndian>(42).unwrap();
+    data.write_u32::<LittleEndian>(0).unwrap();
+    data.write_u32::<LittleEndian>(segment_ids_offset).unwrap();
+    for value in [ 3u32, 0, 1, 2 ] {
+        data.write_u32::<LittleEndian>(value).unwrap();
+    }
+    data.write_u32::<LittleEndian>(42).unwrap();
+    data
+}
+
+/// A 4x2 room background; the left half uses color 1, the right half color 2
+pub fn img() -> Vec<u8> {
+    let mut data = vec![ 0u8; 64 ];
+    data[32..34].copy_from_slice(&4u16.to_le_bytes());
+    data[36..38].copy_from_slice(&2u16.to_le_bytes());
+    data.extend([ 2, 1, 2, 2, 2, 1, 2, 2 ]);
+    data
+}
+
+/// Retail room palette where index n is (n, 255 - n, 0)
+pub fn nod() -> Vec<u8> {
+    let mut data = vec![ 0u8; 168 + 256 * 4 ];
+    data[6] = 4;
+    for n in 0..256 {
+        data[168 + n * 4..168 + n * 4 + 3].copy_from_slice(&[ n as u8, 255 - n as u8, 0 ]);
+    }
+    data
+}
+
+/// Depth map for img(): the top row is near, the bottom row is far
+pub fn zzz() -> Vec<u8> {
+    vec![ 4, 0, 4, 255 ]
+}
+
+/// Offset of the samples in aud()
+pub const AUD_DATA_OFFSET: usize = 48;
+
+/// Mono 22050 Hz IMA ADPCM speech: a single block of 17 samples, starting at 100
+pub fn aud() -> Vec<u8> {
+    let mut fmt = Vec::new();
+    for value in [ 0x11u16, 1 ] {
+        fmt.write_u16::<LittleEndian>(value).unwrap();
+    }
+    fmt.write_u32::<LittleEndian>(22050).unwrap();
+    fmt.write_u32::<LittleEndian>(22050 * 12 / 17).unwrap();
+    // Block size, bits per sample, size of the extra data and samples per block
+    for value in [ 12u16, 4, 2, 17 ] {
+        fmt.write_u16::<LittleEndian>(value).unwrap();
+    }
+    let mut block = vec![ 100, 0, 0, 0 ];
+    block.extend([ 0x17, 0, 0, 0, 0x88, 0x88, 0x88, 0x88 ]);
+    riff_wave(&fmt, &block)
+}
+
+/// 22050 Hz PCM sound with the given samples
+pub fn wav(channels: u16, bits_per_sample: u16, samples: &[u8]) -> Vec<u8> {
+    let block_align = channels * bits_per_sample / 8;
+    let mut fmt = Vec::new();
+    for value in [ 1u16, channels ] {
+        fmt.write_u16::<LittleEndian>(value).unwrap();
+    }
+    fmt.write_u32::<LittleEndian>(22050).unwrap();
+    fmt.write_u32::<LittleEndian>(22050 * block_align as u32).unwrap();
+    for value in [ block_align, bits_per_sample ] {
+        fmt.write_u16::<LittleEndian>(value).unwrap();
+    }
+    riff_wave(&fmt, samples)
+}
+
+fn riff_wave(fmt: &[u8], samples: &[u8]) -> Vec<u8> {
+    let mut data = b"RIFF".to_vec();
+    data.write_u32::<LittleEndian>((20 + fmt.len() + samples.len()) as u32).unwrap();
+    data.extend(b"WAVEfmt ");
+    data.write_u32::<LittleEndian>(fmt.len() as u32).unwrap();
+    data.extend(fmt);
+    data.extend(b"data");
+    data.write_u32::<LittleEndian>(samples.len() as u32).unwrap();
+    data.extend(samples);
+    data
+}
+
+/// An uncompressed SPK archive containing the given files
+pub fn spk(items: &[(&str, &[u8])]) -> Vec<u8> {
+    let items: Vec<_> = items.iter().map(|&(name, contents)| (name, contents, false)).collect();
+    spk_compressed(&items)
+}
+
+/// An SPK archive in which the files marked as compressed are deflated
+pub fn spk_compressed(items: &[(&str, &[u8], bool)]) -> Vec<u8> {
+    // Local file headers are skipped by the reader, so their contents do not matter
+    const LOCAL_HEADER_SIZE: usize = 0x42;
+    let stored: Vec<_> = items.iter().map(|&(_, contents, compressed)| {
+        if compressed { miniz_oxide::deflate::compress_to_vec(contents, 6) } else { contents.to_vec() }
+    }).collect();
+    let mut data = Vec::new();
+    let mut locations = Vec::new();
+    for ((name, _, _), stored) in items.iter().zip(&stored) {
+        locations.push(data.len() as u32);
+        data.extend(vec![ 0u8; LOCAL_HEADER_SIZE ]);
+        data.extend(name.as_bytes());
+        data.extend(stored);
+    }
+    let directory_offset = data.len();
+    for (((name, contents, compressed), stored), location) in items.iter().zip(&stored).zip(locations) {
+        data.extend([ 0u8; 10 ]);
+        // Compression method
+        data.write_u16::<LittleEndian>(if *compressed { 8 } else { 0 }).unwrap();
+        // Time and date
+        data.extend([ 0u8; 4 ]);
+        data.write_u32::<LittleEndian>(crc32fast::hash(contents)).unwrap();
+        data.write_u32::<LittleEndian>(stored.len() as u32).unwrap();
+        data.write_u32::<LittleEndian>(contents.len() as u32).unwrap();
+        data.write_u32::<LittleEndian>(name.len() as u32).unwrap();
+        data.extend([ 0u8; 10 ]);
+        data.write_u32::<LittleEndian>(location).unwrap();
+        data.extend(name.as_bytes());
+    }
+    let directory_size = data.len() - directory_offset;
+    data.write_u16::<LittleEndian>(0x4b50).unwrap();
+    data.write_u16::<LittleEndian>(0x0705).unwrap();
+    data.extend([ 0u8; 4 ]);
+    data.write_u16::<LittleEndian>(items.len() as u16).unwrap();
+    data.write_u16::<LittleEndian>(items.len() as u16).unwrap();
+    data.write_u32::<LittleEndian>(directory_size as u32).unwrap();
+    data.write_u32::<LittleEndian>(directory_offset as u32).unwrap();
+    // Comment length
+    data.write_u16::<LittleEndian>(0).unwrap();
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qfg5resource::*;
+
+    #[test]
+    fn test_fixtures_decode() {
+        let mdl = qfg5mdl::Qfg5Model::new(&mdl()).unwrap();
+        assert_eq!(mdl.submeshes.len(), 1);
+        assert_eq!(mdl.submeshes[0].faces.len(), 2);
+        assert_eq!(mdl.subbitmaps[0].bitmap, vec![ 1, 0, 0, 1 ]);
+
+        let anm = qfg5anm::AnmDecoder::new(&anm()).unwrap();
+        assert_eq!((anm.anims.len(), anm.num_frames(), anm.delay), (1, 2, 100));
+
+        let gra = qfg5gra::GraDecoder::new(&gra()).unwrap();
+        assert_eq!(gra.sprite_collections[0].sprites[1].pixels, vec![ 1, 1, 1, 1 ]);
+        assert_eq!(gra.palette[1], (255, 0, 0));
+
+        let mdl = qfg5mdl::Qfg5Model::new(&mdl_two_subbitmaps()).unwrap();
+        assert_eq!(mdl.subbitmaps[1].bitmap.as_ref(), [ 2, 3, 4, 5 ]);
+
+        let qgm = qfg5qgm::QgmDecoder::new(&qgm()).unwrap();
+        assert_eq!(qgm.messages[0].text, "Hello");
+        assert_eq!(qgm.messages[0].dialog_options.len(), 1);
+
+        let qgf = qfg5qgf::QgfDecoder::new(&qgf()).unwrap();
+        assert_eq!(qgf.chars['A' as usize].data, vec![ 5, 0, 0, 5 ]);
+
+        let rgd = qfg5rgd::RgdDecoder::new(&rgd()).unwrap();
+        assert_eq!(rgd.region_at(2.0, 2.0), Some(0));
+        assert!(rgd.is_special(0));
+
+        let img = qfg5img::ImageDecoder::new(&img()).unwrap();
+        assert_eq!(img.get_pixels(), &[ 1, 1, 2, 2, 1, 1, 2, 2 ]);
+        assert_eq!(qfg5nod::NodDecoder::new(&nod()).unwrap().get_palette()[1], (1, 254, 0));
+        assert_eq!(qfg5zzz::ZzzDecoder::new(&zzz(), &img).unwrap().get_pixels(), &[ 0, 0, 0, 0, 255, 255, 255, 255 ]);
+    }
+
+    #[test]
+    #[cfg(not(target_arch = "wasm32"))]
+    fn test_spk_fixture() {
+        let path = std::env::temp_dir().join(format!("qfg5-fixture-{}.spk", std::process::id()));
+        std::fs::write(&path, spk(&[ ("MDL/1.MDL", b"abc"), ("ANM/2.ANM", b"de") ])).unwrap();
+        let archive = qfg5spk::SpkArchive::new(std::fs::File::open(&path).unwrap()).unwrap();
+        let items = archive.get_items();
+        assert_eq!(items.iter().map(|i| i.filename.as_str()).collect::<Vec<_>>(), vec![ "MDL/1.MDL", "ANM/2.ANM" ]);
+        assert_eq!(archive.read_item(&items[1]).unwrap(), b"de");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_spk_from_bytes() {
+        let mut data = spk(&[ ("MDL/1.MDL", b"abc") ]);
+        let archive = qfg5spk::SpkArchive::from_bytes(data.clone()).unwrap();
+        assert_eq!(archive.read_item(&archive.get_items()[0]).unwrap(), b"abc");
+        assert!(matches!(archive.item_data(&archive.get_items()[0]).unwrap(), std::borrow::Cow::Borrowed(b"abc")));
+
+        // An item that claims to be larger than the archive
+        let directory_entry = 0x42 + 9 + 3;
+        data[directory_entry + 20..directory_entry + 28].copy_from_slice(&[ 0xff, 0xff, 0xff, 0x7f, 0xff, 0xff, 0xff, 0x7f ]);
+        let archive = qfg5spk::SpkArchive::from_bytes(data).unwrap();
+        assert!(matches!(archive.read_item(&archive.get_items()[0]), Err(Error::Truncated{ needed: 0x7fffffff, .. })));
+    }
+}
@@ -0,0 +1,251 @@
+/*-
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * Copyright (c) 2024 Rink Springer <rink@rink.nu>
+ * For conditions of distribution and use, see LICENSE file
+ */
+//! Reads resources from SPK archives, or directories of extracted files, by name. The items read
+//! most recently are kept in memory, up to a number of bytes, so that tools which use the same
+//! resources over and over do not read them from the archive every time. A manager can be shared
+//! between threads.
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use super::qfg5spk::SpkArchive;
+use super::{Error, Result};
+
+/// How well the cache works, for showing in a viewer
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Items in the cache, and their total size
+    pub entries: usize,
+    pub bytes: usize,
+}
+
+/// Archive and item name, both lowercase
+type Key = (String, String);
+
+struct CacheEntry {
+    data: Arc<Vec<u8>>,
+    /// Value of Cache::clock when last used
+    used: u64,
+}
+
+struct Cache {
+    max_bytes: usize,
+    entries: HashMap<Key, CacheEntry>,
+    clock: u64,
+    stats: CacheStats,
+}
+
+impl Cache {
+    fn get(&mut self, key: &Key) -> Option<Arc<Vec<u8>>> {
+        self.clock += 1;
+        match self.entries.get_mut(key) {
+            Some(entry) => {
+                entry.used = self.clock;
+                self.stats.hits += 1;
+                Some(entry.data.clone())
+            },
+            None => {
+                self.stats.misses += 1;
+                None
+            },
+        }
+    }
+
+    /// Items larger than the cache are not kept
+    fn insert(&mut self, key: Key, data: Arc<Vec<u8>>) {
+        if data.len() > self.max_bytes {
+            return;
+        }
+        if let Some(old) = self.entries.remove(&key) {
+            self.stats.bytes -= old.data.len();
+        }
+        while self.stats.bytes + data.len() > self.max_bytes {
+            let Some(oldest) = self.entries.iter().min_by_key(|(_, entry)| entry.used).map(|(key, _)| key.clone()) else { break };
+            self.remove(&oldest);
+        }
+        self.stats.bytes += data.len();
+        self.entries.insert(key, CacheEntry{ data, used: self.clock });
+        self.stats.entries = self.entries.len();
+    }
+
+    fn remove(&mut self, key: &Key) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.stats.bytes -= entry.data.len();
+            self.stats.entries = self.entries.len();
+        }
+    }
+}
+
+enum Source {
+    Spk(SpkArchive),
+    Directory(PathBuf),
+}
+
+pub struct ResourceManager {
+    /// By lowercase name
+    archives: HashMap<String, Source>,
+    cache: Mutex<Cache>,
+}
+
+impl ResourceManager {
+    /// Keeps up to `cache_bytes` bytes of items in memory
+    pub fn new(cache_bytes: usize) -> Self {
+        let cache = Cache{ max_bytes: cache_bytes, entries: HashMap::new(), clock: 0, stats: CacheStats::default() };
+        ResourceManager{ archives: HashMap::new(), cache: Mutex::new(cache) }
+    }
+
+    /// Adds an archive under `name`, such as "hdn.spk", replacing any archive of that name
+    pub fn add_archive(&mut self, name: &str, archive: SpkArchive) {
+        let name = name.to_lowercase();
+        self.invalidate_archive(&name);
+        self.archives.insert(name, Source::Spk(archive));
+    }
+
+    /// Adds directory `path` under `name`, replacing any archive of that name. Its items are the
+    /// files in it, so unlike those of SPK archives their names are case sensitive on most systems.
+    pub fn add_directory(&mut self, name: &str, path: impl Into<PathBuf>) {
+        let name = name.to_lowercase();
+        self.invalidate_archive(&name);
+        self.archives.insert(name, Source::Directory(path.into()));
+    }
+
+    /// Names of the archives, lowercase
+    pub fn archives(&self) -> impl Iterator<Item = &str> {
+        self.archives.keys().map(|name| name.as_str())
+    }
+
+    /// Reads item `name` of `archive`, such as "MDL/063.MDL"; names are compared without regard to
+    /// case. Returns None if there is no such archive or item.
+    pub fn read(&self, archive: &str, name: &str) -> Result<Option<Arc<Vec<u8>>>> {
+        let key = (archive.to_lowercase(), name.to_lowercase());
+        if let Some(data) = self.cache.lock().unwrap().get(&key) {
+            return Ok(Some(data));
+        }
+        // Read without holding the lock, so that other threads can use the cache meanwhile
+        let data = match self.archives.get(&key.0) {
+            Some(Source::Spk(spk)) => {
+                let Some(item) = spk.find(&key.1) else { return Ok(None) };
+                spk.read_item(item)?
+            },
+            Some(Source::Directory(path)) => match std::fs::read(path.join(name)) {
+                Ok(data) => data,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+                Err(source) => return Err(Error::Io{ source, at: 0 }),
+            },
+            None => return Ok(None),
+        };
+        let data = Arc::new(data);
+        self.cache.lock().unwrap().insert(key, data.clone());
+        Ok(Some(data))
+    }
+
+    /// Drops item `name` from the cache, whatever archive it is in, so that it is read again
+    pub fn invalidate(&self, name: &str) {
+        let name = name.to_lowercase();
+        let mut cache = self.cache.lock().unwrap();
+        let keys: Vec<Key> = cache.entries.keys().filter(|(_, item)| *item == name).cloned().collect();
+        for key in keys {
+            cache.remove(&key);
+        }
+    }
+
+    fn invalidate_archive(&self, archive: &str) {
+        let mut cache = self.cache.lock().unwrap();
+        let keys: Vec<Key> = cache.entries.keys().filter(|(a, _)| a == archive).cloned().collect();
+        for key in keys {
+            cache.remove(&key);
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.cache.lock().unwrap().stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qfg5resource::fixtures;
+
+    fn manager(cache_bytes: usize) -> ResourceManager {
+        let spk = fixtures::spk(&[ ("A", &[ 1u8; 10 ]), ("B", &[ 2u8; 10 ]), ("C", &[ 3u8; 10 ]), ("D", &[ 4u8; 30 ]) ]);
+        let mut manager = ResourceManager::new(cache_bytes);
+        manager.add_archive("Test.SPK", SpkArchive::from_bytes(spk).unwrap());
+        manager
+    }
+
+    #[test]
+    fn test_read() {
+        let manager = manager(20);
+        assert_eq!(*manager.read("test.spk", "a").unwrap().unwrap(), [ 1u8; 10 ]);
+        assert!(manager.read("test.spk", "E").unwrap().is_none());
+        assert!(manager.read("other.spk", "A").unwrap().is_none());
+        manager.read("TEST.spk", "A").unwrap();
+        assert_eq!(manager.stats(), CacheStats{ hits: 1, misses: 3, entries: 1, bytes: 10 });
+    }
+
+    #[test]
+    fn test_eviction() {
+        let manager = manager(20);
+        manager.read("test.spk", "A").unwrap();
+        manager.read("test.spk", "B").unwrap();
+        // A was used last, so B goes to make room for C
+        manager.read("test.spk", "A").unwrap();
+        manager.read("test.spk", "C").unwrap();
+        manager.read("test.spk", "A").unwrap();
+        manager.read("test.spk", "B").unwrap();
+        assert_eq!(manager.stats(), CacheStats{ hits: 2, misses: 4, entries: 2, bytes: 20 });
+        // Too large to be kept at all
+        manager.read("test.spk", "D").unwrap();
+        assert_eq!(manager.stats().bytes, 20);
+    }
+
+    #[test]
+    fn test_invalidate() {
+        let manager = manager(100);
+        manager.read("test.spk", "A").unwrap();
+        manager.read("test.spk", "B").unwrap();
+        manager.invalidate("a");
+        assert_eq!(manager.stats().entries, 1);
+        manager.read("test.spk", "A").unwrap();
+        assert_eq!(manager.stats().misses, 3);
+    }
+
+    #[test]
+    fn test_directory() {
+        let dir = std::env::temp_dir().join(format!("qfg5-manager-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("063.MDL"), [ 1u8; 10 ]).unwrap();
+        let mut manager = ResourceManager::new(100);
+        manager.add_directory("mdl", &dir);
+        assert_eq!(*manager.read("mdl", "063.MDL").unwrap().unwrap(), [ 1u8; 10 ]);
+        assert!(manager.read("mdl", "064.MDL").unwrap().is_none());
+
+        // Changes are only seen once the file is dropped from the cache
+        std::fs::write(dir.join("063.MDL"), [ 2u8; 10 ]).unwrap();
+        assert_eq!(*manager.read("mdl", "063.MDL").unwrap().unwrap(), [ 1u8; 10 ]);
+        manager.invalidate("063.MDL");
+        assert_eq!(*manager.read("mdl", "063.MDL").unwrap().unwrap(), [ 2u8; 10 ]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_threads() {
+        let manager = manager(100);
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| for name in [ "A", "B", "C" ] {
+                    assert!(manager.read("test.spk", name).unwrap().is_some());
+                });
+            }
+        });
+        let stats = manager.stats();
+        assert_eq!(stats.hits + stats.misses, 12);
+        assert_eq!((stats.entries, stats.bytes), (3, 30));
+    }
+}
@@ -0,0 +1,121 @@
+/*-
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * Copyright (c) 2024 Rink Springer <rink@rink.nu>
+ * For conditions of distribution and use, see LICENSE file
+ */
+//! Naming conventions of the extracted data tree. Rooms are numbered and their resources are
+//! stored as img/<id>.img, nod/<id>.nod and so on. Models have a 3-digit number; the name of an
+//! animation starts with the number of its model, followed by its index: 06300.anm is the first
+//! animation of 063.mdl. Names are compared without regard to case, as the archive uses uppercase.
+use std::path::{Path, PathBuf};
+use super::ResourceKind;
+
+/// The files of a room, relative to the data directory
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RoomFileSet {
+    pub img: PathBuf,
+    pub nod: PathBuf,
+    pub zzz: PathBuf,
+    pub rgd: PathBuf,
+}
+
+pub fn room_files(id: u32) -> RoomFileSet {
+    let path = |kind: ResourceKind| Path::new(kind.extension()).join(format!("{}.{}", id, kind.extension()));
+    RoomFileSet{
+        img: path(ResourceKind::Img),
+        nod: path(ResourceKind::Nod),
+        zzz: path(ResourceKind::Zzz),
+        rgd: path(ResourceKind::Rgd),
+    }
+}
+
+/// Lowercase stem and kind of a file name, if the extension is that of a known resource
+fn split(filename: &Path) -> Option<(String, ResourceKind)> {
+    let kind = ResourceKind::from_extension(filename.extension()?.to_str()?)?;
+    let stem = filename.file_stem()?.to_str()?.to_lowercase();
+    Some((stem, kind))
+}
+
+fn is_number(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(|c| c.is_ascii_digit())
+}
+
+/// Returns the room of an IMG, NOD, ZZZ or RGD file, such as 2000 for `img/2000.img`
+pub fn parse_room_id(filename: impl AsRef<Path>) -> Option<u32> {
+    let (stem, kind) = split(filename.as_ref())?;
+    if !matches!(kind, ResourceKind::Img | ResourceKind::Nod | ResourceKind::Zzz | ResourceKind::Rgd) || !is_number(&stem) {
+        return None;
+    }
+    stem.parse().ok()
+}
+
+/// Returns the file name of the model that animation `anm` belongs to, such as `063.mdl` for
+/// `anm/06300.ANM`
+pub fn model_for_anim(anm: impl AsRef<Path>) -> Option<String> {
+    match split(anm.as_ref())? {
+        (stem, ResourceKind::Anm) if stem.len() > 3 && is_number(&stem) => {
+            Some(format!("{}.{}", &stem[..3], ResourceKind::Mdl.extension()))
+        },
+        _ => None,
+    }
+}
+
+/// Whether animation `anm` belongs to model `mdl`; only the file names are compared
+pub fn is_anim_of(mdl: impl AsRef<Path>, anm: impl AsRef<Path>) -> bool {
+    match (split(mdl.as_ref()), model_for_anim(anm)) {
+        (Some((stem, ResourceKind::Mdl)), Some(model)) => model == format!("{}.{}", stem, ResourceKind::Mdl.extension()),
+        _ => false,
+    }
+}
+
+/// Returns the animations of model `mdl` among `candidates`, ordered by name
+pub fn anims_for_model<P: AsRef<Path>>(mdl: impl AsRef<Path>, candidates: impl IntoIterator<Item = P>) -> Vec<P> {
+    let mut anims: Vec<P> = candidates.into_iter().filter(|anm| is_anim_of(&mdl, anm)).collect();
+    anims.sort_by_key(|anm| anm.as_ref().file_name().map(|name| name.to_ascii_lowercase()));
+    anims
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_room_files() {
+        assert_eq!(room_files(2000), RoomFileSet{
+            img: PathBuf::from("img/2000.img"),
+            nod: PathBuf::from("nod/2000.nod"),
+            zzz: PathBuf::from("zzz/2000.zzz"),
+            rgd: PathBuf::from("rgd/2000.rgd"),
+        });
+        let files = room_files(2000);
+        for path in [ &files.img, &files.nod, &files.zzz, &files.rgd ] {
+            assert_eq!(parse_room_id(path), Some(2000));
+        }
+    }
+
+    #[test]
+    fn test_parse_room_id() {
+        assert_eq!(parse_room_id("2000.img"), Some(2000));
+        assert_eq!(parse_room_id("IMG/2000.IMG"), Some(2000));
+        assert_eq!(parse_room_id("data/rgd/110.rgd"), Some(110));
+        assert_eq!(parse_room_id("2000.gra"), None);
+        assert_eq!(parse_room_id("2000a.img"), None);
+        assert_eq!(parse_room_id("2000"), None);
+        assert_eq!(parse_room_id(".img"), None);
+    }
+
+    #[test]
+    fn test_anims() {
+        assert_eq!(model_for_anim("anm/06300.anm").as_deref(), Some("063.mdl"));
+        assert_eq!(model_for_anim("06312.ANM").as_deref(), Some("063.mdl"));
+        assert_eq!(model_for_anim("063.anm"), None);
+        assert_eq!(model_for_anim("06300.mdl"), None);
+        assert!(is_anim_of("data/mdl/063.mdl", "data/anm/06300.anm"));
+        assert!(is_anim_of("MDL/063.MDL", "anm/06301.anm"));
+        assert!(!is_anim_of("063.mdl", "06400.anm"));
+        assert!(!is_anim_of("063.anm", "06300.anm"));
+        let candidates = [ "06401.anm", "06301.ANM", "063.mdl", "06300.anm", "notes.txt" ];
+        assert_eq!(anims_for_model("063.mdl", candidates), vec![ "06300.anm", "06301.ANM" ]);
+    }
+}
@@ -4,68 +4,256 @@
  * Copyright (c) 2024 Rink Springer <rink@rink.nu>
  * For conditions of distribution and use, see LICENSE file
  */
-use anyhow::{anyhow, Result};
-use byteorder::LittleEndian;
-use byteorder::ReadBytesExt;
-use std::io::{Cursor, Read, Seek};
+use cgmath::{InnerSpace, Matrix, Matrix3, Matrix4, Quaternion, SquareMatrix, Vector3, VectorSpace};
+use std::time::Duration;
+use crate::qfg5resource::decode::Reader;
+use crate::qfg5resource::error::{Error, ErrorContext, Result};
+use crate::qfg5resource::readutil::{read_array_f32, read_name};
 
+/// Size of an AnmBlock in the file, including two fields that are always 1 and 0
+const ANM_BLOCK_SIZE: usize = 56;
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct AnmBlock {
     pub translation: [ f32; 3 ],
     pub rotation: [ f32; 9 ],
 }
 
+impl AnmBlock {
+    /// The rotation is stored row-major
+    pub fn rotation_matrix(&self) -> Matrix3<f32> {
+        let r = &self.rotation;
+        Matrix3::new(r[0], r[3], r[6], r[1], r[4], r[7], r[2], r[5], r[8])
+    }
+
+    /// Assumes the rotation matrix is orthonormal, i.e. there is no scaling
+    pub fn rotation_quaternion(&self) -> Quaternion<f32> {
+        Quaternion::from(self.rotation_matrix()).normalize()
+    }
+
+    /// Whether the rotation matrix is orthonormal, allowing for the precision of the stored values
+    pub fn is_orthonormal(&self) -> bool {
+        let m = self.rotation_matrix();
+        let product: [[f32; 3]; 3] = (m * m.transpose()).into();
+        let identity: [[f32; 3]; 3] = Matrix3::identity().into();
+        product.iter().flatten().zip(identity.iter().flatten()).all(|(a, b)| (a - b).abs() < 1e-3)
+    }
+}
+
+/// Position of an anim at a point in time, possibly in between frames
+pub struct AnmSample {
+    pub translation: Vector3<f32>,
+    pub rotation: Quaternion<f32>,
+}
+
+impl AnmSample {
+    fn from_block(block: &AnmBlock) -> Self {
+        AnmSample{ translation: block.translation.into(), rotation: block.rotation_quaternion() }
+    }
+
+    pub fn transform(&self) -> Matrix4<f32> {
+        Matrix4::from_translation(self.translation) * Matrix4::from(self.rotation)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct AnmAnim {
     pub blocks: Vec<AnmBlock>,
 }
 
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct AnmDecoder {
+    /// Up to the first zero byte of the name
     pub name: String,
+    /// The name as stored, including what follows its zero byte
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub raw_name: [ u8; 16 ],
     pub delay: u32,
     pub anims: Vec<AnmAnim>,
 }
 
+fn read_block(cursor: &mut Reader) -> Result<AnmBlock> {
+    let at = cursor.position();
+    let a = cursor.u32()?;
+    let b = cursor.u32()?;
+    if a != 1 || b != 0 { return Err(Error::Unsupported{ what: format!("a/b values {}/{}", a, b), at }); }
+    let translation = read_array_f32(cursor)?;
+    let rotation = read_array_f32(cursor)?;
+    Ok(AnmBlock{ translation, rotation })
+}
+
 impl AnmDecoder {
+    /// Number of frames; every anim has the same number of blocks
+    pub fn num_frames(&self) -> usize {
+        self.anims.first().map_or(0, |anim| anim.blocks.len())
+    }
+
+    /// Time every frame is shown; the delay is assumed to be in milliseconds
+    pub fn frame_duration(&self) -> Duration {
+        Duration::from_millis(self.delay as u64)
+    }
+
+    /// Samples anim `anim` at `time` seconds, looping back to the first frame after the last.
+    /// In between frames, the translation is interpolated linearly and the rotation spherically.
+    pub fn sample(&self, anim: usize, time: f32) -> AnmSample {
+        let blocks = &self.anims[anim].blocks;
+        let frame_time = self.frame_duration().as_secs_f32();
+        if blocks.len() < 2 || frame_time <= 0.0 {
+            return AnmSample::from_block(&blocks[0]);
+        }
+        let position = (time / frame_time).rem_euclid(blocks.len() as f32);
+        let frame = (position.floor() as usize).min(blocks.len() - 1);
+        let amount = position - frame as f32;
+        let a = AnmSample::from_block(&blocks[frame]);
+        let b = AnmSample::from_block(&blocks[(frame + 1) % blocks.len()]);
+        // q and -q are the same rotation; take the shortest path
+        let b_rotation = if a.rotation.dot(b.rotation) < 0.0 { -b.rotation } else { b.rotation };
+        AnmSample{
+            translation: a.translation.lerp(b.translation, amount),
+            rotation: a.rotation.slerp(b_rotation, amount),
+        }
+    }
+
+    /// Problems that do not stop decoding: rotations that are not orthonormal, which sample()
+    /// assumes they are
+    pub fn validate(&self) -> Vec<String> {
+        let mut rotated = self.anims.iter().enumerate()
+            .flat_map(|(anim, a)| a.blocks.iter().enumerate().map(move |(block, b)| (anim, block, b)))
+            .filter(|(_, _, b)| !b.is_orthonormal());
+        let Some((anim, block, _)) = rotated.next() else { return Vec::new() };
+        let total = self.anims.iter().map(|a| a.blocks.len()).sum::<usize>();
+        vec![ format!("{} of {} rotations are not orthonormal, the first in anim {} block {}", rotated.count() + 1, total, anim, block) ]
+    }
+
     pub fn new(anm_data: &[u8]) -> Result<Self> {
-        let mut cursor = Cursor::new(&anm_data);
-        let magic = cursor.read_u32::<LittleEndian>()?;
-        if magic != 0x564f5838 && magic != 0x5452494d { return Err(anyhow!("invalid anm magic")); }
-        let header_size = cursor.read_u32::<LittleEndian>()?;
-        if header_size != 36 { return Err(anyhow!("invalid header size")); }
-        let mut name = vec![ 0u8; 16 ];
-        cursor.read_exact(&mut name)?;
-        let name = String::from_utf8(name)?;
-
-        let num_anims = cursor.read_u32::<LittleEndian>()? as usize;
-        let num_anim_blocks = cursor.read_u32::<LittleEndian>()? as usize;
-        let delay = cursor.read_u32::<LittleEndian>()?;
+        Self::read(&mut Reader::new(anm_data))
+    }
+
+    pub(super) fn read(cursor: &mut Reader) -> Result<Self> {
+        let magic = cursor.u32()?;
+        if magic != 0x564f5838 && magic != 0x5452494d { return Err(Error::BadMagic{ expected: "0x564f5838 or 0x5452494d", found: magic, at: 0 }); }
+        cursor.label(0, || format!("magic {:#x}", magic));
+        let header_size = cursor.u32()?;
+        if header_size != 36 { return Err(Error::Unsupported{ what: format!("header size {}", header_size), at: 4 }); }
+        cursor.label(4, || format!("header size {}", header_size));
+        let (name, raw_name) = read_name(cursor)?;
+        cursor.label(8, || format!("name '{}'", name));
+
+        let num_anims = cursor.u32()? as usize;
+        let num_anim_blocks = cursor.u32()? as usize;
+        let delay = cursor.u32()?;
+        cursor.label_range(24, 4, || format!("number of anims {}", num_anims));
+        cursor.label_range(28, 4, || format!("blocks per anim {}", num_anim_blocks));
+        cursor.label_range(32, 4, || format!("delay {}", delay));
+        if num_anims > 0 && num_anim_blocks == 0 {
+            return Err(Error::Invalid{ what: format!("{} anims without blocks", num_anims), at: 24 });
+        }
+        cursor.check_remaining(num_anims.saturating_mul(num_anim_blocks), ANM_BLOCK_SIZE)?;
 
         let mut anims = Vec::with_capacity(num_anims);
-        for _ in 0..num_anims {
+        for anim in 0..num_anims {
             let mut blocks = Vec::with_capacity(num_anim_blocks);
-            for _ in 0..num_anim_blocks {
-                let a = cursor.read_u32::<LittleEndian>()?;
-                let b = cursor.read_u32::<LittleEndian>()?;
-                if a != 1 || b != 0 { return Err(anyhow!("unexpected a/b values {}/{}", a, b)); }
-                let mut translation = [ 0f32; 3 ];
-                for n in 0..3 {
-                    translation[n] = cursor.read_f32::<LittleEndian>()?;
-                }
-                let mut rotation = [ 0f32; 9 ];
-                for n in 0..9 {
-                    rotation[n] = cursor.read_f32::<LittleEndian>()?;
-                }
-                blocks.push(AnmBlock{ translation, rotation });
+            for block in 0..num_anim_blocks {
+                let at = cursor.position();
+                let b = read_block(cursor).with_context(at, || format!("anim {} block {}", anim, block))?;
+                cursor.label(at, || format!("anim {} block {}: translation {:?}, rotation {:?}", anim, block, b.translation, b.rotation));
+                blocks.push(b);
             }
             anims.push(AnmAnim{ blocks });
         }
-        if cursor.stream_position()? != anm_data.len() as u64 {
-            return Err(anyhow!("got extra data after decoding"));
+        if cursor.position() != cursor.size() as u64 {
+            return Err(Error::Invalid{ what: "extra data after the last block".to_string(), at: cursor.position() });
         }
         Ok(AnmDecoder{
             name,
+            raw_name,
             delay,
             anims,
         })
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qfg5resource::fixtures;
+    use cgmath::{Deg, Rotation3};
+
+    fn block(translation: [f32; 3], angle: f32) -> AnmBlock {
+        let m = Matrix3::from_angle_z(Deg(angle));
+        // Stored row-major
+        let rotation = [ m.x.x, m.y.x, m.z.x, m.x.y, m.y.y, m.z.y, m.x.z, m.y.z, m.z.z ];
+        AnmBlock{ translation, rotation }
+    }
+
+    fn assert_close(a: Matrix4<f32>, b: Matrix4<f32>) {
+        let a: [[f32; 4]; 4] = a.into();
+        let b: [[f32; 4]; 4] = b.into();
+        for (ca, cb) in a.iter().zip(b.iter()) {
+            for (va, vb) in ca.iter().zip(cb.iter()) {
+                assert!((va - vb).abs() < 1e-5, "{:?} != {:?}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn test_rotation_quaternion() {
+        let b = block([ 0.0; 3 ], 30.0);
+        let expected = Quaternion::from_angle_z(Deg(30.0));
+        assert!((b.rotation_quaternion().dot(expected).abs() - 1.0).abs() < 1e-5);
+        assert_close(Matrix4::from(b.rotation_quaternion()), Matrix4::from(b.rotation_matrix()));
+        assert_close(Matrix4::from(block([ 0.0; 3 ], 0.0).rotation_matrix()), Matrix4::identity());
+    }
+
+    #[test]
+    fn test_sample() {
+        let anm = AnmDecoder{
+            name: String::new(),
+            raw_name: [ 0; 16 ],
+            delay: 100,
+            anims: vec![ AnmAnim{ blocks: vec![ block([ 0.0, 0.0, 0.0 ], 0.0), block([ 2.0, 0.0, 0.0 ], 90.0) ] } ],
+        };
+        // Exact frames
+        assert_close(anm.sample(0, 0.0).transform(), Matrix4::identity());
+        let frame1 = Matrix4::from_translation(Vector3::new(2.0, 0.0, 0.0)) * Matrix4::from_angle_z(Deg(90.0));
+        assert_close(anm.sample(0, 0.1).transform(), frame1);
+        // Halfway between the frames, and halfway while looping back to the first frame
+        let halfway = Matrix4::from_translation(Vector3::new(1.0, 0.0, 0.0)) * Matrix4::from_angle_z(Deg(45.0));
+        assert_close(anm.sample(0, 0.05).transform(), halfway);
+        assert_close(anm.sample(0, 0.15).transform(), halfway);
+        assert_close(anm.sample(0, 0.25).transform(), halfway);
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut anm = AnmDecoder::new(&fixtures::anm()).unwrap();
+        assert!(anm.validate().is_empty());
+        anm.anims[0].blocks[1].rotation[0] = 2.0;
+        assert_eq!(anm.validate(), [ "1 of 2 rotations are not orthonormal, the first in anim 0 block 1" ]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut anm = fixtures::anm();
+        anm[0] = 0;
+        assert!(matches!(AnmDecoder::new(&anm), Err(Error::BadMagic{ at: 0, .. })));
+        let anm = fixtures::anm();
+        assert!(matches!(AnmDecoder::new(&anm[..anm.len() - 1]), Err(Error::Truncated{ needed: 112, got: 111, at: 36 })));
+        let mut anm = fixtures::anm();
+        anm.push(0);
+        assert!(matches!(AnmDecoder::new(&anm), Err(Error::Invalid{ at: 148, .. })));
+        let mut anm = fixtures::anm();
+        anm[92] = 2;
+        let err = AnmDecoder::new(&anm).unwrap_err();
+        assert_eq!(err.to_string(), "anim 0 block 1 at offset 0x5c: unsupported a/b values 2/0 at offset 0x5c");
+
+        // Huge counts fail before anything is allocated for them
+        let mut anm = fixtures::anm();
+        anm[24..32].copy_from_slice(&[ 0xff; 8 ]);
+        assert!(matches!(AnmDecoder::new(&anm[..36]), Err(Error::Truncated{ needed: usize::MAX, got: 0, at: 36 })));
+    }
+}
@@ -0,0 +1,280 @@
+/*-
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * Copyright (c) 2024 Rink Springer <rink@rink.nu>
+ * For conditions of distribution and use, see LICENSE file
+ */
+//! Speech (*.AUD) and sound effects (*.WAV) are RIFF WAVE files holding either PCM samples or
+//! IMA ADPCM, as written by the Microsoft ACM codec. Both are decoded to 16-bit PCM.
+use std::io::Write;
+use std::time::Duration;
+use crate::qfg5resource::decode::Reader;
+use crate::qfg5resource::error::{Error, ErrorContext, Result};
+
+const RIFF_MAGIC: u32 = 0x46464952; // 'RIFF'
+const WAVE_MAGIC: u32 = 0x45564157; // 'WAVE'
+const FMT_CHUNK: u32 = 0x20746d66; // 'fmt '
+const DATA_CHUNK: u32 = 0x61746164; // 'data'
+
+const FORMAT_PCM: u16 = 1;
+const FORMAT_IMA_ADPCM: u16 = 0x11;
+
+const IMA_INDEX_TABLE: [i32; 16] = [ -1, -1, -1, -1, 2, 4, 6, 8, -1, -1, -1, -1, 2, 4, 6, 8 ];
+const IMA_STEP_TABLE: [i32; 89] = [
+    7, 8, 9, 10, 11, 12, 13, 14, 16, 17, 19, 21, 23, 25, 28, 31, 34, 37, 41, 45, 50, 55, 60, 66, 73, 80, 88, 97, 107, 118,
+    130, 143, 157, 173, 190, 209, 230, 253, 279, 307, 337, 371, 408, 449, 494, 544, 598, 658, 724, 796, 876, 963, 1060,
+    1166, 1282, 1411, 1552, 1707, 1878, 2066, 2272, 2499, 2749, 3024, 3327, 3660, 4026, 4428, 4871, 5358, 5894, 6484, 7132,
+    7845, 8630, 9493, 10442, 11487, 12635, 13899, 15289, 16818, 18500, 20350, 22385, 24623, 27086, 29794, 32767,
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum AudCodec {
+    Pcm,
+    ImaAdpcm,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AudDecoder {
+    pub codec: AudCodec,
+    pub sample_rate: u32,
+    pub channels: u16,
+    /// Bits per stored sample: 8 or 16 for PCM, 4 for IMA ADPCM
+    pub bits_per_sample: u16,
+    /// Decoded samples, with the channels interleaved
+    pub samples: Vec<i16>,
+}
+
+/// Decoder state of an IMA ADPCM channel
+struct ImaChannel {
+    predictor: i32,
+    index: usize,
+}
+
+impl ImaChannel {
+    fn decode(&mut self, nibble: u8) -> i16 {
+        let step = IMA_STEP_TABLE[self.index];
+        let mut diff = step >> 3;
+        if nibble & 4 != 0 { diff += step; }
+        if nibble & 2 != 0 { diff += step >> 1; }
+        if nibble & 1 != 0 { diff += step >> 2; }
+        self.predictor = if nibble & 8 != 0 { self.predictor - diff } else { self.predictor + diff }.clamp(i16::MIN as i32, i16::MAX as i32);
+        self.index = (self.index as i32 + IMA_INDEX_TABLE[nibble as usize]).clamp(0, IMA_STEP_TABLE.len() as i32 - 1) as usize;
+        self.predictor as i16
+    }
+}
+
+/// Decodes a block of IMA ADPCM, which starts with the initial predictor and step index of every
+/// channel, followed by groups of 4 bytes (8 samples) per channel, low nibble first. The last
+/// block of a file may be shorter than the others.
+fn decode_ima_block(cursor: &mut Reader, block_size: usize, num_channels: usize, samples: &mut Vec<i16>) -> Result<()> {
+    let end = cursor.position() + block_size as u64;
+    let mut channels = Vec::with_capacity(num_channels);
+    for _ in 0..num_channels {
+        let predictor = cursor.u16()? as i16;
+        let at = cursor.position();
+        let index = cursor.u8()? as usize;
+        let _reserved = cursor.u8()?;
+        if index >= IMA_STEP_TABLE.len() {
+            return Err(Error::Invalid{ what: format!("step index {}", index), at });
+        }
+        samples.push(predictor);
+        channels.push(ImaChannel{ predictor: predictor as i32, index });
+    }
+    let mut group = [ 0i16; 8 ];
+    while cursor.position() + 4 * num_channels as u64 <= end {
+        let first = samples.len();
+        samples.resize(first + 8 * num_channels, 0);
+        for (c, channel) in channels.iter_mut().enumerate() {
+            for (n, byte) in cursor.bytes(4)?.iter().enumerate() {
+                group[2 * n] = channel.decode(byte & 0xf);
+                group[2 * n + 1] = channel.decode(byte >> 4);
+            }
+            for (n, sample) in group.iter().enumerate() {
+                samples[first + n * num_channels + c] = *sample;
+            }
+        }
+    }
+    cursor.seek(end);
+    Ok(())
+}
+
+impl AudDecoder {
+    pub fn new(aud_data: &[u8]) -> Result<Self> {
+        let mut cursor = Reader::new(aud_data);
+        for expected in [ ("RIFF", RIFF_MAGIC), ("WAVE", WAVE_MAGIC) ] {
+            let at = cursor.position();
+            let found = cursor.u32()?;
+            if found != expected.1 {
+                return Err(Error::BadMagic{ expected: expected.0, found, at });
+            }
+            if expected.1 == RIFF_MAGIC {
+                let _riff_size = cursor.u32()?;
+            }
+        }
+
+        let mut format = None;
+        loop {
+            let at = cursor.position();
+            let id = cursor.u32()?;
+            let size = cursor.u32()? as u64;
+            let start = cursor.position();
+            match id {
+                FMT_CHUNK => format = Some(WaveFormat::read(&mut cursor).with_context(at, || "format chunk".to_string())?),
+                DATA_CHUNK => break,
+                _ => { },
+            }
+            // Chunks are padded to an even size
+            cursor.seek(start + size + (size & 1));
+        }
+        let data_at = cursor.position() - 8;
+        let Some(format) = format else {
+            return Err(Error::Invalid{ what: "data chunk before format chunk".to_string(), at: data_at });
+        };
+        cursor.seek(data_at + 4);
+        // The data chunk is sometimes cut short; use what is there
+        let size = (cursor.u32()? as usize).min(cursor.rest().len());
+        let end = cursor.position() + size as u64;
+
+        let samples = match format.codec {
+            AudCodec::Pcm if format.bits_per_sample == 8 => cursor.bytes(size)?.iter().map(|&v| ((v as i16) - 128) << 8).collect(),
+            AudCodec::Pcm => cursor.bytes(size)?.chunks_exact(2).map(|v| i16::from_le_bytes([ v[0], v[1] ])).collect(),
+            AudCodec::ImaAdpcm => {
+                let mut samples = Vec::new();
+                let mut block = 0;
+                while cursor.position() < end {
+                    let at = cursor.position();
+                    let block_size = format.block_align.min((end - at) as usize);
+                    decode_ima_block(&mut cursor, block_size, format.channels as usize, &mut samples)
+                        .with_context(at, || format!("block {}", block))?;
+                    block += 1;
+                }
+                samples
+            },
+        };
+        Ok(AudDecoder{ codec: format.codec, sample_rate: format.sample_rate, channels: format.channels, bits_per_sample: format.bits_per_sample, samples })
+    }
+
+    /// Number of samples per channel
+    pub fn num_frames(&self) -> usize {
+        self.samples.len() / self.channels as usize
+    }
+
+    pub fn duration(&self) -> Duration {
+        Duration::from_secs_f64(self.num_frames() as f64 / self.sample_rate as f64)
+    }
+
+    /// Writes the decoded samples as a 16-bit PCM RIFF WAVE file
+    pub fn write_wav(&self, w: &mut dyn Write) -> std::io::Result<()> {
+        let data_size = (self.samples.len() * 2) as u32;
+        let block_align = self.channels.saturating_mul(2);
+        w.write_all(b"RIFF")?;
+        w.write_all(&data_size.saturating_add(36).to_le_bytes())?;
+        w.write_all(b"WAVEfmt ")?;
+        w.write_all(&16u32.to_le_bytes())?;
+        w.write_all(&FORMAT_PCM.to_le_bytes())?;
+        w.write_all(&self.channels.to_le_bytes())?;
+        w.write_all(&self.sample_rate.to_le_bytes())?;
+        w.write_all(&self.sample_rate.saturating_mul(block_align as u32).to_le_bytes())?;
+        w.write_all(&block_align.to_le_bytes())?;
+        w.write_all(&16u16.to_le_bytes())?;
+        w.write_all(b"data")?;
+        w.write_all(&data_size.to_le_bytes())?;
+        for sample in &self.samples {
+            w.write_all(&sample.to_le_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+/// Contents of the format chunk
+struct WaveFormat {
+    codec: AudCodec,
+    channels: u16,
+    sample_rate: u32,
+    block_align: usize,
+    bits_per_sample: u16,
+}
+
+impl WaveFormat {
+    fn read(cursor: &mut Reader) -> Result<Self> {
+        let at = cursor.position();
+        let format_tag = cursor.u16()?;
+        let channels = cursor.u16()?;
+        let sample_rate = cursor.u32()?;
+        let _byte_rate = cursor.u32()?;
+        let block_align = cursor.u16()? as usize;
+        let bits_per_sample = cursor.u16()?;
+        let codec = match (format_tag, bits_per_sample) {
+            (FORMAT_PCM, 8 | 16) => AudCodec::Pcm,
+            (FORMAT_IMA_ADPCM, 4) => AudCodec::ImaAdpcm,
+            (FORMAT_PCM | FORMAT_IMA_ADPCM, bits) => {
+                return Err(Error::Unsupported{ what: format!("codec {:#x} with {} bits per sample", format_tag, bits), at: at + 14 });
+            },
+            (tag, _) => return Err(Error::Unsupported{ what: format!("codec {:#x}", tag), at }),
+        };
+        if channels == 0 {
+            return Err(Error::Invalid{ what: "channel count 0".to_string(), at: at + 2 });
+        }
+        if sample_rate == 0 {
+            return Err(Error::Invalid{ what: "sample rate 0".to_string(), at: at + 4 });
+        }
+        // A block must at least hold the header of every channel
+        if codec == AudCodec::ImaAdpcm && block_align < 4 * channels as usize {
+            return Err(Error::Invalid{ what: format!("block size {}", block_align), at: at + 12 });
+        }
+        Ok(WaveFormat{ codec, channels, sample_rate, block_align, bits_per_sample })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qfg5resource::fixtures;
+
+    #[test]
+    fn test_decode_ima_adpcm() {
+        let aud = AudDecoder::new(&fixtures::aud()).unwrap();
+        assert_eq!((aud.codec, aud.sample_rate, aud.channels, aud.bits_per_sample), (AudCodec::ImaAdpcm, 22050, 1, 4));
+        // The sample of the header, then nibble 7 on step 7, 1 on step 16 and 0 on step 14
+        assert_eq!(&aud.samples[..4], [ 100, 111, 117, 118 ]);
+        assert_eq!(aud.num_frames(), 17);
+    }
+
+    #[test]
+    fn test_decode_pcm() {
+        let aud = AudDecoder::new(&fixtures::wav(1, 8, &[ 0x80, 0xff, 0x00 ])).unwrap();
+        assert_eq!(aud.samples, [ 0, 127 << 8, -128 << 8 ]);
+        let aud = AudDecoder::new(&fixtures::wav(2, 16, &[ 1, 0, 0xff, 0xff ])).unwrap();
+        assert_eq!((aud.samples.as_slice(), aud.num_frames()), ([ 1, -1 ].as_slice(), 1));
+        assert_eq!(aud.duration(), Duration::from_secs_f64(1.0 / 22050.0));
+    }
+
+    #[test]
+    fn test_unknown_codec() {
+        let mut data = fixtures::aud();
+        data[20..22].copy_from_slice(&0x55u16.to_le_bytes());
+        assert_eq!(AudDecoder::new(&data).unwrap_err().to_string(),
+            "format chunk at offset 0xc: unsupported codec 0x55 at offset 0x14");
+    }
+
+    #[test]
+    fn test_bad_step_index() {
+        let mut data = fixtures::aud();
+        data[fixtures::AUD_DATA_OFFSET + 2] = 89;
+        assert_eq!(AudDecoder::new(&data).unwrap_err().to_string(),
+            format!("block 0 at offset {:#x}: invalid step index 89 at offset {:#x}", fixtures::AUD_DATA_OFFSET, fixtures::AUD_DATA_OFFSET + 2));
+    }
+
+    #[test]
+    fn test_write_wav() {
+        let aud = AudDecoder::new(&fixtures::aud()).unwrap();
+        let mut out = Vec::new();
+        aud.write_wav(&mut out).unwrap();
+        assert_eq!(out.len(), 44 + 2 * aud.samples.len());
+        let pcm = AudDecoder::new(&out).unwrap();
+        assert_eq!((pcm.codec, pcm.bits_per_sample), (AudCodec::Pcm, 16));
+        assert_eq!((pcm.sample_rate, pcm.channels, pcm.samples), (aud.sample_rate, aud.channels, aud.samples));
+    }
+}
@@ -4,27 +4,37 @@
  * Copyright (c) 2024 Rink Springer <rink@rink.nu>
  * For conditions of distribution and use, see LICENSE file
  */
-use anyhow::Result;
-use std::io::{Cursor, Read, Seek, SeekFrom};
-use byteorder::{ByteOrder, ReadBytesExt, LittleEndian};
-use crate::qfg5resource::decode;
+use byteorder::{ByteOrder, LittleEndian};
+use std::borrow::Cow;
+use crate::qfg5resource::decode::{self, Reader};
+use crate::qfg5resource::error::{Error, ErrorContext, Result};
+use crate::qfg5resource::readutil::read_offset_table;
 
-pub struct GraSprite {
-    pub pixels: Vec<u8>,
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct GraSprite<'a> {
+    /// Borrowed from the GRA data for uncompressed sprites decoded by GraDecoder::new_borrowed()
+    #[cfg_attr(feature = "serde", serde(with = "crate::qfg5resource::serialize::base64"))]
+    pub pixels: Cow<'a, [u8]>,
 }
 
-pub struct GraSpriteCollection {
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct GraSpriteCollection<'a> {
     pub x_position: u32,
     pub y_position: u32,
     pub width: u32,
     pub height: u32,
     pub frame_delay: u32,
-    pub sprites: Vec<GraSprite>,
+    pub sprites: Vec<GraSprite<'a>>,
 }
 
-pub struct GraDecoder {
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct GraDecoder<'a> {
+    #[cfg_attr(feature = "serde", serde(with = "crate::qfg5resource::serialize::palette"))]
     pub palette: [ (u8, u8, u8); 256 ],
-    pub sprite_collections: Vec<GraSpriteCollection>,
+    pub sprite_collections: Vec<GraSpriteCollection<'a>>,
 }
 
 fn decode_rgb555_palette(rgb555: &[u8]) -> [ (u8, u8, u8); 256 ] {
@@ -42,65 +52,128 @@ fn decode_rgb555_palette(rgb555: &[u8]) -> [ (u8, u8, u8); 256 ] {
     result
 }
 
-impl GraDecoder {
+fn read_sprite_pixels<'a>(cursor: &mut Reader<'a>, colour_mode: u32, num_pixels: usize, pixel_budget: &mut decode::Budget) -> Result<Cow<'a, [u8]>> {
+    pixel_budget.take(num_pixels, cursor.position())?;
+    if colour_mode == 0 {
+        Ok(Cow::Borrowed(cursor.bytes(num_pixels)?))
+    } else {
+        let mut pixels = vec![ 0u8; num_pixels ];
+        decode::decode_rle(decode::RleMode::Img, cursor.rest(), &mut pixels, cursor.position())?;
+        Ok(Cow::Owned(pixels))
+    }
+}
+
+/// Reads the sprite collection at the position of `cursor`
+fn read_sprite_collection<'a>(cursor: &mut Reader<'a>, colour_mode: u32, pixel_budget: &mut decode::Budget) -> Result<GraSpriteCollection<'a>> {
+    let offset = cursor.position();
+    let x_position = cursor.u32()?;
+    let y_position = cursor.u32()?;
+    let width = cursor.u32()?;
+    let height = cursor.u32()?;
+    let num_sprites = cursor.u32()? as usize;
+    let frame_delay = cursor.u32()?;
+    let _flags = cursor.u32()?;
+    let num_pixels = width as usize * height as usize;
+    if num_pixels > decode::max_rle_pixels(cursor.size()) {
+        return Err(Error::Invalid{ what: format!("sprite size {}x{}", width, height), at: offset + 8 });
+    }
+
+    let frame_offsets = read_offset_table(cursor, num_sprites, offset)?;
+
+    let mut sprites = Vec::new();
+    for (n, frame_offset) in frame_offsets.into_iter().enumerate() {
+        cursor.seek(frame_offset);
+        let pixels = read_sprite_pixels(cursor, colour_mode, num_pixels, pixel_budget)
+            .with_context(frame_offset, || format!("sprite {}", n))?;
+
+        sprites.push(GraSprite{ pixels });
+    }
+
+    Ok(GraSpriteCollection{
+        x_position, y_position,
+        width, height,
+        frame_delay,
+        sprites
+    })
+}
+
+impl GraDecoder<'static> {
     pub fn new(gra_data: &[u8]) -> Result<Self> {
-        let mut cursor = Cursor::new(gra_data);
+        Ok(GraDecoder::new_borrowed(gra_data)?.into_owned())
+    }
+}
 
-        let colour_mode = cursor.read_u32::<LittleEndian>()?;
-        let num_collections = cursor.read_u32::<LittleEndian>()? as usize;
-        let mut rgb555 = [ 0u8; 512 ];
-        cursor.read_exact(&mut rgb555)?;
-        let palette = decode_rgb555_palette(&rgb555);
+impl<'a> GraDecoder<'a> {
+    /// Like new(), but the pixels of uncompressed sprites refer to `gra_data` instead of being
+    /// copied; RLE-compressed sprites are always decoded into new buffers
+    pub fn new_borrowed(gra_data: &'a [u8]) -> Result<Self> {
+        let mut cursor = Reader::new(gra_data);
 
-        let mut sprite_collection_offsets = vec! [ 0u32; num_collections ];
-        for n in 0..num_collections {
-            sprite_collection_offsets[n] = cursor.read_u32::<LittleEndian>()?;
+        let colour_mode = cursor.u32()?;
+        let num_collections = cursor.u32()? as usize;
+        if colour_mode != 0 && colour_mode != 2 {
+            return Err(Error::Unsupported{ what: format!("colour mode {}", colour_mode), at: 0 });
         }
-        println!("colour_mode {} num_collections {}", colour_mode, num_collections);
+        let palette = decode_rgb555_palette(cursor.bytes(512)?);
+
+        let sprite_collection_offsets = read_offset_table(&mut cursor, num_collections, 0)?;
+        log::debug!("colour_mode {} num_collections {}", colour_mode, num_collections);
 
+        let mut pixel_budget = decode::Budget::new("sprite pixels", decode::max_rle_pixels(gra_data.len()));
         let mut sprite_collections = Vec::new();
-        for offset in &sprite_collection_offsets {
-            cursor.seek(SeekFrom::Start(*offset as u64))?;
-
-            let x_position = cursor.read_u32::<LittleEndian>()?;
-            let y_position = cursor.read_u32::<LittleEndian>()?;
-            let width = cursor.read_u32::<LittleEndian>()?;
-            let height = cursor.read_u32::<LittleEndian>()?;
-            let num_sprites = cursor.read_u32::<LittleEndian>()? as usize;
-            let frame_delay = cursor.read_u32::<LittleEndian>()?;
-            let _flags = cursor.read_u32::<LittleEndian>()?;
-
-            let mut frame_offsets = vec![ 0u32; num_sprites ];
-            for n in 0..num_sprites {
-                frame_offsets[n] = cursor.read_u32::<LittleEndian>()?;
-            }
-
-            let mut sprites = Vec::new();
-            for n in 0..num_sprites {
-                cursor.seek(SeekFrom::Start((*offset + frame_offsets[n]) as u64))?;
-
-                let data = &gra_data[cursor.stream_position()? as usize..];
-                let mut pixels = vec![ 0u8; (width * height) as usize ];
-                match colour_mode {
-                    0 => {
-                        pixels.copy_from_slice(&data[0..(height * width) as usize]);
-                    },
-                    2 => {
-                        decode::decode_rle(&data, &mut pixels);
-                    },
-                    _ => { todo!("colour mode {}", colour_mode); }
-                }
-
-                sprites.push(GraSprite{ pixels });
-            }
-
-            sprite_collections.push(GraSpriteCollection{
-                x_position, y_position,
-                width, height,
-                frame_delay,
-                sprites
-            });
+        for (n, offset) in sprite_collection_offsets.into_iter().enumerate() {
+            cursor.seek(offset);
+            let collection = read_sprite_collection(&mut cursor, colour_mode, &mut pixel_budget)
+                .with_context(offset, || format!("sprite collection {}", n))?;
+            sprite_collections.push(collection);
         }
         Ok(GraDecoder{ palette, sprite_collections })
     }
+
+    /// Copies all borrowed pixels, so that the GRA data can be dropped
+    pub fn into_owned(self) -> GraDecoder<'static> {
+        let sprite_collections = self.sprite_collections.into_iter().map(|c| GraSpriteCollection{
+            x_position: c.x_position, y_position: c.y_position,
+            width: c.width, height: c.height,
+            frame_delay: c.frame_delay,
+            sprites: c.sprites.into_iter().map(|s| GraSprite{ pixels: Cow::Owned(s.pixels.into_owned()) }).collect(),
+        }).collect();
+        GraDecoder{ palette: self.palette, sprite_collections }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qfg5resource::fixtures;
+
+    #[test]
+    fn test_borrowed() {
+        let data = fixtures::gra();
+        let gra = GraDecoder::new_borrowed(&data).unwrap();
+        let pixels = &gra.sprite_collections[0].sprites[1].pixels;
+        assert!(matches!(pixels, Cow::Borrowed(_)));
+        assert_eq!(pixels.as_ptr(), data[data.len() - 4..].as_ptr());
+
+        let owned = gra.clone().into_owned();
+        assert!(matches!(owned.sprite_collections[0].sprites[1].pixels, Cow::Owned(_)));
+        assert_eq!(owned, gra);
+    }
+
+    #[test]
+    fn test_errors() {
+        let gra = fixtures::gra();
+        let err = GraDecoder::new(&gra[..gra.len() - 1]).unwrap_err();
+        assert!(matches!(err.root(), Error::Truncated{ needed: 4, got: 3, at: 0x234 }));
+        assert_eq!(err.to_string(), "sprite collection 0 at offset 0x20c: sprite 1 at offset 0x234: truncated at offset 0x234: needed 4 bytes, got 3");
+
+        // A huge number of collections, or a huge sprite
+        let mut gra = fixtures::gra();
+        gra[4..8].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(GraDecoder::new(&gra[..0x20c]), Err(Error::Truncated{ at: 0x208, .. })));
+        let mut gra = fixtures::gra();
+        gra[0x214..0x21c].copy_from_slice(&[ 0xff; 8 ]);
+        let err = GraDecoder::new(&gra).unwrap_err();
+        assert!(matches!(err.root(), Error::Invalid{ at: 0x214, .. }), "{}", err);
+    }
 }
@@ -4,9 +4,8 @@
  * Copyright (c) 2024 Rink Springer <rink@rink.nu>
  * For conditions of distribution and use, see LICENSE file
  */
-use anyhow::Result;
-use byteorder::{ByteOrder, LittleEndian};
-use crate::qfg5resource::decode;
+use crate::qfg5resource::decode::{self, Reader};
+use crate::qfg5resource::error::{Error, Result};
 
 const IMG_DATA_OFFSET: usize = 64;
 
@@ -18,11 +17,22 @@ pub struct ImageDecoder {
 
 impl ImageDecoder {
     pub fn new(img_data: &[u8]) -> Result<Self> {
-        let width = LittleEndian::read_u16(&img_data[32..34]);
-        let height = LittleEndian::read_u16(&img_data[36..38]);
-        let mut pixels = vec![ 0u8; width as usize * height as usize ];
+        let mut cursor = Reader::new(img_data);
+        cursor.seek(32);
+        let width = cursor.u16()?;
+        cursor.seek(36);
+        let height = cursor.u16()?;
+        cursor.seek(IMG_DATA_OFFSET as u64);
+        if cursor.rest().is_empty() {
+            return Err(Error::Truncated{ needed: 1, got: 0, at: IMG_DATA_OFFSET as u64 });
+        }
+        let num_pixels = width as usize * height as usize;
+        if num_pixels > decode::max_rle_pixels(cursor.rest().len()) {
+            return Err(Error::Invalid{ what: format!("image size {}x{}", width, height), at: 32 });
+        }
+        let mut pixels = vec![ 0u8; num_pixels ];
 
-        decode::decode_rle(&img_data[IMG_DATA_OFFSET..], &mut pixels);
+        decode::decode_rle(decode::RleMode::Img, cursor.rest(), &mut pixels, IMG_DATA_OFFSET as u64)?;
         Ok(ImageDecoder{ height, width, pixels })
     }
 
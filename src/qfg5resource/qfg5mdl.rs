@@ -4,19 +4,38 @@
  * Copyright (c) 2024 Rink Springer <rink@rink.nu>
  * For conditions of distribution and use, see LICENSE file
  */
-use anyhow::{anyhow, Result};
-use byteorder::{ReadBytesExt, LittleEndian};
-use std::io::{Cursor, Read, Seek, SeekFrom};
+use byteorder::{ByteOrder, WriteBytesExt, LittleEndian};
 use log::{info, debug};
+use std::borrow::{Borrow, Cow};
+use std::collections::HashSet;
+use crate::qfg5resource::decode::{self, Reader};
+use crate::qfg5resource::error::{Error, ErrorContext, Result};
+use crate::qfg5resource::qfg5anm::{AnmBlock, AnmDecoder};
+use crate::qfg5resource::qfg5nod::PaletteEntry;
+use crate::qfg5resource::readutil::{decode_name, read_name, read_offset_table, read_vec_f32, SeekGuard};
 
-#[derive(Default, Clone)]
+const PALETTE_SIZE: usize = 1019;
+/// The palette is red, green, blue and a byte whose meaning is not known yet for every entry but
+/// the last, which lacks that byte: there is no room for entry 255
+pub const PALETTE_ENTRIES: usize = 255;
+/// Size of the name, the 20 unknown floats, the counts and the addresses that start a submesh
+const SUBMESH_HEADER_SIZE: u64 = 0x7c;
+/// Size of the fields in front of the pixels of a subbitmap
+const SUBBITMAP_HEADER_SIZE: usize = 24;
+
+#[derive(Default, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Qfg5Vertex {
     pub x: f32,
     pub y: f32,
     pub z: f32,
 }
 
-#[derive(Default, Clone)]
+/// Four values per vertex whose meaning is not known yet. They may be a normal in a, b and c
+/// with an intensity in d; lighting_stats() helps to find out, and lighting_normals() takes them
+/// as normals when they look like ones.
+#[derive(Default, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Qfg5LightingVertex {
     pub a: f32,
     pub b: f32,
@@ -24,13 +43,65 @@ pub struct Qfg5LightingVertex {
     pub d: f32,
 }
 
-#[derive(Default, Clone)]
+impl Qfg5LightingVertex {
+    /// Maps a, b, c and d to red, green, blue and alpha, scaled so that `min`..`max` becomes 0..1.
+    /// What the values encode is not known yet, so this only serves to visualize them.
+    pub fn to_rgba(&self, min: &Qfg5LightingVertex, max: &Qfg5LightingVertex) -> [f32; 4] {
+        let scale = |v: f32, min: f32, max: f32| if max > min { (v - min) / (max - min) } else { 0.5 };
+        [ scale(self.a, min.a, max.a), scale(self.b, min.b, max.b), scale(self.c, min.c, max.c), scale(self.d, min.d, max.d) ]
+    }
+
+    /// Whether a, b and c are a vector of length 1, as a normal would be
+    pub fn is_unit_abc(&self) -> bool {
+        ((self.a * self.a + self.b * self.b + self.c * self.c).sqrt() - 1.0).abs() <= UNIT_LENGTH_TOLERANCE
+    }
+}
+
+/// How far from 1 the length of a, b and c may be for is_unit_abc()
+const UNIT_LENGTH_TOLERANCE: f32 = 0.01;
+
+/// Statistics of lighting values per component, for finding out what they mean
+#[derive(Default, Clone, Debug, PartialEq)]
+pub struct LightingStats {
+    pub count: usize,
+    pub min: Qfg5LightingVertex,
+    pub max: Qfg5LightingVertex,
+    pub mean: Qfg5LightingVertex,
+    /// How many have a, b and c of length 1, see Qfg5LightingVertex::is_unit_abc()
+    pub unit_abc: usize,
+}
+
+impl LightingStats {
+    /// Statistics of `values`, or None if there are none
+    pub fn from_values<'a>(values: impl IntoIterator<Item = &'a Qfg5LightingVertex>) -> Option<Self> {
+        let mut values = values.into_iter();
+        let first = values.next()?;
+        let mut stats = LightingStats{ count: 0, min: first.clone(), max: first.clone(), mean: Qfg5LightingVertex::default(), unit_abc: 0 };
+        let mut sums = [ 0.0f64; 4 ];
+        for v in std::iter::once(first).chain(values) {
+            stats.count += 1;
+            stats.min = Qfg5LightingVertex{ a: stats.min.a.min(v.a), b: stats.min.b.min(v.b), c: stats.min.c.min(v.c), d: stats.min.d.min(v.d) };
+            stats.max = Qfg5LightingVertex{ a: stats.max.a.max(v.a), b: stats.max.b.max(v.b), c: stats.max.c.max(v.c), d: stats.max.d.max(v.d) };
+            for (sum, value) in sums.iter_mut().zip([ v.a, v.b, v.c, v.d ]) {
+                *sum += value as f64;
+            }
+            stats.unit_abc += v.is_unit_abc() as usize;
+        }
+        let mean = |sum: f64| (sum / stats.count as f64) as f32;
+        stats.mean = Qfg5LightingVertex{ a: mean(sums[0]), b: mean(sums[1]), c: mean(sums[2]), d: mean(sums[3]) };
+        Some(stats)
+    }
+}
+
+#[derive(Default, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Qfg5TexCoord {
     pub u: f32,
     pub v: f32,
 }
 
-#[derive(Default, Clone)]
+#[derive(Default, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Qfg5Face {
     pub vertex1: usize,
     pub vertex2: usize,
@@ -44,136 +115,1013 @@ pub struct Qfg5Face {
     pub normal_z: f32,
 }
 
-#[derive(Default, Clone)]
+#[derive(Default, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SubMesh {
     pub name: String,
+    /// The name as stored, see Qfg5Model::raw_name
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub raw_name: [ u8; 16 ],
     pub vertices: Vec<Qfg5Vertex>,
     pub texcoords: Vec<Qfg5TexCoord>,
     pub faces: Vec<Qfg5Face>,
     pub lighting_vertices: Vec<Qfg5LightingVertex>,
+    /// The 20 values after the name, as found in the file. They may hold a bounding box, a pivot
+    /// point and a radius, but none of that is confirmed yet.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub header_floats: [ f32; 20 ],
+}
+
+/// Axis-aligned bounding box, in model coordinates
+#[derive(Default, Clone, Debug, PartialEq)]
+pub struct Qfg5Bounds {
+    pub min: Qfg5Vertex,
+    pub max: Qfg5Vertex,
+}
+
+impl Qfg5Bounds {
+    /// Bounds of `vertices`, or None if there are none
+    pub fn from_vertices<V: Borrow<Qfg5Vertex>>(vertices: impl IntoIterator<Item = V>) -> Option<Self> {
+        let mut vertices = vertices.into_iter();
+        let first = vertices.next()?.borrow().clone();
+        let mut bounds = Qfg5Bounds{ min: first.clone(), max: first };
+        for v in vertices {
+            let v = v.borrow();
+            bounds.min = Qfg5Vertex{ x: bounds.min.x.min(v.x), y: bounds.min.y.min(v.y), z: bounds.min.z.min(v.z) };
+            bounds.max = Qfg5Vertex{ x: bounds.max.x.max(v.x), y: bounds.max.y.max(v.y), z: bounds.max.z.max(v.z) };
+        }
+        Some(bounds)
+    }
+
+    pub fn size(&self) -> Qfg5Vertex {
+        Qfg5Vertex{ x: self.max.x - self.min.x, y: self.max.y - self.min.y, z: self.max.z - self.min.z }
+    }
+
+    pub fn center(&self) -> Qfg5Vertex {
+        Qfg5Vertex{ x: (self.min.x + self.max.x) / 2.0, y: (self.min.y + self.max.y) / 2.0, z: (self.min.z + self.max.z) / 2.0 }
+    }
+
+    /// Radius of the sphere around center() that holds the box: half its diagonal
+    pub fn radius(&self) -> f32 {
+        let size = self.size();
+        (size.x * size.x + size.y * size.y + size.z * size.z).sqrt() / 2.0
+    }
+}
+
+/// `v` rotated and translated by an animation block; the rotation is stored row-major
+fn transform_vertex(block: &AnmBlock, v: &Qfg5Vertex) -> Qfg5Vertex {
+    let (r, t) = (&block.rotation, &block.translation);
+    Qfg5Vertex{
+        x: r[0] * v.x + r[1] * v.y + r[2] * v.z + t[0],
+        y: r[3] * v.x + r[4] * v.y + r[5] * v.z + t[1],
+        z: r[6] * v.x + r[7] * v.y + r[8] * v.z + t[2],
+    }
+}
+
+/// How many faces have a stored normal that points away from the normal implied by their
+/// winding order (counter-clockwise is front facing)
+#[derive(Default, Clone, Debug, PartialEq)]
+pub struct WindingStats {
+    pub faces: usize,
+    pub mismatched: usize,
+    /// Faces without area or without a stored normal, which cannot be judged
+    pub degenerate: usize,
+    /// Faces with a vertex index past the vertices, which cannot be judged either; only models
+    /// that were built or changed in memory have them
+    pub out_of_range: usize,
+}
+
+impl WindingStats {
+    /// How many faces could be judged
+    pub fn judged(&self) -> usize {
+        self.faces - self.degenerate - self.out_of_range
+    }
+
+    /// Fraction of the faces that could be judged whose winding disagrees with their normal
+    pub fn mismatch_fraction(&self) -> f32 {
+        let judged = self.judged();
+        if judged == 0 { 0.0 } else { self.mismatched as f32 / judged as f32 }
+    }
+
+    fn add(&mut self, other: &WindingStats) {
+        self.faces += other.faces;
+        self.mismatched += other.mismatched;
+        self.degenerate += other.degenerate;
+        self.out_of_range += other.out_of_range;
+    }
+}
+
+/// What may be wrong with the geometry of a submesh, as indices of the faces, vertices and
+/// texture coordinates concerned; see SubMesh::analyze()
+#[derive(Default, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SubMeshReport {
+    /// Faces that use a vertex twice or have no area
+    pub degenerate_faces: Vec<usize>,
+    pub unused_vertices: Vec<usize>,
+    pub unused_texcoords: Vec<usize>,
+    /// Texture coordinates with u or v outside 0 to 1, which only show right when the texture repeats
+    pub texcoords_out_of_range: Vec<usize>,
+    /// Faces that use a subbitmap that does not exist
+    pub missing_subbitmap: Vec<usize>,
+    /// Faces with the same three vertices as an earlier face, in any order
+    pub duplicate_faces: Vec<usize>,
+}
+
+impl SubMeshReport {
+    /// Whether nothing was found
+    pub fn is_clean(&self) -> bool {
+        *self == SubMeshReport::default()
+    }
+}
+
+impl SubMesh {
+    /// Looks for geometry that may not survive a re-export, with `num_subbitmaps` the number of
+    /// subbitmaps of the model. Faces with a vertex that does not exist cannot be judged by their
+    /// area and are not counted as degenerate for it.
+    pub fn analyze(&self, num_subbitmaps: usize) -> SubMeshReport {
+        let mut report = SubMeshReport::default();
+        let mut vertex_used = vec![ false; self.vertices.len() ];
+        let mut texcoord_used = vec![ false; self.texcoords.len() ];
+        let mut seen = HashSet::new();
+        for (n, face) in self.faces.iter().enumerate() {
+            let corners = [ face.vertex1, face.vertex2, face.vertex3 ];
+            for &index in &corners {
+                if let Some(used) = vertex_used.get_mut(index) {
+                    *used = true;
+                }
+            }
+            for index in [ face.uv1, face.uv2, face.uv3 ] {
+                if let Some(used) = texcoord_used.get_mut(index) {
+                    *used = true;
+                }
+            }
+
+            let zero_area = match corners.map(|index| self.vertices.get(index)) {
+                [ Some(v1), Some(v2), Some(v3) ] => {
+                    let (ax, ay, az) = (v2.x - v1.x, v2.y - v1.y, v2.z - v1.z);
+                    let (bx, by, bz) = (v3.x - v1.x, v3.y - v1.y, v3.z - v1.z);
+                    (ay * bz - az * by, az * bx - ax * bz, ax * by - ay * bx) == (0.0, 0.0, 0.0)
+                },
+                _ => false,
+            };
+            if corners[0] == corners[1] || corners[1] == corners[2] || corners[0] == corners[2] || zero_area {
+                report.degenerate_faces.push(n);
+            }
+            if face.subbitmap >= num_subbitmaps {
+                report.missing_subbitmap.push(n);
+            }
+            let mut sorted = corners;
+            sorted.sort_unstable();
+            if !seen.insert(sorted) {
+                report.duplicate_faces.push(n);
+            }
+        }
+        report.unused_vertices = (0..self.vertices.len()).filter(|&n| !vertex_used[n]).collect();
+        report.unused_texcoords = (0..self.texcoords.len()).filter(|&n| !texcoord_used[n]).collect();
+        let in_range = |value: f32| (0.0..=1.0).contains(&value);
+        report.texcoords_out_of_range = self.texcoords.iter().enumerate()
+            .filter(|(_, uv)| !in_range(uv.u) || !in_range(uv.v))
+            .map(|(n, _)| n).collect();
+        report
+    }
+
+    /// Bounds of all vertices, or None if there are no vertices
+    pub fn bounds(&self) -> Option<Qfg5Bounds> {
+        Qfg5Bounds::from_vertices(&self.vertices)
+    }
+
+    /// Bounds of all vertices as placed by `block` of an animation
+    pub fn posed_bounds(&self, block: &AnmBlock) -> Option<Qfg5Bounds> {
+        Qfg5Bounds::from_vertices(self.vertices.iter().map(|v| transform_vertex(block, v)))
+    }
+
+    pub fn winding_stats(&self) -> WindingStats {
+        let mut stats = WindingStats{ faces: self.faces.len(), ..Default::default() };
+        for face in &self.faces {
+            let [ Some(v1), Some(v2), Some(v3) ] = [ face.vertex1, face.vertex2, face.vertex3 ].map(|index| self.vertices.get(index)) else {
+                stats.out_of_range += 1;
+                continue;
+            };
+            let (ax, ay, az) = (v2.x - v1.x, v2.y - v1.y, v2.z - v1.z);
+            let (bx, by, bz) = (v3.x - v1.x, v3.y - v1.y, v3.z - v1.z);
+            let (nx, ny, nz) = (ay * bz - az * by, az * bx - ax * bz, ax * by - ay * bx);
+            let dot = nx * face.normal_x + ny * face.normal_y + nz * face.normal_z;
+            if dot == 0.0 {
+                stats.degenerate += 1;
+            } else if dot < 0.0 {
+                stats.mismatched += 1;
+            }
+        }
+        stats
+    }
+
+    /// Statistics of the lighting values, or None if there are none
+    pub fn lighting_stats(&self) -> Option<LightingStats> {
+        LightingStats::from_values(&self.lighting_vertices)
+    }
+
+    /// a, b and c of the lighting values as normals per vertex, if they are of length 1 for
+    /// every vertex and so may well be normals
+    pub fn lighting_normals(&self) -> Option<Vec<[f32; 3]>> {
+        let looks_like_normals = !self.lighting_vertices.is_empty() && self.lighting_vertices.len() == self.vertices.len() &&
+            self.lighting_vertices.iter().all(Qfg5LightingVertex::is_unit_abc);
+        looks_like_normals.then(|| self.lighting_vertices.iter().map(|l| [ l.a, l.b, l.c ]).collect())
+    }
+
+    /// A normal per vertex for smooth shading: the normals of the faces around it, weighted by
+    /// their area. A face counts in the direction of its stored normal, as the winding does not
+    /// always agree with it, see winding_stats(). Vertices of faces without area get the stored
+    /// normals of their faces instead, and [ 0, 0, 0 ] if those are zero as well.
+    pub fn vertex_normals(&self) -> Vec<[f32; 3]> {
+        let mut weighted = vec![ [ 0.0f32; 3 ]; self.vertices.len() ];
+        let mut stored = weighted.clone();
+        for face in &self.faces {
+            let corners = [ face.vertex1, face.vertex2, face.vertex3 ];
+            if corners.iter().any(|&n| n >= self.vertices.len()) {
+                continue;
+            }
+            let (v1, v2, v3) = (&self.vertices[face.vertex1], &self.vertices[face.vertex2], &self.vertices[face.vertex3]);
+            let (ax, ay, az) = (v2.x - v1.x, v2.y - v1.y, v2.z - v1.z);
+            let (bx, by, bz) = (v3.x - v1.x, v3.y - v1.y, v3.z - v1.z);
+            // Twice the area of the face long
+            let (nx, ny, nz) = (ay * bz - az * by, az * bx - ax * bz, ax * by - ay * bx);
+            let sign = if nx * face.normal_x + ny * face.normal_y + nz * face.normal_z < 0.0 { -1.0 } else { 1.0 };
+            for n in corners {
+                for (sum, value) in weighted[n].iter_mut().zip([ nx, ny, nz ]) {
+                    *sum += sign * value;
+                }
+                for (sum, value) in stored[n].iter_mut().zip([ face.normal_x, face.normal_y, face.normal_z ]) {
+                    *sum += value;
+                }
+            }
+        }
+        let normalize = |n: [f32; 3]| {
+            let length = (n[0] * n[0] + n[1] * n[1] + n[2] * n[2]).sqrt();
+            length.is_normal().then(|| n.map(|v| v / length))
+        };
+        weighted.into_iter().zip(stored)
+            .map(|(weighted, stored)| normalize(weighted).or_else(|| normalize(stored)).unwrap_or_default())
+            .collect()
+    }
 }
 
-#[derive(Default, Clone)]
-pub struct SubBitmap {
+#[derive(Default, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SubBitmap<'a> {
     pub width: u32,
     pub height: u32,
-    pub bitmap: Vec<u8>
+    /// Borrowed from the MDL data when decoded by Qfg5Model::new_borrowed()
+    #[cfg_attr(feature = "serde", serde(with = "crate::qfg5resource::serialize::base64"))]
+    pub bitmap: Cow<'a, [u8]>
 }
 
-pub struct Qfg5Model {
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Qfg5Model<'a> {
+    /// The bytes in front of the name, whose meaning is not known yet
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub unknown1: [ u8; 0xc ],
+    /// Up to the first zero byte of the name
     pub name: String,
-    pub palette: Vec<u8>,
+    /// The name as stored, including what follows its zero byte; encode() writes it as long as it
+    /// still holds `name`
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub raw_name: [ u8; 16 ],
+    /// The bytes after the number of submeshes, whose meaning is not known yet
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub unknown2: [ u8; 0xf ],
+    /// Entry 255 is not stored and stays black
+    #[cfg_attr(feature = "serde", serde(with = "crate::qfg5resource::serialize::palette"))]
+    pub palette: [ PaletteEntry; 256 ],
+    /// The fourth byte of the palette entries that have one, see palette_bytes()
+    #[cfg_attr(feature = "serde", serde(with = "crate::qfg5resource::serialize::base64"))]
+    pub palette_extra: Vec<u8>,
     pub submeshes: Vec<SubMesh>,
-    pub subbitmaps: Vec<SubBitmap>,
+    pub subbitmaps: Vec<SubBitmap<'a>>,
+    /// Things in the file that were not understood, but did not stop decoding
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub warnings: Vec<String>,
+}
+
+impl Default for Qfg5Model<'_> {
+    fn default() -> Self {
+        Qfg5Model{ unknown1: Default::default(), name: String::new(), raw_name: [ 0; 16 ], unknown2: Default::default(), palette: [ PaletteEntry::default(); 256 ],
+            palette_extra: vec![ 0; PALETTE_ENTRIES - 1 ], submeshes: Vec::new(), subbitmaps: Vec::new(), warnings: Vec::new() }
+    }
+}
+
+impl Qfg5Model<'_> {
+    /// The palette as stored in the file: `palette` and `palette_extra` interleaved
+    pub fn palette_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(PALETTE_SIZE);
+        for (n, &(r, g, b)) in self.palette[..PALETTE_ENTRIES].iter().enumerate() {
+            bytes.extend([ r, g, b ]);
+            bytes.extend(self.palette_extra.get(n));
+        }
+        bytes
+    }
+
+    /// Bounds of all submeshes combined, or None if there are no vertices
+    pub fn bounds(&self) -> Option<Qfg5Bounds> {
+        Qfg5Bounds::from_vertices(self.submeshes.iter().flat_map(|submesh| &submesh.vertices))
+    }
+
+    /// Bounds of all submeshes as placed by frame `frame` of `anm`, for framing an animated
+    /// pose; submeshes without an animation are left out, as is the whole model if `frame` is
+    /// not in the animation
+    pub fn frame_bounds(&self, anm: &AnmDecoder, frame: usize) -> Option<Qfg5Bounds> {
+        let vertices = self.submeshes.iter().zip(&anm.anims).filter_map(|(submesh, anim)| Some((submesh, anim.blocks.get(frame)?)))
+            .flat_map(|(submesh, block)| submesh.vertices.iter().map(|v| transform_vertex(block, v)));
+        Qfg5Bounds::from_vertices(vertices)
+    }
+
+    /// Smallest and largest lighting values of all submeshes, per component
+    pub fn lighting_range(&self) -> Option<(Qfg5LightingVertex, Qfg5LightingVertex)> {
+        self.lighting_stats().map(|stats| (stats.min, stats.max))
+    }
+
+    /// Statistics of the lighting values of all submeshes
+    pub fn lighting_stats(&self) -> Option<LightingStats> {
+        LightingStats::from_values(self.submeshes.iter().flat_map(|submesh| &submesh.lighting_vertices))
+    }
+
+    /// Problems that do not stop decoding: the warnings, and faces that use a subbitmap that
+    /// does not exist, which decoding rejects but an edited model may have
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = self.warnings.clone();
+        for (n, submesh) in self.submeshes.iter().enumerate() {
+            let missing = submesh.faces.iter().filter(|face| face.subbitmap >= self.subbitmaps.len()).count();
+            if missing > 0 {
+                problems.push(format!("submesh {} ('{}'): {} faces use a subbitmap past the {} there are",
+                    n, submesh.name, missing, self.subbitmaps.len()));
+            }
+        }
+        problems
+    }
+
+    /// SubMesh::analyze() of every submesh
+    pub fn analyze(&self) -> Vec<SubMeshReport> {
+        self.submeshes.iter().map(|submesh| submesh.analyze(self.subbitmaps.len())).collect()
+    }
+
+    /// Winding statistics of all submeshes combined
+    pub fn winding_stats(&self) -> WindingStats {
+        let mut stats = WindingStats::default();
+        for submesh in &self.submeshes {
+            stats.add(&submesh.winding_stats());
+        }
+        stats
+    }
+}
+
+impl Qfg5Model<'static> {
+    pub fn new(data: &[u8]) -> Result<Self> {
+        Ok(Qfg5Model::new_borrowed(data)?.into_owned())
+    }
+}
+
+/// Reads the rest of a submesh, which starts with its name
+fn read_submesh(cursor: &mut Reader, name: String, raw_name: [ u8; 16 ], budget: &mut decode::Budget) -> Result<SubMesh> {
+    let mut header_floats = [ 0.0; 20 ];
+    for (n, value) in header_floats.iter_mut().enumerate() {
+        let at = cursor.position();
+        *value = cursor.f32()?;
+        cursor.label(at, || format!("unknown float {} {}", n, value));
+    }
+    debug!("submesh '{}': unknown floats {:?}", name, header_floats);
+
+    let counts_at = cursor.position();
+    let num_vertices = cursor.u32()? as usize;
+    let num_uv_coords = cursor.u32()? as usize;
+    let num_faces = cursor.u32()? as usize;
+    cursor.label(counts_at, || format!("{} vertices, {} texture coordinates, {} faces", num_vertices, num_uv_coords, num_faces));
+    // The addresses of the lists, relative to the submesh, follow from the sizes of the lists
+    let vlist_addr = SUBMESH_HEADER_SIZE;
+    let r1 = vlist_addr + 12 * num_vertices as u64;
+    let r2 = r1 + 8 * num_uv_coords as u64;
+    let r3 = r2 + 40 * num_faces as u64;
+    for (what, expected) in [ ("vertex list address", vlist_addr), ("r1", r1), ("r2", r2), ("r3", r3) ] {
+        let at = cursor.position();
+        let value = cursor.u32()? as u64;
+        if value != expected { return Err(Error::Invalid{ what: format!("{} {:#x} (expected {:#x})", what, value, expected), at }); }
+        cursor.label(at, || format!("{} {:#x}", what, value));
+    }
+    // Vertices, texture coordinates, faces and lighting vertices
+    let submesh_size = (r3 - vlist_addr) as usize + 16 * num_vertices;
+    cursor.check_remaining(1, submesh_size)?;
+    budget.take(submesh_size, cursor.position())?;
+    let at = cursor.position();
+    let vertices = read_vec_f32(cursor, 3 * num_vertices)?.chunks_exact(3)
+        .map(|v| Qfg5Vertex{ x: v[0], y: v[1], z: v[2] }).collect();
+    cursor.label(at, || format!("{} vertices", num_vertices));
+    let at = cursor.position();
+    let texcoords = read_vec_f32(cursor, 2 * num_uv_coords)?.chunks_exact(2)
+        .map(|t| Qfg5TexCoord{ u: t[0], v: t[1] }).collect();
+    cursor.label(at, || format!("{} texture coordinates", num_uv_coords));
+    let mut faces = vec![ Qfg5Face::default(); num_faces ];
+    for n in 0..num_faces {
+        let at = cursor.position();
+        faces[n].vertex1 = cursor.u32()? as usize;
+        faces[n].vertex2 = cursor.u32()? as usize;
+        faces[n].vertex3 = cursor.u32()? as usize;
+        faces[n].uv1 = cursor.u32()? as usize;
+        faces[n].uv2 = cursor.u32()? as usize;
+        faces[n].uv3 = cursor.u32()? as usize;
+        faces[n].subbitmap = cursor.u32()? as usize;
+        faces[n].normal_x = cursor.f32()?;
+        faces[n].normal_y = cursor.f32()?;
+        faces[n].normal_z = cursor.f32()?;
+        for (what, index, max) in [ ("vertex", faces[n].vertex1, num_vertices), ("vertex", faces[n].vertex2, num_vertices),
+                                    ("vertex", faces[n].vertex3, num_vertices), ("texture coordinate", faces[n].uv1, num_uv_coords),
+                                    ("texture coordinate", faces[n].uv2, num_uv_coords), ("texture coordinate", faces[n].uv3, num_uv_coords) ] {
+            if index >= max { return Err(Error::IndexOutOfRange{ what, index, max, at }).with_context(at, || format!("face {}", n)); }
+        }
+        let face = &faces[n];
+        cursor.label(at, || format!("face {}: vertices {}/{}/{}, texture coordinates {}/{}/{}, subbitmap {}, normal ({}, {}, {})", n,
+            face.vertex1, face.vertex2, face.vertex3, face.uv1, face.uv2, face.uv3, face.subbitmap, face.normal_x, face.normal_y, face.normal_z));
+    }
+    let at = cursor.position();
+    let lighting_vertices = read_vec_f32(cursor, 4 * num_vertices)?.chunks_exact(4)
+        .map(|l| Qfg5LightingVertex{ a: l[0], b: l[1], c: l[2], d: l[3] }).collect();
+    cursor.label(at, || format!("{} lighting vertices", num_vertices));
+    Ok(SubMesh{ name, raw_name, vertices, texcoords, faces, lighting_vertices, header_floats })
+}
+
+/// Reads subbitmap `n`: its size, in three ways that have to agree, and its pixels
+fn read_subbitmap<'a>(cursor: &mut Reader<'a>, n: usize) -> Result<SubBitmap<'a>> {
+    let at = cursor.position();
+    let width = cursor.f32()?;
+    let height = cursor.f32()?;
+    let width_pow_2 = cursor.u32()?;
+    let height_pow_2 = cursor.u32()?;
+    let width_minus_1 = cursor.u32()?;
+    let height_minus_1 = cursor.u32()?;
+    let corrupt = |what: &str, a, b| Err(Error::Invalid{ what: format!("subbitmap {} {}: {} and {}", n, what, a, b), at });
+    if (width_minus_1 as u64 + 1) != width as u64 { return corrupt("width", width_minus_1, width); }
+    if (height_minus_1 as u64 + 1) != height as u64 { return corrupt("height", height_minus_1, height); }
+    if 1u64.checked_shl(width_pow_2) != Some(width as u64) { return corrupt("2-pow-width", width_pow_2, width); }
+    if 1u64.checked_shl(height_pow_2) != Some(height as u64) { return corrupt("2-pow-height", height_pow_2, height); }
+    let Some(width) = width_minus_1.checked_add(1) else { return corrupt("width", width_minus_1, width); };
+    let Some(height) = height_minus_1.checked_add(1) else { return corrupt("height", height_minus_1, height); };
+    cursor.label(at, || format!("subbitmap {}: {}x{}", n, width, height));
+
+    let bitmap_at = cursor.position();
+    let bitmap = Cow::Borrowed(cursor.bytes((width as usize).saturating_mul(height as usize))?);
+    cursor.label(bitmap_at, || format!("subbitmap {} pixels", n));
+    Ok(SubBitmap{ width, height, bitmap })
+}
+
+/// `value` as the u32 the format stores it as, or else an error about `what` at `at`
+fn u32_at(value: usize, at: usize, what: &str) -> Result<u32> {
+    u32::try_from(value).map_err(|_| Error::Invalid{ what: format!("{} {:#x}", what, value), at: at as u64 })
+}
+
+/// Names are 16 bytes, a byte per character and padded with zeroes; `raw` is written as it is
+/// while it holds `name`
+fn write_name(out: &mut Vec<u8>, name: &str, raw: &[ u8; 16 ]) -> Result<()> {
+    if decode_name(raw) == name {
+        out.extend(raw);
+        return Ok(());
+    }
+    let bytes: Option<Vec<u8>> = name.chars().map(|ch| u8::try_from(ch).ok()).collect();
+    match bytes {
+        Some(mut bytes) if bytes.len() <= 16 => {
+            bytes.resize(16, 0);
+            out.extend(bytes);
+            Ok(())
+        },
+        _ => Err(Error::Invalid{ what: format!("name '{}'", name), at: out.len() as u64 }),
+    }
+}
+
+/// Writes a submesh in the layout read_submesh() reads; the addresses of the lists follow from
+/// their sizes
+fn write_submesh(out: &mut Vec<u8>, submesh: &SubMesh) -> Result<()> {
+    let start = out.len();
+    write_name(out, &submesh.name, &submesh.raw_name)?;
+    for value in submesh.header_floats {
+        out.write_f32::<LittleEndian>(value).unwrap();
+    }
+    let (num_vertices, num_uv_coords, num_faces) = (submesh.vertices.len(), submesh.texcoords.len(), submesh.faces.len());
+    if submesh.lighting_vertices.len() != num_vertices {
+        return Err(Error::Invalid{ what: format!("{} lighting vertices for {} vertices", submesh.lighting_vertices.len(), num_vertices), at: start as u64 });
+    }
+    let vlist_addr = SUBMESH_HEADER_SIZE as usize;
+    let r1 = vlist_addr + 12 * num_vertices;
+    let r2 = r1 + 8 * num_uv_coords;
+    let r3 = r2 + 40 * num_faces;
+    for (what, value) in [ ("number of vertices", num_vertices), ("number of texture coordinates", num_uv_coords), ("number of faces", num_faces),
+                           ("vertex list address", vlist_addr), ("r1", r1), ("r2", r2), ("r3", r3) ] {
+        let at = out.len();
+        out.write_u32::<LittleEndian>(u32_at(value, at, what)?).unwrap();
+    }
+    for v in &submesh.vertices {
+        for value in [ v.x, v.y, v.z ] {
+            out.write_f32::<LittleEndian>(value).unwrap();
+        }
+    }
+    for t in &submesh.texcoords {
+        out.write_f32::<LittleEndian>(t.u).unwrap();
+        out.write_f32::<LittleEndian>(t.v).unwrap();
+    }
+    for (n, face) in submesh.faces.iter().enumerate() {
+        let at = out.len();
+        for (what, index, max) in [ ("vertex", face.vertex1, num_vertices), ("vertex", face.vertex2, num_vertices),
+                                    ("vertex", face.vertex3, num_vertices), ("texture coordinate", face.uv1, num_uv_coords),
+                                    ("texture coordinate", face.uv2, num_uv_coords), ("texture coordinate", face.uv3, num_uv_coords) ] {
+            if index >= max { return Err(Error::IndexOutOfRange{ what, index, max, at: at as u64 }).with_context(at as u64, || format!("face {}", n)); }
+        }
+        for value in [ face.vertex1, face.vertex2, face.vertex3, face.uv1, face.uv2, face.uv3, face.subbitmap ] {
+            out.write_u32::<LittleEndian>(u32_at(value, at, "index")?).unwrap();
+        }
+        for value in [ face.normal_x, face.normal_y, face.normal_z ] {
+            out.write_f32::<LittleEndian>(value).unwrap();
+        }
+    }
+    for l in &submesh.lighting_vertices {
+        for value in [ l.a, l.b, l.c, l.d ] {
+            out.write_f32::<LittleEndian>(value).unwrap();
+        }
+    }
+    Ok(())
 }
 
-impl Qfg5Model {
-    pub fn new(data: &[u8]) -> Result<Qfg5Model> {
-        let mut cursor = Cursor::new(data);
-        cursor.seek(SeekFrom::Current(0xc))?;
+impl<'a> Qfg5Model<'a> {
+    /// Like new(), but the subbitmaps refer to `data` instead of being copied
+    pub fn new_borrowed(data: &'a [u8]) -> Result<Self> {
+        Self::read(&mut Reader::new(data))
+    }
 
-        let mut name = vec![ 0u8; 16 ];
-        cursor.read_exact(&mut name)?;
-        let name = String::from_utf8(name)?;
+    pub(super) fn read(cursor: &mut Reader<'a>) -> Result<Self> {
+        let unknown1 = cursor.bytes(0xc)?.try_into().unwrap();
 
-        let num_submeshes = cursor.read_u16::<LittleEndian>()? as usize;
+        let (name, raw_name) = read_name(cursor)?;
+        cursor.label(0xc, || format!("name '{}'", name));
+
+        let num_submeshes = cursor.u16()? as usize;
+        cursor.label(0x1c, || format!("number of submeshes {}", num_submeshes));
         info!("model '{}': {} submeshes", name, num_submeshes);
-        cursor.seek(SeekFrom::Current(0xf))?;
-        let mut palette = vec![ 0u8; 1019 ];
-        cursor.read_exact(&mut palette)?;
-        let bitmap_texture_offset = cursor.read_u32::<LittleEndian>()? as u64;
-        let mut submesh_offset = vec![ 0u64; num_submeshes ];
-        for n in 0..num_submeshes {
-            submesh_offset[n] = cursor.read_u32::<LittleEndian>()? as u64;
+        let unknown2 = cursor.bytes(0xf)?.try_into().unwrap();
+        let palette_at = cursor.position();
+        let palette_data = cursor.bytes(PALETTE_SIZE)?;
+        let mut palette = [ PaletteEntry::default(); 256 ];
+        for (entry, rgbx) in palette.iter_mut().zip(palette_data.chunks(4)) {
+            *entry = (rgbx[0], rgbx[1], rgbx[2]);
         }
+        let palette_extra = palette_data.chunks_exact(4).map(|rgbx| rgbx[3]).collect();
+        cursor.label(palette_at, || "palette".to_string());
+        let bitmap_texture_offset_at = cursor.position();
+        let bitmap_texture_offset = cursor.u32()? as u64;
+        cursor.label(bitmap_texture_offset_at, || format!("subbitmaps offset {:#x}", bitmap_texture_offset));
+        let submesh_offsets = read_offset_table(cursor, num_submeshes, 0)?;
+        cursor.label(bitmap_texture_offset_at + 4, || format!("submesh offsets {:x?}", submesh_offsets));
 
+        let mut submesh_budget = decode::Budget::new("submesh data", cursor.size());
         let mut submeshes = Vec::with_capacity(num_submeshes);
-        for n in 0..num_submeshes {
-            cursor.seek(SeekFrom::Start(submesh_offset[n]))?;
+        for (n, &offset) in submesh_offsets.iter().enumerate() {
+            cursor.seek(offset);
+            let (name, raw_name) = read_name(cursor).with_context(offset, || format!("submesh {}", n))?;
+            cursor.label(offset, || format!("submesh {} name '{}'", n, name));
+            let submesh = read_submesh(cursor, name.clone(), raw_name, &mut submesh_budget)
+                .with_context(offset, || format!("submesh {} ('{}')", n, name))?;
+            submeshes.push(submesh);
+        }
 
-            let mut name = vec![ 0u8; 16 ];
-            cursor.read_exact(&mut name)?;
-            let name = String::from_utf8(name)?;
+        let mut cursor = SeekGuard::new(cursor, bitmap_texture_offset, bitmap_texture_offset_at)?;
+        // The offsets of the subbitmaps from here, the first of which is the size of the offsets
+        let offsets_size = cursor.u32()? as usize;
+        cursor.label(bitmap_texture_offset, || format!("subbitmap offsets size {}", offsets_size));
+        if (offsets_size & 3) != 0 { return Err(Error::Invalid{ what: format!("number of subbitmaps {:x}", offsets_size), at: bitmap_texture_offset }); }
+        let num_subbitmaps = offsets_size / 4;
+        cursor.check_remaining(num_subbitmaps, 24)?;
+        let mut subbitmap_offsets = vec![ bitmap_texture_offset + offsets_size as u64 ];
+        subbitmap_offsets.extend(read_offset_table(&mut cursor, num_subbitmaps.saturating_sub(1), bitmap_texture_offset)?);
+        subbitmap_offsets.truncate(num_subbitmaps);
+        if num_subbitmaps > 1 {
+            debug!("model '{}': {} subbitmaps at {:x?}", name, num_subbitmaps, subbitmap_offsets);
+            cursor.label(bitmap_texture_offset + 4, || format!("subbitmap offsets {:x?}", &subbitmap_offsets[1..]));
+        }
 
-            for _ in 0..20 {
-                let _unk = cursor.read_f32::<LittleEndian>()? as usize;
-                debug!("unknown float value {}", _unk);
-            }
+        let mut subbitmaps = Vec::with_capacity(num_subbitmaps);
+        for (n, &offset) in subbitmap_offsets.iter().enumerate() {
+            let mut cursor = SeekGuard::new(&mut cursor, offset, bitmap_texture_offset + 4 * n as u64)?;
+            subbitmaps.push(read_subbitmap(&mut cursor, n)?);
+        }
 
-            //cursor.seek(SeekFrom::Current(0x50))?;
-            let num_vertices = cursor.read_u32::<LittleEndian>()? as usize;
-            let num_uv_coords = cursor.read_u32::<LittleEndian>()? as usize;
-            let num_faces = cursor.read_u32::<LittleEndian>()? as usize;
-            let vlist_addr = cursor.read_u32::<LittleEndian>()?;
-            if vlist_addr != 0x7c { return Err(anyhow!("unexpected vertex list address {:x}", vlist_addr)); }
-            let r1 = cursor.read_u32::<LittleEndian>()?;
-            if r1 != vlist_addr + (12 * num_vertices as u32) { return Err(anyhow!("unexpected r1 {:x}", r1)); }
-            let r2 = cursor.read_u32::<LittleEndian>()?;
-            if r2 != r1 + (8 * num_uv_coords as u32) { return Err(anyhow!("unexpected r2 {:x}", r2)); }
-            let r3 = cursor.read_u32::<LittleEndian>()?;
-            if r3 != r2 + (40 * num_faces as u32) { return Err(anyhow!("unexpected r3 {:x}", r3)); }
-            let mut vertices = vec![ Qfg5Vertex::default(); num_vertices ];
-            for n in 0..num_vertices {
-                vertices[n].x = cursor.read_f32::<LittleEndian>()?;
-                vertices[n].y = cursor.read_f32::<LittleEndian>()?;
-                vertices[n].z = cursor.read_f32::<LittleEndian>()?;
+        // The subbitmaps of the faces can only be checked once the subbitmaps are known
+        for (n, (submesh, &offset)) in submeshes.iter().zip(&submesh_offsets).enumerate() {
+            let faces_at = offset + SUBMESH_HEADER_SIZE + 12 * submesh.vertices.len() as u64 + 8 * submesh.texcoords.len() as u64;
+            if let Some((f, face)) = submesh.faces.iter().enumerate().find(|(_, face)| face.subbitmap >= subbitmaps.len()) {
+                let at = faces_at + 40 * f as u64;
+                return Err(Error::IndexOutOfRange{ what: "subbitmap", index: face.subbitmap, max: subbitmaps.len(), at })
+                    .with_context(at, || format!("face {}", f))
+                    .with_context(offset, || format!("submesh {} ('{}')", n, submesh.name));
             }
-            let mut texcoords = vec![ Qfg5TexCoord::default(); num_uv_coords ];
-            for n in 0..num_uv_coords {
-                texcoords[n].u = cursor.read_f32::<LittleEndian>()?;
-                texcoords[n].v = cursor.read_f32::<LittleEndian>()?;
+        }
+        Ok(Qfg5Model{ unknown1, name, raw_name, unknown2, palette, palette_extra, submeshes, subbitmaps, warnings: Vec::new() })
+    }
+
+    /// The model in the layout read() reads, for writing back edited models. The submeshes follow
+    /// the offset table and the subbitmaps follow the submeshes, one right after the other, and
+    /// names are padded with zeroes. Fails on what the format cannot hold, such as a palette that
+    /// is not 1019 bytes or a subbitmap that is not a power of two wide, with the offset in the output.
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        out.extend(self.unknown1);
+        write_name(&mut out, &self.name, &self.raw_name)?;
+        let num_submeshes = u16::try_from(self.submeshes.len())
+            .map_err(|_| Error::Invalid{ what: format!("number of submeshes {}", self.submeshes.len()), at: out.len() as u64 })?;
+        out.write_u16::<LittleEndian>(num_submeshes).unwrap();
+        out.extend(self.unknown2);
+        if self.palette_extra.len() != PALETTE_ENTRIES - 1 {
+            return Err(Error::Invalid{ what: format!("{} extra palette bytes (expected {})", self.palette_extra.len(), PALETTE_ENTRIES - 1), at: out.len() as u64 });
+        }
+        if self.palette[PALETTE_ENTRIES..].iter().any(|&entry| entry != PaletteEntry::default()) {
+            return Err(Error::Invalid{ what: format!("palette entry {}, which is not stored", PALETTE_ENTRIES), at: out.len() as u64 });
+        }
+        out.extend(self.palette_bytes());
+        let bitmap_texture_offset_at = out.len();
+        out.write_u32::<LittleEndian>(0).unwrap();
+        let submesh_offsets_at = out.len();
+        out.resize(out.len() + 4 * self.submeshes.len(), 0);
+        for (n, submesh) in self.submeshes.iter().enumerate() {
+            let offset = out.len();
+            LittleEndian::write_u32(&mut out[submesh_offsets_at + 4 * n..], u32_at(offset, submesh_offsets_at + 4 * n, "submesh offset")?);
+            write_submesh(&mut out, submesh)
+                .with_context(offset as u64, || format!("submesh {} ('{}')", n, submesh.name))?;
+        }
+
+        let bitmap_texture_offset = out.len();
+        LittleEndian::write_u32(&mut out[bitmap_texture_offset_at..], u32_at(bitmap_texture_offset, bitmap_texture_offset_at, "subbitmaps offset")?);
+        // Without subbitmaps, there still is the size of the offsets
+        if self.subbitmaps.is_empty() {
+            out.write_u32::<LittleEndian>(0).unwrap();
+        }
+        let mut offset = 4 * self.subbitmaps.len();
+        for subbitmap in &self.subbitmaps {
+            let at = out.len();
+            out.write_u32::<LittleEndian>(u32_at(offset, at, "subbitmap offset")?).unwrap();
+            offset += SUBBITMAP_HEADER_SIZE + subbitmap.bitmap.len();
+        }
+        for (n, subbitmap) in self.subbitmaps.iter().enumerate() {
+            let at = out.len() as u64;
+            let (width, height) = (subbitmap.width, subbitmap.height);
+            if !width.is_power_of_two() || !height.is_power_of_two() {
+                return Err(Error::Invalid{ what: format!("subbitmap {} size {}x{}, which is not a power of two", n, width, height), at });
             }
-            let mut faces = vec![ Qfg5Face::default(); num_faces ];
-            for n in 0..num_faces {
-                faces[n].vertex1 = cursor.read_u32::<LittleEndian>()? as usize;
-                faces[n].vertex2 = cursor.read_u32::<LittleEndian>()? as usize;
-                faces[n].vertex3 = cursor.read_u32::<LittleEndian>()? as usize;
-                faces[n].uv1 = cursor.read_u32::<LittleEndian>()? as usize;
-                faces[n].uv2 = cursor.read_u32::<LittleEndian>()? as usize;
-                faces[n].uv3 = cursor.read_u32::<LittleEndian>()? as usize;
-                faces[n].subbitmap = cursor.read_u32::<LittleEndian>()? as usize;
-                faces[n].normal_x = cursor.read_f32::<LittleEndian>()?;
-                faces[n].normal_y = cursor.read_f32::<LittleEndian>()?;
-                faces[n].normal_z = cursor.read_f32::<LittleEndian>()?;
+            if subbitmap.bitmap.len() != width as usize * height as usize {
+                return Err(Error::Invalid{ what: format!("subbitmap {} of {} pixels for {}x{}", n, subbitmap.bitmap.len(), width, height), at });
             }
-            let mut lighting_vertices = vec![ Qfg5LightingVertex::default(); num_vertices ];
-            for n in 0..num_vertices {
-                lighting_vertices[n].a = cursor.read_f32::<LittleEndian>()?;
-                lighting_vertices[n].b = cursor.read_f32::<LittleEndian>()?;
-                lighting_vertices[n].c = cursor.read_f32::<LittleEndian>()?;
-                lighting_vertices[n].d = cursor.read_f32::<LittleEndian>()?;
+            out.write_f32::<LittleEndian>(width as f32).unwrap();
+            out.write_f32::<LittleEndian>(height as f32).unwrap();
+            for value in [ width.trailing_zeros(), height.trailing_zeros(), width - 1, height - 1 ] {
+                out.write_u32::<LittleEndian>(value).unwrap();
             }
-            submeshes.push(SubMesh{ name, vertices, texcoords, faces, lighting_vertices });
+            out.extend(subbitmap.bitmap.iter());
         }
+        Ok(out)
+    }
 
-        cursor.seek(SeekFrom::Start(bitmap_texture_offset))?;
-        let mut num_subbitmaps = cursor.read_u32::<LittleEndian>()? as usize;
-        if (num_subbitmaps & 3) != 0 { return Err(anyhow!("corrupt number of subbitmaps {:x}", num_subbitmaps)); }
-        num_subbitmaps = num_subbitmaps / 4;
-        if num_subbitmaps > 1 {
-            println!("Note: >1 subbitmaps: {}", num_subbitmaps);
-            cursor.seek(SeekFrom::Current(((num_subbitmaps - 1) * 4) as i64))?;
+    /// Copies the subbitmaps, so that the MDL data can be dropped
+    pub fn into_owned(self) -> Qfg5Model<'static> {
+        let subbitmaps = self.subbitmaps.into_iter()
+            .map(|s| SubBitmap{ width: s.width, height: s.height, bitmap: Cow::Owned(s.bitmap.into_owned()) })
+            .collect();
+        Qfg5Model{ unknown1: self.unknown1, name: self.name, raw_name: self.raw_name, unknown2: self.unknown2, palette: self.palette, palette_extra: self.palette_extra, submeshes: self.submeshes, subbitmaps, warnings: self.warnings }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qfg5resource::fixtures;
+
+    fn vertex(x: f32, y: f32, z: f32) -> Qfg5Vertex {
+        Qfg5Vertex{ x, y, z }
+    }
+
+    #[test]
+    fn test_bounds() {
+        let a = SubMesh{ vertices: vec![ vertex(1.0, -2.0, 3.0), vertex(-1.0, 4.0, 0.0) ], ..Default::default() };
+        let b = SubMesh{ vertices: vec![ vertex(5.0, 0.0, -3.0) ], ..Default::default() };
+        let empty = SubMesh::default();
+
+        let bounds = a.bounds().unwrap();
+        assert_eq!(bounds, Qfg5Bounds{ min: vertex(-1.0, -2.0, 0.0), max: vertex(1.0, 4.0, 3.0) });
+        assert_eq!(bounds.size(), vertex(2.0, 6.0, 3.0));
+        assert_eq!(bounds.center(), vertex(0.0, 1.0, 1.5));
+        assert_eq!(empty.bounds(), None);
+
+        let model = Qfg5Model{ submeshes: vec![ a, empty, b ], ..Default::default() };
+        assert_eq!(model.bounds().unwrap(), Qfg5Bounds{ min: vertex(-1.0, -2.0, -3.0), max: vertex(5.0, 4.0, 3.0) });
+        // Half of the diagonal of 2x6x3
+        assert_eq!(bounds.radius(), 3.5);
+    }
+
+    #[test]
+    fn test_posed_bounds() {
+        let a = SubMesh{ vertices: vec![ vertex(1.0, -2.0, 3.0), vertex(-1.0, 4.0, 0.0) ], ..Default::default() };
+        // A quarter turn around Z, then 10 along X
+        let block = AnmBlock{ translation: [ 10.0, 0.0, 0.0 ], rotation: [ 0.0, -1.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0 ] };
+        assert_eq!(a.posed_bounds(&block).unwrap(), Qfg5Bounds{ min: vertex(6.0, -1.0, 0.0), max: vertex(12.0, 1.0, 3.0) });
+
+        // Frame 1 of the fixture moves the quad one unit along X
+        let model = Qfg5Model::new(&fixtures::mdl()).unwrap();
+        let anm = AnmDecoder::new(&fixtures::anm()).unwrap();
+        assert_eq!(model.frame_bounds(&anm, 1).unwrap(), Qfg5Bounds{ min: vertex(1.0, 0.0, 0.0), max: vertex(2.0, 1.0, 0.0) });
+        assert_eq!(model.frame_bounds(&anm, 2), None);
+    }
+
+    #[test]
+    fn test_winding_stats() {
+        let face = |vertex2, vertex3, normal_z| Qfg5Face{ vertex1: 0, vertex2, vertex3, normal_z, ..Default::default() };
+        let submesh = SubMesh{
+            vertices: vec![ vertex(0.0, 0.0, 0.0), vertex(1.0, 0.0, 0.0), vertex(0.0, 1.0, 0.0), vertex(2.0, 0.0, 0.0) ],
+            faces: vec![
+                // Counter-clockwise seen from +Z
+                face(1, 2, 1.0),
+                face(2, 1, 1.0),
+                face(1, 2, -1.0),
+                // All vertices on a line
+                face(1, 3, 1.0),
+                // No vertex 4, as an edited model may have
+                face(1, 4, 1.0),
+            ],
+            ..Default::default()
+        };
+        let stats = submesh.winding_stats();
+        assert_eq!(stats, WindingStats{ faces: 5, mismatched: 2, degenerate: 1, out_of_range: 1 });
+        assert_eq!(stats.judged(), 3);
+        assert!((stats.mismatch_fraction() - 2.0 / 3.0).abs() < 1e-6);
+        assert_eq!(SubMesh::default().winding_stats().mismatch_fraction(), 0.0);
+    }
+
+    #[test]
+    fn test_analyze() {
+        let model = Qfg5Model::new(&fixtures::mdl()).unwrap();
+        assert!(model.submeshes[0].analyze(1).is_clean());
+        assert_eq!(model.analyze(), [ SubMeshReport::default() ]);
+
+        let mut submesh = model.submeshes[0].clone();
+        // A vertex on the line through vertices 0 and 1, and one that is not used
+        submesh.vertices.extend([ vertex(2.0, 0.0, 0.0), vertex(5.0, 5.0, 5.0) ]);
+        submesh.texcoords.extend([ Qfg5TexCoord{ u: 1.5, v: 0.0 }, Qfg5TexCoord{ u: 0.5, v: -0.25 } ]);
+        let face = |vertex1, vertex2, vertex3, uv, subbitmap| Qfg5Face{ vertex1, vertex2, vertex3, uv1: uv, uv2: uv, uv3: uv, subbitmap, ..Default::default() };
+        submesh.faces.extend([
+            // Face 0 the other way around, on a subbitmap that is not there
+            face(2, 1, 0, 0, 1),
+            face(0, 0, 1, 0, 0),
+            face(0, 1, 4, 4, 0),
+        ]);
+        assert_eq!(submesh.analyze(1), SubMeshReport{
+            degenerate_faces: vec![ 3, 4 ],
+            unused_vertices: vec![ 5 ],
+            unused_texcoords: vec![ 5 ],
+            texcoords_out_of_range: vec![ 4, 5 ],
+            missing_subbitmap: vec![ 2 ],
+            duplicate_faces: vec![ 2 ],
+        });
+
+        // A vertex that does not exist is not in the way
+        submesh.faces.push(face(0, 1, 9, 0, 0));
+        assert_eq!(submesh.analyze(1).degenerate_faces, [ 3, 4 ]);
+    }
+
+    #[test]
+    fn test_vertex_normals() {
+        let face = |vertex1, vertex2, vertex3, normal_z| Qfg5Face{ vertex1, vertex2, vertex3, normal_z, ..Default::default() };
+        let submesh = SubMesh{
+            vertices: vec![ vertex(0.0, 0.0, 0.0), vertex(2.0, 0.0, 0.0), vertex(0.0, 2.0, 0.0), vertex(0.0, 0.0, 1.0),
+                            vertex(5.0, 0.0, 0.0), vertex(6.0, 0.0, 0.0) ],
+            faces: vec![
+                // In the XY plane, wound against its normal, and a smaller face in the XZ plane
+                face(0, 2, 1, 1.0),
+                Qfg5Face{ vertex1: 0, vertex2: 1, vertex3: 3, normal_y: -1.0, ..Default::default() },
+                // Without area; vertex 5 only has this face
+                face(4, 5, 1, -1.0),
+            ],
+            ..Default::default()
+        };
+        let normals = submesh.vertex_normals();
+        // The faces have an area of 2 and 1
+        let (a, b) = (2.0 / 5.0f32.sqrt(), -1.0 / 5.0f32.sqrt());
+        for (normal, expected) in normals.iter().zip([ [ 0.0, b, a ], [ 0.0, b, a ], [ 0.0, 0.0, 1.0 ], [ 0.0, -1.0, 0.0 ], [ 0.0, 0.0, -1.0 ], [ 0.0, 0.0, -1.0 ] ]) {
+            assert!(normal.iter().zip(expected).all(|(n, e)| (n - e).abs() < 1e-6), "{:?} != {:?}", normal, expected);
         }
 
-        let mut subbitmaps = Vec::with_capacity(num_subbitmaps);
-        for n in 0..num_subbitmaps {
-            let width = cursor.read_f32::<LittleEndian>()?;
-            let height = cursor.read_f32::<LittleEndian>()?;
-            let width_pow_2 = cursor.read_u32::<LittleEndian>()?;
-            let height_pow_2 = cursor.read_u32::<LittleEndian>()?;
-            let width_minus_1 = cursor.read_u32::<LittleEndian>()?;
-            let height_minus_1 = cursor.read_u32::<LittleEndian>()?;
-            if (width_minus_1 + 1) != width as u32 { return Err(anyhow!("subbitmap {} width corrupt: {} and {}", n, width_minus_1, width)); }
-            if (height_minus_1 + 1) != height as u32 { return Err(anyhow!("subbitmap {} height corrupt: {} and {}", n, height_minus_1, height)); }
-            if 1 << width_pow_2 != width as u32 { return Err(anyhow!("subbitmap {} 2-pow-height corrupt: {} and {}", n, width_pow_2, width)); }
-            if 1 << height_pow_2 != height as u32 { return Err(anyhow!("subbitmap {} 2-pow-height corrupt: {} and {}", n, height_pow_2, height)); }
-            let width = width_minus_1 + 1;
-            let height = height_minus_1 + 1;
-
-            let mut bitmap = vec![ 0u8; (width * height) as usize ];
-            cursor.read_exact(&mut bitmap)?;
-            subbitmaps.push(SubBitmap{ width, height, bitmap });
-        }
-        Ok(Qfg5Model{ name, palette, submeshes, subbitmaps })
+        // Nothing to go by
+        let submesh = SubMesh{ vertices: vec![ vertex(1.0, 1.0, 1.0); 3 ], faces: vec![ face(0, 1, 2, 0.0) ], ..Default::default() };
+        assert_eq!(submesh.vertex_normals(), [ [ 0.0; 3 ]; 3 ]);
+    }
+
+    #[test]
+    fn test_lighting_colors() {
+        let lighting = |a, b, c, d| Qfg5LightingVertex{ a, b, c, d };
+        let submesh = SubMesh{ lighting_vertices: vec![ lighting(0.0, 1.0, 5.0, 2.0), lighting(2.0, -1.0, 5.0, 4.0) ], ..Default::default() };
+        let model = Qfg5Model{ submeshes: vec![ submesh ], ..Default::default() };
+        let (min, max) = model.lighting_range().unwrap();
+        assert_eq!(model.submeshes[0].lighting_vertices[0].to_rgba(&min, &max), [ 0.0, 1.0, 0.5, 0.0 ]);
+        assert_eq!(lighting(1.0, 0.0, 5.0, 3.0).to_rgba(&min, &max), [ 0.5, 0.5, 0.5, 0.5 ]);
+    }
+
+    #[test]
+    fn test_lighting_stats() {
+        let lighting = |a, b, c, d| Qfg5LightingVertex{ a, b, c, d };
+        let mut submesh = SubMesh{ vertices: vec![ vertex(0.0, 0.0, 0.0); 2 ], lighting_vertices: vec![ lighting(0.0, 0.6, 0.8, 2.0), lighting(-1.0, 0.0, 0.0, 4.0) ], ..Default::default() };
+        let stats = submesh.lighting_stats().unwrap();
+        assert_eq!((stats.count, stats.unit_abc), (2, 2));
+        assert_eq!((stats.min, stats.max), (lighting(-1.0, 0.0, 0.0, 2.0), lighting(0.0, 0.6, 0.8, 4.0)));
+        assert_eq!(stats.mean, lighting(-0.5, 0.3, 0.4, 3.0));
+        assert_eq!(submesh.lighting_normals().unwrap(), [ [ 0.0, 0.6, 0.8 ], [ -1.0, 0.0, 0.0 ] ]);
+
+        submesh.lighting_vertices[1].a = 0.5;
+        assert_eq!(submesh.lighting_stats().unwrap().unit_abc, 1);
+        assert_eq!(submesh.lighting_normals(), None);
+        assert_eq!(SubMesh::default().lighting_stats(), None);
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut mdl = fixtures::mdl();
+        // Make the second face refer to a fifth vertex
+        let header_size = 0x430;
+        let second_face = header_size + 0x7c + 12 * 4 + 8 * 4 + 40;
+        mdl[second_face + 4] = 4;
+        let err = Qfg5Model::new(&mdl).err().unwrap();
+        assert!(matches!(err.root(), Error::IndexOutOfRange{ what: "vertex", index: 4, max: 4, .. }));
+        assert_eq!(err.offset(), second_face as u64);
+
+        let mut mdl = fixtures::mdl();
+        mdl[header_size + 0x74] = 0xd0;
+        let err = Qfg5Model::new(&mdl).err().unwrap();
+        assert_eq!(err.to_string(), "submesh 0 ('quad') at offset 0x430: invalid r2 0xd0 (expected 0xcc) at offset 0x4a4");
+
+        // Truncated within the submesh
+        let mdl = fixtures::mdl();
+        let err = Qfg5Model::new(&mdl[..header_size + 0x90]).err().unwrap();
+        assert!(err.to_string().starts_with("submesh 0 ('quad') at offset 0x430: truncated at offset 0x4ac:"), "{}", err);
+
+        let mdl = fixtures::mdl();
+        assert!(matches!(Qfg5Model::new(&mdl[..mdl.len() - 1]), Err(Error::Truncated{ needed: 4, got: 3, .. })));
+
+        // The subbitmap offset points past the end
+        let mut mdl = fixtures::mdl();
+        let bitmap_texture_offset_at = 0x428;
+        mdl[bitmap_texture_offset_at + 3] = 0xff;
+        assert!(matches!(Qfg5Model::new(&mdl), Err(Error::Invalid{ at: 0x428, .. })));
+
+        // Huge counts of submeshes and subbitmaps fail before anything is allocated for them
+        let mut mdl = fixtures::mdl();
+        mdl[0x1c..0x1e].copy_from_slice(&[ 0xff; 2 ]);
+        assert!(matches!(Qfg5Model::new(&mdl[..0x430]), Err(Error::Truncated{ needed: 0x3fffc, got: 4, at: 0x42c })));
+        let mut mdl = fixtures::mdl();
+        let bitmap_texture_offset = u32::from_le_bytes(mdl[0x428..0x42c].try_into().unwrap()) as usize;
+        mdl[bitmap_texture_offset..bitmap_texture_offset + 4].copy_from_slice(&0xffff_fffc_u32.to_le_bytes());
+        assert!(matches!(Qfg5Model::new(&mdl), Err(Error::Truncated{ at, .. }) if at == bitmap_texture_offset as u64 + 4));
+    }
+
+    #[test]
+    fn test_face_errors() {
+        let header_size = 0x430;
+        let second_face = header_size + 0x7c + 12 * 4 + 8 * 4 + 40;
+        let mut mdl = fixtures::mdl();
+        mdl[second_face + 4 * 4] = 7;
+        assert_eq!(Qfg5Model::new(&mdl).err().unwrap().to_string(),
+            "submesh 0 ('quad') at offset 0x430: face 1 at offset 0x524: texture coordinate 7 out of range at offset 0x524, there are 4");
+
+        // The fixture has a single subbitmap
+        let mut mdl = fixtures::mdl();
+        mdl[second_face + 6 * 4] = 1;
+        let err = Qfg5Model::new(&mdl).err().unwrap();
+        assert!(matches!(err.root(), Error::IndexOutOfRange{ what: "subbitmap", index: 1, max: 1, .. }));
+        assert_eq!(err.to_string(), "submesh 0 ('quad') at offset 0x430: face 1 at offset 0x524: subbitmap 1 out of range at offset 0x524, there are 1");
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut model = Qfg5Model::new(&fixtures::mdl()).unwrap();
+        assert!(model.validate().is_empty());
+        model.submeshes[0].faces[1].subbitmap = 1;
+        model.warnings.push("warning".to_string());
+        assert_eq!(model.validate(), [ "warning", "submesh 0 ('quad'): 1 faces use a subbitmap past the 1 there are" ]);
+    }
+
+    #[test]
+    fn test_palette() {
+        let mut mdl = fixtures::mdl();
+        let palette_at = 0x2d;
+        mdl[palette_at + 4 + 3] = 0x80;
+        // The last entry lacks its fourth byte
+        mdl[palette_at + 1016..palette_at + 1019].copy_from_slice(&[ 1, 2, 3 ]);
+        let model = Qfg5Model::new(&mdl).unwrap();
+        assert_eq!(model.palette[..2], [ (0, 0, 0), (255, 0, 0) ]);
+        assert_eq!(model.palette[254..], [ (1, 2, 3), (0, 0, 0) ]);
+        assert_eq!((model.palette_extra.len(), model.palette_extra[1]), (254, 0x80));
+        assert_eq!(model.palette_bytes(), mdl[palette_at..palette_at + 1019]);
+    }
+
+    #[test]
+    fn test_latin1_name() {
+        let mut mdl = fixtures::mdl();
+        // "fixture" with an e acute
+        mdl[0xc + 6] = 0xe9;
+        mdl[0xc + 9] = b'x';
+        let model = Qfg5Model::new(&mdl).unwrap();
+        assert_eq!(model.name, "fixtur\u{e9}");
+        assert_eq!(model.submeshes[0].name, "quad");
+        // The x after the padding is kept
+        assert_eq!(model.encode().unwrap(), mdl);
+    }
+
+    #[test]
+    fn test_encode_name() {
+        let mut model = Qfg5Model::new(&fixtures::mdl()).unwrap();
+        model.raw_name[12] = b'x';
+        model.submeshes[0].name = "Quad \u{e9}".to_string();
+        let data = model.encode().unwrap();
+        assert_eq!(&data[0xc..0x1c], b"fixture\0\0\0\0\0x\0\0\0");
+        assert_eq!(&data[0x430..0x440], b"Quad \xe9\0\0\0\0\0\0\0\0\0\0");
+        // Only what is stored as a byte can be written
+        model.name = "\u{263a}".to_string();
+        assert!(matches!(model.encode(), Err(Error::Invalid{ at: 0xc, .. })));
+    }
+
+    #[test]
+    fn test_header_floats() {
+        let mut mdl = fixtures::mdl();
+        // After the name of the submesh
+        let header_floats = 0x430 + 16;
+        mdl[header_floats..header_floats + 4].copy_from_slice(&(-1.5f32).to_le_bytes());
+        mdl[header_floats + 76..header_floats + 80].copy_from_slice(&2.0f32.to_le_bytes());
+        let model = Qfg5Model::new(&mdl).unwrap();
+        let floats = model.submeshes[0].header_floats;
+        assert_eq!((floats[0], floats[1], floats[19]), (-1.5, 0.0, 2.0));
+    }
+
+    #[test]
+    fn test_borrowed() {
+        let data = fixtures::mdl();
+        let model = Qfg5Model::new_borrowed(&data).unwrap();
+        assert_eq!(model.subbitmaps[0].bitmap.as_ptr(), data[data.len() - 4..].as_ptr());
+        assert!(matches!(Qfg5Model::new(&data).unwrap().subbitmaps[0].bitmap, Cow::Owned(_)));
+    }
+
+    #[test]
+    fn test_subbitmaps() {
+        let model = Qfg5Model::new(&fixtures::mdl_two_subbitmaps()).unwrap();
+        assert_eq!(model.subbitmaps, [
+            SubBitmap{ width: 2, height: 2, bitmap: vec![ 1, 0, 0, 1 ].into() },
+            SubBitmap{ width: 4, height: 1, bitmap: vec![ 2, 3, 4, 5 ].into() },
+        ]);
+        assert_eq!(model.submeshes[0].faces[1].subbitmap, 1);
+        assert!(model.warnings.is_empty());
+
+        // The second offset points past the end
+        let mut mdl = fixtures::mdl_two_subbitmaps();
+        let second_offset_at = mdl.len() - 4 - 24 - 4 - 28 - 4;
+        mdl[second_offset_at + 3] = 0xff;
+        assert!(matches!(Qfg5Model::new(&mdl), Err(Error::Invalid{ at, .. }) if at == second_offset_at as u64));
+    }
+
+    #[test]
+    fn test_encode() {
+        let data = fixtures::mdl();
+        let model = Qfg5Model::new(&data).unwrap();
+        assert_eq!(model.encode().unwrap(), data);
+
+        // Both subbitmaps come back
+        let mut two = model.clone();
+        two.subbitmaps.push(SubBitmap{ width: 4, height: 1, bitmap: vec![ 1, 2, 3, 4 ].into() });
+        let decoded = Qfg5Model::new(&two.encode().unwrap()).unwrap();
+        assert_eq!(decoded.subbitmaps, two.subbitmaps);
+    }
+
+    #[test]
+    fn test_encode_errors() {
+        let model = Qfg5Model::new(&fixtures::mdl()).unwrap();
+        let mut changed = model.clone();
+        changed.name = "a name longer than 16".to_string();
+        assert!(matches!(changed.encode(), Err(Error::Invalid{ at: 0xc, .. })));
+
+        let mut changed = model.clone();
+        changed.submeshes[0].faces[1].uv2 = 4;
+        let err = changed.encode().err().unwrap();
+        assert!(matches!(err.root(), Error::IndexOutOfRange{ what: "texture coordinate", index: 4, max: 4, .. }));
+        assert_eq!(err.offset(), 0x430 + 0x7c + 12 * 4 + 8 * 4 + 40);
+
+        let mut changed = model.clone();
+        changed.submeshes[0].lighting_vertices.pop();
+        assert!(changed.encode().err().unwrap().to_string().starts_with("submesh 0 ('quad')"));
+
+        let mut changed = model.clone();
+        changed.subbitmaps[0].width = 3;
+        assert!(matches!(changed.encode(), Err(Error::Invalid{ .. })));
+        let mut changed = model;
+        changed.palette[255] = (1, 2, 3);
+        assert!(matches!(changed.encode(), Err(Error::Invalid{ .. })));
+        changed.palette[255] = (0, 0, 0);
+        changed.palette_extra.pop();
+        assert!(matches!(changed.encode(), Err(Error::Invalid{ .. })));
     }
 }
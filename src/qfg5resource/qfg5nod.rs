@@ -4,10 +4,14 @@
  * Copyright (c) 2024 Rink Springer <rink@rink.nu>
  * For conditions of distribution and use, see LICENSE file
  */
-use anyhow::Result;
+use crate::qfg5resource::decode::Reader;
+use crate::qfg5resource::error::Result;
 
 pub type PaletteEntry = (u8, u8, u8);
 
+// TODO: Besides the palette, NOD files contain node records which place the room's models
+// (and presumably cameras/lights). These are not decoded yet; once they are, the room
+// viewer can reconstruct the 3D scene by instantiating each referenced model at its node.
 pub struct NodDecoder {
     version: u8,
     palette: [ PaletteEntry; 256 ],
@@ -17,14 +21,14 @@ const NOD_PALETTE_OFFSET: usize = 168;
 
 impl NodDecoder {
     pub fn new(nod_data: &[u8]) -> Result<Self> {
-        let version = nod_data[6]; // 0 = demo, 4 = retail
+        let mut cursor = Reader::new(nod_data);
+        cursor.seek(6);
+        let version = cursor.u8()?; // 0 = demo, 4 = retail
+        cursor.seek(NOD_PALETTE_OFFSET as u64);
         let mut palette = [ PaletteEntry::default(); 256 ];
-        for n in 0..256_usize {
-            let offset = NOD_PALETTE_OFFSET + n * 4;
-            let r = nod_data[offset+0];
-            let g = nod_data[offset+1];
-            let b = nod_data[offset+2];
-            palette[n] = (r, g, b);
+        for entry in palette.iter_mut() {
+            let rgbx = cursor.bytes(4)?;
+            *entry = (rgbx[0], rgbx[1], rgbx[2]);
         };
         Ok(Self{ version, palette })
     }
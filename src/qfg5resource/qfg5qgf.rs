@@ -4,63 +4,190 @@
  * Copyright (c) 2024 Rink Springer <rink@rink.nu>
  * For conditions of distribution and use, see LICENSE file
  */
-use anyhow::Result;
-use byteorder::LittleEndian;
-use byteorder::ReadBytesExt;
-use std::io::{Cursor, Seek, SeekFrom};
+use crate::qfg5resource::decode::{self, Reader};
+use crate::qfg5resource::error::{Error, ErrorContext, Result};
+use crate::qfg5resource::readutil::read_offset_table;
 
 const QGF_NUM_CHARS: usize = 512;
 
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct QgfChar {
     pub width: u32,
+    #[cfg_attr(feature = "serde", serde(with = "crate::qfg5resource::serialize::base64"))]
     pub data: Vec<u8>,
 }
 
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct QgfDecoder {
     pub max_char_width: u32,
     pub char_height: u32,
+    pub char_space: u32,
     pub is_3d: bool,
     pub chars: Vec<QgfChar>,
 }
 
-impl QgfDecoder {
-    pub fn new(anm_data: &[u8]) -> Result<Self> {
-        let mut cursor = Cursor::new(&anm_data);
-        let max_char_width = cursor.read_u32::<LittleEndian>()?;
-        let char_height = cursor.read_u32::<LittleEndian>()?;
-        let _char_space = cursor.read_u32::<LittleEndian>()?;
-        let _unk1 = cursor.read_u32::<LittleEndian>()?;
-        let flag_3d = cursor.read_u32::<LittleEndian>()?;
-        let _unk2 = cursor.read_u32::<LittleEndian>()?;
-
-        let mut char_widths = vec![ 0u8; QGF_NUM_CHARS ];
-        for n in 0..char_widths.len() {
-            char_widths[n] = cursor.read_u8()?;
-        }
-        let mut char_offsets = vec![ 0u32; QGF_NUM_CHARS ];
-        for n in 0..char_offsets.len() {
-            char_offsets[n] = cursor.read_u32::<LittleEndian>()?;
+/// Rendered glyphs; pixels hold the raw glyph values, where 0 is background
+pub struct QgfBitmap {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+impl QgfBitmap {
+    fn new(width: u32, height: u32) -> Self {
+        QgfBitmap{ width, height, pixels: vec![ 0u8; (width * height) as usize ] }
+    }
+
+    fn blit(&mut self, ch: &QgfChar, char_height: u32, base_x: u32, base_y: u32) {
+        for y in 0..char_height {
+            for x in 0..ch.width {
+                let v = ch.data[((ch.width * y) + x) as usize];
+                if v != 0 && base_x + x < self.width && base_y + y < self.height {
+                    self.pixels[((base_y + y) * self.width + base_x + x) as usize] = v;
+                }
+            }
         }
+    }
+}
 
+fn read_glyph(cursor: &mut Reader, num_pixels: usize, pixel_budget: &mut decode::Budget) -> Result<Vec<u8>> {
+    pixel_budget.take(num_pixels, cursor.position())?;
+    let mut data = vec![ 0u8; num_pixels ];
+    decode::decode_rle(decode::RleMode::Qgf, cursor.rest(), &mut data, cursor.position())?;
+    Ok(data)
+}
+
+impl QgfDecoder {
+    pub fn new(qgf_data: &[u8]) -> Result<Self> {
+        let mut cursor = Reader::new(qgf_data);
+        let max_char_width = cursor.u32()?;
+        let char_height = cursor.u32()?;
+        let char_space = cursor.u32()?;
+        let _unk1 = cursor.u32()?;
+        let flag_3d = cursor.u32()?;
+        let _unk2 = cursor.u32()?;
+
+        let char_widths = cursor.bytes(QGF_NUM_CHARS)?;
+        let char_offsets = read_offset_table(&mut cursor, QGF_NUM_CHARS, 0)?;
+
+        let mut pixel_budget = decode::Budget::new("glyph pixels", decode::max_rle_pixels(qgf_data.len()));
         let mut chars = Vec::new();
-        for n in 0..QGF_NUM_CHARS {
-            cursor.seek(SeekFrom::Start(char_offsets[n] as u64))?;
-            let width = char_widths[n] as u32;
-
-            let mut data = vec![ 0u8; (width * char_height) as usize ];
-            let mut offset: usize = 0;
-            while offset < data.len() {
-                let a = cursor.read_u8()?;
-                let _b = cursor.read_u8()?;
-                if (a & 0x80) == 0 {
-                    data[offset] = a;
-                    offset += 1;
-                } else {
-                    offset += 128 - (a & 0x7f) as usize;
-                }
+        for (n, (&width, offset)) in char_widths.iter().zip(char_offsets).enumerate() {
+            cursor.seek(offset);
+            let width = width as u32;
+
+            let num_pixels = width as usize * char_height as usize;
+            if num_pixels > decode::max_rle_pixels(qgf_data.len()) {
+                return Err(Error::Invalid{ what: format!("character height {}", char_height), at: 4 });
             }
+            let data = read_glyph(&mut cursor, num_pixels, &mut pixel_budget).with_context(offset, || format!("character {}", n))?;
             chars.push(QgfChar{ width, data });
         }
-        Ok(QgfDecoder{ max_char_width, char_height, chars, is_3d: flag_3d != 0 })
+        Ok(QgfDecoder{ max_char_width, char_height, char_space, chars, is_3d: flag_3d != 0 })
+    }
+
+    /// Glyph used for a character; text is single-byte, so anything else is shown as '?'
+    pub fn glyph_index(ch: char) -> usize {
+        let code = ch as usize;
+        if code < 256 { code } else { '?' as usize }
+    }
+
+    fn glyph(&self, ch: char) -> Option<&QgfChar> {
+        self.chars.get(Self::glyph_index(ch))
+    }
+
+    /// Width in pixels of the longest line of `text`
+    pub fn text_width(&self, text: &str) -> u32 {
+        text.lines().map(|line| {
+            let width: u32 = line.chars().filter_map(|ch| self.glyph(ch)).map(|g| g.width + self.char_space).sum();
+            width.saturating_sub(self.char_space)
+        }).max().unwrap_or(0)
+    }
+
+    /// Renders `text`, which may consist of multiple lines
+    pub fn render_text(&self, text: &str) -> QgfBitmap {
+        let num_lines = text.lines().count().max(1) as u32;
+        let mut bitmap = QgfBitmap::new(self.text_width(text), num_lines * self.char_height);
+        for (n, line) in text.lines().enumerate() {
+            let mut x = 0;
+            for glyph in line.chars().filter_map(|ch| self.glyph(ch)) {
+                bitmap.blit(glyph, self.char_height, x, n as u32 * self.char_height);
+                x += glyph.width + self.char_space;
+            }
+        }
+        bitmap
+    }
+
+    /// Renders all glyphs in a grid of `chars_per_line` columns, with a pixel of spacing
+    pub fn render_sheet(&self, chars_per_line: u32) -> QgfBitmap {
+        let chars_per_line = chars_per_line.max(1);
+        let num_lines = (self.chars.len() as u32).div_ceil(chars_per_line);
+        let mut bitmap = QgfBitmap::new(chars_per_line * (self.max_char_width + 1), num_lines * (self.char_height + 1));
+        for (n, ch) in self.chars.iter().enumerate() {
+            let base_x = (n as u32 % chars_per_line) * (self.max_char_width + 1);
+            let base_y = (n as u32 / chars_per_line) * (self.char_height + 1);
+            bitmap.blit(ch, self.char_height, base_x, base_y);
+        }
+        bitmap
+    }
+
+    /// Opacity of a glyph pixel value: 3D fonts store antialiasing levels, others are solid
+    pub fn coverage(&self, value: u8) -> u8 {
+        if value == 0 {
+            0
+        } else if self.is_3d {
+            value.saturating_mul(8)
+        } else {
+            255
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qfg5resource::fixtures;
+
+    fn test_font() -> QgfDecoder {
+        let mut chars: Vec<QgfChar> = (0..QGF_NUM_CHARS).map(|_| QgfChar{ width: 0, data: Vec::new() }).collect();
+        chars['A' as usize] = QgfChar{ width: 2, data: vec![ 1, 2, 3, 4 ] };
+        chars['B' as usize] = QgfChar{ width: 1, data: vec![ 5, 0 ] };
+        chars['?' as usize] = QgfChar{ width: 1, data: vec![ 6, 6 ] };
+        QgfDecoder{ max_char_width: 2, char_height: 2, char_space: 1, is_3d: true, chars }
+    }
+
+    #[test]
+    fn test_render_text() {
+        let font = test_font();
+        assert_eq!(font.text_width(""), 0);
+        assert_eq!(font.text_width("AB"), 4);
+        assert_eq!(font.text_width("A\nABA"), 7);
+
+        let bitmap = font.render_text("AB");
+        assert_eq!((bitmap.width, bitmap.height), (4, 2));
+        assert_eq!(bitmap.pixels, vec![ 1, 2, 0, 5, 3, 4, 0, 0 ]);
+
+        let bitmap = font.render_text("B\u{263a}");
+        assert_eq!(bitmap.pixels, vec![ 5, 0, 6, 0, 0, 6 ]);
+    }
+
+    #[test]
+    fn test_render_sheet() {
+        let font = test_font();
+        let bitmap = font.render_sheet(64);
+        assert_eq!((bitmap.width, bitmap.height), (64 * 3, 8 * 3));
+        // 'A' is glyph 65, which is the second glyph of the second row
+        assert_eq!(bitmap.pixels[(3 * bitmap.width + 3) as usize], 1);
+        assert_eq!(bitmap.pixels[(4 * bitmap.width + 4) as usize], 4);
+    }
+
+    #[test]
+    fn test_errors() {
+        let qgf = fixtures::qgf();
+        let err = QgfDecoder::new(&qgf[..qgf.len() - 1]).unwrap_err();
+        assert_eq!(err.offset(), 0xa1d);
+        assert_eq!(err.to_string(), "character 65 at offset 0xa18: truncated at offset 0xa1d: needed 1 bytes, got 0");
     }
 }
@@ -4,36 +4,98 @@
  * Copyright (c) 2024 Rink Springer <rink@rink.nu>
  * For conditions of distribution and use, see LICENSE file
  */
-use anyhow::{anyhow, Result};
-use std::io::Read;
-use byteorder::{ByteOrder, ReadBytesExt, LittleEndian};
-use std::io::Cursor;
+use byteorder::{ByteOrder, WriteBytesExt, LittleEndian};
 use std::fmt;
+use std::io::Read;
+use crate::qfg5resource::decode::Reader;
+use crate::qfg5resource::error::{Error, ErrorContext, Result};
 
-const FLAG_TEXT_MANGLED: u16 = 4;
+/// The text is mangled, see demangle_text()
+pub const FLAG_TEXT_MANGLED: u16 = 4;
+/// The header of a message and the field after its text
+const MIN_MESSAGE_SIZE: usize = 36;
 
-fn demangle_text(data: &[u8]) -> String {
-    let mut output = String::new();
-    let mut data_cursor = Cursor::new(&data);
+fn demangle_text(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(data.len());
     // Process 4 bytes at a time
-    while let Ok(mut v) = data_cursor.read_u32::<LittleEndian>() {
+    let mut words = data.chunks_exact(4);
+    for word in &mut words {
+        let mut v = LittleEndian::read_u32(word);
         v = v ^ 0xf1acc1d;
         v= v.rotate_right(15);
-        let mut chars = [ 0u8; 4 ];
-        LittleEndian::write_u32(&mut chars, v);
-        for ch in chars {
-            output.push(ch as char);
+        output.write_u32::<LittleEndian>(v).unwrap();
+    }
+    // Process remaining bytes; reading them as a u32 would have consumed them
+    output.extend(words.remainder().iter().map(|v| !v));
+    output
+}
+
+/// How the characters of the text of messages are stored
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Codepage {
+    /// A byte per character, as in the releases of the game; the English one only uses ASCII
+    #[default]
+    Windows1252,
+    /// For files written by other tools
+    Utf8,
+}
+
+/// Windows-1252 differs from Latin-1 in 0x80-0x9f. The five bytes it leaves undefined are
+/// mapped to the control characters of Latin-1, so that every byte is a character.
+const WINDOWS_1252_80_9F: [ char; 32 ] = [
+    '\u{20ac}', '\u{81}', '\u{201a}', '\u{192}', '\u{201e}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{2c6}', '\u{2030}', '\u{160}', '\u{2039}', '\u{152}', '\u{8d}', '\u{17d}', '\u{8f}',
+    '\u{90}', '\u{2018}', '\u{2019}', '\u{201c}', '\u{201d}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{2dc}', '\u{2122}', '\u{161}', '\u{203a}', '\u{153}', '\u{9d}', '\u{17e}', '\u{178}',
+];
+
+impl Codepage {
+    /// None if the data is not valid in this codepage
+    pub fn decode(self, data: &[u8]) -> Option<String> {
+        match self {
+            Codepage::Windows1252 => Some(data.iter().map(|&b| match b {
+                0x80..=0x9f => WINDOWS_1252_80_9F[b as usize - 0x80],
+                _ => b as char,
+            }).collect()),
+            Codepage::Utf8 => String::from_utf8(data.to_vec()).ok(),
         }
     }
-    // Process remaining bytes
-    while let Ok(mut v) = data_cursor.read_u8() {
-        v = !v;
-        output.push(v as char);
+
+    /// None if the text has characters this codepage does not have
+    pub fn encode(self, text: &str) -> Option<Vec<u8>> {
+        match self {
+            Codepage::Windows1252 => text.chars().map(|ch| match ch {
+                '\0'..='\u{7f}' | '\u{a0}'..='\u{ff}' => Some(ch as u8),
+                _ => WINDOWS_1252_80_9F.iter().position(|&c| c == ch).map(|n| 0x80 + n as u8),
+            }).collect(),
+            Codepage::Utf8 => Some(text.as_bytes().to_vec()),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Codepage::Windows1252 => "Windows-1252",
+            Codepage::Utf8 => "UTF-8",
+        }
     }
+}
+
+/// The inverse of demangle_text()
+fn mangle_text(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(data.len());
+    let mut words = data.chunks_exact(4);
+    for word in &mut words {
+        let v = LittleEndian::read_u32(word).rotate_left(15) ^ 0xf1acc1d;
+        output.write_u32::<LittleEndian>(v).unwrap();
+    }
+    output.extend(words.remainder().iter().map(|v| !v));
     output
 }
 
-#[derive(Debug)]
+/// Serialized as its 12 characters
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(into = "String", try_from = "String"))]
 pub struct QgmLabel {
     value: [ u8; 12 ],
 }
@@ -48,7 +110,7 @@ fn encode_digit_base_36(v: u16) -> Option<char> {
     }
 }
 fn encode_base_36(v: u16, num_digits: usize) -> Option<String> {
-    if v >= 36_u16.pow(num_digits as u32) {
+    if 36_u64.checked_pow(num_digits as u32).is_some_and(|max| v as u64 >= max) {
         return None; // can't fit in this amount of digits
     }
     let mut chars = vec![ 0u8; num_digits ];
@@ -60,11 +122,25 @@ fn encode_base_36(v: u16, num_digits: usize) -> Option<String> {
     String::from_utf8(chars).ok()
 }
 
+fn decode_digit_base_36(ch: char) -> Option<u16> {
+    match ch {
+        '0'..='9' => Some(ch as u16 - '0' as u16),
+        'A'..='Z' => Some(ch as u16 - 'A' as u16 + 10),
+        _ => None,
+    }
+}
+
+/// The inverse of encode_base_36(); None for other characters than 0-9 and A-Z, or if the
+/// value does not fit
+pub fn decode_base_36(s: &str) -> Option<u16> {
+    s.chars().try_fold(0u16, |v, ch| v.checked_mul(36)?.checked_add(decode_digit_base_36(ch)?))
+}
+
 impl QgmLabel {
-    pub fn new(cursor: &mut Cursor<&[u8]>) -> Result<QgmLabel> {
-        let mut message = [ 0u8; 13 ];
-        cursor.read_exact(&mut message)?;
-        if message[12] != 0 { return Err(anyhow!("label does not end in zero byte")); }
+    fn new(cursor: &mut Reader) -> Result<QgmLabel> {
+        let at = cursor.position();
+        let message = cursor.bytes(13)?;
+        if message[12] != 0 { return Err(Error::Invalid{ what: "label that does not end in a zero byte".to_string(), at }); }
 
         let mut value = [ 0u8; 12 ];
         value.copy_from_slice(&message[0..12]);
@@ -72,12 +148,43 @@ impl QgmLabel {
     }
 
     pub fn encode(qgm: &QgmDecoder, m: &QgmMessage) -> String {
-        format!("{}{}{}.{}{}",
-            encode_base_36(qgm.file_id, 3).unwrap(),
-            encode_base_36(m.id[0], 2).unwrap(),
-            encode_base_36(m.id[1], 2).unwrap(),
-            encode_base_36(m.id[2], 2).unwrap(),
-            encode_base_36(m.id[3], 1).unwrap())
+        Self::try_encode(qgm, m).unwrap()
+    }
+
+    /// Like encode(), but None if the ids do not fit in the digits of the label
+    pub fn try_encode(qgm: &QgmDecoder, m: &QgmMessage) -> Option<String> {
+        Self::encode_ids(qgm.file_id, &m.id)
+    }
+
+    /// The label of the message with ids `id` in file `file_id`, as encode() gives it; None if
+    /// the ids do not fit
+    pub fn from_ids(file_id: u16, id: [ u16; 4 ]) -> Option<QgmLabel> {
+        Self::encode_ids(file_id, &id)?.parse().ok()
+    }
+
+    fn encode_ids(file_id: u16, id: &[ u16; 4 ]) -> Option<String> {
+        Some(format!("{}{}{}.{}{}",
+            encode_base_36(file_id, 3)?,
+            encode_base_36(id[0], 2)?,
+            encode_base_36(id[1], 2)?,
+            encode_base_36(id[2], 2)?,
+            encode_base_36(id[3], 1)?))
+    }
+
+    /// The file id and message ids of a label as encode() writes it, such as `0BJ0102.034`;
+    /// zero bytes or spaces after it are ignored
+    pub fn parse(label: &str) -> Option<(u16, [ u16; 4 ])> {
+        let label = label.trim_end_matches([ '\0', ' ' ]);
+        let (ids, last) = label.split_once('.')?;
+        if ids.len() != 7 || last.len() != 3 || !ids.is_ascii() || !last.is_ascii() {
+            return None;
+        }
+        Some((decode_base_36(&ids[0..3])?, [
+            decode_base_36(&ids[3..5])?,
+            decode_base_36(&ids[5..7])?,
+            decode_base_36(&last[0..2])?,
+            decode_base_36(&last[2..3])?,
+        ]))
     }
 }
 
@@ -91,6 +198,38 @@ impl fmt::Display for QgmLabel {
     }
 }
 
+impl From<QgmLabel> for String {
+    fn from(label: QgmLabel) -> String {
+        label.to_string()
+    }
+}
+
+impl TryFrom<String> for QgmLabel {
+    type Error = String;
+
+    fn try_from(s: String) -> std::result::Result<Self, Self::Error> {
+        let value = s.as_bytes().try_into().map_err(|_| format!("label '{}' is not 12 bytes", s))?;
+        Ok(QgmLabel{ value })
+    }
+}
+
+/// Text of at most 12 ASCII characters, padded with zero bytes as in the files, such as
+/// `0BJ0102.034` or `LABEL0000001`
+impl std::str::FromStr for QgmLabel {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if !s.is_ascii() || s.len() > 12 {
+            return Err(format!("label '{}' is not at most 12 ASCII characters", s));
+        }
+        let mut value = [ 0u8; 12 ];
+        value[..s.len()].copy_from_slice(s.as_bytes());
+        Ok(QgmLabel{ value })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct QgmMessage {
     pub id: [ u16; 4 ],
     pub speaker_id: u16,
@@ -98,89 +237,468 @@ pub struct QgmMessage {
     pub message_label: Option<QgmLabel>,
     pub dialog_options: Vec<QgmLabel>,
     pub text: String,
+    /// As found in the file; only FLAG_TEXT_MANGLED is understood
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub flags: u16,
+    /// Header fields whose meaning is not known yet, unknown 2 to 6 in the order of the file
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub unknown: [ u16; 5 ],
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub text_flag: u16,
+    /// Follows the text, meaning not known yet
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub unknown8: u32,
 }
 
+impl QgmMessage {
+    /// A message without a message label or dialog options, with the fields whose meaning is not
+    /// known set to zero; its text is not mangled
+    pub fn new(id: [ u16; 4 ], speaker_id: u16, text: &str) -> Self {
+        QgmMessage{ id, speaker_id, msg_id: 0, message_label: None, dialog_options: Vec::new(), text: text.to_string(),
+            flags: 0, unknown: [ 0; 5 ], text_flag: 0, unknown8: 0 }
+    }
+
+    pub fn is_mangled(&self) -> bool {
+        (self.flags & FLAG_TEXT_MANGLED) != 0
+    }
+
+    /// Name of the speech resource of the message in file `file_id`, without its `.AUD`
+    /// extension: the label without the dot, such as `0BJ0102034`. Panics if the ids do not fit
+    /// in a label, like QgmLabel::encode().
+    pub fn audio_name(&self, file_id: u16) -> String {
+        self.try_audio_name(file_id).unwrap()
+    }
+
+    /// Like audio_name(), but None if the ids do not fit in a label
+    pub fn try_audio_name(&self, file_id: u16) -> Option<String> {
+        Some(QgmLabel::encode_ids(file_id, &self.id)?.replace('.', ""))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct QgmDecoder {
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub version: u32,
+    /// Between the number of messages and the file id, meaning not known yet
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub unknown1: u16,
     pub file_id: u16,
     pub messages: Vec<QgmMessage>,
+    /// Of the text of the messages
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub codepage: Codepage,
+    /// Offset of the message at which new_lenient() stopped, as the data ends within it
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub truncated_at: Option<u64>,
+}
+
+/// Names of the speakers of messages by their id, which the game data does not have; see parse()
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SpeakerTable {
+    names: std::collections::BTreeMap<u16, String>,
+}
+
+impl SpeakerTable {
+    /// Reads a CSV mapping with a line `id,name` per speaker, such as `5,Erasmus`. An `id,name`
+    /// header, empty lines and lines starting with `#` are skipped; names may be quoted.
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut names = std::collections::BTreeMap::new();
+        let mut at = 0;
+        for line in text.split_inclusive('\n') {
+            let line_at = at;
+            at += line.len() as u64;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.eq_ignore_ascii_case("id,name") {
+                continue;
+            }
+            let invalid = || Error::Invalid{ what: format!("speaker line '{}'", line), at: line_at };
+            let (id, name) = line.split_once(',').ok_or_else(invalid)?;
+            let id = id.trim().parse::<u16>().map_err(|_| invalid())?;
+            let name = name.trim();
+            let name = match name.strip_prefix('"').and_then(|name| name.strip_suffix('"')) {
+                Some(quoted) => quoted.replace("\"\"", "\""),
+                None => name.to_string(),
+            };
+            names.insert(id, name);
+        }
+        Ok(SpeakerTable{ names })
+    }
+
+    pub fn name(&self, id: u16) -> Option<&str> {
+        self.names.get(&id).map(String::as_str)
+    }
+
+    /// The id of a speaker given by name, without regard to case, or as a number
+    pub fn resolve(&self, name_or_id: &str) -> Option<u16> {
+        name_or_id.parse().ok().or_else(|| {
+            self.names.iter().find(|(_, name)| name.eq_ignore_ascii_case(name_or_id)).map(|(&id, _)| id)
+        })
+    }
+}
+
+fn read_message(cursor: &mut Reader, codepage: Codepage) -> Result<QgmMessage> {
+    // message block header (32 bytes)
+    let header_at = cursor.position();
+    let id1 = cursor.u16()?;
+    let id2 = cursor.u16()?;
+    let id3 = cursor.u16()?;
+    let id4 = cursor.u16()?;
+    let speaker_id = cursor.u16()?; // maybe
+    let unk2 = cursor.u16()?;
+    let unk3 = cursor.u16()?;
+    let unk4 = cursor.u16()?;
+    let num_dialog_options = cursor.u16()?;
+    let flags = cursor.u16()?;
+    let unk5 = cursor.u16()?;
+    let msg_id  = cursor.u16()?;
+    let msg_length = cursor.u16()?;
+    let text_flag = cursor.u16()?;
+    let msg_label_flag = cursor.u16()?;
+    let unk6 = cursor.u16()?;
+    let fields = [ ("id 1", id1), ("id 2", id2), ("id 3", id3), ("id 4", id4), ("speaker id", speaker_id),
+                   ("unknown 2", unk2), ("unknown 3", unk3), ("unknown 4", unk4), ("dialog options", num_dialog_options),
+                   ("flags", flags), ("unknown 5", unk5), ("message id", msg_id), ("text length", msg_length),
+                   ("text flag", text_flag), ("label flag", msg_label_flag), ("unknown 6", unk6) ];
+    for (n, (what, value)) in fields.into_iter().enumerate() {
+        cursor.label_range(header_at + 2 * n as u64, 2, || format!("{} {}", what, value));
+    }
+
+    let message_label: Option<QgmLabel>;
+    if msg_label_flag != 0 {
+        let at = cursor.position();
+        let label = QgmLabel::new(cursor)?;
+        cursor.label(at, || format!("message label {}", label));
+        message_label = Some(label);
+    } else {
+        message_label = None;
+    }
+
+    let mut dialog_options = Vec::new();
+    for n in 0..num_dialog_options {
+        let at = cursor.position();
+        let label = QgmLabel::new(cursor)?;
+        cursor.label(at, || format!("dialog option {} {}", n, label));
+        dialog_options.push(label);
+    }
+
+    let text_at = cursor.position();
+    let text_data = cursor.bytes(msg_length as usize)?;
+    let unk8_at = cursor.position();
+    let unk8 = cursor.u32()?;
+
+    let text_data = if (flags & FLAG_TEXT_MANGLED) != 0 { demangle_text(text_data) } else { text_data.to_vec() };
+    let text = codepage.decode(&text_data)
+        .ok_or_else(|| Error::Invalid{ what: format!("{} message text", codepage.name()), at: text_at })?;
+    cursor.label_range(text_at, msg_length as u64, || format!("text {:?}", text.chars().take(60).collect::<String>()));
+    cursor.label(unk8_at, || format!("unknown 8 {}", unk8));
+
+    log::debug!("id {}/{}/{}/{} speaker_id {} unk2345 {} {} {} {} {} {}: {}",
+        id1, id2, id3, id4,
+        speaker_id,
+        unk2, unk3, unk4, unk5, unk6, unk8, text);
+
+    Ok(QgmMessage{
+        id: [ id1, id2, id3, id4 ],
+        speaker_id, msg_id,
+        message_label,
+        dialog_options,
+        text,
+        flags,
+        unknown: [ unk2, unk3, unk4, unk5, unk6 ],
+        text_flag,
+        unknown8: unk8,
+    })
+}
+
+fn write_message(out: &mut Vec<u8>, m: &QgmMessage, codepage: Codepage) -> Result<()> {
+    let at = out.len() as u64;
+    let text = codepage.encode(&m.text)
+        .ok_or_else(|| Error::Invalid{ what: format!("text for {}", codepage.name()), at })?;
+    let text = if m.is_mangled() { mangle_text(&text) } else { text };
+    let msg_length = u16::try_from(text.len())
+        .map_err(|_| Error::Invalid{ what: format!("text length {}", text.len()), at })?;
+    let num_dialog_options = u16::try_from(m.dialog_options.len())
+        .map_err(|_| Error::Invalid{ what: format!("number of dialog options {}", m.dialog_options.len()), at })?;
+
+    let [ unk2, unk3, unk4, unk5, unk6 ] = m.unknown;
+    let fields = [ m.id[0], m.id[1], m.id[2], m.id[3], m.speaker_id, unk2, unk3, unk4, num_dialog_options,
+                   m.flags, unk5, m.msg_id, msg_length, m.text_flag, m.message_label.is_some() as u16, unk6 ];
+    for value in fields {
+        out.write_u16::<LittleEndian>(value).unwrap();
+    }
+    for label in m.message_label.iter().chain(&m.dialog_options) {
+        out.extend(label.value);
+        out.push(0);
+    }
+    out.extend(text);
+    out.write_u32::<LittleEndian>(m.unknown8).unwrap();
+    Ok(())
 }
 
 impl QgmDecoder {
+    /// Problems that do not stop decoding: messages whose ids do not fit in a label, and
+    /// messages with the same label. Dialog options and message labels are not checked, as they
+    /// may refer to other files; see dangling_references().
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let mut labels = std::collections::HashMap::new();
+        for (n, m) in self.messages.iter().enumerate() {
+            match QgmLabel::try_encode(self, m) {
+                None => problems.push(format!("message {}: ids {:?} do not fit in a label", n, m.id)),
+                Some(label) => {
+                    let first = *labels.entry(label.clone()).or_insert(n);
+                    if first != n {
+                        problems.push(format!("message {}: label {} is also used by message {}", n, label, first));
+                    }
+                },
+            }
+        }
+        problems
+    }
+
+    /// Messages of file `file_id` to encode, such as new dialog; the header fields are those of
+    /// the game files
+    pub fn from_messages(file_id: u16, messages: Vec<QgmMessage>) -> Self {
+        QgmDecoder{ version: 1, unknown1: 0, file_id, messages, codepage: Codepage::default(), truncated_at: None }
+    }
+
+    /// Decodes text as Windows-1252, see with_codepage()
     pub fn new(data: &[u8]) -> Result<Self> {
+        Self::read(&mut Reader::new(data))
+    }
+
+    /// Decodes text in the given codepage, which encode() then uses as well
+    pub fn with_codepage(data: &[u8], codepage: Codepage) -> Result<Self> {
+        Self::read_with(&mut Reader::new(data), codepage, false)
+    }
+
+    /// Like new(), but data that ends within a message gives the messages before it, with
+    /// truncated_at set, instead of an error
+    pub fn new_lenient(data: &[u8]) -> Result<Self> {
+        Self::read_with(&mut Reader::new(data), Codepage::default(), true)
+    }
+
+    /// The messages of one speaker, in the order of the file
+    pub fn messages_by_speaker(&self, speaker_id: u16) -> impl Iterator<Item = &QgmMessage> {
+        self.messages.iter().filter(move |m| m.speaker_id == speaker_id)
+    }
+
+    /// The first message with the ids of `label`, see QgmLabel::parse(); None if the label is
+    /// malformed or of another file
+    pub fn find_by_label(&self, label: &str) -> Option<&QgmMessage> {
+        let (file_id, id) = QgmLabel::parse(label)?;
+        if file_id != self.file_id {
+            return None;
+        }
+        self.messages.iter().find(|m| m.id == id)
+    }
+
+    /// The messages in the layout new() reads, for writing back edited or translated text.
+    /// The lengths are taken from the messages. Fails if a text does not fit, with the offset of its message.
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        out.write_u32::<LittleEndian>(0x51474d20).unwrap();
+        out.write_u32::<LittleEndian>(self.version).unwrap();
+        out.write_u32::<LittleEndian>(self.messages.len() as u32).unwrap();
+        out.write_u16::<LittleEndian>(self.unknown1).unwrap();
+        out.write_u16::<LittleEndian>(self.file_id).unwrap();
+        for (n, m) in self.messages.iter().enumerate() {
+            let at = out.len() as u64;
+            write_message(&mut out, m, self.codepage).with_context(at, || format!("message {}", n))?;
+        }
+        Ok(out)
+    }
+
+    pub(super) fn read(cursor: &mut Reader) -> Result<Self> {
+        Self::read_with(cursor, Codepage::default(), false)
+    }
+
+    fn read_with(cursor: &mut Reader, codepage: Codepage, lenient: bool) -> Result<Self> {
         // decode header (16 bytes)
-        let mut cursor = Cursor::new(data);
-        let magic = cursor.read_u32::<LittleEndian>()?;
-        if magic != 0x51474d20 { return Err(anyhow!("invalid magic")); }
-        let _version = cursor.read_u32::<LittleEndian>()?;
+        let magic = cursor.u32()?;
+        if magic != 0x51474d20 { return Err(Error::BadMagic{ expected: "'QGM '", found: magic, at: 0 }); }
+        cursor.label(0, || "magic 'QGM '".to_string());
+        let version = cursor.u32()?;
         // TODO verify version
-        let num_messages = cursor.read_u32::<LittleEndian>()?;
-        let _unk1 = cursor.read_u16::<LittleEndian>()?;
-        let file_id = cursor.read_u16::<LittleEndian>()?;
+        let num_messages = cursor.u32()?;
+        let unknown1 = cursor.u16()?;
+        let file_id = cursor.u16()?;
+        cursor.label_range(4, 4, || format!("version {}", version));
+        cursor.label_range(8, 4, || format!("number of messages {}", num_messages));
+        cursor.label_range(12, 2, || format!("unknown 1 {}", unknown1));
+        cursor.label_range(14, 2, || format!("file id {}", file_id));
 
+        // A lenient read keeps what there is, which it does without trusting the count up front
+        if !lenient {
+            cursor.check_remaining(num_messages as usize, MIN_MESSAGE_SIZE)?;
+        }
         let mut messages = Vec::new();
-        for _ in 0..num_messages {
-            // message block header (32 bytes)
-            let id1 = cursor.read_u16::<LittleEndian>()?;
-            let id2 = cursor.read_u16::<LittleEndian>()?;
-            let id3 = cursor.read_u16::<LittleEndian>()?;
-            let id4 = cursor.read_u16::<LittleEndian>()?;
-            let speaker_id = cursor.read_u16::<LittleEndian>()?; // maybe
-            let _unk2 = cursor.read_u16::<LittleEndian>()?;
-            let _unk3 = cursor.read_u16::<LittleEndian>()?;
-            let _unk4 = cursor.read_u16::<LittleEndian>()?;
-            let num_dialog_options = cursor.read_u16::<LittleEndian>()?;
-            let flags = cursor.read_u16::<LittleEndian>()?;
-            let _unk5 = cursor.read_u16::<LittleEndian>()?;
-            let msg_id  = cursor.read_u16::<LittleEndian>()?;
-            let msg_length = cursor.read_u16::<LittleEndian>()?;
-            let _msg_flag = cursor.read_u16::<LittleEndian>()?;
-            let msg_label_flag = cursor.read_u16::<LittleEndian>()?;
-            let _unk6 = cursor.read_u16::<LittleEndian>()?;
-
-            let message_label: Option<QgmLabel>;
-            if msg_label_flag != 0 {
-                let label = QgmLabel::new(&mut cursor)?;
-                message_label = Some(label);
-            } else {
-                message_label = None;
+        let mut truncated_at = None;
+        for n in 0..num_messages {
+            let at = cursor.position();
+            match read_message(cursor, codepage) {
+                Ok(message) => messages.push(message),
+                Err(Error::Truncated{ .. }) if lenient => {
+                    truncated_at = Some(at);
+                    break;
+                },
+                Err(e) => return Err(e).with_context(at, || format!("message {}", n)),
             }
+        }
+        Ok(QgmDecoder{ version, unknown1, file_id, messages, codepage, truncated_at })
+    }
+}
 
-            let mut dialog_options = Vec::new();
-            for _ in 0..num_dialog_options {
-                let label = QgmLabel::new(&mut cursor)?;
-                dialog_options.push(label);
-            }
+/// Reads the messages of a QGM file one at a time, for going through many files without
+/// keeping all their messages. The header is read up front; the messages are decoded as
+/// QgmDecoder::new() does. Iteration ends after the first error, such as the data ending
+/// within a message.
+pub struct QgmReader<R: Read> {
+    source: R,
+    pub version: u32,
+    pub unknown1: u16,
+    pub file_id: u16,
+    pub num_messages: u32,
+    codepage: Codepage,
+    /// Messages read so far
+    count: u32,
+    position: u64,
+    failed: bool,
+}
+
+impl<R: Read> QgmReader<R> {
+    /// Decodes text as Windows-1252, see with_codepage()
+    pub fn new(source: R) -> Result<Self> {
+        Self::with_codepage(source, Codepage::default())
+    }
 
-            let mut text_data = vec![ 0u8; msg_length as usize ];
-            cursor.read_exact(&mut text_data)?;
-            let _unk8 = cursor.read_u32::<LittleEndian>()?;
+    pub fn with_codepage(mut source: R, codepage: Codepage) -> Result<Self> {
+        let mut header = [ 0u8; 16 ];
+        read_exact_at(&mut source, &mut header, 0)?;
+        let magic = LittleEndian::read_u32(&header[0..4]);
+        if magic != 0x51474d20 { return Err(Error::BadMagic{ expected: "'QGM '", found: magic, at: 0 }); }
+        Ok(QgmReader{
+            source,
+            version: LittleEndian::read_u32(&header[4..8]),
+            num_messages: LittleEndian::read_u32(&header[8..12]),
+            unknown1: LittleEndian::read_u16(&header[12..14]),
+            file_id: LittleEndian::read_u16(&header[14..16]),
+            codepage,
+            count: 0,
+            position: header.len() as u64,
+            failed: false,
+        })
+    }
 
-            let text = if (flags & FLAG_TEXT_MANGLED) != 0 {
-                demangle_text(&text_data)
-            } else {
-                String::from_utf8(text_data)?
-            };
+    /// Reads the header of a message for its size, then the rest of it
+    fn read_message(&mut self) -> Result<QgmMessage> {
+        let at = self.position;
+        let mut data = vec![ 0u8; 32 ];
+        read_exact_at(&mut self.source, &mut data, at)?;
+        let num_dialog_options = LittleEndian::read_u16(&data[16..18]) as usize;
+        let msg_length = LittleEndian::read_u16(&data[24..26]) as usize;
+        let num_labels = num_dialog_options + (LittleEndian::read_u16(&data[28..30]) != 0) as usize;
+        data.resize(32 + 13 * num_labels + msg_length + 4, 0);
+        read_exact_at(&mut self.source, &mut data[32..], at + 32)?;
+        self.position += data.len() as u64;
+        read_message(&mut Reader::new(&data), self.codepage).map_err(|mut e| { e.move_by(at); e })
+    }
+}
 
-            log::debug!("id {}/{}/{}/{} speaker_id {} unk2345 {} {} {} {} {} {}: {}",
-                id1, id2, id3, id4,
-                speaker_id,
-                _unk2, _unk3, _unk4, _unk5, _unk6, _unk8, text);
+impl<R: Read> Iterator for QgmReader<R> {
+    type Item = Result<QgmMessage>;
 
-            messages.push(QgmMessage{
-                id: [ id1, id2, id3, id4 ],
-                speaker_id, msg_id,
-                message_label,
-                dialog_options,
-                text,
-            });
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed || self.count == self.num_messages {
+            return None;
         }
-        Ok(QgmDecoder{ file_id, messages })
+        let (n, at) = (self.count, self.position);
+        let result = self.read_message().with_context(at, || format!("message {}", n));
+        self.count += 1;
+        self.failed = result.is_err();
+        Some(result)
     }
 }
 
+/// Fills `buf`, which starts at offset `at`; fails if the data ends before that
+fn read_exact_at<R: Read>(source: &mut R, buf: &mut [u8], at: u64) -> Result<()> {
+    let mut got = 0;
+    while got < buf.len() {
+        match source.read(&mut buf[got..]) {
+            Ok(0) => return Err(Error::Truncated{ needed: buf.len(), got, at }),
+            Ok(n) => got += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => { },
+            Err(source) => return Err(Error::Io{ source, at: at + got as u64 }),
+        }
+    }
+    Ok(())
+}
+
+/// A reference of a message, see dangling_references()
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Reference {
+    /// Numbered from 1
+    DialogOption(usize),
+    MessageLabel,
+}
+
+impl fmt::Display for Reference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reference::DialogOption(n) => write!(f, "dialog option {}", n),
+            Reference::MessageLabel => write!(f, "message label"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DanglingReference {
+    /// Index of the file in the files given
+    pub file: usize,
+    /// Label of the message with the reference, or its index if its ids do not fit in a label
+    pub source: String,
+    pub reference: Reference,
+    /// The label referred to, without the zero bytes and spaces after it
+    pub target: String,
+}
+
+impl fmt::Display for DanglingReference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}: {} does not exist", self.source, self.reference, self.target)
+    }
+}
+
+/// The dialog options and message labels of the messages in `qgms` that refer to a label none
+/// of them has, by file and in the order of the messages
+pub fn dangling_references(qgms: &[&QgmDecoder]) -> Vec<DanglingReference> {
+    let labels: std::collections::HashSet<_> = qgms.iter()
+        .flat_map(|qgm| qgm.messages.iter().filter_map(|m| QgmLabel::try_encode(qgm, m)))
+        .collect();
+    let mut dangling = Vec::new();
+    for (file, qgm) in qgms.iter().enumerate() {
+        for (n, m) in qgm.messages.iter().enumerate() {
+            let references = m.dialog_options.iter().enumerate()
+                .map(|(n, label)| (Reference::DialogOption(n + 1), label))
+                .chain(m.message_label.iter().map(|label| (Reference::MessageLabel, label)));
+            for (reference, label) in references {
+                let target = label.to_string();
+                let target = target.trim_end_matches([ '\0', ' ' ]);
+                if labels.contains(target) {
+                    continue;
+                }
+                let source = QgmLabel::try_encode(qgm, m).unwrap_or_else(|| format!("message {}", n));
+                dangling.push(DanglingReference{ file, source, reference, target: target.to_string() });
+            }
+        }
+    }
+    dangling
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::qfg5resource::fixtures;
 
     #[test]
     fn test_encode_digit_base_36() {
@@ -200,4 +718,307 @@ mod tests {
         assert_eq!(encode_base_36(415, 3), Some("0BJ".to_string()));
         assert!(encode_base_36(36, 1).is_none());
     }
+
+    #[test]
+    fn test_decode_digit_base_36() {
+        assert_eq!(decode_digit_base_36('0'), Some(0));
+        assert_eq!(decode_digit_base_36('9'), Some(9));
+        assert_eq!(decode_digit_base_36('A'), Some(10));
+        assert_eq!(decode_digit_base_36('Z'), Some(35));
+        for ch in [ 'a', 'z', '.', ' ', '[', '@', '\u{c0}' ] {
+            assert!(decode_digit_base_36(ch).is_none(), "{}", ch);
+        }
+    }
+
+    #[test]
+    fn test_decode_base_36() {
+        assert_eq!(decode_base_36(""), Some(0));
+        assert_eq!(decode_base_36("00000"), Some(0));
+        assert_eq!(decode_base_36("10"), Some(36));
+        assert_eq!(decode_base_36("ZZZ"), Some(46655));
+        assert_eq!(decode_base_36("0BJ"), Some(415));
+        assert!(decode_base_36("0bj").is_none());
+        assert!(decode_base_36("1-").is_none());
+        // 36^4 does not fit in a u16
+        assert!(decode_base_36("ZZZZ").is_none());
+        for v in [ 0, 1, 35, 36, 1295, 46655 ] {
+            assert_eq!(decode_base_36(&encode_base_36(v, 3).unwrap()), Some(v));
+        }
+    }
+
+    #[test]
+    fn test_parse_label() {
+        assert_eq!(QgmLabel::parse("0BJ0102.034"), Some((415, [ 1, 2, 3, 4 ])));
+        assert_eq!(QgmLabel::parse("0BJ0102.034\0"), Some((415, [ 1, 2, 3, 4 ])));
+        assert_eq!(QgmLabel::parse("ZZZZZZZ.ZZZ"), Some((46655, [ 1295, 1295, 1295, 35 ])));
+        for label in [ "", "0BJ0102034", "0BJ0102.0345", "0BJ010.2034", "0bj0102.034", "0BJ0102.03-", "0BJ01é2.034" ] {
+            assert!(QgmLabel::parse(label).is_none(), "{}", label);
+        }
+    }
+
+    #[test]
+    fn test_label_from_str() {
+        let label: QgmLabel = "0BJ0102.034".parse().unwrap();
+        assert_eq!(label.to_string(), "0BJ0102.034\0");
+        assert_eq!("LABEL0000001".parse::<QgmLabel>().unwrap().to_string(), "LABEL0000001");
+        assert_eq!("LABEL00000012".parse::<QgmLabel>().unwrap_err(), "label 'LABEL00000012' is not at most 12 ASCII characters");
+        assert!("LABEL000000é".parse::<QgmLabel>().is_err());
+
+        assert_eq!(QgmLabel::from_ids(415, [ 1, 2, 3, 4 ]), Some(label));
+        assert_eq!(QgmLabel::from_ids(415, [ 1, 2, 3, 36 ]), None);
+    }
+
+    #[test]
+    fn test_from_messages() {
+        let mut m = QgmMessage::new([ 1, 2, 3, 4 ], 5, "Hello");
+        m.msg_id = 7;
+        m.message_label = Some("LABEL0000001".parse().unwrap());
+        m.dialog_options.push("OPTION000001".parse().unwrap());
+        let qgm = QgmDecoder::from_messages(415, vec![ m ]);
+        let data = qgm.encode().unwrap();
+        assert_eq!(data, fixtures::qgm());
+        assert_eq!(QgmDecoder::new(&data).unwrap(), qgm);
+
+        // Dialog between messages of its own
+        let question = QgmMessage{ dialog_options: vec![ QgmLabel::from_ids(7, [ 0, 0, 1, 0 ]).unwrap() ],
+            ..QgmMessage::new([ 0, 0, 0, 0 ], 1, "Who are you?") };
+        let answer = QgmMessage{ flags: FLAG_TEXT_MANGLED, ..QgmMessage::new([ 0, 0, 1, 0 ], 2, "A wizard.") };
+        let qgm = QgmDecoder::from_messages(7, vec![ question, answer ]);
+        let decoded = QgmDecoder::new(&qgm.encode().unwrap()).unwrap();
+        assert_eq!(decoded, qgm);
+        assert!(dangling_references(&[ &decoded ]).is_empty());
+    }
+
+    #[test]
+    fn test_audio_name() {
+        let qgm = QgmDecoder::new(&fixtures::qgm()).unwrap();
+        assert_eq!(qgm.messages[0].audio_name(qgm.file_id), "0BJ0102034");
+        assert_eq!(qgm.messages[0].audio_name(0), "0000102034");
+        assert_eq!(qgm.messages[0].try_audio_name(46656), None);
+    }
+
+    #[test]
+    fn test_find_by_label() {
+        let qgm = QgmDecoder::new(&fixtures::qgm()).unwrap();
+        let label = QgmLabel::encode(&qgm, &qgm.messages[0]);
+        assert_eq!(qgm.find_by_label(&label), Some(&qgm.messages[0]));
+        assert!(qgm.find_by_label("0BJ0102.035").is_none());
+        // Another file
+        assert!(qgm.find_by_label("0BK0102.034").is_none());
+        assert!(qgm.find_by_label("hello").is_none());
+    }
+
+    #[test]
+    fn test_speaker_table() {
+        let speakers = SpeakerTable::parse("id,name\n# the hero\n1,Hero\r\n\n5, \"Erasmus, the \"\"wizard\"\"\"\n").unwrap();
+        assert_eq!(speakers.name(1), Some("Hero"));
+        assert_eq!(speakers.name(5), Some("Erasmus, the \"wizard\""));
+        assert_eq!(speakers.name(2), None);
+        assert_eq!(speakers.resolve("hero"), Some(1));
+        assert_eq!(speakers.resolve("7"), Some(7));
+        assert_eq!(speakers.resolve("Fenrus"), None);
+
+        let err = SpeakerTable::parse("1,Hero\nx,Fenrus\n").unwrap_err();
+        assert_eq!(err.to_string(), "invalid speaker line 'x,Fenrus' at offset 0x7");
+        assert!(SpeakerTable::parse("70000,Hero").is_err());
+        assert!(SpeakerTable::parse("Hero").is_err());
+    }
+
+    #[test]
+    fn test_messages_by_speaker() {
+        let mut qgm = QgmDecoder::new(&fixtures::qgm()).unwrap();
+        qgm.messages.push(QgmMessage{ speaker_id: 6, ..qgm.messages[0].clone() });
+        qgm.messages.push(QgmMessage{ msg_id: 8, ..qgm.messages[0].clone() });
+        let ids: Vec<_> = qgm.messages_by_speaker(5).map(|m| m.msg_id).collect();
+        assert_eq!(ids, [ 7, 8 ]);
+        assert_eq!(qgm.messages_by_speaker(1).count(), 0);
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut qgm = QgmDecoder::new(&fixtures::qgm()).unwrap();
+        assert!(qgm.validate().is_empty());
+        qgm.messages.push(qgm.messages[0].clone());
+        qgm.messages.push(QgmMessage{ id: [ 1, 2, 3, 36 ], ..qgm.messages[0].clone() });
+        assert_eq!(qgm.validate(), [ "message 1: label 0BJ0102.034 is also used by message 0", "message 2: ids [1, 2, 3, 36] do not fit in a label" ]);
+    }
+
+    #[test]
+    fn test_dangling_references() {
+        let qgm = QgmDecoder::new(&fixtures::qgm()).unwrap();
+        let dangling = dangling_references(&[ &qgm ]);
+        assert_eq!(dangling, [
+            DanglingReference{ file: 0, source: "0BJ0102.034".to_string(), reference: Reference::DialogOption(1), target: "OPTION000001".to_string() },
+            DanglingReference{ file: 0, source: "0BJ0102.034".to_string(), reference: Reference::MessageLabel, target: "LABEL0000001".to_string() },
+        ]);
+        assert_eq!(dangling[0].to_string(), "0BJ0102.034 dialog option 1: OPTION000001 does not exist");
+
+        // Answered by a message in another file, and by a message of the same file
+        let mut other = qgm.clone();
+        other.file_id = 416;
+        other.messages[0].dialog_options.clear();
+        other.messages[0].message_label = None;
+        let mut qgm = qgm;
+        qgm.messages[0].dialog_options[0] = "0BK0102.034\0".to_string().try_into().unwrap();
+        qgm.messages[0].message_label = Some("0BJ0102.034 ".to_string().try_into().unwrap());
+        assert!(dangling_references(&[ &qgm, &other ]).is_empty());
+        assert!(dangling_references(&[ &other, &qgm ]).is_empty());
+        let dangling = dangling_references(&[ &qgm ]);
+        assert_eq!(dangling.len(), 1);
+        assert_eq!(dangling[0].to_string(), "0BJ0102.034 dialog option 1: 0BK0102.034 does not exist");
+    }
+
+    #[test]
+    fn test_mangle_text() {
+        for text in [ &b""[..], b"abc", b"Hello", b"Hello, world" ] {
+            let mangled = mangle_text(text);
+            assert_eq!(mangled.len(), text.len());
+            assert_eq!(demangle_text(&mangled), text);
+        }
+    }
+
+    #[test]
+    fn test_codepage() {
+        let all: Vec<u8> = (0..=255).collect();
+        let text = Codepage::Windows1252.decode(&all).unwrap();
+        assert_eq!(text.chars().count(), 256);
+        assert_eq!(Codepage::Windows1252.encode(&text).unwrap(), all);
+        assert_eq!(Codepage::Windows1252.decode(b"\x80 \x92 \x9f \xe9 \xfc"), Some("\u{20ac} \u{2019} \u{178} \u{e9} \u{fc}".to_string()));
+        // Characters 0x80-0x9f are replaced by others, except where Windows-1252 has a gap
+        assert_eq!(Codepage::Windows1252.encode("\u{81}"), Some(vec![ 0x81 ]));
+        assert!(Codepage::Windows1252.encode("\u{80}").is_none());
+        assert!(Codepage::Windows1252.encode("\u{263a}").is_none());
+        assert!(Codepage::Utf8.decode(b"\xe9").is_none());
+        assert_eq!(Codepage::Utf8.encode("\u{e9}"), Some(vec![ 0xc3, 0xa9 ]));
+    }
+
+    #[test]
+    fn test_text_codepage() {
+        // The text of the fixture is 5 bytes at 0x4a
+        let text_at = 0x4a;
+        let mut data = fixtures::qgm();
+        data[text_at..text_at + 5].copy_from_slice(b"Caf\xe9\x80");
+        let qgm = QgmDecoder::new(&data).unwrap();
+        assert_eq!(qgm.messages[0].text, "Caf\u{e9}\u{20ac}");
+        assert_eq!(qgm.encode().unwrap(), data);
+        let err = QgmDecoder::with_codepage(&data, Codepage::Utf8).unwrap_err();
+        assert_eq!(err.to_string(), "message 0 at offset 0x10: invalid UTF-8 message text at offset 0x4a");
+
+        // Mangled text is decoded the same way after demangling
+        data[text_at..text_at + 5].copy_from_slice(&mangle_text(b"\xfcber!"));
+        data[0x10 + 18] = FLAG_TEXT_MANGLED as u8;
+        let qgm = QgmDecoder::new(&data).unwrap();
+        assert_eq!(qgm.messages[0].text, "\u{fc}ber!");
+        assert_eq!(qgm.encode().unwrap(), data);
+
+        let mut utf8 = QgmDecoder::with_codepage(&fixtures::qgm(), Codepage::Utf8).unwrap();
+        utf8.messages[0].text = "\u{263a}".to_string();
+        let encoded = utf8.encode().unwrap();
+        assert_eq!(QgmDecoder::with_codepage(&encoded, Codepage::Utf8).unwrap(), utf8);
+    }
+
+    #[test]
+    fn test_encode() {
+        let data = fixtures::qgm();
+        let qgm = QgmDecoder::new(&data).unwrap();
+        assert_eq!(qgm.encode().unwrap(), data);
+
+        let mut edited = qgm.clone();
+        edited.unknown1 = 3;
+        edited.messages[0].unknown = [ 1, 2, 3, 4, 5 ];
+        edited.messages[0].text_flag = 6;
+        edited.messages[0].unknown8 = 0x12345678;
+        edited.messages[0].text = "Guten Tag, Fremder".to_string();
+        edited.messages[0].message_label = None;
+        let mut mangled = qgm.messages[0].clone();
+        mangled.flags = FLAG_TEXT_MANGLED;
+        mangled.text = "Caf\u{e9}".to_string();
+        mangled.dialog_options.clear();
+        edited.messages.push(mangled);
+        let encoded = edited.encode().unwrap();
+        assert_eq!(QgmDecoder::new(&encoded).unwrap(), edited);
+        assert!(!edited.messages[0].is_mangled() && edited.messages[1].is_mangled());
+        // The mangled text is not stored as it is
+        assert!(!encoded.windows(4).any(|w| w == b"Caf\xe9"));
+
+        edited.messages[1].text = "\u{263a}".to_string();
+        let err = edited.encode().unwrap_err();
+        assert_eq!(err.to_string(), "message 1 at offset 0x53: invalid text for Windows-1252 at offset 0x53");
+        edited.messages[1].flags = 0;
+        edited.messages[1].text = "x".repeat(65536);
+        assert!(matches!(edited.encode().unwrap_err().root(), Error::Invalid{ .. }));
+    }
+
+    #[test]
+    fn test_lenient() {
+        let mut data = fixtures::qgm();
+        let length = data.len();
+        data.extend_from_within(16..);
+        data[8] = 2;
+        let qgm = QgmDecoder::new_lenient(&data).unwrap();
+        assert_eq!((qgm.messages.len(), qgm.truncated_at), (2, None));
+
+        // Anywhere in the second message
+        for end in [ length, length + 1, length + 40, data.len() - 1 ] {
+            assert!(QgmDecoder::new(&data[..end]).is_err());
+            let qgm = QgmDecoder::new_lenient(&data[..end]).unwrap();
+            assert_eq!((qgm.messages.len(), qgm.truncated_at), (1, Some(length as u64)));
+            assert_eq!(qgm.messages[0], QgmDecoder::new(&fixtures::qgm()).unwrap().messages[0]);
+        }
+        // Nothing to go on without the header, and other errors still fail
+        assert!(QgmDecoder::new_lenient(&data[..10]).is_err());
+        // The zero byte after the label of the second message
+        data[length + 32 + 12] = b'X';
+        assert!(matches!(QgmDecoder::new_lenient(&data).unwrap_err().root(), Error::Invalid{ .. }));
+    }
+
+    #[test]
+    fn test_qgm_reader() {
+        let mut qgm = QgmDecoder::new(&fixtures::qgm()).unwrap();
+        qgm.messages.push(QgmMessage{ id: [ 1, 2, 3, 5 ], flags: FLAG_TEXT_MANGLED, message_label: None, ..qgm.messages[0].clone() });
+        qgm.messages.push(QgmMessage{ id: [ 1, 2, 3, 6 ], text: "Caf\u{e9}".to_string(), dialog_options: Vec::new(), ..qgm.messages[0].clone() });
+        let data = qgm.encode().unwrap();
+        let reader = QgmReader::new(&data[..]).unwrap();
+        assert_eq!((reader.version, reader.unknown1, reader.file_id, reader.num_messages), (qgm.version, qgm.unknown1, qgm.file_id, 3));
+        let messages: Vec<_> = reader.collect::<Result<_>>().unwrap();
+        assert_eq!(messages, qgm.messages);
+
+        // The data ends within the last message, as new_lenient() stops there
+        let lenient = QgmDecoder::new_lenient(&data[..data.len() - 1]).unwrap();
+        let mut reader = QgmReader::new(&data[..data.len() - 1]).unwrap();
+        for m in &lenient.messages {
+            assert_eq!(reader.next().unwrap().unwrap(), *m);
+        }
+        let err = reader.next().unwrap().unwrap_err();
+        assert!(matches!(err.root(), Error::Truncated{ .. }));
+        // After the header of the message, where the rest of it is read
+        assert_eq!(err.offset(), lenient.truncated_at.unwrap() + 32);
+        assert!(err.to_string().starts_with(&format!("message 2 at offset {:#x}: truncated", lenient.truncated_at.unwrap())), "{}", err);
+        assert!(reader.next().is_none());
+
+        // Errors within a message have its offset in the file
+        let mut data = fixtures::qgm();
+        data[16 + 32 + 12] = b'x';
+        let err = QgmReader::new(&data[..]).unwrap().next().unwrap().unwrap_err();
+        assert_eq!(err.to_string(), QgmDecoder::new(&data).unwrap_err().to_string());
+        assert!(matches!(QgmReader::new(&data[..10]), Err(Error::Truncated{ needed: 16, got: 10, at: 0 })));
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut qgm = fixtures::qgm();
+        assert!(matches!(QgmDecoder::new(&qgm[..10]), Err(Error::Truncated{ needed: 4, got: 2, at: 8 })));
+        qgm[0] = b'X';
+        assert!(matches!(QgmDecoder::new(&qgm), Err(Error::BadMagic{ expected: "'QGM '", at: 0, .. })));
+        let qgm = fixtures::qgm();
+        let err = QgmDecoder::new(&qgm[..qgm.len() - 1]).unwrap_err();
+        assert_eq!(err.to_string(), "message 0 at offset 0x10: truncated at offset 0x4f: needed 4 bytes, got 3");
+
+        // A huge number of messages fails before reading any
+        let mut qgm = fixtures::qgm();
+        qgm[8..12].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(QgmDecoder::new(&qgm), Err(Error::Truncated{ at: 0x10, .. })));
+        assert!(QgmDecoder::new(&qgm[..16]).is_err());
+        assert_eq!(QgmDecoder::new_lenient(&qgm).unwrap().messages.len(), 1);
+    }
 }
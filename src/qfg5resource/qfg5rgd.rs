@@ -4,130 +4,312 @@
  * Copyright (c) 2024 Rink Springer <rink@rink.nu>
  * For conditions of distribution and use, see LICENSE file
  */
-use anyhow::{anyhow, Result};
-use byteorder::LittleEndian;
-use byteorder::ReadBytesExt;
-use std::io::{Cursor, Seek, SeekFrom};
+use crate::qfg5resource::decode::{self, Reader};
+use crate::qfg5resource::error::{Error, Result};
+use crate::qfg5resource::readutil::{read_part, read_vec_u32};
 
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct RgdDecoder {
+    pub points: Vec<RgdPoint>,
+    pub vectors: Vec<RgdVector>,
+    pub segments: Vec<RgdSegment>,
+    pub regions: Vec<RgdRegion>,
+    pub region_ids: Vec<u32>,
+    pub special_region_ids: Vec<u32>,
 }
 
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct RgdPoint {
     pub x: f64,
     pub y: f64,
 }
 
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct RgdVector {
     pub x: f64,
     pub y: f64,
     pub z: f64,
 }
 
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct RgdSegment {
     pub point1: usize,
     pub point2: usize,
     pub regionid_offset: u64,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct RgdRegion {
     pub vector_index: usize,
     pub offset_segment_ids: u64,
+    pub segment_ids: Vec<usize>,
 }
 
 impl RgdDecoder {
     pub fn new(rgd_data: &[u8]) -> Result<Self> {
-        let mut cursor = Cursor::new(&rgd_data);
-        let a = cursor.read_u32::<LittleEndian>()?;
-        if a != 0 { return Err(anyhow!("a mismatch")); }
-        let b = cursor.read_u32::<LittleEndian>()?;
-        if b != 2 { return Err(anyhow!("b mismatch")); }
+        Self::read(&mut Reader::new(rgd_data))
+    }
+
+    pub(super) fn read(cursor: &mut Reader) -> Result<Self> {
+        let a = cursor.u32()?;
+        if a != 0 { return Err(Error::Unsupported{ what: format!("a value {}", a), at: 0 }); }
+        let b = cursor.u32()?;
+        if b != 2 { return Err(Error::Unsupported{ what: format!("b value {}", b), at: 4 }); }
         // [ok] total number of regions;
-        let num_regions = cursor.read_u32::<LittleEndian>()? as usize;
+        let num_regions = cursor.u32()? as usize;
         // [ok] region data offset (each region includes among other things a 3-D vector index and an offset to a list of segment IDs);
-        let offset_region_data = cursor.read_u32::<LittleEndian>()? as u64;
+        let offset_region_data = cursor.u32()? as u64;
 
         // TODO: offset to a list of offsets, data those offsets has a list of vector indices
-        let _offset_to_list_of_offsets = cursor.read_u32::<LittleEndian>()? as u64;
+        let _offset_to_list_of_offsets = cursor.u32()? as u64;
         // some ignored offset;
-        let _ignored_offset = cursor.read_u32::<LittleEndian>()? as u64;
+        let _ignored_offset = cursor.u32()? as u64;
         // TODO: offset to an array of some region positioning information
-        let _offset_region_pos_info = cursor.read_u32::<LittleEndian>()? as u64;
+        let _offset_region_pos_info = cursor.u32()? as u64;
 
         // [ok] total number of regions
-        let num_regions2 = cursor.read_u32::<LittleEndian>()? as usize;
+        let num_regions2 = cursor.u32()? as usize;
         // [ok] offset to a full list of region IDs
-        let offset_full_list_regionids = cursor.read_u32::<LittleEndian>()? as u64;
+        let offset_full_list_regionids = cursor.u32()? as u64;
 
         // TODO: total number of region IDs
-        let _num_regions3 = cursor.read_u32::<LittleEndian>()? as usize;
+        let _num_regions3 = cursor.u32()? as usize;
         // data start offset (seems to be always 0x5C)?
-        let offset_data_start = cursor.read_u32::<LittleEndian>()? as u64;
-        if offset_data_start != 0x5c { return Err(anyhow!("invalid data start offset {:x}", offset_data_start)); }
+        let offset_data_start = cursor.u32()? as u64;
+        if offset_data_start != 0x5c { return Err(Error::Invalid{ what: format!("data start offset {:x}", offset_data_start), at: 40 }); }
         // [ok] number of segments
-        let num_segments = cursor.read_u32::<LittleEndian>()? as usize;
+        let num_segments = cursor.u32()? as usize;
         // [ok] offset to segment data (which includes two point indices and an offset to region ID list);
-        let offset_segment_data = cursor.read_u32::<LittleEndian>()? as u64;
+        let offset_segment_data = cursor.u32()? as u64;
         // [ok] number of points 
-        let num_points = cursor.read_u32::<LittleEndian>()? as usize;
+        let num_points = cursor.u32()? as usize;
         // [ok] offset to point data (two doubles per point)
-        let offset_point_data = cursor.read_u32::<LittleEndian>()? as u64;
+        let offset_point_data = cursor.u32()? as u64;
         // [ok] number of vectors
-        let num_vectors = cursor.read_u32::<LittleEndian>()? as usize;
+        let num_vectors = cursor.u32()? as usize;
         // [ok] offset to vector data (three doubles per vector)
-        let offset_vector_data = cursor.read_u32::<LittleEndian>()? as u64;
+        let offset_vector_data = cursor.u32()? as u64;
         // TODO: flag signalling that the following fields are meaningful
-        let _flag = cursor.read_u32::<LittleEndian>()?;
+        let _flag = cursor.u32()?;
         // TODO: number of special (walkable?) regions
-        let _num_special_regions = cursor.read_u32::<LittleEndian>()? as usize;
+        let num_special_regions = cursor.u32()? as usize;
         // TODO: connectivity matrix offset (that number of regions squared, -1 and -2 mean there’s no connection)
-        let _connectivity_matrix1_offset = cursor.read_u32::<LittleEndian>()? as u64;
+        let _connectivity_matrix1_offset = cursor.u32()? as u64;
         // TODO: another connectivity matrix (in the same format) offset
-        let _connectivity_matrix2_offset = cursor.read_u32::<LittleEndian>()? as u64;
+        let _connectivity_matrix2_offset = cursor.u32()? as u64;
         // TODO: offset to the list of special region IDs.
-        let _offset_special_region_ids = cursor.read_u32::<LittleEndian>()? as u64;
-
-        cursor.seek(SeekFrom::Start(offset_point_data))?;
-        let mut points = Vec::with_capacity(num_points);
-        for _ in 0..num_points {
-            let x = cursor.read_f64::<LittleEndian>()?;
-            let y = cursor.read_f64::<LittleEndian>()?;
-            points.push(RgdPoint{ x, y });
+        let offset_special_region_ids = cursor.u32()? as u64;
+        let fields = [ ("a", a as u64), ("b", b as u64), ("number of regions", num_regions as u64), ("region data offset", offset_region_data),
+                       ("unknown offset list offset", _offset_to_list_of_offsets), ("ignored offset", _ignored_offset),
+                       ("region positioning offset", _offset_region_pos_info), ("number of region IDs", num_regions2 as u64),
+                       ("region IDs offset", offset_full_list_regionids), ("unknown count", _num_regions3 as u64),
+                       ("data start offset", offset_data_start), ("number of segments", num_segments as u64),
+                       ("segment data offset", offset_segment_data), ("number of points", num_points as u64),
+                       ("point data offset", offset_point_data), ("number of vectors", num_vectors as u64),
+                       ("vector data offset", offset_vector_data), ("flag", _flag as u64),
+                       ("number of special regions", num_special_regions as u64),
+                       ("connectivity matrix 1 offset", _connectivity_matrix1_offset),
+                       ("connectivity matrix 2 offset", _connectivity_matrix2_offset),
+                       ("special region IDs offset", offset_special_region_ids) ];
+        for (n, (what, value)) in fields.into_iter().enumerate() {
+            cursor.label_range(4 * n as u64, 4, || format!("{} {:#x}", what, value));
         }
 
-        cursor.seek(SeekFrom::Start(offset_vector_data))?;
-        let mut vectors = Vec::with_capacity(num_vectors);
-        for _ in 0..num_vectors {
-            let x = cursor.read_f64::<LittleEndian>()?;
-            let y = cursor.read_f64::<LittleEndian>()?;
-            let z = cursor.read_f64::<LittleEndian>()?;
-            vectors.push(RgdVector{ x, y, z });
+        let points = read_part(cursor, offset_point_data, 56, || "points".to_string(), |cursor| {
+            cursor.check_remaining(num_points, 16)?;
+            let mut points = Vec::with_capacity(num_points);
+            for n in 0..num_points {
+                let at = cursor.position();
+                let x = cursor.f64()?;
+                let y = cursor.f64()?;
+                cursor.label(at, || format!("point {} ({}, {})", n, x, y));
+                points.push(RgdPoint{ x, y });
+            }
+            Ok(points)
+        })?;
+
+        let vectors = read_part(cursor, offset_vector_data, 64, || "vectors".to_string(), |cursor| {
+            cursor.check_remaining(num_vectors, 24)?;
+            let mut vectors = Vec::with_capacity(num_vectors);
+            for n in 0..num_vectors {
+                let at = cursor.position();
+                let x = cursor.f64()?;
+                let y = cursor.f64()?;
+                let z = cursor.f64()?;
+                cursor.label(at, || format!("vector {} ({}, {}, {})", n, x, y, z));
+                vectors.push(RgdVector{ x, y, z });
+            }
+            Ok(vectors)
+        })?;
+
+        let segments = read_part(cursor, offset_segment_data, 48, || "segments".to_string(), |cursor| {
+            cursor.check_remaining(num_segments, 16)?;
+            let mut segments = Vec::with_capacity(num_segments);
+            for n in 0..num_segments {
+                let at = cursor.position();
+                let point1 = cursor.u32()? as usize;
+                let point2 = cursor.u32()? as usize;
+                let regionid_offset = cursor.u32()? as u64;
+                cursor.label(at, || format!("segment {}: points {} and {}, region ID offset {:#x}", n, point1, point2, regionid_offset));
+                let unknown_at = cursor.position();
+                let _unknown = cursor.u32()?;
+                cursor.label(unknown_at, || format!("segment {}: unknown {:#x}", n, _unknown));
+                segments.push(RgdSegment{ point1, point2, regionid_offset });
+            }
+            Ok(segments)
+        })?;
+
+        let region_ids = read_part(cursor, offset_full_list_regionids, 32, || "region IDs".to_string(), |cursor| {
+            let ids = read_vec_u32(cursor, num_regions2)?;
+            cursor.label(offset_full_list_regionids, || format!("region IDs {:?}", ids));
+            Ok(ids)
+        })?;
+
+        let mut regions = read_part(cursor, offset_region_data, 12, || "regions".to_string(), |cursor| {
+            cursor.check_remaining(num_regions, 8)?;
+            let mut regions = Vec::with_capacity(num_regions);
+            for n in 0..num_regions {
+                let at = cursor.position();
+                let vector_index = cursor.u32()? as usize;
+                let offset_segment_ids = cursor.u32()? as u64;
+                cursor.label(at, || format!("region {}: vector {}, segment IDs offset {:#x}", n, vector_index, offset_segment_ids));
+                regions.push(RgdRegion{ vector_index, offset_segment_ids, segment_ids: Vec::new() });
+            }
+            Ok(regions)
+        })?;
+
+        // TODO: the segment ID list is assumed to be a u32 count followed by that many u32 IDs
+        let mut segment_id_budget = decode::Budget::new("region segment IDs", cursor.size() / 4);
+        for (n, region) in regions.iter_mut().enumerate() {
+            let at = offset_region_data + 8 * n as u64 + 4;
+            region.segment_ids = read_part(cursor, region.offset_segment_ids, at, || format!("region {} segment IDs", n), |cursor| {
+                let num_segment_ids = cursor.u32()? as usize;
+                segment_id_budget.take(num_segment_ids, region.offset_segment_ids)?;
+                let ids = read_vec_u32(cursor, num_segment_ids)?;
+                cursor.label(region.offset_segment_ids, || format!("region {} segment IDs {:?}", n, ids));
+                Ok(ids.into_iter().map(|id| id as usize).collect())
+            })?;
         }
+        log::debug!("regions {:x?}", regions);
 
-        cursor.seek(SeekFrom::Start(offset_segment_data))?;
-        let mut segments = Vec::with_capacity(num_segments);
-        for _ in 0..num_segments {
-            let point1 = cursor.read_u32::<LittleEndian>()? as usize;
-            let point2 = cursor.read_u32::<LittleEndian>()? as usize;
-            let regionid_offset = cursor.read_f64::<LittleEndian>()? as u64;
-            segments.push(RgdSegment{ point1, point2, regionid_offset });
+        let mut special_region_ids = Vec::new();
+        if num_special_regions > 0 {
+            special_region_ids = read_part(cursor, offset_special_region_ids, 84, || "special region IDs".to_string(), |cursor| {
+                let ids = read_vec_u32(cursor, num_special_regions)?;
+                cursor.label(offset_special_region_ids, || format!("special region IDs {:?}", ids));
+                Ok(ids)
+            })?;
         }
+        Ok(Self{ points, vectors, segments, regions, region_ids, special_region_ids })
+    }
 
-        cursor.seek(SeekFrom::Start(offset_full_list_regionids))?;
-        let mut region_ids = Vec::with_capacity(num_regions2);
-        for _ in 0..num_regions2 {
-            let region_id = cursor.read_u32::<LittleEndian>()?;
-            region_ids.push(region_id);
+    /// Problems that do not stop decoding: indices past the end of the list they refer to, and
+    /// region IDs that do not match the regions
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        for (n, segment) in self.segments.iter().enumerate() {
+            for point in [ segment.point1, segment.point2 ] {
+                if point >= self.points.len() {
+                    problems.push(format!("segment {}: point {} out of range, there are {}", n, point, self.points.len()));
+                }
+            }
+        }
+        for (n, region) in self.regions.iter().enumerate() {
+            if region.vector_index >= self.vectors.len() {
+                problems.push(format!("region {}: vector {} out of range, there are {}", n, region.vector_index, self.vectors.len()));
+            }
+            for &segment in region.segment_ids.iter().filter(|&&id| id >= self.segments.len()) {
+                problems.push(format!("region {}: segment {} out of range, there are {}", n, segment, self.segments.len()));
+            }
+        }
+        if self.region_ids.len() != self.regions.len() {
+            problems.push(format!("{} region IDs for {} regions", self.region_ids.len(), self.regions.len()));
         }
+        for id in self.special_region_ids.iter().filter(|id| !self.region_ids.contains(id)) {
+            problems.push(format!("special region ID {} is not a region ID", id));
+        }
+        problems
+    }
 
-        cursor.seek(SeekFrom::Start(offset_region_data))?;
-        let mut regions = Vec::with_capacity(num_regions);
-        for _ in 0..num_regions {
-            let vector_index = cursor.read_u32::<LittleEndian>()? as usize;
-            let offset_segment_ids = cursor.read_u32::<LittleEndian>()? as u64;
-            regions.push(RgdRegion{ vector_index, offset_segment_ids });
+    /// Returns whether (x, y) lies within the region, using the even-odd rule on its segments
+    pub fn region_contains(&self, region: &RgdRegion, x: f64, y: f64) -> bool {
+        let mut inside = false;
+        for segment in region.segment_ids.iter().filter_map(|id| self.segments.get(*id)) {
+            let (Some(a), Some(b)) = (self.points.get(segment.point1), self.points.get(segment.point2)) else { continue };
+            if (a.y > y) != (b.y > y) && x < (b.x - a.x) * (y - a.y) / (b.y - a.y) + a.x {
+                inside = !inside;
+            }
         }
-        println!("{:x?}", regions);
-        Ok(Self{})
+        inside
+    }
+
+    /// Returns the index of the first region containing (x, y)
+    pub fn region_at(&self, x: f64, y: f64) -> Option<usize> {
+        self.regions.iter().position(|region| self.region_contains(region, x, y))
+    }
+
+    /// Returns whether the region with the given index is listed as a special (walkable) region
+    pub fn is_special(&self, region_index: usize) -> bool {
+        self.region_ids.get(region_index).is_some_and(|id| self.special_region_ids.contains(id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qfg5resource::fixtures;
+
+    #[test]
+    fn test_region_at() {
+        // A 10x10 square and a triangle next to it
+        let point = |x, y| RgdPoint{ x, y };
+        let segment = |point1, point2| RgdSegment{ point1, point2, regionid_offset: 0 };
+        let region = |segment_ids: Vec<usize>| RgdRegion{ vector_index: 0, offset_segment_ids: 0, segment_ids };
+        let rgd = RgdDecoder{
+            points: vec![ point(0.0, 0.0), point(10.0, 0.0), point(10.0, 10.0), point(0.0, 10.0), point(20.0, 0.0) ],
+            vectors: Vec::new(),
+            segments: vec![ segment(0, 1), segment(1, 2), segment(2, 3), segment(3, 0), segment(1, 4), segment(4, 2) ],
+            regions: vec![ region(vec![ 0, 1, 2, 3 ]), region(vec![ 1, 4, 5 ]) ],
+            region_ids: vec![ 7, 8 ],
+            special_region_ids: vec![ 8 ],
+        };
+        assert_eq!(rgd.region_at(5.0, 5.0), Some(0));
+        assert_eq!(rgd.region_at(12.0, 3.0), Some(1));
+        assert_eq!(rgd.region_at(15.0, 9.0), None);
+        assert_eq!(rgd.region_at(-1.0, 5.0), None);
+        assert!(!rgd.is_special(0));
+        assert!(rgd.is_special(1));
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut rgd = RgdDecoder::new(&fixtures::rgd()).unwrap();
+        assert!(rgd.validate().is_empty());
+        rgd.segments[2].point2 = 3;
+        rgd.regions[0].segment_ids.push(5);
+        rgd.special_region_ids.push(43);
+        assert_eq!(rgd.validate(), [ "segment 2: point 3 out of range, there are 3", "region 0: segment 5 out of range, there are 3",
+                                     "special region ID 43 is not a region ID" ]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut rgd = fixtures::rgd();
+        rgd[4] = 3;
+        assert!(matches!(RgdDecoder::new(&rgd), Err(Error::Unsupported{ at: 4, .. })));
+
+        // The offset of the vector data points past the end
+        let mut rgd = fixtures::rgd();
+        rgd[64 + 3] = 0xff;
+        assert!(matches!(RgdDecoder::new(&rgd), Err(Error::Invalid{ at: 64, .. })));
+
+        let rgd = fixtures::rgd();
+        let err = RgdDecoder::new(&rgd[..rgd.len() - 1]).unwrap_err();
+        assert_eq!(err.to_string(), "special region IDs at offset 0xf0: truncated at offset 0xf0: needed 4 bytes, got 3");
     }
 }
@@ -4,74 +4,921 @@
  * Copyright (c) 2024 Rink Springer <rink@rink.nu>
  * For conditions of distribution and use, see LICENSE file
  */
-use anyhow::{anyhow, Result};
-use std::io::{Read, Seek, SeekFrom};
-use byteorder::{ReadBytesExt, LittleEndian};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+use byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian};
+#[cfg(not(target_arch = "wasm32"))]
 use std::fs::File;
-use std::os::unix::fs::FileExt;
+use crate::qfg5resource::error::{Error, Result};
 
+/// Size of the pieces read_item_to() copies items in
+const COPY_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Size of the "local file header" in front of the name of every item
+const LOCAL_HEADER_SIZE: u64 = 0x42;
+
+/// Attaches the current file position to I/O errors
+fn io_error<R: Seek>(f: &mut R) -> impl FnMut(std::io::Error) -> Error + '_ {
+    |source| Error::Io{ at: f.stream_position().unwrap_or(0), source }
+}
+
+/// How an item is stored, from the compression method of its directory entry
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize), serde(rename_all = "lowercase"))]
+pub enum SpkCompression {
+    Stored,
+    Deflate,
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct SpkItem {
     pub filename: String,
     pub offset: u64,
+    /// Size of the data once decompressed
     pub length: usize,
+    pub compression: SpkCompression,
+    /// Size of the data in the archive
+    pub stored_length: usize,
+    /// CRC-32 of the decompressed data, according to the directory
+    pub crc32: u32,
+}
+
+/// A source of an archive other than a file or bytes, see SpkArchive::from_reader()
+pub trait ReadSeek: Read + Seek + Send {}
+
+impl<R: Read + Seek + Send> ReadSeek for R {}
+
+/// Case of the file names of extracted items
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FileNameCase {
+    #[default]
+    Lowercase,
+    /// As stored in the archive, usually uppercase
+    Original,
+}
+
+impl SpkItem {
+    /// Where the item is extracted to below `out_dir`. Fails for names that would end up outside
+    /// of it: absolute names, names with a drive letter and names with `..`.
+    pub fn extract_path(&self, out_dir: &std::path::Path, case: FileNameCase) -> Result<std::path::PathBuf> {
+        let mut path = out_dir.to_path_buf();
+        for (n, component) in self.filename.split([ '/', '\\' ]).enumerate() {
+            let unsafe_component = component == ".." || component.contains(':') || (n == 0 && component.is_empty());
+            if unsafe_component {
+                return Err(Error::Invalid{ what: format!("item name {:?}, which leads outside of the output directory", self.filename), at: self.offset });
+            }
+            if component.is_empty() || component == "." {
+                continue;
+            }
+            match case {
+                FileNameCase::Lowercase => path.push(component.to_lowercase()),
+                FileNameCase::Original => path.push(component),
+            }
+        }
+        Ok(path)
+    }
+}
+
+enum Storage {
+    // The browser has no files, only data that is already in memory
+    #[cfg(not(target_arch = "wasm32"))]
+    File(File),
+    /// Reading moves the position of the reader, so it can be used by one thread at a time
+    Reader(Mutex<Box<dyn ReadSeek>>),
+    #[cfg(all(feature = "mmap", not(target_arch = "wasm32")))]
+    Mmap(memmap2::Mmap),
+    Bytes(Vec<u8>),
 }
 
 pub struct SpkArchive {
-    f: File,
-    items: Vec<SpkItem>
+    storage: Storage,
+    length: u64,
+    items: Vec<SpkItem>,
+    entries: Vec<DirectoryEntry>,
+    /// Index in items by lowercase file name
+    index: HashMap<String, usize>,
+    diagnostics: Vec<SpkDiagnostic>,
+}
+
+/// Something wrong with the directory that does not stop the archive from being read, but makes
+/// some items unreliable
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum SpkDiagnostic {
+    /// The data of `second` starts before that of `first` ends
+    Overlap{ first: String, second: String, at: u64 },
+    /// The data of the item ends at `end`, past the end of the archive
+    PastEnd{ filename: String, end: u64, length: u64 },
+    /// The names differ at most in case; only `first` can be found by name
+    Duplicate{ first: String, second: String },
+}
+
+impl std::fmt::Display for SpkDiagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SpkDiagnostic::Overlap{ first, second, at } => write!(f, "{} overlaps {} at offset {:#x}", second, first, at),
+            SpkDiagnostic::PastEnd{ filename, end, length } => write!(f, "{} ends at offset {:#x}, past the end of the archive at {:#x}", filename, end, length),
+            SpkDiagnostic::Duplicate{ first, second } => write!(f, "{} has the same name as {}, only the first can be found", second, first),
+        }
+    }
+}
+
+/// Indexes the items by lowercase file name; of items whose names differ only in case, the first
+/// is found, and the others are returned as diagnostics
+fn index_items(items: &[SpkItem]) -> (HashMap<String, usize>, Vec<SpkDiagnostic>) {
+    let mut index: HashMap<String, usize> = HashMap::with_capacity(items.len());
+    let mut duplicates = Vec::new();
+    for (n, item) in items.iter().enumerate() {
+        let key = item.filename.to_lowercase();
+        if let Some(&first) = index.get(&key) {
+            duplicates.push(SpkDiagnostic::Duplicate{ first: items[first].filename.clone(), second: item.filename.clone() });
+            continue;
+        }
+        index.insert(key, n);
+    }
+    (index, duplicates)
+}
+
+/// Finds items whose data overlaps, or extends past the end of the archive of `length` bytes
+fn check_ranges(items: &[SpkItem], length: u64) -> Vec<SpkDiagnostic> {
+    let mut diagnostics = Vec::new();
+    let mut by_offset: Vec<_> = items.iter().filter(|item| item.stored_length > 0).collect();
+    by_offset.sort_by_key(|item| item.offset);
+    // The item that ends last of those seen so far
+    let mut furthest: Option<&SpkItem> = None;
+    for item in by_offset {
+        let end = item.offset + item.stored_length as u64;
+        if end > length {
+            diagnostics.push(SpkDiagnostic::PastEnd{ filename: item.filename.clone(), end, length });
+        }
+        if let Some(previous) = furthest {
+            if item.offset < previous.offset + previous.stored_length as u64 {
+                diagnostics.push(SpkDiagnostic::Overlap{ first: previous.filename.clone(), second: item.filename.clone(), at: item.offset });
+            }
+        }
+        if furthest.is_none_or(|previous| end > previous.offset + previous.stored_length as u64) {
+            furthest = Some(item);
+        }
+    }
+    diagnostics
+}
+
+/// Where the directory entry of an item is, for copying it
+struct DirectoryEntry {
+    position: u64,
+    name_len: u64,
+}
+
+/// Size of a directory entry, without the name
+const DIRECTORY_ENTRY_SIZE: u64 = 46;
+
+struct Directory {
+    /// Length of the archive
+    length: u64,
+    items: Vec<SpkItem>,
+    /// In the same order as items
+    entries: Vec<DirectoryEntry>,
+}
+
+fn read_directory<R: Read + Seek>(f: &mut R) -> Result<Directory> {
+    let length = f.seek(SeekFrom::End(0)).map_err(io_error(f))?;
+    let file_len = length as u32;
+    if file_len < 22 {
+        return Err(Error::Truncated{ needed: 22, got: file_len as usize, at: 0 });
+    }
+
+    // Last 22 bytes of the SPK archive contain a end-of-directory structure.
+    let end_of_directory = (file_len - 22) as u64;
+    f.seek(SeekFrom::Start(end_of_directory)).map_err(io_error(f))?;
+    let pk = f.read_u16::<LittleEndian>().map_err(io_error(f))?;
+    if pk != 0x4b50 { return Err(Error::BadMagic{ expected: "PK in end-of-directory record", found: pk as u32, at: end_of_directory }); }
+    let id = f.read_u16::<LittleEndian>().map_err(io_error(f))?;
+    if id != 0x0705 { return Err(Error::BadMagic{ expected: "end-of-directory record id 0x0705", found: id as u32, at: end_of_directory + 2 }); }
+    f.seek(SeekFrom::Current(4)).map_err(io_error(f))?; // 0, unknown purpose
+    let num_files = f.read_u16::<LittleEndian>().map_err(io_error(f))?;
+    let num_files_dup = f.read_u16::<LittleEndian>().map_err(io_error(f))?;
+    if num_files != num_files_dup {
+        return Err(Error::Invalid{ what: format!("file counts that do not match ({} vs {})", num_files, num_files_dup), at: end_of_directory + 8 });
+    }
+    // 
+    let a = f.read_u32::<LittleEndian>().map_err(io_error(f))?;
+    let b = f.read_u32::<LittleEndian>().map_err(io_error(f))?;
+
+    let (Some(central_directory_offset), Some(local_file_start)) = (file_len.checked_sub(a).and_then(|v| v.checked_sub(0x16)),
+            file_len.checked_sub(a).and_then(|v| v.checked_sub(b)).and_then(|v| v.checked_sub(0x16))) else {
+        return Err(Error::Invalid{ what: format!("directory size {:x} or offset {:x}", a, b), at: end_of_directory + 12 });
+    };
+    f.seek(SeekFrom::Start(central_directory_offset as u64)).map_err(io_error(f))?;
+
+    let mut items = Vec::<SpkItem>::with_capacity(num_files as usize);
+    let mut entries = Vec::with_capacity(num_files as usize);
+    for n in 0..num_files {
+        let position = f.stream_position().map_err(io_error(f))?;
+        let method_at = f.seek(SeekFrom::Current(10)).map_err(io_error(f))?;
+        let compression = match f.read_u16::<LittleEndian>().map_err(io_error(f))? {
+            0 => SpkCompression::Stored,
+            8 => SpkCompression::Deflate,
+            method => return Err(Error::Unsupported{ what: format!("compression method {}", method), at: method_at }),
+        };
+        // Time and date
+        f.seek(SeekFrom::Current(4)).map_err(io_error(f))?;
+        let crc32 = f.read_u32::<LittleEndian>().map_err(io_error(f))?;
+        let entry = f.stream_position().map_err(io_error(f))?;
+        let compr_size = f.read_u32::<LittleEndian>().map_err(io_error(f))?;
+        let decompr_size = f.read_u32::<LittleEndian>().map_err(io_error(f))?;
+        if compression == SpkCompression::Stored && compr_size != decompr_size {
+            return Err(Error::Invalid{ what: format!("stored entry of {} bytes that decompresses to {}", compr_size, decompr_size), at: entry });
+        }
+        let fname_len = f.read_u32::<LittleEndian>().map_err(io_error(f))?;
+        if fname_len > 0xffff { return Err(Error::Invalid{ what: format!("file name length {}", fname_len), at: entry + 8 }); }
+        f.seek(SeekFrom::Current(10)).map_err(io_error(f))?;
+        let item_location = f.read_u32::<LittleEndian>().map_err(io_error(f))?;
+        // All entries are prefixed by a "local file header", which can be skipped
+        let offset = local_file_start as u64 + item_location as u64 + LOCAL_HEADER_SIZE + fname_len as u64;
+        let mut fname = vec![ 0u8; fname_len as usize ];
+        f.read_exact(&mut fname).map_err(io_error(f))?;
+
+        let filename = String::from_utf8(fname).unwrap_or_else(|_| format!("<corrupt-{}>", n));
+        items.push(SpkItem{ filename, offset, length: decompr_size as usize, compression, stored_length: compr_size as usize, crc32 });
+        entries.push(DirectoryEntry{ position, name_len: fname_len as u64 });
+    }
+    Ok(Directory{ length, items, entries })
+}
+
+/// Reads at `offset` without moving the file position, so that items can be read from several
+/// threads at once
+#[cfg(unix)]
+fn read_exact_at(f: &File, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(f, buf, offset)
+}
+
+/// Windows has no read_exact_at(), and seek_read() may return less than asked for. It moves the
+/// file position, but nothing depends on that.
+#[cfg(windows)]
+fn read_exact_at(f: &File, mut buf: &mut [u8], mut offset: u64) -> std::io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match f.seek_read(buf, offset) {
+            Ok(0) => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => { },
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Writes the directory, which starts at `position`, followed by the end-of-directory record
+fn write_end_of_directory<W: Write>(mut w: W, mut directory: Vec<u8>, num_items: u16, position: u64) -> Result<()> {
+    let (Ok(directory_offset), Ok(directory_size)) = (u32::try_from(position), u32::try_from(directory.len())) else {
+        return Err(Error::Invalid{ what: "archive of more than 4GB".to_string(), at: position });
+    };
+    directory.write_u16::<LittleEndian>(0x4b50).unwrap();
+    directory.write_u16::<LittleEndian>(0x0705).unwrap();
+    directory.extend([ 0u8; 4 ]);
+    directory.write_u16::<LittleEndian>(num_items).unwrap();
+    directory.write_u16::<LittleEndian>(num_items).unwrap();
+    directory.write_u32::<LittleEndian>(directory_size).unwrap();
+    directory.write_u32::<LittleEndian>(directory_offset).unwrap();
+    // Comment length
+    directory.write_u16::<LittleEndian>(0).unwrap();
+    w.write_all(&directory).and_then(|_| w.flush()).map_err(|source| Error::Io{ source, at: position })
+}
+
+/// Feeds what is written to a CRC-32
+struct Crc32Writer(crc32fast::Hasher);
+
+impl Write for Crc32Writer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Decompresses raw deflate data, which must come to exactly the length in the directory
+fn inflate(data: &[u8], item: &SpkItem) -> Result<Vec<u8>> {
+    let corrupt = |what: String| Error::Invalid{ what: format!("deflate data of {} ({})", item.filename, what), at: item.offset };
+    // Data that inflates to more than the directory says fails with the limit reached, without
+    // decompressing all of it
+    let inflated = miniz_oxide::inflate::decompress_to_vec_with_limit(data, item.length)
+        .map_err(|e| corrupt(e.to_string()))?;
+    if inflated.len() != item.length {
+        return Err(corrupt(format!("{} bytes instead of {}", inflated.len(), item.length)));
+    }
+    Ok(inflated)
 }
 
 impl SpkArchive {
+    fn with_storage(storage: Storage, directory: Directory) -> Self {
+        let Directory{ length, items, entries } = directory;
+        let (index, mut diagnostics) = index_items(&items);
+        diagnostics.extend(check_ranges(&items, length));
+        Self{ storage, length, items, entries, index, diagnostics }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
     pub fn new(mut f: File) -> Result<SpkArchive> {
-        let file_len = f.seek(SeekFrom::End(0))? as u32;
-
-        // Last 22 bytes of the SPK archive contain a end-of-directory structure.
-        f.seek(SeekFrom::End(-22))?;
-        let pk = f.read_u16::<LittleEndian>()?;
-        if pk != 0x4b50 { return Err(anyhow!("invalid PK magic in end-of-directory record")); }
-        let id = f.read_u16::<LittleEndian>()?;
-        if id != 0x0705 { return Err(anyhow!("invalid PK id in end-of-directory record")); }
-        f.seek(SeekFrom::Current(4))?; // 0, unknown purpose
-        let num_files = f.read_u16::<LittleEndian>()?;
-        let num_files_dup = f.read_u16::<LittleEndian>()?;
-        if num_files != num_files_dup { return Err(anyhow!("file counts do not match ({} vs {})", num_files, num_files_dup)); }
-        // 
-        let a = f.read_u32::<LittleEndian>()?;
-        let b = f.read_u32::<LittleEndian>()?;
-
-        let local_file_start = (file_len - a) - b - 0x16;
-
-        let central_directory_offset = (file_len - a) - 0x16;
-        f.seek(SeekFrom::Start(central_directory_offset as u64))?;
-
-        let mut items = Vec::<SpkItem>::with_capacity(num_files as usize);
-        for n in 0..num_files {
-            f.seek(SeekFrom::Current(20))?;
-            let compr_size = f.read_u32::<LittleEndian>()?;
-            let decompr_size = f.read_u32::<LittleEndian>()?;
-            if compr_size != decompr_size { return Err(anyhow!("compressed entries are not supported")); }
-            let fname_len = f.read_u32::<LittleEndian>()?;
-            f.seek(SeekFrom::Current(10))?;
-            let item_location = f.read_u32::<LittleEndian>()?;
-            // All entries are prefixed by a "local file header", which can be skipped
-            let offset = local_file_start + item_location + 0x42 + fname_len;
-            let mut fname = vec![ 0u8; fname_len as usize ];
-            f.read_exact(&mut fname)?;
-
-            let filename = String::from_utf8(fname).unwrap_or_else(|_| format!("<corrupt-{}>", n));
-            items.push(SpkItem{ filename, length: decompr_size as usize, offset: offset as u64 });
-        }
-        Ok(Self{ f, items })
+        let directory = read_directory(&mut f)?;
+        Ok(Self::with_storage(Storage::File(f), directory))
+    }
+
+    /// The same as new(), by the name it has next to from_bytes() and from_reader()
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn from_file(f: File) -> Result<SpkArchive> {
+        Self::new(f)
+    }
+
+    /// Reads an archive that is already in memory
+    pub fn from_bytes(data: Vec<u8>) -> Result<SpkArchive> {
+        let directory = read_directory(&mut Cursor::new(&data[..]))?;
+        Ok(Self::with_storage(Storage::Bytes(data), directory))
+    }
+
+    /// Reads an archive from any reader, such as a part of another file. Prefer new() for files,
+    /// which reads items without a lock.
+    ///
+    /// The archive keeps the reader, so it must be owned and Send: a borrowed one such as
+    /// `Cursor<&[u8]>` or `Take<&mut File>` is not accepted; copy the data for from_bytes() or
+    /// hand over the file instead. Positions count from the start of the archive and it ends where
+    /// the reader does, so an archive inside another file needs a reader that offsets and limits
+    /// seeks to its part (`Take` does not seek).
+    pub fn from_reader<R: ReadSeek + 'static>(mut reader: R) -> Result<SpkArchive> {
+        let directory = read_directory(&mut reader)?;
+        Ok(Self::with_storage(Storage::Reader(Mutex::new(Box::new(reader))), directory))
+    }
+
+    /// Maps the archive at `path` into memory, so that items can be borrowed with item_bytes()
+    /// instead of read. The archive must not be changed while it is open.
+    #[cfg(all(feature = "mmap", not(target_arch = "wasm32")))]
+    pub fn open_mmap(path: &std::path::Path) -> Result<SpkArchive> {
+        let f = File::open(path).map_err(|source| Error::Io{ source, at: 0 })?;
+        // SAFETY: the map is read-only and changing the file underneath it is documented above as
+        // not allowed; the game data is not written to
+        let map = unsafe { memmap2::Mmap::map(&f) }.map_err(|source| Error::Io{ source, at: 0 })?;
+        let directory = read_directory(&mut Cursor::new(&map[..]))?;
+        Ok(Self::with_storage(Storage::Mmap(map), directory))
+    }
+
+    /// The stored data of the item, for archives in memory: those from from_bytes() and
+    /// open_mmap(). Compressed items cannot be borrowed, see item_data().
+    pub fn item_bytes(&self, item: &SpkItem) -> Result<&[u8]> {
+        let Some(data) = self.memory() else {
+            return Err(Error::Unsupported{ what: "borrowing an item of an archive that is not in memory".to_string(), at: item.offset });
+        };
+        if item.compression != SpkCompression::Stored {
+            return Err(Error::Unsupported{ what: format!("borrowing compressed item {}", item.filename), at: item.offset });
+        }
+        let end = self.stored_end(item)?;
+        Ok(&data[item.offset as usize..end as usize])
+    }
+
+    /// The whole archive, if it is in memory
+    fn memory(&self) -> Option<&[u8]> {
+        match &self.storage {
+            Storage::Bytes(data) => Some(data),
+            #[cfg(all(feature = "mmap", not(target_arch = "wasm32")))]
+            Storage::Mmap(map) => Some(map),
+            _ => None,
+        }
+    }
+
+    /// What is wrong with the directory, such as items whose data overlaps
+    pub fn diagnostics(&self) -> &[SpkDiagnostic] {
+        &self.diagnostics
+    }
+
+    /// The item named `name`, such as "MDL/063.MDL", compared without regard to case
+    pub fn find(&self, name: &str) -> Option<&SpkItem> {
+        self.index.get(&name.to_lowercase()).map(|&n| &self.items[n])
+    }
+
+    /// The items whose names start with `prefix`, such as "mdl/", compared without regard to case
+    pub fn find_prefix<'a>(&'a self, prefix: &str) -> impl Iterator<Item = &'a SpkItem> {
+        let prefix = prefix.to_lowercase();
+        self.items.iter().filter(move |item| item.filename.to_lowercase().starts_with(&prefix))
+    }
+
+    /// Writes an archive containing `items`, given as their name and contents. The contents are
+    /// stored uncompressed, like in the archives of the game; the result reads back with new().
+    pub fn create<W: Write>(mut w: W, items: &[(&str, &[u8])]) -> Result<()> {
+        if items.len() > u16::MAX as usize {
+            return Err(Error::Invalid{ what: format!("number of items {} for an archive", items.len()), at: 0 });
+        }
+        let mut position = 0u64;
+        let mut directory = Vec::new();
+        for (name, contents) in items {
+            let (Ok(name_len), Ok(length), Ok(location)) = (u16::try_from(name.len()), u32::try_from(contents.len()), u32::try_from(position)) else {
+                return Err(Error::Invalid{ what: format!("item {} of {} bytes for an archive", name, contents.len()), at: position });
+            };
+            let crc32 = crc32fast::hash(contents);
+            // Written like in ZIP files and padded to size; the reader skips these anyway
+            let mut header = Vec::with_capacity(LOCAL_HEADER_SIZE as usize + name.len());
+            header.extend(b"PK\x03\x04");
+            header.extend([ 0u8; 10 ]);
+            header.write_u32::<LittleEndian>(crc32).unwrap();
+            header.write_u32::<LittleEndian>(length).unwrap();
+            header.write_u32::<LittleEndian>(length).unwrap();
+            header.write_u16::<LittleEndian>(name_len).unwrap();
+            header.resize(LOCAL_HEADER_SIZE as usize, 0);
+            header.extend(name.as_bytes());
+            for data in [ &header[..], contents ] {
+                w.write_all(data).map_err(|source| Error::Io{ source, at: position })?;
+                position += data.len() as u64;
+            }
+
+            // Version, flags, compression method (stored), time and date are all zero
+            directory.extend(b"PK\x01\x02");
+            directory.extend([ 0u8; 12 ]);
+            directory.write_u32::<LittleEndian>(crc32).unwrap();
+            directory.write_u32::<LittleEndian>(length).unwrap();
+            directory.write_u32::<LittleEndian>(length).unwrap();
+            // The name length, followed by a zero extra field length; the reader takes both as one
+            directory.write_u32::<LittleEndian>(name_len as u32).unwrap();
+            directory.extend([ 0u8; 10 ]);
+            directory.write_u32::<LittleEndian>(location).unwrap();
+            directory.extend(name.as_bytes());
+        }
+        write_end_of_directory(w, directory, items.len() as u16, position)
     }
 
     pub fn get_items(&self) -> &Vec<SpkItem> {
         &self.items
     }
 
+    /// Fails if the item extends past the end of the archive, before allocating anything.
+    /// Compressed items are decompressed.
     pub fn read_item(&self, item: &SpkItem) -> Result<Vec<u8>> {
-        let mut buf = vec![ 0u8; item.length ];
-        self.f.read_exact_at(&mut buf, item.offset)?;
-        Ok(buf)
+        Ok(self.item_data(item)?.into_owned())
+    }
+
+    /// Like read_item(), but an archive in memory returns the stored data itself instead of a copy
+    pub fn item_data(&self, item: &SpkItem) -> Result<Cow<'_, [u8]>> {
+        let stored = self.stored_data(item)?;
+        match item.compression {
+            SpkCompression::Stored => Ok(stored),
+            SpkCompression::Deflate => inflate(&stored, item).map(Cow::Owned),
+        }
+    }
+
+    /// Writes the contents of the item to `w` a piece at a time, so that large items need not fit
+    /// in memory; returns the number of bytes written. Fails before writing anything if the item
+    /// extends past the end of the archive. Compressed items are still decompressed in memory.
+    pub fn read_item_to<W: Write + ?Sized>(&self, item: &SpkItem, w: &mut W) -> Result<u64> {
+        if item.compression != SpkCompression::Stored {
+            let data = self.item_data(item)?;
+            w.write_all(&data).map_err(|source| Error::Io{ source, at: item.offset })?;
+            return Ok(data.len() as u64);
+        }
+        let end = self.stored_end(item)?;
+        self.copy_range(item.offset, end, w)?;
+        Ok(item.stored_length as u64)
+    }
+
+    /// Copies the bytes from `start` up to `end`, which must be within the archive, to `w`
+    fn copy_range<W: Write + ?Sized>(&self, start: u64, end: u64, w: &mut W) -> Result<()> {
+        if let Some(data) = self.memory() {
+            return w.write_all(&data[start as usize..end as usize]).map_err(|source| Error::Io{ source, at: start });
+        }
+        let mut buf = vec![ 0u8; (end - start).min(COPY_CHUNK_SIZE as u64) as usize ];
+        let mut offset = start;
+        while offset < end {
+            let chunk = &mut buf[..(end - offset).min(COPY_CHUNK_SIZE as u64) as usize];
+            self.read_at(chunk, offset)?;
+            w.write_all(chunk).map_err(|source| Error::Io{ source, at: offset })?;
+            offset += chunk.len() as u64;
+        }
+        Ok(())
+    }
+
+    /// Writes a copy of the archive to `w` in which item `name` (see find()) contains `data`,
+    /// stored uncompressed. The other items are copied as they are, headers included; only their
+    /// locations change.
+    pub fn replace_item<W: Write>(&self, name: &str, data: &[u8], mut w: W) -> Result<()> {
+        let Some(&replaced) = self.index.get(&name.to_lowercase()) else {
+            return Err(Error::Invalid{ what: format!("item name {}, which is not in the archive", name), at: 0 });
+        };
+        let Ok(length) = u32::try_from(data.len()) else {
+            return Err(Error::Invalid{ what: format!("item {} of {} bytes for an archive", name, data.len()), at: 0 });
+        };
+        let mut position = 0u64;
+        let mut directory = Vec::new();
+        for (n, (item, entry)) in self.items.iter().zip(&self.entries).enumerate() {
+            let header_start = item.offset.checked_sub(LOCAL_HEADER_SIZE + entry.name_len)
+                .ok_or_else(|| Error::Invalid{ what: format!("location of {}", item.filename), at: entry.position })?;
+            let Ok(location) = u32::try_from(position) else {
+                return Err(Error::Invalid{ what: "archive of more than 4GB".to_string(), at: position });
+            };
+            let mut header = vec![ 0u8; (LOCAL_HEADER_SIZE + entry.name_len) as usize ];
+            if header_start + header.len() as u64 > self.length {
+                return Err(Error::Truncated{ needed: header.len(), got: self.length.saturating_sub(header_start) as usize, at: header_start });
+            }
+            self.read_at(&mut header, header_start)?;
+            let mut directory_entry = vec![ 0u8; (DIRECTORY_ENTRY_SIZE + entry.name_len) as usize ];
+            self.read_at(&mut directory_entry, entry.position)?;
+            directory_entry[42..46].copy_from_slice(&location.to_le_bytes());
+            if n == replaced {
+                // Stored, with the CRC and sizes at the same places in both headers
+                let crc32 = crc32fast::hash(data);
+                for (header, method_at) in [ (&mut header, 8), (&mut directory_entry, 10) ] {
+                    header[method_at..method_at + 2].copy_from_slice(&0u16.to_le_bytes());
+                    header[method_at + 6..method_at + 10].copy_from_slice(&crc32.to_le_bytes());
+                    header[method_at + 10..method_at + 14].copy_from_slice(&length.to_le_bytes());
+                    header[method_at + 14..method_at + 18].copy_from_slice(&length.to_le_bytes());
+                }
+            }
+            w.write_all(&header).map_err(|source| Error::Io{ source, at: position })?;
+            position += header.len() as u64;
+            if n == replaced {
+                w.write_all(data).map_err(|source| Error::Io{ source, at: position })?;
+                position += data.len() as u64;
+            } else {
+                let end = self.stored_end(item)?;
+                self.copy_range(item.offset, end, &mut w)?;
+                position += item.stored_length as u64;
+            }
+            directory.extend(directory_entry);
+        }
+        write_end_of_directory(w, directory, self.items.len() as u16, position)
+    }
+
+    /// Writes the item to a new file at `path`, creating its directory, without reading all of it
+    /// into memory. A file that could not be written completely is removed.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn extract_item(&self, item: &SpkItem, path: &std::path::Path) -> Result<()> {
+        let result = (|| {
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir).map_err(|source| Error::Io{ source, at: item.offset })?;
+            }
+            let mut f = File::create(path).map_err(|source| Error::Io{ source, at: item.offset })?;
+            if let Err(e) = self.read_item_to(item, &mut f) {
+                drop(f);
+                let _ = std::fs::remove_file(path);
+                return Err(e);
+            }
+            Ok(())
+        })();
+        result.map_err(|inner| Error::Context{ what: format!("extracting {} to {}", item.filename, path.display()), at: item.offset, inner: Box::new(inner) })
+    }
+
+    /// Extracts `items` below `out_dir`, see SpkItem::extract_path(); returns the paths written.
+    /// Before each item, `progress` is called with it, its index in `items` and the number of
+    /// items.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn extract_items(&self, items: &[&SpkItem], out_dir: &std::path::Path, case: FileNameCase,
+                         mut progress: impl FnMut(&SpkItem, usize, usize)) -> Result<Vec<std::path::PathBuf>> {
+        let mut paths = Vec::with_capacity(items.len());
+        for (n, item) in items.iter().enumerate() {
+            progress(item, n, items.len());
+            let path = item.extract_path(out_dir, case)?;
+            self.extract_item(item, &path)?;
+            paths.push(path);
+        }
+        Ok(paths)
+    }
+
+    /// Extracts every item, see extract_items(); returns the number of items
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn extract_all(&self, out_dir: &std::path::Path, case: FileNameCase, progress: impl FnMut(&SpkItem, usize, usize)) -> Result<usize> {
+        let items: Vec<_> = self.items.iter().collect();
+        Ok(self.extract_items(&items, out_dir, case, progress)?.len())
+    }
+
+    /// Computes the CRC-32 of the contents of the item, to compare with SpkItem::crc32
+    pub fn item_crc32(&self, item: &SpkItem) -> Result<u32> {
+        let mut hasher = Crc32Writer(crc32fast::Hasher::new());
+        self.read_item_to(item, &mut hasher)?;
+        Ok(hasher.0.finalize())
+    }
+
+    /// Where the data of the item in the archive ends, if it does not extend past the end
+    fn stored_end(&self, item: &SpkItem) -> Result<u64> {
+        let end = item.offset.saturating_add(item.stored_length as u64);
+        if end > self.length {
+            return Err(Error::Truncated{ needed: item.stored_length, got: self.length.saturating_sub(item.offset) as usize, at: item.offset });
+        }
+        Ok(end)
+    }
+
+    /// The data of the item as it is in the archive
+    fn stored_data(&self, item: &SpkItem) -> Result<Cow<'_, [u8]>> {
+        let end = self.stored_end(item)?;
+        match self.memory() {
+            Some(data) => Ok(Cow::Borrowed(&data[item.offset as usize..end as usize])),
+            None => {
+                let mut buf = vec![ 0u8; item.stored_length ];
+                self.read_at(&mut buf, item.offset)?;
+                Ok(Cow::Owned(buf))
+            },
+        }
+    }
+
+    /// Fills `buf` from `offset` in the archive
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
+        let result = match &self.storage {
+            #[cfg(not(target_arch = "wasm32"))]
+            Storage::File(f) => read_exact_at(f, buf, offset),
+            Storage::Reader(reader) => {
+                let mut reader = reader.lock().unwrap();
+                reader.seek(SeekFrom::Start(offset)).and_then(|_| reader.read_exact(buf))
+            },
+            _ => {
+                let data = self.memory().unwrap();
+                let stored = usize::try_from(offset).ok().and_then(|start| data.get(start..start.checked_add(buf.len())?));
+                let stored = stored.ok_or(Error::Truncated{ needed: buf.len(), got: self.length.saturating_sub(offset) as usize, at: offset })?;
+                buf.copy_from_slice(stored);
+                Ok(())
+            },
+        };
+        result.map_err(|source| Error::Io{ source, at: offset })
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qfg5resource::fixtures;
+
+    #[test]
+    fn test_compressed() {
+        let contents: Vec<u8> = (0..2000).map(|n| (n % 7) as u8).collect();
+        let archive = SpkArchive::from_bytes(fixtures::spk_compressed(&[ ("A/1.NOD", &contents, true), ("B/2.NOD", b"abc", false) ])).unwrap();
+        let items = archive.get_items();
+        assert_eq!((items[0].compression, items[0].length), (SpkCompression::Deflate, 2000));
+        assert!(items[0].stored_length < 100);
+        assert_eq!(archive.read_item(&items[0]).unwrap(), contents);
+        assert_eq!((items[1].compression, items[1].stored_length), (SpkCompression::Stored, 3));
+        assert!(matches!(archive.item_data(&items[1]).unwrap(), Cow::Borrowed(b"abc")));
+    }
+
+    #[test]
+    fn test_create() {
+        let items: [(&str, &[u8]); 3] = [ ("MDL/HERO.MDL", b"model"), ("EMPTY", b""), ("QGM/1.QGM", &[ 7u8; 1000 ]) ];
+        let mut data = Vec::new();
+        SpkArchive::create(&mut data, &items).unwrap();
+        let archive = SpkArchive::from_bytes(data).unwrap();
+        assert_eq!(archive.get_items().len(), 3);
+        for (item, (name, contents)) in archive.get_items().iter().zip(items) {
+            assert_eq!((item.filename.as_str(), item.compression), (name, SpkCompression::Stored));
+            assert_eq!(archive.read_item(item).unwrap(), contents);
+        }
+
+        let item = &archive.get_items()[0];
+        assert_eq!((item.crc32, archive.item_crc32(item).unwrap()), (0xd79572d9, 0xd79572d9));
+
+        let mut data = Vec::new();
+        SpkArchive::create(&mut data, &[]).unwrap();
+        assert_eq!(data.len(), 22);
+        assert!(SpkArchive::from_bytes(data).unwrap().get_items().is_empty());
+        let long_name = "A".repeat(0x10000);
+        assert!(SpkArchive::create(&mut Vec::new(), &[ (&long_name, b"") ]).is_err());
+    }
+
+    #[test]
+    fn test_from_reader() {
+        let contents: Vec<u8> = (0..COPY_CHUNK_SIZE + 10).map(|n| (n % 251) as u8).collect();
+        let data = fixtures::spk_compressed(&[ ("MDL/1.MDL", b"abc", false), ("BIG", &contents, false), ("A/1.NOD", &contents, true) ]);
+        let archive = SpkArchive::from_reader(Cursor::new(data)).unwrap();
+        assert_eq!(archive.get_items().len(), 3);
+        assert_eq!(archive.read_item(archive.find("mdl/1.mdl").unwrap()).unwrap(), b"abc");
+        let mut out = Vec::new();
+        archive.read_item_to(archive.find("BIG").unwrap(), &mut out).unwrap();
+        assert_eq!(out, contents);
+        assert_eq!(archive.read_item(archive.find("A/1.NOD").unwrap()).unwrap(), contents);
+        assert!(SpkArchive::from_reader(Cursor::new(b"short".to_vec())).is_err());
+    }
+
+    /// The `length` bytes from `start` of a reader, as a reader of their own
+    struct Window<R> {
+        inner: R,
+        start: u64,
+        length: u64,
+    }
+
+    impl<R: Read + Seek> Read for Window<R> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let left = (self.start + self.length).saturating_sub(self.inner.stream_position()?);
+            let len = buf.len().min(left as usize);
+            self.inner.read(&mut buf[..len])
+        }
+    }
+
+    impl<R: Read + Seek> Seek for Window<R> {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            let position = match pos {
+                SeekFrom::Start(n) => Some(n),
+                SeekFrom::End(n) => self.length.checked_add_signed(n),
+                SeekFrom::Current(n) => (self.inner.stream_position()? - self.start).checked_add_signed(n),
+            };
+            let position = position.ok_or(std::io::ErrorKind::InvalidInput)?;
+            Ok(self.inner.seek(SeekFrom::Start(self.start + position))? - self.start)
+        }
+    }
+
+    #[test]
+    fn test_from_reader_window() {
+        let spk = fixtures::spk_compressed(&[ ("MDL/1.MDL", b"abc", false), ("A/1.NOD", &[ 7u8; 300 ], true) ]);
+        // The archive between other data, as in another container
+        let mut container = vec![ 0xaau8; 100 ];
+        container.extend(&spk);
+        container.extend([ 0x55u8; 50 ]);
+        let archive = SpkArchive::from_reader(Window{ inner: Cursor::new(container), start: 100, length: spk.len() as u64 }).unwrap();
+        assert!(archive.diagnostics().is_empty());
+        assert_eq!(archive.read_item(archive.find("mdl/1.mdl").unwrap()).unwrap(), b"abc");
+        assert_eq!(archive.read_item(archive.find("A/1.NOD").unwrap()).unwrap(), [ 7u8; 300 ]);
+    }
+
+    #[test]
+    fn test_item_bytes() {
+        let data = fixtures::spk_compressed(&[ ("A", b"abc", false), ("B", &[ 1u8; 100 ], true) ]);
+        let archive = SpkArchive::from_bytes(data.clone()).unwrap();
+        let items = archive.get_items();
+        assert_eq!(archive.item_bytes(&items[0]).unwrap(), b"abc");
+        assert!(matches!(archive.item_bytes(&items[1]), Err(Error::Unsupported{ .. })));
+        let past_end = SpkItem{ filename: "C".to_string(), offset: archive.length - 2, length: 3, compression: SpkCompression::Stored, stored_length: 3, crc32: 0 };
+        assert!(matches!(archive.item_bytes(&past_end), Err(Error::Truncated{ needed: 3, got: 2, .. })));
+        let archive = SpkArchive::from_reader(Cursor::new(data)).unwrap();
+        assert!(matches!(archive.item_bytes(&archive.get_items()[0]), Err(Error::Unsupported{ .. })));
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn test_open_mmap() {
+        let contents: Vec<u8> = (0..1000).map(|n| (n % 251) as u8).collect();
+        let path = std::env::temp_dir().join(format!("qfg5-open-mmap-{}.spk", std::process::id()));
+        std::fs::write(&path, fixtures::spk_compressed(&[ ("MDL/1.MDL", &contents, false), ("A/1.NOD", &contents, true) ])).unwrap();
+        let archive = SpkArchive::open_mmap(&path).unwrap();
+        assert_eq!(archive.item_bytes(archive.find("mdl/1.mdl").unwrap()).unwrap(), contents);
+        assert!(matches!(archive.item_data(archive.find("mdl/1.mdl").unwrap()).unwrap(), Cow::Borrowed(_)));
+        assert_eq!(archive.read_item(archive.find("a/1.nod").unwrap()).unwrap(), contents);
+        drop(archive);
+        std::fs::remove_file(&path).unwrap();
+        assert!(SpkArchive::open_mmap(&path).is_err());
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let data = fixtures::spk(&[ ("A", b"abc"), ("B", b"de"), ("a", b"f"), ("EMPTY", b"") ]);
+        let archive = SpkArchive::from_bytes(data.clone()).unwrap();
+        assert_eq!(archive.diagnostics(), [ SpkDiagnostic::Duplicate{ first: "A".to_string(), second: "a".to_string() } ]);
+        assert_eq!(archive.diagnostics()[0].to_string(), "a has the same name as A, only the first can be found");
+        assert!(SpkArchive::from_bytes(fixtures::spk(&[ ("A", b"abc"), ("B", b"") ])).unwrap().diagnostics().is_empty());
+
+        // The items as they would be if B started within A and the last item went past the end
+        let items = archive.get_items();
+        let mut moved: Vec<_> = items.iter().map(|item| SpkItem{ filename: item.filename.clone(), ..*item }).collect();
+        moved[1].offset = items[0].offset + 1;
+        moved[2].stored_length = 1000;
+        let diagnostics = check_ranges(&moved, archive.length);
+        assert_eq!(diagnostics, [
+            SpkDiagnostic::Overlap{ first: "A".to_string(), second: "B".to_string(), at: items[0].offset + 1 },
+            SpkDiagnostic::PastEnd{ filename: "a".to_string(), end: items[2].offset + 1000, length: archive.length },
+        ]);
+        assert!(diagnostics[0].to_string().starts_with("B overlaps A at offset 0x"));
+    }
+
+    #[test]
+    fn test_extract_all() {
+        let dir = std::env::temp_dir().join(format!("qfg5-extract-all-{}", std::process::id()));
+        let archive = SpkArchive::from_bytes(fixtures::spk(&[ ("MDL/HERO.MDL", b"abc"), ("README", b"") ])).unwrap();
+        let mut seen = Vec::new();
+        assert_eq!(archive.extract_all(&dir, FileNameCase::Lowercase, |item, n, total| seen.push(format!("[{}/{}] {}", n + 1, total, item.filename))).unwrap(), 2);
+        assert_eq!(seen, [ "[1/2] MDL/HERO.MDL", "[2/2] README" ]);
+        assert_eq!(std::fs::read(dir.join("mdl/hero.mdl")).unwrap(), b"abc");
+        assert!(dir.join("readme").exists());
+
+        // A file that cannot be written completely is removed
+        let item = &archive.get_items()[0];
+        let past_end = SpkItem{ filename: item.filename.clone(), offset: archive.length - 2, stored_length: 3, ..*item };
+        let err = archive.extract_item(&past_end, &dir.join("broken")).unwrap_err().to_string();
+        assert!(err.starts_with("extracting MDL/HERO.MDL to "), "{}", err);
+        assert!(!dir.join("broken").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_extract_path() {
+        let out = std::path::Path::new("out");
+        let item = |filename: &str| SpkItem{ filename: filename.to_string(), offset: 0x10, length: 0, compression: SpkCompression::Stored, stored_length: 0, crc32: 0 };
+        assert_eq!(item("MDL/HERO.MDL").extract_path(out, FileNameCase::Lowercase).unwrap(), out.join("mdl").join("hero.mdl"));
+        assert_eq!(item("MDL/HERO.MDL").extract_path(out, FileNameCase::Original).unwrap(), out.join("MDL").join("HERO.MDL"));
+        assert_eq!(item("A\\./B//C").extract_path(out, FileNameCase::Original).unwrap(), out.join("A").join("B").join("C"));
+        for filename in [ "../A", "A/../../B", "/etc/passwd", "\\A", "C:/A", "A/C:B", ".." ] {
+            let err = item(filename).extract_path(out, FileNameCase::Lowercase).unwrap_err().to_string();
+            assert!(err.contains("which leads outside of the output directory at offset 0x10"), "{}: {}", filename, err);
+        }
+    }
+
+    #[test]
+    fn test_replace_item() {
+        let data = fixtures::spk_compressed(&[ ("MDL/1.MDL", b"abc", false), ("QGM/2.QGM", &[ 2u8; 100 ], true), ("C", b"xyz", false) ]);
+        let archive = SpkArchive::from_bytes(data.clone()).unwrap();
+        for (name, contents) in [ ("qgm/2.qgm", &b"translated"[..]), ("MDL/1.MDL", &[ 9u8; 5000 ][..]), ("C", b"") ] {
+            let mut out = Vec::new();
+            archive.replace_item(name, contents, &mut out).unwrap();
+            let replaced = SpkArchive::from_bytes(out.clone()).unwrap();
+            assert!(replaced.diagnostics().is_empty());
+            for (item, original) in replaced.get_items().iter().zip(archive.get_items()) {
+                assert_eq!(item.filename, original.filename);
+                let expected = if item.filename.eq_ignore_ascii_case(name) { contents.to_vec() } else { archive.read_item(original).unwrap() };
+                assert_eq!(replaced.read_item(item).unwrap(), expected);
+                assert_eq!(replaced.item_crc32(item).unwrap(), item.crc32);
+            }
+            // The local headers are changed where ZIP has the method, CRC and sizes
+            let item = replaced.find(name).unwrap();
+            let header = &out[(item.offset as usize - item.filename.len() - LOCAL_HEADER_SIZE as usize)..];
+            assert_eq!(header[8..10], [ 0, 0 ]);
+            assert_eq!(header[14..18], item.crc32.to_le_bytes());
+            assert_eq!(header[18..22], (contents.len() as u32).to_le_bytes());
+        }
+        // The deflated item is copied as it is
+        let mut out = Vec::new();
+        archive.replace_item("C", b"", &mut out).unwrap();
+        let replaced = SpkArchive::from_bytes(out).unwrap();
+        assert_eq!(replaced.find("QGM/2.QGM").unwrap().compression, SpkCompression::Deflate);
+        assert!(archive.replace_item("D", b"", &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_replace_item_bad_location() {
+        let mut data = fixtures::spk(&[ ("A", b"abc") ]);
+        // The location of the only directory entry, which starts the directory
+        let directory = u32::from_le_bytes(data[data.len() - 6..data.len() - 2].try_into().unwrap()) as usize;
+        data[directory + 42..directory + 46].copy_from_slice(&0x00ff0000u32.to_le_bytes());
+        let archive = SpkArchive::from_bytes(data).unwrap();
+        assert!(matches!(archive.replace_item("A", b"xyz", &mut Vec::new()), Err(Error::Truncated{ at: 0x00ff0000, .. })));
+        let mut buf = [ 0u8; 4 ];
+        assert!(matches!(archive.read_at(&mut buf, u64::MAX - 1), Err(Error::Truncated{ needed: 4, got: 0, .. })));
+    }
+
+    #[test]
+    fn test_find() {
+        let archive = SpkArchive::from_bytes(fixtures::spk(&[ ("MDL/1.MDL", b"1"), ("mdl/2.mdl", b"2"), ("QGM/1.QGM", b"3"), ("Mdl/1.mdl", b"4") ])).unwrap();
+        assert_eq!(archive.read_item(archive.find("mdl/1.MDL").unwrap()).unwrap(), b"1");
+        assert_eq!(archive.find("MDL/2.MDL").unwrap().filename, "mdl/2.mdl");
+        assert!(archive.find("1.MDL").is_none());
+        let names: Vec<_> = archive.find_prefix("MDL/").map(|item| item.filename.as_str()).collect();
+        assert_eq!(names, [ "MDL/1.MDL", "mdl/2.mdl", "Mdl/1.mdl" ]);
+        assert_eq!(archive.find_prefix("").count(), 4);
+        assert_eq!(archive.find_prefix("nod/").count(), 0);
+    }
+
+    #[test]
+    fn test_read_item_to() {
+        let contents: Vec<u8> = (0..COPY_CHUNK_SIZE * 2 + 10).map(|n| (n % 251) as u8).collect();
+        let mut data = Vec::new();
+        SpkArchive::create(&mut data, &[ ("BIG", &contents), ("SMALL", b"abc") ]).unwrap();
+        let path = std::env::temp_dir().join(format!("qfg5-read-item-to-{}.spk", std::process::id()));
+        std::fs::write(&path, &data).unwrap();
+        let from_file = SpkArchive::from_file(File::open(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let from_bytes = SpkArchive::from_bytes(data).unwrap();
+        for archive in [ from_file, from_bytes ] {
+            let mut out = Vec::new();
+            assert_eq!(archive.read_item_to(&archive.get_items()[0], &mut out).unwrap(), contents.len() as u64);
+            assert_eq!(out, contents);
+            let mut out = Vec::new();
+            assert_eq!(archive.read_item_to(&archive.get_items()[1], &mut out).unwrap(), 3);
+            assert_eq!(out, b"abc");
+
+            // Nothing is written when the archive is too short
+            let item = &archive.get_items()[1];
+            let past_end = SpkItem{ filename: item.filename.clone(), offset: archive.length - 2, length: 3, compression: item.compression, stored_length: 3, crc32: 0 };
+            let mut out = Vec::new();
+            assert!(matches!(archive.read_item_to(&past_end, &mut out), Err(Error::Truncated{ needed: 3, got: 2, .. })));
+            assert!(out.is_empty());
+        }
+
+        let archive = SpkArchive::from_bytes(fixtures::spk_compressed(&[ ("A", &contents, true) ])).unwrap();
+        let mut out = Vec::new();
+        assert_eq!(archive.read_item_to(&archive.get_items()[0], &mut out).unwrap(), contents.len() as u64);
+        assert_eq!(out, contents);
+    }
+
+    #[test]
+    fn test_corrupt_compressed() {
+        let mut data = fixtures::spk_compressed(&[ ("A/1.NOD", &[ 1u8; 100 ], true) ]);
+        let archive = SpkArchive::from_bytes(data.clone()).unwrap();
+        let item = &archive.get_items()[0];
+        // Invalid block type
+        data[item.offset as usize] = 0xff;
+        let corrupt = SpkArchive::from_bytes(data).unwrap();
+        let err = corrupt.read_item(&corrupt.get_items()[0]).unwrap_err().to_string();
+        assert!(err.starts_with("invalid deflate data of A/1.NOD ("), "{}", err);
+
+        // The data does not decompress to the size in the directory
+        let other_length = |length| SpkItem{ filename: item.filename.clone(), offset: item.offset, length, compression: item.compression, stored_length: item.stored_length, crc32: item.crc32 };
+        assert!(archive.read_item(&other_length(10)).is_err());
+        let err = archive.read_item(&other_length(200)).unwrap_err().to_string();
+        assert!(err.starts_with("invalid deflate data of A/1.NOD (100 bytes instead of 200) at offset "), "{}", err);
+    }
+}
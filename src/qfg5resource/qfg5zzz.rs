@@ -4,8 +4,8 @@
  * Copyright (c) 2024 Rink Springer <rink@rink.nu>
  * For conditions of distribution and use, see LICENSE file
  */
-use anyhow::Result;
 use crate::qfg5resource::{decode, qfg5img};
+use crate::qfg5resource::error::{Error, Result};
 
 pub struct ZzzDecoder {
     width: u16,
@@ -17,8 +17,12 @@ impl ZzzDecoder {
     pub fn new(zzz_data: &[u8], img: &qfg5img::ImageDecoder) -> Result<Self> {
         let width = img.get_width();
         let height = img.get_height();
-        let mut pixels = vec![ 0u8; width as usize * height as usize ];
-        decode::decode_rle(zzz_data, &mut pixels);
+        let num_pixels = width as usize * height as usize;
+        if num_pixels > decode::max_rle_pixels(zzz_data.len()) {
+            return Err(Error::Truncated{ needed: num_pixels / 64, got: zzz_data.len(), at: 0 });
+        }
+        let mut pixels = vec![ 0u8; num_pixels ];
+        decode::decode_rle(decode::RleMode::Img, zzz_data, &mut pixels, 0)?;
         Ok(ZzzDecoder{ height, width, pixels })
     }
 
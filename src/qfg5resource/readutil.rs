@@ -0,0 +1,157 @@
+/*-
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * Copyright (c) 2024 Rink Springer <rink@rink.nu>
+ * For conditions of distribution and use, see LICENSE file
+ */
+//! Reading of the structures that most formats share: zero-padded names, lists of numbers and
+//! tables of offsets to the items of a list.
+use std::ops::{Deref, DerefMut};
+use crate::qfg5resource::decode::Reader;
+use crate::qfg5resource::error::{Error, ErrorContext, Result};
+
+/// A zero-padded name. The game uses a single-byte character set, so bytes are read as Latin-1
+/// instead of failing on anything that is not UTF-8. The name ends at the first zero byte.
+pub fn decode_name(bytes: &[u8]) -> String {
+    let length = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    bytes[..length].iter().map(|&b| b as char).collect()
+}
+
+/// A fixed-size name as decode_name() reads it, along with the bytes as stored: the padding is
+/// not always zeros, and writing the bytes back keeps it
+pub fn read_name<const N: usize>(cursor: &mut Reader) -> Result<(String, [ u8; N ])> {
+    let raw: [ u8; N ] = cursor.bytes(N)?.try_into().unwrap();
+    Ok((decode_name(&raw), raw))
+}
+
+pub fn read_vec_f32(cursor: &mut Reader, count: usize) -> Result<Vec<f32>> {
+    cursor.check_remaining(count, 4)?;
+    (0..count).map(|_| cursor.f32()).collect()
+}
+
+pub fn read_array_f32<const N: usize>(cursor: &mut Reader) -> Result<[ f32; N ]> {
+    let mut values = [ 0f32; N ];
+    for value in values.iter_mut() {
+        *value = cursor.f32()?;
+    }
+    Ok(values)
+}
+
+pub fn read_vec_u32(cursor: &mut Reader, count: usize) -> Result<Vec<u32>> {
+    cursor.check_remaining(count, 4)?;
+    (0..count).map(|_| cursor.u32()).collect()
+}
+
+/// Reads `count` u32 offsets relative to `base`, failing at the first one that is past the end of
+/// the data
+pub fn read_offset_table(cursor: &mut Reader, count: usize, base: u64) -> Result<Vec<u64>> {
+    cursor.check_remaining(count, 4)?;
+    (0..count).map(|_| {
+        let at = cursor.position();
+        let offset = base + cursor.u32()? as u64;
+        check_offset(cursor, offset, at)?;
+        Ok(offset)
+    }).collect()
+}
+
+fn check_offset(cursor: &Reader, offset: u64, at: u64) -> Result<()> {
+    if offset > cursor.size() as u64 {
+        return Err(Error::Invalid{ what: format!("offset {:#x} past the end of the data", offset), at });
+    }
+    Ok(())
+}
+
+/// Reads from an offset found in the data, moving back to where the reader was when dropped.
+/// The offset is checked up front, so that a corrupt one is reported where it was read instead of
+/// as truncated data further on.
+pub struct SeekGuard<'r, 'a> {
+    cursor: &'r mut Reader<'a>,
+    previous: u64,
+}
+
+impl<'r, 'a> SeekGuard<'r, 'a> {
+    /// Moves to `offset`, which was read at `at`
+    pub fn new(cursor: &'r mut Reader<'a>, offset: u64, at: u64) -> Result<Self> {
+        check_offset(cursor, offset, at)?;
+        let previous = cursor.position();
+        cursor.seek(offset);
+        Ok(SeekGuard{ cursor, previous })
+    }
+}
+
+impl<'a> Deref for SeekGuard<'_, 'a> {
+    type Target = Reader<'a>;
+
+    fn deref(&self) -> &Reader<'a> {
+        self.cursor
+    }
+}
+
+impl<'a> DerefMut for SeekGuard<'_, 'a> {
+    fn deref_mut(&mut self) -> &mut Reader<'a> {
+        self.cursor
+    }
+}
+
+impl Drop for SeekGuard<'_, '_> {
+    fn drop(&mut self) {
+        self.cursor.seek(self.previous);
+    }
+}
+
+/// Reads the part of the data at `offset`, which was read at `at`, using a SeekGuard. Errors in
+/// the part tell which part it was; a corrupt offset is reported as is.
+pub fn read_part<'a, T>(cursor: &mut Reader<'a>, offset: u64, at: u64, what: impl FnOnce() -> String,
+                        read: impl FnOnce(&mut Reader<'a>) -> Result<T>) -> Result<T> {
+    let mut cursor = SeekGuard::new(cursor, offset, at)?;
+    read(&mut cursor).with_context(offset, what)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_name() {
+        let mut cursor = Reader::new(b"ab\0x\xe9c\0\0abcd");
+        assert_eq!(read_name::<4>(&mut cursor).unwrap(), ("ab".to_string(), *b"ab\0x"));
+        assert_eq!(read_name::<4>(&mut cursor).unwrap(), ("\u{e9}c".to_string(), *b"\xe9c\0\0"));
+        // No zero byte at all
+        assert_eq!(read_name::<4>(&mut cursor).unwrap().0, "abcd");
+        assert!(matches!(read_name::<1>(&mut cursor), Err(Error::Truncated{ needed: 1, got: 0, at: 12 })));
+    }
+
+    #[test]
+    fn test_read_offset_table() {
+        let data = [ 4u32, 8, 12 ].iter().flat_map(|v| v.to_le_bytes()).collect::<Vec<_>>();
+        assert_eq!(read_offset_table(&mut Reader::new(&data), 3, 0).unwrap(), [ 4, 8, 12 ]);
+        let err = read_offset_table(&mut Reader::new(&data), 3, 4).unwrap_err();
+        assert!(matches!(err, Error::Invalid{ at: 8, .. }), "{}", err);
+        assert!(matches!(read_offset_table(&mut Reader::new(&data), 4, 0), Err(Error::Truncated{ needed: 16, got: 12, at: 0 })));
+    }
+
+    #[test]
+    fn test_seek_guard() {
+        let data = [ 1u8, 0, 0, 0, 2, 0, 0, 0 ];
+        let mut cursor = Reader::new(&data);
+        cursor.seek(2);
+        {
+            let mut guard = SeekGuard::new(&mut cursor, 4, 0).unwrap();
+            assert_eq!(read_vec_u32(&mut guard, 1).unwrap(), [ 2 ]);
+        }
+        assert_eq!(cursor.position(), 2);
+        assert!(matches!(SeekGuard::new(&mut cursor, 9, 1), Err(Error::Invalid{ at: 1, .. })));
+    }
+
+    #[test]
+    fn test_read_part() {
+        let data = [ 1u8, 0, 0, 0, 2, 0, 0, 0 ];
+        let mut cursor = Reader::new(&data);
+        assert_eq!(read_part(&mut cursor, 4, 0, || "list".to_string(), |cursor| read_vec_u32(cursor, 1)).unwrap(), [ 2 ]);
+        assert_eq!(cursor.position(), 0);
+        let err = read_part(&mut cursor, 4, 0, || "list".to_string(), |cursor| read_vec_u32(cursor, 2)).unwrap_err();
+        assert_eq!(err.to_string(), "list at offset 0x4: truncated at offset 0x4: needed 8 bytes, got 4");
+        assert!(matches!(err.root(), Error::Truncated{ at: 4, .. }));
+        assert!(matches!(read_part(&mut cursor, 9, 1, String::new, |cursor| cursor.u8()), Err(Error::Invalid{ at: 1, .. })));
+    }
+}
@@ -0,0 +1,288 @@
+/*-
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * Copyright (c) 2024 Rink Springer <rink@rink.nu>
+ * For conditions of distribution and use, see LICENSE file
+ */
+//! A common interface to the decoders that need nothing but the contents of a file, so that tools
+//! can handle resources without knowing their kind. ZZZ depth maps also need the room's IMG and an
+//! SPK archive holds many resources, so these two are decoded on their own.
+use std::fmt;
+use super::decode::Reader;
+use super::trace::ParseTrace;
+use super::{qfg5anm, qfg5gra, qfg5img, qfg5mdl, qfg5nod, qfg5qgf, qfg5qgm, qfg5rgd, ResourceKind, Result};
+
+/// What a resource contains, for listings
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ResourceSummary {
+    pub name: Option<String>,
+    /// Number of items of every kind, such as ("submeshes", 3)
+    pub counts: Vec<(&'static str, usize)>,
+    /// Width and height, for resources that have a single size
+    pub dimensions: Option<(u32, u32)>,
+}
+
+impl fmt::Display for ResourceSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(name) = &self.name {
+            parts.push(format!("'{}'", name));
+        }
+        if let Some((width, height)) = self.dimensions {
+            parts.push(format!("{}x{}", width, height));
+        }
+        parts.extend(self.counts.iter().map(|(what, count)| format!("{} {}", count, what)));
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+pub trait Qfg5Resource: Sized {
+    const KIND: ResourceKind;
+
+    fn parse(data: &[u8]) -> Result<Self>;
+
+    /// Like parse(), but also records where every field was found, also when decoding fails.
+    /// None for decoders that do not record their fields yet
+    fn parse_traced(_data: &[u8]) -> Option<(Result<Self>, ParseTrace)> {
+        None
+    }
+
+    fn summary(&self) -> ResourceSummary;
+
+    /// Problems that did not stop decoding, such as indices that are out of range
+    fn validate(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+impl Qfg5Resource for qfg5anm::AnmDecoder {
+    const KIND: ResourceKind = ResourceKind::Anm;
+
+    fn parse(data: &[u8]) -> Result<Self> {
+        Self::new(data)
+    }
+
+    fn parse_traced(data: &[u8]) -> Option<(Result<Self>, ParseTrace)> {
+        Some(Reader::trace(data, Self::read))
+    }
+
+    fn summary(&self) -> ResourceSummary {
+        ResourceSummary{
+            name: Some(self.name.clone()),
+            counts: vec![ ("anims", self.anims.len()), ("frames", self.num_frames()) ],
+            dimensions: None,
+        }
+    }
+
+    fn validate(&self) -> Vec<String> {
+        qfg5anm::AnmDecoder::validate(self)
+    }
+}
+
+impl Qfg5Resource for qfg5gra::GraDecoder<'static> {
+    const KIND: ResourceKind = ResourceKind::Gra;
+
+    fn parse(data: &[u8]) -> Result<Self> {
+        Self::new(data)
+    }
+
+    fn summary(&self) -> ResourceSummary {
+        let frames = self.sprite_collections.iter().map(|sc| sc.sprites.len()).sum();
+        ResourceSummary{
+            counts: vec![ ("sprite collections", self.sprite_collections.len()), ("frames", frames) ],
+            ..Default::default()
+        }
+    }
+}
+
+impl Qfg5Resource for qfg5img::ImageDecoder {
+    const KIND: ResourceKind = ResourceKind::Img;
+
+    fn parse(data: &[u8]) -> Result<Self> {
+        Self::new(data)
+    }
+
+    fn summary(&self) -> ResourceSummary {
+        ResourceSummary{ dimensions: Some((self.get_width() as u32, self.get_height() as u32)), ..Default::default() }
+    }
+}
+
+impl Qfg5Resource for qfg5mdl::Qfg5Model<'static> {
+    const KIND: ResourceKind = ResourceKind::Mdl;
+
+    fn parse(data: &[u8]) -> Result<Self> {
+        Self::new(data)
+    }
+
+    fn parse_traced(data: &[u8]) -> Option<(Result<Self>, ParseTrace)> {
+        Some(Reader::trace(data, |cursor| qfg5mdl::Qfg5Model::read(cursor).map(qfg5mdl::Qfg5Model::into_owned)))
+    }
+
+    fn summary(&self) -> ResourceSummary {
+        ResourceSummary{
+            name: Some(self.name.clone()),
+            counts: vec![
+                ("submeshes", self.submeshes.len()),
+                ("vertices", self.submeshes.iter().map(|s| s.vertices.len()).sum()),
+                ("faces", self.submeshes.iter().map(|s| s.faces.len()).sum()),
+                ("subbitmaps", self.subbitmaps.len()),
+            ],
+            dimensions: None,
+        }
+    }
+
+    fn validate(&self) -> Vec<String> {
+        qfg5mdl::Qfg5Model::validate(self)
+    }
+}
+
+impl Qfg5Resource for qfg5nod::NodDecoder {
+    const KIND: ResourceKind = ResourceKind::Nod;
+
+    fn parse(data: &[u8]) -> Result<Self> {
+        Self::new(data)
+    }
+
+    /// Only the palette is decoded, so there is nothing to count yet
+    fn summary(&self) -> ResourceSummary {
+        ResourceSummary::default()
+    }
+}
+
+impl Qfg5Resource for qfg5qgf::QgfDecoder {
+    const KIND: ResourceKind = ResourceKind::Qgf;
+
+    fn parse(data: &[u8]) -> Result<Self> {
+        Self::new(data)
+    }
+
+    /// The dimensions are those of the widest character
+    fn summary(&self) -> ResourceSummary {
+        ResourceSummary{
+            counts: vec![ ("chars", self.chars.len()) ],
+            dimensions: Some((self.max_char_width, self.char_height)),
+            ..Default::default()
+        }
+    }
+}
+
+impl Qfg5Resource for qfg5qgm::QgmDecoder {
+    const KIND: ResourceKind = ResourceKind::Qgm;
+
+    fn parse(data: &[u8]) -> Result<Self> {
+        Self::new(data)
+    }
+
+    fn parse_traced(data: &[u8]) -> Option<(Result<Self>, ParseTrace)> {
+        Some(Reader::trace(data, Self::read))
+    }
+
+    fn summary(&self) -> ResourceSummary {
+        ResourceSummary{ counts: vec![ ("messages", self.messages.len()) ], ..Default::default() }
+    }
+
+    fn validate(&self) -> Vec<String> {
+        qfg5qgm::QgmDecoder::validate(self)
+    }
+}
+
+impl Qfg5Resource for qfg5rgd::RgdDecoder {
+    const KIND: ResourceKind = ResourceKind::Rgd;
+
+    fn parse(data: &[u8]) -> Result<Self> {
+        Self::new(data)
+    }
+
+    fn parse_traced(data: &[u8]) -> Option<(Result<Self>, ParseTrace)> {
+        Some(Reader::trace(data, Self::read))
+    }
+
+    fn summary(&self) -> ResourceSummary {
+        ResourceSummary{
+            counts: vec![ ("points", self.points.len()), ("segments", self.segments.len()), ("regions", self.regions.len()) ],
+            ..Default::default()
+        }
+    }
+
+    fn validate(&self) -> Vec<String> {
+        qfg5rgd::RgdDecoder::validate(self)
+    }
+}
+
+/// What a traced decoder made of a resource, and where it found it
+pub type TracedSummary = (Result<ResourceSummary>, ParseTrace);
+
+/// A Qfg5Resource implementation, for when the kind is only known at run time
+pub struct Registration {
+    pub kind: ResourceKind,
+    pub summarize: fn(&[u8]) -> Result<ResourceSummary>,
+    pub validate: fn(&[u8]) -> Result<Vec<String>>,
+    /// See Qfg5Resource::parse_traced(); the summary if decoding succeeded
+    pub trace: fn(&[u8]) -> Option<TracedSummary>,
+}
+
+impl Registration {
+    const fn of<T: Qfg5Resource>() -> Self {
+        Registration{
+            kind: T::KIND,
+            summarize: |data| T::parse(data).map(|resource| resource.summary()),
+            validate: |data| T::parse(data).map(|resource| resource.validate()),
+            trace: |data| T::parse_traced(data).map(|(resource, trace)| (resource.map(|resource| resource.summary()), trace)),
+        }
+    }
+}
+
+pub static REGISTRY: [ Registration; 8 ] = [
+    Registration::of::<qfg5anm::AnmDecoder>(),
+    Registration::of::<qfg5gra::GraDecoder>(),
+    Registration::of::<qfg5img::ImageDecoder>(),
+    Registration::of::<qfg5mdl::Qfg5Model>(),
+    Registration::of::<qfg5nod::NodDecoder>(),
+    Registration::of::<qfg5qgf::QgfDecoder>(),
+    Registration::of::<qfg5qgm::QgmDecoder>(),
+    Registration::of::<qfg5rgd::RgdDecoder>(),
+];
+
+/// The implementation for `kind`, if it can be decoded on its own
+pub fn registration(kind: ResourceKind) -> Option<&'static Registration> {
+    REGISTRY.iter().find(|registration| registration.kind == kind)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qfg5resource::fixtures;
+
+    #[test]
+    fn test_summary() {
+        let summary = (registration(ResourceKind::Mdl).unwrap().summarize)(&fixtures::mdl()).unwrap();
+        assert_eq!(summary.to_string(), "'fixture', 1 submeshes, 4 vertices, 2 faces, 1 subbitmaps");
+        let summary = qfg5img::ImageDecoder::parse(&fixtures::img()).unwrap().summary();
+        assert_eq!(summary.dimensions, Some((4, 2)));
+        assert!(registration(ResourceKind::Zzz).is_none());
+        for registration in &REGISTRY {
+            assert!((registration.summarize)(b"").is_err(), "{}", registration.kind);
+            assert!((registration.validate)(b"").is_err(), "{}", registration.kind);
+        }
+    }
+
+    #[test]
+    fn test_parse_traced() {
+        let (anm, trace) = qfg5anm::AnmDecoder::parse_traced(&fixtures::anm()).unwrap();
+        assert_eq!(anm.unwrap(), qfg5anm::AnmDecoder::new(&fixtures::anm()).unwrap());
+        assert_eq!(trace.records[2].label, "name 'fixture'");
+        // Every byte of an ANM is understood
+        assert_eq!(trace.unparsed(fixtures::anm().len() as u64), 0);
+        for (kind, data) in [ (ResourceKind::Mdl, fixtures::mdl()), (ResourceKind::Qgm, fixtures::qgm()), (ResourceKind::Rgd, fixtures::rgd()) ] {
+            let (summary, trace) = (registration(kind).unwrap().trace)(&data).unwrap();
+            assert_eq!(summary.unwrap(), (registration(kind).unwrap().summarize)(&data).unwrap());
+            assert!(trace.records.iter().all(|record| record.offset + record.length <= data.len() as u64), "{}", kind);
+            assert!(trace.unparsed(data.len() as u64) < data.len() as u64 / 2, "{}", kind);
+        }
+        assert!((registration(ResourceKind::Gra).unwrap().trace)(&fixtures::gra()).is_none());
+
+        let (rgd, trace) = qfg5rgd::RgdDecoder::parse_traced(b"\0\0\0\0\x02\0\0\0").unwrap();
+        assert!(rgd.is_err());
+        assert_eq!(trace.records.len(), 0);
+    }
+}
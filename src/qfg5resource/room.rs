@@ -0,0 +1,41 @@
+/*-
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * Copyright (c) 2024 Rink Springer <rink@rink.nu>
+ * For conditions of distribution and use, see LICENSE file
+ */
+use anyhow::{Context, Result};
+use std::path::Path;
+use crate::qfg5resource::{names, qfg5img, qfg5nod, qfg5rgd, qfg5zzz};
+
+/// The 2D resources of a single room: background, palette, depth map and regions
+pub struct Room {
+    pub id: u32,
+    pub img: qfg5img::ImageDecoder,
+    pub nod: qfg5nod::NodDecoder,
+    pub zzz: qfg5zzz::ZzzDecoder,
+    /// Not every room has regions, and their decoding is incomplete
+    pub rgd: Option<qfg5rgd::RgdDecoder>,
+}
+
+impl Room {
+    /// Loads room `id` from an extracted data tree, i.e. img/<id>.img, nod/<id>.nod and so on
+    pub fn load(data_dir: &Path, id: u32) -> Result<Self> {
+        let files = names::room_files(id);
+        let read = |path: &Path| {
+            let path = data_dir.join(path);
+            std::fs::read(&path).with_context(|| format!("cannot read {}", path.display()))
+        };
+        let img = qfg5img::ImageDecoder::new(&read(&files.img)?)?;
+        let nod = qfg5nod::NodDecoder::new(&read(&files.nod)?)?;
+        let zzz = qfg5zzz::ZzzDecoder::new(&read(&files.zzz)?, &img)?;
+        let rgd = match read(&files.rgd).and_then(|data| Ok(qfg5rgd::RgdDecoder::new(&data)?)) {
+            Ok(rgd) => Some(rgd),
+            Err(e) => {
+                log::warn!("room {}: no regions: {:#}", id, e);
+                None
+            }
+        };
+        Ok(Room{ id, img, nod, zzz, rgd })
+    }
+}
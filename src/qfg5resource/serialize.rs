@@ -0,0 +1,74 @@
+/*-
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * Copyright (c) 2024 Rink Springer <rink@rink.nu>
+ * For conditions of distribution and use, see LICENSE file
+ */
+//! Serde helpers for fields that do not map well onto JSON. Binary data, such as pixels and
+//! bitmaps, is stored as a base64 string instead of an array of numbers.
+
+/// Vec<u8> or Cow<[u8]> as a base64 string
+pub mod base64 {
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer, B: AsRef<[u8]>>(bytes: &B, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, B: From<Vec<u8>>>(deserializer: D) -> Result<B, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        STANDARD.decode(s).map(B::from).map_err(serde::de::Error::custom)
+    }
+}
+
+/// A 256-entry palette, which is longer than the arrays serde supports
+pub mod palette {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use crate::qfg5resource::qfg5nod::PaletteEntry;
+
+    pub fn serialize<S: Serializer>(palette: &[ PaletteEntry; 256 ], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(palette.iter())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[ PaletteEntry; 256 ], D::Error> {
+        let entries = Vec::<PaletteEntry>::deserialize(deserializer)?;
+        let len = entries.len();
+        entries.try_into().map_err(|_| serde::de::Error::invalid_length(len, &"256 palette entries"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::qfg5resource::*;
+    use crate::qfg5resource::fixtures;
+
+    /// Checks that deserializing and serializing again gives the same JSON
+    fn round_trip<T: serde::Serialize + serde::de::DeserializeOwned>(value: &T) -> String {
+        let json = serde_json::to_string(value).unwrap();
+        let value: T = serde_json::from_str(&json).unwrap();
+        assert_eq!(serde_json::to_string(&value).unwrap(), json);
+        json
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let json = round_trip(&qfg5mdl::Qfg5Model::new(&fixtures::mdl()).unwrap());
+        // Bitmap [ 1, 0, 0, 1 ]
+        assert!(json.contains(r#""bitmap":"AQAAAQ==""#));
+        round_trip(&qfg5anm::AnmDecoder::new(&fixtures::anm()).unwrap());
+        let json = round_trip(&qfg5gra::GraDecoder::new(&fixtures::gra()).unwrap());
+        assert!(json.contains(r#""palette":[[0,0,0],[255,0,0],[0,0,0]"#));
+        let json = round_trip(&qfg5qgm::QgmDecoder::new(&fixtures::qgm()).unwrap());
+        assert!(json.contains(r#""message_label":"LABEL0000001""#));
+        round_trip(&qfg5qgf::QgfDecoder::new(&fixtures::qgf()).unwrap());
+        round_trip(&qfg5rgd::RgdDecoder::new(&fixtures::rgd()).unwrap());
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(serde_json::from_str::<qfg5gra::GraDecoder>(r#"{"palette":[[0,0,0]],"sprite_collections":[]}"#).is_err());
+        assert!(serde_json::from_str::<qfg5qgm::QgmLabel>(r#""TOO SHORT""#).is_err());
+    }
+}
@@ -0,0 +1,91 @@
+/*-
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * Copyright (c) 2024 Rink Springer <rink@rink.nu>
+ * For conditions of distribution and use, see LICENSE file
+ */
+//! Where a decoder found what: every field it reads can be recorded with a label and its value,
+//! so that the parts of a resource nobody has worked out yet stand out as the gaps in between.
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct TraceRecord {
+    pub offset: u64,
+    pub length: u64,
+    /// Name of the field, usually with its value
+    pub label: String,
+}
+
+/// The records of a decoder, in the order it read them
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ParseTrace {
+    pub records: Vec<TraceRecord>,
+}
+
+/// A part of a resource, see ParseTrace::regions()
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Region<'t> {
+    Parsed(&'t TraceRecord),
+    /// Bytes no record covers
+    Gap{ offset: u64, length: u64 },
+}
+
+impl ParseTrace {
+    pub fn push(&mut self, offset: u64, length: u64, label: String) {
+        self.records.push(TraceRecord{ offset, length, label });
+    }
+
+    /// The records by offset, with gaps for what they leave uncovered of the first `size` bytes.
+    /// Records may overlap, for instance a list and its items.
+    pub fn regions(&self, size: u64) -> Vec<Region<'_>> {
+        let mut records: Vec<_> = self.records.iter().collect();
+        records.sort_by_key(|record| (record.offset, std::cmp::Reverse(record.length)));
+        let mut regions = Vec::new();
+        let mut covered = 0;
+        for record in records {
+            if record.offset > covered {
+                regions.push(Region::Gap{ offset: covered, length: record.offset.min(size).saturating_sub(covered) });
+            }
+            regions.push(Region::Parsed(record));
+            covered = covered.max(record.offset + record.length);
+        }
+        if covered < size {
+            regions.push(Region::Gap{ offset: covered, length: size - covered });
+        }
+        regions.retain(|region| !matches!(region, Region::Gap{ length: 0, .. }));
+        regions
+    }
+
+    /// Number of the first `size` bytes that no record covers
+    pub fn unparsed(&self, size: u64) -> u64 {
+        self.regions(size).iter().map(|region| match region {
+            Region::Gap{ length, .. } => *length,
+            Region::Parsed(_) => 0,
+        }).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_regions() {
+        let mut trace = ParseTrace::default();
+        trace.push(8, 4, "b".to_string());
+        trace.push(4, 2, "a".to_string());
+        // Inside the list that follows
+        trace.push(14, 2, "item".to_string());
+        trace.push(12, 8, "list".to_string());
+        let regions = trace.regions(24);
+        let labels: Vec<_> = regions.iter().map(|region| match region {
+            Region::Parsed(record) => format!("{}@{}", record.label, record.offset),
+            Region::Gap{ offset, length } => format!("gap@{}+{}", offset, length),
+        }).collect();
+        assert_eq!(labels, [ "gap@0+4", "a@4", "gap@6+2", "b@8", "list@12", "item@14", "gap@20+4" ]);
+        assert_eq!(trace.unparsed(24), 10);
+        assert_eq!(trace.unparsed(20), 6);
+        assert_eq!(ParseTrace::default().regions(3), [ Region::Gap{ offset: 0, length: 3 } ]);
+    }
+}
@@ -4,7 +4,24 @@
  * Copyright (c) 2024 Rink Springer <rink@rink.nu>
  * For conditions of distribution and use, see LICENSE file
  */
+//! Rendering with wgpu for the viewers, behind the "viewer" feature. Posing models only needs
+//! cgmath, so the pose module is always there for the tools that export models.
+#[cfg(feature = "viewer")]
 pub mod camera;
+#[cfg(feature = "viewer")]
+pub mod light;
+#[cfg(feature = "viewer")]
+pub mod lines;
+#[cfg(feature = "viewer")]
 pub mod model;
+pub mod pose;
+#[cfg(feature = "viewer")]
+pub mod profiling;
+#[cfg(feature = "viewer")]
+pub mod quad;
+#[cfg(feature = "viewer")]
 pub mod resources;
-pub mod texture;
\ No newline at end of file
+#[cfg(feature = "viewer")]
+pub mod screenshot;
+#[cfg(feature = "viewer")]
+pub mod texture;
@@ -6,32 +6,112 @@
  */
 use winit::{
     event::*,
-    keyboard::{KeyCode, PhysicalKey},
+    keyboard::{KeyCode, ModifiersState, PhysicalKey},
 };
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Projection {
+    /// Vertical field of view in degrees
+    Perspective{ fovy: f32 },
+    /// Height of the view volume in world units
+    Orthographic{ height: f32 },
+}
+
+impl Projection {
+    /// Scales the visible area; this is what zooming changes
+    pub fn scaled(self, factor: f32) -> Self {
+        match self {
+            Projection::Perspective{ fovy } => Projection::Perspective{ fovy: fovy * factor },
+            Projection::Orthographic{ height } => Projection::Orthographic{ height: height * factor },
+        }
+    }
+
+    fn is_same_kind(&self, other: &Projection) -> bool {
+        std::mem::discriminant(self) == std::mem::discriminant(other)
+    }
+}
+
 pub struct Camera {
     pub eye: cgmath::Point3<f32>,
     pub target: cgmath::Point3<f32>,
     pub up: cgmath::Vector3<f32>,
     pub aspect: f32,
-    pub fovy: f32,
+    pub projection: Projection,
     pub znear: f32,
     pub zfar: f32,
 }
 
+// Maps OpenGL depth (-1..1) to wgpu depth (0..1); note that the arguments are column-major
 #[rustfmt::skip]
 const OPENGL_TO_WGPU_MATRIX: cgmath::Matrix4<f32> = cgmath::Matrix4::new(
     1.0, 0.0, 0.0, 0.0,
     0.0, 1.0, 0.0, 0.0,
-    0.0, 0.0, 0.5, 0.5,
-    0.0, 0.0, 0.0, 1.0,
+    0.0, 0.0, 0.5, 0.0,
+    0.0, 0.0, 0.5, 1.0,
 );
 
 impl Camera {
+    /// Updates the aspect ratio for a new viewport size; a zero-sized (minimized) viewport is ignored
+    pub fn resize(&mut self, width: u32, height: u32) {
+        if width > 0 && height > 0 {
+            self.aspect = width as f32 / height as f32;
+        }
+    }
+
+    /// Looks at a sphere from the current direction, from far enough away that all of it is in
+    /// view, and moves the far plane behind it
+    pub fn frame(&mut self, center: cgmath::Point3<f32>, radius: f32) {
+        use cgmath::InnerSpace;
+        // Some room around the sphere
+        let radius = if radius > 0.0 { radius * 1.1 } else { 1.0 };
+        let direction = (self.eye - self.target).normalize();
+        let distance = match self.projection {
+            Projection::Perspective{ fovy } => {
+                let half_fovy = (fovy / 2.0).to_radians();
+                let half_fovx = (half_fovy.tan() * self.aspect).atan();
+                radius / half_fovy.min(half_fovx).sin()
+            },
+            Projection::Orthographic{ .. } => {
+                self.projection = Projection::Orthographic{ height: 2.0 * radius * (1.0 / self.aspect).max(1.0) };
+                2.0 * radius
+            },
+        };
+        self.target = center;
+        self.eye = center + direction * distance;
+        self.zfar = self.zfar.max(distance + 2.0 * radius);
+    }
+
+    /// Switches between perspective and orthographic projection, keeping the size of
+    /// objects at the target the same
+    pub fn toggle_projection(&mut self) {
+        use cgmath::InnerSpace;
+        let distance = (self.target - self.eye).magnitude();
+        self.projection = match self.projection {
+            Projection::Perspective{ fovy } => {
+                Projection::Orthographic{ height: 2.0 * distance * (fovy.to_radians() / 2.0).tan() }
+            },
+            Projection::Orthographic{ height } => {
+                Projection::Perspective{ fovy: (2.0 * (height / (2.0 * distance)).atan()).to_degrees() }
+            },
+        };
+    }
+
+    fn build_projection_matrix(&self) -> cgmath::Matrix4<f32> {
+        let proj = match self.projection {
+            Projection::Perspective{ fovy } => {
+                cgmath::perspective(cgmath::Deg(fovy), self.aspect, self.znear, self.zfar)
+            },
+            Projection::Orthographic{ height } => {
+                let width = height * self.aspect;
+                cgmath::ortho(-width / 2.0, width / 2.0, -height / 2.0, height / 2.0, self.znear, self.zfar)
+            },
+        };
+        OPENGL_TO_WGPU_MATRIX * proj
+    }
+
     fn build_view_projection_matrix(&self) -> cgmath::Matrix4<f32> {
         let view = cgmath::Matrix4::look_at_rh(self.eye, self.target, self.up);
-        let proj = cgmath::perspective(cgmath::Deg(self.fovy), self.aspect, self.znear, self.zfar);
-        return OPENGL_TO_WGPU_MATRIX * proj * view;
+        return self.build_projection_matrix() * view;
     }
 }
 
@@ -56,6 +136,15 @@ impl CameraUniform {
     }
 }
 
+const SPEED_STEP: f32 = 1.25;
+const MIN_SPEED: f32 = 0.01;
+const MAX_SPEED: f32 = 100.0;
+const SPRINT_MULTIPLIER: f32 = 4.0;
+/// Field of view (or orthographic height) while zoomed, relative to the normal one
+const ZOOM_FOVY_SCALE: f32 = 0.25;
+/// Fraction of the remaining zoom distance covered per update
+const ZOOM_SMOOTHING: f32 = 0.2;
+
 pub struct CameraController {
     speed: f32,
     is_forward_pressed: bool,
@@ -64,6 +153,13 @@ pub struct CameraController {
     is_right_pressed: bool,
     is_up_pressed: bool,
     is_down_pressed: bool,
+    is_sprint_pressed: bool,
+    is_zoom_pressed: bool,
+    is_ctrl_pressed: bool,
+    // 0.0 is the normal field of view, 1.0 is fully zoomed in
+    zoom: f32,
+    // Projection of the camera without zoom applied, captured on first update
+    base_projection: Option<Projection>,
 }
 
 impl CameraController {
@@ -76,9 +172,88 @@ impl CameraController {
             is_right_pressed: false,
             is_up_pressed: false,
             is_down_pressed: false,
+            is_sprint_pressed: false,
+            is_zoom_pressed: false,
+            is_ctrl_pressed: false,
+            zoom: 0.0,
+            base_projection: None,
         }
     }
 
+    /// Base speed, as adjusted by the user
+    pub fn speed(&self) -> f32 {
+        self.speed
+    }
+
+    /// Speed that is actually used to move the camera
+    pub fn effective_speed(&self) -> f32 {
+        if self.is_sprint_pressed { self.speed * SPRINT_MULTIPLIER } else { self.speed }
+    }
+
+    pub fn increase_speed(&mut self) {
+        self.speed = (self.speed * SPEED_STEP).min(MAX_SPEED);
+    }
+
+    pub fn decrease_speed(&mut self) {
+        self.speed = (self.speed / SPEED_STEP).max(MIN_SPEED);
+    }
+
+    pub fn set_modifiers(&mut self, modifiers: ModifiersState) {
+        self.is_sprint_pressed = modifiers.shift_key();
+        self.is_ctrl_pressed = modifiers.control_key();
+    }
+
+    pub fn process_key(&mut self, keycode: KeyCode, is_pressed: bool) -> bool {
+        match keycode {
+            KeyCode::KeyW | KeyCode::ArrowUp => {
+                self.is_forward_pressed = is_pressed;
+                true
+            }
+            KeyCode::KeyA | KeyCode::ArrowLeft => {
+                self.is_left_pressed = is_pressed;
+                true
+            }
+            KeyCode::KeyS | KeyCode::ArrowDown => {
+                self.is_backward_pressed = is_pressed;
+                true
+            }
+            KeyCode::KeyD | KeyCode::ArrowRight => {
+                self.is_right_pressed = is_pressed;
+                true
+            }
+            KeyCode::KeyQ => {
+                self.is_up_pressed = is_pressed;
+                true
+            },
+            KeyCode::KeyE => {
+                self.is_down_pressed = is_pressed;
+                true
+            },
+            KeyCode::KeyZ => {
+                self.is_zoom_pressed = is_pressed;
+                true
+            },
+            KeyCode::NumpadAdd => {
+                if is_pressed { self.increase_speed(); }
+                true
+            },
+            KeyCode::NumpadSubtract => {
+                if is_pressed { self.decrease_speed(); }
+                true
+            },
+            _ => false,
+        }
+    }
+
+    /// Ctrl+scroll adjusts the speed; returns whether the scroll was used
+    pub fn process_scroll(&mut self, delta: f32) -> bool {
+        if !self.is_ctrl_pressed || delta == 0.0 {
+            return false;
+        }
+        if delta > 0.0 { self.increase_speed(); } else { self.decrease_speed(); }
+        true
+    }
+
     pub fn process_events(&mut self, event: &WindowEvent) -> bool {
         match event {
             WindowEvent::KeyboardInput {
@@ -91,50 +266,56 @@ impl CameraController {
                 ..
             } => {
                 let is_pressed = *state == ElementState::Pressed;
-                match keycode {KeyCode::KeyW | KeyCode::ArrowUp => {
-                        self.is_forward_pressed = is_pressed;
-                        true
-                    }
-                    KeyCode::KeyA | KeyCode::ArrowLeft => {
-                        self.is_left_pressed = is_pressed;
-                        true
-                    }
-                    KeyCode::KeyS | KeyCode::ArrowDown => {
-                        self.is_backward_pressed = is_pressed;
-                        true
-                    }
-                    KeyCode::KeyD | KeyCode::ArrowRight => {
-                        self.is_right_pressed = is_pressed;
-                        true
-                    }
-                    KeyCode::KeyQ => {
-                        self.is_up_pressed = is_pressed;
-                        true
-                    },
-                    KeyCode::KeyE => {
-                        self.is_down_pressed = is_pressed;
-                        true
-                    },
-                    _ => false,
-                }
+                self.process_key(*keycode, is_pressed)
+            }
+            WindowEvent::ModifiersChanged(modifiers) => {
+                self.set_modifiers(modifiers.state());
+                false
+            }
+            WindowEvent::MouseWheel { delta, .. } => {
+                let delta = match delta {
+                    MouseScrollDelta::LineDelta(_, y) => *y,
+                    MouseScrollDelta::PixelDelta(pos) => pos.y as f32,
+                };
+                self.process_scroll(delta)
             }
             _ => false,
         }
     }
 
-    pub fn update_camera(&self, camera: &mut Camera) {
+    fn update_zoom(&mut self, camera: &mut Camera) {
+        let zoom_factor = |zoom: f32| 1.0 - zoom * (1.0 - ZOOM_FOVY_SCALE);
+        // When the kind of projection changed, derive the new base from the (zoomed) camera
+        let base = match self.base_projection {
+            Some(base) if base.is_same_kind(&camera.projection) => base,
+            _ => camera.projection.scaled(1.0 / zoom_factor(self.zoom)),
+        };
+        self.base_projection = Some(base);
+
+        let target = if self.is_zoom_pressed { 1.0 } else { 0.0 };
+        self.zoom += (target - self.zoom) * ZOOM_SMOOTHING;
+        if (target - self.zoom).abs() < 0.001 {
+            self.zoom = target;
+        }
+        camera.projection = base.scaled(zoom_factor(self.zoom));
+    }
+
+    pub fn update_camera(&mut self, camera: &mut Camera) {
         use cgmath::InnerSpace;
+        self.update_zoom(camera);
+
+        let speed = self.effective_speed();
         let forward = camera.target - camera.eye;
         let forward_norm = forward.normalize();
         let forward_mag = forward.magnitude();
 
         // Prevents glitching when the camera gets too close to the
         // center of the scene.
-        if self.is_forward_pressed && forward_mag > self.speed {
-            camera.eye += forward_norm * self.speed;
+        if self.is_forward_pressed && forward_mag > speed {
+            camera.eye += forward_norm * speed;
         }
         if self.is_backward_pressed {
-            camera.eye -= forward_norm * self.speed;
+            camera.eye -= forward_norm * speed;
         }
         let right = forward_norm.cross(camera.up);
 
@@ -146,16 +327,198 @@ impl CameraController {
             // Rescale the distance between the target and the eye so 
             // that it doesn't change. The eye, therefore, still 
             // lies on the circle made by the target and eye.
-            camera.eye = camera.target - (forward + right * self.speed).normalize() * forward_mag;
+            camera.eye = camera.target - (forward + right * speed).normalize() * forward_mag;
         }
         if self.is_left_pressed {
-            camera.eye = camera.target - (forward - right * self.speed).normalize() * forward_mag;
+            camera.eye = camera.target - (forward - right * speed).normalize() * forward_mag;
         }
         if self.is_up_pressed {
-            camera.eye += cgmath::Vector3{ x: 0.0, y: 1.0, z: 0.0 } * self.speed;
+            camera.eye += cgmath::Vector3{ x: 0.0, y: 1.0, z: 0.0 } * speed;
         }
         if self.is_down_pressed {
-            camera.eye -= cgmath::Vector3{ x: 0.0, y: 1.0, z: 0.0 } * self.speed;
+            camera.eye -= cgmath::Vector3{ x: 0.0, y: 1.0, z: 0.0 } * speed;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_camera() -> Camera {
+        Camera {
+            eye: (0.0, 0.0, 10.0).into(),
+            target: (0.0, 0.0, 0.0).into(),
+            up: cgmath::Vector3::unit_y(),
+            aspect: 1.0,
+            projection: Projection::Perspective{ fovy: 40.0 },
+            znear: 0.1,
+            zfar: 100.0,
+        }
+    }
+
+    #[test]
+    fn test_frame() {
+        use cgmath::{InnerSpace, Point3, Transform};
+        let mut camera = test_camera();
+        camera.aspect = 0.5;
+        let center = Point3{ x: 100.0, y: 0.0, z: 0.0 };
+        camera.frame(center, 50.0);
+        assert_eq!(camera.target, center);
+        assert_eq!((camera.eye - center).normalize(), cgmath::Vector3::unit_z());
+        // The extremes of the sphere are within the view volume
+        let view_proj = camera.build_view_projection_matrix();
+        for offset in [ (50.0, 0.0, 0.0), (-50.0, 0.0, 0.0), (0.0, 50.0, 0.0), (0.0, -50.0, 0.0), (0.0, 0.0, 50.0), (0.0, 0.0, -50.0) ] {
+            let p = view_proj.transform_point(center + cgmath::Vector3::from(offset));
+            assert!(p.x.abs() <= 1.0 && p.y.abs() <= 1.0 && (0.0..=1.0).contains(&p.z), "{:?} {:?}", offset, p);
         }
     }
+
+    #[test]
+    fn test_projection_follows_aspect() {
+        let mut camera = test_camera();
+        let square = camera.build_view_projection_matrix();
+        camera.resize(200, 100);
+        assert_eq!(camera.aspect, 2.0);
+        let wide = camera.build_view_projection_matrix();
+        // A wider viewport squeezes x, but leaves y alone
+        assert_eq!(wide.x.x, square.x.x / 2.0);
+        assert_eq!(wide.y.y, square.y.y);
+
+        camera.resize(200, 0);
+        assert_eq!(camera.aspect, 2.0);
+        assert_eq!(camera.build_view_projection_matrix(), wide);
+    }
+
+    #[test]
+    fn test_speed_adjustment() {
+        let mut controller = CameraController::new(1.0);
+        assert!(controller.process_key(KeyCode::NumpadAdd, true));
+        assert_eq!(controller.speed(), SPEED_STEP);
+        // Releasing the key must not change the speed again
+        assert!(controller.process_key(KeyCode::NumpadAdd, false));
+        assert_eq!(controller.speed(), SPEED_STEP);
+        controller.process_key(KeyCode::NumpadSubtract, true);
+        assert_eq!(controller.speed(), 1.0);
+
+        for _ in 0..1000 { controller.decrease_speed(); }
+        assert_eq!(controller.speed(), MIN_SPEED);
+        for _ in 0..1000 { controller.increase_speed(); }
+        assert_eq!(controller.speed(), MAX_SPEED);
+    }
+
+    #[test]
+    fn test_scroll_requires_ctrl() {
+        let mut controller = CameraController::new(1.0);
+        assert!(!controller.process_scroll(1.0));
+        assert_eq!(controller.speed(), 1.0);
+        controller.set_modifiers(ModifiersState::CONTROL);
+        assert!(controller.process_scroll(1.0));
+        assert_eq!(controller.speed(), SPEED_STEP);
+        assert!(controller.process_scroll(-1.0));
+        assert_eq!(controller.speed(), 1.0);
+    }
+
+    #[test]
+    fn test_sprint() {
+        let mut controller = CameraController::new(2.0);
+        controller.set_modifiers(ModifiersState::SHIFT);
+        assert_eq!(controller.effective_speed(), 2.0 * SPRINT_MULTIPLIER);
+        assert_eq!(controller.speed(), 2.0);
+        controller.set_modifiers(ModifiersState::empty());
+        assert_eq!(controller.effective_speed(), 2.0);
+
+        let mut camera = test_camera();
+        controller.set_modifiers(ModifiersState::SHIFT);
+        controller.process_key(KeyCode::KeyS, true);
+        controller.update_camera(&mut camera);
+        assert_eq!(camera.eye.z, 10.0 + 2.0 * SPRINT_MULTIPLIER);
+    }
+
+    fn fovy(camera: &Camera) -> f32 {
+        let Projection::Perspective{ fovy } = camera.projection else { panic!("not a perspective projection") };
+        fovy
+    }
+
+    #[test]
+    fn test_zoom_interpolation() {
+        let mut controller = CameraController::new(1.0);
+        let mut camera = test_camera();
+        controller.process_key(KeyCode::KeyZ, true);
+        controller.update_camera(&mut camera);
+        // Zooming is gradual
+        assert!(fovy(&camera) < 40.0 && fovy(&camera) > 40.0 * ZOOM_FOVY_SCALE);
+        for _ in 0..100 { controller.update_camera(&mut camera); }
+        assert_eq!(fovy(&camera), 40.0 * ZOOM_FOVY_SCALE);
+
+        controller.process_key(KeyCode::KeyZ, false);
+        controller.update_camera(&mut camera);
+        assert!(fovy(&camera) < 40.0 && fovy(&camera) > 40.0 * ZOOM_FOVY_SCALE);
+        for _ in 0..100 { controller.update_camera(&mut camera); }
+        assert_eq!(fovy(&camera), 40.0);
+    }
+
+    #[test]
+    fn test_orthographic_zoom() {
+        let mut controller = CameraController::new(1.0);
+        let mut camera = test_camera();
+        camera.projection = Projection::Orthographic{ height: 8.0 };
+        controller.process_key(KeyCode::KeyZ, true);
+        for _ in 0..100 { controller.update_camera(&mut camera); }
+        // Zooming scales the height; the eye stays where it is
+        assert_eq!(camera.projection, Projection::Orthographic{ height: 8.0 * ZOOM_FOVY_SCALE });
+        assert_eq!(camera.eye, (0.0, 0.0, 10.0).into());
+
+        // Switching projection while zoomed keeps the zoom factor
+        camera.toggle_projection();
+        controller.process_key(KeyCode::KeyZ, false);
+        for _ in 0..100 { controller.update_camera(&mut camera); }
+        let expected = (2.0 * (2.0f32 / 20.0).atan()).to_degrees() / ZOOM_FOVY_SCALE;
+        assert!((fovy(&camera) - expected).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_toggle_projection() {
+        let mut camera = test_camera();
+        camera.projection = Projection::Perspective{ fovy: 90.0 };
+        camera.toggle_projection();
+        // Objects at the target, 10 units away, fill the same height
+        let Projection::Orthographic{ height } = camera.projection else { panic!("not orthographic") };
+        assert!((height - 20.0).abs() < 1e-4);
+        camera.toggle_projection();
+        assert!((fovy(&camera) - 90.0).abs() < 1e-4);
+    }
+
+    fn assert_matrix_eq(actual: cgmath::Matrix4<f32>, expected: [[f32; 4]; 4]) {
+        let actual: [[f32; 4]; 4] = actual.into();
+        for (a, e) in actual.iter().flatten().zip(expected.iter().flatten()) {
+            assert!((a - e).abs() < 1e-5, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn test_projection_matrices() {
+        let mut camera = test_camera();
+        camera.znear = 1.0;
+        camera.zfar = 3.0;
+
+        // 90 degrees gives a focal length of 1; depth maps near..far to 0..1
+        camera.projection = Projection::Perspective{ fovy: 90.0 };
+        assert_matrix_eq(camera.build_projection_matrix(), [
+            [ 1.0, 0.0, 0.0, 0.0 ],
+            [ 0.0, 1.0, 0.0, 0.0 ],
+            [ 0.0, 0.0, -1.5, -1.0 ],
+            [ 0.0, 0.0, -1.5, 0.0 ],
+        ]);
+
+        // 8 wide and 4 high
+        camera.aspect = 2.0;
+        camera.projection = Projection::Orthographic{ height: 4.0 };
+        assert_matrix_eq(camera.build_projection_matrix(), [
+            [ 0.25, 0.0, 0.0, 0.0 ],
+            [ 0.0, 0.5, 0.0, 0.0 ],
+            [ 0.0, 0.0, -0.5, 0.0 ],
+            [ 0.0, 0.0, -0.5, 1.0 ],
+        ]);
+    }
 }
@@ -0,0 +1,146 @@
+/*-
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * Copyright (c) 2024 Rink Springer <rink@rink.nu>
+ * For conditions of distribution and use, see LICENSE file
+ */
+use cgmath::Vector3;
+use wgpu::util::DeviceExt;
+
+/// Matches LightUniform in the shader
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct LightUniform {
+    /// Normalized direction towards the light
+    pub direction: [f32; 3],
+    pub ambient: f32,
+    pub color: [f32; 3],
+    /// 0.0 renders unlit
+    pub enabled: f32,
+}
+
+/// A directional light, such as the sun
+#[derive(Debug, Clone, PartialEq)]
+pub struct Light {
+    /// Rotation around the up (Y) axis in degrees, 0 is towards +Z
+    pub azimuth: f32,
+    /// Angle above the horizon in degrees
+    pub elevation: f32,
+    pub color: [f32; 3],
+    /// Fraction of the light that reaches surfaces facing away from it
+    pub ambient: f32,
+    pub enabled: bool,
+}
+
+impl Default for Light {
+    fn default() -> Self {
+        Self{ azimuth: 30.0, elevation: 45.0, color: [ 1.0; 3 ], ambient: 0.3, enabled: true }
+    }
+}
+
+impl Light {
+    pub fn direction(&self) -> Vector3<f32> {
+        let (azimuth, elevation) = (self.azimuth.to_radians(), self.elevation.to_radians());
+        Vector3::new(elevation.cos() * azimuth.sin(), elevation.sin(), elevation.cos() * azimuth.cos())
+    }
+
+    /// Elevation is limited to straight above or below
+    pub fn rotate(&mut self, azimuth: f32, elevation: f32) {
+        self.azimuth = (self.azimuth + azimuth).rem_euclid(360.0);
+        self.elevation = (self.elevation + elevation).clamp(-90.0, 90.0);
+    }
+
+    pub fn adjust_ambient(&mut self, delta: f32) {
+        self.ambient = (self.ambient + delta).clamp(0.0, 1.0);
+    }
+
+    pub fn to_uniform(&self) -> LightUniform {
+        LightUniform{
+            direction: self.direction().into(),
+            ambient: self.ambient,
+            color: self.color,
+            enabled: if self.enabled { 1.0 } else { 0.0 },
+        }
+    }
+}
+
+/// Layout of the light bind group, which the model shader expects as group 2
+pub fn create_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+    device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        entries: &[
+            wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            }
+        ],
+        label: Some("light_bind_group_layout"),
+    })
+}
+
+/// The uniform buffer of a light and the bind group referring to it
+pub struct LightBinding {
+    buffer: wgpu::Buffer,
+    pub bind_group: wgpu::BindGroup,
+}
+
+impl LightBinding {
+    pub fn new(device: &wgpu::Device, layout: &wgpu::BindGroupLayout, light: &Light) -> Self {
+        let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Light Buffer"),
+            contents: bytemuck::cast_slice(&[ light.to_uniform() ]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: buffer.as_entire_binding(),
+                }
+            ],
+            label: Some("light_bind_group"),
+        });
+        Self{ buffer, bind_group }
+    }
+
+    pub fn update(&self, queue: &wgpu::Queue, light: &Light) {
+        queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(&[ light.to_uniform() ]));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::InnerSpace;
+
+    #[test]
+    fn test_direction() {
+        let mut light = Light{ azimuth: 0.0, elevation: 0.0, ..Default::default() };
+        assert!((light.direction() - Vector3::unit_z()).magnitude() < 1e-6);
+        light.rotate(90.0, 0.0);
+        assert!((light.direction() - Vector3::unit_x()).magnitude() < 1e-6);
+        light.rotate(0.0, 120.0);
+        assert_eq!(light.elevation, 90.0);
+        assert!((light.direction() - Vector3::unit_y()).magnitude() < 1e-6);
+        light.rotate(-180.0, 0.0);
+        assert_eq!(light.azimuth, 270.0);
+    }
+
+    #[test]
+    fn test_uniform() {
+        let mut light = Light{ azimuth: 0.0, elevation: 0.0, ..Default::default() };
+        light.adjust_ambient(1.0);
+        light.enabled = false;
+        let uniform = light.to_uniform();
+        assert_eq!(uniform.ambient, 1.0);
+        assert_eq!(uniform.enabled, 0.0);
+        // Laid out like the WGSL struct: two vec3 each followed by a f32
+        assert_eq!(std::mem::size_of::<LightUniform>(), 32);
+    }
+}
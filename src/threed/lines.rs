@@ -0,0 +1,150 @@
+/*-
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * Copyright (c) 2024 Rink Springer <rink@rink.nu>
+ * For conditions of distribution and use, see LICENSE file
+ */
+use cgmath::{Matrix4, Point3, Transform};
+use wgpu::util::DeviceExt;
+use crate::threed::texture;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct LineVertex {
+    pub position: [f32; 3],
+    pub color: [f32; 3],
+}
+
+impl LineVertex {
+    fn desc() -> wgpu::VertexBufferLayout<'static> {
+        use std::mem;
+        wgpu::VertexBufferLayout {
+            array_stride: mem::size_of::<LineVertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: &[
+                wgpu::VertexAttribute {
+                    offset: 0,
+                    shader_location: 0,
+                    format: wgpu::VertexFormat::Float32x3,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 3]>() as wgpu::BufferAddress,
+                    shader_location: 1,
+                    format: wgpu::VertexFormat::Float32x3,
+                },
+            ],
+        }
+    }
+}
+
+/// Red, green and blue lines of `length` along the X, Y and Z axes of `transform`, starting at its origin
+pub fn axis_triad(transform: &Matrix4<f32>, length: f32) -> [LineVertex; 6] {
+    let point = |x, y, z| -> [f32; 3] { transform.transform_point(Point3{ x, y, z }).into() };
+    let origin = point(0.0, 0.0, 0.0);
+    let red = [ 1.0, 0.0, 0.0 ];
+    let green = [ 0.0, 1.0, 0.0 ];
+    let blue = [ 0.0, 0.0, 1.0 ];
+    [
+        LineVertex{ position: origin, color: red }, LineVertex{ position: point(length, 0.0, 0.0), color: red },
+        LineVertex{ position: origin, color: green }, LineVertex{ position: point(0.0, length, 0.0), color: green },
+        LineVertex{ position: origin, color: blue }, LineVertex{ position: point(0.0, 0.0, length), color: blue },
+    ]
+}
+
+/// Pipeline to draw a list of colored lines on top of the scene, ignoring the depth buffer.
+/// The camera bind group is expected at group 0.
+pub struct LineRenderer {
+    pipeline: wgpu::RenderPipeline,
+    vertex_buffer: Option<wgpu::Buffer>,
+    num_vertices: u32,
+}
+
+impl LineRenderer {
+    pub fn new(device: &wgpu::Device, format: wgpu::TextureFormat, camera_bind_group_layout: &wgpu::BindGroupLayout) -> Self {
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Line Shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("lines.wgsl").into()),
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Line Pipeline Layout"),
+            bind_group_layouts: &[camera_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Line Pipeline"),
+            layout: Some(&pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: Some("vs_main"),
+                buffers: &[ LineVertex::desc() ],
+                compilation_options: wgpu::PipelineCompilationOptions::default(),
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: Some("fs_main"),
+                targets: &[Some(wgpu::ColorTargetState {
+                    format,
+                    blend: Some(wgpu::BlendState::REPLACE),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+                compilation_options: wgpu::PipelineCompilationOptions::default(),
+            }),
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::LineList,
+                ..Default::default()
+            },
+            // Compatible with the scene's depth buffer, but always drawn on top
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: texture::Texture::DEPTH_FORMAT,
+                depth_write_enabled: false,
+                depth_compare: wgpu::CompareFunction::Always,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+            }),
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+            cache: None,
+        });
+        Self{ pipeline, vertex_buffer: None, num_vertices: 0 }
+    }
+
+    /// Replaces the lines to draw; every pair of vertices is a line
+    pub fn set_lines(&mut self, device: &wgpu::Device, vertices: &[LineVertex]) {
+        self.num_vertices = vertices.len() as u32;
+        self.vertex_buffer = if vertices.is_empty() {
+            None
+        } else {
+            Some(device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("Line Vertex Buffer"),
+                contents: bytemuck::cast_slice(vertices),
+                usage: wgpu::BufferUsages::VERTEX,
+            }))
+        };
+    }
+
+    pub fn draw<'a>(&'a self, render_pass: &mut wgpu::RenderPass<'a>, camera_bind_group: &'a wgpu::BindGroup) {
+        let Some(vertex_buffer) = &self.vertex_buffer else { return; };
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(0, camera_bind_group, &[]);
+        render_pass.set_vertex_buffer(0, vertex_buffer.slice(..));
+        render_pass.draw(0..self.num_vertices, 0..1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_axis_triad() {
+        let transform = Matrix4::from_translation(cgmath::Vector3{ x: 1.0, y: 2.0, z: 3.0 }) * Matrix4::from_scale(2.0);
+        let triad = axis_triad(&transform, 0.5);
+        let positions: Vec<_> = triad.iter().map(|v| v.position).collect();
+        assert_eq!(positions, vec![
+            [ 1.0, 2.0, 3.0 ], [ 2.0, 2.0, 3.0 ],
+            [ 1.0, 2.0, 3.0 ], [ 1.0, 3.0, 3.0 ],
+            [ 1.0, 2.0, 3.0 ], [ 1.0, 2.0, 4.0 ],
+        ]);
+        assert_eq!(triad[3].color, [ 0.0, 1.0, 0.0 ]);
+    }
+}
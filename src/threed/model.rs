@@ -8,7 +8,7 @@ use crate::threed::texture;
 use std::ops::Range;
 
 #[repr(C)]
-#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+#[derive(Copy, Clone, Debug, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct Vertex {
     pub position: [f32; 3],
     pub tex_coords: [f32; 2],
@@ -19,11 +19,23 @@ pub trait ModelVertex {
     fn desc() -> wgpu::VertexBufferLayout<'static>;
 }
 
+/// All meshes of a model share a single vertex, color and index buffer
 pub struct Model {
+    pub vertex_buffer: wgpu::Buffer,
+    /// Optional per-vertex RGBA colors, see ColorVertex
+    pub color_buffer: Option<wgpu::Buffer>,
+    pub index_buffer: wgpu::Buffer,
     pub meshes: Vec<Mesh>,
     pub materials: Vec<Material>,
 }
 
+impl Model {
+    /// Total size of the GPU buffers holding the geometry, in bytes
+    pub fn buffer_size(&self) -> u64 {
+        self.vertex_buffer.size() + self.index_buffer.size() + self.color_buffer.as_ref().map_or(0, |b| b.size())
+    }
+}
+
 impl ModelVertex for Vertex {
     fn desc() -> wgpu::VertexBufferLayout<'static> {
         use std::mem;
@@ -51,95 +63,162 @@ impl ModelVertex for Vertex {
     }
 }
 
+/// Per-vertex color, kept in a separate buffer (slot 2) so that Vertex does not grow for
+/// meshes without colors
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct ColorVertex {
+    pub color: [f32; 4],
+}
+
+impl ModelVertex for ColorVertex {
+    fn desc() -> wgpu::VertexBufferLayout<'static> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<ColorVertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: &[
+                wgpu::VertexAttribute {
+                    offset: 0,
+                    shader_location: 3,
+                    format: wgpu::VertexFormat::Float32x4,
+                },
+            ],
+        }
+    }
+}
+
 pub struct Material {
     pub name: String,
     pub diffuse_texture: texture::Texture,
     pub bind_group: wgpu::BindGroup,
 }
 
+/// Indices of a mesh that are drawn with the same material
+#[derive(Clone, Debug, PartialEq)]
+pub struct MaterialRange {
+    pub indices: Range<u32>,
+    pub material: usize,
+}
+
+/// Part of the index buffer of a Model
+#[derive(Clone, Debug, PartialEq)]
 pub struct Mesh {
-    pub vertex_buffer: wgpu::Buffer,
-    pub index_buffer: wgpu::Buffer,
-    pub num_elements: u32,
+    pub indices: Range<u32>,
     pub material: usize,
+    /// If empty, the entire mesh is drawn using `material`
+    pub material_ranges: Vec<MaterialRange>,
 }
 
 
 pub trait DrawModel<'a> {
-    fn draw_mesh(&mut self, mesh: &'a Mesh, material: &'a Material, camera_bind_group: &'a wgpu::BindGroup);
+    fn draw_mesh(&mut self, model: &'a Model, mesh_index: usize, camera_bind_group: &'a wgpu::BindGroup);
     fn draw_mesh_instanced(
         &mut self,
-        mesh: &'a Mesh,
-        material: &'a Material,
+        model: &'a Model,
+        mesh_index: usize,
         instances: Range<u32>,
         camera_bind_group: &'a wgpu::BindGroup,
     );
-
-    fn draw_model(&mut self, model: &'a Model, camera_bind_group: &'a wgpu::BindGroup);
-    fn draw_model_instanced(
+    /// Draws a number of meshes, each with their own instances; the buffers of the model are
+    /// only bound once
+    fn draw_meshes_instanced<I: IntoIterator<Item = (usize, Range<u32>)>>(
+        &mut self,
+        model: &'a Model,
+        meshes: I,
+        camera_bind_group: &'a wgpu::BindGroup,
+    );
+    /// Draws the mesh using `material`, ignoring the materials of the mesh itself
+    fn draw_mesh_with_material(
         &mut self,
         model: &'a Model,
+        mesh_index: usize,
+        material: &'a Material,
         instances: Range<u32>,
         camera_bind_group: &'a wgpu::BindGroup,
     );
 
-    fn draw_single_mesh_instanced(
+    fn draw_model(&mut self, model: &'a Model, camera_bind_group: &'a wgpu::BindGroup);
+    fn draw_model_instanced(
         &mut self,
         model: &'a Model,
-        mesh_index: usize,
         instances: Range<u32>,
         camera_bind_group: &'a wgpu::BindGroup,
     );
 }
 
+fn set_model_buffers<'a>(pass: &mut wgpu::RenderPass<'a>, model: &'a Model, camera_bind_group: &'a wgpu::BindGroup) {
+    pass.set_vertex_buffer(0, model.vertex_buffer.slice(..));
+    if let Some(color_buffer) = &model.color_buffer {
+        pass.set_vertex_buffer(2, color_buffer.slice(..));
+    }
+    pass.set_index_buffer(model.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+    pass.set_bind_group(1, camera_bind_group, &[]);
+}
+
 impl<'a, 'b> DrawModel<'b> for wgpu::RenderPass<'a>
 where
     'b: 'a,
 {
-    fn draw_mesh(&mut self, mesh: &'b Mesh, material: &'b Material, camera_bind_group: &'b wgpu::BindGroup) {
-        self.draw_mesh_instanced(mesh, material, 0..1, camera_bind_group);
+    fn draw_mesh(&mut self, model: &'b Model, mesh_index: usize, camera_bind_group: &'b wgpu::BindGroup) {
+        self.draw_mesh_instanced(model, mesh_index, 0..1, camera_bind_group);
     }
 
     fn draw_mesh_instanced(
         &mut self,
-        mesh: &'b Mesh,
-        material: &'b Material,
+        model: &'b Model,
+        mesh_index: usize,
         instances: Range<u32>,
         camera_bind_group: &'b wgpu::BindGroup,
     ) {
-        self.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
-        self.set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
-        self.set_bind_group(0, &material.bind_group, &[]);
-        self.set_bind_group(1, camera_bind_group, &[]);
-        self.draw_indexed(0..mesh.num_elements, 0, instances);
+        self.draw_meshes_instanced(model, [ (mesh_index, instances) ], camera_bind_group);
     }
 
-    fn draw_model(&mut self, model: &'b Model, camera_bind_group: &'b wgpu::BindGroup) {
-        self.draw_model_instanced(model, 0..1, camera_bind_group);
+    fn draw_meshes_instanced<I: IntoIterator<Item = (usize, Range<u32>)>>(
+        &mut self,
+        model: &'b Model,
+        meshes: I,
+        camera_bind_group: &'b wgpu::BindGroup,
+    ) {
+        set_model_buffers(self, model, camera_bind_group);
+        // Only switch materials when needed
+        let mut current_material = None;
+        for (mesh_index, instances) in meshes {
+            let mesh = &model.meshes[mesh_index];
+            let whole_mesh = [ MaterialRange{ indices: mesh.indices.clone(), material: mesh.material } ];
+            let ranges = if mesh.material_ranges.is_empty() { &whole_mesh[..] } else { &mesh.material_ranges[..] };
+            for range in ranges {
+                if current_material != Some(range.material) {
+                    self.set_bind_group(0, &model.materials[range.material].bind_group, &[]);
+                    current_material = Some(range.material);
+                }
+                self.draw_indexed(range.indices.clone(), 0, instances.clone());
+            }
+        }
     }
 
-    fn draw_model_instanced(
+    fn draw_mesh_with_material(
         &mut self,
         model: &'b Model,
+        mesh_index: usize,
+        material: &'b Material,
         instances: Range<u32>,
         camera_bind_group: &'b wgpu::BindGroup,
     ) {
-        for mesh in &model.meshes {
-            let material = &model.materials[mesh.material];
-            self.draw_mesh_instanced(mesh, material, instances.clone(), camera_bind_group);
-        }
+        set_model_buffers(self, model, camera_bind_group);
+        self.set_bind_group(0, &material.bind_group, &[]);
+        self.draw_indexed(model.meshes[mesh_index].indices.clone(), 0, instances);
+    }
+
+    fn draw_model(&mut self, model: &'b Model, camera_bind_group: &'b wgpu::BindGroup) {
+        self.draw_model_instanced(model, 0..1, camera_bind_group);
     }
 
-    fn draw_single_mesh_instanced(
+    fn draw_model_instanced(
         &mut self,
         model: &'b Model,
-        mesh_index: usize,
         instances: Range<u32>,
         camera_bind_group: &'b wgpu::BindGroup,
-    )
-    {
-        let mesh = &model.meshes[mesh_index];
-        let material = &model.materials[mesh.material];
-        self.draw_mesh_instanced(mesh, material, instances.clone(), camera_bind_group);
+    ) {
+        self.draw_meshes_instanced(model, (0..model.meshes.len()).map(|n| (n, instances.clone())), camera_bind_group);
     }
 }
@@ -0,0 +1,127 @@
+/*-
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * Copyright (c) 2024 Rink Springer <rink@rink.nu>
+ * For conditions of distribution and use, see LICENSE file
+ */
+use cgmath::{Matrix4, Vector3, Vector4};
+use crate::qfg5resource::{qfg5anm, qfg5mdl};
+
+/// A submesh placed in the world by a transform
+pub struct PosePart {
+    pub submesh: usize,
+    pub transform: Matrix4<f32>,
+}
+
+/// The submeshes of a model as they are displayed, with their transforms
+pub struct Pose {
+    pub parts: Vec<PosePart>,
+}
+
+/// Converts an animation block to a model matrix; the rotation is stored row-major
+pub fn anm_block_transform(block: &qfg5anm::AnmBlock) -> Matrix4<f32> {
+    let r = &block.rotation;
+    let t = &block.translation;
+    let x = Vector4{ x: r[0], y: r[3], z: r[6], w: 0.0 };
+    let y = Vector4{ x: r[1], y: r[4], z: r[7], w: 0.0 };
+    let z = Vector4{ x: r[2], y: r[5], z: r[8], w: 0.0 };
+    let w = Vector4{ x: t[0], y: t[1], z: t[2], w: 1.0 };
+    Matrix4{ x, y, z, w }
+}
+
+/// Rotates the model's Z-up axis so that it points up
+fn z_up_rotation() -> Matrix4<f32> {
+    use cgmath::Rotation3;
+    let rotation = cgmath::Quaternion::from_axis_angle(Vector3::unit_x(), cgmath::Deg(-90.0));
+    Matrix4::from(rotation)
+}
+
+impl Pose {
+    /// A single submesh, rotated so that the model's Z-up axis points up
+    pub fn submesh(index: usize) -> Self {
+        Pose{ parts: vec![ PosePart{ submesh: index, transform: z_up_rotation() } ] }
+    }
+
+    /// All submeshes side by side in a grid, each centered in a cell large enough to hold any
+    /// of them. Rows run along X and downwards in Y, facing the camera.
+    pub fn exploded(model: &qfg5mdl::Qfg5Model) -> Self {
+        let bounds: Vec<_> = model.submeshes.iter().map(|submesh| submesh.bounds().unwrap_or_default()).collect();
+        let largest = bounds.iter().map(|b| { let s = b.size(); s.x.max(s.y).max(s.z) }).fold(0.0f32, f32::max);
+        // Leave some space between the cells
+        let cell_size = if largest > 0.0 { largest * 1.25 } else { 1.0 };
+        let columns = (bounds.len() as f32).sqrt().ceil().max(1.0) as usize;
+
+        let parts = bounds.iter().enumerate().map(|(submesh, b)| {
+            let center = b.center();
+            let cell = Vector3{ x: (submesh % columns) as f32 * cell_size, y: -((submesh / columns) as f32) * cell_size, z: 0.0 };
+            let transform = Matrix4::from_translation(cell) * z_up_rotation() * Matrix4::from_translation(Vector3{ x: -center.x, y: -center.y, z: -center.z });
+            PosePart{ submesh, transform }
+        }).collect();
+        Pose{ parts }
+    }
+
+    /// All submeshes, positioned as in frame `frame` of the animation
+    pub fn anm_frame(anm: &qfg5anm::AnmDecoder, frame: usize) -> Self {
+        let parts = anm.anims.iter().enumerate().map(|(submesh, anim)| {
+            PosePart{ submesh, transform: anm_block_transform(&anim.blocks[frame]) }
+        }).collect();
+        Pose{ parts }
+    }
+
+    /// Bounds of the parts as placed, or None if they have no vertices
+    pub fn bounds(&self, model: &qfg5mdl::Qfg5Model) -> Option<qfg5mdl::Qfg5Bounds> {
+        use cgmath::{Point3, Transform};
+        let vertices = self.parts.iter().filter_map(|part| Some((model.submeshes.get(part.submesh)?, part.transform))).flat_map(|(submesh, transform)| {
+            submesh.vertices.iter().map(move |v| {
+                let p = transform.transform_point(Point3{ x: v.x, y: v.y, z: v.z });
+                qfg5mdl::Qfg5Vertex{ x: p.x, y: p.y, z: p.z }
+            })
+        });
+        qfg5mdl::Qfg5Bounds::from_vertices(vertices)
+    }
+
+    /// All submeshes, positioned as in the animation `time` seconds in, interpolating between frames
+    pub fn anm_time(anm: &qfg5anm::AnmDecoder, time: f32) -> Self {
+        let parts = (0..anm.anims.len()).map(|submesh| {
+            PosePart{ submesh, transform: anm.sample(submesh, time).transform() }
+        }).collect();
+        Pose{ parts }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::{Point3, Transform};
+
+    #[test]
+    fn test_exploded() {
+        let submesh = |x: f32| qfg5mdl::SubMesh{
+            vertices: vec![ qfg5mdl::Qfg5Vertex{ x, y: 0.0, z: 0.0 }, qfg5mdl::Qfg5Vertex{ x: x + 2.0, y: 1.0, z: 4.0 } ],
+            ..Default::default()
+        };
+        let model = qfg5mdl::Qfg5Model{ submeshes: (0..3).map(|n| submesh(n as f32 * 10.0)).collect(), ..Default::default() };
+        let pose = Pose::exploded(&model);
+        assert_eq!(pose.parts.len(), 3);
+
+        // Two columns of 4 * 1.25 wide; the center of each submesh ends up in its cell
+        let centers: Vec<_> = pose.parts.iter().map(|part| {
+            let c = model.submeshes[part.submesh].bounds().unwrap().center();
+            part.transform.transform_point(Point3{ x: c.x, y: c.y, z: c.z })
+        }).collect();
+        for (center, expected) in centers.iter().zip([ (0.0, 0.0), (5.0, 0.0), (0.0, -5.0) ]) {
+            assert!((center.x - expected.0).abs() < 1e-5 && (center.y - expected.1).abs() < 1e-5 && center.z.abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn test_bounds() {
+        let model = qfg5mdl::Qfg5Model::new(&crate::qfg5resource::fixtures::mdl()).unwrap();
+        let anm = qfg5anm::AnmDecoder::new(&crate::qfg5resource::fixtures::anm()).unwrap();
+        assert_eq!(Pose::anm_frame(&anm, 1).bounds(&model), model.frame_bounds(&anm, 1));
+        // Z-up becomes Y-up: the quad in the XY plane ends up in the XZ plane
+        let bounds = Pose::submesh(0).bounds(&model).unwrap();
+        assert!((bounds.size().x - 1.0).abs() < 1e-5 && bounds.size().y.abs() < 1e-5 && (bounds.size().z - 1.0).abs() < 1e-5);
+        assert_eq!(Pose::submesh(1).bounds(&model), None);
+    }
+}
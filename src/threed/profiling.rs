@@ -0,0 +1,229 @@
+/*-
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * Copyright (c) 2024 Rink Springer <rink@rink.nu>
+ * For conditions of distribution and use, see LICENSE file
+ */
+//! Frame timing for the overlay of the viewers: the time between frames on the CPU and, where the
+//! adapter supports timestamp queries, the time the GPU spends on the scene.
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use web_time::Instant;
+
+/// Number of frames the statistics are taken over
+pub const WINDOW_FRAMES: usize = 240;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FrameStats {
+    /// Average over the window
+    pub fps: f32,
+    /// Frame rate of the slowest 1% of the frames in the window
+    pub low_fps: f32,
+    /// Frames since the start
+    pub frames: u64,
+}
+
+/// The times between the last frames
+pub struct FrameTimes {
+    times: VecDeque<Duration>,
+    capacity: usize,
+    frames: u64,
+    last_frame: Option<Instant>,
+}
+
+impl FrameTimes {
+    pub fn new(capacity: usize) -> Self {
+        FrameTimes{ times: VecDeque::with_capacity(capacity), capacity: capacity.max(1), frames: 0, last_frame: None }
+    }
+
+    /// Call once per frame; the first call only starts the clock
+    pub fn tick(&mut self) {
+        let now = Instant::now();
+        if let Some(last_frame) = self.last_frame {
+            self.record(now - last_frame);
+        }
+        self.last_frame = Some(now);
+    }
+
+    pub fn record(&mut self, frame_time: Duration) {
+        if self.times.len() == self.capacity {
+            self.times.pop_front();
+        }
+        self.times.push_back(frame_time);
+        self.frames += 1;
+    }
+
+    /// None until a frame has been recorded
+    pub fn stats(&self) -> Option<FrameStats> {
+        if self.times.is_empty() {
+            return None;
+        }
+        let total: Duration = self.times.iter().sum();
+        let mut sorted: Vec<_> = self.times.iter().copied().collect();
+        sorted.sort_unstable_by(|a, b| b.cmp(a));
+        let slowest = &sorted[..sorted.len().div_ceil(100)];
+        let slowest_total: Duration = slowest.iter().sum();
+        Some(FrameStats{
+            fps: rate(total, self.times.len()),
+            low_fps: rate(slowest_total, slowest.len()),
+            frames: self.frames,
+        })
+    }
+}
+
+/// Frames per second when `count` frames take `total`
+fn rate(total: Duration, count: usize) -> f32 {
+    if total.is_zero() { f32::INFINITY } else { count as f32 / total.as_secs_f32() }
+}
+
+/// Measures how long render passes take on the GPU, using timestamp queries. The result of a frame
+/// is read back while later frames are rendered, so it lags a few frames behind; frames are not
+/// measured while an earlier result is being read.
+pub struct GpuTimer {
+    query_set: wgpu::QuerySet,
+    resolve_buffer: wgpu::Buffer,
+    read_buffer: wgpu::Buffer,
+    /// Nanoseconds per timestamp tick
+    period: f32,
+    /// Whether read_buffer is in use, and the outcome of mapping it once known
+    pending: bool,
+    mapped: Arc<Mutex<Option<bool>>>,
+    /// Whether the passes of the current frame write timestamps
+    measuring: bool,
+    last: Option<Duration>,
+}
+
+impl GpuTimer {
+    /// The feature to request from the adapter, if it has it
+    pub const FEATURES: wgpu::Features = wgpu::Features::TIMESTAMP_QUERY;
+
+    /// None if the device was created without timestamp queries
+    pub fn new(device: &wgpu::Device, queue: &wgpu::Queue) -> Option<Self> {
+        if !device.features().contains(Self::FEATURES) {
+            return None;
+        }
+        let query_set = device.create_query_set(&wgpu::QuerySetDescriptor {
+            label: Some("GPU Timer Queries"),
+            ty: wgpu::QueryType::Timestamp,
+            count: 2,
+        });
+        let size = 2 * wgpu::QUERY_SIZE as wgpu::BufferAddress;
+        let resolve_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("GPU Timer Resolve Buffer"),
+            size,
+            usage: wgpu::BufferUsages::QUERY_RESOLVE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let read_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("GPU Timer Read Buffer"),
+            size,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+        Some(GpuTimer{ query_set, resolve_buffer, read_buffer, period: queue.get_timestamp_period(), pending: false,
+            mapped: Arc::new(Mutex::new(None)), measuring: false, last: None })
+    }
+
+    /// Call at the start of a frame; the frame is measured unless an earlier result is still
+    /// being read back
+    pub fn begin_frame(&mut self) {
+        self.measuring = !self.pending;
+    }
+
+    /// Timestamps for the pass to measure, or None if this frame is not measured; at most one
+    /// pass per frame can be measured
+    pub fn timestamp_writes(&self) -> Option<wgpu::RenderPassTimestampWrites<'_>> {
+        self.measuring.then_some(wgpu::RenderPassTimestampWrites {
+            query_set: &self.query_set,
+            beginning_of_pass_write_index: Some(0),
+            end_of_pass_write_index: Some(1),
+        })
+    }
+
+    /// Call after the measured pass, before finishing the encoder
+    pub fn resolve(&self, encoder: &mut wgpu::CommandEncoder) {
+        if self.measuring {
+            encoder.resolve_query_set(&self.query_set, 0..2, &self.resolve_buffer, 0);
+            encoder.copy_buffer_to_buffer(&self.resolve_buffer, 0, &self.read_buffer, 0, self.read_buffer.size());
+        }
+    }
+
+    /// Call after submitting the encoder; starts reading the timestamps back
+    pub fn submitted(&mut self) {
+        if !self.measuring {
+            return;
+        }
+        self.measuring = false;
+        self.pending = true;
+        let mapped = self.mapped.clone();
+        self.read_buffer.slice(..).map_async(wgpu::MapMode::Read, move |result| {
+            *mapped.lock().unwrap() = Some(result.is_ok());
+        });
+    }
+
+    /// Picks up the result of an earlier frame if it has been read back; returns the most recent
+    pub fn poll(&mut self, device: &wgpu::Device) -> Option<Duration> {
+        if self.pending {
+            device.poll(wgpu::Maintain::Poll);
+            let mapped = self.mapped.lock().unwrap().take();
+            if mapped == Some(true) {
+                let ticks: Vec<u64> = self.read_buffer.slice(..).get_mapped_range()
+                    .chunks_exact(8).map(|b| u64::from_le_bytes(b.try_into().unwrap())).collect();
+                self.read_buffer.unmap();
+                let nanos = ticks[1].saturating_sub(ticks[0]) as f64 * self.period as f64;
+                self.last = Some(Duration::from_nanos(nanos as u64));
+            }
+            // A frame whose timestamps could not be read is skipped
+            self.pending = mapped.is_none();
+        }
+        self.last
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn millis(times: &[u64]) -> FrameTimes {
+        let mut frame_times = FrameTimes::new(200);
+        for &ms in times {
+            frame_times.record(Duration::from_millis(ms));
+        }
+        frame_times
+    }
+
+    #[test]
+    fn test_stats() {
+        assert_eq!(FrameTimes::new(10).stats(), None);
+        let stats = millis(&[ 10, 10, 10, 10 ]).stats().unwrap();
+        assert_eq!((stats.fps.round(), stats.low_fps.round(), stats.frames), (100.0, 100.0, 4));
+
+        // Of 150 frames, the 2 slowest make up the 1% low
+        let mut times = vec![ 10; 148 ];
+        times.extend([ 40, 60 ]);
+        let stats = millis(&times).stats().unwrap();
+        assert_eq!(stats.low_fps.round(), 20.0);
+        assert!((stats.fps - 150.0 / 1.58).abs() < 0.01, "{}", stats.fps);
+    }
+
+    #[test]
+    fn test_window() {
+        let mut frame_times = FrameTimes::new(3);
+        for ms in [ 100, 10, 10, 10 ] {
+            frame_times.record(Duration::from_millis(ms));
+        }
+        let stats = frame_times.stats().unwrap();
+        // The slow first frame has left the window, but is still counted
+        assert_eq!((stats.fps.round(), stats.low_fps.round(), stats.frames), (100.0, 100.0, 4));
+    }
+
+    #[test]
+    fn test_tick() {
+        let mut frame_times = FrameTimes::new(3);
+        frame_times.tick();
+        assert_eq!(frame_times.stats(), None);
+        frame_times.tick();
+        assert_eq!(frame_times.stats().unwrap().frames, 1);
+    }
+}
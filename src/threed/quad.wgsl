@@ -0,0 +1,47 @@
+// Draws a single textured rectangle, positioned in window pixels
+
+struct QuadUniform {
+    // x, y, width, height in pixels, origin at the top-left of the window
+    rect: vec4<f32>,
+    viewport: vec2<f32>,
+    // Non-zero to show transparent texels over a checkerboard
+    checkerboard: u32,
+    _padding: u32,
+};
+@group(1) @binding(0)
+var<uniform> quad: QuadUniform;
+
+@group(0) @binding(0)
+var t_diffuse: texture_2d<f32>;
+@group(0) @binding(1)
+var s_diffuse: sampler;
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) tex_coords: vec2<f32>,
+};
+
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> VertexOutput {
+    // Triangle strip: (0, 0), (1, 0), (0, 1), (1, 1)
+    let corner = vec2<f32>(f32(index & 1u), f32(index >> 1u));
+    let pixel = quad.rect.xy + corner * quad.rect.zw;
+    var out: VertexOutput;
+    out.clip_position = vec4<f32>(
+        pixel.x / quad.viewport.x * 2.0 - 1.0,
+        1.0 - pixel.y / quad.viewport.y * 2.0,
+        0.0, 1.0);
+    out.tex_coords = corner;
+    return out;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let color = textureSample(t_diffuse, s_diffuse, in.tex_coords);
+    if (quad.checkerboard == 0u) {
+        return color;
+    }
+    let cell = vec2<u32>(in.clip_position.xy / 8.0);
+    let shade = select(0.4, 0.6, ((cell.x + cell.y) & 1u) == 0u);
+    return vec4<f32>(mix(vec3<f32>(shade), color.rgb, color.a), 1.0);
+}
@@ -4,21 +4,60 @@
  * Copyright (c) 2024 Rink Springer <rink@rink.nu>
  * For conditions of distribution and use, see LICENSE file
  */
+use std::collections::HashMap;
 use wgpu::util::DeviceExt;
 
 use crate::threed::{model, texture};
 use crate::qfg5resource::qfg5mdl;
 
+#[cfg(not(target_arch = "wasm32"))]
 pub async fn load_string(file_name: &str) -> anyhow::Result<String> {
     let data = std::fs::read_to_string(std::path::Path::new(file_name))?;
     Ok(data)
 }
 
+#[cfg(not(target_arch = "wasm32"))]
 pub async fn load_binary(file_name: &str) -> anyhow::Result<Vec<u8>> {
     let data = std::fs::read(std::path::Path::new(file_name))?;
     Ok(data)
 }
 
+// In the browser, file names are URLs relative to the page that hosts the viewer
+#[cfg(target_arch = "wasm32")]
+async fn fetch(file_name: &str) -> anyhow::Result<web_sys::Response> {
+    use anyhow::anyhow;
+    use wasm_bindgen::JsCast;
+    let window = web_sys::window().ok_or_else(|| anyhow!("no browser window"))?;
+    let response = wasm_bindgen_futures::JsFuture::from(window.fetch_with_str(file_name)).await
+        .map_err(|e| anyhow!("cannot fetch '{}': {:?}", file_name, e))?;
+    let response: web_sys::Response = response.dyn_into()
+        .map_err(|_| anyhow!("cannot fetch '{}': not a response", file_name))?;
+    if !response.ok() {
+        return Err(anyhow!("cannot fetch '{}': HTTP status {}", file_name, response.status()));
+    }
+    Ok(response)
+}
+
+#[cfg(target_arch = "wasm32")]
+pub async fn load_string(file_name: &str) -> anyhow::Result<String> {
+    use anyhow::anyhow;
+    let response = fetch(file_name).await?;
+    let text = response.text().map_err(|e| anyhow!("cannot read '{}': {:?}", file_name, e))?;
+    let text = wasm_bindgen_futures::JsFuture::from(text).await
+        .map_err(|e| anyhow!("cannot read '{}': {:?}", file_name, e))?;
+    text.as_string().ok_or_else(|| anyhow!("'{}' is not text", file_name))
+}
+
+#[cfg(target_arch = "wasm32")]
+pub async fn load_binary(file_name: &str) -> anyhow::Result<Vec<u8>> {
+    use anyhow::anyhow;
+    let response = fetch(file_name).await?;
+    let buffer = response.array_buffer().map_err(|e| anyhow!("cannot read '{}': {:?}", file_name, e))?;
+    let buffer = wasm_bindgen_futures::JsFuture::from(buffer).await
+        .map_err(|e| anyhow!("cannot read '{}': {:?}", file_name, e))?;
+    Ok(js_sys::Uint8Array::new(&buffer).to_vec())
+}
+
 pub async fn load_texture(
     file_name: &str,
     device: &wgpu::Device,
@@ -28,16 +67,34 @@ pub async fn load_texture(
     texture::Texture::from_bytes(device, queue, &data, file_name)
 }
 
-pub async fn load_qfg5model(
+/// Optional data to upload with a model
+#[derive(Default)]
+pub struct Qfg5ModelOptions {
+    /// Upload the lighting data as vertex colors, see qfg5mdl::Qfg5LightingVertex::to_rgba()
+    pub lighting_colors: bool,
+    /// Generate mipmaps for the textures, see texture::generate_mipmaps()
+    pub mipmaps: bool,
+    /// Shade smoothly with normals per vertex instead of the normal of every face: those of the
+    /// lighting data if they look like normals, see qfg5mdl::SubMesh::lighting_normals(), and
+    /// otherwise those of qfg5mdl::SubMesh::vertex_normals()
+    pub smooth_normals: bool,
+    /// Give every corner of every face a vertex of its own instead of sharing identical vertices,
+    /// for debugging
+    pub unshared_vertices: bool,
+}
+
+/// Creates a material per subbitmap; this can be used to replace the materials of a loaded model
+pub fn create_qfg5materials(
     model: &qfg5mdl::Qfg5Model,
     device: &wgpu::Device,
     queue: &wgpu::Queue,
     layout: &wgpu::BindGroupLayout,
-) -> anyhow::Result<model::Model> {
+    mipmaps: bool,
+) -> anyhow::Result<Vec<model::Material>> {
     let mut materials = Vec::new();
     for (n, subbitmap) in model.subbitmaps.iter().enumerate() {
         let name = format!("subbitmap-{}", n);
-        let texture = texture::Texture::from_palettized_raw(device, queue, &subbitmap.bitmap, &model.palette, subbitmap.height, subbitmap.width, name.as_str())?;
+        let texture = texture::Texture::from_palettized_raw(device, queue, &subbitmap.bitmap, &model.palette, subbitmap.height, subbitmap.width, mipmaps, name.as_str())?;
 
         let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
             layout,
@@ -60,62 +117,256 @@ pub async fn load_qfg5model(
             bind_group,
         })
     }
+    Ok(materials)
+}
 
-    let mut meshes: Vec<model::Mesh> = Vec::new();
+pub async fn load_qfg5model(
+    model: &qfg5mdl::Qfg5Model<'_>,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    layout: &wgpu::BindGroupLayout,
+) -> anyhow::Result<model::Model> {
+    load_qfg5model_with_options(model, device, queue, layout, &Qfg5ModelOptions::default()).await
+}
+
+pub async fn load_qfg5model_with_options(
+    model: &qfg5mdl::Qfg5Model<'_>,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    layout: &wgpu::BindGroupLayout,
+    options: &Qfg5ModelOptions,
+) -> anyhow::Result<model::Model> {
+    let started = web_time::Instant::now();
+    let materials = create_qfg5materials(model, device, queue, layout, options.mipmaps)?;
+    let geometry = build_geometry(model, materials.len(), options)?;
+    let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some(&format!("{} Vertex Buffer", model.name)),
+        contents: bytemuck::cast_slice(&geometry.vertices),
+        usage: wgpu::BufferUsages::VERTEX,
+    });
+    let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some(&format!("{} Index Buffer", model.name)),
+        contents: bytemuck::cast_slice(&geometry.indices),
+        usage: wgpu::BufferUsages::INDEX,
+    });
+    let color_buffer = options.lighting_colors.then(|| {
+        device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&format!("{} Color Buffer", model.name)),
+            contents: bytemuck::cast_slice(&geometry.colors),
+            usage: wgpu::BufferUsages::VERTEX,
+        })
+    });
+
+    let model = model::Model { vertex_buffer, color_buffer, index_buffer, meshes: geometry.meshes, materials };
+    log::info!("loaded model: {} vertices, {} indices, {} bytes of buffers in {:?}",
+        geometry.vertices.len(), geometry.indices.len(), model.buffer_size(), started.elapsed());
+    Ok(model)
+}
+
+/// Geometry of all submeshes, with identical vertices merged unless unshared_vertices is set
+struct Geometry {
+    vertices: Vec<model::Vertex>,
+    /// Either empty or one per vertex
+    colors: Vec<model::ColorVertex>,
+    indices: Vec<u32>,
+    meshes: Vec<model::Mesh>,
+}
+
+fn build_geometry(model: &qfg5mdl::Qfg5Model, num_materials: usize, options: &Qfg5ModelOptions) -> anyhow::Result<Geometry> {
+    let lighting_range = model.lighting_range().unwrap_or_default();
+    let mut geometry = Geometry{ vertices: Vec::new(), colors: Vec::new(), indices: Vec::new(), meshes: Vec::new() };
+    // Keyed by the bit patterns of the vertex and its color, as f32 is neither Eq nor Hash
+    let mut vertex_indices: HashMap<[u32; 12], u32> = HashMap::new();
     for (submesh_index, submesh) in model.submeshes.iter().enumerate() {
+        let mesh_start = geometry.indices.len() as u32;
+        let mut material_ranges: Vec<model::MaterialRange> = Vec::new();
+        let vertex_normals = if options.smooth_normals {
+            submesh.lighting_normals().unwrap_or_else(|| submesh.vertex_normals())
+        } else {
+            Vec::new()
+        };
+        if let Some((face_index, face)) = submesh.faces.iter().enumerate().find(|(_, face)| face.subbitmap >= num_materials) {
+            return Err(anyhow::anyhow!("submesh {} ('{}') face {} refers to subbitmap {} which does not exist (there are {})",
+                submesh_index, submesh.name, face_index, face.subbitmap, num_materials));
+        }
+        // The faces grouped by subbitmap, in their order otherwise, so that every subbitmap has a
+        // single range even if the faces using it are not next to each other
+        let mut faces: Vec<_> = submesh.faces.iter().collect();
+        faces.sort_by_key(|face| face.subbitmap);
+        for face in faces {
+            let start = geometry.indices.len() as u32;
+            match material_ranges.last_mut() {
+                Some(range) if range.material == face.subbitmap => {},
+                _ => material_ranges.push(model::MaterialRange{ indices: start..start, material: face.subbitmap }),
+            }
+            // Every face is a triangle; the file only has a normal per face
+            let face_normal = [ face.normal_x, face.normal_y, face.normal_z ];
+            for (vertex, uv) in [ (face.vertex1, face.uv1), (face.vertex2, face.uv2), (face.vertex3, face.uv3) ] {
+                let v = &submesh.vertices[vertex];
+                let uv = &submesh.texcoords[uv];
+                let vertex_data = model::Vertex {
+                    position: [ v.x, v.y, v.z ],
+                    tex_coords: [ uv.u, uv.v ],
+                    normal: vertex_normals.get(vertex).copied().unwrap_or(face_normal),
+                };
+                let color = options.lighting_colors.then(|| {
+                    let color = submesh.lighting_vertices.get(vertex)
+                        .map_or([ 1.0; 4 ], |l| l.to_rgba(&lighting_range.0, &lighting_range.1));
+                    model::ColorVertex{ color }
+                });
 
-        let mut vertices: Vec<model::Vertex> = Vec::new();
-        let mut indices: Vec<u32> = Vec::new();
-        for face in &submesh.faces {
-            // Every face is a triangle
-            let v1 = &submesh.vertices[face.vertex1];
-            let v2 = &submesh.vertices[face.vertex2];
-            let v3 = &submesh.vertices[face.vertex3];
-            let uv1 = &submesh.texcoords[face.uv1];
-            let uv2 = &submesh.texcoords[face.uv2];
-            let uv3 = &submesh.texcoords[face.uv3];
-            // TODO: Is this correct? We should have normals _per vertex_, not per _face_ ... ?
-            let nx = face.normal_x;
-            let ny = face.normal_y;
-            let nz = face.normal_z;
-            vertices.push(model::Vertex {
-                position: [ v1.x, v1.y, v1.z ],
-                tex_coords: [ uv1.u, uv1.v ],
-                normal: [ nx, ny, nz ]
-            });
-            indices.push((vertices.len() - 1) as u32);
-            vertices.push(model::Vertex {
-                position: [ v2.x, v2.y, v2.z ],
-                tex_coords: [ uv2.u, uv2.v ],
-                normal: [ nx, ny, nz ]
-            });
-            indices.push((vertices.len() - 1) as u32);
-            vertices.push(model::Vertex {
-                position: [ v3.x, v3.y, v3.z ],
-                tex_coords: [ uv3.u, uv3.v ],
-                normal: [ nx, ny, nz ]
-            });
-            indices.push((vertices.len() - 1) as u32);
+                let mut key = [ 0u32; 12 ];
+                let values = bytemuck::cast_slice::<_, f32>(std::slice::from_ref(&vertex_data)).iter()
+                    .chain(color.iter().flat_map(|c| c.color.iter()));
+                for (k, value) in key.iter_mut().zip(values) {
+                    *k = value.to_bits();
+                }
+                let index = match vertex_indices.get(&key) {
+                    Some(&index) if !options.unshared_vertices => index,
+                    _ => {
+                        geometry.vertices.push(vertex_data);
+                        geometry.colors.extend(color);
+                        let index = (geometry.vertices.len() - 1) as u32;
+                        vertex_indices.insert(key, index);
+                        index
+                    },
+                };
+                geometry.indices.push(index);
+            }
+            if let Some(range) = material_ranges.last_mut() {
+                range.indices.end = geometry.indices.len() as u32;
+            }
         }
 
-        let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some(&format!("{} Vertex Buffer {}", model.name, submesh_index)),
-            contents: bytemuck::cast_slice(&vertices),
-            usage: wgpu::BufferUsages::VERTEX,
-        });
-        let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some(&format!("{} Index Buffer {}", model.name, submesh_index)),
-            contents: bytemuck::cast_slice(&indices),
-            usage: wgpu::BufferUsages::INDEX,
+        geometry.meshes.push(model::Mesh{
+            indices: mesh_start..geometry.indices.len() as u32,
+            material: material_ranges.first().map_or(0, |range| range.material),
+            material_ranges,
         });
+    }
+    Ok(geometry)
+}
 
-        meshes.push(model::Mesh{
-            vertex_buffer,
-            index_buffer,
-            num_elements: indices.len() as u32,
-            material: 0, // TODO face.subbitmap
-        });
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use qfg5mdl::{Qfg5Face, Qfg5TexCoord, Qfg5Vertex, SubMesh};
+
+    fn quad(subbitmaps: [usize; 2]) -> SubMesh {
+        let vertex = |x, y| Qfg5Vertex{ x, y, z: 0.0 };
+        let face = |vertex1, vertex2, vertex3, subbitmap| Qfg5Face{ vertex1, vertex2, vertex3, uv1: vertex1, uv2: vertex2, uv3: vertex3, subbitmap, normal_z: 1.0, ..Default::default() };
+        SubMesh{
+            vertices: vec![ vertex(0.0, 0.0), vertex(1.0, 0.0), vertex(1.0, 1.0), vertex(0.0, 1.0) ],
+            texcoords: vec![ Qfg5TexCoord{ u: 0.0, v: 0.0 }, Qfg5TexCoord{ u: 1.0, v: 0.0 }, Qfg5TexCoord{ u: 1.0, v: 1.0 }, Qfg5TexCoord{ u: 0.0, v: 1.0 } ],
+            faces: vec![ face(0, 1, 2, subbitmaps[0]), face(0, 2, 3, subbitmaps[1]) ],
+            ..Default::default()
+        }
+    }
+
+    fn model(submeshes: Vec<SubMesh>) -> qfg5mdl::Qfg5Model<'static> {
+        qfg5mdl::Qfg5Model{ submeshes, ..Default::default() }
     }
 
-    Ok(model::Model { meshes, materials })
+    #[test]
+    fn test_build_geometry() {
+        let geometry = build_geometry(&model(vec![ quad([ 0, 0 ]), quad([ 0, 1 ]) ]), 2, &Qfg5ModelOptions::default()).unwrap();
+        // The faces of a quad share two corners, and both quads are identical
+        assert_eq!(geometry.vertices.len(), 4);
+        assert!(geometry.colors.is_empty());
+        assert_eq!(geometry.indices, vec![ 0, 1, 2, 0, 2, 3, 0, 1, 2, 0, 2, 3 ]);
+        assert_eq!(geometry.meshes, vec![
+            model::Mesh{ indices: 0..6, material: 0, material_ranges: vec![ model::MaterialRange{ indices: 0..6, material: 0 } ] },
+            model::Mesh{ indices: 6..12, material: 0, material_ranges: vec![
+                model::MaterialRange{ indices: 6..9, material: 0 },
+                model::MaterialRange{ indices: 9..12, material: 1 },
+            ] },
+        ]);
+    }
+
+    #[test]
+    fn test_build_geometry_colors() {
+        let mut submesh = quad([ 0, 0 ]);
+        let lighting = |a| qfg5mdl::Qfg5LightingVertex{ a, b: 0.0, c: 0.0, d: 0.0 };
+        submesh.lighting_vertices = vec![ lighting(0.0), lighting(1.0), lighting(0.0), lighting(1.0) ];
+        // Same position, but a different color
+        submesh.vertices[3] = submesh.vertices[0].clone();
+        submesh.texcoords[3] = submesh.texcoords[0].clone();
+        let geometry = build_geometry(&model(vec![ submesh ]), 1, &Qfg5ModelOptions{ lighting_colors: true, ..Default::default() }).unwrap();
+        assert_eq!(geometry.vertices.len(), 4);
+        assert_eq!(geometry.colors.len(), 4);
+        assert_eq!(geometry.indices, vec![ 0, 1, 2, 0, 2, 3 ]);
+        assert_eq!(geometry.vertices[0], geometry.vertices[3]);
+    }
+
+    #[test]
+    fn test_build_geometry_unshared() {
+        let model = qfg5mdl::Qfg5Model::new(&crate::qfg5resource::fixtures::mdl()).unwrap();
+        let triangles = |geometry: &Geometry| -> Vec<_> {
+            geometry.indices.iter().map(|&index| (geometry.vertices[index as usize], geometry.colors[index as usize])).collect()
+        };
+        let options = Qfg5ModelOptions{ lighting_colors: true, ..Default::default() };
+        let shared = build_geometry(&model, 1, &options).unwrap();
+        let unshared = build_geometry(&model, 1, &Qfg5ModelOptions{ unshared_vertices: true, ..options }).unwrap();
+        assert_eq!((shared.vertices.len(), unshared.vertices.len()), (4, 6));
+        assert_eq!(unshared.indices, (0..6).collect::<Vec<_>>());
+        assert_eq!(triangles(&shared), triangles(&unshared));
+        assert_eq!(shared.meshes, unshared.meshes);
+    }
+
+    #[test]
+    fn test_build_geometry_smooth_normals() {
+        // Folded along the diagonal, so that the faces have different normals
+        let mut submesh = quad([ 0, 0 ]);
+        submesh.vertices[3].z = 1.0;
+        submesh.faces[1].normal_y = -1.0;
+        let geometry = build_geometry(&model(vec![ submesh.clone() ]), 1, &Qfg5ModelOptions::default()).unwrap();
+        // Flat: the shared corners are separate vertices for either face
+        assert_eq!(geometry.vertices.len(), 6);
+        let geometry = build_geometry(&model(vec![ submesh.clone() ]), 1, &Qfg5ModelOptions{ smooth_normals: true, ..Default::default() }).unwrap();
+        assert_eq!(geometry.vertices.len(), 4);
+        assert_eq!(geometry.indices, vec![ 0, 1, 2, 0, 2, 3 ]);
+        let normals = submesh.vertex_normals();
+        assert_eq!(geometry.vertices.iter().map(|v| v.normal).collect::<Vec<_>>(), normals);
+        assert_eq!(normals[1], [ 0.0, 0.0, 1.0 ]);
+        assert!(normals[0][1] < 0.0 && normals[0][2] > 0.0);
+
+        // Lighting data that looks like normals is used instead
+        let lighting = |a, b, c| qfg5mdl::Qfg5LightingVertex{ a, b, c, d: 1.0 };
+        submesh.lighting_vertices = vec![ lighting(1.0, 0.0, 0.0), lighting(0.0, 1.0, 0.0), lighting(0.0, 0.0, 1.0), lighting(0.0, 0.0, -1.0) ];
+        let geometry = build_geometry(&model(vec![ submesh ]), 1, &Qfg5ModelOptions{ smooth_normals: true, ..Default::default() }).unwrap();
+        assert_eq!(geometry.vertices.iter().map(|v| v.normal).collect::<Vec<_>>(), [ [ 1.0, 0.0, 0.0 ], [ 0.0, 1.0, 0.0 ], [ 0.0, 0.0, 1.0 ], [ 0.0, 0.0, -1.0 ] ]);
+    }
+
+    #[test]
+    fn test_build_geometry_two_subbitmaps() {
+        // create_qfg5materials() makes a material per subbitmap
+        let model = qfg5mdl::Qfg5Model::new(&crate::qfg5resource::fixtures::mdl_two_subbitmaps()).unwrap();
+        let geometry = build_geometry(&model, model.subbitmaps.len(), &Qfg5ModelOptions::default()).unwrap();
+        assert_eq!(geometry.meshes[0].material_ranges, vec![
+            model::MaterialRange{ indices: 0..3, material: 0 },
+            model::MaterialRange{ indices: 3..6, material: 1 },
+        ]);
+    }
+
+    #[test]
+    fn test_build_geometry_interleaved_subbitmaps() {
+        let mut submesh = quad([ 1, 0 ]);
+        submesh.faces.extend(quad([ 1, 0 ]).faces);
+        let geometry = build_geometry(&model(vec![ submesh ]), 2, &Qfg5ModelOptions::default()).unwrap();
+        // The second faces of both quads first, as they use subbitmap 0
+        assert_eq!(geometry.indices, vec![ 0, 1, 2, 0, 1, 2, 0, 3, 1, 0, 3, 1 ]);
+        assert_eq!(geometry.meshes[0].material_ranges, vec![
+            model::MaterialRange{ indices: 0..6, material: 0 },
+            model::MaterialRange{ indices: 6..12, material: 1 },
+        ]);
+    }
+
+    #[test]
+    fn test_build_geometry_missing_subbitmap() {
+        let mut submesh = quad([ 0, 1 ]);
+        submesh.name = "arm".to_string();
+        let err = build_geometry(&model(vec![ quad([ 0, 0 ]), submesh ]), 1, &Qfg5ModelOptions::default()).err().unwrap();
+        assert_eq!(err.to_string(), "submesh 1 ('arm') face 1 refers to subbitmap 1 which does not exist (there are 1)");
+    }
 }
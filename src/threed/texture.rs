@@ -4,8 +4,94 @@
  * Copyright (c) 2024 Rink Springer <rink@rink.nu>
  * For conditions of distribution and use, see LICENSE file
  */
-use image::{DynamicImage, GenericImageView, RgbaImage};
+use image::{DynamicImage, RgbaImage};
 use anyhow::Result;
+use crate::qfg5resource::qfg5nod::PaletteEntry;
+use crate::pixels::indexed_to_rgba;
+
+/// An 8-bit image with its own palette
+pub struct IndexedImage {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+    pub palette: Vec<PaletteEntry>,
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(bytes.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+/// Decodes an uncompressed 8-bit palettized BMP; returns None for anything else
+pub fn parse_indexed_bmp(bytes: &[u8]) -> Option<IndexedImage> {
+    if bytes.get(0..2)? != b"BM" { return None; }
+    let pixel_offset = read_u32(bytes, 10)? as usize;
+    let header_size = read_u32(bytes, 14)? as usize;
+    // BITMAPCOREHEADER (12 bytes) has 16-bit dimensions and 3-byte palette entries
+    if header_size < 40 { return None; }
+    let width = read_u32(bytes, 18)? as i32;
+    let height = read_u32(bytes, 22)? as i32;
+    let bits_per_pixel = read_u16(bytes, 28)?;
+    let compression = read_u32(bytes, 30)?;
+    if bits_per_pixel != 8 || compression != 0 || width <= 0 || height == 0 { return None; }
+
+    let num_colors = match read_u32(bytes, 46)? { 0 => 256, n => n.min(256) } as usize;
+    let palette_offset = 14 + header_size;
+    // Entries are stored as blue, green, red, reserved
+    let palette = bytes.get(palette_offset..palette_offset + num_colors * 4)?
+        .chunks_exact(4)
+        .map(|bgrx| (bgrx[2], bgrx[1], bgrx[0]))
+        .collect();
+
+    // Rows are padded to 4 bytes and stored bottom-up, unless the height is negative
+    let width = width as u32;
+    let top_down = height < 0;
+    let height = height.unsigned_abs();
+    let stride = (width as usize + 3) & !3;
+    let mut pixels = Vec::with_capacity((width * height) as usize);
+    for y in 0..height as usize {
+        let row = if top_down { y } else { height as usize - 1 - y };
+        let start = pixel_offset + row * stride;
+        pixels.extend_from_slice(bytes.get(start..start + width as usize)?);
+    }
+    Some(IndexedImage{ width, height, pixels, palette })
+}
+
+/// Halves the image until it is 1x1, returning every level after the image itself. Each pixel
+/// is the average of (up to) 2x2 pixels of the previous level, weighted by alpha so that
+/// transparent pixels do not darken their neighbours.
+pub fn generate_mipmaps(image: &RgbaImage) -> Vec<RgbaImage> {
+    let mut levels: Vec<RgbaImage> = Vec::new();
+    loop {
+        let previous = levels.last().unwrap_or(image);
+        let (width, height) = previous.dimensions();
+        if width <= 1 && height <= 1 {
+            return levels;
+        }
+        let level = RgbaImage::from_fn((width / 2).max(1), (height / 2).max(1), |x, y| {
+            let mut sum = [ 0u32; 4 ];
+            let mut count = 0;
+            for (sx, sy) in [ (2 * x, 2 * y), (2 * x + 1, 2 * y), (2 * x, 2 * y + 1), (2 * x + 1, 2 * y + 1) ] {
+                let Some(pixel) = (sx < width && sy < height).then(|| previous.get_pixel(sx, sy)) else { continue };
+                let alpha = pixel[3] as u32;
+                for c in 0..3 {
+                    sum[c] += pixel[c] as u32 * alpha;
+                }
+                sum[3] += alpha;
+                count += 1;
+            }
+            if sum[3] == 0 {
+                return image::Rgba([ 0, 0, 0, 0 ]);
+            }
+            let color = |c: usize| ((sum[c] + sum[3] / 2) / sum[3]) as u8;
+            image::Rgba([ color(0), color(1), color(2), ((sum[3] + count / 2) / count) as u8 ])
+        });
+        levels.push(level);
+    }
+}
 
 pub struct Texture {
     #[allow(unused)]
@@ -23,46 +109,47 @@ impl Texture {
         bytes: &[u8], 
         label: &str
     ) -> Result<Self> {
+        if let Some(indexed) = parse_indexed_bmp(bytes) {
+            return Self::from_indexed(device, queue, &indexed.pixels, &indexed.palette, indexed.width, indexed.height, None, label);
+        }
         let img = image::load_from_memory(bytes)?;
         Self::from_image(device, queue, &img, Some(label))
     }
 
+    #[allow(clippy::too_many_arguments)]
+    pub fn from_indexed(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        pixels: &[u8],
+        palette: &[PaletteEntry],
+        width: u32,
+        height: u32,
+        transparent_index: Option<u8>,
+        label: &str
+    ) -> Result<Self> {
+        if pixels.len() < (width * height) as usize {
+            return Err(anyhow::anyhow!("{}: {} pixels for a {}x{} image", label, pixels.len(), width, height));
+        }
+        let img = DynamicImage::ImageRgba8(indexed_to_rgba(pixels, palette, width, height, transparent_index));
+        Self::from_image(device, queue, &img, Some(label))
+    }
+
+    #[allow(clippy::too_many_arguments)]
     pub fn from_palettized_raw(
         device: &wgpu::Device,
         queue: &wgpu::Queue,
         pixels: &[u8],
-        palette: &[u8],
+        palette: &[PaletteEntry],
         height: u32,
         width: u32,
+        mipmaps: bool,
         label: &str
     ) -> Result<Self> {
-        let mut rgba = RgbaImage::new(width, height);
-        for y in 0..height {
-            for x in 0..width {
-                let value = pixels[(y * width + x) as usize] as usize;
-                let r;
-                let g;
-                let b;
-                let a;
-                if value * 4 + 3 < palette.len() {
-                    r = palette[value * 4 + 0];
-                    g = palette[value * 4 + 1];
-                    b = palette[value * 4 + 2];
-                    a = 255;
-                } else {
-                    r = 0;
-                    g = 0;
-                    b = 0;
-                    a = 0;
-                }
-                let value = image::Rgba([ r, g, b, a ]);
-                rgba.put_pixel(x, y, value);
-            }
+        if pixels.len() < (width * height) as usize {
+            return Err(anyhow::anyhow!("{}: {} pixels for a {}x{} image", label, pixels.len(), width, height));
         }
-
-        let img = DynamicImage::ImageRgba8(rgba);
-        // img.save("/tmp/a.png");
-        Self::from_image(device, queue, &img, Some(label))
+        let rgba = indexed_to_rgba(pixels, palette, width, height, None);
+        Self::from_rgba(device, queue, &rgba, Some(label), mipmaps)
     }
 
     pub fn from_image(
@@ -71,8 +158,20 @@ impl Texture {
         img: &image::DynamicImage,
         label: Option<&str>
     ) -> Result<Self> {
-        let rgba = img.to_rgba8();
-        let dimensions = img.dimensions();
+        Self::from_rgba(device, queue, &img.to_rgba8(), label, false)
+    }
+
+    /// With `mipmaps`, the full mip chain is generated (see generate_mipmaps) and sampled
+    /// trilinearly; this avoids shimmering of textures seen at a distance
+    pub fn from_rgba(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        rgba: &RgbaImage,
+        label: Option<&str>,
+        mipmaps: bool
+    ) -> Result<Self> {
+        let dimensions = rgba.dimensions();
+        let mip_levels = if mipmaps { generate_mipmaps(rgba) } else { Vec::new() };
 
         let size = wgpu::Extent3d {
             width: dimensions.0,
@@ -83,7 +182,7 @@ impl Texture {
             &wgpu::TextureDescriptor {
                 label,
                 size,
-                mip_level_count: 1,
+                mip_level_count: 1 + mip_levels.len() as u32,
                 sample_count: 1,
                 dimension: wgpu::TextureDimension::D2,
                 format: wgpu::TextureFormat::Rgba8UnormSrgb,
@@ -99,7 +198,7 @@ impl Texture {
                 mip_level: 0,
                 origin: wgpu::Origin3d::ZERO,
             },
-            &rgba,
+            rgba,
             wgpu::ImageDataLayout {
                 offset: 0,
                 bytes_per_row: Some(4 * dimensions.0),
@@ -107,6 +206,23 @@ impl Texture {
             },
             size,
         );
+        for (n, level) in mip_levels.iter().enumerate() {
+            queue.write_texture(
+                wgpu::ImageCopyTexture {
+                    aspect: wgpu::TextureAspect::All,
+                    texture: &texture,
+                    mip_level: n as u32 + 1,
+                    origin: wgpu::Origin3d::ZERO,
+                },
+                level,
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(4 * level.width()),
+                    rows_per_image: Some(level.height()),
+                },
+                wgpu::Extent3d { width: level.width(), height: level.height(), depth_or_array_layers: 1 },
+            );
+        }
 
         let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
         let sampler = device.create_sampler(
@@ -115,12 +231,12 @@ impl Texture {
                 address_mode_v: wgpu::AddressMode::ClampToEdge,
                 address_mode_w: wgpu::AddressMode::ClampToEdge,
                 mag_filter: wgpu::FilterMode::Linear,
-                min_filter: wgpu::FilterMode::Nearest,
-                mipmap_filter: wgpu::FilterMode::Nearest,
+                min_filter: if mipmaps { wgpu::FilterMode::Linear } else { wgpu::FilterMode::Nearest },
+                mipmap_filter: if mipmaps { wgpu::FilterMode::Linear } else { wgpu::FilterMode::Nearest },
                 ..Default::default()
             }
         );
-        
+
         Ok(Self { texture, view, sampler })
     }
 
@@ -161,4 +277,70 @@ impl Texture {
 
         Self { texture, view, sampler }
     } 
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate_mipmaps() {
+        let mut image = RgbaImage::new(4, 3);
+        image.put_pixel(0, 0, image::Rgba([ 200, 100, 0, 255 ]));
+        image.put_pixel(1, 1, image::Rgba([ 100, 50, 0, 255 ]));
+        let levels = generate_mipmaps(&image);
+        assert_eq!(levels.iter().map(|l| l.dimensions()).collect::<Vec<_>>(), vec![ (2, 1), (1, 1) ]);
+        // Transparent pixels only lower the alpha, not the color
+        assert_eq!(levels[0].get_pixel(0, 0).0, [ 150, 75, 0, 128 ]);
+        assert_eq!(levels[0].get_pixel(1, 0).0, [ 0, 0, 0, 0 ]);
+        assert_eq!(levels[1].get_pixel(0, 0).0, [ 150, 75, 0, 64 ]);
+
+        assert!(generate_mipmaps(&RgbaImage::new(1, 1)).is_empty());
+        assert_eq!(generate_mipmaps(&RgbaImage::new(1, 4)).len(), 2);
+    }
+
+    fn indexed_bmp(height: i32, rows: &[[u8; 3]]) -> Vec<u8> {
+        let num_colors = 2u32;
+        let pixel_offset = 14 + 40 + num_colors * 4;
+        let mut bmp = Vec::new();
+        bmp.extend_from_slice(b"BM");
+        bmp.extend_from_slice(&(pixel_offset + rows.len() as u32 * 4).to_le_bytes());
+        bmp.extend_from_slice(&[ 0; 4 ]);
+        bmp.extend_from_slice(&pixel_offset.to_le_bytes());
+        bmp.extend_from_slice(&40u32.to_le_bytes());
+        bmp.extend_from_slice(&3i32.to_le_bytes());
+        bmp.extend_from_slice(&height.to_le_bytes());
+        bmp.extend_from_slice(&1u16.to_le_bytes());
+        bmp.extend_from_slice(&8u16.to_le_bytes());
+        bmp.extend_from_slice(&[ 0; 12 ]); // compression, image size, horizontal resolution
+        bmp.extend_from_slice(&[ 0; 4 ]); // vertical resolution
+        bmp.extend_from_slice(&num_colors.to_le_bytes());
+        bmp.extend_from_slice(&[ 0; 4 ]);
+        bmp.extend_from_slice(&[ 3, 2, 1, 0, 6, 5, 4, 0 ]);
+        for row in rows {
+            bmp.extend_from_slice(row);
+            bmp.push(0xff); // padding
+        }
+        bmp
+    }
+
+    #[test]
+    fn test_parse_indexed_bmp() {
+        let indexed = parse_indexed_bmp(&indexed_bmp(2, &[ [ 1, 1, 1 ], [ 0, 1, 0 ] ])).unwrap();
+        assert_eq!((indexed.width, indexed.height), (3, 2));
+        assert_eq!(indexed.palette, vec![ (1, 2, 3), (4, 5, 6) ]);
+        // Bottom-up
+        assert_eq!(indexed.pixels, vec![ 0, 1, 0, 1, 1, 1 ]);
+
+        let indexed = parse_indexed_bmp(&indexed_bmp(-2, &[ [ 1, 1, 1 ], [ 0, 1, 0 ] ])).unwrap();
+        assert_eq!(indexed.pixels, vec![ 1, 1, 1, 0, 1, 0 ]);
+
+        // Anything else is left to the image crate
+        let mut png = Vec::new();
+        RgbaImage::new(1, 1).write_to(&mut std::io::Cursor::new(&mut png), image::ImageOutputFormat::Png).unwrap();
+        assert!(parse_indexed_bmp(&png).is_none());
+        let mut truncated = indexed_bmp(2, &[ [ 1, 1, 1 ], [ 0, 1, 0 ] ]);
+        truncated.truncate(truncated.len() - 2);
+        assert!(parse_indexed_bmp(&truncated).is_none());
+    }
+}
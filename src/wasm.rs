@@ -0,0 +1,217 @@
+/*-
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * Copyright (c) 2024 Rink Springer <rink@rink.nu>
+ * For conditions of distribution and use, see LICENSE file
+ */
+//! wasm-bindgen classes to decode resources in a web page, without the viewer:
+//!   cargo rustc --lib --release --target wasm32-unknown-unknown --features wasm --crate-type cdylib
+//!   wasm-bindgen --target web --out-dir pkg target/wasm32-unknown-unknown/release/qfg5reenigne.wasm
+//! The classes are constructed from the contents of a file as a Uint8Array. Metadata is returned
+//! as plain objects; pixels as RGBA, ready for ImageData.
+use wasm_bindgen::prelude::*;
+use crate::pixels;
+use crate::qfg5resource::{qfg5gra, qfg5qgf, qfg5qgm, qfg5spk};
+
+fn to_js<T: serde::Serialize + ?Sized>(value: &T) -> Result<JsValue, JsError> {
+    Ok(serde_wasm_bindgen::to_value(value)?)
+}
+
+/// RGBA pixels, row by row
+#[wasm_bindgen(getter_with_clone)]
+pub struct Bitmap {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+impl From<image::RgbaImage> for Bitmap {
+    fn from(img: image::RgbaImage) -> Self {
+        Bitmap{ width: img.width(), height: img.height(), pixels: img.into_raw() }
+    }
+}
+
+#[wasm_bindgen(js_name = QgmDecoder)]
+pub struct Qgm(qfg5qgm::QgmDecoder);
+
+#[wasm_bindgen(js_class = QgmDecoder)]
+impl Qgm {
+    #[wasm_bindgen(constructor)]
+    pub fn new(data: &[u8]) -> Result<Qgm, JsError> {
+        Ok(Qgm(qfg5qgm::QgmDecoder::new(data)?))
+    }
+
+    #[wasm_bindgen(getter, js_name = fileId)]
+    pub fn file_id(&self) -> u16 {
+        self.0.file_id
+    }
+
+    /// Objects with `id`, `speaker_id`, `msg_id`, `message_label`, `dialog_options` and `text`
+    #[wasm_bindgen(getter)]
+    pub fn messages(&self) -> Result<JsValue, JsError> {
+        to_js(&self.0.messages)
+    }
+}
+
+/// A sprite collection, without the frames themselves
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CollectionInfo {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+    frame_delay: u32,
+    frames: usize,
+}
+
+#[wasm_bindgen(js_name = GraDecoder)]
+pub struct Gra(qfg5gra::GraDecoder<'static>);
+
+#[wasm_bindgen(js_class = GraDecoder)]
+impl Gra {
+    #[wasm_bindgen(constructor)]
+    pub fn new(data: &[u8]) -> Result<Gra, JsError> {
+        Ok(Gra(qfg5gra::GraDecoder::new(data)?))
+    }
+
+    /// 256 red, green and blue triplets
+    #[wasm_bindgen(getter)]
+    pub fn palette(&self) -> Vec<u8> {
+        self.0.palette.iter().flat_map(|&(r, g, b)| [ r, g, b ]).collect()
+    }
+
+    /// Objects with `x`, `y`, `width`, `height`, `frameDelay` and the number of `frames`
+    #[wasm_bindgen(getter)]
+    pub fn collections(&self) -> Result<JsValue, JsError> {
+        let collections: Vec<_> = self.0.sprite_collections.iter().map(|sc| CollectionInfo{
+            x: sc.x_position, y: sc.y_position, width: sc.width, height: sc.height, frame_delay: sc.frame_delay, frames: sc.sprites.len(),
+        }).collect();
+        to_js(&collections)
+    }
+
+    /// A frame, with palette index 0 transparent
+    pub fn frame(&self, collection: usize, frame: usize) -> Result<Bitmap, JsError> {
+        let sc = self.0.sprite_collections.get(collection).ok_or_else(|| JsError::new("no such sprite collection"))?;
+        let sprite = sc.sprites.get(frame).ok_or_else(|| JsError::new("no such frame"))?;
+        Ok(pixels::indexed_to_rgba(&sprite.pixels, &self.0.palette, sc.width, sc.height, Some(0)).into())
+    }
+}
+
+#[wasm_bindgen(js_name = QgfDecoder)]
+pub struct Qgf(qfg5qgf::QgfDecoder);
+
+#[wasm_bindgen(js_class = QgfDecoder)]
+impl Qgf {
+    #[wasm_bindgen(constructor)]
+    pub fn new(data: &[u8]) -> Result<Qgf, JsError> {
+        Ok(Qgf(qfg5qgf::QgfDecoder::new(data)?))
+    }
+
+    #[wasm_bindgen(getter, js_name = maxCharWidth)]
+    pub fn max_char_width(&self) -> u32 {
+        self.0.max_char_width
+    }
+
+    #[wasm_bindgen(getter, js_name = charHeight)]
+    pub fn char_height(&self) -> u32 {
+        self.0.char_height
+    }
+
+    #[wasm_bindgen(getter, js_name = charSpace)]
+    pub fn char_space(&self) -> u32 {
+        self.0.char_space
+    }
+
+    #[wasm_bindgen(getter, js_name = is3d)]
+    pub fn is_3d(&self) -> bool {
+        self.0.is_3d
+    }
+
+    /// `text` as black on transparent
+    pub fn render(&self, text: &str) -> Bitmap {
+        pixels::glyphs_to_rgba(&self.0, &self.0.render_text(text)).into()
+    }
+
+    /// All glyphs in a grid of `chars_per_line` columns
+    #[wasm_bindgen(js_name = renderSheet)]
+    pub fn render_sheet(&self, chars_per_line: u32) -> Bitmap {
+        pixels::glyphs_to_rgba(&self.0, &self.0.render_sheet(chars_per_line)).into()
+    }
+}
+
+#[wasm_bindgen(js_name = SpkArchive)]
+pub struct Spk(qfg5spk::SpkArchive);
+
+#[wasm_bindgen(js_class = SpkArchive)]
+impl Spk {
+    #[wasm_bindgen(js_name = fromBytes)]
+    pub fn from_bytes(data: Vec<u8>) -> Result<Spk, JsError> {
+        Ok(Spk(qfg5spk::SpkArchive::from_bytes(data)?))
+    }
+
+    /// Objects with `filename`, `offset` and `length`
+    pub fn entries(&self) -> Result<JsValue, JsError> {
+        to_js(self.0.get_items())
+    }
+
+    /// Contents of an entry; the file name is not case sensitive
+    pub fn extract(&self, filename: &str) -> Result<Vec<u8>, JsError> {
+        let item = self.0.find(filename)
+            .ok_or_else(|| JsError::new(&format!("no entry {}", filename)))?;
+        Ok(self.0.item_data(item)?.into_owned())
+    }
+}
+
+// Run in Node.js with wasm-bindgen-test-runner, from `cargo install wasm-bindgen-cli`:
+//   CARGO_TARGET_WASM32_UNKNOWN_UNKNOWN_RUNNER=wasm-bindgen-test-runner \
+//     cargo test --target wasm32-unknown-unknown --features wasm --lib
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    const GRA: &[u8] = include_bytes!("../tests/fixtures/fixture.gra");
+    const QGF: &[u8] = include_bytes!("../tests/fixtures/fixture.qgf");
+    const QGM: &[u8] = include_bytes!("../tests/fixtures/fixture.qgm");
+    const SPK: &[u8] = include_bytes!("../tests/fixtures/fixture.spk");
+
+    #[wasm_bindgen_test]
+    fn test_qgm() {
+        let qgm = Qgm::new(QGM).unwrap();
+        assert_eq!(qgm.file_id(), 415);
+        let messages: Vec<qfg5qgm::QgmMessage> = serde_wasm_bindgen::from_value(qgm.messages().unwrap()).unwrap();
+        assert_eq!(messages, qfg5qgm::QgmDecoder::new(QGM).unwrap().messages);
+        assert!(Qgm::new(GRA).is_err());
+    }
+
+    #[wasm_bindgen_test]
+    fn test_gra() {
+        let gra = Gra::new(GRA).unwrap();
+        let collections: Vec<serde_json::Value> = serde_wasm_bindgen::from_value(gra.collections().unwrap()).unwrap();
+        assert_eq!(collections, vec![ serde_json::json!({ "x": 10, "y": 20, "width": 2, "height": 2, "frameDelay": 100, "frames": 2 }) ]);
+        let frame = gra.frame(0, 0).unwrap();
+        assert_eq!((frame.width, frame.height), (2, 2));
+        let (r, g, b) = qfg5gra::GraDecoder::new(GRA).unwrap().palette[1];
+        assert_eq!(frame.pixels, [ 0, 0, 0, 0, r, g, b, 255, r, g, b, 255, 0, 0, 0, 0 ]);
+        assert!(gra.frame(0, 2).is_err());
+    }
+
+    #[wasm_bindgen_test]
+    fn test_qgf() {
+        let qgf = Qgf::new(QGF).unwrap();
+        assert_eq!((qgf.char_height(), qgf.char_space(), qgf.is_3d()), (2, 1, false));
+        let text = qgf.render("AA");
+        assert_eq!((text.width, text.height), (5, 2));
+        assert_eq!(&text.pixels[..4], &[ 0, 0, 0, 255 ]);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_spk() {
+        let spk = Spk::from_bytes(SPK.to_vec()).unwrap();
+        let entries: Vec<serde_json::Value> = serde_wasm_bindgen::from_value(spk.entries().unwrap()).unwrap();
+        assert_eq!(entries.iter().map(|e| e["filename"].as_str().unwrap()).collect::<Vec<_>>(), [ "MDL/1.MDL", "ANM/1.ANM" ]);
+        assert_eq!(spk.extract("mdl/1.mdl").unwrap(), include_bytes!("../tests/fixtures/fixture.mdl"));
+        assert!(spk.extract("QGM/1.QGM").is_err());
+    }
+}
@@ -0,0 +1,298 @@
+/*-
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * Copyright (c) 2024 Rink Springer <rink@rink.nu>
+ * For conditions of distribution and use, see LICENSE file
+ */
+//! Round-trip tests: a value is encoded, decoded again and must come back unchanged. Values are
+//! either generated by the proptest strategies below or decoded from the files in tests/fixtures;
+//! the game data cannot be distributed, so those are the synthetic fixtures of the unit tests.
+//!
+//! A writer for a format adds a test that uses assert_roundtrip() with its encoder and the decoder.
+#![cfg(not(target_arch = "wasm32"))]
+use proptest::prelude::*;
+use std::fmt::Debug;
+use std::path::Path;
+use qfg5reenigne::qfg5resource::{qfg5anm, qfg5mdl, qfg5qgm};
+
+/// Returns a line per difference between the pretty-printed Debug output of `a` and `b`,
+/// prefixed by the path of the field, such as `submeshes[0].vertices[2].x`
+fn field_diffs<T: Debug>(a: &T, b: &T) -> Vec<String> {
+    let (a, b) = (format!("{:#?}", a), format!("{:#?}", b));
+    let (a, b): (Vec<_>, Vec<_>) = (a.lines().collect(), b.lines().collect());
+    let mut diffs = Vec::new();
+    // Opened structs and lists: indentation, name and the index of the next element in a list
+    let mut stack: Vec<(usize, String, Option<usize>)> = Vec::new();
+    for n in 0..a.len().max(b.len()) {
+        let (Some(line_a), Some(line_b)) = (a.get(n), b.get(n)) else {
+            diffs.push(format!("{}: lengths differ", path(&stack)));
+            break;
+        };
+        let indent = line_a.len() - line_a.trim_start().len();
+        let text = line_a.trim();
+        while stack.last().is_some_and(|(i, _, _)| *i >= indent) {
+            stack.pop();
+        }
+        if text.trim_end_matches(',').chars().all(|c| "}])".contains(c)) {
+            continue;
+        }
+        let name = match (text.split_once(": "), stack.last_mut()) {
+            (Some((field, _)), _) if !field.contains(' ') => field.to_string(),
+            (_, Some((_, _, Some(index)))) => { *index += 1; format!("[{}]", *index - 1) },
+            _ => String::new(),
+        };
+        if line_a != line_b {
+            let field = format!("{}{}{}", path(&stack), if name.starts_with('[') || stack.is_empty() { "" } else { "." }, name);
+            if a.len() != b.len() {
+                // A list differs in length, so everything after this is shifted
+                diffs.push(format!("{}: lengths differ", field));
+                break;
+            }
+            diffs.push(format!("{}: {} != {}", field, value(line_a), value(line_b)));
+        }
+        if text.ends_with('{') || text.ends_with('[') || text.ends_with('(') {
+            stack.push((indent, name, text.ends_with('[').then_some(0)));
+        }
+    }
+    diffs
+}
+
+fn path(stack: &[(usize, String, Option<usize>)]) -> String {
+    let mut path = String::new();
+    for (_, name, _) in stack.iter().filter(|(_, name, _)| !name.is_empty()) {
+        if !path.is_empty() && !name.starts_with('[') {
+            path.push('.');
+        }
+        path.push_str(name);
+    }
+    path
+}
+
+fn value(line: &str) -> &str {
+    let line = line.trim().trim_end_matches(',');
+    line.split_once(": ").map_or(line, |(_, value)| value)
+}
+
+/// Encodes `value`, decodes the result and fails with the fields that differ
+pub fn assert_roundtrip<T: Debug + PartialEq, E>(value: &T, encode: impl FnOnce(&T) -> E, decode: impl FnOnce(E) -> T) {
+    let decoded = decode(encode(value));
+    if decoded != *value {
+        let diffs = field_diffs(value, &decoded);
+        panic!("round trip changed {} field(s):\n  {}", diffs.len(), diffs.join("\n  "));
+    }
+}
+
+fn fixture(name: &str) -> Vec<u8> {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name);
+    std::fs::read(&path).unwrap_or_else(|e| panic!("cannot read {}: {}", path.display(), e))
+}
+
+/// Fixed-size name as stored in MDL and ANM files, and the bytes it is read from: the name, a
+/// zero byte and any padding
+fn name16() -> impl Strategy<Value = (String, [ u8; 16 ])> {
+    ("[a-z0-9_]{1,15}", any::<[u8; 16]>()).prop_map(|(name, mut raw_name)| {
+        raw_name[..name.len()].copy_from_slice(name.as_bytes());
+        raw_name[name.len()] = 0;
+        (name, raw_name)
+    })
+}
+
+fn coordinate() -> impl Strategy<Value = f32> {
+    -1000.0f32..1000.0
+}
+
+fn vertex() -> impl Strategy<Value = qfg5mdl::Qfg5Vertex> {
+    (coordinate(), coordinate(), coordinate()).prop_map(|(x, y, z)| qfg5mdl::Qfg5Vertex{ x, y, z })
+}
+
+/// A submesh with 3 to 8 vertices; faces only refer to existing vertices and texture coordinates
+pub fn submesh() -> impl Strategy<Value = qfg5mdl::SubMesh> {
+    (name16(), prop::collection::vec(vertex(), 3..8), 1..8usize).prop_flat_map(|((name, raw_name), vertices, num_uvs)| {
+        let num_vertices = vertices.len();
+        let texcoord = (0.0f32..1.0, 0.0f32..1.0).prop_map(|(u, v)| qfg5mdl::Qfg5TexCoord{ u, v });
+        let face = (prop::array::uniform3(0..num_vertices), prop::array::uniform3(0..num_uvs), vertex())
+            .prop_map(|(v, uv, normal)| qfg5mdl::Qfg5Face{
+                vertex1: v[0], vertex2: v[1], vertex3: v[2], uv1: uv[0], uv2: uv[1], uv3: uv[2], subbitmap: 0,
+                normal_x: normal.x, normal_y: normal.y, normal_z: normal.z,
+            });
+        let lighting = (-1.0f32..1.0, -1.0f32..1.0, -1.0f32..1.0, 0.0f32..1.0)
+            .prop_map(|(a, b, c, d)| qfg5mdl::Qfg5LightingVertex{ a, b, c, d });
+        (Just((name, raw_name)), Just(vertices), prop::collection::vec(texcoord, num_uvs), prop::collection::vec(face, 1..8),
+         prop::collection::vec(lighting, num_vertices), prop::array::uniform20(coordinate()))
+            .prop_map(|((name, raw_name), vertices, texcoords, faces, lighting_vertices, header_floats)|
+                qfg5mdl::SubMesh{ name, raw_name, vertices, texcoords, faces, lighting_vertices, header_floats })
+    })
+}
+
+/// Width, height and pixels of a palettized image of at most 16x16
+pub fn indexed_image() -> impl Strategy<Value = (u32, u32, Vec<u8>)> {
+    (1..16u32, 1..16u32).prop_flat_map(|(width, height)| (Just(width), Just(height), prop::collection::vec(any::<u8>(), (width * height) as usize)))
+}
+
+/// MDL textures have power-of-two sizes
+fn subbitmap() -> impl Strategy<Value = qfg5mdl::SubBitmap<'static>> {
+    (0..5u32, 0..5u32).prop_flat_map(|(w, h)| {
+        let (width, height) = (1 << w, 1 << h);
+        prop::collection::vec(any::<u8>(), (width * height) as usize).prop_map(move |bitmap| qfg5mdl::SubBitmap{ width, height, bitmap: bitmap.into() })
+    })
+}
+
+pub fn model() -> impl Strategy<Value = qfg5mdl::Qfg5Model<'static>> {
+    // Entry 255 of the palette is not stored
+    let palette = prop::collection::vec(any::<(u8, u8, u8)>(), 255).prop_map(|mut entries| {
+        entries.push((0, 0, 0));
+        <[ (u8, u8, u8); 256 ]>::try_from(entries).unwrap()
+    });
+    (any::<[u8; 0xc]>(), name16(), any::<[u8; 0xf]>(), palette, prop::collection::vec(any::<u8>(), 254), prop::collection::vec(submesh(), 1..4), subbitmap())
+        .prop_map(|(unknown1, (name, raw_name), unknown2, palette, palette_extra, submeshes, subbitmap)|
+            qfg5mdl::Qfg5Model{ unknown1, name, raw_name, unknown2, palette, palette_extra, submeshes, subbitmaps: vec![ subbitmap ], warnings: Vec::new() })
+}
+
+fn anm_block() -> impl Strategy<Value = qfg5anm::AnmBlock> {
+    (prop::array::uniform3(coordinate()), prop::array::uniform9(-1.0f32..1.0))
+        .prop_map(|(translation, rotation)| qfg5anm::AnmBlock{ translation, rotation })
+}
+
+/// An animation of 1 to 3 anims, which all have the same number of blocks
+pub fn anim() -> impl Strategy<Value = qfg5anm::AnmDecoder> {
+    (name16(), 1..3usize, 1..5usize, 0..1000u32).prop_flat_map(|((name, raw_name), num_anims, num_frames, delay)| {
+        let anim = prop::collection::vec(anm_block(), num_frames).prop_map(|blocks| qfg5anm::AnmAnim{ blocks });
+        prop::collection::vec(anim, num_anims).prop_map(move |anims| qfg5anm::AnmDecoder{ name: name.clone(), raw_name, delay, anims })
+    })
+}
+
+fn qgm_label() -> impl Strategy<Value = qfg5qgm::QgmLabel> {
+    "[A-Z0-9]{12}".prop_map(|label| label.try_into().unwrap())
+}
+
+/// Text is limited to Latin-1, as mangled text is stored a byte per character
+fn qgm_message() -> impl Strategy<Value = qfg5qgm::QgmMessage> {
+    (prop::array::uniform4(any::<u16>()), any::<u16>(), any::<u16>(), prop::option::of(qgm_label()),
+     prop::collection::vec(qgm_label(), 0..4), "[ -~\n\u{a0}-\u{ff}]{0,40}", any::<u16>(),
+     any::<[ u16; 5 ]>(), any::<u16>(), any::<u32>())
+        .prop_map(|(id, speaker_id, msg_id, message_label, dialog_options, text, flags, unknown, text_flag, unknown8)|
+            qfg5qgm::QgmMessage{ id, speaker_id, msg_id, message_label, dialog_options, text, flags, unknown, text_flag, unknown8 })
+}
+
+pub fn qgm() -> impl Strategy<Value = qfg5qgm::QgmDecoder> {
+    (any::<u32>(), any::<u16>(), any::<u16>(), prop::collection::vec(qgm_message(), 0..8))
+        .prop_map(|(version, unknown1, file_id, messages)| qfg5qgm::QgmDecoder{ version, unknown1, file_id, messages, codepage: Default::default(), truncated_at: None })
+}
+
+fn encode_qgm(qgm: &qfg5qgm::QgmDecoder) -> Vec<u8> {
+    qgm.encode().unwrap()
+}
+
+fn decode_qgm(data: Vec<u8>) -> qfg5qgm::QgmDecoder {
+    qfg5qgm::QgmDecoder::new(&data).unwrap()
+}
+
+fn encode_mdl(model: &qfg5mdl::Qfg5Model) -> Vec<u8> {
+    model.encode().unwrap()
+}
+
+fn decode_mdl(data: Vec<u8>) -> qfg5mdl::Qfg5Model<'static> {
+    qfg5mdl::Qfg5Model::new(&data).unwrap().into_owned()
+}
+
+#[test]
+fn test_mdl_fixture() {
+    let data = fixture("fixture.mdl");
+    assert_roundtrip(&decode_mdl(data.clone()), encode_mdl, decode_mdl);
+    assert_eq!(encode_mdl(&decode_mdl(data.clone())), data);
+}
+
+#[test]
+fn test_qgm_fixture() {
+    let data = fixture("fixture.qgm");
+    assert_roundtrip(&decode_qgm(data.clone()), encode_qgm, decode_qgm);
+    assert_eq!(encode_qgm(&decode_qgm(data.clone())), data);
+}
+
+#[test]
+fn test_field_diffs() {
+    let model = qfg5mdl::Qfg5Model::new(&fixture("fixture.mdl")).unwrap();
+    let mut changed = model.clone();
+    changed.submeshes[0].vertices[2].x = 5.0;
+    changed.subbitmaps[0].width = 3;
+    assert_eq!(field_diffs(&model, &changed), vec![
+        "submeshes[0].vertices[2].x: 1.0 != 5.0".to_string(),
+        "subbitmaps[0].width: 2 != 3".to_string(),
+    ]);
+    let mut changed = model.clone();
+    changed.submeshes[0].faces.pop();
+    assert_eq!(field_diffs(&model, &changed), vec![ "submeshes[0].faces[1]: lengths differ".to_string() ]);
+    assert!(field_diffs(&model, &model).is_empty());
+}
+
+#[test]
+#[should_panic(expected = "round trip changed 1 field(s):\n  translation[1]: 2.0 != 0.0")]
+fn test_assert_roundtrip() {
+    let block = qfg5anm::AnmBlock{ translation: [ 1.0, 2.0, 3.0 ], rotation: [ 0.0; 9 ] };
+    assert_roundtrip(&block, |b| b.translation, |t| qfg5anm::AnmBlock{ translation: [ t[0], 0.0, t[2] ], rotation: [ 0.0; 9 ] });
+}
+
+proptest! {
+    #[test]
+    fn test_strategies(model in model(), anim in anim()) {
+        for submesh in &model.submeshes {
+            prop_assert_eq!(submesh.lighting_vertices.len(), submesh.vertices.len());
+            prop_assert!(submesh.faces.iter().all(|f| f.vertex3 < submesh.vertices.len() && f.uv3 < submesh.texcoords.len()));
+        }
+        prop_assert!(anim.anims.iter().all(|a| a.blocks.len() == anim.num_frames()));
+    }
+
+    #[test]
+    fn test_mdl(model in model()) {
+        assert_roundtrip(&model, encode_mdl, decode_mdl);
+    }
+
+    #[test]
+    fn test_qgm(qgm in qgm()) {
+        assert_roundtrip(&qgm, encode_qgm, decode_qgm);
+    }
+}
+
+#[cfg(feature = "serde")]
+mod json {
+    use super::*;
+    use qfg5reenigne::qfg5resource::{qfg5gra, qfg5qgf, qfg5qgm, qfg5rgd};
+
+    fn to_json<T: serde::Serialize>(value: &T) -> String {
+        serde_json::to_string(value).unwrap()
+    }
+
+    fn from_json<T: serde::de::DeserializeOwned>(json: String) -> T {
+        serde_json::from_str(&json).unwrap()
+    }
+
+    #[test]
+    fn test_fixtures() {
+        assert_roundtrip(&qfg5mdl::Qfg5Model::new(&fixture("fixture.mdl")).unwrap(), to_json, from_json);
+        assert_roundtrip(&qfg5anm::AnmDecoder::new(&fixture("fixture.anm")).unwrap(), to_json, from_json);
+        assert_roundtrip(&qfg5gra::GraDecoder::new(&fixture("fixture.gra")).unwrap(), to_json, from_json);
+        assert_roundtrip(&qfg5qgf::QgfDecoder::new(&fixture("fixture.qgf")).unwrap(), to_json, from_json);
+        assert_roundtrip(&qfg5qgm::QgmDecoder::new(&fixture("fixture.qgm")).unwrap(), to_json, from_json);
+        assert_roundtrip(&qfg5rgd::RgdDecoder::new(&fixture("fixture.rgd")).unwrap(), to_json, from_json);
+    }
+
+    proptest! {
+        #[test]
+        fn test_model(model in model()) {
+            assert_roundtrip(&model, to_json, from_json);
+        }
+
+        #[test]
+        fn test_anim(anim in anim()) {
+            assert_roundtrip(&anim, to_json, from_json);
+        }
+
+        #[test]
+        fn test_sprite((width, height, pixels) in indexed_image()) {
+            let gra = qfg5gra::GraDecoder{ palette: [ (1, 2, 3); 256 ], sprite_collections: vec![ qfg5gra::GraSpriteCollection{
+                x_position: 0, y_position: 0, width, height, frame_delay: 0, sprites: vec![ qfg5gra::GraSprite{ pixels: pixels.into() } ],
+            } ] };
+            assert_roundtrip(&gra, to_json, from_json);
+        }
+    }
+}